@@ -395,3 +395,29 @@ pub fn download_treecover<F: FnMut(String, usize, usize) + Send>(
 
     Ok(())
 }
+
+pub fn download_landcover<F: FnMut(String, usize, usize) + Send>(
+    path: &Path,
+    mut progress_callback: F,
+) -> Result<(), anyhow::Error> {
+    let directory = path.join("download").join("landcover");
+    std::fs::create_dir_all(&directory)?;
+
+    // Copernicus Global Land Service, Land Cover 100m, collection 3, epoch 2019.
+    const FILENAME: &str =
+        "PROBAV_LC100_global_v3.0.1_2019-nrt_Discrete-Classification-map_EPSG-4326.tif";
+    bulk_http_download(
+        "Downloading landcover".to_string(),
+        vec![(
+            format!("https://zenodo.org/record/3939050/files/{}", FILENAME),
+            directory.join(FILENAME),
+        )],
+        &mut progress_callback,
+    )?;
+
+    if !directory.join("merged.vrt").exists() {
+        make_vrt(&directory, OsStr::new("tif"))?;
+    }
+
+    Ok(())
+}
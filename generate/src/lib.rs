@@ -5,6 +5,7 @@ use anyhow::Error;
 use atomicwrites::{AtomicFile, OverwriteBehavior};
 use cgmath::{InnerSpace, Vector3};
 use cogbuilder::CogBuilder;
+use fs2::FileExt;
 use itertools::Itertools;
 use rayon::prelude::*;
 use std::collections::{BTreeMap, HashSet};
@@ -35,9 +36,21 @@ pub async fn generate<P: AsRef<std::path::Path>, F: FnMut(String, usize, usize)
     std::fs::create_dir_all(dataset_directory.join("serve").join("tiles"))?;
     std::fs::create_dir_all(dataset_directory.join("serve").join("assets"))?;
 
+    // Terra purges raw downloads to stay within its disk quota, but leaves them alone while this
+    // lock is held. It is released when the lock file is closed at the end of generation.
+    let download_directory = dataset_directory.join("download");
+    std::fs::create_dir_all(&download_directory)?;
+    let download_lock = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(download_directory.join(".lock"))?;
+    download_lock.lock_exclusive()?;
+
     if download {
         download::download_bluemarble(&dataset_directory, &mut progress_callback)?;
         download::download_treecover(&dataset_directory, &mut progress_callback)?;
+        download::download_landcover(&dataset_directory, &mut progress_callback)?;
         download::download_copernicus_wbm(&dataset_directory, &mut progress_callback)?;
         download::download_copernicus_hgt(&dataset_directory, &mut progress_callback)?;
     }
@@ -97,6 +110,18 @@ pub async fn generate<P: AsRef<std::path::Path>, F: FnMut(String, usize, usize)
     treecover.reproject(&mut progress_callback)?;
     treecover.downsample_average_int(&mut progress_callback)?;
 
+    let landcover = Dataset {
+        base_directory: dataset_directory.to_owned(),
+        dataset_name: "landcover",
+        max_level: VNode::LEVEL_CELL_76M,
+        no_data_value: 0u8,
+        grid_registration: false,
+        bits_per_sample: vec![8],
+        signed: false,
+    };
+    landcover.reproject(&mut progress_callback)?;
+    landcover.downsample_majority(&mut progress_callback)?;
+
     let blue_marble = Dataset {
         base_directory: dataset_directory.to_owned(),
         dataset_name: "bluemarble",
@@ -140,6 +165,7 @@ pub async fn generate<P: AsRef<std::path::Path>, F: FnMut(String, usize, usize)
         shore_distance,
         blue_marble,
         treecover,
+        landcover,
         landfraction,
         &mut progress_callback,
     )?;
@@ -670,6 +696,19 @@ where
         )
     }
 
+    /// Downsample a dataset of classes by picking the most common value out of each group of
+    /// four, ignoring missing values. Ties go to the first of the values.
+    pub fn downsample_majority<F>(&self, progress_callback: F) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(String, usize, usize) + Send,
+    {
+        let no_data_value = self.no_data_value;
+        self.downsample(
+            progress_callback,
+            Some(move |a: T, b: T, c: T, d: T| majority([a, b, c, d], no_data_value)),
+        )
+    }
+
     pub fn downsample<F, Downsample>(
         &self,
         progress_callback: F,
@@ -781,6 +820,17 @@ where
     }
 }
 
+fn majority<T: Copy + PartialEq>(values: [T; 4], no_data_value: T) -> T {
+    let count = |v: T| values.iter().filter(|&&w| w == v).count();
+    let mut best = no_data_value;
+    for &v in values.iter().filter(|&&v| v != no_data_value) {
+        if best == no_data_value || count(v) > count(best) {
+            best = v;
+        }
+    }
+    best
+}
+
 #[allow(unused)]
 fn crop<T: Copy>(output: &mut [T], output_resolution: usize, input: &[T], input_resolution: usize) {
     assert!(input_resolution > output_resolution);
@@ -801,6 +851,7 @@ pub fn merge_datasets_to_tiles<F>(
     shore_distance_dataset: Dataset<i16>,
     albedo_dataset: Dataset<u8>,
     tree_cover_dataset: Dataset<u8>,
+    landcover_dataset: Dataset<u8>,
     land_fraction_dataset: Dataset<u8>,
     progress_callback: F,
 ) -> Result<(), anyhow::Error>
@@ -850,6 +901,7 @@ where
     const LAYER_ALBEDO: usize = 3;
     const LAYER_TREECOVER: usize = 4;
     const LAYER_LAND_FRACT: usize = 5;
+    const LAYER_LANDCOVER: usize = 6;
 
     // Per-layer parameters
     let cogs: Vec<Vec<_>> = vec![
@@ -859,9 +911,10 @@ where
         albedo_dataset.cogs()?.into_iter().map(|(_, c)| c).collect(),
         tree_cover_dataset.cogs()?.into_iter().map(|(_, c)| c).collect(),
         land_fraction_dataset.cogs()?.into_iter().map(|(_, c)| c).collect(),
+        landcover_dataset.cogs()?.into_iter().map(|(_, c)| c).collect(),
     ];
-    let grid_registration = vec![true, true, true, false, false, false];
-    let bytes_per_element = vec![2, 2, 2, 3, 1, 1];
+    let grid_registration = vec![true, true, true, false, false, false, false];
+    let bytes_per_element = vec![2, 2, 2, 3, 1, 1, 1];
     let no_data_values: Vec<Vec<u8>> = [
         bytemuck::bytes_of(&heights_dataset.no_data_value),
         bytemuck::bytes_of(&water_level_dataset.no_data_value),
@@ -869,6 +922,7 @@ where
         bytemuck::bytes_of(&albedo_dataset.no_data_value),
         bytemuck::bytes_of(&tree_cover_dataset.no_data_value),
         bytemuck::bytes_of(&land_fraction_dataset.no_data_value),
+        bytemuck::bytes_of(&landcover_dataset.no_data_value),
     ]
     .into_iter()
     .map(|slice| slice.into_iter().cycle().cloned().take(1024).collect())
@@ -971,6 +1025,7 @@ where
             let shore_distance = layers[LAYER_SHORE_DIST].take().unwrap().as_slice_mut::<i16>();
             let tree_cover = layers[LAYER_TREECOVER].take().unwrap().as_slice_mut::<u8>();
             let land_fraction = layers[LAYER_LAND_FRACT].take().unwrap().as_slice_mut::<u8>();
+            let landcover = layers[LAYER_LANDCOVER].take().unwrap().as_slice_mut::<u8>();
 
             let encode_height = |h: i16| ((h as i32 + 1024) * 4).max(0).min(u16::MAX as i32) as u16;
             let mut heights = heights.iter().copied().map(encode_height).collect_vec();
@@ -1028,6 +1083,14 @@ where
                     encode_ktx2_simple(land_fraction, 516, 516, ktx2::Format::R8_UNORM)?
                 },
             );
+            compressed_layers.insert(
+                "landcover.ktx2",
+                if landcover.iter().all(|&l| l == 0) {
+                    Vec::new()
+                } else {
+                    encode_ktx2_simple(landcover, 516, 516, ktx2::Format::R8_UNORM)?
+                },
+            );
 
             if let Some(ref layer) = layers[LAYER_ALBEDO] {
                 if layer.as_slice::<u8>().iter().all(|v| *v == 0) {
@@ -1090,3 +1153,16 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn majority_ignores_missing_values() {
+        assert_eq!(majority([111u8, 0, 0, 0], 0), 111);
+        assert_eq!(majority([20u8, 111, 111, 0], 0), 111);
+        assert_eq!(majority([20u8, 111, 0, 0], 0), 20);
+        assert_eq!(majority([0u8, 0, 0, 0], 0), 0);
+    }
+}
@@ -2,6 +2,21 @@ use std::f64::consts::PI;
 
 use geo::prelude::*;
 use mint::{ColumnMatrix3, ColumnMatrix4, Vector3};
+use terra_types::{PLANET_RADIUS, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Radius of the sphere that `geo`'s haversine functions measure distances on, which is that of
+/// the Earth regardless of which body is being rendered.
+const HAVERSINE_RADIUS: f64 = 6371008.8;
+
+/// Point `meters` away from `start` along the surface of the body, heading towards `bearing`.
+fn destination(start: geo::Point<f64>, bearing: f64, meters: f64) -> geo::Point<f64> {
+    start.haversine_destination(bearing, meters * HAVERSINE_RADIUS / PLANET_RADIUS)
+}
+
+/// Distance in meters between two points along the surface of the body.
+fn distance(start: geo::Point<f64>, end: geo::Point<f64>) -> f64 {
+    start.haversine_distance(&end) * PLANET_RADIUS / HAVERSINE_RADIUS
+}
 
 #[derive(Clone, Debug)]
 struct PlanetCam {
@@ -19,7 +34,7 @@ impl PlanetCam {
         }
 
         let start = geo::Point::new(self.longitude, self.latitude);
-        let end = start.haversine_destination(self.bearing, meters);
+        let end = destination(start, self.bearing, meters);
         let new_bearing = if meters > 0.0 {
             end.haversine_bearing(start) + 180.0
         } else {
@@ -39,7 +54,7 @@ impl PlanetCam {
         }
 
         let start = geo::Point::new(self.longitude, self.latitude);
-        let end = start.haversine_destination(self.bearing + 90.0, meters);
+        let end = destination(start, self.bearing + 90.0, meters);
         let new_bearing = if meters > 0.0 {
             end.haversine_bearing(start) + 90.0
         } else {
@@ -70,12 +85,12 @@ impl PlanetCam {
 
     /// Returns the ECEF position and the view matrix associated with this camera.
     fn position_view(&self, terrain_elevation: f64) -> (Vector3<f64>, ColumnMatrix3<f32>) {
-        let r = 6371000.0 + self.height + terrain_elevation;
+        let r = PLANET_RADIUS + self.height + terrain_elevation;
         let lat = self.latitude.to_radians();
         let long = self.longitude.to_radians();
 
-        const A: f64 = 6378137.0;
-        const B: f64 = 6356752.314245;
+        const A: f64 = PLANET_SEMIMAJOR_AXIS;
+        const B: f64 = PLANET_SEMIMINOR_AXIS;
 
         let up = cgmath::Vector3::new(lat.cos() * long.cos(), lat.cos() * long.sin(), lat.sin());
 
@@ -87,13 +102,13 @@ impl PlanetCam {
         );
 
         let adjusted_pitch =
-            (self.pitch.to_radians() - f64::acos(6371000.0 / r)).clamp(-0.499 * PI, 0.499 * PI);
+            (self.pitch.to_radians() - f64::acos(PLANET_RADIUS / r)).clamp(-0.499 * PI, 0.499 * PI);
 
         let start = geo::Point::new(self.longitude, self.latitude);
-        let center = start.haversine_destination(self.bearing, 1.0);
+        let center = destination(start, self.bearing, 1.0);
         let latc = center.y().to_radians();
         let longc = center.x().to_radians();
-        let forward = (1.0 + adjusted_pitch.tan() / 6371000.0)
+        let forward = (1.0 + adjusted_pitch.tan() / PLANET_RADIUS)
             * cgmath::Vector3::new(latc.cos() * longc.cos(), latc.cos() * longc.sin(), latc.sin())
             - up;
 
@@ -102,13 +117,127 @@ impl PlanetCam {
     }
 }
 
+/// Keeps a camera at least `min_clearance` meters above the terrain. Rather than snapping to the
+/// minimum altitude, cameras that end up too low are smoothly pushed back up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainClearance {
+    /// Minimum distance in meters between the camera and the ground below it.
+    pub min_clearance: f64,
+    /// Time constant in seconds of the pushback. Smaller values correct violations faster.
+    pub pushback_time: f64,
+}
+impl Default for TerrainClearance {
+    fn default() -> Self {
+        Self { min_clearance: 2.0, pushback_time: 0.15 }
+    }
+}
+impl TerrainClearance {
+    /// Returns the altitude a camera currently at `altitude` should have after `dt` seconds,
+    /// given that the ground below it is at `ground_height`. Both heights must be measured
+    /// relative to the same reference. The camera is never left below the ground itself.
+    pub fn apply(&self, altitude: f64, ground_height: f64, dt: f64) -> f64 {
+        let target = ground_height + self.min_clearance;
+        if altitude >= target {
+            return altitude;
+        }
+
+        let t = if self.pushback_time > 0.0 { 1.0 - (-dt / self.pushback_time).exp() } else { 1.0 };
+        (altitude + (target - altitude) * t).max(ground_height.min(target))
+    }
+}
+
+/// Places a camera on a circle around a target point, looking at it from `elevation` degrees
+/// above the horizon. Useful for inspecting a specific feature from all sides.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orbit {
+    /// Latitude of the target in degrees.
+    pub latitude: f64,
+    /// Longitude of the target in degrees.
+    pub longitude: f64,
+    /// Distance in meters between the camera and the target.
+    pub radius: f64,
+    /// Angle in degrees between the horizon at the target and the line to the camera.
+    pub elevation: f64,
+    /// Direction in degrees clockwise from north that the camera faces. It is placed on the
+    /// opposite side of the target.
+    pub azimuth: f64,
+}
+impl Orbit {
+    pub fn increase_azimuth(&mut self, degrees: f64) {
+        self.azimuth = (self.azimuth + degrees).rem_euclid(360.0);
+    }
+    pub fn increase_elevation(&mut self, degrees: f64) {
+        self.elevation = (self.elevation + degrees).clamp(1.0, 89.0);
+    }
+    pub fn scale_radius(&mut self, factor: f64) {
+        self.radius = (self.radius * factor).clamp(10.0, 1e7);
+    }
+
+    /// Latitude and longitude of the camera in degrees.
+    pub fn camera_latitude_longitude(&self) -> (f64, f64) {
+        let target = geo::Point::new(self.longitude, self.latitude);
+        let distance = self.radius * self.elevation.to_radians().cos();
+        let camera = destination(target, self.azimuth + 180.0, distance);
+        (camera.y().clamp(-89.999, 89.999), camera.x())
+    }
+
+    /// Move `camera` onto the orbit, pointed at the target. `target_altitude` is the height of the
+    /// target above sea level, and `terrain_elevation` is the value that will be passed to
+    /// `anchored_position_view`.
+    pub fn apply(&self, camera: &mut DualPlanetCam, target_altitude: f64, terrain_elevation: f64) {
+        let (latitude, longitude) = self.camera_latitude_longitude();
+        let altitude = target_altitude + self.radius * self.elevation.to_radians().sin();
+
+        // The target is further below the camera's horizon than `elevation` because the ground
+        // curves away in between. `position_view` also tilts the view down by the dip of the
+        // horizon, which needs to be undone.
+        let start = geo::Point::new(longitude, latitude);
+        let target = geo::Point::new(self.longitude, self.latitude);
+        let arc = distance(start, target) / PLANET_RADIUS;
+        let dip = f64::acos(PLANET_RADIUS / (PLANET_RADIUS + altitude));
+        camera.free = PlanetCam {
+            latitude,
+            longitude,
+            bearing: start.haversine_bearing(target).rem_euclid(360.0),
+            pitch: (dip - arc).to_degrees() - self.elevation,
+            height: (altitude - terrain_elevation).max(0.001),
+        };
+    }
+}
+
 pub struct DualPlanetCam {
     anchored: Option<PlanetCam>,
     free: PlanetCam,
+    clearance: Option<TerrainClearance>,
 }
 impl DualPlanetCam {
     pub fn new(latitude: f64, longitude: f64, bearing: f64, pitch: f64, height: f64) -> Self {
-        Self { anchored: None, free: PlanetCam { latitude, longitude, bearing, pitch, height } }
+        Self {
+            anchored: None,
+            free: PlanetCam { latitude, longitude, bearing, pitch, height },
+            clearance: None,
+        }
+    }
+
+    /// Prevent the camera from going below the terrain, or remove the constraint if `None`.
+    ///
+    /// The constraint is enforced by `apply_terrain_clearance`, which should be called once per
+    /// frame after moving the camera.
+    pub fn set_terrain_clearance(&mut self, clearance: Option<TerrainClearance>) {
+        self.clearance = clearance;
+    }
+
+    /// Push the camera back above the terrain if it violates the clearance constraint.
+    ///
+    /// `terrain_elevation` is the value later passed to `anchored_position_view`, while
+    /// `ground_height` is the actual height of the ground below the camera. `dt` is the time in
+    /// seconds since the last call.
+    pub fn apply_terrain_clearance(&mut self, terrain_elevation: f64, ground_height: f64, dt: f64) {
+        if let Some(clearance) = self.clearance {
+            let altitude = terrain_elevation + self.free.height;
+            let height = clearance.apply(altitude, ground_height, dt) - terrain_elevation;
+            self.free.height = height.max(self.free.height);
+        }
     }
 
     pub fn detach(&mut self) {
@@ -144,6 +273,12 @@ impl DualPlanetCam {
     pub fn height(&self) -> f64 {
         self.free.height
     }
+    pub fn bearing(&self) -> f64 {
+        self.free.bearing
+    }
+    pub fn pitch(&self) -> f64 {
+        self.free.pitch
+    }
 
     pub fn anchored_latitude_longitude(&self) -> (f64, f64) {
         let c = self.anchored.as_ref().unwrap_or(&self.free);
@@ -181,9 +316,9 @@ impl DualPlanetCam {
 #[cfg(test)]
 mod tests {
     use cgmath::{assert_abs_diff_eq, MetricSpace};
-    use geo::prelude::HaversineDestination;
 
-    use crate::PlanetCam;
+    use crate::{destination, distance, DualPlanetCam, Orbit, PlanetCam, TerrainClearance};
+    use terra_types::PLANET_RADIUS;
 
     #[test]
     fn it_works() {
@@ -201,6 +336,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn terrain_clearance() {
+        let clearance = TerrainClearance { min_clearance: 10.0, pushback_time: 0.1 };
+        assert_eq!(clearance.apply(120.0, 100.0, 0.1), 120.0);
+
+        // Pushback is gradual but converges on the minimum clearance.
+        let mut altitude = 105.0;
+        for _ in 0..100 {
+            let next = clearance.apply(altitude, 100.0, 0.016);
+            assert!(next > altitude && next <= 110.0);
+            altitude = next;
+        }
+        assert_abs_diff_eq!(altitude, 110.0, epsilon = 0.001);
+
+        // Cameras below the ground are immediately moved above it.
+        assert!(clearance.apply(50.0, 100.0, 0.016) >= 100.0);
+    }
+
+    #[test]
+    fn orbit() {
+        let orbit = Orbit {
+            latitude: 46.5,
+            longitude: 7.9,
+            radius: 5000.0,
+            elevation: 30.0,
+            azimuth: 90.0,
+        };
+        let mut camera = DualPlanetCam::new(0.0, 0.0, 0.0, 0.0, 1.0);
+        orbit.apply(&mut camera, 3000.0, 1000.0);
+
+        // The camera is west of the target, at the right distance, looking east and down.
+        let (latitude, longitude) = camera.latitude_longitude();
+        let start = geo::Point::new(longitude, latitude);
+        let target = geo::Point::new(orbit.longitude, orbit.latitude);
+        assert_abs_diff_eq!(distance(start, target), 5000.0 * 0.75f64.sqrt(), epsilon = 0.1);
+        assert!(longitude < orbit.longitude);
+        assert_abs_diff_eq!(camera.bearing(), 90.0, epsilon = 0.1);
+        assert_abs_diff_eq!(camera.height(), 2000.0 + 2500.0, epsilon = 0.001);
+        assert!(camera.pitch() < 0.0 && camera.pitch() > -orbit.elevation);
+    }
+
     #[test]
     fn move_distance() {
         let camera =
@@ -208,33 +384,24 @@ mod tests {
         let mut camera2 = camera.clone();
         camera2.move_forward(100.0);
 
-        const MEAN_EARTH_RADIUS: f64 = 6371008.8;
-        const MEAN_EARTH_CIRCUMFERENCE: f64 = MEAN_EARTH_RADIUS * std::f64::consts::PI;
+        const DEGREES_PER_METER: f64 = 180.0 / (std::f64::consts::PI * PLANET_RADIUS);
 
         let (lat, long) = (camera.latitude.to_radians(), camera.longitude.to_radians());
-        let position = MEAN_EARTH_RADIUS
+        let position = PLANET_RADIUS
             * cgmath::Vector3::new(lat.cos() * long.cos(), lat.cos() * long.sin(), lat.sin());
 
         let (lat, long) = (camera2.latitude.to_radians(), camera2.longitude.to_radians());
-        let position2 = MEAN_EARTH_RADIUS
+        let position2 = PLANET_RADIUS
             * cgmath::Vector3::new(lat.cos() * long.cos(), lat.cos() * long.sin(), lat.sin());
 
         let distance = position.distance(position2);
         assert_abs_diff_eq!(distance, 100.0, epsilon = 0.1);
 
         let start = geo::Point::new(camera.longitude, 0.0);
-        let end = start.haversine_destination(0.0, 1000.0);
-        assert_abs_diff_eq!(
-            end.y() - start.y(),
-            1000.0 * 180.0 / MEAN_EARTH_CIRCUMFERENCE,
-            epsilon = 0.0000001
-        );
+        let end = destination(start, 0.0, 1000.0);
+        assert_abs_diff_eq!(end.y() - start.y(), 1000.0 * DEGREES_PER_METER, epsilon = 0.0000001);
 
-        let end = start.haversine_destination(90.0, 1000.0);
-        assert_abs_diff_eq!(
-            end.x() - start.x(),
-            1000.0 * 180.0 / MEAN_EARTH_CIRCUMFERENCE,
-            epsilon = 0.0000001
-        );
+        let end = destination(start, 90.0, 1000.0);
+        assert_abs_diff_eq!(end.x() - start.x(), 1000.0 * DEGREES_PER_METER, epsilon = 0.0000001);
     }
 }
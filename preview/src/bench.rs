@@ -0,0 +1,109 @@
+//! Benchmarking: fly a camera path at a fixed time step and report how long frames took.
+
+use crate::camera_path::{CameraPath, Keyframe};
+use std::time::Instant;
+
+/// Path time that passes each frame. Using a fixed step rather than wall clock time means every
+/// run renders the same sequence of camera poses, regardless of how fast frames are.
+const TIME_STEP: f64 = 1.0 / 60.0;
+
+/// How much of the terrain to load before frames start being timed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Prewarm {
+    /// Only wait for the root tiles, so streaming cost is included in the frame times.
+    None,
+    /// Wait for the tiles around the start of the path to load.
+    Start,
+    /// Also fly the whole path once before timing a second pass, so most tiles along it are
+    /// already cached.
+    Path,
+}
+
+pub struct Bench {
+    path: CameraPath,
+    prewarm: Prewarm,
+    /// Number of frames into the current pass over the path.
+    frame: u64,
+    /// Whether the current pass is the untimed one flown by `Prewarm::Path`.
+    warming_up: bool,
+    frame_times: Vec<f64>,
+    last_frame: Option<Instant>,
+    /// Tiles streamed and generated before the timed pass started.
+    start_totals: (u64, u64),
+}
+impl Bench {
+    pub fn new(path: CameraPath, prewarm: Prewarm) -> Self {
+        Self {
+            path,
+            prewarm,
+            frame: 0,
+            warming_up: prewarm == Prewarm::Path,
+            frame_times: Vec::new(),
+            last_frame: None,
+            start_totals: (0, 0),
+        }
+    }
+
+    /// Pose at the start of the path.
+    pub fn start(&self) -> Keyframe {
+        self.path.sample(0.0)
+    }
+
+    /// Pose to render the next frame from, or `None` once the timed pass is complete. Must be
+    /// called exactly once per frame.
+    pub fn next_frame(&mut self, statistics: &terra::Statistics) -> Option<Keyframe> {
+        let now = Instant::now();
+        let time = self.frame as f64 * TIME_STEP;
+        if time > self.path.duration() {
+            if !self.warming_up {
+                return None;
+            }
+            self.warming_up = false;
+            self.frame = 0;
+            self.last_frame = None;
+            return self.next_frame(statistics);
+        }
+
+        if self.frame == 0 {
+            self.start_totals = (statistics.tiles_streamed, statistics.tiles_generated);
+        }
+        if let Some(last_frame) = self.last_frame.filter(|_| !self.warming_up) {
+            self.frame_times.push((now - last_frame).as_secs_f64());
+        }
+        self.last_frame = Some(now);
+        self.frame += 1;
+        Some(self.path.sample(time))
+    }
+
+    /// Frame time statistics and tile counts for the timed pass, as JSON.
+    pub fn report(&self, statistics: &terra::Statistics) -> serde_json::Value {
+        let mut sorted = self.frame_times.clone();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| match sorted.len() {
+            0 => 0.0,
+            n => sorted[((n - 1) as f64 * p).round() as usize] * 1000.0,
+        };
+        let total: f64 = sorted.iter().sum();
+
+        serde_json::json!({
+            "prewarm": format!("{:?}", self.prewarm).to_lowercase(),
+            "frames": sorted.len(),
+            "seconds": total,
+            "fps": sorted.len() as f64 / total.max(f64::MIN_POSITIVE),
+            "frame_time_ms": {
+                "mean": total * 1000.0 / sorted.len().max(1) as f64,
+                "p50": percentile(0.5),
+                "p95": percentile(0.95),
+                "p99": percentile(0.99),
+                "max": percentile(1.0),
+            },
+            "tiles_streamed": statistics.tiles_streamed - self.start_totals.0,
+            "tiles_generated": statistics.tiles_generated - self.start_totals.1,
+            "generator_costs_ms": statistics
+                .generator_costs
+                .iter()
+                .map(|t| (t.name.to_string(), (t.duration.as_secs_f64() * 1000.0).into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>(),
+        })
+    }
+}
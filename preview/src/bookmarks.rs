@@ -0,0 +1,99 @@
+//! Saved camera locations, so that problems at specific sites can be revisited quickly.
+//!
+//! Bookmarks are stored one per line as `latitude longitude altitude heading name`, with the
+//! angles in degrees and the altitude in meters above the terrain. Blank lines and lines starting
+//! with `#` are ignored.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Place to put the camera, facing along `heading` degrees clockwise from north.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+    pub heading: f64,
+}
+impl Location {
+    /// Parse `latitude,longitude[,altitude[,heading]]`, taking any missing values from
+    /// `defaults`.
+    pub fn parse(s: &str, defaults: Location) -> Result<Self, String> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| format!("Invalid number '{}'", v.trim())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let location = match values[..] {
+            [latitude, longitude] => Location { latitude, longitude, ..defaults },
+            [latitude, longitude, altitude] => {
+                Location { latitude, longitude, altitude, ..defaults }
+            }
+            [latitude, longitude, altitude, heading] => {
+                Location { latitude, longitude, altitude, heading }
+            }
+            _ => return Err("Expected latitude,longitude[,altitude[,heading]]".to_string()),
+        };
+        if !(-90.0..=90.0).contains(&location.latitude) {
+            return Err(format!("Latitude {} is out of range", location.latitude));
+        }
+        if location.altitude <= 0.0 {
+            return Err("Altitude must be above the terrain".to_string());
+        }
+        Ok(location)
+    }
+}
+
+pub struct Bookmarks {
+    path: PathBuf,
+    entries: Vec<(String, Location)>,
+}
+impl Bookmarks {
+    /// Load the bookmarks in `path`, which need not exist yet. Malformed lines are reported and
+    /// skipped.
+    pub fn load(path: PathBuf) -> Self {
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        let mut entries = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let values: Vec<f64> = fields.by_ref().take(4).map_while(|v| v.parse().ok()).collect();
+            let name = fields.collect::<Vec<_>>().join(" ");
+            match values[..] {
+                [latitude, longitude, altitude, heading] if !name.is_empty() => {
+                    entries.push((name, Location { latitude, longitude, altitude, heading }))
+                }
+                _ => eprintln!("Skipping malformed bookmark on line {} of {:?}", i + 1, path),
+            }
+        }
+        Self { path, entries }
+    }
+
+    /// Bookmark number `n`, counting from one in the order they appear in the file.
+    pub fn nth(&self, n: usize) -> Option<&(String, Location)> {
+        self.entries.get(n.checked_sub(1)?)
+    }
+
+    pub fn find(&self, name: &str) -> Option<Location> {
+        self.entries.iter().find(|(n, _)| n == name).map(|&(_, location)| location)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, Location)> {
+        self.entries.iter()
+    }
+
+    /// Add a bookmark and append it to the file. Returns its number.
+    pub fn add(&mut self, name: String, location: Location) -> std::io::Result<usize> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(
+            file,
+            "{:.6} {:.6} {:.1} {:.1} {}",
+            location.latitude, location.longitude, location.altitude, location.heading, name
+        )?;
+        self.entries.push((name, location));
+        Ok(self.entries.len())
+    }
+}
@@ -0,0 +1,104 @@
+//! Timed camera paths that can be recorded while flying and played back later, for repeatable
+//! fly-throughs.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+/// Minimum time between recorded keyframes, in seconds. Playback interpolates between them.
+const RECORD_INTERVAL: f64 = 0.1;
+
+/// Camera pose at a point along a path. Angles are in degrees, and the altitude is in meters
+/// above the terrain.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// Seconds since the start of the path.
+    pub time: f64,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+    pub heading: f64,
+    pub pitch: f64,
+}
+
+/// Difference between two angles in degrees, wrapped to the shorter way around.
+fn angle_delta(from: f64, to: f64) -> f64 {
+    (to - from + 180.0).rem_euclid(360.0) - 180.0
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+}
+impl CameraPath {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let camera_path: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        if camera_path.keyframes.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Camera path has no keyframes",
+            ));
+        }
+        if camera_path.keyframes.windows(2).any(|w| w[1].time < w[0].time) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Camera path keyframes must be in order",
+            ));
+        }
+        Ok(camera_path)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Length of the path in seconds.
+    pub fn duration(&self) -> f64 {
+        self.keyframes.last().map(|k| k.time).unwrap_or(0.0)
+    }
+
+    /// Pose `time` seconds into the path, interpolated between the surrounding keyframes. Times
+    /// beyond either end are clamped to it. The path must not be empty.
+    pub fn sample(&self, time: f64) -> Keyframe {
+        let i = self.keyframes.partition_point(|k| k.time <= time);
+        if i == 0 {
+            return self.keyframes[0];
+        } else if i == self.keyframes.len() {
+            return self.keyframes[i - 1];
+        }
+
+        let (a, b) = (self.keyframes[i - 1], self.keyframes[i]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe {
+            time,
+            latitude: a.latitude + (b.latitude - a.latitude) * t,
+            longitude: a.longitude + angle_delta(a.longitude, b.longitude) * t,
+            altitude: a.altitude + (b.altitude - a.altitude) * t,
+            heading: a.heading + angle_delta(a.heading, b.heading) * t,
+            pitch: a.pitch + (b.pitch - a.pitch) * t,
+        }
+    }
+}
+
+/// Records the camera pose as it flies, sampling at most every `RECORD_INTERVAL` seconds.
+pub struct Recorder {
+    start: Instant,
+    path: CameraPath,
+}
+impl Recorder {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), path: CameraPath::default() }
+    }
+
+    /// Record the current pose. The `time` field of `keyframe` is ignored.
+    pub fn record(&mut self, keyframe: Keyframe) {
+        let time = self.start.elapsed().as_secs_f64();
+        if self.path.keyframes.last().map_or(true, |k| time - k.time >= RECORD_INTERVAL) {
+            self.path.keyframes.push(Keyframe { time, ..keyframe });
+        }
+    }
+
+    pub fn finish(self) -> CameraPath {
+        self.path
+    }
+}
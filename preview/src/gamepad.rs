@@ -0,0 +1,146 @@
+//! Gamepad tuning and button bindings, loaded from a JSON file such as:
+//!
+//! ```json
+//! { "deadzone": 0.2, "response_exponent": 2.5, "bindings": { "ascend": "North" } }
+//! ```
+//!
+//! Any field that is left out keeps its default. Buttons are named as in `gilrs::Button`.
+
+use gilrs::{Axis, Button, Gamepad};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ButtonName(pub Button);
+impl TryFrom<String> for ButtonName {
+    type Error = String;
+    fn try_from(name: String) -> Result<Self, String> {
+        const BUTTONS: [Button; 19] = [
+            Button::South,
+            Button::East,
+            Button::North,
+            Button::West,
+            Button::C,
+            Button::Z,
+            Button::LeftTrigger,
+            Button::LeftTrigger2,
+            Button::RightTrigger,
+            Button::RightTrigger2,
+            Button::Select,
+            Button::Start,
+            Button::Mode,
+            Button::LeftThumb,
+            Button::RightThumb,
+            Button::DPadUp,
+            Button::DPadDown,
+            Button::DPadLeft,
+            Button::DPadRight,
+        ];
+        BUTTONS
+            .into_iter()
+            .find(|b| format!("{:?}", b) == name)
+            .map(ButtonName)
+            .ok_or_else(|| format!("Unknown gamepad button '{}'", name))
+    }
+}
+
+/// Buttons that trigger each action while held.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bindings {
+    pub ascend: ButtonName,
+    pub descend: ButtonName,
+    /// Move faster, like holding Shift.
+    pub boost: ButtonName,
+}
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            ascend: ButtonName(Button::DPadUp),
+            descend: ButtonName(Button::DPadDown),
+            boost: ButtonName(Button::RightTrigger),
+        }
+    }
+}
+
+/// Control inputs read from a gamepad for one frame.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GamepadInput {
+    pub forward: f64,
+    pub right: f64,
+    pub up: f64,
+    /// Degrees to turn the camera by, per second.
+    pub bearing: f64,
+    pub pitch: f64,
+    pub boost: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    /// Fraction of each stick's travel around the center that is ignored, so that drifting
+    /// controllers stay still when released.
+    pub deadzone: f32,
+    /// Exponent applied to stick deflection outside the deadzone. Values above one give finer
+    /// control near the center while still reaching full speed at the edge.
+    pub response_exponent: f32,
+    /// Multiplier on movement speed from the left stick.
+    pub move_sensitivity: f64,
+    /// Degrees per second that the camera turns with the right stick fully deflected.
+    pub look_sensitivity: f64,
+    pub invert_look_y: bool,
+    pub bindings: Bindings,
+}
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.15,
+            response_exponent: 2.0,
+            move_sensitivity: 1.0,
+            look_sensitivity: 120.0,
+            invert_look_y: false,
+            bindings: Bindings::default(),
+        }
+    }
+}
+impl GamepadConfig {
+    /// Load the config in `path`, or the defaults if it doesn't exist.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Apply the deadzone and response curve to a stick position. The deadzone is radial, so
+    /// moving along a diagonal isn't slower to start than along an axis.
+    fn stick(&self, x: f32, y: f32) -> (f64, f64) {
+        let magnitude = x.hypot(y);
+        if magnitude <= self.deadzone {
+            return (0.0, 0.0);
+        }
+        let scaled = ((magnitude - self.deadzone) / (1.0 - self.deadzone)).min(1.0);
+        let scale = scaled.powf(self.response_exponent) / magnitude;
+        ((x * scale) as f64, (y * scale) as f64)
+    }
+
+    pub fn read(&self, gamepad: Gamepad) -> GamepadInput {
+        let (right, forward) =
+            self.stick(gamepad.value(Axis::LeftStickX), gamepad.value(Axis::LeftStickY));
+        let (look_x, look_y) =
+            self.stick(gamepad.value(Axis::RightStickX), gamepad.value(Axis::RightStickY));
+        let (look_z, _) = self.stick(gamepad.value(Axis::RightZ), 0.0);
+        let pressed = |binding: ButtonName| gamepad.is_pressed(binding.0) as i32 as f64;
+
+        GamepadInput {
+            forward: forward * self.move_sensitivity,
+            right: right * self.move_sensitivity,
+            up: pressed(self.bindings.ascend) - pressed(self.bindings.descend),
+            bearing: (look_x + look_z) * self.look_sensitivity,
+            pitch: look_y * self.look_sensitivity * if self.invert_look_y { -1.0 } else { 1.0 },
+            boost: pressed(self.bindings.boost) > 0.0,
+        }
+    }
+}
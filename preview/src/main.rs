@@ -1,27 +1,244 @@
+mod bench;
+mod bookmarks;
+mod camera_path;
+mod gamepad;
+mod soak;
+mod stats;
+
+use bench::Bench;
+use bookmarks::{Bookmarks, Location};
+use camera_path::{CameraPath, Keyframe, Recorder};
 use clap::{Parser, Subcommand};
-use gilrs::{Axis, Button, Gilrs};
-use planetcam::DualPlanetCam;
-use std::time::Instant;
+use gamepad::GamepadConfig;
+use gilrs::Gilrs;
+use planetcam::{DualPlanetCam, Orbit, TerrainClearance};
+use std::time::{Duration, Instant};
 use winit::{
     dpi::PhysicalPosition,
-    event::{self, ElementState, MouseButton},
+    event::{self, ElementState, MouseButton, MouseScrollDelta},
     event_loop::{ControlFlow, EventLoop},
+    window::{CursorGrabMode, Window},
 };
 
+/// Degrees the camera turns per unit of mouse motion while the mouse is captured.
+const MOUSE_LOOK_SENSITIVITY: f64 = 0.1;
+/// Factor that movement speeds up by while Shift is held.
+const SPEED_BOOST: f64 = 4.0;
+/// Factor that each notch of the scroll wheel changes the movement speed by.
+const SCROLL_SPEED_STEP: f64 = 1.25;
+/// Rates that the - and = keys switch the clock between, in simulated seconds per second.
+const TIME_RATES: [f64; 6] = [0.0, 1.0, 60.0, 600.0, 3600.0, 36000.0];
+
+/// Graphics APIs that the preview can be run with.
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+enum Backend {
+    /// Whichever of Vulkan, DirectX 12 or Metal the platform supports.
+    Primary,
+    Vulkan,
+    Dx12,
+    Metal,
+    Gl,
+}
+impl From<Backend> for wgpu::Backends {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Primary => wgpu::Backends::PRIMARY,
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::Gl => wgpu::Backends::GL,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+enum Quality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+impl From<Quality> for terra::TerrainQuality {
+    fn from(quality: Quality) -> Self {
+        match quality {
+            Quality::Low => terra::TerrainQuality::Low,
+            Quality::Medium => terra::TerrainQuality::Medium,
+            Quality::High => terra::TerrainQuality::High,
+            Quality::Ultra => terra::TerrainQuality::Ultra,
+        }
+    }
+}
+
+/// Parse a window size given as "WIDTHxHEIGHT".
+fn parse_window_size(s: &str) -> Result<(u32, u32), String> {
+    let size = s.split_once('x').and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+    match size {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("Expected WIDTHxHEIGHT, got '{}'", s)),
+    }
+}
+
+fn parse_latitude_longitude(s: &str) -> Result<(f64, f64), String> {
+    let point = s
+        .split_once(',')
+        .and_then(|(lat, lon)| Some((lat.trim().parse().ok()?, lon.trim().parse().ok()?)));
+    match point {
+        Some((latitude, longitude)) if (-90.0..=90.0).contains(&latitude) => {
+            Ok((latitude, longitude))
+        }
+        _ => Err(format!("Expected latitude,longitude in degrees, got '{}'", s)),
+    }
+}
+
+/// Seconds since the Unix epoch, for naming screenshots.
+fn timestamp() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Turn the camera, or swing it around the target while orbiting.
+fn turn(camera: &mut DualPlanetCam, orbit: &mut Option<Orbit>, bearing: f64, pitch: f64) {
+    match orbit {
+        Some(orbit) => {
+            orbit.increase_azimuth(bearing);
+            orbit.increase_elevation(-pitch);
+        }
+        None => {
+            camera.increase_bearing(bearing);
+            camera.increase_pitch(pitch);
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long, global = true, default_value = "8FH495PF+29")]
     plus: String,
     #[arg(long, global = true, default_value = "0")]
     heading: f64,
+    /// Latitude to start at in degrees, instead of at the plus code.
+    #[arg(long, global = true, requires = "longitude", allow_negative_numbers = true)]
+    latitude: Option<f64>,
+    /// Longitude to start at in degrees, instead of at the plus code.
+    #[arg(long, global = true, requires = "latitude", allow_negative_numbers = true)]
+    longitude: Option<f64>,
     #[arg(short, long, global = true, default_value = "200000")]
     elevation: f64,
+    /// Start at "latitude,longitude[,altitude[,heading]]" in degrees and meters above the terrain
+    /// instead of at the plus code.
+    #[arg(long, global = true)]
+    goto: Option<String>,
+    /// Start orbiting around "latitude,longitude" in degrees.
+    #[arg(long, global = true, value_parser = parse_latitude_longitude, allow_hyphen_values = true)]
+    orbit: Option<(f64, f64)>,
+    /// Distance in meters between the camera and the target when orbiting.
+    #[arg(long, global = true, default_value = "5000")]
+    orbit_radius: f64,
+    /// Angle in degrees above the horizon that the target is viewed from when orbiting.
+    #[arg(long, global = true, default_value = "30")]
+    orbit_elevation: f64,
+    /// JSON file with gamepad deadzones, sensitivity and button bindings. Defaults are used if it
+    /// doesn't exist.
+    #[arg(long, global = true, default_value = "gamepad.json")]
+    gamepad_config: std::path::PathBuf,
+    /// File that bookmarked locations are loaded from and saved to.
+    #[arg(long, global = true, default_value = "bookmarks.txt")]
+    bookmarks: std::path::PathBuf,
+    /// Graphics API to render with.
+    #[arg(long, global = true, value_enum, default_value = "primary")]
+    backend: Backend,
+    /// Index of the GPU to render with, as printed by --list-adapters. By default a high
+    /// performance GPU is chosen.
+    #[arg(long, global = true)]
+    adapter: Option<usize>,
+    /// Print the GPUs available with the selected backend and exit.
+    #[arg(long)]
+    list_adapters: bool,
+    /// Open a window of this size in pixels, given as WIDTHxHEIGHT, instead of going fullscreen.
+    #[arg(long, global = true, value_parser = parse_window_size)]
+    window_size: Option<(u32, u32)>,
+    /// Quality preset to start with.
+    #[arg(long, global = true, value_enum)]
+    quality: Option<Quality>,
+    /// UTC time of day to start at, as HH:MM:SS.
     #[arg(long, global = true)]
     time: Option<String>,
+    /// Simulated seconds that pass per second.
     #[arg(long, global = true, default_value = "0.0")]
     timescale: f64,
     #[arg(long, global = true)]
     server: Option<String>,
+    /// Additional tile server to layer on top of the main one. May be repeated.
+    #[arg(long, global = true)]
+    mount: Vec<String>,
+    /// Directory to cache downloaded tiles in.
+    #[arg(long, global = true)]
+    cache_directory: Option<std::path::PathBuf>,
+    /// Maximum disk space for cached tiles, in gigabytes.
+    #[arg(long, global = true)]
+    max_disk_usage: Option<f64>,
+    /// Directory passed to `generate`, whose raw downloads also count toward --max-disk-usage.
+    #[arg(long, global = true)]
+    dataset_directory: Option<std::path::PathBuf>,
+    /// Deepest quadtree level to load tiles for.
+    #[arg(long, global = true)]
+    max_level: Option<u8>,
+    /// Vertex of a polygon outside of which only coarse tiles are loaded, given as
+    /// "latitude,longitude" in degrees. May be repeated.
+    #[arg(long, global = true)]
+    region_vertex: Vec<String>,
+    /// Largest on-screen terrain error to tolerate, in pixels. Smaller values load more detail.
+    #[arg(long, global = true, default_value = "0.25")]
+    max_pixel_error: f32,
+    /// Largest on-screen error to tolerate for terrain drawn into the shadow map, in pixels.
+    #[arg(long, global = true)]
+    shadow_max_pixel_error: Option<f32>,
+    /// Reduce detail toward the edges of the view, as would be done for a VR headset.
+    #[arg(long, global = true)]
+    fixed_foveation: bool,
+    /// Number of samples per pixel to use for MSAA.
+    #[arg(long, global = true, default_value = "1")]
+    msaa: u32,
+    /// Skip drawing meshes that were hidden behind nearer terrain in the previous frame.
+    #[arg(long, global = true)]
+    occlusion_culling: bool,
+    /// Reflect terrain visible on screen off water, in addition to the sky.
+    #[arg(long, global = true)]
+    screen_space_reflections: bool,
+    /// Carve gullies into generated terrain detail with hydraulic erosion.
+    #[arg(long, global = true)]
+    erosion: bool,
+    /// Check a sample of generated tiles for invalid contents and log any problems found.
+    #[arg(long, global = true)]
+    validate: bool,
+    /// Keep the camera at least this many meters above the terrain.
+    #[arg(long, global = true)]
+    min_clearance: Option<f64>,
+    /// GeoJSON file whose lines and polygons are draped over the terrain. May be repeated.
+    #[arg(long, global = true)]
+    geojson: Vec<std::path::PathBuf>,
+    /// File that camera paths are recorded to with F5 and played back from with F6.
+    #[arg(long, global = true, default_value = "camera-path.json")]
+    camera_path: std::path::PathBuf,
+    /// Start playing back the camera path right away.
+    #[arg(long)]
+    play: bool,
+    /// Fly the camera path in this file at a fixed time step, then report frame time statistics
+    /// as JSON and exit.
+    #[arg(long)]
+    bench: Option<std::path::PathBuf>,
+    /// How much terrain to load before the benchmark starts timing frames.
+    #[arg(long, value_enum, default_value = "start")]
+    bench_prewarm: bench::Prewarm,
+    /// Write the benchmark results to this file instead of printing them.
+    #[arg(long)]
+    bench_output: Option<std::path::PathBuf>,
+    /// Fly random routes while checking for resource leaks and streaming stalls.
+    #[arg(long)]
+    soak: bool,
+    #[arg(long, default_value = "1")]
+    soak_seed: u64,
+    #[arg(long, default_value = "4.0")]
+    soak_hours: f64,
 
     #[command(subcommand)]
     subcommand: Option<SubcommandArgs>,
@@ -37,28 +254,35 @@ enum SubcommandArgs {
     },
 }
 
-fn compute_projection_matrix(width: f32, height: f32) -> cgmath::Matrix4<f32> {
+/// Reversed-Z perspective projection mapping `near` to a depth of one and `far` to zero.
+fn compute_projection_matrix(width: f32, height: f32, near: f32, far: f32) -> cgmath::Matrix4<f32> {
     let aspect = width / height;
     let f = 1.0 / (45.0f32.to_radians() / aspect).tan();
-    let near = 0.1;
+    let a = near / (far - near);
+    let b = far * near / (far - near);
 
     #[cfg_attr(rustfmt, rustfmt_skip)]
     cgmath::Matrix4::new(
         f/aspect,  0.0,  0.0,   0.0,
         0.0,       f,    0.0,   0.0,
-        0.0,       0.0,  0.0,  -1.0,
-        0.0,       0.0,  near,  0.0)
+        0.0,       0.0,  a,    -1.0,
+        0.0,       0.0,  b,     0.0)
 }
 
-fn make_depth_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+fn make_depth_buffer(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> wgpu::TextureView {
     device
         .create_texture(&wgpu::TextureDescriptor {
             size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Depth32Float,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
             view_formats: &[],
             label: None,
         })
@@ -70,6 +294,7 @@ fn configure_surface(
     surface: &wgpu::Surface,
     swapchain_format: wgpu::TextureFormat,
     size: winit::dpi::PhysicalSize<u32>,
+    present_mode: wgpu::PresentMode,
 ) {
     surface.configure(
         &device,
@@ -78,13 +303,85 @@ fn configure_surface(
             format: swapchain_format,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo, // disable vsync by switching to Mailbox,
+            present_mode,
             alpha_mode: wgpu::CompositeAlphaMode::Opaque,
             view_formats: Vec::new(),
         },
     );
 }
 
+/// Grab and hide the cursor so that mouse motion turns the camera, or release it again. Returns
+/// whether the cursor ended up captured.
+fn set_mouse_captured(window: &Window, captured: bool) -> bool {
+    if captured {
+        // Not every platform supports locking the cursor in place, but confining it to the window
+        // works just as well since only relative motion is used.
+        let grab = window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined));
+        if let Err(e) = grab {
+            eprintln!("Failed to capture mouse: {}", e);
+            return false;
+        }
+    } else {
+        let _ = window.set_cursor_grab(CursorGrabMode::None);
+    }
+    window.set_cursor_visible(!captured);
+    captured
+}
+
+/// Key used to jump to a bookmark, with 1 being the first.
+fn bookmark_key(keycode: event::VirtualKeyCode) -> Option<usize> {
+    use event::VirtualKeyCode::*;
+    [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9]
+        .iter()
+        .position(|&k| k == keycode)
+        .map(|i| i + 1)
+}
+
+/// Run a command typed into the terminal, returning where to move the camera if it asked for a
+/// jump.
+fn run_command(command: &str, current: Location, bookmarks: &mut Bookmarks) -> Option<Location> {
+    let (name, argument) = command.trim().split_once(' ').unwrap_or((command.trim(), ""));
+    let argument = argument.trim();
+    match name {
+        "goto" if !argument.is_empty() => {
+            let bookmark = match argument.parse::<usize>() {
+                Ok(n) => bookmarks.nth(n).map(|&(_, location)| location),
+                Err(_) => bookmarks.find(argument),
+            };
+            match bookmark.map(Ok).unwrap_or_else(|| Location::parse(argument, current)) {
+                Ok(location) => return Some(location),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        "save" if !argument.is_empty() => match bookmarks.add(argument.to_string(), current) {
+            Ok(n) => println!("Saved bookmark {}: {}", n, argument),
+            Err(e) => eprintln!("Failed to save bookmark: {}", e),
+        },
+        "list" => {
+            for (i, (name, l)) in bookmarks.iter().enumerate() {
+                println!(
+                    "{}: {} ({:.5}, {:.5}, {:.0} m)",
+                    i + 1,
+                    name,
+                    l.latitude,
+                    l.longitude,
+                    l.altitude
+                );
+            }
+        }
+        _ => {
+            println!("Commands:");
+            println!("  goto <latitude>,<longitude>[,<altitude>[,<heading>]]");
+            println!("  goto <bookmark name or number>");
+            println!("  save <bookmark name>");
+            println!("  list");
+        }
+    }
+    None
+}
+
 fn main() {
     env_logger::init();
 
@@ -110,28 +407,41 @@ fn main() {
         None
     };
 
+    let backends = wgpu::Backends::from(opt.backend);
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor { backends, ..Default::default() });
+    if opt.list_adapters {
+        for (i, adapter) in instance.enumerate_adapters(backends).enumerate() {
+            let info = adapter.get_info();
+            println!("{}: {} ({:?}, {:?})", i, info.name, info.backend, info.device_type);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new();
-    let monitor = event_loop
-        .available_monitors()
-        .find(|monitor| monitor.video_modes().any(|mode| mode.size().width == 1920));
-    let window = winit::window::WindowBuilder::new()
-        .with_visible(false)
-        .with_fullscreen(Some(winit::window::Fullscreen::Borderless(monitor)))
-        .build(&event_loop)
-        .unwrap();
-
-    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-        backends: wgpu::Backends::PRIMARY,
-        ..Default::default()
-    });
+    let window = winit::window::WindowBuilder::new().with_title("Terra").with_visible(false);
+    let window = match opt.window_size {
+        Some((width, height)) => {
+            window.with_inner_size(winit::dpi::PhysicalSize::new(width, height))
+        }
+        None => window.with_fullscreen(Some(winit::window::Fullscreen::Borderless(None))),
+    };
+    let window = window.build(&event_loop).unwrap();
+
     let surface = unsafe { instance.create_surface(&window).unwrap() };
-    let adapter = runtime
-        .block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        }))
-        .expect("Unable to create compatible wgpu adapter");
+    let adapter = match opt.adapter {
+        Some(index) => instance
+            .enumerate_adapters(backends)
+            .nth(index)
+            .expect("No adapter with that index, see --list-adapters"),
+        None => runtime
+            .block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: Some(&surface),
+                force_fallback_adapter: false,
+            }))
+            .expect("Unable to create compatible wgpu adapter"),
+    };
+    assert!(adapter.is_surface_supported(&surface), "The adapter can't present to the window");
     let swapchain_format = surface.get_capabilities(&adapter).formats[0];
 
     // Terra requires support for BC texture compression.
@@ -141,14 +451,21 @@ fn main() {
         | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
         | wgpu::Features::PUSH_CONSTANTS
         | wgpu::Features::TEXTURE_FORMAT_16BIT_NORM
-        | adapter.features() & wgpu::Features::MULTI_DRAW_INDIRECT;
+        | adapter.features() & wgpu::Features::MULTI_DRAW_INDIRECT
+        | adapter.features() & wgpu::Features::MULTI_DRAW_INDIRECT_COUNT
+        | adapter.features() & wgpu::Features::POLYGON_MODE_LINE
+        | adapter.features() & wgpu::Features::TEXTURE_BINDING_ARRAY
+        | adapter.features()
+            & wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
+        | adapter.features() & wgpu::Features::TIMESTAMP_QUERY;
 
     let (device, queue) = runtime
         .block_on(adapter.request_device(
             &wgpu::DeviceDescriptor {
                 features,
                 limits: wgpu::Limits {
-                    max_texture_array_layers: 1024,
+                    // Terra falls back to reduced quality on adapters with fewer array layers.
+                    max_texture_array_layers: adapter.limits().max_texture_array_layers.min(1024),
                     max_compute_invocations_per_workgroup: 512,
                     max_push_constant_size: 128,
                     ..wgpu::Limits::default()
@@ -160,9 +477,14 @@ fn main() {
         .expect("Unable to create compatible wgpu device");
 
     let mut size = window.inner_size();
-    let mut depth_buffer = make_depth_buffer(&device, size.width, size.height);
+    let mut depth_buffer = make_depth_buffer(&device, size.width, size.height, opt.msaa);
 
-    configure_surface(&device, &surface, swapchain_format, size);
+    // Benchmarks shouldn't be limited to the refresh rate of the display.
+    let present_mode = match opt.bench {
+        Some(_) => wgpu::PresentMode::AutoNoVsync,
+        None => wgpu::PresentMode::Fifo,
+    };
+    configure_surface(&device, &surface, swapchain_format, size, present_mode);
 
     #[cfg(feature = "smaa")]
     let mut smaa_target = smaa::SmaaTarget::new(
@@ -174,6 +496,8 @@ fn main() {
         smaa::SmaaMode::Smaa1X,
     );
 
+    let gamepad_config =
+        GamepadConfig::load(&opt.gamepad_config).expect("Failed to load gamepad config");
     let mut gilrs = Gilrs::new().unwrap();
     let mut current_gamepad = None;
     for (_id, gamepad) in gilrs.gamepads() {
@@ -182,12 +506,79 @@ fn main() {
 
     let plus_center =
         open_location_code::decode(&opt.plus).expect("Failed to parse plus code").center;
+    let mut start = Location {
+        latitude: plus_center.y(),
+        longitude: plus_center.x(),
+        altitude: opt.elevation,
+        heading: opt.heading,
+    };
+    if let (Some(latitude), Some(longitude)) = (opt.latitude, opt.longitude) {
+        start = Location { latitude, longitude, ..start };
+    }
+    if let Some(goto) = &opt.goto {
+        start = Location::parse(goto, start).expect("Failed to parse --goto location");
+    }
+    let mut bench = opt.bench.as_ref().map(|path| {
+        let path = CameraPath::load(path).expect("Failed to load benchmark camera path");
+        Bench::new(path, opt.bench_prewarm)
+    });
+    if let Some(k) = bench.as_ref().map(Bench::start) {
+        start = Location {
+            latitude: k.latitude,
+            longitude: k.longitude,
+            altitude: k.altitude,
+            heading: k.heading,
+        };
+    }
 
-    let mut camera =
-        DualPlanetCam::new(plus_center.y(), plus_center.x(), opt.heading, -10.0, opt.elevation);
+    let clearance = opt
+        .min_clearance
+        .map(|min_clearance| TerrainClearance { min_clearance, ..Default::default() });
+    let camera_at = move |location: Location| {
+        let mut camera = DualPlanetCam::new(
+            location.latitude,
+            location.longitude,
+            location.heading,
+            -10.0,
+            location.altitude,
+        );
+        camera.set_terrain_clearance(clearance);
+        camera
+    };
+    let mut camera = camera_at(start);
+    let current_location = |camera: &DualPlanetCam| {
+        let (latitude, longitude) = camera.latitude_longitude();
+        Location { latitude, longitude, altitude: camera.height(), heading: camera.bearing() }
+    };
+
+    let mut orbit = opt.orbit.map(|(latitude, longitude)| Orbit {
+        latitude,
+        longitude,
+        radius: opt.orbit_radius.max(10.0),
+        elevation: opt.orbit_elevation.clamp(1.0, 89.0),
+        azimuth: start.heading,
+    });
+    if let Some(orbit) = &orbit {
+        // Terrain heights aren't known yet, but this puts the camera close enough to stream in
+        // the right tiles.
+        orbit.apply(&mut camera, 0.0, 0.0);
+    }
+
+    let mut bookmarks = Bookmarks::load(opt.bookmarks.clone());
+    let (command_sender, commands) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            match line {
+                Ok(line) if command_sender.send(line).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
 
     let mut mouse_state = false;
     let mut last_mouse_position: Option<PhysicalPosition<f64>> = None;
+    let mut mouse_captured = false;
+    let mut speed_scale = 1.0;
 
     let mut up_key = false;
     let mut down_key = false;
@@ -195,6 +586,32 @@ fn main() {
     let mut left_key = false;
     let mut space_key = false;
     let mut z_key = false;
+    let mut w_key = false;
+    let mut a_key = false;
+    let mut s_key = false;
+    let mut d_key = false;
+    let mut shift_key = false;
+    let mut wireframe = false;
+    let mut bounds_overlay = false;
+    let mut debug_view = None;
+    let mut auto_exposure = false;
+    let mut tonemapper = terra::Tonemapper::default();
+    let mut light_shafts = false;
+    let mut height_fog = false;
+    let mut precipitation = None;
+    let mut show_stats = false;
+    let mut frame_stats = stats::FrameStats::default();
+    let mut stats_overlay = stats::StatsOverlay::new(&device, swapchain_format);
+    let mut recorder: Option<Recorder> = None;
+    let mut frame_dump: Option<(std::path::PathBuf, u64)> = None;
+    let mut playback: Option<(CameraPath, Instant)> = None;
+    if opt.play {
+        let path = CameraPath::load(&opt.camera_path).expect("Failed to load camera path");
+        playback = Some((path, Instant::now()));
+    }
+    let mut soak = opt
+        .soak
+        .then(|| soak::Soak::new(opt.soak_seed, Duration::from_secs_f64(opt.soak_hours * 3600.0)));
 
     if let Some(opt2) = opt.subcommand {
         match opt2 {
@@ -227,9 +644,50 @@ fn main() {
     };
 
     let server = opt.server.unwrap_or_else(|| terra::DEFAULT_TILE_SERVER_URL.to_string());
-    let mut terrain = runtime.block_on(terra::Terrain::new(&device, &queue, server)).unwrap();
+    let mut builder =
+        opt.mount.into_iter().fold(terra::MapFileBuilder::new(server), |b, m| b.mount(m));
+    if let Some(directory) = opt.cache_directory {
+        builder = builder.cache_directory(directory);
+    }
+    if let Some(gigabytes) = opt.max_disk_usage {
+        builder = builder.max_disk_usage((gigabytes * 1e9) as u64);
+    }
+    if let Some(directory) = opt.dataset_directory {
+        builder = builder.dataset_directory(directory);
+    }
+    let mut builder = terra::TerrainBuilder::new(builder);
+    if !opt.region_vertex.is_empty() {
+        let polygon = opt
+            .region_vertex
+            .iter()
+            .map(|v| {
+                let (lat, lon) = v.split_once(',').expect("expected latitude,longitude");
+                (lat.trim().parse().unwrap(), lon.trim().parse().unwrap())
+            })
+            .collect();
+        builder = builder.region_of_interest(terra::RegionOfInterest::new(polygon));
+    }
+    if let Some(level) = opt.max_level {
+        builder = builder.max_level(level);
+    }
+    if let Some(quality) = opt.quality {
+        builder = builder.quality(quality.into());
+    }
+    let mut terrain = runtime.block_on(builder.build(&device, &queue)).unwrap();
+    if terrain.reduced_quality() {
+        eprintln!("GPU limits are too low for full quality, running at reduced detail");
+    }
+    terrain.set_validation(opt.validate);
+    terrain.set_sample_count(opt.msaa).unwrap();
+    terrain.set_occlusion_culling(opt.occlusion_culling);
+    terrain.set_screen_space_reflections(opt.screen_space_reflections);
+    terrain.set_erosion(opt.erosion.then(terra::Erosion::default));
+    for path in &opt.geojson {
+        let geojson = std::fs::read_to_string(path).unwrap();
+        terrain.add_geojson_overlay(&geojson, &Default::default()).unwrap();
+    }
 
-    {
+    if opt.bench.is_none() || opt.bench_prewarm != bench::Prewarm::None {
         let pb = indicatif::ProgressBar::new(100);
         pb.set_style(
             indicatif::ProgressStyle::default_bar()
@@ -249,7 +707,17 @@ fn main() {
     }
 
     let mut last_time = None;
-    let start_time = std::time::Instant::now();
+    let mut julian_day = 2451545.0 + epoch;
+    let mut time_rate = opt.timescale;
+    let print_time = |julian_day: f64, time_rate: f64| {
+        let hours = (julian_day + 0.5).rem_euclid(1.0) * 24.0;
+        println!(
+            "Time {:02}:{:02} UTC, running at {}x",
+            hours as u32,
+            (hours.fract() * 60.0) as u32,
+            time_rate
+        );
+    };
     window.set_visible(true);
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
@@ -259,15 +727,44 @@ fn main() {
                     *control_flow = ControlFlow::Exit;
                 }
                 event::WindowEvent::MouseInput { button: MouseButton::Left, state, .. } => {
-                    mouse_state = state == ElementState::Pressed;
+                    mouse_state = state == ElementState::Pressed && !mouse_captured;
                     if !mouse_state {
                         last_mouse_position = None;
                     }
                 }
+                event::WindowEvent::MouseInput {
+                    button: MouseButton::Right,
+                    state: ElementState::Pressed,
+                    ..
+                } => {
+                    mouse_captured = set_mouse_captured(&window, !mouse_captured);
+                    mouse_state = false;
+                    last_mouse_position = None;
+                }
+                event::WindowEvent::Focused(false) if mouse_captured => {
+                    mouse_captured = set_mouse_captured(&window, false);
+                }
+                event::WindowEvent::MouseWheel { delta, .. } => {
+                    let notches = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y as f64,
+                        MouseScrollDelta::PixelDelta(position) => position.y / 50.0,
+                    };
+                    match orbit.as_mut() {
+                        Some(orbit) => orbit.scale_radius(SCROLL_SPEED_STEP.powf(-notches)),
+                        None => {
+                            speed_scale = (speed_scale * SCROLL_SPEED_STEP.powf(notches))
+                                .clamp(1.0 / 64.0, 64.0)
+                        }
+                    }
+                }
                 event::WindowEvent::CursorMoved { position, .. } => {
                     if let Some(last_position) = last_mouse_position {
-                        camera.increase_bearing((position.x - last_position.x) * -0.2);
-                        camera.increase_pitch((position.y - last_position.y) * 0.1);
+                        turn(
+                            &mut camera,
+                            &mut orbit,
+                            (position.x - last_position.x) * -0.2,
+                            (position.y - last_position.y) * 0.1,
+                        );
                     }
                     if mouse_state {
                         last_mouse_position = Some(position);
@@ -283,7 +780,20 @@ fn main() {
                 } => {
                     let pressed = state == event::ElementState::Pressed;
                     match keycode {
-                        event::VirtualKeyCode::Escape => *control_flow = ControlFlow::Exit,
+                        event::VirtualKeyCode::Escape if pressed => {
+                            if mouse_captured {
+                                mouse_captured = set_mouse_captured(&window, false);
+                            } else {
+                                *control_flow = ControlFlow::Exit;
+                            }
+                        }
+                        event::VirtualKeyCode::W => w_key = pressed,
+                        event::VirtualKeyCode::A => a_key = pressed,
+                        event::VirtualKeyCode::S => s_key = pressed,
+                        event::VirtualKeyCode::D => d_key = pressed,
+                        event::VirtualKeyCode::LShift | event::VirtualKeyCode::RShift => {
+                            shift_key = pressed
+                        }
                         event::VirtualKeyCode::Left => left_key = pressed,
                         event::VirtualKeyCode::Right => right_key = pressed,
                         event::VirtualKeyCode::Up => up_key = pressed,
@@ -292,6 +802,185 @@ fn main() {
                         event::VirtualKeyCode::Z | event::VirtualKeyCode::Semicolon => {
                             z_key = pressed
                         }
+                        event::VirtualKeyCode::F if pressed => {
+                            terrain.set_lod_frozen(!terrain.is_lod_frozen());
+                        }
+                        event::VirtualKeyCode::N if pressed => terrain.step_lod(),
+                        event::VirtualKeyCode::C if pressed => {
+                            terrain.set_culling_frozen(!terrain.is_culling_frozen());
+                        }
+                        event::VirtualKeyCode::LBracket | event::VirtualKeyCode::RBracket
+                            if pressed =>
+                        {
+                            let hours = if shift_key { 10.0 / 60.0 } else { 1.0 };
+                            let sign =
+                                if keycode == event::VirtualKeyCode::LBracket { -1.0 } else { 1.0 };
+                            julian_day += sign * hours / 24.0;
+                            print_time(julian_day, time_rate);
+                        }
+                        event::VirtualKeyCode::Minus if pressed => {
+                            time_rate = TIME_RATES
+                                .into_iter()
+                                .rev()
+                                .find(|&r| r < time_rate)
+                                .unwrap_or(0.0);
+                            print_time(julian_day, time_rate);
+                        }
+                        event::VirtualKeyCode::Equals if pressed => {
+                            time_rate = TIME_RATES
+                                .into_iter()
+                                .find(|&r| r > time_rate)
+                                .unwrap_or(time_rate);
+                            print_time(julian_day, time_rate);
+                        }
+                        event::VirtualKeyCode::F1 if pressed => {
+                            show_stats = !show_stats;
+                            frame_stats = stats::FrameStats::default();
+                            stats_overlay.set_text(&device, &queue, &[]);
+                        }
+                        event::VirtualKeyCode::F2 if pressed => {
+                            wireframe = !wireframe;
+                            terrain.set_wireframe(wireframe);
+                        }
+                        event::VirtualKeyCode::F3 if pressed => {
+                            bounds_overlay = !bounds_overlay;
+                            terrain.set_bounds_overlay(bounds_overlay);
+                        }
+                        event::VirtualKeyCode::F4 if pressed => {
+                            debug_view = match debug_view {
+                                None => Some(terra::DebugView::Levels),
+                                Some(terra::DebugView::Levels) => Some(terra::DebugView::Normals),
+                                Some(terra::DebugView::Normals) => Some(terra::DebugView::Albedo),
+                                Some(terra::DebugView::Albedo) => Some(terra::DebugView::Heights),
+                                Some(terra::DebugView::Heights) => {
+                                    Some(terra::DebugView::TreeCover)
+                                }
+                                Some(terra::DebugView::TreeCover) => None,
+                            };
+                            terrain.set_debug_view(debug_view);
+                        }
+                        event::VirtualKeyCode::F5 if pressed => match recorder.take() {
+                            Some(recorder) => match recorder.finish().save(&opt.camera_path) {
+                                Ok(()) => println!("Saved camera path to {:?}", opt.camera_path),
+                                Err(e) => eprintln!("Failed to save camera path: {}", e),
+                            },
+                            None => {
+                                println!("Recording camera path");
+                                recorder = Some(Recorder::new());
+                            }
+                        },
+                        event::VirtualKeyCode::F6 if pressed => {
+                            if playback.take().is_none() {
+                                match CameraPath::load(&opt.camera_path) {
+                                    Ok(path) => playback = Some((path, Instant::now())),
+                                    Err(e) => eprintln!("Failed to load camera path: {}", e),
+                                }
+                            }
+                        }
+                        event::VirtualKeyCode::F11 if pressed => match frame_dump.take() {
+                            Some((directory, frames)) => {
+                                println!("Saved {} frames to {}", frames, directory.display())
+                            }
+                            None => {
+                                let directory = std::path::PathBuf::from(format!(
+                                    "terra-frames-{}",
+                                    timestamp()
+                                ));
+                                match std::fs::create_dir_all(&directory) {
+                                    Ok(()) => {
+                                        println!("Saving frames to {}", directory.display());
+                                        frame_dump = Some((directory, 0));
+                                    }
+                                    Err(e) => eprintln!("Failed to create frame directory: {}", e),
+                                }
+                            }
+                        },
+                        event::VirtualKeyCode::F12 if pressed => {
+                            let path = format!("terra-{}.png", timestamp());
+                            match runtime.block_on(terrain.capture_frame(&device, &queue)) {
+                                Ok(image) => match image.save(&path) {
+                                    Ok(()) => println!("Saved screenshot to {}", path),
+                                    Err(e) => eprintln!("Failed to save screenshot: {}", e),
+                                },
+                                Err(e) => eprintln!("Failed to capture frame: {}", e),
+                            }
+                        }
+                        event::VirtualKeyCode::E if pressed => {
+                            auto_exposure = !auto_exposure;
+                            terrain.set_exposure(if auto_exposure {
+                                terra::Exposure::auto()
+                            } else {
+                                terra::Exposure::default()
+                            });
+                        }
+                        event::VirtualKeyCode::T if pressed => {
+                            tonemapper = match tonemapper {
+                                terra::Tonemapper::Aces => terra::Tonemapper::Reinhard,
+                                terra::Tonemapper::Reinhard => terra::Tonemapper::Uncharted2,
+                                terra::Tonemapper::Uncharted2 => terra::Tonemapper::Aces,
+                            };
+                            terrain.set_tonemapper(tonemapper);
+                        }
+                        event::VirtualKeyCode::L if pressed => {
+                            light_shafts = !light_shafts;
+                            terrain.set_light_shafts(if light_shafts { 1.0 } else { 0.0 });
+                        }
+                        event::VirtualKeyCode::G if pressed => {
+                            height_fog = !height_fog;
+                            terrain.set_height_fog(height_fog.then(terra::HeightFog::default));
+                        }
+                        event::VirtualKeyCode::R if pressed => {
+                            precipitation = match precipitation {
+                                None => Some(terra::PrecipitationKind::Rain),
+                                Some(terra::PrecipitationKind::Rain) => {
+                                    Some(terra::PrecipitationKind::Snow)
+                                }
+                                Some(terra::PrecipitationKind::Snow) => None,
+                            };
+                            terrain.set_precipitation(
+                                precipitation.map(|kind| terra::Precipitation {
+                                    kind,
+                                    ..Default::default()
+                                }),
+                            );
+                            terrain.set_surface_conditions(match precipitation {
+                                None => terra::SurfaceConditions::default(),
+                                Some(terra::PrecipitationKind::Rain) => {
+                                    terra::SurfaceConditions { wetness: 1.0, snow_cover: 0.0 }
+                                }
+                                Some(terra::PrecipitationKind::Snow) => {
+                                    terra::SurfaceConditions { wetness: 0.0, snow_cover: 0.5 }
+                                }
+                            });
+                        }
+                        event::VirtualKeyCode::O if pressed => {
+                            orbit = match orbit {
+                                Some(_) => None,
+                                None => {
+                                    // Orbit the point on the ground that the camera is facing.
+                                    let elevation = (-camera.pitch()).clamp(10.0, 80.0);
+                                    let height = camera.height().max(10.0);
+                                    let mut target = camera_at(current_location(&camera));
+                                    target.move_forward(height / elevation.to_radians().tan());
+                                    let (latitude, longitude) = target.latitude_longitude();
+                                    Some(Orbit {
+                                        latitude,
+                                        longitude,
+                                        radius: height / elevation.to_radians().sin(),
+                                        elevation,
+                                        azimuth: camera.bearing(),
+                                    })
+                                }
+                            };
+                        }
+                        event::VirtualKeyCode::B if pressed => {
+                            let name = format!("bookmark {}", bookmarks.iter().count() + 1);
+                            let location = current_location(&camera);
+                            match bookmarks.add(name.clone(), location) {
+                                Ok(n) => println!("Saved bookmark {}: {}", n, name),
+                                Err(e) => eprintln!("Failed to save bookmark: {}", e),
+                            }
+                        }
                         event::VirtualKeyCode::Tab => {
                             if pressed && modifiers.ctrl() {
                                 if camera.is_detached() {
@@ -301,7 +990,18 @@ fn main() {
                                 }
                             }
                         }
-                        _ => {}
+                        _ => {
+                            if let Some(n) = bookmark_key(keycode).filter(|_| pressed) {
+                                match bookmarks.nth(n) {
+                                    Some((name, location)) => {
+                                        println!("Jumping to bookmark {}: {}", n, name);
+                                        camera = camera_at(*location);
+                                        orbit = None;
+                                    }
+                                    None => eprintln!("No bookmark {}", n),
+                                }
+                            }
+                        }
                     }
                 }
                 event::WindowEvent::Resized(new_size) => {
@@ -310,11 +1010,22 @@ fn main() {
                     #[cfg(feature = "smaa")]
                     smaa_target.resize(&device, new_size.width, new_size.height);
 
-                    configure_surface(&device, &surface, swapchain_format, size);
-                    depth_buffer = make_depth_buffer(&device, size.width, size.height);
+                    configure_surface(&device, &surface, swapchain_format, size, present_mode);
+                    depth_buffer = make_depth_buffer(&device, size.width, size.height, opt.msaa);
                 }
                 _ => {}
             },
+            event::Event::DeviceEvent {
+                event: event::DeviceEvent::MouseMotion { delta: (dx, dy) },
+                ..
+            } if mouse_captured => {
+                turn(
+                    &mut camera,
+                    &mut orbit,
+                    dx * MOUSE_LOOK_SENSITIVITY,
+                    -dy * MOUSE_LOOK_SENSITIVITY,
+                );
+            }
             event::Event::MainEventsCleared => {
                 window.request_redraw();
             }
@@ -335,42 +1046,133 @@ fn main() {
                 let time = Instant::now();
                 let dt = (time - last_time.unwrap_or(time)).as_secs_f64();
                 last_time = Some(time);
+                julian_day += dt * time_rate / 86400.0;
+
+                while let Ok(command) = commands.try_recv() {
+                    let current = current_location(&camera);
+                    if let Some(location) = run_command(&command, current, &mut bookmarks) {
+                        camera = camera_at(location);
+                        orbit = None;
+                    }
+                }
 
                 // Compute motion from keyboard.
                 let mut up_factor = space_key as i32 as f64 - z_key as i32 as f64;
-                let mut right_factor = right_key as i32 as f64 - left_key as i32 as f64;
-                let mut forward_factor = up_key as i32 as f64 - down_key as i32 as f64;
+                let mut right_factor =
+                    (right_key || d_key) as i32 as f64 - (left_key || a_key) as i32 as f64;
+                let mut forward_factor =
+                    (up_key || w_key) as i32 as f64 - (down_key || s_key) as i32 as f64;
 
                 // Incorporate gamepad input.
                 while let Some(gilrs::Event { id, event: _event, time: _ }) = gilrs.next_event() {
                     current_gamepad = Some(id);
                 }
-                if let Some(gamepad) = current_gamepad.map(|id| gilrs.gamepad(id)) {
-                    forward_factor += gamepad.value(Axis::LeftStickY) as f64;
-                    right_factor += gamepad.value(Axis::LeftStickX) as f64;
-                    if gamepad.is_pressed(Button::DPadUp) {
-                        up_factor += 1.0;
+                let gamepad = current_gamepad
+                    .map(|id| gamepad_config.read(gilrs.gamepad(id)))
+                    .unwrap_or_default();
+                forward_factor += gamepad.forward;
+                right_factor += gamepad.right;
+                up_factor += gamepad.up;
+                turn(&mut camera, &mut orbit, gamepad.bearing * dt, gamepad.pitch * dt);
+
+                // Use control inputs to update camera location. While orbiting they swing the
+                // camera around the target and zoom in and out instead.
+                let boost = shift_key || gamepad.boost;
+                let speed_scale = if boost { speed_scale * SPEED_BOOST } else { speed_scale };
+                if let Some(orbit) = orbit.as_mut() {
+                    orbit.increase_azimuth(right_factor * 45.0 * speed_scale * dt);
+                    orbit.increase_elevation(up_factor * 30.0 * speed_scale * dt);
+                    orbit.scale_radius((-forward_factor * speed_scale * dt).exp());
+
+                    let (lat, long) = orbit.camera_latitude_longitude();
+                    let target_height = terrain
+                        .get_height(orbit.latitude.to_radians(), orbit.longitude.to_radians())
+                        as f64;
+                    let surface_height =
+                        terrain.get_height(lat.to_radians(), long.to_radians()) as f64;
+                    orbit.apply(&mut camera, target_height, surface_height + 2.0);
+                } else {
+                    let vertical_speed = 3.0 * camera.height() * speed_scale;
+                    let horizontal_speed =
+                        12.0 * camera.height().clamp(2.0, 100000.0) * speed_scale;
+                    camera.move_up(up_factor * vertical_speed * dt);
+                    camera.move_forward(forward_factor * horizontal_speed * dt);
+                    camera.move_right(right_factor * horizontal_speed * dt);
+                }
+
+                if let Some(soak) = soak.as_mut() {
+                    if soak.is_finished() {
+                        println!("Soak test completed successfully");
+                        *control_flow = ControlFlow::Exit;
+                        return;
                     }
-                    if gamepad.is_pressed(Button::DPadDown) {
-                        up_factor += -1.0;
+                    let c = soak.advance();
+                    camera =
+                        DualPlanetCam::new(c.latitude, c.longitude, c.bearing, -10.0, c.altitude);
+                    camera.set_terrain_clearance(clearance);
+                }
+
+                if let Some(bench) = bench.as_mut() {
+                    match bench.next_frame(&terrain.statistics()) {
+                        Some(k) => {
+                            camera = DualPlanetCam::new(
+                                k.latitude,
+                                k.longitude,
+                                k.heading,
+                                k.pitch,
+                                k.altitude,
+                            );
+                            camera.set_terrain_clearance(clearance);
+                        }
+                        None => {
+                            let report = bench.report(&terrain.statistics());
+                            let report = serde_json::to_string_pretty(&report).unwrap();
+                            match &opt.bench_output {
+                                Some(path) => std::fs::write(path, report)
+                                    .expect("Failed to write benchmark results"),
+                                None => println!("{}", report),
+                            }
+                            *control_flow = ControlFlow::Exit;
+                            return;
+                        }
                     }
-                    camera.increase_bearing(120.0 * gamepad.value(Axis::RightZ) as f64 * dt);
-                    camera.increase_bearing(120.0 * gamepad.value(Axis::RightStickX) as f64 * dt);
-                    camera.increase_pitch(120.0 * gamepad.value(Axis::RightStickY) as f64 * dt);
                 }
 
-                // Use control inputs to update camera location.
-                let vertical_speed = 3.0 * camera.height();
-                let horizontal_speed = 12.0 * camera.height().clamp(2.0, 100000.0);
-                camera.move_up(up_factor * vertical_speed * dt);
-                camera.move_forward(forward_factor * horizontal_speed * dt);
-                camera.move_right(right_factor * horizontal_speed * dt);
+                if let Some((path, start)) = &playback {
+                    let k = path.sample(start.elapsed().as_secs_f64());
+                    camera =
+                        DualPlanetCam::new(k.latitude, k.longitude, k.heading, k.pitch, k.altitude);
+                    camera.set_terrain_clearance(clearance);
+                    if start.elapsed().as_secs_f64() > path.duration() {
+                        println!("Finished playing camera path");
+                        playback = None;
+                    }
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    let (latitude, longitude) = camera.latitude_longitude();
+                    recorder.record(Keyframe {
+                        time: 0.0,
+                        latitude,
+                        longitude,
+                        altitude: camera.height(),
+                        heading: camera.bearing(),
+                        pitch: camera.pitch(),
+                    });
+                }
 
                 // Compute position and camera matrices.
                 let (lat, long) = camera.latitude_longitude();
                 let surface_height = terrain.get_height(lat.to_radians(), long.to_radians()) as f64;
+                camera.apply_terrain_clearance(surface_height + 2.0, surface_height, dt);
                 let (position, view) = camera.anchored_position_view(surface_height + 2.0);
-                let proj = compute_projection_matrix(size.width as f32, size.height as f32);
+                let position: mint::Point3<f64> = position.into();
+                let (near, far) = terrain.recommended_clip_planes(position);
+                let proj = compute_projection_matrix(
+                    size.width as f32,
+                    size.height as f32,
+                    near as f32,
+                    far as f32,
+                );
                 let view: cgmath::Matrix4<f32> = cgmath::Matrix3::from(view).into();
                 let view_proj = proj * view;
                 let view_proj = mint::ColumnMatrix4 {
@@ -389,15 +1191,14 @@ fn main() {
                     w: render_view_proj.w.into(),
                 };
 
-                terrain.update(
-                    &device,
-                    &queue,
-                    view_proj,
-                    position.into(),
-                    2451545.0
-                        + epoch
-                        + start_time.elapsed().as_secs_f64() * opt.timescale / 86400.0,
-                );
+                terrain.set_lod_target(terra::LodTarget {
+                    viewport_height: size.height,
+                    max_pixel_error: opt.max_pixel_error,
+                    foveation: opt.fixed_foveation.then(terra::Foveation::default),
+                    shadow_max_pixel_error: opt.shadow_max_pixel_error,
+                    ..Default::default()
+                });
+                terrain.update(&device, &queue, view_proj, position, julian_day);
                 terrain.render_shadows(&device, &queue);
                 terrain.render(
                     &device,
@@ -407,9 +1208,35 @@ fn main() {
                     (size.width, size.height),
                     render_view_proj,
                 );
+                if let Some((directory, frames)) = frame_dump.as_mut() {
+                    let path = directory.join(format!("frame-{:06}.png", frames));
+                    match runtime.block_on(terrain.capture_frame(&device, &queue)) {
+                        Ok(image) => match image.save(&path) {
+                            Ok(()) => *frames += 1,
+                            Err(e) => {
+                                eprintln!("Failed to save frame: {}", e);
+                                frame_dump = None;
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("Failed to capture frame: {}", e);
+                            frame_dump = None;
+                        }
+                    }
+                }
+                if show_stats {
+                    if frame_stats.record_frame(dt, &terrain.statistics()) {
+                        stats_overlay.set_text(&device, &queue, frame_stats.lines());
+                    }
+                    stats_overlay.render(&device, &queue, &frame, (size.width, size.height));
+                }
 
                 drop(frame);
                 frame_texture.present();
+
+                if let Some(soak) = soak.as_mut() {
+                    soak.check(&terrain.statistics(), &terrain.resource_usage());
+                }
             }
             _ => (),
         }
@@ -0,0 +1,213 @@
+//! Soak testing: fly random great-circle routes for a long time while checking invariants.
+
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+/// Simulated time that passes each frame. Using a fixed step rather than wall clock time makes
+/// the flown routes depend only on the seed and the frame number.
+const TIME_STEP: f64 = 1.0 / 60.0;
+
+/// How often to log a statistics snapshot and check for backlog growth.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of consecutive snapshot windows over which the streaming backlog may grow before it
+/// is considered a failure.
+const MAX_GROWING_WINDOWS: usize = 5;
+
+const MAX_STREAMING_INFLIGHT: usize = 128;
+const MAX_HEIGHTMAP_DOWNLOADS: usize = 64;
+
+const PLANET_RADIUS: f64 = terra::BODY.radius();
+
+/// Small deterministic random number generator, so that soak runs can be reproduced exactly.
+struct XorShift(u64);
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Unit vector for the given latitude and longitude, in radians.
+fn to_unit(latitude: f64, longitude: f64) -> [f64; 3] {
+    [latitude.cos() * longitude.cos(), latitude.cos() * longitude.sin(), latitude.sin()]
+}
+
+struct Route {
+    start: [f64; 3],
+    end: [f64; 3],
+    /// Angle between `start` and `end`, in radians.
+    angle: f64,
+    altitude: f64,
+    traveled: f64,
+}
+impl Route {
+    fn random(rng: &mut XorShift) -> Self {
+        let mut random_point = || {
+            let latitude = (2.0 * rng.next_f64() - 1.0).asin();
+            let longitude = 2.0 * PI * rng.next_f64() - PI;
+            to_unit(latitude, longitude)
+        };
+        let start = random_point();
+        let end = random_point();
+        let dot = start[0] * end[0] + start[1] * end[1] + start[2] * end[2];
+        let altitude = 200.0 * 500.0f64.powf(rng.next_f64());
+        Self { start, end, angle: dot.clamp(-1.0, 1.0).acos(), altitude, traveled: 0.0 }
+    }
+
+    /// Position along the route after traveling the given fraction of it.
+    fn point(&self, t: f64) -> [f64; 3] {
+        if self.angle < 1e-9 {
+            return self.start;
+        }
+        let a = ((1.0 - t) * self.angle).sin() / self.angle.sin();
+        let b = (t * self.angle).sin() / self.angle.sin();
+        [
+            a * self.start[0] + b * self.end[0],
+            a * self.start[1] + b * self.end[1],
+            a * self.start[2] + b * self.end[2],
+        ]
+    }
+
+    fn is_finished(&self) -> bool {
+        self.traveled >= self.angle * PLANET_RADIUS
+    }
+}
+
+/// Current camera placement computed by the soak test.
+pub struct SoakCamera {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub bearing: f64,
+    pub altitude: f64,
+}
+
+pub struct Soak {
+    rng: XorShift,
+    route: Route,
+    routes_flown: usize,
+    frames: u64,
+
+    duration: Duration,
+    start: Instant,
+    last_snapshot: Instant,
+
+    /// Smallest backlog seen during the current snapshot window.
+    window_backlog: usize,
+    /// Smallest backlog seen during each completed snapshot window.
+    backlog_history: Vec<usize>,
+}
+impl Soak {
+    pub fn new(seed: u64, duration: Duration) -> Self {
+        let mut rng = XorShift::new(seed);
+        let route = Route::random(&mut rng);
+        println!("Soak test with seed {} for {:?}", seed, duration);
+        Self {
+            rng,
+            route,
+            routes_flown: 0,
+            frames: 0,
+            duration,
+            start: Instant::now(),
+            last_snapshot: Instant::now(),
+            window_backlog: usize::MAX,
+            backlog_history: Vec::new(),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.start.elapsed() >= self.duration
+    }
+
+    /// Move along the current route by one time step, starting a new route if needed.
+    pub fn advance(&mut self) -> SoakCamera {
+        if self.route.is_finished() {
+            self.route = Route::random(&mut self.rng);
+            self.routes_flown += 1;
+        }
+
+        // Match the speed of keyboard navigation in the preview.
+        let speed = 12.0 * self.route.altitude.clamp(2.0, 100000.0);
+        self.route.traveled += speed * TIME_STEP;
+        self.frames += 1;
+
+        let total = self.route.angle * PLANET_RADIUS;
+        let t = if total > 0.0 { (self.route.traveled / total).min(1.0) } else { 1.0 };
+        let p = self.route.point(t);
+        let latitude = p[2].asin();
+        let longitude = p[1].atan2(p[0]);
+
+        // Initial bearing of the great circle from the current point toward the destination.
+        let end_latitude = self.route.end[2].asin();
+        let end_longitude = self.route.end[1].atan2(self.route.end[0]);
+        let delta = end_longitude - longitude;
+        let bearing = (delta.sin() * end_latitude.cos()).atan2(
+            latitude.cos() * end_latitude.sin() - latitude.sin() * end_latitude.cos() * delta.cos(),
+        );
+
+        SoakCamera {
+            latitude: latitude.to_degrees(),
+            longitude: longitude.to_degrees(),
+            bearing: bearing.to_degrees(),
+            altitude: self.route.altitude,
+        }
+    }
+
+    /// Check invariants against the latest statistics, and periodically log a snapshot.
+    pub fn check(&mut self, statistics: &terra::Statistics, resources: &[terra::ResourceUsage]) {
+        for (level, (occupancy, capacity)) in
+            statistics.level_occupancy.iter().zip(&statistics.level_capacity).enumerate()
+        {
+            assert!(
+                occupancy <= capacity,
+                "Level {} holds {} nodes but only has {} slots",
+                level,
+                occupancy,
+                capacity
+            );
+        }
+        assert!(statistics.streaming_inflight <= MAX_STREAMING_INFLIGHT);
+        assert!(statistics.heightmap_downloads_inflight <= MAX_HEIGHTMAP_DOWNLOADS);
+
+        self.window_backlog = self.window_backlog.min(statistics.pending_tiles);
+        if self.last_snapshot.elapsed() < SNAPSHOT_INTERVAL {
+            return;
+        }
+        self.last_snapshot = Instant::now();
+        self.backlog_history.push(self.window_backlog);
+        self.window_backlog = usize::MAX;
+
+        let resource_bytes: u64 = resources.iter().map(|r| r.bytes).sum();
+        println!(
+            "[{:>8.1}s] frames={} routes={} altitude={:.0}m occupancy={} pending={} \
+             inflight={} downloads={} gpu_memory={}MiB",
+            self.start.elapsed().as_secs_f64(),
+            self.frames,
+            self.routes_flown,
+            self.route.altitude,
+            statistics.level_occupancy.iter().sum::<usize>(),
+            statistics.pending_tiles,
+            statistics.streaming_inflight,
+            statistics.heightmap_downloads_inflight,
+            resource_bytes / (1024 * 1024),
+        );
+
+        // Even while flying, the backlog should drain down to a steady level every so often. If
+        // its minimum keeps rising, streaming isn't keeping up and will eventually fall over.
+        if self.backlog_history.len() > MAX_GROWING_WINDOWS {
+            let recent =
+                &self.backlog_history[self.backlog_history.len() - MAX_GROWING_WINDOWS - 1..];
+            assert!(
+                !recent.windows(2).all(|w| w[1] > w[0]),
+                "Streaming backlog grew for {} consecutive windows: {:?}",
+                MAX_GROWING_WINDOWS,
+                recent
+            );
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! On-screen overlay showing frame timings and the state of the tile cache.
+
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+/// Number of recent frames that frame time percentiles are computed over.
+const FRAME_WINDOW: usize = 240;
+
+/// How often the text of the overlay is refreshed, so that it stays readable.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Each pixel of the font is drawn as a square this many pixels across.
+const SCALE: u32 = 2;
+/// Distance of the overlay from the top left corner of the window, in pixels.
+const MARGIN: u32 = 8;
+/// Space around the text inside the overlay's background, in font pixels.
+const PADDING: u32 = 3;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Horizontal and vertical distance between neighboring characters, in font pixels.
+const ADVANCE: (u32, u32) = (GLYPH_WIDTH + 1, GLYPH_HEIGHT + 3);
+
+/// Rows of a 5x7 pixel glyph from top to bottom, with the leftmost pixel in bit 4. Characters
+/// without a glyph are drawn as spaces.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0; 7],
+    }
+}
+
+/// Draw `lines` in white over a translucent black background, returning the width, height and
+/// sRGB pixels of the image.
+fn rasterize(lines: &[String]) -> (u32, u32, Vec<u8>) {
+    let columns = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) as u32;
+    let width = columns * ADVANCE.0 - 1 + 2 * PADDING;
+    let height = lines.len() as u32 * ADVANCE.1 - 3 + 2 * PADDING;
+    let mut pixels = [0, 0, 0, 160].repeat((width * height) as usize);
+    for (row, line) in lines.iter().enumerate() {
+        for (column, c) in line.chars().enumerate() {
+            let x0 = PADDING + column as u32 * ADVANCE.0;
+            let y0 = PADDING + row as u32 * ADVANCE.1;
+            for (y, bits) in glyph(c).into_iter().enumerate() {
+                for x in 0..GLYPH_WIDTH {
+                    if bits & (0x10 >> x) != 0 {
+                        let i = ((y0 + y as u32) * width + x0 + x) as usize * 4;
+                        pixels[i..][..4].copy_from_slice(&[255; 4]);
+                    }
+                }
+            }
+        }
+    }
+    (width, height, pixels)
+}
+
+/// Tracks frame times and tile cache totals, and formats them for the overlay.
+#[derive(Default)]
+pub struct FrameStats {
+    frame_times: VecDeque<f64>,
+    /// Time of the last refresh, along with the number of frames and tile totals at that point.
+    last_refresh: Option<(Instant, u64, u64, u64)>,
+    frames: u64,
+    lines: Vec<String>,
+}
+impl FrameStats {
+    /// Record a frame that took `dt` seconds. Returns whether the text changed.
+    pub fn record_frame(&mut self, dt: f64, statistics: &terra::Statistics) -> bool {
+        if self.frame_times.len() == FRAME_WINDOW {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(dt);
+        self.frames += 1;
+
+        let now = Instant::now();
+        let (streamed, generated) = (statistics.tiles_streamed, statistics.tiles_generated);
+        let (last_time, last_frames, last_streamed, last_generated) = match self.last_refresh {
+            Some(last) if now - last.0 < REFRESH_INTERVAL => return false,
+            Some(last) => last,
+            None => {
+                self.last_refresh = Some((now, self.frames, streamed, generated));
+                return false;
+            }
+        };
+        self.last_refresh = Some((now, self.frames, streamed, generated));
+
+        let elapsed = (now - last_time).as_secs_f64();
+        let mut sorted: Vec<f64> = self.frame_times.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize] * 1000.0;
+        let occupied: usize = statistics.level_occupancy.iter().sum();
+        let capacity: usize = statistics.level_capacity.iter().sum();
+
+        self.lines = vec![
+            format!(
+                "FPS {:.1}  FRAME {:.2} MS",
+                (self.frames - last_frames) as f64 / elapsed,
+                elapsed * 1000.0 / (self.frames - last_frames) as f64
+            ),
+            format!(
+                "P50 {:.2}  P95 {:.2}  P99 {:.2} MS",
+                percentile(0.5),
+                percentile(0.95),
+                percentile(0.99)
+            ),
+            format!(
+                "STREAMED {:.0}/S  GENERATED {:.0}/S",
+                (streamed - last_streamed) as f64 / elapsed,
+                (generated - last_generated) as f64 / elapsed
+            ),
+            format!(
+                "CACHE {}/{} ({:.0}%)",
+                occupied,
+                capacity,
+                occupied as f64 * 100.0 / capacity.max(1) as f64
+            ),
+            format!(
+                "IN FLIGHT {}  PENDING {}",
+                statistics.streaming_inflight, statistics.pending_tiles
+            ),
+        ];
+        true
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+const SHADER: &str = r"
+@group(0) @binding(0) var overlay: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<f32>(textureDimensions(overlay));
+    return textureLoad(overlay, vec2<i32>(in.uv * size), 0);
+}
+";
+
+/// Draws the text from `FrameStats` over the top left corner of the frame.
+pub struct StatsOverlay {
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    /// Texture holding the rasterized text, along with its size.
+    texture: Option<(wgpu::Texture, wgpu::BindGroup, (u32, u32))>,
+}
+impl StatsOverlay {
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shader.stats_overlay"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("layout.stats_overlay"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("pipeline_layout.stats_overlay"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("pipeline.stats_overlay"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState { module: &module, entry_point: "vs_main", buffers: &[] },
+            fragment: Some(wgpu::FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: Default::default(),
+            depth_stencil: None,
+            multisample: Default::default(),
+            multiview: None,
+        });
+        Self { bind_group_layout, pipeline, texture: None }
+    }
+
+    /// Rasterize new text into the overlay's texture.
+    pub fn set_text(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, lines: &[String]) {
+        if lines.is_empty() {
+            self.texture = None;
+            return;
+        }
+        let (width, height, pixels) = rasterize(lines);
+        let size = wgpu::Extent3d { width, height, depth_or_array_layers: 1 };
+        if self.texture.as_ref().map(|t| t.2) != Some((width, height)) {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("texture.stats_overlay"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("bindgroup.stats_overlay"),
+                layout: &self.bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &texture.create_view(&Default::default()),
+                    ),
+                }],
+            });
+            self.texture = Some((texture, bind_group, (width, height)));
+        }
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture.as_ref().unwrap().0,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(width * 4),
+                rows_per_image: None,
+            },
+            size,
+        );
+    }
+
+    /// Draw the overlay into `frame`, which is `frame_size` pixels. Does nothing if the overlay
+    /// doesn't fit.
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        frame: &wgpu::TextureView,
+        frame_size: (u32, u32),
+    ) {
+        let (bind_group, (width, height)) = match &self.texture {
+            Some((_, bind_group, size)) => (bind_group, *size),
+            None => return,
+        };
+        if MARGIN + width * SCALE > frame_size.0 || MARGIN + height * SCALE > frame_size.1 {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.stats_overlay"),
+        });
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("renderpass.stats_overlay"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: frame,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: true },
+                })],
+                depth_stencil_attachment: None,
+            });
+            rpass.set_viewport(
+                MARGIN as f32,
+                MARGIN as f32,
+                (width * SCALE) as f32,
+                (height * SCALE) as f32,
+                0.0,
+                1.0,
+            );
+            rpass.set_pipeline(&self.pipeline);
+            rpass.set_bind_group(0, bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
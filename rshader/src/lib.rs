@@ -1,14 +1,17 @@
 use anyhow::anyhow;
+use instant::Instant;
 use naga::{
     AddressSpace, ImageClass, ImageDimension, ScalarKind, StorageAccess, StorageFormat, TypeInner,
     WithSpan,
 };
+#[cfg(feature = "dynamic_shaders")]
 use notify::{self, RecommendedWatcher, RecursiveMode, Watcher};
 use std::collections::HashMap;
 use std::collections::{btree_map::Entry, BTreeMap};
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use std::time::Instant;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+#[cfg(feature = "dynamic_shaders")]
+use std::{path::Path, sync::Mutex};
 
 pub enum ShaderSource {
     Inline {
@@ -28,8 +31,13 @@ pub enum ShaderSource {
         path: PathBuf,
         header_paths: HashMap<&'static str, PathBuf>,
     },
+    InlineWGSL {
+        name: &'static str,
+        contents: String,
+    },
 }
 impl ShaderSource {
+    #[cfg(feature = "dynamic_shaders")]
     pub fn new(
         directory: PathBuf,
         name: &'static str,
@@ -43,6 +51,7 @@ impl ShaderSource {
         }
         ShaderSource::Files { name, path, header_paths, defines }
     }
+    #[cfg(feature = "dynamic_shaders")]
     pub fn new_wgsl(
         directory: PathBuf,
         name: &'static str,
@@ -79,9 +88,12 @@ impl ShaderSource {
                 file.push_str(&std::fs::read_to_string(path)?);
                 (name, file, HashMap::new(), None)
             }
+            ShaderSource::InlineWGSL { name, contents } => {
+                (name, contents.clone(), HashMap::new(), None)
+            }
         };
 
-        if let ShaderSource::FilesWGSL { name, .. } = self {
+        if let ShaderSource::FilesWGSL { .. } | ShaderSource::InlineWGSL { .. } = self {
             match naga::front::wgsl::parse_str(&contents) {
                 Err(e) => {
                     e.emit_to_stderr_with_path(&contents, name);
@@ -147,7 +159,8 @@ impl ShaderSource {
     }
     pub(crate) fn needs_update(&self, last_update: Instant) -> bool {
         match self {
-            ShaderSource::Inline { .. } => false,
+            ShaderSource::Inline { .. } | ShaderSource::InlineWGSL { .. } => false,
+            #[cfg(feature = "dynamic_shaders")]
             ShaderSource::Files { path, header_paths, .. }
             | ShaderSource::FilesWGSL { path, header_paths, .. } => {
                 let directory_watcher = DIRECTORY_WATCHER.lock().unwrap();
@@ -157,6 +170,9 @@ impl ShaderSource {
                     .filter_map(|f| directory_watcher.last_modifications.get(f))
                     .any(|&t| t > last_update)
             }
+            // Without a watcher, changes to files are never noticed.
+            #[cfg(not(feature = "dynamic_shaders"))]
+            ShaderSource::Files { .. } | ShaderSource::FilesWGSL { .. } => false,
         }
     }
 }
@@ -207,10 +223,12 @@ impl ShaderSetInner {
     }
 }
 
+#[cfg(feature = "dynamic_shaders")]
 pub(crate) struct DirectoryWatcher {
     watcher: RecommendedWatcher,
     last_modifications: HashMap<PathBuf, Instant>,
 }
+#[cfg(feature = "dynamic_shaders")]
 impl DirectoryWatcher {
     pub fn new() -> Self {
         let watcher = notify::recommended_watcher(|event: Result<notify::Event, _>| {
@@ -343,6 +361,7 @@ impl ShaderSet {
     }
 }
 
+#[cfg(feature = "dynamic_shaders")]
 lazy_static::lazy_static! {
     static ref DIRECTORY_WATCHER: Mutex<DirectoryWatcher> = Mutex::new(DirectoryWatcher::new());
 }
@@ -386,6 +405,21 @@ macro_rules! shader_source {
 }
 
 #[macro_export]
+#[cfg(not(feature = "dynamic_shaders"))]
+macro_rules! wgsl_source {
+    ($directory:literal, $filename:literal $(, $header:literal )* ) => {
+        $crate::ShaderSource::InlineWGSL {
+            name: $filename,
+            contents: concat!(
+                $( include_str!(concat!($directory, "/", $header)), )*
+                include_str!(concat!($directory, "/", $filename))
+            ).to_string(),
+        }
+    };
+}
+
+#[macro_export]
+#[cfg(feature = "dynamic_shaders")]
 macro_rules! wgsl_source {
     ($directory:literal, $filename:literal $(, $header:literal )* ) => {
 		{
@@ -405,8 +439,10 @@ fn reflect_naga(
     (Vec<wgpu::VertexAttribute>, Vec<Option<String>>, Vec<wgpu::BindGroupLayoutEntry>, [u32; 3]),
     anyhow::Error,
 > {
-    let mut binding_map: BTreeMap<u32, (Option<String>, wgpu::BindingType, wgpu::ShaderStages)> =
-        BTreeMap::new();
+    let mut binding_map: BTreeMap<
+        u32,
+        (Option<String>, wgpu::BindingType, wgpu::ShaderStages, Option<NonZeroU32>),
+    > = BTreeMap::new();
 
     // let mut attribute_offset = 0;
     // let mut attributes = Vec::new();
@@ -420,7 +456,7 @@ fn reflect_naga(
 
         let _module_info = naga::valid::Validator::new(
             naga::valid::ValidationFlags::all(),
-            naga::valid::Capabilities::FLOAT64 | naga::valid::Capabilities::PUSH_CONSTANT | naga::valid::Capabilities::STORAGE_TEXTURE_16BIT_NORM_FORMATS, /*naga::valid::Capabilities::empty()*/
+            naga::valid::Capabilities::FLOAT64 | naga::valid::Capabilities::PUSH_CONSTANT | naga::valid::Capabilities::STORAGE_TEXTURE_16BIT_NORM_FORMATS | naga::valid::Capabilities::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING, /*naga::valid::Capabilities::empty()*/
         )
         .validate(&module)?;
 
@@ -452,6 +488,20 @@ fn reflect_naga(
                 }
             }
 
+            // Binding arrays are reflected as their element type, with the length as the count.
+            let (ty, count) = match *ty {
+                TypeInner::BindingArray { base, size } => match size {
+                    naga::ArraySize::Constant(c) => (
+                        &module.types[base].inner,
+                        module.constants[c].to_array_length().and_then(NonZeroU32::new),
+                    ),
+                    naga::ArraySize::Dynamic => {
+                        return Err(anyhow!("binding arrays must have a fixed length"))
+                    }
+                },
+                _ => (ty, None),
+            };
+
             let ty = match ty {
                 TypeInner::Sampler { comparison } => wgpu::BindingType::Sampler(if *comparison {
                     wgpu::SamplerBindingType::Comparison
@@ -533,10 +583,10 @@ fn reflect_naga(
 
             match binding_map.entry(binding) {
                 Entry::Vacant(v) => {
-                    v.insert((name, ty, stage));
+                    v.insert((name, ty, stage, count));
                 }
                 Entry::Occupied(mut e) => {
-                    let (ref n, ref t, ref mut s) = e.get_mut();
+                    let (ref n, ref t, ref mut s, c) = e.get_mut();
                     *s = *s | stage;
 
                     if *n != name {
@@ -546,7 +596,7 @@ fn reflect_naga(
                             name.unwrap_or("<unamed>".to_string())
                         ));
                     }
-                    if *t != ty {
+                    if *t != ty || *c != count {
                         return Err(anyhow!(
                             "descriptor mismatch for {}: {:?} vs {:?}",
                             n.as_ref().unwrap_or(&"<unamed>".to_string()),
@@ -561,9 +611,9 @@ fn reflect_naga(
 
     let mut names = Vec::new();
     let mut bindings = Vec::new();
-    for (binding, (name, ty, visibility)) in binding_map.into_iter() {
+    for (binding, (name, ty, visibility, count)) in binding_map.into_iter() {
         names.push(name);
-        bindings.push(wgpu::BindGroupLayoutEntry { binding, visibility, ty, count: None });
+        bindings.push(wgpu::BindGroupLayoutEntry { binding, visibility, ty, count });
     }
 
     Ok((Vec::new(), names, bindings, workgroup_size.unwrap()))
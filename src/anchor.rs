@@ -0,0 +1,142 @@
+//! Points on the terrain surface that follow refinements of the heightmaps.
+
+use crate::cache::TileCache;
+use crate::overlay::ellipsoid_point;
+use crate::weather::local_frame;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use terra_types::VNode;
+
+/// Handle to an anchor returned by `Terrain::anchor`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AnchorId(u64);
+
+/// Where an anchored object should currently be placed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AnchorTransform {
+    /// Maps a local frame with x pointing east, y north and z up to ECEF coordinates, with the
+    /// origin on the terrain surface.
+    pub transform: mint::ColumnMatrix4<f64>,
+    /// Quadtree level of the heightmap the anchor was placed with, or `None` if no heightmap
+    /// covering it was loaded and it sits at sea level.
+    pub level: Option<u8>,
+    /// Incremented every time the anchor moves, so objects can be updated only when needed.
+    pub revision: u64,
+}
+
+struct Anchor {
+    id: AnchorId,
+    latitude: f64,
+    longitude: f64,
+    height: Option<(u8, f32)>,
+    current: AnchorTransform,
+}
+impl Anchor {
+    /// Place the anchor on the most detailed heightmap in the cache, returning whether it moved.
+    fn snap(&mut self, cache: &TileCache) -> bool {
+        let (latitude, longitude) = (self.latitude.to_radians(), self.longitude.to_radians());
+        let cspace = Vector3::new(
+            latitude.cos() * longitude.cos(),
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        );
+        let height = (0..=VNode::LEVEL_CELL_1M)
+            .rev()
+            .find_map(|level| cache.get_height_cspace(cspace, level).map(|h| (level, h)));
+        if height == self.height {
+            return false;
+        }
+
+        self.height = height;
+        let p = ellipsoid_point((self.latitude, self.longitude));
+        self.current = AnchorTransform {
+            transform: transform(p, height.map_or(0.0, |h| h.1 as f64)),
+            level: height.map(|h| h.0),
+            revision: self.current.revision + 1,
+        };
+        true
+    }
+}
+
+/// Transform for an object `height` meters above the point on the ellipsoid `p`.
+fn transform(p: Vector3<f64>, height: f64) -> mint::ColumnMatrix4<f64> {
+    let (east, north, up) = local_frame(p);
+    let position = p + p.normalize() * height;
+    Matrix4::from_cols(east.extend(0.0), north.extend(0.0), up.extend(0.0), position.extend(1.0))
+        .into()
+}
+
+/// Anchors created with `Terrain::anchor`.
+pub(crate) struct Anchors {
+    anchors: Vec<Anchor>,
+    next_id: u64,
+    /// Heightmap generation that the anchors were last placed with.
+    generation: u64,
+    /// Anchors that moved since the last call to `take_moved`.
+    moved: Vec<AnchorId>,
+}
+impl Anchors {
+    pub fn new() -> Self {
+        Self { anchors: Vec::new(), next_id: 0, generation: 0, moved: Vec::new() }
+    }
+
+    pub fn add(&mut self, latitude: f64, longitude: f64, cache: &TileCache) -> AnchorId {
+        let id = AnchorId(self.next_id);
+        self.next_id += 1;
+
+        let p = ellipsoid_point((latitude, longitude));
+        let mut anchor = Anchor {
+            id,
+            latitude,
+            longitude,
+            height: None,
+            current: AnchorTransform { transform: transform(p, 0.0), level: None, revision: 0 },
+        };
+        anchor.snap(cache);
+        self.anchors.push(anchor);
+        id
+    }
+
+    pub fn remove(&mut self, id: AnchorId) -> bool {
+        let len = self.anchors.len();
+        self.anchors.retain(|anchor| anchor.id != id);
+        self.moved.retain(|&moved| moved != id);
+        self.anchors.len() != len
+    }
+
+    pub fn get(&self, id: AnchorId) -> Option<AnchorTransform> {
+        self.anchors.iter().find(|anchor| anchor.id == id).map(|anchor| anchor.current)
+    }
+
+    /// Place every anchor again if heights in the cache may have changed.
+    pub fn update(&mut self, cache: &TileCache) {
+        let generation = cache.heightmap_generation();
+        if generation == self.generation {
+            return;
+        }
+        self.generation = generation;
+        for anchor in &mut self.anchors {
+            if anchor.snap(cache) && !self.moved.contains(&anchor.id) {
+                self.moved.push(anchor.id);
+            }
+        }
+    }
+
+    pub fn take_moved(&mut self) -> Vec<AnchorId> {
+        std::mem::take(&mut self.moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_is_placed_on_the_surface() {
+        let p = ellipsoid_point((45.0, 10.0));
+        let m: Matrix4<f64> = transform(p, 100.0).into();
+        let up = m.z.truncate();
+        assert!((up - p.normalize()).magnitude() < 1e-9);
+        assert!((m.x.truncate().cross(m.y.truncate()) - up).magnitude() < 1e-9);
+        assert!((m.w.truncate() - (p + up * 100.0)).magnitude() < 1e-6);
+    }
+}
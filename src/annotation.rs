@@ -0,0 +1,507 @@
+//! Screen facing labels and icons anchored to points on the planet.
+
+use crate::cache::TileCache;
+use crate::gpu_state::GpuState;
+use crate::overlay::ellipsoid_point;
+use crate::resources::{texture_bytes, ResourceKind, Tracked};
+use anyhow::Error;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use maplit::hashmap;
+use std::num::NonZeroU32;
+use terra_types::VNode;
+
+/// Width and height of the texture that annotation images are packed into.
+const ATLAS_SIZE: u32 = 2048;
+/// Largest width or height of an annotation image.
+pub const MAX_ANNOTATION_SIZE: u32 = 512;
+/// Space left between images in the atlas so that filtering doesn't bleed between them.
+const ATLAS_PADDING: u32 = 1;
+
+/// A label or icon drawn facing the screen at a fixed size in pixels.
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    /// Latitude of the anchor point in degrees.
+    pub latitude: f64,
+    /// Longitude of the anchor point in degrees.
+    pub longitude: f64,
+    /// Height of the anchor point in meters above the terrain.
+    pub height: f32,
+    /// Image to draw, in sRGB with straight alpha. Text must be rasterized by the application.
+    /// Neither side may be larger than `MAX_ANNOTATION_SIZE`.
+    pub image: image::RgbaImage,
+    /// Point in the image that is placed over the anchor, as a fraction of its size measured from
+    /// the top left corner.
+    pub anchor: [f32; 2],
+    /// When annotations overlap on screen, only the one with the highest priority is drawn.
+    /// Ties go to the closest.
+    pub priority: i32,
+    /// Hide the annotation while terrain blocks the line of sight to its anchor.
+    pub occluded_by_terrain: bool,
+    /// Hide the annotation beyond this distance from the camera in meters.
+    pub max_distance: f64,
+}
+impl Annotation {
+    /// Annotation centered over the terrain at the given latitude and longitude in degrees.
+    pub fn new(latitude: f64, longitude: f64, image: image::RgbaImage) -> Self {
+        Self {
+            latitude,
+            longitude,
+            height: 0.0,
+            image,
+            anchor: [0.5, 0.5],
+            priority: 0,
+            occluded_by_terrain: true,
+            max_distance: f64::INFINITY,
+        }
+    }
+}
+
+/// Handle to an annotation returned by `Terrain::add_annotation`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AnnotationId(u64);
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct AnnotationInstance {
+    /// Top left and bottom right corners in normalized device coordinates.
+    rect: [f32; 4],
+    /// Texture coordinates of the top left and bottom right corners.
+    texcoords: [f32; 4],
+}
+unsafe impl bytemuck::Pod for AnnotationInstance {}
+unsafe impl bytemuck::Zeroable for AnnotationInstance {}
+
+/// Annotation that is on screen before decluttering.
+struct Candidate {
+    index: usize,
+    /// Left, top, right and bottom edges in pixels.
+    rect: [f32; 4],
+    priority: i32,
+    distance: f64,
+}
+
+/// Pack rectangles of the given sizes into a square of side `size` using rows of decreasing
+/// height, returning the top left corner of each or `None` for any that didn't fit.
+fn pack(sizes: &[(u32, u32)], size: u32) -> Vec<Option<(u32, u32)>> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut positions = vec![None; sizes.len()];
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for i in order {
+        let (w, h) = (sizes[i].0 + ATLAS_PADDING, sizes[i].1 + ATLAS_PADDING);
+        if x + w > size {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+        if x + w > size || y + h > size {
+            continue;
+        }
+        positions[i] = Some((x, y));
+        x += w;
+        row_height = row_height.max(h);
+    }
+    positions
+}
+
+/// Choose which candidates to draw so that none overlap, returning them from highest to lowest
+/// priority.
+fn declutter(mut candidates: Vec<Candidate>) -> Vec<Candidate> {
+    candidates.sort_by(|a, b| {
+        b.priority.cmp(&a.priority).then(a.distance.partial_cmp(&b.distance).unwrap())
+    });
+
+    let mut accepted: Vec<Candidate> = Vec::new();
+    for candidate in candidates {
+        let [left, top, right, bottom] = candidate.rect;
+        if accepted.iter().all(|a| {
+            left >= a.rect[2] || right <= a.rect[0] || top >= a.rect[3] || bottom <= a.rect[1]
+        }) {
+            accepted.push(candidate);
+        }
+    }
+    accepted
+}
+
+struct Entry {
+    id: AnnotationId,
+    annotation: Annotation,
+    /// Position of the anchor in ECEF coordinates, if it has been computed.
+    position: Option<Vector3<f64>>,
+    /// Top left corner of the image in the atlas, if it fit.
+    atlas_position: Option<(u32, u32)>,
+}
+
+/// Labels and icons drawn over the tonemapped frame.
+pub(crate) struct Annotations {
+    entries: Vec<Entry>,
+    next_id: u64,
+    /// Whether the atlas needs to be packed again.
+    atlas_dirty: bool,
+    /// Heightmap generation that anchor positions were computed with.
+    positions_generation: u64,
+    /// Annotations drawn in the most recent frame.
+    visible: Vec<AnnotationId>,
+
+    atlas: Option<(Tracked<wgpu::Texture>, wgpu::TextureView)>,
+    instances: Option<Tracked<wgpu::Buffer>>,
+    num_instances: u32,
+
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    output_format: Option<wgpu::TextureFormat>,
+}
+impl Annotations {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            next_id: 0,
+            atlas_dirty: false,
+            positions_generation: 0,
+            visible: Vec::new(),
+            atlas: None,
+            instances: None,
+            num_instances: 0,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "annotation.vert"),
+                rshader::shader_source!("shaders", "annotation.frag"),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            output_format: None,
+        }
+    }
+
+    pub fn add(&mut self, annotation: Annotation) -> Result<AnnotationId, Error> {
+        let (width, height) = annotation.image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(anyhow::format_err!("Annotation image must not be empty"));
+        }
+        if width > MAX_ANNOTATION_SIZE || height > MAX_ANNOTATION_SIZE {
+            return Err(anyhow::format_err!(
+                "Annotation images may be at most {}x{} pixels",
+                MAX_ANNOTATION_SIZE,
+                MAX_ANNOTATION_SIZE
+            ));
+        }
+        if !(-90.0..=90.0).contains(&annotation.latitude) {
+            return Err(anyhow::format_err!("Invalid annotation latitude"));
+        }
+
+        let id = AnnotationId(self.next_id);
+        self.next_id += 1;
+        self.entries.push(Entry { id, annotation, position: None, atlas_position: None });
+        self.atlas_dirty = true;
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: AnnotationId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        self.visible.retain(|&v| v != id);
+        self.atlas_dirty |= self.entries.len() != len;
+        self.entries.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.visible.clear();
+        self.atlas_dirty = true;
+    }
+
+    pub fn visible(&self) -> &[AnnotationId] {
+        &self.visible
+    }
+
+    /// Pack every image into the atlas and upload them.
+    fn update_atlas(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, gpu_state: &GpuState) {
+        if self.atlas.is_none() {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width: ATLAS_SIZE,
+                    height: ATLAS_SIZE,
+                    depth_or_array_layers: 1,
+                },
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                label: Some("texture.annotation_atlas"),
+                view_formats: &[],
+            });
+            let token = gpu_state.resources.track(
+                ResourceKind::Texture,
+                "annotation_atlas",
+                texture_bytes(&texture),
+            );
+            let view = texture.create_view(&Default::default());
+            self.atlas = Some((Tracked::new(texture, token), view));
+            self.bindgroup_pipeline = None;
+        }
+
+        let sizes: Vec<_> = self.entries.iter().map(|e| e.annotation.image.dimensions()).collect();
+        let positions = pack(&sizes, ATLAS_SIZE);
+        if positions.iter().any(Option::is_none) {
+            log::warn!("Annotation atlas is full, so some annotations won't be drawn");
+        }
+        for (entry, position) in self.entries.iter_mut().zip(positions) {
+            entry.atlas_position = position;
+            if let Some((x, y)) = position {
+                let image = &entry.annotation.image;
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: &self.atlas.as_ref().unwrap().0,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d { x, y, z: 0 },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    image,
+                    wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(NonZeroU32::new(image.width() * 4).unwrap()),
+                        rows_per_image: None,
+                    },
+                    wgpu::Extent3d {
+                        width: image.width(),
+                        height: image.height(),
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+        self.atlas_dirty = false;
+    }
+
+    /// Decide which annotations are visible from `camera` and draw them over `color_buffer`,
+    /// which must already hold the tonemapped frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        cache: &TileCache,
+        camera: Vector3<f64>,
+        view_proj: Matrix4<f32>,
+        frame_size: (u32, u32),
+        color_buffer: &wgpu::TextureView,
+        color_format: wgpu::TextureFormat,
+    ) {
+        self.visible.clear();
+        self.num_instances = 0;
+        if self.entries.is_empty() {
+            return;
+        }
+        if self.atlas_dirty {
+            self.update_atlas(device, queue, gpu_state);
+        }
+
+        // Anchors follow the terrain as more detailed heightmaps arrive.
+        let generation = cache.heightmap_generation();
+        for entry in &mut self.entries {
+            if entry.position.is_none() || generation != self.positions_generation {
+                let a = &entry.annotation;
+                let p = ellipsoid_point((a.latitude, a.longitude));
+                let surface = cache.terrain_point(p, VNode::LEVEL_CELL_1M).unwrap_or(p);
+                entry.position = Some(surface + surface.normalize() * a.height as f64);
+            }
+        }
+        self.positions_generation = generation;
+
+        let (width, height) = (frame_size.0 as f32, frame_size.1 as f32);
+        let view_proj: Matrix4<f64> = view_proj.cast().unwrap();
+        let mut candidates = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let (position, (w, h)) = match (entry.position, entry.atlas_position) {
+                (Some(p), Some(_)) => (p, entry.annotation.image.dimensions()),
+                _ => continue,
+            };
+            let distance = (position - camera).magnitude();
+            if distance > entry.annotation.max_distance {
+                continue;
+            }
+
+            let clip = view_proj * (position - camera).extend(1.0);
+            if clip.w <= 0.0 {
+                continue;
+            }
+            let (x, y) = ((clip.x / clip.w) as f32, (clip.y / clip.w) as f32);
+            let (x, y) = ((x * 0.5 + 0.5) * width, (0.5 - y * 0.5) * height);
+
+            // Snap to whole pixels so that text stays sharp.
+            let [ax, ay] = entry.annotation.anchor;
+            let left = (x - ax * w as f32).round();
+            let top = (y - ay * h as f32).round();
+            let rect = [left, top, left + w as f32, top + h as f32];
+            if rect[2] <= 0.0 || rect[0] >= width || rect[3] <= 0.0 || rect[1] >= height {
+                continue;
+            }
+            candidates.push(Candidate {
+                index,
+                rect,
+                priority: entry.annotation.priority,
+                distance,
+            });
+        }
+
+        // Occlusion is only tested for annotations that would otherwise be drawn, since each
+        // test casts a ray against the terrain.
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|c| {
+                let entry = &self.entries[c.index];
+                !entry.annotation.occluded_by_terrain
+                    || cache.line_of_sight(camera, entry.position.unwrap())
+            })
+            .collect();
+
+        let mut instances = Vec::new();
+        for candidate in declutter(candidates) {
+            let entry = &self.entries[candidate.index];
+            let (ax, ay) = entry.atlas_position.unwrap();
+            let (w, h) = entry.annotation.image.dimensions();
+            let [left, top, right, bottom] = candidate.rect;
+            instances.push(AnnotationInstance {
+                rect: [
+                    left / width * 2.0 - 1.0,
+                    1.0 - top / height * 2.0,
+                    right / width * 2.0 - 1.0,
+                    1.0 - bottom / height * 2.0,
+                ],
+                texcoords: [
+                    ax as f32 / ATLAS_SIZE as f32,
+                    ay as f32 / ATLAS_SIZE as f32,
+                    (ax + w) as f32 / ATLAS_SIZE as f32,
+                    (ay + h) as f32 / ATLAS_SIZE as f32,
+                ],
+            });
+            self.visible.push(entry.id);
+        }
+        if instances.is_empty() {
+            return;
+        }
+
+        let bytes: &[u8] = bytemuck::cast_slice(&instances);
+        if self.instances.as_ref().map(|b| b.size() < bytes.len() as u64).unwrap_or(true) {
+            let size = (bytes.len() as u64).next_power_of_two().max(1024);
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+                label: Some("buffer.annotation.instances"),
+            });
+            let token =
+                gpu_state.resources.track(ResourceKind::Buffer, "annotation.instances", size);
+            self.instances = Some(Tracked::new(buffer, token));
+            self.bindgroup_pipeline = None;
+        }
+        queue.write_buffer(self.instances.as_ref().unwrap(), 0, bytes);
+        self.num_instances = instances.len() as u32;
+
+        if self.output_format != Some(color_format) {
+            self.output_format = Some(color_format);
+            self.bindgroup_pipeline = None;
+        }
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                hashmap!["annotation_instances".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: self.instances.as_ref().unwrap(),
+                    offset: 0,
+                    size: None,
+                }))],
+                hashmap!["annotation_atlas".into() => &self.atlas.as_ref().unwrap().1],
+                "annotation",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.annotation.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.annotation.vertex"),
+                            source: self.shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.annotation.fragment"),
+                            source: self.shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: color_format,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            write_mask: wgpu::ColorWrites::COLOR,
+                        })],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: None,
+                    multisample: Default::default(),
+                    multiview: None,
+                    label: Some("pipeline.annotation"),
+                }),
+            ));
+        }
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color_buffer,
+                resolve_target: None,
+                ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: true },
+            })],
+            depth_stencil_attachment: None,
+            label: Some("renderpass.annotation"),
+        });
+        let (bind_group, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..self.num_instances * 6, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_rows_by_height() {
+        let positions = pack(&[(100, 10), (100, 20), (1000, 5)], 256);
+        assert_eq!(positions[1], Some((0, 0)));
+        assert_eq!(positions[0], Some((101, 0)));
+        assert_eq!(positions[2], None);
+    }
+
+    #[test]
+    fn declutter_keeps_priority_then_distance() {
+        let candidate = |index, left, priority, distance| Candidate {
+            index,
+            rect: [left, 0.0, left + 10.0, 10.0],
+            priority,
+            distance,
+        };
+        let accepted = declutter(vec![
+            candidate(0, 0.0, 0, 10.0),
+            candidate(1, 5.0, 1, 100.0),
+            candidate(2, 20.0, 0, 50.0),
+            candidate(3, 16.0, 0, 20.0),
+        ]);
+        let indices: Vec<_> = accepted.iter().map(|c| c.index).collect();
+        assert_eq!(indices, vec![1, 3]);
+    }
+}
@@ -23,6 +23,22 @@
 //!    OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //!    DEALINGS IN THE SOFTWARE.
 
+use cgmath::Vector3;
+
+/// Julian day of the Unix epoch, 1970 January 1 at 0h UTC.
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2440587.5;
+
+/// Computes the Julian day for a point in time, suitable for passing to `Terrain::update`.
+///
+/// Leap seconds are ignored, which shifts the result by under a minute.
+pub fn julian_day(time: std::time::SystemTime) -> f64 {
+    let seconds = match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    UNIX_EPOCH_JULIAN_DAY + seconds / 86400.0
+}
+
 /// Computes Julian century for a Julian day
 ///
 /// # Arguments
@@ -143,3 +159,126 @@ pub(crate) fn dec_frm_gal(gal_long: f64, gal_lat: f64) -> f64 {
         + gal_lat.cos() * 27.4_f64.to_radians().cos() * (gal_long - 123_f64.to_radians()).cos())
     .asin()
 }
+
+/// Computes the mean obliquity of the ecliptic, using the same low precision formula as the
+/// solar position below
+///
+/// # Returns
+///
+/// * `oblq_eclip`: Mean obliquity of the ecliptic *| in radians*
+///
+/// # Arguments
+///
+/// * `JD`: Julian day
+fn mn_oblq(jd: f64) -> f64 {
+    (23.439 - 0.0000004 * (jd - 2451545.0)).to_radians()
+}
+
+/// Converts equatorial coordinates to a unit vector in the Earth-fixed frame
+///
+/// # Arguments
+///
+/// * `asc`: Right ascension *| in radians*
+/// * `dec`: Declination *| in radians*
+/// * `sidr`: Sidereal time *| in radians*
+fn earth_fixed(asc: f64, dec: f64, sidr: f64) -> Vector3<f64> {
+    Vector3::new(dec.cos() * (asc - sidr).cos(), dec.cos() * (asc - sidr).sin(), dec.sin())
+}
+
+/// Computes the direction of the Sun in the Earth-fixed frame, accurate to about 0.01 degrees
+///
+/// # Arguments
+///
+/// * `JD`: Julian day
+pub(crate) fn sun_direction(jd: f64) -> Vector3<f64> {
+    let n = jd - 2451545.0;
+    let l: f64 = (280.460 + 0.9856474 * n).to_radians();
+    let g: f64 = (357.528 + 0.9856003 * n).to_radians();
+    let oblq_eclip = mn_oblq(jd);
+    let lambda = l + (1.915 * g.sin() + 0.02 * (2.0 * g).sin()).to_radians();
+    let declination = dec_frm_ecl(lambda, 0.0, oblq_eclip);
+    let ascension = asc_frm_ecl(lambda, 0.0, oblq_eclip);
+    earth_fixed(ascension, declination, mn_sidr(jd))
+}
+
+/// Computes the geocentric ecliptic position of the Moon, using the largest periodic terms from
+/// chapter 47 of Meeus' *Astronomical Algorithms*. Accurate to about 0.1 degrees.
+///
+/// # Returns
+///
+/// `(moon_ecl_long, moon_ecl_lat, dist_to_earth)`
+///
+/// * `moon_ecl_long`: Ecliptic longitude of the Moon *| in radians*
+/// * `moon_ecl_lat`: Ecliptic latitude of the Moon *| in radians*
+/// * `dist_to_earth`: Distance between the centers of the Earth and the Moon *| in kilometers*
+///
+/// # Arguments
+///
+/// * `JD`: Julian day
+pub(crate) fn moon_geocent_ecl_pos(jd: f64) -> (f64, f64, f64) {
+    let t = julian_cent(jd);
+    let mn_long = 218.3164477 + 481267.88123421 * t;
+    let d = (297.8501921 + 445267.1114034 * t).to_radians();
+    let m = (357.5291092 + 35999.0502909 * t).to_radians();
+    let m1 = (134.9633964 + 477198.8675055 * t).to_radians();
+    let f = (93.2720950 + 483202.0175233 * t).to_radians();
+
+    let long = mn_long
+        + 6.288774 * m1.sin()
+        + 1.274027 * (2.0 * d - m1).sin()
+        + 0.658314 * (2.0 * d).sin()
+        + 0.213618 * (2.0 * m1).sin()
+        - 0.185116 * m.sin()
+        - 0.114332 * (2.0 * f).sin();
+    let lat = 5.128122 * f.sin()
+        + 0.280602 * (m1 + f).sin()
+        + 0.277693 * (m1 - f).sin()
+        + 0.173237 * (2.0 * d - f).sin();
+    let dist = 385000.56
+        - 20905.355 * m1.cos()
+        - 3699.111 * (2.0 * d - m1).cos()
+        - 2955.968 * (2.0 * d).cos()
+        - 569.925 * (2.0 * m1).cos();
+
+    (limit_to_360(long).to_radians(), lat.to_radians(), dist)
+}
+
+/// Computes the position of the Moon's center in the Earth-fixed frame
+///
+/// # Returns
+///
+/// * `position`: Position of the Moon *| in meters*
+///
+/// # Arguments
+///
+/// * `JD`: Julian day
+pub(crate) fn moon_position(jd: f64) -> Vector3<f64> {
+    let (long, lat, dist) = moon_geocent_ecl_pos(jd);
+    let oblq_eclip = mn_oblq(jd);
+    let declination = dec_frm_ecl(long, lat, oblq_eclip);
+    let ascension = asc_frm_ecl(long, lat, oblq_eclip);
+    earth_fixed(ascension, declination, mn_sidr(jd)) * dist * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_julian_day() {
+        use std::time::{Duration, UNIX_EPOCH};
+        assert_eq!(julian_day(UNIX_EPOCH), 2440587.5);
+        // J2000.0 is 2000 January 1 at 12h.
+        assert_eq!(julian_day(UNIX_EPOCH + Duration::from_secs(946728000)), 2451545.0);
+        assert_eq!(julian_day(UNIX_EPOCH - Duration::from_secs(43200)), 2440587.0);
+    }
+
+    #[test]
+    fn test_moon_position() {
+        // Example 47.a from Astronomical Algorithms: 1992 April 12, 0h TD.
+        let (long, lat, dist) = moon_geocent_ecl_pos(2448724.5);
+        assert!((long.to_degrees() - 133.162655).abs() < 0.2);
+        assert!((lat.to_degrees() - -3.229126).abs() < 0.2);
+        assert!((dist - 368409.7).abs() < 300.0);
+    }
+}
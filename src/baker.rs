@@ -0,0 +1,148 @@
+//! Streaming and generating tiles without rendering anything, so that tiles can be baked on
+//! servers and the generators tested on machines without a display.
+
+use crate::export::{self, ExportFormat, ExportLayer, ExportRegion};
+use crate::{Error, MapFileBuilder, Statistics, Terrain, TileEvent, ValidationIssue};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Generates and exports terrain using only a `wgpu::Device` and `wgpu::Queue`, with no surface
+/// or camera.
+///
+/// Rather than loading tiles around a camera, each export loads exactly the tiles covering the
+/// requested region, in batches small enough to fit in the tile cache.
+///
+/// ```no_run
+/// # async fn f(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), anyhow::Error> {
+/// let builder = terra::MapFileBuilder::new(terra::DEFAULT_TILE_SERVER_URL.to_string());
+/// let mut baker = terra::TerrainBaker::new(device, queue, builder).await?;
+/// let region = terra::ExportRegion {
+///     min_latitude: 46.5,
+///     max_latitude: 46.6,
+///     min_longitude: 7.9,
+///     max_longitude: 8.0,
+/// };
+/// baker.export_layer(
+///     device,
+///     queue,
+///     terra::ExportLayer::Heightmap,
+///     region,
+///     12,
+///     terra::ExportFormat::GeoTiff,
+///     "eiger.tif",
+///     |percent| println!("{:.0}%", percent),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TerrainBaker {
+    terrain: Terrain,
+}
+impl TerrainBaker {
+    /// Create a baker using the tile sources and local cache settings from `builder`.
+    pub async fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        builder: MapFileBuilder,
+    ) -> Result<Self, Error> {
+        Ok(Self { terrain: Terrain::with_map_file(device, queue, builder).await? })
+    }
+
+    /// Stream and generate every tile of `layer` covering `region` at `level`, and export them
+    /// like `Terrain::export_layer`. Calls `progress_callback` with the percentage of tiles done
+    /// after each batch.
+    ///
+    /// Blocks until all tiles have been downloaded and generated, so this isn't available on the
+    /// web.
+    #[allow(clippy::too_many_arguments)]
+    pub fn export_layer<P: AsRef<std::path::Path>, F: FnMut(f32)>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layer: ExportLayer,
+        region: ExportRegion,
+        level: u8,
+        format: ExportFormat,
+        path: P,
+        mut progress_callback: F,
+    ) -> Result<(), Error> {
+        if cfg!(target_arch = "wasm32") {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Baking tiles isn't supported on the web"
+            )));
+        }
+        export::export_size(layer, region, level).map_err(Error::InvalidArgument)?;
+
+        let nodes = export::region_nodes(region, level);
+        let batch_size = self.terrain.cache.bake_batch_size();
+        let mut tiles = HashMap::new();
+        for (i, batch) in nodes.chunks(batch_size).enumerate() {
+            loop {
+                self.terrain.profiler.begin_frame(device);
+                let done = self.terrain.cache.update_for_nodes(
+                    device,
+                    queue,
+                    &self.terrain.gpu_state,
+                    batch,
+                    &mut self.terrain.profiler,
+                ) == batch.len();
+                self.terrain.gpu_state.objects.end_frame();
+                if done {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            // Read back the tiles of the batch along with their ancestors, which the export falls
+            // back to wherever finer tiles are missing.
+            for &node in batch {
+                let mut node = Some(node);
+                while let Some(n) = node {
+                    if let Some(tile_layer) = layer.layer_type(n.level()) {
+                        tiles.entry((tile_layer, n)).or_insert_with(|| {
+                            futures::executor::block_on(self.terrain.cache.readback_layer(
+                                device,
+                                queue,
+                                &self.terrain.gpu_state,
+                                tile_layer,
+                                n,
+                            ))
+                        });
+                    }
+                    node = n.parent().map(|(parent, _)| parent);
+                }
+            }
+            progress_callback(((i * batch_size + batch.len()) * 100) as f32 / nodes.len() as f32);
+        }
+
+        export::export_layer(
+            |tile_layer, node| tiles.get(&(tile_layer, node)).cloned().flatten(),
+            layer,
+            region,
+            level,
+            format,
+            path.as_ref(),
+        )
+        .map_err(|e| Error::categorize(e, Error::InvalidArgument))
+    }
+
+    /// See `Terrain::set_validation`. Useful for checking the generators on CI machines.
+    pub fn set_validation(&mut self, enabled: bool) {
+        self.terrain.set_validation(enabled);
+    }
+
+    /// Returns the problems found by validation since the last call.
+    pub fn validation_issues(&mut self) -> Vec<ValidationIssue> {
+        self.terrain.validation_issues()
+    }
+
+    /// See `Terrain::subscribe_tile_events`.
+    pub fn subscribe_tile_events(&mut self) -> std::sync::mpsc::Receiver<TileEvent> {
+        self.terrain.subscribe_tile_events()
+    }
+
+    /// Returns a snapshot of the tile cache and streaming state.
+    pub fn statistics(&self) -> Statistics {
+        self.terrain.cache.statistics()
+    }
+}
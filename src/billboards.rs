@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     io::{Cursor, Read},
     num::NonZeroU32,
+    ops::Range,
 };
 
 use anyhow::Error;
@@ -18,52 +19,94 @@ use crate::{
 const RESOLUTION: u32 = 256;
 const FRAMES_PER_SIDE: u32 = 6;
 
+/// Tree models, indexed by the species stored in the tree attributes layer: broadleaf, conifer and
+/// palm. Species past the end of the list are drawn with the last model, and billboards are only
+/// rendered from the first one.
+const TREE_SPECIES: &[&str] = &["Oak_English_Sapling"];
+
 pub(crate) struct Models {
-    tree: SpeedTreeModel,
+    trees: Vec<SpeedTreeModel>,
     shader: rshader::ShaderSet,
-    albedo_texture: Vec<u8>,
+    albedo_textures: Vec<Vec<u8>>,
 }
 impl Models {
     pub async fn new(mapfile: &MapFile) -> Result<Self, Error> {
-        let file = mapfile.read_asset("Oak_English_Sapling.xml.zip").await?;
-        let mut zip = ZipArchive::new(Cursor::new(file))?;
+        let mut trees = Vec::new();
+        let mut albedo_textures = Vec::new();
+        for name in TREE_SPECIES {
+            let file = mapfile.read_asset(&format!("{}.xml.zip", name)).await?;
+            let mut zip = ZipArchive::new(Cursor::new(file))?;
 
-        let mut contents = String::new();
-        zip.by_name("Oak_English_Sapling.xml")?.read_to_string(&mut contents)?;
+            let mut contents = String::new();
+            zip.by_name(&format!("{}.xml", name))?.read_to_string(&mut contents)?;
 
-        let albedo_texture = mapfile.read_asset("Oak_English_Sapling_Color.ktx2").await?;
+            trees.push(parse_xml(&contents)?);
+            albedo_textures.push(mapfile.read_asset(&format!("{}_Color.ktx2", name)).await?);
+        }
 
-        let tree = parse_xml(&contents).unwrap();
         let shader = rshader::ShaderSet::simple(
             rshader::shader_source!("shaders", "model.vert", "declarations.glsl"),
             rshader::shader_source!("shaders", "model.frag", "declarations.glsl"),
         )
         .unwrap();
 
-        Ok(Self { tree, shader, albedo_texture })
+        Ok(Self { trees, shader, albedo_textures })
+    }
+
+    /// Number of tree species that models were loaded for.
+    pub fn species(&self) -> usize {
+        self.trees.len()
     }
 
     pub fn make_buffers(&self, device: &wgpu::Device) -> (wgpu::Buffer, wgpu::Buffer) {
+        self.make_species_buffers(device, 0)
+    }
+
+    /// Vertex and index buffers for the model of a single species.
+    pub fn make_species_buffers(
+        &self,
+        device: &wgpu::Device,
+        species: usize,
+    ) -> (wgpu::Buffer, wgpu::Buffer) {
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("buffer.tree.vertex"),
-            contents: bytemuck::cast_slice(&self.tree.vertices),
+            label: Some(&format!("buffer.tree.{}.vertex", species)),
+            contents: bytemuck::cast_slice(&self.trees[species].vertices),
             usage: wgpu::BufferUsages::STORAGE,
         });
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("buffer.tree.index"),
-            contents: bytemuck::cast_slice(&self.tree.indices),
+            label: Some(&format!("buffer.tree.{}.index", species)),
+            contents: bytemuck::cast_slice(&self.trees[species].indices),
             usage: wgpu::BufferUsages::INDEX,
         });
 
         (vertex_buffer, index_buffer)
     }
 
+    /// Range of the index buffer holding the most detailed LOD of a species.
+    pub fn species_lod(&self, species: usize) -> Range<u32> {
+        self.trees[species].lods[0].clone()
+    }
+
     pub fn make_models_albedo(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
     ) -> Result<wgpu::Texture, Error> {
-        texture_from_ktx2_bytes(device, queue, &self.albedo_texture, "model_albedo")
+        self.make_species_albedo(device, queue, 0)
+    }
+
+    pub fn make_species_albedo(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        species: usize,
+    ) -> Result<wgpu::Texture, Error> {
+        texture_from_ktx2_bytes(
+            device,
+            queue,
+            &self.albedo_textures[species],
+            &format!("model_albedo.{}", species),
+        )
     }
 
     fn default_billboard_desc() -> wgpu::TextureDescriptor<'static> {
@@ -319,7 +362,7 @@ impl Models {
                                 2.0 * y as f32 / FRAMES_PER_SIDE as f32 - 1.0,
                             ]),
                         );
-                        rpass.draw_indexed(self.tree.lods.last().unwrap().clone(), 0, 0..1);
+                        rpass.draw_indexed(self.trees[0].lods.last().unwrap().clone(), 0, 0..1);
                     }
                 }
             }
@@ -394,7 +437,7 @@ impl Models {
                     0,
                     bytemuck::cast_slice(&[0.0f32, 0.0f32]),
                 );
-                rpass.draw_indexed(self.tree.lods.last().unwrap().clone(), 0, 0..1);
+                rpass.draw_indexed(self.trees[0].lods.last().unwrap().clone(), 0, 0..1);
             }
         }
 
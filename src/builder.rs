@@ -0,0 +1,256 @@
+//! Options for constructing a `Terrain`, checked before any GPU resources are created.
+
+use crate::cache::region::{InsetRegion, RegionOfInterest};
+use crate::cache::{
+    AerialPerspectiveQuality, DetailLayer, DetailLimits, TerrainQuality, SLOTS_PER_LEVEL,
+};
+use crate::flat::FlatMap;
+use crate::procedural::ProceduralPlanet;
+use crate::{Error, MapFileBuilder, Terrain};
+use std::sync::Arc;
+use terra_types::MAX_QUADTREE_LEVEL;
+
+/// Largest number of tile cache slots for each level of the quadtree, which is also the default.
+/// See `TerrainBuilder::tile_cache_slots`.
+pub const MAX_TILE_CACHE_SLOTS: usize = SLOTS_PER_LEVEL;
+
+/// Fewest tile cache slots for each level of the quadtree. Fewer than this can't hold the nodes
+/// around the camera, and would cause them to be evicted and reloaded every frame.
+const MIN_TILE_CACHE_SLOTS: usize = 8;
+
+/// Configures and creates a `Terrain`.
+///
+/// Tile sources and the local cache directory come from the `MapFileBuilder`, while the builder
+/// adds detail limits and other settings that are fixed once the terrain is created or that are
+/// convenient to apply from the start.
+///
+/// ```no_run
+/// # async fn f(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), terra::Error> {
+/// let map_file = terra::MapFileBuilder::new(terra::DEFAULT_TILE_SERVER_URL.to_string());
+/// let terrain = terra::TerrainBuilder::new(map_file)
+///     .quality(terra::TerrainQuality::Medium)
+///     .grass(false)
+///     .tile_cache_slots(16)
+///     .build(device, queue)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TerrainBuilder {
+    pub(crate) map_file: MapFileBuilder,
+    pub(crate) quality: Option<TerrainQuality>,
+    pub(crate) disabled_layers: Vec<DetailLayer>,
+    pub(crate) detail_limits: DetailLimits,
+    pub(crate) aerial_perspective_quality: Option<AerialPerspectiveQuality>,
+    pub(crate) region: Option<RegionOfInterest>,
+    pub(crate) insets: Vec<InsetRegion>,
+    pub(crate) flat_map: Option<Arc<FlatMap>>,
+    pub(crate) procedural_planet: Option<ProceduralPlanet>,
+    pub(crate) atmosphere: bool,
+    pub(crate) tile_cache_slots: usize,
+    pub(crate) sample_count: u32,
+}
+impl TerrainBuilder {
+    /// Start from the tile sources and local cache settings in `map_file`.
+    pub fn new(map_file: MapFileBuilder) -> Self {
+        Self {
+            map_file,
+            quality: None,
+            disabled_layers: Vec::new(),
+            detail_limits: DetailLimits::default(),
+            aerial_perspective_quality: None,
+            region: None,
+            insets: Vec::new(),
+            flat_map: None,
+            procedural_planet: None,
+            atmosphere: true,
+            tile_cache_slots: MAX_TILE_CACHE_SLOTS,
+            sample_count: 1,
+        }
+    }
+
+    /// Apply a quality preset from the start, like `Terrain::set_quality`. Unless
+    /// `aerial_perspective_quality` is also set, the aerial perspective textures are allocated at
+    /// the resolution of the preset.
+    pub fn quality(mut self, quality: TerrainQuality) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
+    /// Whether to generate and draw grass. Enabled by default.
+    pub fn grass(self, enabled: bool) -> Self {
+        self.layer(DetailLayer::Grass, enabled)
+    }
+
+    /// Whether to generate and draw trees. Enabled by default.
+    pub fn trees(self, enabled: bool) -> Self {
+        self.layer(DetailLayer::Trees, enabled)
+    }
+
+    /// Whether to generate and draw scattered rocks, boulders and bushes. Enabled by default.
+    pub fn rocks(self, enabled: bool) -> Self {
+        self.layer(DetailLayer::Rocks, enabled)
+    }
+
+    /// Deepest quadtree level to load tiles for. Each level halves the size of the smallest
+    /// rendered features, starting from roughly 20 km at level 0. Lowering this improves
+    /// performance at the expense of ground-level detail. Defaults to level 22, the deepest level
+    /// that tiles are generated for, and `build` rejects anything deeper. Quality presets may
+    /// lower this further, and inset regions may exceed it.
+    pub fn max_level(mut self, level: u8) -> Self {
+        self.detail_limits.max_level = level;
+        self
+    }
+
+    /// Deepest quadtree level to load tiles of `layer` for, in addition to the overall limit
+    /// from `max_level`.
+    pub fn layer_max_level(mut self, layer: DetailLayer, level: u8) -> Self {
+        self.detail_limits.layer_max_levels.retain(|&(l, _)| l != layer);
+        self.detail_limits.layer_max_levels.push((layer, level));
+        self
+    }
+
+    /// Resolution of the aerial perspective textures, which can't be changed once the terrain is
+    /// created. Quality presets only change how often the aerial perspective is updated. Defaults
+    /// to the resolution of the `quality` preset, or `AerialPerspectiveQuality::Medium` without
+    /// one.
+    pub fn aerial_perspective_quality(mut self, quality: AerialPerspectiveQuality) -> Self {
+        self.aerial_perspective_quality = Some(quality);
+        self
+    }
+
+    /// Only load detailed tiles inside of `region`. Everything outside of it is limited to a
+    /// coarse base level and faded out when rendering.
+    pub fn region_of_interest(mut self, region: RegionOfInterest) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Load extra detail inside of `inset`. May be called multiple times to add several insets.
+    /// Insets can also be changed later with `Terrain::set_inset_regions`.
+    pub fn inset_region(mut self, inset: InsetRegion) -> Self {
+        self.insets.push(inset);
+        self
+    }
+
+    /// Render `map` without curvature in place of the terrain it covers. See `FlatMap`.
+    pub fn flat_map(mut self, map: FlatMap) -> Self {
+        self.flat_map = Some(Arc::new(map));
+        self
+    }
+
+    /// Generate every tile from `planet` instead of downloading them. Servers are still used for
+    /// assets like tree models and ground textures. See `ProceduralPlanet`.
+    pub fn procedural_planet(mut self, planet: ProceduralPlanet) -> Self {
+        self.procedural_planet = Some(planet);
+        self
+    }
+
+    /// Whether to simulate the atmosphere. Without it the sky is black and distant terrain isn't
+    /// hazed, as on bodies with no atmosphere. Enabled by default.
+    pub fn atmosphere(mut self, enabled: bool) -> Self {
+        self.atmosphere = enabled;
+        self
+    }
+
+    /// Number of tile cache slots for each level of the quadtree from level 2 down, between 8
+    /// and `MAX_TILE_CACHE_SLOTS`. Fewer slots use less GPU memory but load less detail around
+    /// the camera. Quality presets may lower this further.
+    pub fn tile_cache_slots(mut self, slots: usize) -> Self {
+        self.tile_cache_slots = slots;
+        self
+    }
+
+    /// Maximum number of bytes of downloaded tiles and assets to keep on disk. See
+    /// `MapFileBuilder::max_disk_usage`.
+    pub fn max_disk_usage(mut self, bytes: u64) -> Self {
+        self.map_file = self.map_file.max_disk_usage(bytes);
+        self
+    }
+
+    /// Number of samples per pixel used for MSAA, like `Terrain::set_sample_count`.
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    /// Check the options, then download the tile lists and create the terrain.
+    pub async fn build(self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Terrain, Error> {
+        self.validate()?;
+        Terrain::from_builder(device, queue, self).await
+    }
+
+    fn layer(mut self, layer: DetailLayer, enabled: bool) -> Self {
+        self.disabled_layers.retain(|&l| l != layer);
+        if !enabled {
+            self.disabled_layers.push(layer);
+        }
+        self
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !(MIN_TILE_CACHE_SLOTS..=MAX_TILE_CACHE_SLOTS).contains(&self.tile_cache_slots) {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Tile cache slots must be between {} and {}, got {}",
+                MIN_TILE_CACHE_SLOTS,
+                MAX_TILE_CACHE_SLOTS,
+                self.tile_cache_slots
+            )));
+        }
+        // Tiles don't exist below the deepest level, and nodes are packed into the shaders with
+        // room for no more levels than that.
+        let levels = std::iter::once(self.detail_limits.max_level)
+            .chain(self.detail_limits.layer_max_levels.iter().map(|&(_, level)| level));
+        if let Some(level) = levels.filter(|&level| level > MAX_QUADTREE_LEVEL).max() {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Maximum level must be at most {}, got {}",
+                MAX_QUADTREE_LEVEL,
+                level
+            )));
+        }
+        if ![1, 2, 4, 8].contains(&self.sample_count) {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Unsupported sample count: {}",
+                self.sample_count
+            )));
+        }
+        Ok(())
+    }
+}
+impl From<MapFileBuilder> for TerrainBuilder {
+    fn from(map_file: MapFileBuilder) -> Self {
+        Self::new(map_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_validated() {
+        let builder = || TerrainBuilder::new(MapFileBuilder::new("file:///tiles/".to_owned()));
+        assert!(builder().validate().is_ok());
+        assert!(builder().quality(TerrainQuality::Low).tile_cache_slots(8).validate().is_ok());
+        assert!(matches!(
+            builder().tile_cache_slots(MAX_TILE_CACHE_SLOTS + 1).validate(),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(builder().tile_cache_slots(2).validate().is_err());
+        assert!(builder().sample_count(3).validate().is_err());
+        assert!(builder().max_level(MAX_QUADTREE_LEVEL).validate().is_ok());
+        assert!(builder().max_level(MAX_QUADTREE_LEVEL + 1).validate().is_err());
+        assert!(builder()
+            .max_level(12)
+            .layer_max_level(DetailLayer::Grass, MAX_QUADTREE_LEVEL + 1)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn layer_toggles_replace_earlier_ones() {
+        let map_file = MapFileBuilder::new("file:///tiles/".to_owned());
+        let builder = TerrainBuilder::new(map_file).grass(false).trees(false).trees(true);
+        assert_eq!(builder.disabled_layers, vec![DetailLayer::Grass]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Block compression of generated tiles.
+//!
+//! Layers with a compressed format are generated into small uncompressed staging arrays, one
+//! batch of tiles at a time. A compute shader then encodes each 4x4 block of the staging array into
+//! a buffer, from which the blocks are copied into the tile cache. On devices without support for
+//! BC compression the staging arrays are copied into the tile cache as is.
+
+use crate::cache::{layer::LayerType, Levels};
+use crate::compute_shader::ComputeShader;
+use crate::gpu_state::GpuState;
+use std::num::NonZeroU32;
+use terra_types::VNode;
+use vec_map::VecMap;
+
+/// Number of tiles that the staging arrays hold, and so the most tiles compressed at once.
+pub(crate) const STAGING_LAYERS: u32 = 8;
+
+/// Size of a compressed block in bytes. Both formats in use have 16 byte blocks.
+const BLOCK_BYTES: u32 = 16;
+
+/// Bytes between rows of blocks in `GpuState::tile_compression`, padded for copies to textures.
+/// Must match `block_offset` in bc.glsl.
+pub(crate) fn block_row_pitch(resolution: u32) -> u32 {
+    let row_bytes = (resolution + 3) / 4 * BLOCK_BYTES;
+    let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    (row_bytes + alignment - 1) / alignment * alignment
+}
+
+/// Size of the buffer that compressed blocks are written to.
+pub(crate) fn tile_compression_buffer_size(resolution: u32) -> u64 {
+    block_row_pitch(resolution) as u64 * ((resolution + 3) / 4) as u64 * STAGING_LAYERS as u64
+}
+
+pub(crate) struct TileCompressor {
+    shaders: VecMap<ComputeShader<()>>,
+}
+impl TileCompressor {
+    pub fn new() -> Self {
+        let mut shaders = VecMap::new();
+        shaders.insert(
+            LayerType::AlbedoRoughness.index(),
+            ComputeShader::new(
+                rshader::shader_source!("../shaders", "compress-bc3.comp", "bc.glsl"),
+                "compress.albedo".to_owned(),
+            ),
+        );
+        shaders.insert(
+            LayerType::Normals.index(),
+            ComputeShader::new(
+                rshader::shader_source!("../shaders", "compress-bc5.comp", "bc.glsl"),
+                "compress.normals".to_owned(),
+            ),
+        );
+        Self { shaders }
+    }
+
+    /// Move the tiles of `layer` for `nodes` from the first layers of its staging array into the
+    /// tile cache, compressing them if the tile cache texture is compressed.
+    pub fn store(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        layer: LayerType,
+        nodes: &[(VNode, usize)],
+    ) {
+        assert!(nodes.len() <= STAGING_LAYERS as usize);
+        let texture = &gpu_state.tile_cache[layer][0].0;
+        let resolution = layer.texture_resolution();
+        let extent =
+            wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 };
+        let destination = |slot: usize| wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d {
+                x: 0,
+                y: 0,
+                z: (slot - Levels::base_slot(layer.min_level())) as u32,
+            },
+            aspect: wgpu::TextureAspect::All,
+        };
+
+        if layer.compressed_format(device.features()).is_none() {
+            for (i, (_, slot)) in nodes.iter().enumerate() {
+                encoder.copy_texture_to_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: &gpu_state.material_staging[layer].0,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d { x: 0, y: 0, z: i as u32 },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    destination(*slot),
+                    extent,
+                );
+            }
+            return;
+        }
+
+        let blocks = (resolution + 3) / 4;
+        let shader = &mut self.shaders[layer];
+        shader.refresh(device, gpu_state);
+        shader.run(
+            device,
+            encoder,
+            gpu_state,
+            ((blocks + 7) / 8, (blocks + 7) / 8, nodes.len() as u32),
+            &(),
+        );
+
+        let row_pitch = block_row_pitch(resolution);
+        for (i, (_, slot)) in nodes.iter().enumerate() {
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer: &gpu_state.tile_compression,
+                    layout: wgpu::ImageDataLayout {
+                        offset: i as u64 * row_pitch as u64 * blocks as u64,
+                        bytes_per_row: NonZeroU32::new(row_pitch),
+                        rows_per_image: None,
+                    },
+                },
+                destination(*slot),
+                extent,
+            );
+        }
+    }
+}
+
+/// Expand the endpoints of a BC4 block into its palette, indexed by the codes stored per texel.
+fn bc4_palette(a0: u8, a1: u8) -> [u8; 8] {
+    let (e0, e1) = (a0 as u32, a1 as u32);
+    let mut palette = [a0, a1, 0, 0, 0, 0, 0, 255];
+    if a0 > a1 {
+        for i in 1..7 {
+            palette[i as usize + 1] = (((7 - i) * e0 + i * e1 + 3) / 7) as u8;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i as usize + 1] = (((5 - i) * e0 + i * e1 + 2) / 5) as u8;
+        }
+    }
+    palette
+}
+
+/// Decode an 8 byte BC4 block into the values of its 16 texels, in row major order.
+fn decode_bc4(block: &[u8]) -> [u8; 16] {
+    let palette = bc4_palette(block[0], block[1]);
+    let mut bits = [0u8; 8];
+    bits[..6].copy_from_slice(&block[2..8]);
+    let bits = u64::from_le_bytes(bits);
+    let mut values = [0; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = palette[((bits >> (3 * i)) & 7) as usize];
+    }
+    values
+}
+
+/// Decode the 8 byte color half of a BC3 block into the colors of its 16 texels. Unlike plain BC1,
+/// the block is always in four color mode.
+fn decode_bc3_color(block: &[u8]) -> [[u8; 3]; 16] {
+    let endpoint = |c: u16| {
+        let (r, g, b) = ((c >> 11) as u32, ((c >> 5) & 63) as u32, (c & 31) as u32);
+        [(r * 255 + 15) / 31, (g * 255 + 31) / 63, (b * 255 + 15) / 31]
+    };
+    let c0 = endpoint(u16::from_le_bytes([block[0], block[1]]));
+    let c1 = endpoint(u16::from_le_bytes([block[2], block[3]]));
+    let mix = |w0: u32, w1: u32| [0, 1, 2].map(|c| ((w0 * c0[c] + w1 * c1[c] + 1) / 3) as u8);
+    let palette = [mix(3, 0), mix(0, 3), mix(2, 1), mix(1, 2)];
+
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let mut colors = [[0; 3]; 16];
+    for (i, color) in colors.iter_mut().enumerate() {
+        *color = palette[((indices >> (2 * i)) & 3) as usize];
+    }
+    colors
+}
+
+/// Decode a tile of BC3 or BC5 blocks, with rows of blocks `row_pitch` bytes apart, into tightly
+/// packed RGBA8 or RG8 texels respectively.
+pub(crate) fn decode_blocks(
+    format: wgpu::TextureFormat,
+    blocks: &[u8],
+    resolution: usize,
+    row_pitch: usize,
+) -> Vec<u8> {
+    let channels = match format {
+        wgpu::TextureFormat::Bc3RgbaUnorm => 4,
+        wgpu::TextureFormat::Bc5RgUnorm => 2,
+        _ => unreachable!("unsupported compressed format {:?}", format),
+    };
+
+    let mut data = vec![0; resolution * resolution * channels];
+    for by in 0..(resolution + 3) / 4 {
+        for bx in 0..(resolution + 3) / 4 {
+            let block = &blocks[by * row_pitch + bx * BLOCK_BYTES as usize..][..16];
+            let mut texels = [[0u8; 4]; 16];
+            if channels == 4 {
+                let alpha = decode_bc4(&block[..8]);
+                for (i, color) in decode_bc3_color(&block[8..]).iter().enumerate() {
+                    texels[i] = [color[0], color[1], color[2], alpha[i]];
+                }
+            } else {
+                let (red, green) = (decode_bc4(&block[..8]), decode_bc4(&block[8..]));
+                for (i, texel) in texels.iter_mut().enumerate() {
+                    texel[..2].copy_from_slice(&[red[i], green[i]]);
+                }
+            }
+
+            for (i, texel) in texels.iter().enumerate() {
+                let (x, y) = (bx * 4 + i % 4, by * 4 + i / 4);
+                if x < resolution && y < resolution {
+                    let offset = (x + y * resolution) * channels;
+                    data[offset..][..channels].copy_from_slice(&texel[..channels]);
+                }
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_pitch_is_aligned() {
+        assert_eq!(block_row_pitch(516), 2304);
+        assert_eq!(block_row_pitch(64), 256);
+        assert_eq!(block_row_pitch(65) % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, 0);
+    }
+
+    #[test]
+    fn bc4_blocks_decode() {
+        // Eight value mode, with texel i using code i % 8.
+        let mut bits = 0u64;
+        for i in 0..16 {
+            bits |= (i as u64 % 8) << (3 * i);
+        }
+        let mut block = [255, 0, 0, 0, 0, 0, 0, 0];
+        block[2..].copy_from_slice(&bits.to_le_bytes()[..6]);
+        let values = decode_bc4(&block);
+        assert_eq!(values[..8], [255, 0, 219, 182, 146, 109, 73, 36]);
+        assert_eq!(values[..8], values[8..]);
+
+        // Six value mode, with explicit zero and one.
+        assert_eq!(bc4_palette(0, 250), [0, 250, 50, 100, 150, 200, 0, 255]);
+    }
+
+    #[test]
+    fn bc5_tile_decodes() {
+        // A 4x4 tile of a single block, with a constant red channel and a green channel that
+        // alternates between its endpoints.
+        let mut block = [0u8; 16];
+        block[..2].copy_from_slice(&[100, 100]);
+        let mut bits = 0u64;
+        for i in 0..16 {
+            bits |= (i as u64 % 2) << (3 * i);
+        }
+        block[8..10].copy_from_slice(&[200, 10]);
+        block[10..].copy_from_slice(&bits.to_le_bytes()[..6]);
+
+        let data = decode_blocks(wgpu::TextureFormat::Bc5RgUnorm, &block, 4, 16);
+        assert_eq!(data.len(), 32);
+        for (i, texel) in data.chunks_exact(2).enumerate() {
+            assert_eq!(texel, [100, if i % 2 == 0 { 200 } else { 10 }]);
+        }
+    }
+
+    #[test]
+    fn bc3_color_decodes() {
+        // Pure red and pure blue endpoints, with texels using each of the four palette entries.
+        let mut block = [0u8; 8];
+        block[..2].copy_from_slice(&0xf800u16.to_le_bytes());
+        block[2..4].copy_from_slice(&0x001fu16.to_le_bytes());
+        block[4..].copy_from_slice(&0xe4e4e4e4u32.to_le_bytes());
+        let colors = decode_bc3_color(&block);
+        assert_eq!(colors[..4], [[255, 0, 0], [0, 0, 255], [170, 0, 85], [85, 0, 170]]);
+    }
+}
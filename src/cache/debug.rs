@@ -0,0 +1,217 @@
+use crate::cache::layer::MeshType;
+use crate::cache::TileCache;
+use crate::gpu_state::GpuState;
+use crate::postprocess::{TargetConfig, HDR_FORMAT};
+use crate::resources::Tracked;
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+use terra_types::{Priority, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Maximum number of node bounds drawn by the overlay. Any beyond this are skipped.
+pub(crate) const MAX_DEBUG_BOXES: usize = 4096;
+
+/// Size in bytes of the GPU buffer holding the boxes drawn by the overlay.
+pub(crate) const DEBUG_BOXES_BUFFER_SIZE: u64 =
+    (MAX_DEBUG_BOXES * std::mem::size_of::<DebugBox>()) as u64;
+
+/// Same palette as the terrain wireframe, so the two overlays can be compared.
+const LEVEL_COLORS: [[f32; 3]; 8] = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [0.0, 1.0, 1.0],
+    [1.0, 1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [1.0, 0.5, 0.0],
+];
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DebugBox {
+    /// Corners relative to the camera, with the bottom four first.
+    corners: [[f32; 4]; 8],
+    color: [f32; 4],
+}
+unsafe impl bytemuck::Pod for DebugBox {}
+unsafe impl bytemuck::Zeroable for DebugBox {}
+
+/// Draws the bounds of the nodes selected for rendering as wireframe boxes. Boxes are colored
+/// by level, dimmed while the node is still missing tiles, and drawn translucent if the node is
+/// only partially visible because some of its children are drawn instead.
+pub(crate) struct BoundsOverlay {
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    num_boxes: u32,
+    target_config: TargetConfig,
+}
+impl BoundsOverlay {
+    pub fn new() -> Self {
+        Self {
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("../shaders", "debug-bounds.vert", "declarations.glsl"),
+                rshader::shader_source!("../shaders", "debug-bounds.frag"),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            num_boxes: 0,
+            target_config: TargetConfig::default(),
+        }
+    }
+
+    fn refresh(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        target_config: TargetConfig,
+    ) {
+        if self.target_config != target_config {
+            self.target_config = target_config;
+            self.bindgroup_pipeline = None;
+        }
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.bindgroup_pipeline.is_some() {
+            return;
+        }
+
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            &self.shader,
+            HashMap::new(),
+            HashMap::new(),
+            "debug_bounds",
+        );
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+                label: Some("pipeline.debug_bounds.layout"),
+            });
+        self.bindgroup_pipeline = Some((
+            bind_group,
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.debug_bounds.vertex"),
+                        source: self.shader.vertex(),
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.debug_bounds.fragment"),
+                        source: self.shader.fragment(),
+                    }),
+                    entry_point: "main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: HDR_FORMAT,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::COLOR,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_write_enabled: false,
+                    depth_compare: self.target_config.depth_compare(true),
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: self.target_config.multisample(),
+                multiview: None,
+                label: Some("pipeline.debug_bounds"),
+            }),
+        ));
+    }
+
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        if let Some((ref bind_group, ref pipeline)) = self.bindgroup_pipeline {
+            if self.num_boxes > 0 {
+                rpass.set_pipeline(pipeline);
+                rpass.set_bind_group(0, bind_group, &[]);
+                rpass.draw(0..self.num_boxes * 24, 0..1);
+            }
+        }
+    }
+}
+
+impl TileCache {
+    /// Rebuild the boxes drawn by the bounds overlay, if it is enabled.
+    pub(super) fn update_bounds_overlay(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        camera: mint::Point3<f64>,
+    ) {
+        if self.bounds_overlay.is_none() {
+            return;
+        }
+
+        let camera = Vector3::new(camera.x, camera.y, camera.z);
+        let mut boxes = Vec::new();
+        for (node, quadrants) in
+            self.compute_visible(MeshType::Terrain.bit_mask(), Priority::cutoff(), Some(0))
+        {
+            if boxes.len() == MAX_DEBUG_BOXES {
+                break;
+            }
+
+            let ((min, max), _, _) = self.bounds(node, None);
+            let mut corners = [[0.0; 4]; 8];
+            for (i, corner) in corners.iter_mut().enumerate() {
+                let cspace = node.grid_position_cspace((i & 1) as i32, ((i >> 1) & 1) as i32, 0, 2);
+                let d = cspace.normalize();
+                let surface = Vector3::new(
+                    d.x * PLANET_SEMIMAJOR_AXIS,
+                    d.y * PLANET_SEMIMAJOR_AXIS,
+                    d.z * PLANET_SEMIMINOR_AXIS,
+                );
+                let height = if i < 4 { min } else { max } as f64;
+                let p = surface + surface.normalize() * height - camera;
+                *corner = [p.x as f32, p.y as f32, p.z as f32, 1.0];
+            }
+
+            let mask = self.level_masks[node.level() as usize];
+            let resident = self.levels.contains_layers(node, mask);
+            let color = LEVEL_COLORS[node.level() as usize % 8];
+            let brightness = if resident { 1.0 } else { 0.4 };
+            boxes.push(DebugBox {
+                corners,
+                color: [
+                    color[0] * brightness,
+                    color[1] * brightness,
+                    color[2] * brightness,
+                    if quadrants == 15 { 1.0 } else { 0.5 },
+                ],
+            });
+        }
+
+        if !boxes.is_empty() {
+            queue.write_buffer(&gpu_state.debug_boxes, 0, bytemuck::cast_slice(&boxes));
+        }
+        let overlay = self.bounds_overlay.as_mut().unwrap();
+        overlay.refresh(device, gpu_state, self.target_config);
+        overlay.num_boxes = boxes.len() as u32;
+    }
+
+    /// Enable or disable drawing the bounds of the nodes selected for rendering.
+    pub fn set_bounds_overlay(&mut self, enabled: bool) {
+        if enabled != self.bounds_overlay.is_some() {
+            self.bounds_overlay = enabled.then(BoundsOverlay::new);
+        }
+    }
+
+    pub fn render_bounds_overlay<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        if let Some(overlay) = &self.bounds_overlay {
+            overlay.render(rpass);
+        }
+    }
+}
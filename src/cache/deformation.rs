@@ -0,0 +1,198 @@
+use crate::Error;
+use cgmath::{InnerSpace, Vector3};
+use terra_types::{VNode, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Maximum number of deformations that can be active at once.
+pub const MAX_DEFORMATIONS: usize = 1024;
+
+/// Change applied to the terrain height within a deformation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DeformationKind {
+    /// Raise the terrain by the given number of meters, or lower it if negative. Useful for
+    /// craters and excavation.
+    Offset(f32),
+    /// Move the terrain to the given height above sea level, such as for building pads.
+    Flatten(f32),
+}
+
+/// Circular modification of the terrain height made at runtime. Deformations are applied in the
+/// order they were added, on top of the streamed and generated heights.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Deformation {
+    /// Center of the deformation in ECEF coordinates. Only its direction from the center of the
+    /// planet matters.
+    pub center: mint::Point3<f64>,
+    /// Distance in meters from the center beyond which the terrain is unaffected.
+    pub radius: f32,
+    /// Width in meters of the band at the edge of the deformation over which it blends into the
+    /// surrounding terrain.
+    pub falloff: f32,
+    pub kind: DeformationKind,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GpuDeformation {
+    center: [f32; 3],
+    radius: f32,
+    falloff: f32,
+    amount: f32,
+    kind: u32,
+    _padding: u32,
+}
+unsafe impl bytemuck::Pod for GpuDeformation {}
+unsafe impl bytemuck::Zeroable for GpuDeformation {}
+
+/// Size in bytes of the GPU buffer holding all deformations.
+pub(crate) const DEFORMATIONS_BUFFER_SIZE: u64 =
+    16 + (MAX_DEFORMATIONS * std::mem::size_of::<GpuDeformation>()) as u64;
+
+/// Direction from the center of the planet in the space where the ellipsoid is a unit sphere.
+/// The displacements shader measures distances the same way, so that the CPU and GPU agree.
+fn direction(position: Vector3<f64>) -> Vector3<f64> {
+    Vector3::new(
+        position.x / PLANET_SEMIMAJOR_AXIS,
+        position.y / PLANET_SEMIMAJOR_AXIS,
+        position.z / PLANET_SEMIMINOR_AXIS,
+    )
+    .normalize()
+}
+
+/// The set of active deformations.
+#[derive(Default)]
+pub(crate) struct Deformations {
+    deformations: Vec<(Deformation, Vector3<f64>)>,
+}
+impl Deformations {
+    pub fn push(&mut self, deformation: Deformation) -> Result<(), Error> {
+        if self.deformations.len() >= MAX_DEFORMATIONS {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "At most {} deformations may be active at once",
+                MAX_DEFORMATIONS
+            )));
+        }
+        let center = deformation.center;
+        let center = direction(Vector3::new(center.x, center.y, center.z));
+        self.deformations.push((deformation, center));
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.deformations.clear();
+    }
+
+    /// Height of the terrain in the direction of `cspace` after deforming `height`.
+    pub fn apply(&self, cspace: Vector3<f64>, height: f32) -> f32 {
+        if self.deformations.is_empty() {
+            return height;
+        }
+        let d = cspace.normalize();
+        let mut height = height;
+        for (deformation, center) in &self.deformations {
+            let distance = ((d - center).magnitude() * PLANET_SEMIMAJOR_AXIS) as f32;
+            let weight = 1.0
+                - smoothstep(
+                    deformation.radius - deformation.falloff,
+                    deformation.radius,
+                    distance,
+                );
+            height = match deformation.kind {
+                DeformationKind::Offset(amount) => height + amount * weight,
+                DeformationKind::Flatten(target) => height + (target - height) * weight,
+            };
+        }
+        height
+    }
+
+    /// Widen a range of heights to cover any changes the deformations may make to it.
+    pub fn extend_range(&self, range: (f32, f32)) -> (f32, f32) {
+        self.deformations.iter().fold(range, |(min, max), (deformation, _)| {
+            match deformation.kind {
+                DeformationKind::Offset(amount) => (min + amount.min(0.0), max + amount.max(0.0)),
+                DeformationKind::Flatten(target) => (min.min(target), max.max(target)),
+            }
+        })
+    }
+
+    /// Whether `deformation` may change any part of `node`.
+    pub fn overlaps(deformation: &Deformation, node: VNode) -> bool {
+        let (center, radius) = node.bounding_sphere((0.0, 0.0));
+        let c = deformation.center;
+        let d = direction(Vector3::new(c.x, c.y, c.z));
+        let position = Vector3::new(
+            d.x * PLANET_SEMIMAJOR_AXIS,
+            d.y * PLANET_SEMIMAJOR_AXIS,
+            d.z * PLANET_SEMIMINOR_AXIS,
+        );
+        // The bounding sphere only encloses the corners of the node, and deformation radii are
+        // measured slightly differently than distances along the ellipsoid, so pad both.
+        (position - center).magnitude() <= (radius + deformation.radius as f64) * 1.01
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Deformation> {
+        self.deformations.iter().map(|(d, _)| d)
+    }
+
+    /// Contents of the GPU buffer: a count padded to 16 bytes followed by each deformation.
+    pub fn gpu_data(&self) -> Vec<u8> {
+        let mut data = vec![0; 16];
+        data[..4].copy_from_slice(&(self.deformations.len() as u32).to_ne_bytes());
+        for (deformation, center) in &self.deformations {
+            let (kind, amount) = match deformation.kind {
+                DeformationKind::Offset(amount) => (0, amount),
+                DeformationKind::Flatten(target) => (1, target),
+            };
+            data.extend_from_slice(bytemuck::bytes_of(&GpuDeformation {
+                center: [center.x as f32, center.y as f32, center.z as f32],
+                radius: deformation.radius,
+                falloff: deformation.falloff,
+                amount,
+                kind,
+                _padding: 0,
+            }));
+        }
+        data
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x < edge0 { 0.0 } else { 1.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deformations() {
+        let center = Vector3::new(PLANET_SEMIMAJOR_AXIS, 0.0, 0.0);
+        let mut deformations = Deformations::default();
+        deformations
+            .push(Deformation {
+                center: mint::Point3 { x: center.x, y: center.y, z: center.z },
+                radius: 100.0,
+                falloff: 20.0,
+                kind: DeformationKind::Offset(-10.0),
+            })
+            .unwrap();
+        deformations
+            .push(Deformation {
+                center: mint::Point3 { x: center.x, y: 50.0, z: center.z },
+                radius: 10.0,
+                falloff: 0.0,
+                kind: DeformationKind::Flatten(500.0),
+            })
+            .unwrap();
+
+        let at = |y: f64| Vector3::new(1.0, y / PLANET_SEMIMAJOR_AXIS, 0.0);
+        assert!((deformations.apply(at(0.0), 100.0) - 90.0).abs() < 1e-3);
+        assert!((deformations.apply(at(90.0), 100.0) - 95.0).abs() < 1e-2);
+        assert_eq!(deformations.apply(at(150.0), 100.0), 100.0);
+        assert_eq!(deformations.apply(at(50.0), 100.0), 500.0);
+        assert_eq!(deformations.extend_range((100.0, 200.0)), (90.0, 500.0));
+    }
+}
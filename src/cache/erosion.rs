@@ -0,0 +1,155 @@
+use terra_types::VNode;
+
+/// Most simulation steps that a single erosion pass will run.
+pub const MAX_EROSION_ITERATIONS: u32 = 256;
+
+/// Number of heightmap tiles eroded at once, which sizes the simulation state buffer. Must match
+/// `EROSION_BATCH` in erosion.glsl.
+pub(crate) const EROSION_BATCH: usize = 4;
+
+/// Hydraulic erosion applied to heightmaps that are upsampled past the resolution of the source
+/// elevation data. Rain falls on every texel and flows downhill, picking up sediment where the
+/// ground is steep and dropping it where water pools, which carves gullies into slopes and fills
+/// in valley floors instead of only adding fractal noise.
+///
+/// Distances are measured in multiples of the spacing between heightmap texels, so erosion
+/// produces features of the same relative size at every level. Each tile starts from the eroded
+/// heights of its parent when those are resident, so that gullies carry over between levels
+/// rather than being carved anew by each one. The effect fades out towards tile edges so that
+/// neighboring tiles still line up. Heights sampled on the CPU don't include erosion.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Erosion {
+    /// Number of simulation steps, at most `MAX_EROSION_ITERATIONS`. Each one costs two passes
+    /// over every texel of the tile.
+    pub iterations: u32,
+    /// Depth of rain added to every texel each step.
+    pub rainfall: f32,
+    /// Fraction of the water that evaporates each step.
+    pub evaporation: f32,
+    /// Sediment that water can carry, per unit of water depth and slope.
+    pub sediment_capacity: f32,
+    /// Fraction of the unused carrying capacity that is dissolved from the ground each step.
+    pub erosion_rate: f32,
+    /// Fraction of the sediment beyond the carrying capacity that settles each step.
+    pub deposition_rate: f32,
+}
+impl Default for Erosion {
+    fn default() -> Self {
+        Self {
+            iterations: 48,
+            rainfall: 0.002,
+            evaporation: 0.05,
+            sediment_capacity: 0.5,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+        }
+    }
+}
+impl Erosion {
+    pub(crate) fn clamped_iterations(&self) -> u32 {
+        self.iterations.min(MAX_EROSION_ITERATIONS)
+    }
+}
+
+/// Split `nodes` into batches to erode together. A batch never mixes levels, so that a parent is
+/// eroded before any of its children read its heights.
+pub(crate) fn erosion_batches(nodes: &[(VNode, usize)]) -> Vec<&[(VNode, usize)]> {
+    let mut batches = Vec::new();
+    let mut remaining = nodes;
+    while let Some(&(first, _)) = remaining.first() {
+        let len = remaining
+            .iter()
+            .take(EROSION_BATCH)
+            .take_while(|(node, _)| node.level() == first.level())
+            .count();
+        let (batch, rest) = remaining.split_at(len);
+        batches.push(batch);
+        remaining = rest;
+    }
+    batches
+}
+
+/// Stages of the erosion shader, selected by `ErosionUniforms::stage`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ErosionStage {
+    /// Load heights from the heightmap tiles.
+    Load = 0,
+    /// Move water and sediment downhill.
+    Flow = 1,
+    /// Dissolve or deposit sediment, then add rain and evaporate water.
+    Erode = 2,
+    /// Write the eroded heights back to the heightmap tiles. Done by a separate shader, since
+    /// the tiles can't be both read and written by the same one.
+    Store = 3,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ErosionUniforms {
+    /// Tile cache slots of the nodes being eroded. Entries past the end of the batch are unused.
+    slots: [u32; EROSION_BATCH],
+    stage: u32,
+    rainfall: f32,
+    evaporation: f32,
+    sediment_capacity: f32,
+    erosion_rate: f32,
+    deposition_rate: f32,
+    _padding: [u32; 2],
+}
+unsafe impl bytemuck::Pod for ErosionUniforms {}
+unsafe impl bytemuck::Zeroable for ErosionUniforms {}
+impl ErosionUniforms {
+    pub fn new(erosion: &Erosion, stage: ErosionStage, slots: &[usize]) -> Self {
+        assert!(slots.len() <= EROSION_BATCH);
+        let mut uniforms = Self {
+            slots: [0; EROSION_BATCH],
+            stage: stage as u32,
+            rainfall: erosion.rainfall.max(0.0),
+            evaporation: erosion.evaporation.clamp(0.0, 1.0),
+            sediment_capacity: erosion.sediment_capacity.max(0.0),
+            erosion_rate: erosion.erosion_rate.clamp(0.0, 1.0),
+            deposition_rate: erosion.deposition_rate.clamp(0.0, 1.0),
+            _padding: [0; 2],
+        };
+        for (dst, &slot) in uniforms.slots.iter_mut().zip(slots) {
+            *dst = slot as u32;
+        }
+        uniforms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniforms_are_clamped() {
+        let erosion = Erosion {
+            iterations: 1000,
+            evaporation: 2.0,
+            erosion_rate: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(erosion.clamped_iterations(), MAX_EROSION_ITERATIONS);
+
+        let uniforms = ErosionUniforms::new(&erosion, ErosionStage::Flow, &[7, 9]);
+        assert_eq!(uniforms.slots, [7, 9, 0, 0]);
+        assert_eq!(uniforms.stage, 1);
+        assert_eq!(uniforms.evaporation, 1.0);
+        assert_eq!(uniforms.erosion_rate, 0.0);
+        assert_eq!(std::mem::size_of::<ErosionUniforms>() % 16, 0);
+    }
+
+    #[test]
+    fn batches_hold_one_level() {
+        let parent = VNode::roots()[0].children()[0];
+        let mut nodes = vec![(parent, 0)];
+        nodes.extend(parent.children().iter().enumerate().map(|(i, &c)| (c, i + 1)));
+        nodes.extend(parent.children()[0].children().iter().map(|&c| (c, 5)));
+
+        let lengths: Vec<_> = erosion_batches(&nodes).iter().map(|b| b.len()).collect();
+        assert_eq!(lengths, [1, 4, 4]);
+        assert!(erosion_batches(&[]).is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::cache::validation::ValidationIssue;
+use std::sync::mpsc;
+use terra_types::VNode;
+
+/// Change in the residency of a node in the tile cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NodeEventKind {
+    /// All tiles for the node have been streamed or generated, so it can now be drawn.
+    Loaded,
+    /// The node was removed from the cache to make room for others. Only nodes that were
+    /// previously reported as loaded are reported when evicted.
+    Evicted,
+}
+
+/// Notification that a node was loaded or evicted, so that applications can spawn and despawn
+/// their own content in lockstep with the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeEvent {
+    pub kind: NodeEventKind,
+    pub node: VNode,
+    /// Range of terrain heights within the node, in meters above sea level.
+    pub height_range: (f32, f32),
+    /// Center of a sphere enclosing the node, in ECEF coordinates.
+    pub bounding_center: mint::Point3<f64>,
+    /// Radius of a sphere enclosing the node, in meters.
+    pub bounding_radius: f64,
+}
+
+/// Step in the lifecycle of a node's tiles, sent to every receiver returned by
+/// `Terrain::subscribe_tile_events`.
+///
+/// Unlike `NodeEvent`s, these report every step in the order it happened, which makes them
+/// suited to driving loading screens, logging anomalies and checking the behavior of the cache in
+/// tests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileEvent {
+    /// The streamed layers of the node were requested from the tile server or local cache.
+    Requested { node: VNode },
+    /// The streamed layers of the node arrived and were uploaded to the GPU.
+    Streamed { node: VNode },
+    /// Loading the streamed layers of the node failed repeatedly, so empty tiles were used in their
+    /// place.
+    StreamingFailed { node: VNode, error: String },
+    /// The named generator was dispatched to produce the listed layers and meshes of the node.
+    Generated { node: VNode, generator: &'static str, layers: Vec<&'static str> },
+    /// Validation found a problem with a generated tile. Only reported while validation is
+    /// enabled.
+    GenerationFailed(ValidationIssue),
+    /// The node was removed from the cache, along with all of its tiles.
+    Evicted { node: VNode },
+}
+
+/// Senders for every subscriber to `TileEvent`s. Subscribers that have dropped their receiver
+/// are removed the next time an event is sent.
+#[derive(Default)]
+pub(crate) struct TileEventSenders(Vec<mpsc::Sender<TileEvent>>);
+impl TileEventSenders {
+    pub fn subscribe(&mut self) -> mpsc::Receiver<TileEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.0.push(sender);
+        receiver
+    }
+
+    /// Whether anyone is subscribed, so that callers can skip building events nobody receives.
+    pub fn is_active(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    pub fn send(&mut self, event: TileEvent) {
+        self.0.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_subscribers_are_removed() {
+        let mut senders = TileEventSenders::default();
+        assert!(!senders.is_active());
+
+        let kept = senders.subscribe();
+        drop(senders.subscribe());
+        let node = VNode::roots()[0];
+        senders.send(TileEvent::Requested { node });
+        assert_eq!(senders.0.len(), 1);
+        assert_eq!(kept.try_recv(), Ok(TileEvent::Requested { node }));
+    }
+}
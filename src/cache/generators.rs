@@ -2,22 +2,32 @@ use std::{
     collections::HashMap,
     mem,
     num::{NonZeroU32, NonZeroU64},
+    sync::Arc,
 };
 
 use super::{layer::MeshType, LayerMask, LayerType, MeshCache};
 use crate::{
-    cache::{mesh::MeshGenerateUniforms, Levels},
+    cache::{
+        compress::{TileCompressor, STAGING_LAYERS},
+        erosion::{erosion_batches, Erosion, ErosionStage, ErosionUniforms, EROSION_BATCH},
+        mesh::MeshGenerateUniforms,
+        uniforms::GenerateUniforms,
+        Levels,
+    },
     gpu_state::{DrawIndexedIndirect, GpuState},
+    resources::{ResourceKind, Tracked},
 };
 use cgmath::InnerSpace;
 use maplit::hashmap;
 use rayon::prelude::*;
 use rshader::{ShaderSet, ShaderSource};
-use terra_types::{VNode, EARTH_SEMIMAJOR_AXIS, EARTH_SEMIMINOR_AXIS};
+use terra_types::{VNode, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
 use vec_map::VecMap;
 use wgpu::util::DeviceExt;
 
 pub(crate) trait GenerateTile: Send {
+    /// Name used to identify the generator in diagnostics.
+    fn name(&self) -> &'static str;
     /// Layers that must be present at `level` or the maximum level of the layer (whichever is smaller).
     fn inputs(&self) -> LayerMask;
     /// Layers generated by this object. Zero means generate cannot operate for nodes of this level.
@@ -28,6 +38,8 @@ pub(crate) trait GenerateTile: Send {
     fn tiles_per_frame(&self) -> usize {
         16
     }
+    /// Configure hydraulic erosion of generated heightmaps. Ignored by other generators.
+    fn set_erosion(&mut self, _erosion: Option<Erosion>) {}
     /// Run the generator for `node`.
     fn generate(
         &mut self,
@@ -35,17 +47,18 @@ pub(crate) trait GenerateTile: Send {
         encoder: &mut wgpu::CommandEncoder,
         state: &GpuState,
         nodes: &[(VNode, usize)],
-        uniform_data: &mut Vec<u8>,
+        uniforms: &mut GenerateUniforms,
     );
 }
 
 struct MeshGen {
     shaders: Vec<ShaderSet>,
     dimensions: Vec<(u32, u32, u32)>,
-    bindgroup_pipeline: Vec<Option<(wgpu::BindGroup, wgpu::ComputePipeline)>>,
+    /// Indices of shaders that were reloaded, whose cached pipelines must be dropped.
+    refreshed: Vec<usize>,
     inputs: LayerMask,
     outputs: LayerMask,
-    name: String,
+    name: &'static str,
 
     min_level: u8,
     base_entry: u32,
@@ -54,6 +67,9 @@ struct MeshGen {
     clear_indirect_buffer: wgpu::Buffer,
 }
 impl GenerateTile for MeshGen {
+    fn name(&self) -> &'static str {
+        self.name
+    }
     fn outputs(&self) -> LayerMask {
         self.outputs
     }
@@ -61,14 +77,12 @@ impl GenerateTile for MeshGen {
         self.inputs
     }
     fn needs_refresh(&mut self) -> bool {
-        let mut refreshed = false;
         for (i, shader) in self.shaders.iter_mut().enumerate() {
             if shader.refresh() {
-                self.bindgroup_pipeline[i] = None;
-                refreshed = true;
+                self.refreshed.push(i);
             }
         }
-        refreshed
+        !self.refreshed.is_empty()
     }
     fn generate(
         &mut self,
@@ -76,21 +90,39 @@ impl GenerateTile for MeshGen {
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &GpuState,
         nodes: &[(VNode, usize)],
-        uniform_data: &mut Vec<u8>,
+        uniforms: &mut GenerateUniforms,
     ) {
+        for i in self.refreshed.drain(..) {
+            gpu_state.objects.invalidate(&format!("generate.{}.{}", self.name, i));
+        }
+        let bindgroups_pipelines: Vec<_> = self
+            .shaders
+            .iter()
+            .enumerate()
+            .map(|(i, shader)| {
+                gpu_state.compute_bind_group_pipeline(
+                    device,
+                    shader,
+                    hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &gpu_state.generate_uniforms,
+                        offset: 0,
+                        size: Some(NonZeroU64::new(mem::size_of::<MeshGenerateUniforms>() as u64).unwrap()),
+                    }))],
+                    HashMap::new(),
+                    &format!("generate.{}.{}", self.name, i),
+                )
+            })
+            .collect();
+
         for (_, slot) in nodes {
             let entry = (slot - Levels::base_slot(self.min_level)) as u32 * self.entries_per_node;
-            let uniforms = MeshGenerateUniforms {
+            let (uniform_offset, data) = uniforms.allocate(mem::size_of::<MeshGenerateUniforms>());
+            data.copy_from_slice(bytemuck::bytes_of(&MeshGenerateUniforms {
                 slot: *slot as u32,
                 storage_base_entry: entry,
                 mesh_base_entry: self.base_entry + entry,
                 entries_per_node: self.entries_per_node,
-            };
-
-            assert!(std::mem::size_of::<MeshGenerateUniforms>() <= 256);
-            let uniform_offset = uniform_data.len();
-            uniform_data.extend_from_slice(bytemuck::bytes_of(&uniforms));
-            uniform_data.resize(uniform_offset + 256, 0);
+            }));
 
             encoder.copy_buffer_to_buffer(
                 &self.clear_indirect_buffer,
@@ -100,48 +132,11 @@ impl GenerateTile for MeshGen {
                 mem::size_of::<DrawIndexedIndirect>() as u64 * self.entries_per_node as u64,
             );
 
-            for i in 0..self.shaders.len() {
-                if self.bindgroup_pipeline[i].is_none() {
-                    let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
-                    device,
-                    &self.shaders[i],
-                    hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &gpu_state.generate_uniforms,
-                        offset: 0,
-                        size: Some(NonZeroU64::new(mem::size_of::<MeshGenerateUniforms>() as u64).unwrap()),
-                    }))],
-                    HashMap::new(),
-                    &format!("generate.{}", self.name),
-                );
-                    let pipeline =
-                        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                            layout: Some(&device.create_pipeline_layout(
-                                &wgpu::PipelineLayoutDescriptor {
-                                    bind_group_layouts: [&bind_group_layout][..].into(),
-                                    push_constant_ranges: &[],
-                                    label: None,
-                                },
-                            )),
-                            module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                                label: Some(&format!("shader.generate.{}", self.name)),
-                                source: self.shaders[i].compute(),
-                            }),
-                            entry_point: "main",
-                            label: Some(&format!("pipeline.generate.{}{}", self.name, i)),
-                        });
-                    self.bindgroup_pipeline[i] = Some((bind_group, pipeline));
-                }
-            }
-
             let mut cpass =
                 encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-            for i in 0..self.shaders.len() {
-                cpass.set_pipeline(&self.bindgroup_pipeline[i].as_ref().unwrap().1);
-                cpass.set_bind_group(
-                    0,
-                    &self.bindgroup_pipeline[i].as_ref().unwrap().0,
-                    &[uniform_offset as u32],
-                );
+            for (i, (bind_group, pipeline)) in bindgroups_pipelines.iter().enumerate() {
+                cpass.set_pipeline(pipeline);
+                cpass.set_bind_group(0, bind_group, &[uniform_offset]);
                 cpass.dispatch_workgroups(
                     self.dimensions[i].0,
                     self.dimensions[i].1,
@@ -154,13 +149,17 @@ impl GenerateTile for MeshGen {
 
 struct ShaderGen {
     shader: ShaderSet,
-    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::ComputePipeline)>,
+    /// Set when the shader was reloaded, so that its cached pipeline must be dropped.
+    refreshed: bool,
     dimensions: u32,
     inputs: LayerMask,
     outputs: LayerMask,
-    name: String,
+    name: &'static str,
 }
 impl GenerateTile for ShaderGen {
+    fn name(&self) -> &'static str {
+        self.name
+    }
     fn outputs(&self) -> LayerMask {
         self.outputs
     }
@@ -168,12 +167,8 @@ impl GenerateTile for ShaderGen {
         self.inputs
     }
     fn needs_refresh(&mut self) -> bool {
-        if self.shader.refresh() {
-            self.bindgroup_pipeline = None;
-            true
-        } else {
-            false
-        }
+        self.refreshed |= self.shader.refresh();
+        self.refreshed
     }
     fn generate(
         &mut self,
@@ -181,49 +176,35 @@ impl GenerateTile for ShaderGen {
         encoder: &mut wgpu::CommandEncoder,
         state: &GpuState,
         nodes: &[(VNode, usize)],
-        uniform_data: &mut Vec<u8>,
+        uniforms: &mut GenerateUniforms,
     ) {
         assert!(nodes.len() < 4096 / mem::size_of::<u32>());
-        let uniform_offset = uniform_data.len();
-        for (_, slot) in nodes {
-            uniform_data.extend_from_slice(bytemuck::bytes_of(&(*slot as u32)));
+        let (uniform_offset, data) = uniforms.allocate(4096);
+        for (i, (_, slot)) in nodes.iter().enumerate() {
+            data[i * 4..][..4].copy_from_slice(bytemuck::bytes_of(&(*slot as u32)));
         }
-        uniform_data.resize(uniform_offset + 4096, 0);
 
-        if self.bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = state.bind_group_for_shader(
-                device,
-                &self.shader,
-                hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &state.generate_uniforms,
-                    offset: 0,
-                    size: NonZeroU64::new(1024),
-                }))],
-                HashMap::new(),
-                &format!("generate.{}", self.name),
-            );
-
-            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: [&bind_group_layout][..].into(),
-                    push_constant_ranges: &[],
-                    label: None,
-                })),
-                module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                    label: Some(&format!("shader.generate.{}", self.name)),
-                    source: self.shader.compute().into(),
-                }),
-                entry_point: "main",
-                label: Some(&format!("pipeline.generate.{}", self.name)),
-            });
-            self.bindgroup_pipeline = Some((bind_group, pipeline));
+        let group_name = format!("generate.{}", self.name);
+        if self.refreshed {
+            state.objects.invalidate(&group_name);
+            self.refreshed = false;
         }
+        let (bindgroup, pipeline) = state.compute_bind_group_pipeline(
+            device,
+            &self.shader,
+            hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &state.generate_uniforms,
+                offset: 0,
+                size: NonZeroU64::new(1024),
+            }))],
+            HashMap::new(),
+            &group_name,
+        );
 
         let workgroup_size = self.shader.workgroup_size();
-        let (bindgroup, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
         let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-        cpass.set_pipeline(pipeline);
-        cpass.set_bind_group(0, bindgroup, &[uniform_offset as u32]);
+        cpass.set_pipeline(&pipeline);
+        cpass.set_bind_group(0, &bindgroup, &[uniform_offset]);
         cpass.dispatch_workgroups(
             (self.dimensions + workgroup_size[0] - 1) / workgroup_size[0],
             (self.dimensions + workgroup_size[1] - 1) / workgroup_size[1],
@@ -233,20 +214,22 @@ impl GenerateTile for ShaderGen {
 }
 
 struct ShaderGenBuilder {
-    name: String,
+    name: &'static str,
     dimensions: u32,
     shader: ShaderSource,
     inputs: LayerMask,
     outputs: LayerMask,
+    compressed: bool,
 }
 impl ShaderGenBuilder {
-    fn new(name: String, shader: ShaderSource) -> Self {
+    fn new(name: &'static str, shader: ShaderSource) -> Self {
         Self {
             name,
             dimensions: 0,
             shader,
             inputs: LayerMask::empty(),
             outputs: LayerMask::empty(),
+            compressed: false,
         }
     }
     fn dimensions(mut self, dimensions: u32) -> Self {
@@ -261,20 +244,231 @@ impl ShaderGenBuilder {
         self.outputs = outputs;
         self
     }
+    /// Write outputs to `GpuState::material_staging` and compress them into the tile cache.
+    fn compressed(mut self) -> Self {
+        self.compressed = true;
+        self
+    }
     fn build(self) -> Box<dyn GenerateTile> {
-        Box::new(ShaderGen {
+        let generator: Box<dyn GenerateTile> = Box::new(ShaderGen {
             name: self.name,
             shader: ShaderSet::compute_only(self.shader).unwrap(),
-            bindgroup_pipeline: None,
+            refreshed: false,
             inputs: self.inputs,
             outputs: self.outputs,
             dimensions: self.dimensions,
-        })
+        });
+        if self.compressed {
+            Box::new(CompressedGen { inner: generator, compressor: TileCompressor::new() })
+        } else {
+            generator
+        }
+    }
+}
+
+/// Wraps a generator whose outputs are written to `GpuState::material_staging`, moving them into
+/// the tile cache (and compressing them if supported) after each batch.
+struct CompressedGen {
+    inner: Box<dyn GenerateTile>,
+    compressor: TileCompressor,
+}
+impl GenerateTile for CompressedGen {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn outputs(&self) -> LayerMask {
+        self.inner.outputs()
+    }
+    fn inputs(&self) -> LayerMask {
+        self.inner.inputs()
+    }
+    fn needs_refresh(&mut self) -> bool {
+        self.inner.needs_refresh()
+    }
+    fn tiles_per_frame(&self) -> usize {
+        self.inner.tiles_per_frame()
+    }
+    fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        nodes: &[(VNode, usize)],
+        uniforms: &mut GenerateUniforms,
+    ) {
+        let outputs = self.inner.outputs();
+        for batch in nodes.chunks(STAGING_LAYERS as usize) {
+            self.inner.generate(device, encoder, state, batch, uniforms);
+            for layer in LayerType::iter().filter(|layer| outputs.contains_layer(*layer)) {
+                self.compressor.store(device, encoder, state, layer, batch);
+            }
+        }
+    }
+}
+
+/// Wraps the heightmap generator, running hydraulic erosion over its output when enabled.
+struct ErosionGen {
+    inner: Box<dyn GenerateTile>,
+    erosion: Option<Erosion>,
+    shader: ShaderSet,
+    store_shader: ShaderSet,
+    /// Set when either shader was reloaded, so that their cached pipelines must be dropped.
+    refreshed: bool,
+    /// Simulation state, which is only allocated once erosion is first enabled.
+    state: Option<Tracked<wgpu::Buffer>>,
+}
+impl ErosionGen {
+    fn new(inner: Box<dyn GenerateTile>) -> Self {
+        Self {
+            inner,
+            erosion: None,
+            shader: ShaderSet::compute_only(rshader::shader_source!(
+                "../shaders",
+                "erode-heightmaps.comp",
+                "declarations.glsl",
+                "erosion.glsl"
+            ))
+            .unwrap(),
+            store_shader: ShaderSet::compute_only(rshader::shader_source!(
+                "../shaders",
+                "store-eroded-heightmaps.comp",
+                "declarations.glsl",
+                "erosion.glsl"
+            ))
+            .unwrap(),
+            refreshed: false,
+            state: None,
+        }
+    }
+}
+impl GenerateTile for ErosionGen {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+    fn outputs(&self) -> LayerMask {
+        self.inner.outputs()
+    }
+    fn inputs(&self) -> LayerMask {
+        self.inner.inputs()
+    }
+    fn needs_refresh(&mut self) -> bool {
+        self.refreshed |= self.shader.refresh();
+        self.refreshed |= self.store_shader.refresh();
+        // Only report the erosion shaders as changed while they are in use.
+        let refreshed = self.refreshed && self.erosion.is_some();
+        self.inner.needs_refresh() || refreshed
+    }
+    fn tiles_per_frame(&self) -> usize {
+        // Erosion is far more expensive than generating the heightmaps themselves.
+        match self.erosion {
+            Some(_) => EROSION_BATCH,
+            None => self.inner.tiles_per_frame(),
+        }
+    }
+    fn set_erosion(&mut self, erosion: Option<Erosion>) {
+        self.erosion = erosion;
+    }
+    fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        nodes: &[(VNode, usize)],
+        uniforms: &mut GenerateUniforms,
+    ) {
+        self.inner.generate(device, encoder, state, nodes, uniforms);
+        let erosion = match self.erosion {
+            Some(erosion) => erosion,
+            None => return,
+        };
+
+        let texels = LayerType::Heightmaps.texture_resolution() as u64
+            * LayerType::Heightmaps.texture_resolution() as u64;
+        let buffer: &wgpu::Buffer = self.state.get_or_insert_with(|| {
+            let size = 2 * EROSION_BATCH as u64 * texels * mem::size_of::<[f32; 4]>() as u64;
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                size,
+                usage: wgpu::BufferUsages::STORAGE,
+                label: Some("buffer.generate.erosion"),
+                mapped_at_creation: false,
+            });
+            Tracked::new(buffer, state.resources.track(ResourceKind::Buffer, "erosion", size))
+        });
+
+        if self.refreshed {
+            state.objects.invalidate("generate.erosion");
+            state.objects.invalidate("generate.erosion-store");
+            self.refreshed = false;
+        }
+        let buffers = move || {
+            hashmap![
+                "ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &state.generate_uniforms,
+                    offset: 0,
+                    size: NonZeroU64::new(mem::size_of::<ErosionUniforms>() as u64),
+                })),
+                "erosion_state".into() => (false, buffer.as_entire_binding()),
+            ]
+        };
+        let (bindgroup, pipeline) = state.compute_bind_group_pipeline(
+            device,
+            &self.shader,
+            buffers(),
+            HashMap::new(),
+            "generate.erosion",
+        );
+        let (store_bindgroup, store_pipeline) = state.compute_bind_group_pipeline(
+            device,
+            &self.store_shader,
+            buffers(),
+            HashMap::new(),
+            "generate.erosion-store",
+        );
+
+        let resolution = LayerType::Heightmaps.texture_resolution();
+        let workgroup_size = self.shader.workgroup_size();
+        let workgroups_x = (resolution + workgroup_size[0] - 1) / workgroup_size[0];
+        let workgroups_y = (resolution + workgroup_size[1] - 1) / workgroup_size[1];
+        for batch in erosion_batches(nodes) {
+            let slots: Vec<usize> = batch.iter().map(|(_, slot)| *slot).collect();
+            let mut stage_offset = |stage| {
+                let (offset, data) = uniforms.allocate(mem::size_of::<ErosionUniforms>());
+                data.copy_from_slice(bytemuck::bytes_of(&ErosionUniforms::new(
+                    &erosion, stage, &slots,
+                )));
+                offset
+            };
+            let load = stage_offset(ErosionStage::Load);
+            let flow = stage_offset(ErosionStage::Flow);
+            let erode = stage_offset(ErosionStage::Erode);
+            let store = stage_offset(ErosionStage::Store);
+
+            // Every dispatch covers the whole batch, and alternates between the two copies of the
+            // simulation state.
+            let workgroups_z = batch.len() as u32;
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(&pipeline);
+            cpass.set_bind_group(0, &bindgroup, &[load]);
+            cpass.dispatch_workgroups(workgroups_x, workgroups_y, workgroups_z);
+            for _ in 0..erosion.clamped_iterations() {
+                cpass.set_bind_group(0, &bindgroup, &[flow]);
+                cpass.dispatch_workgroups(workgroups_x, workgroups_y, workgroups_z);
+                cpass.set_bind_group(0, &bindgroup, &[erode]);
+                cpass.dispatch_workgroups(workgroups_x, workgroups_y, workgroups_z);
+            }
+            cpass.set_pipeline(&store_pipeline);
+            cpass.set_bind_group(0, &store_bindgroup, &[store]);
+            cpass.dispatch_workgroups(workgroups_x, workgroups_y, workgroups_z);
+        }
     }
 }
 
 struct EllipsoidGen;
 impl GenerateTile for EllipsoidGen {
+    fn name(&self) -> &'static str {
+        "ellipsoid"
+    }
     fn outputs(&self) -> LayerMask {
         LayerType::Ellipsoid.bit_mask()
     }
@@ -293,49 +487,44 @@ impl GenerateTile for EllipsoidGen {
         encoder: &mut wgpu::CommandEncoder,
         state: &GpuState,
         nodes: &[(VNode, usize)],
-        _uniform_data: &mut Vec<u8>,
+        _uniforms: &mut GenerateUniforms,
     ) {
-        let values: Vec<f32> = nodes
-            .par_iter()
-            .map(|(node, _)| {
-                let mut values = vec![0f32; 65 * 320];
-                let center = node.center_wspace();
-                let base_x = node.x() as u64 * 64;
-                let base_y = node.y() as u64 * 64;
-                let scale = 2.0 / (1u32 << node.level()) as f64 / 64.0;
-                for y in 0..65 {
-                    for x in 0..65 {
-                        let fx = (base_x + x as u64) as f64 * scale - 1.0;
-                        let fy = (base_y + y as u64) as f64 * scale - 1.0;
-                        let position = node.fspace_to_cspace(fx, fy);
-                        let position =
-                            cgmath::Vector3::new(position.x, position.y, position.z).normalize();
+        let (buffer, offset) = state.stage(device, nodes.len() as u64 * 65 * 1280, |bytes| {
+            bytemuck::cast_slice_mut::<u8, f32>(bytes)
+                .par_chunks_mut(65 * 320)
+                .zip(nodes.par_iter())
+                .for_each(|(values, (node, _))| {
+                    // Staging memory is reused, so also clear the texels that aren't set below.
+                    values.fill(0.0);
+                    let center = node.center_wspace();
+                    let base_x = node.x() as u64 * 64;
+                    let base_y = node.y() as u64 * 64;
+                    let scale = 2.0 / (1u32 << node.level()) as f64 / 64.0;
+                    for y in 0..65 {
+                        for x in 0..65 {
+                            let fx = (base_x + x as u64) as f64 * scale - 1.0;
+                            let fy = (base_y + y as u64) as f64 * scale - 1.0;
+                            let position = node.fspace_to_cspace(fx, fy);
+                            let position = cgmath::Vector3::new(position.x, position.y, position.z)
+                                .normalize();
 
-                        values[y * 320 + x * 4 + 0] =
-                            (position.x * EARTH_SEMIMAJOR_AXIS - center.x) as f32;
-                        values[y * 320 + x * 4 + 1] =
-                            (position.y * EARTH_SEMIMAJOR_AXIS - center.y) as f32;
-                        values[y * 320 + x * 4 + 2] =
-                            (position.z * EARTH_SEMIMINOR_AXIS - center.z) as f32;
+                            values[y * 320 + x * 4 + 0] =
+                                (position.x * PLANET_SEMIMAJOR_AXIS - center.x) as f32;
+                            values[y * 320 + x * 4 + 1] =
+                                (position.y * PLANET_SEMIMAJOR_AXIS - center.y) as f32;
+                            values[y * 320 + x * 4 + 2] =
+                                (position.z * PLANET_SEMIMINOR_AXIS - center.z) as f32;
+                        }
                     }
-                }
-                values
-            })
-            .flatten()
-            .collect();
-
-        let buffer = &device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("upload.ellipsoid"),
-            contents: bytemuck::cast_slice(&values),
-            usage: wgpu::BufferUsages::COPY_SRC,
+                });
         });
 
         for (i, (_, slot)) in nodes.iter().enumerate() {
             encoder.copy_buffer_to_texture(
                 wgpu::ImageCopyBuffer {
-                    buffer,
+                    buffer: &buffer,
                     layout: wgpu::ImageDataLayout {
-                        offset: i as u64 * 65 * 1280,
+                        offset: offset + i as u64 * 65 * 1280,
                         bytes_per_row: NonZeroU32::new(1280),
                         rows_per_image: None,
                     },
@@ -364,21 +553,23 @@ pub(crate) fn generators(
 
     vec![
         Box::new(EllipsoidGen),
+        Box::new(ErosionGen::new(
+            ShaderGenBuilder::new(
+                "heightmaps",
+                rshader::shader_source!(
+                    "../shaders",
+                    "gen-heightmaps.comp",
+                    "declarations.glsl",
+                    "hash.glsl"
+                ),
+            )
+            .inputs(LayerType::BaseHeightmaps.bit_mask())
+            .outputs(LayerType::Heightmaps.bit_mask())
+            .dimensions(heightmaps_resolution)
+            .build(),
+        )),
         ShaderGenBuilder::new(
-            "heightmaps".into(),
-            rshader::shader_source!(
-                "../shaders",
-                "gen-heightmaps.comp",
-                "declarations.glsl",
-                "hash.glsl"
-            ),
-        )
-        .inputs(LayerType::BaseHeightmaps.bit_mask())
-        .outputs(LayerType::Heightmaps.bit_mask())
-        .dimensions(heightmaps_resolution)
-        .build(),
-        ShaderGenBuilder::new(
-            "displacements".into(),
+            "displacements",
             rshader::shader_source!("../shaders", "gen-displacements.comp", "declarations.glsl"),
         )
         .inputs(
@@ -390,7 +581,7 @@ pub(crate) fn generators(
         .dimensions(displacements_resolution)
         .build(),
         ShaderGenBuilder::new(
-            "tree-attributes".into(),
+            "tree-attributes",
             rshader::shader_source!(
                 "../shaders",
                 "gen-tree-attributes.comp",
@@ -400,6 +591,7 @@ pub(crate) fn generators(
         )
         .inputs(
             LayerType::TreeCover.bit_mask()
+                | LayerType::Landcover.bit_mask()
                 | LayerType::Heightmaps.bit_mask()
                 | LayerType::WaterLevel.bit_mask(),
         )
@@ -407,7 +599,7 @@ pub(crate) fn generators(
         .dimensions(tree_attributes_resolution)
         .build(),
         ShaderGenBuilder::new(
-            "materials".into(),
+            "materials",
             rshader::shader_source!(
                 "../shaders",
                 "gen-materials.comp",
@@ -426,9 +618,10 @@ pub(crate) fn generators(
         )
         .outputs(LayerType::Normals.bit_mask() | LayerType::AlbedoRoughness.bit_mask())
         .dimensions(normals_resolution)
+        .compressed()
         .build(),
         ShaderGenBuilder::new(
-            "grass-canopy".into(),
+            "grass-canopy",
             rshader::shader_source!(
                 "../shaders",
                 "gen-grass-canopy.comp",
@@ -445,7 +638,7 @@ pub(crate) fn generators(
         .dimensions(grass_canopy_resolution)
         .build(),
         ShaderGenBuilder::new(
-            "bent-normals".into(),
+            "bent-normals",
             rshader::shader_source!(
                 "../shaders",
                 "gen-bent-normals.comp",
@@ -465,11 +658,21 @@ pub(crate) fn generators(
                 //     "declarations.glsl",
                 //     "hash.glsl"
                 // )).unwrap(),
-                ShaderSet::compute_only(rshader::wgsl_source!(
-                    "../shaders",
-                    "gen-grass.wgsl",
-                    "declarations.wgsl"
-                ))
+                ShaderSet::compute_only(if GpuState::tile_layer_array_supported(device) {
+                    rshader::wgsl_source!(
+                        "../shaders",
+                        "gen-grass.wgsl",
+                        "declarations.wgsl",
+                        "tile-layers.wgsl"
+                    )
+                } else {
+                    rshader::wgsl_source!(
+                        "../shaders",
+                        "gen-grass.wgsl",
+                        "declarations.wgsl",
+                        "gen-grass-layers.wgsl"
+                    )
+                })
                 .unwrap(),
                 ShaderSet::compute_only(rshader::shader_source!(
                     "../shaders",
@@ -479,13 +682,13 @@ pub(crate) fn generators(
                 .unwrap(),
             ],
             dimensions: vec![(16, 16, 1), (16, 1, 1)],
-            bindgroup_pipeline: vec![None, None],
+            refreshed: Vec::new(),
             inputs: LayerType::Displacements.bit_mask()
                 | LayerType::AlbedoRoughness.bit_mask()
                 | LayerType::Normals.bit_mask()
                 | LayerType::GrassCanopy.bit_mask(),
             outputs: MeshType::Grass.bit_mask(),
-            name: "grass-mesh".to_string(),
+            name: "grass-mesh",
             min_level: meshes[MeshType::Grass].desc.min_level,
             base_entry: meshes[MeshType::Grass].base_entry as u32,
             entries_per_node: meshes[MeshType::Grass].desc.entries_per_node as u32,
@@ -503,10 +706,10 @@ pub(crate) fn generators(
             ))
             .unwrap()],
             dimensions: vec![(4, 1, 1)],
-            bindgroup_pipeline: vec![None],
+            refreshed: Vec::new(),
             inputs: LayerType::Displacements.bit_mask(),
             outputs: MeshType::Terrain.bit_mask(),
-            name: "terrain-mesh".to_string(),
+            name: "terrain-mesh",
             min_level: meshes[MeshType::Terrain].desc.min_level,
             base_entry: meshes[MeshType::Terrain].base_entry as u32,
             entries_per_node: meshes[MeshType::Terrain].desc.entries_per_node as u32,
@@ -542,10 +745,10 @@ pub(crate) fn generators(
                 .unwrap(),
             ],
             dimensions: vec![(16, 16, 1), (16, 1, 1)],
-            bindgroup_pipeline: vec![None, None],
+            refreshed: Vec::new(),
             inputs: LayerType::Displacements.bit_mask() | LayerType::TreeAttributes.bit_mask(),
             outputs: MeshType::TreeBillboards.bit_mask(),
-            name: "tree-billboards-mesh".to_string(),
+            name: "tree-billboards-mesh",
             min_level: meshes[MeshType::TreeBillboards].desc.min_level,
             base_entry: meshes[MeshType::TreeBillboards].base_entry as u32,
             entries_per_node: meshes[MeshType::TreeBillboards].desc.entries_per_node as u32,
@@ -555,6 +758,41 @@ pub(crate) fn generators(
                 contents: &vec![0; mem::size_of::<DrawIndexedIndirect>() * 16],
             }),
         }),
+        Box::new(MeshGen {
+            shaders: vec![
+                ShaderSet::compute_only(rshader::shader_source!(
+                    "../shaders",
+                    "gen-rocks.comp",
+                    "declarations.glsl",
+                    "hash.glsl"
+                ))
+                .unwrap(),
+                ShaderSet::compute_only(rshader::shader_source!(
+                    "../shaders",
+                    "bounding-rocks.comp",
+                    "declarations.glsl"
+                ))
+                .unwrap(),
+            ],
+            dimensions: vec![(16, 16, 1), (16, 1, 1)],
+            refreshed: Vec::new(),
+            inputs: LayerType::Displacements.bit_mask()
+                | LayerType::AlbedoRoughness.bit_mask()
+                | LayerType::Normals.bit_mask()
+                | LayerType::Heightmaps.bit_mask()
+                | LayerType::WaterLevel.bit_mask()
+                | LayerType::Landcover.bit_mask(),
+            outputs: MeshType::Rocks.bit_mask(),
+            name: "rocks-mesh",
+            min_level: meshes[MeshType::Rocks].desc.min_level,
+            base_entry: meshes[MeshType::Rocks].base_entry as u32,
+            entries_per_node: meshes[MeshType::Rocks].desc.entries_per_node as u32,
+            clear_indirect_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                usage: wgpu::BufferUsages::COPY_SRC,
+                label: Some("buffer.rocks.clear_indirect"),
+                contents: &vec![0; mem::size_of::<DrawIndexedIndirect>() * 16],
+            }),
+        }),
     ]
 }
 
@@ -564,8 +802,8 @@ pub(super) struct DynamicGenerator {
     pub max_level: u8,
 
     pub shader: ShaderSet,
-    pub resolution: (u32, u32),
-    pub bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::ComputePipeline)>,
+    pub layer: LayerType,
+    pub bindgroup_pipeline: Option<(Arc<Tracked<wgpu::BindGroup>>, Arc<wgpu::ComputePipeline>)>,
 
     pub name: &'static str,
 }
@@ -583,7 +821,7 @@ pub(super) fn dynamic_generators() -> Vec<DynamicGenerator> {
                 "atmosphere.glsl"
             ))
             .unwrap(),
-            resolution: (1, 1),
+            layer: LayerType::AerialPerspective,
             bindgroup_pipeline: None,
             name: "aerial-perspective",
         },
@@ -598,7 +836,7 @@ pub(super) fn dynamic_generators() -> Vec<DynamicGenerator> {
                 "atmosphere.glsl"
             ))
             .unwrap(),
-            resolution: (9, 9),
+            layer: LayerType::RootAerialPerspective,
             bindgroup_pipeline: None,
             name: "root-aerial-perspective",
         },
@@ -0,0 +1,240 @@
+use crate::cache::events::{NodeEvent, NodeEventKind};
+use crate::cache::raycast::{ellipsoid_point, to_cspace};
+use crate::cache::TileCache;
+use crate::weather::local_frame;
+use anyhow::Error;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use terra_types::VNode;
+
+/// Square patch of terrain to build a collision heightfield for, centered on a point on the
+/// ellipsoid and aligned with the local east and north directions there.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeightfieldRegion {
+    /// Latitude of the center in degrees.
+    pub latitude: f64,
+    /// Longitude of the center in degrees.
+    pub longitude: f64,
+    /// Length of each side in meters.
+    pub size: f64,
+}
+
+/// Terrain heights laid out for heightfield colliders in physics engines like rapier and PhysX.
+///
+/// The collider frame has x pointing east, y up and z south, with its origin on the plane tangent
+/// to the ellipsoid at the center of the region. Heights are measured along y from that plane, so
+/// the curvature of the planet is included rather than lost.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollisionHeightfield {
+    /// Number of samples along each side.
+    pub resolution: usize,
+    /// Distance in meters between adjacent samples.
+    pub spacing: f64,
+    /// Heights in meters, stored column by column: the sample `x` columns east and `z` rows south
+    /// of the northwest corner is at index `x * resolution + z`. This is the column-major layout
+    /// of `nalgebra::DMatrix::from_vec(resolution, resolution, heights)` expected by rapier, and
+    /// the row-major layout of PhysX heightfields, whose rows run along x.
+    pub heights: Vec<f32>,
+    /// Maps the collider frame to ECEF coordinates.
+    pub transform: mint::ColumnMatrix4<f64>,
+    /// Quadtree levels of the coarsest and finest heightmaps sampled, or `None` if no heightmap
+    /// covering the region was loaded and it sits at sea level.
+    pub levels: Option<(u8, u8)>,
+}
+impl CollisionHeightfield {
+    /// Height of the sample `x` columns east and `z` rows south of the northwest corner.
+    pub fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[x * self.resolution + z]
+    }
+
+    /// Length of each side in meters.
+    pub fn size(&self) -> f64 {
+        self.spacing * (self.resolution - 1) as f64
+    }
+
+    /// Scale of a rapier `HeightField` built from `heights`, which is centered on the origin of
+    /// the collider frame.
+    pub fn rapier_scale(&self) -> mint::Vector3<f32> {
+        let size = self.size() as f32;
+        mint::Vector3 { x: size, y: 1.0, z: size }
+    }
+
+    /// Heights quantized to the 16-bit samples used by PhysX, along with the height scale that
+    /// converts them back to meters.
+    pub fn quantized_heights(&self) -> (Vec<i16>, f32) {
+        let max = self.heights.iter().fold(0.0f32, |max, h| max.max(h.abs()));
+        let scale = (max / i16::MAX as f32).max(1e-6);
+        (self.heights.iter().map(|h| (h / scale).round() as i16).collect(), scale)
+    }
+
+    /// Maps a frame like the collider frame but with its origin at the northwest corner to ECEF
+    /// coordinates. PhysX heightfields start at the origin of their frame rather than being
+    /// centered on it, so should be placed with this transform instead.
+    pub fn corner_transform(&self) -> mint::ColumnMatrix4<f64> {
+        let half = self.size() * 0.5;
+        (Matrix4::from(self.transform) * Matrix4::from_translation(Vector3::new(-half, 0.0, -half)))
+            .into()
+    }
+
+    /// Whether the node in `event` may change the heights of this heightfield, in which case it
+    /// should be built again. Newly loaded nodes only matter if they are more detailed than some
+    /// of the heightmaps sampled, and evicted nodes only if they could have been sampled.
+    pub fn affected_by(&self, event: &NodeEvent) -> bool {
+        let transform = Matrix4::from(self.transform);
+        let center = transform.w.truncate();
+        let max_height = self.heights.iter().fold(0.0f64, |max, &h| max.max(h.abs() as f64));
+        let half_diagonal = self.size() * std::f64::consts::FRAC_1_SQRT_2;
+        let radius = (half_diagonal * half_diagonal + max_height * max_height).sqrt();
+
+        let c = event.bounding_center;
+        let event_center = Vector3::new(c.x, c.y, c.z);
+        if (event_center - center).magnitude() > radius + event.bounding_radius {
+            return false;
+        }
+        match (self.levels, event.kind) {
+            (None, kind) => kind == NodeEventKind::Loaded,
+            (Some((coarsest, _)), NodeEventKind::Loaded) => event.node.level() > coarsest,
+            (Some((_, finest)), NodeEventKind::Evicted) => event.node.level() <= finest,
+        }
+    }
+}
+
+impl TileCache {
+    /// Sample a heightfield covering `region` from the most detailed resident heightmaps, with
+    /// `resolution` samples along each side.
+    pub fn collision_heightfield(
+        &self,
+        region: HeightfieldRegion,
+        resolution: usize,
+    ) -> Result<CollisionHeightfield, Error> {
+        Ok(self.sample_heightfield(region, resolution, VNode::LEVEL_CELL_1M)?.0)
+    }
+
+    /// Sample a heightfield covering `region` from resident heightmaps no more detailed than
+    /// `max_level`. Also returns which samples are at or below sea level.
+    pub(super) fn sample_heightfield(
+        &self,
+        region: HeightfieldRegion,
+        resolution: usize,
+        max_level: u8,
+    ) -> Result<(CollisionHeightfield, Vec<bool>), Error> {
+        if resolution < 2 {
+            return Err(anyhow::format_err!("Heightfields need at least two samples per side"));
+        }
+        if !region.size.is_finite() || region.size <= 0.0 {
+            return Err(anyhow::format_err!("Heightfield size must be positive"));
+        }
+
+        let origin = crate::overlay::ellipsoid_point((region.latitude, region.longitude));
+        let (east, north, up) = local_frame(origin);
+        let spacing = region.size / (resolution - 1) as f64;
+        let half = region.size * 0.5;
+
+        let mut heights = Vec::with_capacity(resolution * resolution);
+        let mut sea = Vec::with_capacity(resolution * resolution);
+        let mut levels: Option<(u8, u8)> = None;
+        for x in 0..resolution {
+            for z in 0..resolution {
+                let offset =
+                    east * (x as f64 * spacing - half) - north * (z as f64 * spacing - half);
+                let cspace = to_cspace(origin + offset);
+                let surface = ellipsoid_point(cspace);
+                let height = (0..=max_level)
+                    .rev()
+                    .find_map(|level| self.get_height_cspace(cspace, level).map(|h| (level, h)));
+                if let Some((level, _)) = height {
+                    levels = Some(levels.map_or((level, level), |(coarsest, finest)| {
+                        (coarsest.min(level), finest.max(level))
+                    }));
+                }
+
+                let height = height.map_or(0.0, |h| h.1);
+                let position = surface + surface.normalize() * height as f64;
+                heights.push((position - origin).dot(up) as f32);
+                sea.push(height <= 0.0);
+            }
+        }
+
+        let transform = Matrix4::from_cols(
+            east.extend(0.0),
+            up.extend(0.0),
+            (-north).extend(0.0),
+            origin.extend(1.0),
+        );
+        let heightfield = CollisionHeightfield {
+            resolution,
+            spacing,
+            heights,
+            transform: transform.into(),
+            levels,
+        };
+        Ok((heightfield, sea))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heightfield(levels: Option<(u8, u8)>) -> CollisionHeightfield {
+        let origin = crate::overlay::ellipsoid_point((46.5, 7.9));
+        let (east, north, up) = local_frame(origin);
+        let transform = Matrix4::from_cols(
+            east.extend(0.0),
+            up.extend(0.0),
+            (-north).extend(0.0),
+            origin.extend(1.0),
+        );
+        CollisionHeightfield {
+            resolution: 3,
+            spacing: 100.0,
+            heights: vec![0.0, 10.0, -20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 2000.0],
+            transform: transform.into(),
+            levels,
+        }
+    }
+
+    #[test]
+    fn collider_frame_is_right_handed() {
+        let transform = Matrix4::from(heightfield(None).transform);
+        let (x, y, z) = (transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+        assert!((x.cross(y) - z).magnitude() < 1e-9);
+
+        let corner = Matrix4::from(heightfield(None).corner_transform());
+        let expected = transform.w.truncate() - x * 100.0 - z * 100.0;
+        assert!((corner.w.truncate() - expected).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn quantized_heights_round_trip() {
+        let heightfield = heightfield(None);
+        let (samples, scale) = heightfield.quantized_heights();
+        for (sample, height) in samples.iter().zip(&heightfield.heights) {
+            assert!((*sample as f32 * scale - height).abs() <= scale);
+        }
+    }
+
+    #[test]
+    fn only_relevant_events_affect_heightfield() {
+        let heightfield = heightfield(Some((10, 12)));
+        let center = Matrix4::from(heightfield.transform).w.truncate();
+        let event = |kind, level, offset: f64| {
+            let cspace = to_cspace(center);
+            let cspace = cspace / cspace.x.abs().max(cspace.y.abs()).max(cspace.z.abs());
+            let node = VNode::from_cspace(cspace, level).0;
+            let center = center + Vector3::new(0.0, 0.0, offset);
+            NodeEvent {
+                kind,
+                node,
+                height_range: (0.0, 0.0),
+                bounding_center: mint::Point3 { x: center.x, y: center.y, z: center.z },
+                bounding_radius: 500.0,
+            }
+        };
+
+        assert!(heightfield.affected_by(&event(NodeEventKind::Loaded, 13, 0.0)));
+        assert!(!heightfield.affected_by(&event(NodeEventKind::Loaded, 10, 0.0)));
+        assert!(!heightfield.affected_by(&event(NodeEventKind::Loaded, 13, 1e5)));
+        assert!(heightfield.affected_by(&event(NodeEventKind::Evicted, 12, 0.0)));
+        assert!(!heightfield.affected_by(&event(NodeEventKind::Evicted, 13, 0.0)));
+    }
+}
@@ -104,8 +104,9 @@ impl TextureFormat {
     }
 }
 
+/// Kind of texture stored for each node of the quadtree.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum LayerType {
+pub enum LayerType {
     BaseHeightmaps = 0,
     Displacements = 1,
     AlbedoRoughness = 2,
@@ -121,12 +122,13 @@ pub(crate) enum LayerType {
     Ellipsoid = 12,
     Heightmaps = 13,
     WaterLevel = 14,
+    Landcover = 15,
 }
 impl LayerType {
-    pub fn index(&self) -> usize {
+    pub(crate) fn index(&self) -> usize {
         *self as usize
     }
-    pub fn from_index(i: usize) -> Self {
+    pub(crate) fn from_index(i: usize) -> Self {
         match i {
             0 => LayerType::BaseHeightmaps,
             1 => LayerType::Displacements,
@@ -143,6 +145,7 @@ impl LayerType {
             12 => LayerType::Ellipsoid,
             13 => LayerType::Heightmaps,
             14 => LayerType::WaterLevel,
+            15 => LayerType::Landcover,
             _ => unreachable!(),
         }
     }
@@ -166,25 +169,27 @@ impl LayerType {
             LayerType::Ellipsoid => "ellipsoid",
             LayerType::Heightmaps => "heightmaps",
             LayerType::WaterLevel => "waterlevel",
+            LayerType::Landcover => "landcover",
         }
     }
-    pub fn streamed_levels(&self) -> u8 {
+    pub(crate) fn streamed_levels(&self) -> u8 {
         match *self {
             LayerType::BaseHeightmaps => VNode::LEVEL_CELL_76M + 1,
             LayerType::BaseAlbedo => VNode::LEVEL_CELL_610M + 1,
             LayerType::TreeCover => VNode::LEVEL_CELL_76M + 1,
             LayerType::LandFraction => VNode::LEVEL_CELL_76M + 1,
             LayerType::WaterLevel => 1,
+            LayerType::Landcover => VNode::LEVEL_CELL_76M + 1,
             _ => 0,
         }
     }
-    pub fn dynamic(&self) -> bool {
+    pub(crate) fn dynamic(&self) -> bool {
         match *self {
             LayerType::AerialPerspective | LayerType::RootAerialPerspective => true,
             _ => false,
         }
     }
-    pub fn grid_registration(&self) -> bool {
+    pub(crate) fn grid_registration(&self) -> bool {
         match *self {
             LayerType::BaseHeightmaps => true,
             LayerType::Displacements => true,
@@ -201,10 +206,11 @@ impl LayerType {
             LayerType::Ellipsoid => true,
             LayerType::Heightmaps => true,
             LayerType::WaterLevel => true,
+            LayerType::Landcover => false,
         }
     }
     /// Number of samples in each dimension, per tile.
-    pub fn texture_resolution(&self) -> u32 {
+    pub(crate) fn texture_resolution(&self) -> u32 {
         match *self {
             LayerType::BaseHeightmaps => 521,
             LayerType::Displacements => 65,
@@ -221,10 +227,11 @@ impl LayerType {
             LayerType::Ellipsoid => 65,
             LayerType::Heightmaps => 521,
             LayerType::WaterLevel => 521,
+            LayerType::Landcover => 516,
         }
     }
     /// Number of samples outside the tile on each side.
-    pub fn texture_border_size(&self) -> u32 {
+    pub(crate) fn texture_border_size(&self) -> u32 {
         match *self {
             LayerType::BaseHeightmaps => 4,
             LayerType::Displacements => 0,
@@ -241,9 +248,10 @@ impl LayerType {
             LayerType::Ellipsoid => 0,
             LayerType::Heightmaps => 4,
             LayerType::WaterLevel => 4,
+            LayerType::Landcover => 2,
         }
     }
-    pub fn texture_formats(&self) -> &'static [TextureFormat] {
+    pub(crate) fn texture_formats(&self) -> &'static [TextureFormat] {
         match *self {
             LayerType::BaseHeightmaps => &[TextureFormat::R16],
             LayerType::Displacements => &[TextureFormat::RGBA32F],
@@ -260,9 +268,28 @@ impl LayerType {
             LayerType::Ellipsoid => &[TextureFormat::RGBA32F],
             LayerType::Heightmaps => &[TextureFormat::R16],
             LayerType::WaterLevel => &[TextureFormat::R16],
+            LayerType::Landcover => &[TextureFormat::R8],
         }
     }
-    pub fn level_range(&self) -> RangeInclusive<u8> {
+    /// Block compressed format that the tile cache stores generated tiles of this layer in, if
+    /// they are compressed after generation. Tiles are still generated and read back in the
+    /// uncompressed format given by `texture_formats`.
+    pub(crate) fn compressed_format(
+        &self,
+        wgpu_features: wgpu::Features,
+    ) -> Option<wgpu::TextureFormat> {
+        if !wgpu_features.contains(wgpu::Features::TEXTURE_COMPRESSION_BC) {
+            return None;
+        }
+        match *self {
+            // Roughness is stored in the alpha channel, so albedo needs BC3's separate alpha block
+            // rather than plain BC1.
+            LayerType::AlbedoRoughness => Some(wgpu::TextureFormat::Bc3RgbaUnorm),
+            LayerType::Normals => Some(wgpu::TextureFormat::Bc5RgUnorm),
+            _ => None,
+        }
+    }
+    pub(crate) fn level_range(&self) -> RangeInclusive<u8> {
         match *self {
             LayerType::BaseHeightmaps => 0..=VNode::LEVEL_CELL_76M,
             LayerType::Displacements => 0..=VNode::LEVEL_CELL_5MM,
@@ -279,16 +306,17 @@ impl LayerType {
             LayerType::Ellipsoid => 0..=VNode::LEVEL_CELL_5MM,
             LayerType::Heightmaps => VNode::LEVEL_CELL_38M..=VNode::LEVEL_CELL_5M,
             LayerType::WaterLevel => VNode::LEVEL_CELL_76M..=VNode::LEVEL_CELL_76M,
+            LayerType::Landcover => 0..=VNode::LEVEL_CELL_76M,
         }
     }
-    pub fn min_level(&self) -> u8 {
+    pub(crate) fn min_level(&self) -> u8 {
         *self.level_range().start()
     }
-    pub fn max_level(&self) -> u8 {
+    pub(crate) fn max_level(&self) -> u8 {
         *self.level_range().end()
     }
     pub fn iter() -> impl Iterator<Item = Self> {
-        (0..=14).map(Self::from_index)
+        (0..=15).map(Self::from_index)
     }
 }
 impl<T> Index<LayerType> for VecMap<T> {
@@ -303,11 +331,13 @@ impl<T> IndexMut<LayerType> for VecMap<T> {
     }
 }
 
+/// Kind of mesh generated for each node of the quadtree.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum MeshType {
+pub enum MeshType {
     Terrain = 0,
     Grass = 1,
     TreeBillboards = 2,
+    Rocks = 3,
 }
 impl MeshType {
     pub fn bit_mask(&self) -> LayerMask {
@@ -318,6 +348,7 @@ impl MeshType {
             MeshType::Terrain => "terrain",
             MeshType::Grass => "grass",
             MeshType::TreeBillboards => "tree_billboards",
+            MeshType::Rocks => "rocks",
         }
     }
     fn from_index(i: usize) -> Self {
@@ -325,11 +356,12 @@ impl MeshType {
             0 => MeshType::Terrain,
             1 => MeshType::Grass,
             2 => MeshType::TreeBillboards,
+            3 => MeshType::Rocks,
             _ => unreachable!(),
         }
     }
     pub fn iter() -> impl Iterator<Item = Self> {
-        (0..=2).map(Self::from_index)
+        (0..=3).map(Self::from_index)
     }
 }
 impl<T> Index<MeshType> for VecMap<T> {
@@ -344,22 +376,33 @@ impl<T> IndexMut<MeshType> for VecMap<T> {
     }
 }
 
+/// Set of layers and meshes, like those that are currently valid for a node.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub(crate) struct LayerMask(NonZeroU32);
+pub struct LayerMask(NonZeroU32);
 impl LayerMask {
     const VALID: u32 = 0x80000000;
 
-    pub fn empty() -> Self {
+    pub(crate) fn empty() -> Self {
         Self(NonZeroU32::new(Self::VALID).unwrap())
     }
+    /// Whether the mask includes the layer `t`.
     pub fn contains_layer(&self, t: LayerType) -> bool {
         assert!((t as usize) < 16);
         self.0.get() & (1 << (t as usize)) != 0
     }
+    /// Whether the mask includes the mesh `t`.
     pub fn contains_mesh(&self, t: MeshType) -> bool {
         assert!((t as usize) < 8);
         self.0.get() & (1 << (t as usize + 16)) != 0
     }
+    /// Names of the layers and meshes in the mask.
+    pub fn names(&self) -> Vec<&'static str> {
+        LayerType::iter()
+            .filter(|t| self.contains_layer(*t))
+            .map(|t| t.name())
+            .chain(MeshType::iter().filter(|t| self.contains_mesh(*t)).map(|t| t.name()))
+            .collect()
+    }
 }
 impl From<LayerType> for LayerMask {
     fn from(t: LayerType) -> Self {
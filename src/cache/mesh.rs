@@ -1,5 +1,7 @@
 use crate::cache::layer::MeshType;
 use crate::gpu_state::{DrawIndexedIndirect, GpuState};
+use crate::postprocess::{TargetConfig, HDR_FORMAT};
+use crate::resources::Tracked;
 use std::mem;
 use std::{collections::HashMap, ops::Range};
 
@@ -22,10 +24,30 @@ pub(crate) struct CullMeshUniforms {
     pub(super) entries_per_node: u32,
     pub(super) base_slot: u32,
     pub(super) mesh_index: u32,
+    pub(super) view: u32,
+    /// Whether to also cull entries hidden behind the depth in the Hi-Z pyramid.
+    pub(super) occlusion_culling: u32,
+    pub(super) hiz_levels: u32,
+    /// Offset from the camera position the Hi-Z pyramid was rendered from to the current one.
+    pub(super) camera_offset: [f32; 3],
+    pub(super) _padding: f32,
+    /// View projection matrix of the frame the Hi-Z pyramid was built from.
+    pub(super) hiz_view_proj: mint::ColumnMatrix4<f32>,
 }
 unsafe impl bytemuck::Zeroable for CullMeshUniforms {}
 unsafe impl bytemuck::Pod for CullMeshUniforms {}
 
+/// Selects which set of nodes is drawn by the meshes after culling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CullView {
+    /// Nodes selected for the main view.
+    Main = 0,
+    /// Coarser nodes used for rendering the shadow map.
+    Shadows = 1,
+    /// Coarse terrain only, for rendering reflection probes.
+    Probe = 2,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 struct MeshNodeState {
@@ -46,6 +68,9 @@ pub(crate) struct MeshCacheDesc {
     pub index_buffer: Vec<u32>,
     pub render: rshader::ShaderSet,
     pub render_shadow: Option<rshader::ShaderSet>,
+    pub render_wireframe: Option<rshader::ShaderSet>,
+    /// Variant of `render` used when a `DebugView` is selected.
+    pub render_debug: Option<rshader::ShaderSet>,
     pub cull_mode: Option<wgpu::Face>,
     pub render_overlapping_levels: bool,
     pub entries_per_node: usize,
@@ -62,8 +87,12 @@ pub(crate) struct MeshCache {
 
     index_buffer_range: Range<u64>,
 
-    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
-    shadow_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    shadow_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    wireframe_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    debug_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    /// Attachments that the render, debug and wireframe pipelines were built for.
+    target_config: TargetConfig,
 }
 impl MeshCache {
     pub(super) fn new(
@@ -78,70 +107,110 @@ impl MeshCache {
             num_entries: num_slots,
             bindgroup_pipeline: None,
             shadow_bindgroup_pipeline: None,
+            wireframe_bindgroup_pipeline: None,
+            debug_bindgroup_pipeline: None,
+            target_config: TargetConfig::default(),
             index_buffer_range,
         }
     }
 
-    pub fn update(&mut self, device: &wgpu::Device, gpu_state: &GpuState) {
+    fn build_render_pipeline(
+        &self,
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        shader: &rshader::ShaderSet,
+        name: &str,
+    ) -> (Tracked<wgpu::BindGroup>, wgpu::RenderPipeline) {
+        let (bind_group, bind_group_layout) =
+            gpu_state.bind_group_for_shader(device, shader, HashMap::new(), HashMap::new(), name);
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+                label: Some(&format!("{}.pipeline_layout", name)),
+            });
+        (
+            bind_group,
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some(&format!("shader.{}.vertex", name)),
+                        source: shader.vertex(),
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some(&format!("shader.{}.fragment", name)),
+                        source: shader.fragment(),
+                    }),
+                    entry_point: "main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: HDR_FORMAT,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    cull_mode: self.desc.cull_mode,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_write_enabled: true,
+                    depth_compare: self.target_config.depth_compare(false),
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: self.target_config.multisample(),
+                multiview: None,
+                label: Some(&format!("pipeline.render.{}", name)),
+            }),
+        )
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        target_config: TargetConfig,
+    ) {
+        if self.target_config != target_config {
+            self.target_config = target_config;
+            self.bindgroup_pipeline = None;
+            self.wireframe_bindgroup_pipeline = None;
+            self.debug_bindgroup_pipeline = None;
+        }
         if self.desc.render.refresh() {
             self.bindgroup_pipeline = None;
         }
         if self.bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            self.bindgroup_pipeline = Some(self.build_render_pipeline(
                 device,
+                gpu_state,
                 &self.desc.render,
-                HashMap::new(),
-                HashMap::new(),
                 self.desc.ty.name(),
-            );
-            let render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: &[&bind_group_layout],
-                    push_constant_ranges: &[],
-                    label: Some(&format!("{}.pipeline_layout", self.desc.ty.name())),
-                });
-            self.bindgroup_pipeline = Some((
-                bind_group,
-                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    layout: Some(&render_pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                            label: Some(&format!("shader.{}.vertex", self.desc.ty.name())),
-                            source: self.desc.render.vertex(),
-                        }),
-                        entry_point: "main",
-                        buffers: &[],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                            label: Some(&format!("shader.{}.fragment", self.desc.ty.name())),
-                            source: self.desc.render.fragment(),
-                        }),
-                        entry_point: "main",
-                        targets: &[Some(wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrites::ALL,
-                        })],
-                    }),
-                    primitive: wgpu::PrimitiveState {
-                        cull_mode: self.desc.cull_mode,
-                        ..Default::default()
-                    },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Greater,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
-                    multiview: None,
-                    label: Some(&format!("pipeline.render.{}", self.desc.ty.name())),
-                }),
+            ));
+        }
+
+        if let Some(ref mut render_debug) = self.desc.render_debug {
+            if render_debug.refresh() {
+                self.debug_bindgroup_pipeline = None;
+            }
+        }
+        if let (None, Some(render_debug)) =
+            (&self.debug_bindgroup_pipeline, &self.desc.render_debug)
+        {
+            self.debug_bindgroup_pipeline = Some(self.build_render_pipeline(
+                device,
+                gpu_state,
+                render_debug,
+                &format!("{}_debug", self.desc.ty.name()),
             ));
         }
 
@@ -211,36 +280,126 @@ impl MeshCache {
                 ));
             }
         }
+
+        if let Some(ref mut render_wireframe) = self.desc.render_wireframe {
+            if render_wireframe.refresh() {
+                self.wireframe_bindgroup_pipeline = None;
+            }
+            if self.wireframe_bindgroup_pipeline.is_none()
+                && device.features().contains(wgpu::Features::POLYGON_MODE_LINE)
+            {
+                let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                    device,
+                    &render_wireframe,
+                    HashMap::new(),
+                    HashMap::new(),
+                    &format!("{}_wireframe", self.desc.ty.name()),
+                );
+                let render_pipeline_layout =
+                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: &[&bind_group_layout],
+                        push_constant_ranges: &[],
+                        label: Some(&format!("{}_wireframe.pipeline_layout", self.desc.ty.name())),
+                    });
+                self.wireframe_bindgroup_pipeline = Some((
+                    bind_group,
+                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        layout: Some(&render_pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                                label: Some(&format!(
+                                    "shader.{}_wireframe.vertex",
+                                    self.desc.ty.name()
+                                )),
+                                source: render_wireframe.vertex(),
+                            }),
+                            entry_point: "main",
+                            buffers: &[],
+                        },
+                        fragment: Some(wgpu::FragmentState {
+                            module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                                label: Some(&format!(
+                                    "shader.{}_wireframe.fragment",
+                                    self.desc.ty.name()
+                                )),
+                                source: render_wireframe.fragment(),
+                            }),
+                            entry_point: "main",
+                            targets: &[Some(wgpu::ColorTargetState {
+                                format: HDR_FORMAT,
+                                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                                write_mask: wgpu::ColorWrites::ALL,
+                            })],
+                        }),
+                        primitive: wgpu::PrimitiveState {
+                            cull_mode: self.desc.cull_mode,
+                            polygon_mode: wgpu::PolygonMode::Line,
+                            ..Default::default()
+                        },
+                        depth_stencil: Some(wgpu::DepthStencilState {
+                            format: wgpu::TextureFormat::Depth32Float,
+                            depth_write_enabled: false,
+                            depth_compare: self.target_config.depth_compare(true),
+                            bias: Default::default(),
+                            stencil: Default::default(),
+                        }),
+                        multisample: self.target_config.multisample(),
+                        multiview: None,
+                        label: Some(&format!("pipeline.render.{}_wireframe", self.desc.ty.name())),
+                    }),
+                ));
+            }
+        }
     }
 
-    pub fn render<'a>(
-        &'a self,
+    /// Issue the draws that survived culling. Devices that can read the draw count from a buffer
+    /// only process the compacted draws, others skip over the culled entries.
+    fn draw_indirect<'a>(
+        &self,
         device: &wgpu::Device,
         rpass: &mut wgpu::RenderPass<'a>,
         gpu_state: &'a GpuState,
     ) {
-        rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
-        rpass.set_index_buffer(
-            gpu_state.mesh_index.slice(self.index_buffer_range.clone()),
-            wgpu::IndexFormat::Uint32,
-        );
-        rpass.set_bind_group(0, &self.bindgroup_pipeline.as_ref().unwrap().0, &[]);
-        if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+        let offset = (self.base_entry * mem::size_of::<DrawIndexedIndirect>()) as u64;
+        if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT_COUNT) {
+            rpass.multi_draw_indexed_indirect_count(
+                &gpu_state.mesh_indirect_compacted,
+                offset,
+                &gpu_state.mesh_draw_count,
+                (self.desc.ty as usize * mem::size_of::<u32>()) as u64,
+                self.num_entries as u32,
+            );
+        } else if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
             rpass.multi_draw_indexed_indirect(
                 &gpu_state.mesh_indirect,
-                (self.base_entry * mem::size_of::<DrawIndexedIndirect>()) as u64,
+                offset,
                 self.num_entries as u32,
             );
         } else {
             for i in 0..self.num_entries {
                 rpass.draw_indexed_indirect(
                     &gpu_state.mesh_indirect,
-                    ((self.base_entry + i) * mem::size_of::<DrawIndexedIndirect>()) as u64,
+                    offset + (i * mem::size_of::<DrawIndexedIndirect>()) as u64,
                 );
             }
         }
     }
 
+    pub fn render<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
+        rpass.set_index_buffer(
+            gpu_state.mesh_index.slice(self.index_buffer_range.clone()),
+            wgpu::IndexFormat::Uint32,
+        );
+        rpass.set_bind_group(0, &self.bindgroup_pipeline.as_ref().unwrap().0, &[]);
+        self.draw_indirect(device, rpass, gpu_state);
+    }
+
     pub fn render_shadow<'a>(
         &'a self,
         device: &wgpu::Device,
@@ -254,20 +413,42 @@ impl MeshCache {
                 wgpu::IndexFormat::Uint32,
             );
             rpass.set_bind_group(0, &self.shadow_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
-                rpass.multi_draw_indexed_indirect(
-                    &gpu_state.mesh_indirect,
-                    (self.base_entry * mem::size_of::<DrawIndexedIndirect>()) as u64,
-                    self.num_entries as u32,
-                );
-            } else {
-                for i in 0..self.num_entries {
-                    rpass.draw_indexed_indirect(
-                        &gpu_state.mesh_indirect,
-                        ((self.base_entry + i) * mem::size_of::<DrawIndexedIndirect>()) as u64,
-                    );
-                }
-            }
+            self.draw_indirect(device, rpass, gpu_state);
+        }
+    }
+
+    /// Render with the debug variant of the shader, if there is one.
+    pub fn render_debug<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        if let Some((ref bind_group, ref pipeline)) = self.debug_bindgroup_pipeline {
+            rpass.set_pipeline(pipeline);
+            rpass.set_index_buffer(
+                gpu_state.mesh_index.slice(self.index_buffer_range.clone()),
+                wgpu::IndexFormat::Uint32,
+            );
+            rpass.set_bind_group(0, bind_group, &[]);
+            self.draw_indirect(device, rpass, gpu_state);
+        }
+    }
+
+    pub fn render_wireframe<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        if let Some((ref bind_group, ref pipeline)) = self.wireframe_bindgroup_pipeline {
+            rpass.set_pipeline(pipeline);
+            rpass.set_index_buffer(
+                gpu_state.mesh_index.slice(self.index_buffer_range.clone()),
+                wgpu::IndexFormat::Uint32,
+            );
+            rpass.set_bind_group(0, bind_group, &[]);
+            self.draw_indirect(device, rpass, gpu_state);
         }
     }
 }
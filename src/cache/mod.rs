@@ -1,31 +1,72 @@
+pub(crate) mod compress;
+pub(crate) mod debug;
+pub(crate) mod deformation;
+pub(crate) mod erosion;
+pub(crate) mod events;
 pub(crate) mod generators;
+pub(crate) mod heightfield;
 pub(crate) mod layer;
 mod mesh;
+pub(crate) mod navmesh;
+pub(crate) mod path;
+pub(crate) mod raycast;
+pub(crate) mod region;
+mod scheduling;
+pub(crate) mod snow;
+pub(crate) mod splatting;
 mod tile;
+mod uniforms;
+pub(crate) mod validation;
 
-pub(crate) use crate::cache::mesh::{MeshCache, MeshCacheDesc};
-use crate::stream::TileStreamerEndpoint;
+pub(crate) use crate::cache::mesh::{CullView, MeshCache, MeshCacheDesc};
+use crate::stream::{TileSource, TileStreamerEndpoint};
 use crate::{
-    cache::tile::NodeSlot, compute_shader::ComputeShader, gpu_state::GpuState, mapfile::MapFile,
+    cache::{
+        tile::{NodeSlot, Scratch},
+        uniforms::GenerateUniforms,
+    },
+    compute_shader::ComputeShader,
+    flat::FlatMap,
+    gpu_state::GpuState,
+    hiz::{OcclusionTest, HIZ_MIP_LEVELS},
+    mapfile::MapFile,
+    postprocess::TargetConfig,
+    procedural::ProceduralPlanet,
+    profiler::{GpuProfiler, PassTiming},
+    resources::Tracked,
+    telemetry,
 };
-use cgmath::Vector3;
+use anyhow::Error;
+use cgmath::{InnerSpace, SquareMatrix, Vector3};
 use fnv::FnvHashMap;
 use maplit::hashmap;
+use serde::{Deserialize, Serialize};
 use std::cmp::Eq;
 use std::hash::Hash;
 use std::num::NonZeroU64;
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::HashMap, num::NonZeroU32};
-use terra_types::{Priority, VNode, MAX_QUADTREE_LEVEL, NODE_OFFSETS};
+use terra_types::{Priority, VNode, MAX_QUADTREE_LEVEL, MAX_TERRAIN_HEIGHT, NODE_OFFSETS};
 use vec_map::VecMap;
 use wgpu::util::DeviceExt;
 
-use self::layer::{LayerMask, LayerType};
+use self::debug::BoundsOverlay;
+use self::deformation::{Deformation, Deformations};
+use self::erosion::Erosion;
+use self::events::{NodeEvent, NodeEventKind, TileEvent, TileEventSenders};
+use self::generators::GenerateTile;
+use self::layer::{LayerMask, LayerType, MeshType};
+use self::region::{Inset, Region};
+use self::scheduling::GeneratorCosts;
+use self::snow::{SnowLine, SnowLineUniformBlock};
+pub(crate) use self::tile::CpuHeightmap;
 use self::tile::Entry;
+use self::validation::{ValidationIssue, Validator};
 use self::{generators::DynamicGenerator, mesh::CullMeshUniforms};
-use self::{generators::GenerateTile, tile::CpuHeightmap};
 
-const SLOTS_PER_LEVEL: usize = 30;
+pub(crate) const SLOTS_PER_LEVEL: usize = 30;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub(crate) struct GeneratorMask(NonZeroU32);
@@ -92,8 +133,11 @@ impl<T: PriorityCacheEntry> PriorityCache<T> {
     pub fn new(size: usize) -> Self {
         Self { size, slots: Vec::new(), reverse: HashMap::new() }
     }
-    pub fn insert(&mut self, mut entries: Vec<T>) {
+    /// Insert entries, evicting lower priority ones if the cache is full. Returns the evicted
+    /// entries.
+    pub fn insert(&mut self, mut entries: Vec<T>) -> Vec<T> {
         entries.sort_by_key(T::priority);
+        let mut evicted = Vec::new();
 
         // Add tiles until all cache entries are full.
         while !entries.is_empty() && self.slots.len() < self.size {
@@ -128,18 +172,33 @@ impl<T: PriorityCacheEntry> PriorityCache<T> {
 
                 self.reverse.remove(&self.slots[index].key());
                 self.reverse.insert(e.key(), index);
-                self.slots[index] = e;
+                evicted.push(std::mem::replace(&mut self.slots[index], e));
                 index += 1;
                 if index == self.slots.len() {
                     break;
                 }
             }
         }
+        evicted
+    }
+
+    /// Change the number of entries the cache can hold. Returns the entries evicted because they
+    /// no longer fit, regardless of their priority.
+    pub fn set_capacity(&mut self, size: usize) -> Vec<T> {
+        self.size = size;
+        let evicted = self.slots.split_off(size.min(self.slots.len()));
+        for entry in &evicted {
+            self.reverse.remove(&entry.key());
+        }
+        evicted
     }
 
     pub fn is_full(&self) -> bool {
         self.slots.len() == self.size
     }
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
     pub fn contains(&self, key: &T::Key) -> bool {
         self.reverse.contains_key(key)
     }
@@ -200,7 +259,9 @@ impl Levels {
             .unwrap_or(false)
     }
 
-    fn update(&mut self, node_priorities: FnvHashMap<VNode, Priority>) {
+    /// Update the priorities of all entries and insert any missing nodes that are needed.
+    /// Returns the entries that were evicted to make room.
+    fn update(&mut self, node_priorities: FnvHashMap<VNode, Priority>) -> Vec<Entry> {
         let mut min_priorities = Vec::new();
         for cache in &mut self.0 {
             for entry in cache.slots_mut() {
@@ -227,9 +288,11 @@ impl Levels {
             node.level() < MAX_QUADTREE_LEVEL
         });
 
+        let mut evicted = Vec::new();
         for (cache, missing) in self.0.iter_mut().zip(missing.into_iter()) {
-            cache.insert(missing);
+            evicted.extend(cache.insert(missing));
         }
+        evicted
     }
 
     fn generator_dependencies(&self, node: VNode, mask: LayerMask) -> GeneratorMask {
@@ -248,20 +311,549 @@ impl Levels {
     }
 }
 
+/// Snapshot of the state of the tile cache, used to monitor long running sessions.
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    /// Number of occupied cache slots at each level of the quadtree.
+    pub level_occupancy: Vec<usize>,
+    /// Number of cache slots available at each level of the quadtree.
+    pub level_capacity: Vec<usize>,
+    /// Number of cached nodes that are needed for rendering but are still missing some layers.
+    pub pending_tiles: usize,
+    /// Number of tiles requested from the streamer that haven't arrived yet.
+    pub streaming_inflight: usize,
+    /// Number of heightmaps currently being read back from the GPU.
+    pub heightmap_downloads_inflight: usize,
+    /// Tiles received from the streamer since the terrain was created.
+    pub tiles_streamed: u64,
+    /// Tiles generated on the GPU since the terrain was created, counting each generator that
+    /// ran for a tile separately.
+    pub tiles_generated: u64,
+    /// Time the GPU spent on each pass of the most recent frame whose timings have been read
+    /// back, which lags a few frames behind. Passes are reported in the order they ran: tile
+    /// generation from `Terrain::update`, followed by dynamic generators, mesh culling, the sky
+    /// view, the main render pass and post-processing from `Terrain::render`. Empty unless the
+    /// device was created with `wgpu::Features::TIMESTAMP_QUERY`.
+    pub gpu_timings: Vec<PassTiming>,
+    /// Expected GPU time for each tile generator to generate a single tile, which decides how
+    /// many tiles it gets to generate per frame. Only generators that have been measured are
+    /// listed, so this is also empty without `wgpu::Features::TIMESTAMP_QUERY`.
+    pub generator_costs: Vec<PassTiming>,
+}
+
+/// Terrain node selected for rendering in the current frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VisibleNode {
+    /// Node of the quadtree, which identifies the tiles it draws and gives its level and
+    /// position on its face of the cube.
+    pub node: VNode,
+    /// Bitmask of the quadrants this node draws, indexed like its children. Quadrants that are
+    /// missing are drawn by more detailed nodes instead.
+    pub quadrants: u8,
+    /// Range of terrain heights within the node, in meters above sea level.
+    pub height_range: (f32, f32),
+    /// Center of a sphere enclosing the node, in ECEF coordinates.
+    pub bounding_center: mint::Point3<f64>,
+    /// Radius of a sphere enclosing the node, in meters.
+    pub bounding_radius: f64,
+    /// Layers and meshes that are currently valid for the node.
+    pub valid_layers: LayerMask,
+}
+impl VisibleNode {
+    /// Whether some of this node is drawn by more detailed nodes.
+    pub fn is_partial(&self) -> bool {
+        self.quadrants != 15
+    }
+}
+
+/// Quality target for level of detail selection. Tiles are refined until their geometric error,
+/// projected onto the screen, is below `max_pixel_error`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LodTarget {
+    /// Height of the viewport in pixels.
+    pub viewport_height: u32,
+    /// Largest tolerated error in pixels. Smaller values load more detail.
+    pub max_pixel_error: f32,
+    /// Optionally tolerate more error away from where the user is looking.
+    pub foveation: Option<Foveation>,
+    /// Largest tolerated error in pixels for meshes drawn into the shadow map. When set to more
+    /// than `max_pixel_error`, shadows are rendered from a coarser subset of the tiles loaded for
+    /// the main view, so no additional tiles need to be resident.
+    pub shadow_max_pixel_error: Option<f32>,
+    /// Largest tolerated error in pixels, relative to the main view, for terrain drawn into
+    /// reflection probes. Probes only use tiles already loaded for the main view.
+    pub probe_max_pixel_error: f32,
+}
+impl Default for LodTarget {
+    fn default() -> Self {
+        Self {
+            viewport_height: 1080,
+            max_pixel_error: 0.25,
+            foveation: None,
+            shadow_max_pixel_error: None,
+            probe_max_pixel_error: 4.0,
+        }
+    }
+}
+impl LodTarget {
+    /// Size of a one meter error seen from one meter away, relative to the tolerated error, for a
+    /// projection with the given focal length (the cotangent of half the vertical field of view).
+    pub(crate) fn error_scale(&self, focal_length: f64) -> f64 {
+        0.5 * self.viewport_height as f64 * focal_length / self.max_pixel_error as f64
+    }
+
+    /// Minimum priority of nodes used for shadows. Priorities scale with the square of the
+    /// inverse tolerated error, so a node meets the shadow target if its priority is at least
+    /// the squared ratio between the two error targets.
+    pub(crate) fn shadow_priority_cutoff(&self) -> Option<Priority> {
+        self.shadow_max_pixel_error
+            .map(|e| Priority::from_f32((e / self.max_pixel_error).powi(2).max(1.0)))
+    }
+
+    /// Minimum priority of nodes used for reflection probes, computed like the shadow cutoff.
+    pub(crate) fn probe_priority_cutoff(&self) -> Priority {
+        Priority::from_f32((self.probe_max_pixel_error / self.max_pixel_error).powi(2).max(1.0))
+    }
+}
+
+/// Parts of the terrain whose maximum level of detail can be limited separately.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DetailLayer {
+    /// Surface color and roughness. Deeper nodes reuse the most detailed tile available.
+    Albedo,
+    /// Surface normals. Deeper nodes reuse the most detailed tile available.
+    Normals,
+    /// Grass. This is only generated at a single level, so lower limits disable it entirely.
+    Grass,
+    /// Tree billboards. These are only generated at a single level, so lower limits disable them
+    /// entirely.
+    Trees,
+    /// Scattered rocks, boulders and bushes. These are only generated at a single level, so lower
+    /// limits disable them entirely.
+    Rocks,
+}
+impl DetailLayer {
+    fn layers(&self) -> LayerMask {
+        match *self {
+            DetailLayer::Albedo => LayerType::AlbedoRoughness.bit_mask(),
+            DetailLayer::Normals => LayerType::Normals.bit_mask(),
+            DetailLayer::Grass => LayerType::GrassCanopy.bit_mask() | MeshType::Grass.bit_mask(),
+            DetailLayer::Trees => MeshType::TreeBillboards.bit_mask(),
+            DetailLayer::Rocks => MeshType::Rocks.bit_mask(),
+        }
+    }
+}
+
+/// How much effort to spend on the aerial perspective, which is recomputed for every visible node
+/// as the camera moves.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AerialPerspectiveQuality {
+    /// 9x9 textures per node, with distant nodes only updated every eighth frame and all others
+    /// every other frame. Suited to mobile-class GPUs.
+    Low,
+    /// 17x17 textures per node, with distant nodes updated every other frame.
+    #[default]
+    Medium,
+    /// 33x33 textures per node, with every node updated every frame. Gives smoother gradients
+    /// across large nodes.
+    High,
+}
+impl AerialPerspectiveQuality {
+    /// Deepest level whose nodes count as distant when deciding how often to update them.
+    const DISTANT_MAX_LEVEL: u8 = VNode::LEVEL_SIDE_156KM;
+
+    /// Resolution of the tiles of `layer`, which for the aerial perspective layers depends on the
+    /// quality.
+    pub(crate) fn texture_resolution(&self, layer: LayerType) -> u32 {
+        let base = layer.texture_resolution();
+        match layer {
+            LayerType::AerialPerspective | LayerType::RootAerialPerspective => match *self {
+                AerialPerspectiveQuality::Low => (base - 1) / 2 + 1,
+                AerialPerspectiveQuality::Medium => base,
+                AerialPerspectiveQuality::High => (base - 1) * 2 + 1,
+            },
+            _ => base,
+        }
+    }
+
+    /// Number of frames between updates of the aerial perspective of nodes at `level`.
+    pub(crate) fn update_interval(&self, level: u8) -> u64 {
+        let distant = level <= Self::DISTANT_MAX_LEVEL;
+        match *self {
+            AerialPerspectiveQuality::Low if distant => 8,
+            AerialPerspectiveQuality::Low => 2,
+            AerialPerspectiveQuality::Medium if distant => 2,
+            AerialPerspectiveQuality::Medium | AerialPerspectiveQuality::High => 1,
+        }
+    }
+}
+
+/// Presets that trade visual quality for performance, by adjusting several settings at once.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TerrainQuality {
+    /// Coarse terrain without grass or trees, suited to integrated and mobile-class GPUs.
+    Low,
+    /// Trees but no grass, with smaller tile caches than `High`.
+    Medium,
+    /// Everything enabled, with slightly coarser shadows and detail than `Ultra`.
+    High,
+    /// The most detailed terrain and shadows, and the highest quality aerial perspective.
+    Ultra,
+}
+impl TerrainQuality {
+    /// Number of cache slots for each level of the quadtree from level 2 down. Levels 0 and 1
+    /// always hold every node.
+    pub(crate) fn slots_per_level(&self) -> usize {
+        match *self {
+            TerrainQuality::Low => 12,
+            TerrainQuality::Medium => 20,
+            TerrainQuality::High | TerrainQuality::Ultra => SLOTS_PER_LEVEL,
+        }
+    }
+
+    /// Deepest quadtree level to load tiles for.
+    pub(crate) fn max_level(&self) -> u8 {
+        match *self {
+            TerrainQuality::Low => VNode::LEVEL_CELL_5M,
+            TerrainQuality::Medium => VNode::LEVEL_CELL_30CM,
+            TerrainQuality::High => VNode::LEVEL_CELL_1CM,
+            TerrainQuality::Ultra => MAX_QUADTREE_LEVEL,
+        }
+    }
+
+    /// Layers that are disabled entirely.
+    pub(crate) fn disabled_layers(&self) -> &'static [DetailLayer] {
+        match *self {
+            TerrainQuality::Low => &[DetailLayer::Grass, DetailLayer::Trees],
+            TerrainQuality::Medium => &[DetailLayer::Grass],
+            TerrainQuality::High | TerrainQuality::Ultra => &[],
+        }
+    }
+
+    /// Largest tolerated error in pixels for meshes drawn into the shadow map. See
+    /// `LodTarget::shadow_max_pixel_error`.
+    pub(crate) fn shadow_max_pixel_error(&self) -> Option<f32> {
+        match *self {
+            TerrainQuality::Low => Some(4.0),
+            TerrainQuality::Medium => Some(2.0),
+            TerrainQuality::High => Some(1.0),
+            TerrainQuality::Ultra => None,
+        }
+    }
+
+    pub(crate) fn aerial_perspective_quality(&self) -> AerialPerspectiveQuality {
+        match *self {
+            TerrainQuality::Low => AerialPerspectiveQuality::Low,
+            TerrainQuality::Medium | TerrainQuality::High => AerialPerspectiveQuality::Medium,
+            TerrainQuality::Ultra => AerialPerspectiveQuality::High,
+        }
+    }
+}
+
+/// Settings from the `TerrainBuilder` that the tile cache is created with.
+pub(crate) struct TileCacheOptions {
+    pub detail_limits: DetailLimits,
+    pub aerial_perspective_quality: AerialPerspectiveQuality,
+    pub region: Option<Region>,
+    pub insets: Vec<Inset>,
+    pub flat_map: Option<Arc<FlatMap>>,
+    pub procedural_planet: Option<ProceduralPlanet>,
+}
+
+/// Limits on the quadtree levels that tiles are loaded for.
+#[derive(Clone, Debug)]
+pub(crate) struct DetailLimits {
+    pub max_level: u8,
+    pub layer_max_levels: Vec<(DetailLayer, u8)>,
+}
+impl Default for DetailLimits {
+    fn default() -> Self {
+        Self { max_level: MAX_QUADTREE_LEVEL, layer_max_levels: Vec::new() }
+    }
+}
+impl DetailLimits {
+    /// Deepest level at which tiles of the layers in `mask` may be loaded.
+    fn max_level(&self, mask: LayerMask) -> u8 {
+        self.layer_max_levels
+            .iter()
+            .filter(|(layer, _)| layer.layers() & mask != LayerMask::empty())
+            .map(|&(_, level)| level)
+            .fold(self.max_level, u8::min)
+    }
+
+    /// Deepest level at which tiles of `layer` are produced.
+    fn layer_max_level(&self, layer: LayerType) -> u8 {
+        layer.max_level().min(self.max_level(layer.into()))
+    }
+
+    /// Copy of these limits with the maximum level raised to that of the deepest inset, but no
+    /// further than `ceiling`.
+    fn with_insets(&self, insets: &[Inset], ceiling: u8) -> Self {
+        let max_level = insets.iter().map(Inset::max_level).fold(self.max_level, u8::max);
+        Self { max_level: max_level.min(ceiling), ..self.clone() }
+    }
+}
+
+/// Reductions applied on devices that fall short of the usual limits, such as integrated GPUs or
+/// the GL backend, so that they can still run Terra at lower quality.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Downlevel {
+    /// Deepest level that tiles are loaded for. Chosen so that the tile cache textures fit within
+    /// the device's limit on array layers, which also leaves out layers only used at finer levels.
+    pub max_level: u8,
+    /// Most tiles that a generator may produce per frame.
+    pub tiles_per_frame: usize,
+    /// Most tiles that a generator processes in a single compute pass.
+    pub tiles_per_pass: usize,
+}
+impl Downlevel {
+    /// Returns the reductions needed to run on `device`, or `None` if it supports full quality.
+    pub fn detect(device: &wgpu::Device) -> Option<Self> {
+        let max_layers = device.limits().max_texture_array_layers as usize;
+        if max_layers >= Levels::base_slot(MAX_QUADTREE_LEVEL + 1) {
+            return None;
+        }
+
+        let max_level = (0..=MAX_QUADTREE_LEVEL)
+            .take_while(|&level| Levels::base_slot(level + 1) <= max_layers)
+            .last()
+            .unwrap_or(0);
+        Some(Self { max_level, tiles_per_frame: 8, tiles_per_pass: 4 })
+    }
+}
+
+/// Reduces the level of detail away from where the user is looking, which is mainly useful for VR
+/// headsets. Uses eye tracking if a gaze direction is provided, and otherwise applies a fixed
+/// foveation centered on the view direction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Foveation {
+    /// Direction the user is looking in world space, or `None` to use the center of the view.
+    pub gaze_direction: Option<mint::Vector3<f64>>,
+    /// Angle around the gaze direction that keeps full detail, in degrees.
+    pub inner_angle: f32,
+    /// How much the tolerated error grows for each degree beyond `inner_angle`.
+    pub falloff: f32,
+    /// Largest factor by which the tolerated error may grow in the periphery.
+    pub max_scale: f32,
+}
+impl Default for Foveation {
+    fn default() -> Self {
+        Self { gaze_direction: None, inner_angle: 10.0, falloff: 0.1, max_scale: 4.0 }
+    }
+}
+impl Foveation {
+    /// Factor by which the tolerated error grows for content `angle` degrees from the gaze.
+    fn tolerance_scale(&self, angle: f32) -> f32 {
+        (1.0 + (angle - self.inner_angle).max(0.0) * self.falloff).clamp(1.0, self.max_scale)
+    }
+}
+
+/// Additional camera whose surroundings should be loaded alongside the main view, such as the
+/// second player of a split-screen game or a remote player.
+///
+/// Tiles are loaded for every viewer, but `Terrain::render` only draws the nodes selected for the
+/// camera passed to `Terrain::update`. Node positions are also stored relative to that camera, so
+/// rendering from another viewer with `Terrain::render` requires a view projection relative to
+/// it, and shows the level of detail chosen for the main camera. `Terrain::render_view` instead
+/// takes a viewer directly and draws the nodes selected for it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Viewer {
+    pub view_proj: mint::ColumnMatrix4<f32>,
+    pub position: mint::Point3<f64>,
+    pub lod_target: LodTarget,
+}
+
+/// Position the terrain is viewed from, along with the level of detail needed there.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Viewpoint {
+    pub position: mint::Point3<f64>,
+    /// See `LodTarget::error_scale`.
+    pub error_scale: f64,
+    /// Foveation settings along with the normalized gaze direction.
+    pub foveation: Option<(Foveation, Vector3<f64>)>,
+    /// Distance beyond which no terrain can be visible due to the curvature of the planet.
+    pub max_distance: f64,
+}
+impl Viewpoint {
+    pub fn new(
+        view_proj: mint::ColumnMatrix4<f32>,
+        position: mint::Point3<f64>,
+        target: &LodTarget,
+    ) -> Self {
+        // The view projection is a perspective projection applied after a rotation, so the length
+        // of the rotated Y axis recovers the focal length of the projection. Before any view
+        // projection is known, assume a 90 degree field of view.
+        let focal_length =
+            Vector3::new(view_proj.x.y, view_proj.y.y, view_proj.z.y).magnitude() as f64;
+        let focal_length = if focal_length > 0.0 { focal_length } else { 1.0 };
+
+        let foveation = target.foveation.map(|foveation| {
+            // Without eye tracking, the gaze is fixed to the view direction which is recovered
+            // from the row of the projection that computes clip space W.
+            let gaze = match foveation.gaze_direction {
+                Some(gaze) => Vector3::from(gaze),
+                None => {
+                    Vector3::new(view_proj.x.w, view_proj.y.w, view_proj.z.w).cast::<f64>().unwrap()
+                }
+            };
+            (foveation, gaze.normalize())
+        });
+
+        let altitude = terra_types::altitude(Vector3::new(position.x, position.y, position.z));
+        let max_distance = terra_types::max_visible_distance(altitude);
+
+        Self { position, error_scale: target.error_scale(focal_length), foveation, max_distance }
+    }
+
+    /// Whether priorities computed for this viewpoint are out of date for `other`. Tiny changes
+    /// from rounding or small eye movements are ignored, since they would otherwise force
+    /// priorities to be recomputed every frame.
+    fn differs_from(&self, other: &Viewpoint) -> bool {
+        let foveation_changed = match (self.foveation, other.foveation) {
+            (Some((old, old_gaze)), Some((new, new_gaze))) => {
+                old != new || old_gaze.dot(new_gaze) < 0.5f64.to_radians().cos()
+            }
+            (None, None) => false,
+            _ => true,
+        };
+        self.position != other.position
+            || (other.error_scale / self.error_scale - 1.0).abs() > 1e-3
+            || foveation_changed
+    }
+
+    /// Priority of `node` as seen from this viewpoint, with the tolerated error divided by
+    /// `detail_scale`.
+    fn priority(
+        &self,
+        node: VNode,
+        height_range: (f32, f32),
+        geometric_error: Option<f32>,
+        detail_scale: f64,
+    ) -> Priority {
+        let camera = Vector3::new(self.position.x, self.position.y, self.position.z);
+        if node.level() > 0
+            && (node.below_horizon(camera, height_range)
+                || node.distance2(camera, height_range) > self.max_distance * self.max_distance)
+        {
+            return Priority::none();
+        }
+
+        let error_scale = match self.foveation {
+            Some((foveation, gaze)) => {
+                let angle = angle_from_gaze(node, height_range, camera, gaze);
+                self.error_scale / foveation.tolerance_scale(angle) as f64
+            }
+            None => self.error_scale,
+        } * detail_scale;
+        node.screen_space_priority(camera, height_range, geometric_error, error_scale)
+    }
+}
+
+/// Smallest angle in degrees between `gaze` and the direction from `camera` to any part of `node`.
+fn angle_from_gaze(
+    node: VNode,
+    height_range: (f32, f32),
+    camera: Vector3<f64>,
+    gaze: Vector3<f64>,
+) -> f32 {
+    let mid_height = (height_range.0 + height_range.1) as f64 * 0.5;
+    let offset = node.center_wspace() + node.center_wspace().normalize() * mid_height - camera;
+    let distance = offset.magnitude();
+    let radius = node.aprox_side_length() as f64 * 0.75
+        + (height_range.1 - height_range.0).abs() as f64 * 0.5;
+    if distance <= radius {
+        return 0.0;
+    }
+
+    let angle = (offset.dot(gaze) / distance).clamp(-1.0, 1.0).acos();
+    (angle - (radius / distance).asin()).max(0.0).to_degrees() as f32
+}
+
 pub(crate) struct TileCache {
     levels: Levels,
     level_masks: Vec<LayerMask>,
 
     meshes: VecMap<MeshCache>,
     generators: Vec<Box<dyn GenerateTile>>,
+    /// Measured GPU time of each generator, used to fit generation into its budget.
+    generator_costs: GeneratorCosts,
     dynamic_generators: Vec<DynamicGenerator>,
 
     streamer: TileStreamerEndpoint,
-    completed_downloads_tx: crossbeam::channel::Sender<(VNode, wgpu::Buffer, CpuHeightmap)>,
-    completed_downloads_rx: crossbeam::channel::Receiver<(VNode, wgpu::Buffer, CpuHeightmap)>,
-    free_download_buffers: Vec<wgpu::Buffer>,
+    completed_downloads_tx:
+        crossbeam::channel::Sender<(VNode, Tracked<wgpu::Buffer>, CpuHeightmap)>,
+    completed_downloads_rx:
+        crossbeam::channel::Receiver<(VNode, Tracked<wgpu::Buffer>, CpuHeightmap)>,
+    free_download_buffers: Vec<Tracked<wgpu::Buffer>>,
     total_download_buffers: usize,
-    last_camera_position: Option<mint::Point3<f64>>,
+    /// Totals reported by `statistics`.
+    tiles_streamed: u64,
+    tiles_generated: u64,
+    /// Incremented whenever a CPU heightmap becomes available or the deformations change, so
+    /// that anything sampling heights on the CPU can tell when they may be out of date.
+    heightmap_generation: u64,
+    /// Viewpoints used to compute the current node priorities.
+    viewpoints: Vec<Viewpoint>,
+    /// Priority that each of `viewpoints` assigns to the nodes it was computed for. Nodes are
+    /// loaded for the highest of them, but only drawn in a view if its own priority is high
+    /// enough. Empty if priorities didn't come from viewpoints.
+    viewpoint_priorities: Vec<FnvHashMap<VNode, Priority>>,
+    /// Viewpoint whose nodes are drawn with `CullView::Main`, or `None` for the nodes needed by
+    /// any of them. See `select_viewpoint`.
+    selected_viewpoint: Option<usize>,
+    lod_frozen: bool,
+    lod_step_requested: bool,
+    /// Whether new tiles are only requested from the streamer for the root nodes.
+    streaming_paused: bool,
+    validation: Option<Validator>,
+    /// Limits on loaded tiles set through the `TerrainBuilder`.
+    configured_limits: DetailLimits,
+    /// Limits on loaded tiles, with the maximum level raised to cover all inset regions.
+    detail_limits: DetailLimits,
+    /// Maximum level to load tiles for outside of inset regions.
+    base_max_level: u8,
+    /// Reductions in quality needed to run on the device, if any.
+    downlevel: Option<Downlevel>,
+    /// Area outside of which tiles are only loaded up to a base level.
+    region: Option<Region>,
+    /// Areas that are loaded with extra detail.
+    insets: Vec<Inset>,
+    /// Debug overlay drawing the bounds of visible nodes, if enabled.
+    bounds_overlay: Option<BoundsOverlay>,
+    /// Attachments that meshes are rendered into.
+    target_config: TargetConfig,
+    /// Loaded and evicted nodes not yet retrieved by the application, if enabled.
+    node_events: Option<Vec<NodeEvent>>,
+    /// Subscribers to the lifecycle of tiles.
+    tile_events: TileEventSenders,
+    /// Runtime changes to the terrain height.
+    deformations: Deformations,
+    /// Whether the GPU copy of `deformations` is out of date.
+    deformations_dirty: bool,
+    /// Where generated materials are covered in snow, if anywhere.
+    snow_line: Option<SnowLine>,
+    /// Whether the GPU copy of `snow_line` is out of date.
+    snow_line_dirty: bool,
+    /// Hydraulic erosion applied to generated heightmaps, if enabled.
+    erosion: Option<Erosion>,
+    /// Minimum priority of nodes drawn into the shadow map, if it differs from the main view.
+    shadow_priority_cutoff: Option<Priority>,
+    /// Minimum priority of terrain nodes drawn into reflection probes.
+    probe_priority_cutoff: Priority,
+    /// Update frequency of the aerial perspective.
+    aerial_perspective_quality: AerialPerspectiveQuality,
+    /// Quality that the aerial perspective textures were allocated for, which fixes their
+    /// resolution.
+    aerial_perspective_textures: AerialPerspectiveQuality,
+    /// Largest number of cache slots for each level of the quadtree from level 2 down, which
+    /// quality presets may lower further.
+    slot_budget: usize,
+    /// Number of times the dynamic generators have run, used to stagger their updates.
+    dynamic_frame: u64,
+    /// Layout of the uniforms used by the static and dynamic generators this frame.
+    generate_uniforms: GenerateUniforms,
+    /// Per-frame allocations reused across frames.
+    scratch: Scratch,
 
     index_buffer_contents: Vec<u32>,
     cull_shader: ComputeShader<mesh::CullMeshUniforms>,
@@ -272,7 +864,10 @@ impl TileCache {
         device: &wgpu::Device,
         mapfile: Arc<MapFile>,
         mesh_layers: Vec<MeshCacheDesc>,
+        options: TileCacheOptions,
     ) -> Self {
+        let configured_limits = options.detail_limits;
+        let aerial_perspective_quality = options.aerial_perspective_quality;
         let mut index_buffer_contents = Vec::new();
 
         let mut base_slot = 0;
@@ -297,18 +892,20 @@ impl TileCache {
         let meshes = meshes.into_iter().collect();
 
         let generators = generators::generators(device, &meshes);
+        let generator_costs = GeneratorCosts::new(generators.iter().map(|g| g.name()).collect());
 
-        let mut level_masks = vec![LayerMask::empty(); 23];
-        for layer in LayerType::iter() {
-            for i in layer.level_range() {
-                level_masks[i as usize] |= layer.bit_mask();
-            }
-        }
-        for mesh in meshes.values() {
-            for i in mesh.desc.min_level..=mesh.desc.max_level {
-                level_masks[i as usize] |= mesh.desc.ty.bit_mask();
-            }
+        let downlevel = Downlevel::detect(device);
+        if let Some(downlevel) = downlevel {
+            log::warn!(
+                "Device limits are too low for full quality, only loading tiles up to level {}",
+                downlevel.max_level
+            );
         }
+        let level_ceiling = downlevel.map(|d| d.max_level).unwrap_or(MAX_QUADTREE_LEVEL);
+
+        let base_max_level = configured_limits.max_level.min(level_ceiling);
+        let detail_limits = configured_limits.with_insets(&options.insets, level_ceiling);
+        let level_masks = Self::compute_level_masks(&detail_limits, &meshes);
 
         let mut levels = vec![PriorityCache::new(6), PriorityCache::new(24)];
         for _ in 2..=MAX_QUADTREE_LEVEL {
@@ -317,100 +914,388 @@ impl TileCache {
 
         let (completed_tx, completed_rx) = crossbeam::channel::unbounded();
 
-        let transcode_format = if device.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC)
-        {
-            wgpu::TextureFormat::Bc7RgbaUnorm
-        } else {
-            wgpu::TextureFormat::Astc {
-                block: wgpu::AstcBlock::B4x4,
-                channel: wgpu::AstcChannel::Unorm,
-            }
-        };
-
         Self {
-            streamer: TileStreamerEndpoint::new(mapfile, transcode_format).unwrap(),
+            streamer: TileStreamerEndpoint::new(TileSource {
+                mapfile,
+                flat_map: options.flat_map,
+                procedural_planet: options.procedural_planet,
+            })
+            .unwrap(),
             level_masks,
             completed_downloads_tx: completed_tx,
             completed_downloads_rx: completed_rx,
+            heightmap_generation: 0,
             free_download_buffers: Vec::new(),
             total_download_buffers: 0,
+            tiles_streamed: 0,
+            tiles_generated: 0,
             levels: Levels(levels),
             meshes,
             generators,
+            generator_costs,
             dynamic_generators: generators::dynamic_generators(),
             index_buffer_contents,
             cull_shader: ComputeShader::new(
                 rshader::shader_source!("../shaders", "cull-meshes.comp", "declarations.glsl"),
                 "cull-meshes".to_owned(),
             ),
-            last_camera_position: None,
+            viewpoints: Vec::new(),
+            viewpoint_priorities: Vec::new(),
+            selected_viewpoint: Some(0),
+            lod_frozen: false,
+            streaming_paused: false,
+            lod_step_requested: false,
+            validation: None,
+            configured_limits,
+            detail_limits,
+            base_max_level,
+            downlevel,
+            region: options.region,
+            insets: options.insets,
+            bounds_overlay: None,
+            target_config: TargetConfig::default(),
+            node_events: None,
+            tile_events: TileEventSenders::default(),
+            deformations: Deformations::default(),
+            deformations_dirty: false,
+            snow_line: Some(SnowLine::default()),
+            snow_line_dirty: true,
+            erosion: None,
+            shadow_priority_cutoff: None,
+            probe_priority_cutoff: LodTarget::default().probe_priority_cutoff(),
+            aerial_perspective_quality,
+            aerial_perspective_textures: aerial_perspective_quality,
+            slot_budget: SLOTS_PER_LEVEL,
+            dynamic_frame: 0,
+            generate_uniforms: GenerateUniforms::default(),
+            scratch: Scratch::default(),
         }
     }
 
-    fn refresh_shaders(&mut self, device: &wgpu::Device, gpu_state: &GpuState) {
-        for (i, gen) in self.generators.iter_mut().enumerate() {
-            if gen.needs_refresh() {
-                assert!(i < 32);
-                let mask = GeneratorMask::from_index(i);
-                for cache in self.levels.0.iter_mut() {
-                    for slot in cache.slots_mut() {
-                        for (layer, generator_mask) in &slot.generators {
-                            if generator_mask.intersects(mask) {
-                                slot.valid &= !LayerType::from_index(layer).bit_mask();
-                            }
-                        }
-                        // Directly remove any meshes that were generated by this.
-                        slot.valid &= !gen.outputs();
+    fn compute_level_masks(
+        detail_limits: &DetailLimits,
+        meshes: &VecMap<MeshCache>,
+    ) -> Vec<LayerMask> {
+        let mut level_masks = vec![LayerMask::empty(); 23];
+        for layer in LayerType::iter() {
+            for i in layer.min_level()..=detail_limits.layer_max_level(layer) {
+                level_masks[i as usize] |= layer.bit_mask();
+            }
+        }
+        for mesh in meshes.values() {
+            let max_level = mesh.desc.max_level.min(detail_limits.max_level(mesh.desc.ty.into()));
+            for i in mesh.desc.min_level..=max_level {
+                level_masks[i as usize] |= mesh.desc.ty.bit_mask();
+            }
+        }
+        level_masks
+    }
+
+    /// Replace the inset regions. Takes effect the next time node priorities are computed.
+    pub fn set_insets(&mut self, insets: Vec<Inset>) {
+        self.insets = insets;
+        self.detail_limits.max_level = self.base_max_level;
+        self.detail_limits = self.detail_limits.with_insets(&self.insets, self.level_ceiling());
+        self.level_masks = Self::compute_level_masks(&self.detail_limits, &self.meshes);
+        self.viewpoints.clear();
+    }
+
+    /// Apply a quality preset, or go back to the limits set through the `TerrainBuilder` with
+    /// `None`. Tiles no longer covered by the preset are evicted or have the affected layers
+    /// invalidated, and anything newly enabled is loaded as the camera moves.
+    ///
+    /// The resolution of the aerial perspective is fixed once the textures are allocated, so only
+    /// its update frequency follows the preset.
+    pub fn set_quality(&mut self, quality: Option<TerrainQuality>) {
+        let mut limits = self.configured_limits.clone();
+        let mut slots_per_level = self.slot_budget;
+        self.aerial_perspective_quality = self.aerial_perspective_textures;
+        if let Some(quality) = quality {
+            limits.max_level = limits.max_level.min(quality.max_level());
+            limits.layer_max_levels.extend(quality.disabled_layers().iter().map(|&l| (l, 0)));
+            slots_per_level = slots_per_level.min(quality.slots_per_level());
+            self.aerial_perspective_quality = quality.aerial_perspective_quality();
+        }
+
+        self.base_max_level = limits.max_level.min(self.level_ceiling());
+        self.detail_limits = limits.with_insets(&self.insets, self.level_ceiling());
+        self.level_masks = Self::compute_level_masks(&self.detail_limits, &self.meshes);
+        for (level, cache) in self.levels.0.iter_mut().enumerate() {
+            for slot in cache.slots_mut() {
+                slot.valid &= self.level_masks[level];
+            }
+        }
+
+        let mut evicted = Vec::new();
+        for cache in self.levels.0.iter_mut().skip(2) {
+            evicted.extend(cache.set_capacity(slots_per_level));
+        }
+        self.record_evictions(evicted);
+        self.viewpoints.clear();
+    }
+
+    /// Limit the number of cache slots for each level of the quadtree from level 2 down. Quality
+    /// presets applied afterwards may lower it further.
+    pub fn set_slot_budget(&mut self, slots_per_level: usize) {
+        self.slot_budget = slots_per_level.min(SLOTS_PER_LEVEL);
+        let mut evicted = Vec::new();
+        for cache in self.levels.0.iter_mut().skip(2) {
+            evicted.extend(cache.set_capacity(self.slot_budget));
+        }
+        self.record_evictions(evicted);
+    }
+
+    /// Add a deformation, regenerating any tiles that it touches.
+    pub fn add_deformation(&mut self, deformation: Deformation) -> Result<(), crate::Error> {
+        self.deformations.push(deformation)?;
+        self.invalidate_deformed(&deformation);
+        self.deformations_dirty = true;
+        self.heightmap_generation += 1;
+        Ok(())
+    }
+
+    /// Remove all deformations, restoring the original terrain.
+    pub fn clear_deformations(&mut self) {
+        let deformations: Vec<_> = self.deformations.iter().cloned().collect();
+        for deformation in &deformations {
+            self.invalidate_deformed(deformation);
+        }
+        self.deformations.clear();
+        self.deformations_dirty = true;
+        self.heightmap_generation += 1;
+    }
+
+    /// Mark the displacements and meshes of every node that `deformation` touches as invalid.
+    fn invalidate_deformed(&mut self, deformation: &Deformation) {
+        let mask = LayerType::Displacements.bit_mask()
+            | MeshType::Terrain.bit_mask()
+            | MeshType::Grass.bit_mask()
+            | MeshType::TreeBillboards.bit_mask()
+            | MeshType::Rocks.bit_mask();
+        for cache in self.levels.0.iter_mut() {
+            for slot in cache.slots_mut() {
+                if Deformations::overlaps(deformation, slot.node) {
+                    slot.valid &= !mask;
+                }
+            }
+        }
+    }
+
+    fn upload_deformations(&mut self, queue: &wgpu::Queue, gpu_state: &GpuState) {
+        if self.deformations_dirty {
+            queue.write_buffer(&gpu_state.deformations, 0, &self.deformations.gpu_data());
+            self.deformations_dirty = false;
+        }
+    }
+
+    /// Change where snow lies, regenerating the materials of every loaded node.
+    pub fn set_snow_line(&mut self, snow_line: Option<SnowLine>) {
+        if snow_line == self.snow_line {
+            return;
+        }
+        self.snow_line = snow_line;
+        self.snow_line_dirty = true;
+        self.invalidate_materials();
+    }
+
+    /// Enable or disable hydraulic erosion of generated heightmaps, regenerating the heightmaps
+    /// of every loaded node and everything derived from them.
+    pub fn set_erosion(&mut self, erosion: Option<Erosion>) {
+        if erosion == self.erosion {
+            return;
+        }
+        self.erosion = erosion;
+        for i in 0..self.generators.len() {
+            self.generators[i].set_erosion(erosion);
+            if self.generators[i].outputs().contains_layer(LayerType::Heightmaps) {
+                self.invalidate_generator(i);
+            }
+        }
+    }
+
+    /// Counter that changes whenever heights sampled on the CPU may have changed.
+    pub fn heightmap_generation(&self) -> u64 {
+        self.heightmap_generation
+    }
+
+    /// Mark the grass of every loaded node as invalid, so it is regenerated.
+    pub fn invalidate_grass(&mut self) {
+        for cache in self.levels.0.iter_mut() {
+            for slot in cache.slots_mut() {
+                slot.valid &= !MeshType::Grass.bit_mask();
+            }
+        }
+    }
+
+    /// Mark the materials of every loaded node as invalid, so they are regenerated.
+    pub fn invalidate_materials(&mut self) {
+        for cache in self.levels.0.iter_mut() {
+            for slot in cache.slots_mut() {
+                slot.valid &= !LayerType::AlbedoRoughness.bit_mask();
+            }
+        }
+    }
+
+    fn upload_snow_line(&mut self, queue: &wgpu::Queue, gpu_state: &GpuState) {
+        if self.snow_line_dirty {
+            let block = SnowLineUniformBlock::new(self.snow_line.as_ref());
+            queue.write_buffer(&gpu_state.snow_line, 0, bytemuck::bytes_of(&block));
+            self.snow_line_dirty = false;
+        }
+    }
+
+    /// Deepest level that `node` may be refined to, and the factor by which the tolerated error
+    /// is reduced for it.
+    fn inset_limits(&self, node: VNode) -> (u8, f64) {
+        let mut limits = (self.base_max_level, 1.0);
+        for inset in self.insets.iter().filter(|inset| inset.overlaps(node)) {
+            limits = (limits.0.max(inset.max_level()), limits.1.max(inset.detail_scale()));
+        }
+        (limits.0.min(self.level_ceiling()), limits.1)
+    }
+
+    /// Deepest level that tiles can ever be loaded for on this device.
+    fn level_ceiling(&self) -> u8 {
+        self.downlevel.map(|d| d.max_level).unwrap_or(MAX_QUADTREE_LEVEL)
+    }
+
+    /// Whether the device's limits forced reduced quality. See `Downlevel`.
+    pub(crate) fn is_downlevel(&self) -> bool {
+        self.downlevel.is_some()
+    }
+
+    /// Region of interest that detailed tiles are restricted to, if any.
+    pub(crate) fn region(&self) -> Option<&Region> {
+        self.region.as_ref()
+    }
+
+    /// Quality that the aerial perspective textures are allocated for.
+    pub(crate) fn aerial_perspective_textures(&self) -> AerialPerspectiveQuality {
+        self.aerial_perspective_textures
+    }
+
+    /// Number of array layers in the tile cache textures of `layer`. Layers that can't be loaded on
+    /// this device still get a single layer, so that shaders can bind them.
+    pub(crate) fn tile_cache_layers(&self, layer: LayerType) -> u32 {
+        let max_level = layer.max_level().min(self.level_ceiling());
+        if max_level < layer.min_level() {
+            return 1;
+        }
+        (Levels::base_slot(max_level + 1) - Levels::base_slot(layer.min_level())) as u32
+    }
+
+    /// Mark everything that generator `index` produced, and every layer derived from its
+    /// outputs, as invalid so that it is regenerated.
+    fn invalidate_generator(&mut self, index: usize) {
+        assert!(index < 32);
+        let mask = GeneratorMask::from_index(index);
+        let outputs = self.generators[index].outputs();
+        for cache in self.levels.0.iter_mut() {
+            for slot in cache.slots_mut() {
+                for (layer, generator_mask) in &slot.generators {
+                    if generator_mask.intersects(mask) {
+                        slot.valid &= !LayerType::from_index(layer).bit_mask();
                     }
                 }
+                // Directly remove any meshes that were generated by this.
+                slot.valid &= !outputs;
+            }
+        }
+    }
+
+    fn refresh_shaders(&mut self, device: &wgpu::Device, gpu_state: &GpuState) {
+        for i in 0..self.generators.len() {
+            if self.generators[i].needs_refresh() {
+                self.invalidate_generator(i);
             }
         }
 
         for g in &mut self.dynamic_generators {
-            if g.bindgroup_pipeline.is_none() || g.shader.refresh() {
-                let (bindgroup, layout) = gpu_state.bind_group_for_shader(
+            if g.shader.refresh() {
+                gpu_state.objects.invalidate(&format!("generate.{}", g.name));
+                g.bindgroup_pipeline = None;
+            }
+            if g.bindgroup_pipeline.is_none() {
+                g.bindgroup_pipeline = Some(gpu_state.compute_bind_group_pipeline(
                     device,
                     &g.shader,
                     hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &gpu_state.generate_uniforms,
-                    offset: 0,
-                    size: Some(NonZeroU64::new(4096).unwrap()),
-                }))],
-                HashMap::new(),
-                &format!("generate.{}", g.name),
-                );
-                let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                        bind_group_layouts: [&layout][..].into(),
-                        push_constant_ranges: &[],
-                        label: None,
-                    })),
-                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                        label: Some(&format!("shader.generate.{}", g.name)),
-                        source: g.shader.compute(),
-                    }),
-                    entry_point: "main",
-                    label: Some(&format!("pipeline.generate.{}", g.name)),
-                });
-                g.bindgroup_pipeline = Some((bindgroup, pipeline));
+                        buffer: &gpu_state.generate_uniforms,
+                        offset: 0,
+                        size: Some(NonZeroU64::new(4096).unwrap()),
+                    }))],
+                    HashMap::new(),
+                    &format!("generate.{}", g.name),
+                ));
             }
         }
 
         self.cull_shader.refresh(device, gpu_state);
     }
 
-    fn update_priorities(&mut self, camera: mint::Point3<f64>) {
-        if self.last_camera_position != Some(camera) {
-            self.last_camera_position = Some(camera);
-            let camera = Vector3::new(camera.x, camera.y, camera.z);
+    /// Recompute node priorities if any of the viewpoints changed. Each node gets the highest
+    /// priority any viewpoint assigns it, so tiles needed by any of them are loaded, while the
+    /// priorities from each viewpoint are kept to pick the nodes drawn in its view.
+    fn update_priorities(&mut self, viewpoints: &[Viewpoint]) {
+        if self.lod_frozen && !self.lod_step_requested {
+            return;
+        }
+        if self.viewpoints.len() != viewpoints.len()
+            || self.viewpoints.iter().zip(viewpoints).any(|(old, new)| old.differs_from(new))
+            || self.lod_step_requested
+        {
+            self.viewpoints = viewpoints.to_vec();
+            self.lod_step_requested = false;
 
             let mut node_priorities = FnvHashMap::default();
+            let mut viewpoint_priorities = vec![FnvHashMap::default(); self.viewpoints.len()];
             VNode::breadth_first(|node| {
-                let priority = node.priority(camera, self.get_height_range(node));
+                // Nodes entirely outside of the region of interest are never refined past its
+                // base level.
+                if let Some(region) = &self.region {
+                    if node.level() > region.outside_max_level() && !region.polygon().overlaps(node)
+                    {
+                        node_priorities.insert(node, Priority::none());
+                        return false;
+                    }
+                }
+
+                let (max_level, detail_scale) = self.inset_limits(node);
+                let height_range = self.get_height_range(node);
+                let geometric_error = self.get_geometric_error(node);
+                let mut priority = Priority::none();
+                for (v, priorities) in self.viewpoints.iter().zip(&mut viewpoint_priorities) {
+                    let p = v.priority(node, height_range, geometric_error, detail_scale);
+                    priorities.insert(node, p);
+                    priority = priority.max(p);
+                }
                 node_priorities.insert(node, priority);
-                priority >= Priority::cutoff() && node.level() < MAX_QUADTREE_LEVEL
+                priority >= Priority::cutoff() && node.level() < max_level
             });
-            self.levels.update(node_priorities);
+            self.viewpoint_priorities = viewpoint_priorities;
+            let evicted = self.levels.update(node_priorities);
+            self.record_evictions(evicted);
+        }
+    }
+
+    /// Queue events for the loaded nodes among `evicted`, if node events are enabled, and notify
+    /// tile event subscribers of all of them.
+    fn record_evictions(&mut self, evicted: Vec<Entry>) {
+        metrics::counter!(telemetry::NODES_EVICTED, evicted.len() as u64);
+        for entry in &evicted {
+            self.tile_events.send(TileEvent::Evicted { node: entry.node });
+        }
+        if self.node_events.is_some() {
+            let events: Vec<_> = evicted
+                .into_iter()
+                .filter(|entry| entry.loaded)
+                .map(|entry| {
+                    let range = entry.heightmap.as_ref().map(CpuHeightmap::height_range);
+                    let bounds = self.bounds(entry.node, range);
+                    node_event(NodeEventKind::Evicted, entry.node, bounds)
+                })
+                .collect();
+            self.node_events.as_mut().unwrap().extend(events);
         }
     }
 
@@ -419,11 +1304,15 @@ impl TileCache {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         gpu_state: &GpuState,
-        camera: mint::Point3<f64>,
+        viewpoints: &[Viewpoint],
         mut progress_callback: F,
     ) {
         self.refresh_shaders(device, gpu_state);
-        self.update_priorities(camera);
+        self.update_priorities(viewpoints);
+        self.upload_deformations(queue, gpu_state);
+        self.upload_snow_line(queue, gpu_state);
+        // Waiting for tiles that will never be requested would block forever.
+        let paused = std::mem::replace(&mut self.streaming_paused, false);
         self.upload_tiles(queue, &gpu_state.tile_cache);
 
         let total: usize = (0..self.levels.0.len())
@@ -450,13 +1339,14 @@ impl TileCache {
                 })
                 .sum();
             progress_callback((total - missing) as f32 * 100.0 / total as f32);
-            if missing == 0 {
+            if missing == 0 || cfg!(target_arch = "wasm32") {
                 break;
             }
 
-            std::thread::sleep(std::time::Duration::from_millis(10));
+            std::thread::sleep(Duration::from_millis(10));
             self.upload_tiles(queue, &gpu_state.tile_cache);
         }
+        self.streaming_paused = paused;
     }
 
     pub fn update(
@@ -464,29 +1354,150 @@ impl TileCache {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         gpu_state: &GpuState,
-        camera: mint::Point3<f64>,
+        viewpoints: &[Viewpoint],
+        profiler: &mut GpuProfiler,
     ) {
         self.refresh_shaders(device, gpu_state);
-        self.update_priorities(camera);
+        self.update_priorities(viewpoints);
+        self.upload_deformations(queue, gpu_state);
+        self.upload_snow_line(queue, gpu_state);
+        self.upload_tiles(queue, &gpu_state.tile_cache);
+        // Node positions are stored relative to the first viewpoint, which is the main camera.
+        self.generate_tiles(device, queue, gpu_state, viewpoints[0].position, profiler);
+        self.report_loaded_nodes();
+        self.update_bounds_overlay(device, queue, gpu_state, viewpoints[0].position);
+        self.readback_tiles(device, queue, gpu_state);
+        self.validate_tiles(device, queue, gpu_state);
+    }
+
+    /// Like `update`, but loads exactly `nodes` and their ancestors rather than picking nodes
+    /// based on viewpoints. Returns how many of `nodes` have had every layer streamed or
+    /// generated, apart from the dynamic ones that depend on the camera.
+    ///
+    /// Only `bake_batch_size` nodes fit in the cache per level. Any nodes beyond that are skipped,
+    /// and counted as done so that callers waiting on all of them don't wait forever.
+    pub fn update_for_nodes(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        nodes: &[VNode],
+        profiler: &mut GpuProfiler,
+    ) -> usize {
+        let mut node_priorities = FnvHashMap::default();
+        for &node in nodes {
+            let mut node = Some(node);
+            while let Some(n) = node {
+                node_priorities.insert(n, Priority::cutoff());
+                node = n.parent().map(|(parent, _)| parent);
+            }
+        }
+        let evicted = self.levels.update(node_priorities);
+        self.record_evictions(evicted);
+        // Force priorities to be recomputed once there are viewpoints again.
+        self.viewpoints.clear();
+        self.viewpoint_priorities.clear();
+
+        self.refresh_shaders(device, gpu_state);
+        self.upload_deformations(queue, gpu_state);
+        self.upload_snow_line(queue, gpu_state);
         self.upload_tiles(queue, &gpu_state.tile_cache);
-        self.generate_tiles(device, queue, gpu_state, camera);
+        self.generate_tiles(
+            device,
+            queue,
+            gpu_state,
+            mint::Point3 { x: 0.0, y: 0.0, z: 0.0 },
+            profiler,
+        );
+        self.report_loaded_nodes();
         self.readback_tiles(device, queue, gpu_state);
+        self.validate_tiles(device, queue, gpu_state);
+
+        let static_layers = LayerType::iter()
+            .filter(|layer| !layer.dynamic())
+            .fold(LayerMask::empty(), |a, b| a | b.bit_mask());
+        nodes
+            .iter()
+            .filter(|&&node| {
+                let mask = self.level_masks[node.level() as usize] & static_layers;
+                !self.levels.contains(node) || self.levels.contains_layers(node, mask)
+            })
+            .count()
     }
 
-    fn write_nodes(&self, queue: &wgpu::Queue, gpu_state: &GpuState, camera: mint::Point3<f64>) {
+    /// Nodes currently needed for rendering, coarsest first.
+    pub fn resident_nodes(&self) -> Vec<VNode> {
+        self.levels
+            .0
+            .iter()
+            .flat_map(|cache| cache.slots())
+            .filter(|entry| entry.priority >= Priority::cutoff())
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// Most nodes of a single level that `update_for_nodes` can load at once. Levels 0 and 1
+    /// always have room for every node.
+    pub fn bake_batch_size(&self) -> usize {
+        self.levels.0[2..].iter().map(PriorityCache::capacity).min().unwrap()
+    }
+
+    /// Read back one of the recently generated tiles and check its contents. Only a single tile
+    /// is checked per frame since each readback stalls until the GPU is idle.
+    fn validate_tiles(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, gpu_state: &GpuState) {
+        let (generator, node, layer) = match self.validation.as_mut().and_then(Validator::pop) {
+            Some(tile) => tile,
+            None => return,
+        };
+        let data =
+            futures::executor::block_on(self.readback_layer(device, queue, gpu_state, layer, node));
+        if let Some(data) = data {
+            let issue = self.validation.as_mut().unwrap().report(generator, node, layer, &data);
+            if let Some(issue) = issue {
+                self.tile_events.send(TileEvent::GenerationFailed(issue));
+            }
+        }
+    }
+
+    fn write_nodes(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        camera: mint::Point3<f64>,
+    ) {
         assert_eq!(std::mem::size_of::<NodeSlot>(), 1024);
 
-        let mut frame_nodes: VecMap<HashMap<_, _>> = VecMap::new();
+        // Nodes are selected for the main camera, which shadows and reflection probes are also
+        // rendered for. Other views select their own with `select_viewpoint`.
+        self.selected_viewpoint = Some(0);
+        let frame_nodes = self.frame_nodes(Some(0));
+        let mut shadow_frame_nodes: VecMap<HashMap<_, _>> = VecMap::new();
+        let mut probe_frame_nodes: VecMap<HashMap<_, _>> = VecMap::new();
         for (index, mesh) in &self.meshes {
             if !mesh.desc.render_overlapping_levels {
-                frame_nodes.insert(
-                    index,
-                    self.compute_visible(mesh.desc.ty.bit_mask()).into_iter().collect(),
-                );
+                let layer_mask = mesh.desc.ty.bit_mask();
+                if let Some(cutoff) = self.shadow_priority_cutoff {
+                    shadow_frame_nodes.insert(
+                        index,
+                        self.compute_visible(layer_mask, cutoff, Some(0)).into_iter().collect(),
+                    );
+                }
+                if mesh.desc.ty == MeshType::Terrain {
+                    probe_frame_nodes.insert(
+                        index,
+                        self.compute_visible(layer_mask, self.probe_priority_cutoff, Some(0))
+                            .into_iter()
+                            .collect(),
+                    );
+                }
             }
         }
 
-        let mut data: Vec<NodeSlot> = vec![
+        let mut data = std::mem::take(&mut self.scratch.node_slots);
+        data.clear();
+        data.resize(
+            Levels::base_slot(self.levels.0.len() as u8),
             NodeSlot {
                 node_center: [0.0; 3],
                 layers: [(0.0, 0.0, 0.0, -1); 48],
@@ -496,11 +1507,12 @@ impl TileCache {
                 level: 0,
                 face: 0,
                 coords: [0; 2],
+                shadow_mesh_valid_mask: [0; 4],
+                probe_mesh_valid_mask: [0; 4],
                 parent: -1,
-                padding: [0; 48],
-            };
-            Levels::base_slot(self.levels.0.len() as u8)
-        ];
+                padding: [0; 40],
+            },
+        );
         for (level_index, level) in self.levels.0.iter().enumerate() {
             for (slot_index, slot) in level.slots().into_iter().enumerate() {
                 let index = Levels::base_slot(level_index as u8) + slot_index;
@@ -527,17 +1539,20 @@ impl TileCache {
                     .unwrap_or(-1);
 
                 for (mesh_index, m) in &self.meshes {
-                    assert!(m.desc.entries_per_node <= 32);
-                    data[index].mesh_valid_mask[mesh_index] = if slot.valid.contains_mesh(m.desc.ty)
-                    {
-                        0xffffffff >> (32 - m.desc.entries_per_node)
-                    } else {
-                        0
-                    };
-                    if let Some(ref frame_nodes) = frame_nodes.get(mesh_index) {
-                        data[index].mesh_valid_mask[mesh_index] &=
-                            *frame_nodes.get(&slot.node).unwrap_or(&0) as u32;
-                    }
+                    let valid_mask = Self::mesh_valid_mask(m, slot);
+                    data[index].mesh_valid_mask[mesh_index] =
+                        Self::selected_mask(valid_mask, frame_nodes.get(mesh_index), slot.node);
+                    data[index].shadow_mesh_valid_mask[mesh_index] =
+                        match shadow_frame_nodes.get(mesh_index) {
+                            Some(nodes) => valid_mask & *nodes.get(&slot.node).unwrap_or(&0) as u32,
+                            None => data[index].mesh_valid_mask[mesh_index],
+                        };
+                    // Reflection probes skip vegetation entirely.
+                    data[index].probe_mesh_valid_mask[mesh_index] =
+                        match probe_frame_nodes.get(mesh_index) {
+                            Some(nodes) => valid_mask & *nodes.get(&slot.node).unwrap_or(&0) as u32,
+                            None => 0,
+                        };
                 }
 
                 let mut ancestor = slot.node;
@@ -562,7 +1577,8 @@ impl TileCache {
                                 continue;
                             };
 
-                            let texture_resolution = layer.texture_resolution() as f32;
+                            let texture_resolution =
+                                self.aerial_perspective_textures.texture_resolution(layer) as f32;
                             let texture_border = layer.texture_border_size() as f32;
                             let texture_ratio = if layer.grid_registration() {
                                 (texture_resolution - 2.0 * texture_border - 1.0)
@@ -596,7 +1612,74 @@ impl TileCache {
                 }
             }
         }
-        queue.write_buffer(&gpu_state.nodes, 0, bytemuck::cast_slice(&data));
+        gpu_state.upload(device, encoder, &gpu_state.nodes, 0, bytemuck::cast_slice(&data));
+        self.scratch.node_slots = data;
+    }
+
+    /// Nodes drawn by each mesh that doesn't render overlapping levels, as seen from
+    /// `viewpoint`. See `compute_visible`.
+    fn frame_nodes(&self, viewpoint: Option<usize>) -> VecMap<HashMap<VNode, u8>> {
+        let mut frame_nodes = VecMap::new();
+        for (index, mesh) in &self.meshes {
+            if !mesh.desc.render_overlapping_levels {
+                let visible =
+                    self.compute_visible(mesh.desc.ty.bit_mask(), Priority::cutoff(), viewpoint);
+                frame_nodes.insert(index, visible.into_iter().collect());
+            }
+        }
+        frame_nodes
+    }
+
+    /// Bitmask of the mesh entries of `mesh` that are valid for `slot`.
+    fn mesh_valid_mask(mesh: &MeshCache, slot: &Entry) -> u32 {
+        assert!(mesh.desc.entries_per_node <= 32);
+        if slot.valid.contains_mesh(mesh.desc.ty) {
+            0xffffffff >> (32 - mesh.desc.entries_per_node)
+        } else {
+            0
+        }
+    }
+
+    /// Restrict `valid_mask` to the quadrants of `node` among `nodes`, if the mesh draws only
+    /// selected nodes.
+    fn selected_mask(valid_mask: u32, nodes: Option<&HashMap<VNode, u8>>, node: VNode) -> u32 {
+        match nodes {
+            Some(nodes) => valid_mask & *nodes.get(&node).unwrap_or(&0) as u32,
+            None => valid_mask,
+        }
+    }
+
+    /// Draw the nodes selected for `viewpoint` with `CullView::Main`, where viewpoint zero is the
+    /// main camera and the rest are the other viewpoints passed to `update`, in order. With
+    /// `None`, nodes needed by any viewpoint are drawn. Each update selects the main camera again,
+    /// and node slots are only rewritten if the selection changes.
+    pub fn select_viewpoint(
+        &mut self,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        viewpoint: Option<usize>,
+    ) {
+        if viewpoint == self.selected_viewpoint {
+            return;
+        }
+        self.selected_viewpoint = viewpoint;
+
+        let frame_nodes = self.frame_nodes(viewpoint);
+        let data = &mut self.scratch.node_slots;
+        if data.is_empty() {
+            return;
+        }
+        for (level_index, level) in self.levels.0.iter().enumerate() {
+            for (slot_index, slot) in level.slots().iter().enumerate() {
+                let index = Levels::base_slot(level_index as u8) + slot_index;
+                for (mesh_index, m) in &self.meshes {
+                    let valid_mask = Self::mesh_valid_mask(m, slot);
+                    data[index].mesh_valid_mask[mesh_index] =
+                        Self::selected_mask(valid_mask, frame_nodes.get(mesh_index), slot.node);
+                }
+            }
+        }
+        queue.write_buffer(&gpu_state.nodes, 0, bytemuck::cast_slice(data));
     }
 
     pub fn make_gpu_mesh_index(&self, device: &wgpu::Device) -> wgpu::Buffer {
@@ -627,13 +1710,24 @@ impl TileCache {
     pub fn total_mesh_entries(&self) -> usize {
         self.meshes.values().map(|m| m.num_entries).sum()
     }
+    /// Entries of `mesh_indirect` and `mesh_bounding` used by a mesh type.
+    pub fn mesh_entries(&self, ty: MeshType) -> Range<usize> {
+        let mesh = &self.meshes[ty];
+        mesh.base_entry..mesh.base_entry + mesh.num_entries
+    }
 
+    /// Cull mesh entries against the current view, keeping only the entries of nodes selected
+    /// for `view`. Entries that remain are also packed into `mesh_indirect_compacted`, and if
+    /// `occlusion` is set, entries hidden behind the depth in the Hi-Z pyramid are culled too.
     pub fn cull_meshes<'a>(
         &'a self,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &'a GpuState,
+        view: CullView,
+        occlusion: Option<OcclusionTest>,
     ) {
+        encoder.clear_buffer(&gpu_state.mesh_draw_count, 0, None);
         for (mesh_index, c) in &self.meshes {
             self.cull_shader.run(
                 device,
@@ -646,6 +1740,13 @@ impl TileCache {
                     num_nodes: (c.num_entries / c.desc.entries_per_node) as u32,
                     base_slot: Levels::base_slot(c.desc.min_level) as u32,
                     mesh_index: mesh_index as u32,
+                    view: view as u32,
+                    occlusion_culling: occlusion.is_some() as u32,
+                    hiz_levels: HIZ_MIP_LEVELS,
+                    camera_offset: occlusion.map_or([0.0; 3], |o| o.camera_offset),
+                    _padding: 0.0,
+                    hiz_view_proj: occlusion
+                        .map_or(cgmath::Matrix4::<f32>::identity().into(), |o| o.view_proj),
                 },
             );
         }
@@ -653,10 +1754,16 @@ impl TileCache {
 
     pub fn update_meshes(&mut self, device: &wgpu::Device, gpu_state: &GpuState) {
         for (_, c) in &mut self.meshes {
-            c.update(device, gpu_state);
+            c.update(device, gpu_state, self.target_config);
         }
     }
 
+    /// Set the attachments that meshes are rendered into. Pipelines are rebuilt on the next
+    /// update.
+    pub(crate) fn set_target_config(&mut self, target_config: TargetConfig) {
+        self.target_config = target_config;
+    }
+
     pub fn render_meshes<'a>(
         &'a self,
         device: &wgpu::Device,
@@ -668,6 +1775,38 @@ impl TileCache {
         }
     }
 
+    /// Render only the terrain mesh, for use after culling with `CullView::Probe`.
+    pub fn render_probe_meshes<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        self.meshes[MeshType::Terrain].render(device, rpass, gpu_state);
+    }
+
+    /// Render the terrain with its debug shader variant in place of the regular one. Other mesh
+    /// types have no debug variant and are skipped.
+    pub fn render_debug_meshes<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        self.meshes[MeshType::Terrain].render_debug(device, rpass, gpu_state);
+    }
+
+    pub fn render_mesh_wireframes<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        for (_, c) in &self.meshes {
+            c.render_wireframe(device, rpass, gpu_state);
+        }
+    }
+
     pub fn render_mesh_shadows<'a>(
         &'a self,
         device: &wgpu::Device,
@@ -679,7 +1818,208 @@ impl TileCache {
         }
     }
 
+    /// Terrain nodes selected for the main camera by the last update, along with their bounds.
+    /// Frustum culling happens on the GPU, so this includes nodes that are offscreen.
+    pub fn visible_nodes(&self) -> impl Iterator<Item = VisibleNode> + '_ {
+        self.compute_visible(MeshType::Terrain.bit_mask(), Priority::cutoff(), Some(0))
+            .into_iter()
+            .map(move |(node, quadrants)| {
+                let (height_range, center, radius) = self.bounds(node, None);
+                VisibleNode {
+                    node,
+                    quadrants,
+                    height_range,
+                    bounding_center: mint::Point3 { x: center.x, y: center.y, z: center.z },
+                    bounding_radius: radius,
+                    valid_layers: self.levels.get(node).map_or(LayerMask::empty(), |e| e.valid),
+                }
+            })
+    }
+
+    /// Height range and bounding sphere of `node`. If `range` isn't given, the heights of the
+    /// closest ancestor with a resident heightmap are used. Like when raycasting, heights are
+    /// clamped to sea level and then deformed.
+    fn bounds(&self, node: VNode, range: Option<(f32, f32)>) -> ((f32, f32), Vector3<f64>, f64) {
+        let range = range
+            .or_else(|| {
+                std::iter::successors(Some(node), |n| n.parent().map(|p| p.0))
+                    .find_map(|n| self.get_heightmap_range(n))
+            })
+            .map_or((0.0, MAX_TERRAIN_HEIGHT as f32), |(min, max)| (min.max(0.0), max.max(0.0)));
+        let height_range = self.deformations.extend_range(range);
+        let (center, radius) = node.bounding_sphere(height_range);
+        (height_range, center, radius)
+    }
+
+    /// Queue a `Loaded` event for every node that has all of its tiles for the first time.
+    fn report_loaded_nodes(&mut self) {
+        if self.node_events.is_none() {
+            return;
+        }
+        let mut loaded = Vec::new();
+        for (level, cache) in self.levels.0.iter_mut().enumerate() {
+            let mask = self.level_masks[level];
+            for entry in cache.slots_mut() {
+                if !entry.loaded && (entry.valid & mask) == mask {
+                    entry.loaded = true;
+                    loaded.push(entry.node);
+                }
+            }
+        }
+        for node in loaded {
+            let event = node_event(NodeEventKind::Loaded, node, self.bounds(node, None));
+            self.node_events.as_mut().unwrap().push(event);
+        }
+    }
+
+    /// Start or stop recording node events. Nodes that are already loaded when recording starts
+    /// are reported as loaded on the next update.
+    pub fn set_node_events(&mut self, enabled: bool) {
+        if enabled != self.node_events.is_some() {
+            self.node_events = enabled.then(Vec::new);
+            if !enabled {
+                for cache in self.levels.0.iter_mut() {
+                    for entry in cache.slots_mut() {
+                        entry.loaded = false;
+                    }
+                }
+            }
+        }
+    }
+    pub fn subscribe_tile_events(&mut self) -> std::sync::mpsc::Receiver<TileEvent> {
+        self.tile_events.subscribe()
+    }
+
+    pub fn take_node_events(&mut self) -> Vec<NodeEvent> {
+        self.node_events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        let mut pending_tiles = 0;
+        for (level, cache) in self.levels.0.iter().enumerate() {
+            let mask = self.level_masks[level];
+            pending_tiles += cache
+                .slots()
+                .iter()
+                .filter(|e| e.priority >= Priority::cutoff() && (e.valid & mask) != mask)
+                .count();
+        }
+
+        Statistics {
+            level_occupancy: self.levels.0.iter().map(|c| c.slots().len()).collect(),
+            level_capacity: self.levels.0.iter().map(PriorityCache::capacity).collect(),
+            pending_tiles,
+            streaming_inflight: self.streamer.num_inflight(),
+            heightmap_downloads_inflight: self.total_download_buffers
+                - self.free_download_buffers.len(),
+            tiles_streamed: self.tiles_streamed,
+            tiles_generated: self.tiles_generated,
+            gpu_timings: Vec::new(),
+            generator_costs: self.generator_costs.tile_costs(),
+        }
+    }
+
+    pub fn generation_budget(&self) -> Duration {
+        self.generator_costs.budget()
+    }
+
+    pub fn set_generation_budget(&mut self, budget: Duration) {
+        self.generator_costs.set_budget(budget);
+    }
+
+    pub fn set_validation(&mut self, enabled: bool) {
+        if enabled && cfg!(target_arch = "wasm32") {
+            log::warn!("Tile validation isn't supported on the web");
+            return;
+        }
+        if enabled != self.validation.is_some() {
+            self.validation = enabled.then(Validator::default);
+        }
+    }
+    pub fn take_validation_issues(&mut self) -> Vec<ValidationIssue> {
+        self.validation.as_mut().map(Validator::take_issues).unwrap_or_default()
+    }
+
+    pub fn set_shadow_priority_cutoff(&mut self, cutoff: Option<Priority>) {
+        self.shadow_priority_cutoff = cutoff;
+    }
+    pub fn set_probe_priority_cutoff(&mut self, cutoff: Priority) {
+        self.probe_priority_cutoff = cutoff;
+    }
+
+    pub fn set_lod_frozen(&mut self, frozen: bool) {
+        self.lod_frozen = frozen;
+        self.lod_step_requested = false;
+    }
+    pub fn lod_frozen(&self) -> bool {
+        self.lod_frozen
+    }
+    pub fn step_lod(&mut self) {
+        self.lod_step_requested = true;
+    }
+
+    pub fn set_streaming_paused(&mut self, paused: bool) {
+        self.streaming_paused = paused;
+    }
+    pub fn streaming_paused(&self) -> bool {
+        self.streaming_paused
+    }
+
+    /// Stop the tile streamer and wait for it to exit. No more tiles are streamed afterwards.
+    pub async fn shutdown_streaming(&mut self) {
+        self.streamer.shutdown().await;
+    }
+
     pub fn contains_layers(&self, node: VNode, layers: LayerMask) -> bool {
         self.levels.contains_layers(node, layers)
     }
 }
+
+fn node_event(
+    kind: NodeEventKind,
+    node: VNode,
+    (height_range, center, radius): ((f32, f32), Vector3<f64>, f64),
+) -> NodeEvent {
+    NodeEvent {
+        kind,
+        node,
+        height_range,
+        bounding_center: mint::Point3 { x: center.x, y: center.y, z: center.z },
+        bounding_radius: radius,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestEntry(u32, f32);
+    impl PriorityCacheEntry for TestEntry {
+        type Key = u32;
+        fn priority(&self) -> Priority {
+            Priority::from_f32(self.1)
+        }
+        fn key(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn shrinking_priority_cache_evicts_excess_slots() {
+        let mut cache = PriorityCache::new(4);
+        cache.insert((0..4).map(|i| TestEntry(i, i as f32 + 1.0)).collect());
+
+        let evicted = cache.set_capacity(2);
+        assert_eq!(evicted.len(), 2);
+        assert_eq!(cache.slots().len(), 2);
+        for entry in &evicted {
+            assert!(!cache.contains(&entry.0));
+        }
+        for (i, entry) in cache.slots().iter().enumerate() {
+            assert_eq!(cache.index_of(&entry.0), Some(i));
+        }
+
+        assert!(cache.set_capacity(4).is_empty());
+        assert!(!cache.is_full());
+    }
+}
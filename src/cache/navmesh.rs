@@ -0,0 +1,160 @@
+use crate::cache::heightfield::{CollisionHeightfield, HeightfieldRegion};
+use crate::cache::TileCache;
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3};
+use terra_types::{VNode, MAX_QUADTREE_LEVEL, ROOT_SIDE_LENGTH};
+
+/// Largest number of samples along each side of a navigation mesh. Larger regions should be
+/// split into several meshes, like navmesh builders already do with tiles.
+pub const MAX_NAVIGATION_MESH_RESOLUTION: usize = 4097;
+
+/// Kind of surface a navigation mesh triangle lies on, for assigning Recast area types.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NavigationArea {
+    /// Ground that agents can walk on.
+    Walkable,
+    /// Ground steeper than the slope limit of the mesh.
+    Steep,
+    /// Sea, or terrain at or below sea level that is flooded by it.
+    Water,
+}
+
+/// Triangle soup covering a square of terrain, for feeding to Recast-style navmesh builders.
+///
+/// Vertices are in the same frame as `CollisionHeightfield`: x points east, y up and z south,
+/// matching the y-up convention of Recast.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NavigationMesh {
+    /// Vertex positions in meters.
+    pub vertices: Vec<[f32; 3]>,
+    /// Three vertex indices per triangle, wound so that `cross(v1 - v0, v2 - v0)` points up as
+    /// Recast expects.
+    pub indices: Vec<u32>,
+    /// Surface of each triangle.
+    pub areas: Vec<NavigationArea>,
+    /// Maps the mesh frame to ECEF coordinates.
+    pub transform: mint::ColumnMatrix4<f64>,
+    /// Quadtree levels of the coarsest and finest heightmaps sampled, or `None` if no heightmap
+    /// covering the region was loaded and it sits at sea level.
+    pub levels: Option<(u8, u8)>,
+}
+impl NavigationMesh {
+    /// Triangulate `heightfield` with two triangles per cell, classifying each of them.
+    fn from_heightfield(heightfield: CollisionHeightfield, sea: &[bool], max_slope: f32) -> Self {
+        let resolution = heightfield.resolution;
+        let half = heightfield.size() * 0.5;
+        let vertices: Vec<[f32; 3]> = (0..resolution * resolution)
+            .map(|i| {
+                let (x, z) = (i / resolution, i % resolution);
+                [
+                    (x as f64 * heightfield.spacing - half) as f32,
+                    heightfield.heights[i],
+                    (z as f64 * heightfield.spacing - half) as f32,
+                ]
+            })
+            .collect();
+
+        let min_up = max_slope.to_radians().cos();
+        let cells = (resolution - 1) * (resolution - 1);
+        let mut indices = Vec::with_capacity(cells * 6);
+        let mut areas = Vec::with_capacity(cells * 2);
+        for x in 0..resolution - 1 {
+            for z in 0..resolution - 1 {
+                let i00 = x * resolution + z;
+                let (i10, i01, i11) = (i00 + resolution, i00 + 1, i00 + resolution + 1);
+                for triangle in [[i00, i01, i10], [i10, i01, i11]] {
+                    let [a, b, c] = triangle.map(|i| Vector3::from(vertices[i]));
+                    let normal = (b - a).cross(c - a).normalize();
+                    areas.push(if triangle.iter().all(|&i| sea[i]) {
+                        NavigationArea::Water
+                    } else if normal.y < min_up {
+                        NavigationArea::Steep
+                    } else {
+                        NavigationArea::Walkable
+                    });
+                    indices.extend(triangle.map(|i| i as u32));
+                }
+            }
+        }
+
+        NavigationMesh {
+            vertices,
+            indices,
+            areas,
+            transform: heightfield.transform,
+            levels: heightfield.levels,
+        }
+    }
+}
+
+impl TileCache {
+    /// Build a navigation mesh covering `region` from resident heightmaps no more detailed than
+    /// `level`, with vertices spaced like the samples of heightmaps at that level.
+    pub fn navigation_mesh(
+        &self,
+        region: HeightfieldRegion,
+        level: u8,
+        max_slope: f32,
+    ) -> Result<NavigationMesh, Error> {
+        if level > MAX_QUADTREE_LEVEL {
+            return Err(anyhow::format_err!(
+                "Level {} is beyond the deepest quadtree level",
+                level
+            ));
+        }
+        let spacing = (ROOT_SIDE_LENGTH / (512u64 << level) as f32) as f64;
+        let resolution = (region.size / spacing).ceil() as usize + 1;
+        if resolution > MAX_NAVIGATION_MESH_RESOLUTION {
+            return Err(anyhow::format_err!(
+                "Navigation mesh would need {} samples per side, more than the maximum of {}",
+                resolution,
+                MAX_NAVIGATION_MESH_RESOLUTION
+            ));
+        }
+
+        let max_level = level.min(VNode::LEVEL_CELL_1M);
+        let (heightfield, sea) = self.sample_heightfield(region, resolution, max_level)?;
+        Ok(NavigationMesh::from_heightfield(heightfield, &sea, max_slope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangles_are_wound_upward_and_classified() {
+        // A 3x3 grid with a tall spike in the middle and its western edge at sea level.
+        let heightfield = CollisionHeightfield {
+            resolution: 3,
+            spacing: 10.0,
+            heights: vec![0.0, 0.0, 0.0, 1.0, 50.0, 1.0, 1.0, 1.0, 1.0],
+            transform: cgmath::Matrix4::from_scale(1.0).into(),
+            levels: Some((12, 12)),
+        };
+        let sea = [true, true, true, false, false, false, false, false, false];
+        let mesh = NavigationMesh::from_heightfield(heightfield, &sea, 45.0);
+
+        assert_eq!(mesh.vertices.len(), 9);
+        assert_eq!(mesh.indices.len(), 24);
+        assert_eq!(mesh.areas.len(), 8);
+        for triangle in mesh.indices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(mesh.vertices[triangle[i] as usize]));
+            assert!((b - a).cross(c - a).y > 0.0);
+        }
+
+        assert!(mesh.areas.contains(&NavigationArea::Steep));
+        assert!(mesh.areas.contains(&NavigationArea::Walkable));
+        assert!(!mesh.areas.contains(&NavigationArea::Water));
+
+        let flooded = CollisionHeightfield {
+            resolution: 2,
+            spacing: 10.0,
+            heights: vec![0.0; 4],
+            transform: cgmath::Matrix4::from_scale(1.0).into(),
+            levels: None,
+        };
+        let mesh = NavigationMesh::from_heightfield(flooded, &[true; 4], 45.0);
+        assert_eq!(mesh.areas, vec![NavigationArea::Water; 2]);
+    }
+}
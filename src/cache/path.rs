@@ -0,0 +1,131 @@
+use crate::cache::layer::LayerType;
+use crate::cache::tile::CpuHeightmap;
+use crate::cache::TileCache;
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3, VectorSpace};
+use fnv::FnvHashMap;
+use terra_types::{VNode, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS, ROOT_SIDE_LENGTH};
+
+/// Height of the terrain at one point along a path.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PathSample {
+    /// Latitude of the sample in degrees.
+    pub latitude: f64,
+    /// Longitude of the sample in degrees.
+    pub longitude: f64,
+    /// Distance in meters along the path from its start.
+    pub distance: f64,
+    /// Height of the terrain above sea level in meters.
+    pub height: f32,
+}
+
+/// Unit vector pointing towards the given latitude and longitude, which are in degrees.
+fn direction(latitude: f64, longitude: f64) -> Vector3<f64> {
+    let (lat, lon) = (latitude.to_radians(), longitude.to_radians());
+    Vector3::new(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin())
+}
+
+/// Points along the great circle from `start` to `end` at most `spacing` meters apart, as
+/// (direction, distance) pairs. Both endpoints are always included.
+///
+/// Distances are measured on a sphere with the mean radius of the planet, which is accurate to
+/// within about half a percent.
+fn great_circle(start: (f64, f64), end: (f64, f64), spacing: f64) -> Vec<(Vector3<f64>, f64)> {
+    let radius = (2.0 * PLANET_SEMIMAJOR_AXIS + PLANET_SEMIMINOR_AXIS) / 3.0;
+    let (a, b) = (direction(start.0, start.1), direction(end.0, end.1));
+    let angle = a.angle(b).0;
+    let length = angle * radius;
+    let segments = (length / spacing.max(1e-3)).ceil().max(1.0) as usize;
+
+    // Spherical linear interpolation, which degrades to a straight line for tiny angles.
+    (0..=segments)
+        .map(|i| {
+            let t = i as f64 / segments as f64;
+            let d = if angle < 1e-9 {
+                a.lerp(b, t)
+            } else {
+                (a * ((1.0 - t) * angle).sin() + b * (t * angle).sin()) / angle.sin()
+            };
+            (d.normalize(), t * length)
+        })
+        .collect()
+}
+
+impl TileCache {
+    /// Sample the terrain height along the great circle between two latitude/longitude pairs
+    /// given in degrees, with samples at most `spacing` meters apart.
+    ///
+    /// Heightmaps are taken from the tile cache where possible, and any others are loaded
+    /// directly from the tile source. Only streamed heightmaps are used, so heights are limited
+    /// to the resolution of the coarsest level with cells no larger than `spacing`, and never
+    /// finer than the deepest streamed level.
+    pub(crate) async fn sample_path(
+        &self,
+        start: (f64, f64),
+        end: (f64, f64),
+        spacing: f64,
+    ) -> Result<Vec<PathSample>, Error> {
+        let layer = LayerType::BaseHeightmaps;
+        let cells = (layer.texture_resolution() - 2 * layer.texture_border_size() - 1) as f64;
+        let max_level = layer.min_level() + layer.streamed_levels() - 1;
+        let level = (layer.min_level()..=max_level)
+            .find(|&l| ROOT_SIDE_LENGTH as f64 / (1u64 << l) as f64 / cells <= spacing)
+            .unwrap_or(max_level);
+
+        let points: Vec<_> = great_circle(start, end, spacing)
+            .into_iter()
+            .map(|(d, distance)| {
+                let cspace = d / d.x.abs().max(d.y.abs()).max(d.z.abs());
+                (d, distance, VNode::from_cspace(cspace, level))
+            })
+            .collect();
+
+        // Load any heightmaps that aren't already resident.
+        let missing: Vec<VNode> = points
+            .iter()
+            .map(|&(_, _, (node, _, _))| node)
+            .filter(|&node| self.get_cpu_heightmap(node).is_none())
+            .collect();
+        let mut loaded = FnvHashMap::default();
+        for node in missing {
+            if !loaded.contains_key(&node) {
+                let heights = crate::stream::load_heightmap(self.streamer.source(), node).await?;
+                loaded.insert(node, CpuHeightmap::from_streamed(heights));
+            }
+        }
+
+        Ok(points
+            .into_iter()
+            .map(|(d, distance, (node, x, y))| {
+                let heightmap = self.get_cpu_heightmap(node).or_else(|| loaded.get(&node));
+                PathSample {
+                    latitude: d.z.asin().to_degrees(),
+                    longitude: d.y.atan2(d.x).to_degrees(),
+                    distance,
+                    height: self.deformations.apply(d, heightmap.unwrap().sample(x, y)),
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_great_circle() {
+        // A quarter of the way around the equator.
+        let points = great_circle((0.0, 0.0), (0.0, 90.0), 100_000.0);
+        let (last, length) = *points.last().unwrap();
+        assert!((last - Vector3::unit_y()).magnitude() < 1e-9);
+        assert!((length - 10_007_543.0).abs() < 1000.0);
+        for w in points.windows(2) {
+            assert!(w[1].1 - w[0].1 <= 100_000.0 + 1e-6);
+            assert!(w[0].0.z.abs() < 1e-9);
+        }
+
+        // Paths between identical points have both endpoints.
+        assert_eq!(great_circle((45.0, 7.0), (45.0, 7.0), 10.0).len(), 2);
+    }
+}
@@ -0,0 +1,514 @@
+use crate::cache::layer::LayerType;
+use crate::cache::TileCache;
+use cgmath::{InnerSpace, Vector3};
+use terra_types::{VNode, MAX_QUADTREE_LEVEL, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Node bounding spheres only enclose the corners of each node, so they are padded by this
+/// fraction of their radius to also cover the ellipsoid bulging outward between the corners.
+const BOUNDS_MARGIN: f64 = 0.01;
+
+/// Number of bisection steps used to refine an intersection once it has been bracketed.
+const REFINEMENT_STEPS: usize = 24;
+
+/// Terrain closer than this many meters to the end of a sight line doesn't block it, so that
+/// points on the surface are visible despite small differences in how heights are interpolated.
+const LINE_OF_SIGHT_TOLERANCE: f64 = 1.0;
+
+/// Intersection of a ray with the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RaycastHit {
+    /// Position of the intersection in ECEF coordinates.
+    pub position: mint::Point3<f64>,
+    /// Distance in meters from the ray origin to the intersection.
+    pub distance: f64,
+    /// Unit normal of the terrain surface at the intersection.
+    pub normal: mint::Vector3<f64>,
+    /// Quadtree level of the heightmap the intersection was found with. Lower levels are coarser.
+    pub level: u8,
+}
+
+/// Which parts of the terrain around an observer are visible from it, sampled on a square grid
+/// centered below the observer and aligned with the local east and north directions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Viewshed {
+    /// Number of samples along each side of the grid.
+    pub resolution: usize,
+    /// Distance in meters between adjacent samples.
+    pub spacing: f64,
+    /// Visibility of each sample in row-major order. Rows run from south to north and columns
+    /// from west to east. Samples without any resident heightmap are marked as not visible.
+    pub visible: Vec<bool>,
+}
+impl Viewshed {
+    /// Whether the sample `x` columns east and `y` rows north of the southwest corner is visible.
+    pub fn is_visible(&self, x: usize, y: usize) -> bool {
+        self.visible[y * self.resolution + x]
+    }
+}
+
+/// Direction in cube space of the terrain below or above an ECEF position.
+pub(crate) fn to_cspace(position: Vector3<f64>) -> Vector3<f64> {
+    Vector3::new(
+        position.x / PLANET_SEMIMAJOR_AXIS,
+        position.y / PLANET_SEMIMAJOR_AXIS,
+        position.z / PLANET_SEMIMINOR_AXIS,
+    )
+}
+
+/// Point on the ellipsoid in the direction of `cspace`.
+pub(crate) fn ellipsoid_point(cspace: Vector3<f64>) -> Vector3<f64> {
+    let d = cspace.normalize();
+    Vector3::new(
+        d.x * PLANET_SEMIMAJOR_AXIS,
+        d.y * PLANET_SEMIMAJOR_AXIS,
+        d.z * PLANET_SEMIMINOR_AXIS,
+    )
+}
+
+/// Distances along a ray at which it enters and exits a sphere, clamped to start at the origin.
+fn ray_sphere(
+    origin: Vector3<f64>,
+    direction: Vector3<f64>,
+    center: Vector3<f64>,
+    radius: f64,
+) -> Option<(f64, f64)> {
+    let offset = origin - center;
+    let b = offset.dot(direction);
+    let discriminant = b * b - (offset.magnitude2() - radius * radius);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t1 = -b + discriminant.sqrt();
+    if t1 < 0.0 {
+        return None;
+    }
+    Some(((-b - discriminant.sqrt()).max(0.0), t1))
+}
+
+/// Node still to be searched by a raycast.
+struct Candidate {
+    node: VNode,
+    /// Heights used to bound the node.
+    height_range: (f32, f32),
+    /// Level of the most detailed heightmap covering the node.
+    data_level: u8,
+    t0: f64,
+    t1: f64,
+}
+
+/// Heightmaps that rays are cast against. The tile cache provides the CPU copies of its resident
+/// heightmaps, while tests use synthetic terrain.
+trait HeightSource {
+    /// Range of heights in the heightmap of `node`, or `None` if it isn't resident.
+    fn heightmap_range(&self, node: VNode) -> Option<(f32, f32)>;
+
+    /// Height of the terrain in the direction of `cspace`, using the heightmap of the node at
+    /// `level` if it is resident.
+    fn height(&self, cspace: Vector3<f64>, level: u8) -> Option<f32>;
+
+    /// Widen a range of heights to cover any deformations applied to them.
+    fn extend_range(&self, range: (f32, f32)) -> (f32, f32);
+
+    /// Height of the terrain in the direction of `cspace`, using the most detailed resident
+    /// heightmap no deeper than `max_level`.
+    fn terrain_height(&self, cspace: Vector3<f64>, max_level: u8) -> Option<f32> {
+        (0..=max_level).rev().find_map(|level| self.height(cspace, level))
+    }
+
+    fn height_above_terrain(&self, position: Vector3<f64>, max_level: u8) -> Option<f64> {
+        let cspace = to_cspace(position);
+        let height = self.terrain_height(cspace, max_level)? as f64;
+        Some(position.magnitude() - ellipsoid_point(cspace).magnitude() - height)
+    }
+
+    fn terrain_point(&self, position: Vector3<f64>, max_level: u8) -> Option<Vector3<f64>> {
+        let cspace = to_cspace(position);
+        let surface = ellipsoid_point(cspace);
+        Some(surface + surface.normalize() * self.terrain_height(cspace, max_level)? as f64)
+    }
+
+    fn candidate(
+        &self,
+        origin: Vector3<f64>,
+        direction: Vector3<f64>,
+        node: VNode,
+        height_range: (f32, f32),
+        data_level: u8,
+    ) -> Option<Candidate> {
+        // Heights are clamped to sea level when sampled, and then deformed.
+        let height_range = (height_range.0.max(0.0), height_range.1.max(0.0));
+        let (center, radius) = node.bounding_sphere(self.extend_range(height_range));
+        let (t0, t1) = ray_sphere(origin, direction, center, radius * (1.0 + BOUNDS_MARGIN))?;
+        Some(Candidate { node, height_range, data_level, t0, t1 })
+    }
+
+    fn line_of_sight(&self, a: Vector3<f64>, b: Vector3<f64>) -> bool {
+        let distance = (b - a).magnitude();
+        distance <= LINE_OF_SIGHT_TOLERANCE
+            || self.raycast_within(a, b - a, distance - LINE_OF_SIGHT_TOLERANCE).is_none()
+    }
+
+    fn viewshed(
+        &self,
+        observer: Vector3<f64>,
+        radius: f64,
+        resolution: usize,
+        target_height: f64,
+    ) -> Viewshed {
+        let resolution = resolution.max(2);
+        let spacing = 2.0 * radius / (resolution - 1) as f64;
+
+        let up = ellipsoid_point(to_cspace(observer)).normalize();
+        let east = Vector3::unit_z().cross(up);
+        let east = if east.magnitude2() > 1e-12 { east.normalize() } else { Vector3::unit_y() };
+        let north = up.cross(east);
+
+        let mut visible = Vec::with_capacity(resolution * resolution);
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let offset =
+                    east * (x as f64 * spacing - radius) + north * (y as f64 * spacing - radius);
+                visible.push(
+                    self.terrain_point(observer + offset, VNode::LEVEL_CELL_1M)
+                        .map(|p| p + p.normalize() * target_height)
+                        .map_or(false, |target| self.line_of_sight(observer, target)),
+                );
+            }
+        }
+
+        Viewshed { resolution, spacing, visible }
+    }
+
+    /// Find the first intersection of a ray with the terrain that is at most `max_distance`
+    /// meters from its origin.
+    fn raycast_within(
+        &self,
+        origin: Vector3<f64>,
+        direction: Vector3<f64>,
+        max_distance: f64,
+    ) -> Option<RaycastHit> {
+        let direction = direction.normalize();
+
+        let mut candidates: Vec<Candidate> = VNode::roots()
+            .iter()
+            .filter_map(|&root| {
+                let range = self.heightmap_range(root)?;
+                self.candidate(origin, direction, root, range, 0)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.t0.total_cmp(&a.t0));
+
+        let mut closest: Option<RaycastHit> = None;
+        while let Some(c) = candidates.pop() {
+            let t1 = c.t1.min(closest.map_or(max_distance, |hit| hit.distance));
+            if c.t0 >= t1 {
+                continue;
+            }
+
+            // Descend into the children if any of them have more detailed heightmaps. Children
+            // without their own heightmaps are searched using this node's.
+            let mut children = Vec::new();
+            if c.data_level == c.node.level() && c.node.level() < MAX_QUADTREE_LEVEL {
+                let ranges = c.node.children().map(|child| (child, self.heightmap_range(child)));
+                if ranges.iter().any(|(_, range)| range.is_some()) {
+                    for (child, range) in ranges {
+                        let (range, data_level) = match range {
+                            Some(range) => (range, child.level()),
+                            None => (c.height_range, c.data_level),
+                        };
+                        children
+                            .extend(self.candidate(origin, direction, child, range, data_level));
+                    }
+                }
+            }
+            if !children.is_empty() {
+                children.sort_by(|a, b| b.t0.total_cmp(&a.t0));
+                candidates.extend(children);
+                continue;
+            }
+
+            if let Some(hit) = self.march(origin, direction, &c, t1) {
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
+
+    /// Step along the ray through a single node until it passes below the terrain, then refine
+    /// the intersection by bisection.
+    fn march(
+        &self,
+        origin: Vector3<f64>,
+        direction: Vector3<f64>,
+        c: &Candidate,
+        t1: f64,
+    ) -> Option<RaycastHit> {
+        let resolution = LayerType::BaseHeightmaps.texture_resolution();
+        let border = LayerType::BaseHeightmaps.texture_border_size();
+        let spacing = c.node.aprox_side_length() as f64
+            * (1u64 << (c.node.level() - c.data_level)) as f64
+            / (resolution - 2 * border - 1) as f64;
+        let step = spacing * 0.5;
+
+        let height = |t: f64| self.height_above_terrain(origin + direction * t, c.data_level);
+        let in_node = |t: f64| {
+            let cspace = to_cspace(origin + direction * t);
+            let cspace = cspace / cspace.x.abs().max(cspace.y.abs()).max(cspace.z.abs());
+            VNode::from_cspace(cspace, c.node.level()).0 == c.node
+        };
+
+        let mut t = c.t0;
+        let mut above = height(t)? > 0.0;
+        if !above && in_node(t) {
+            return self.hit(origin, direction, t, c.data_level, spacing);
+        }
+        while t < t1 {
+            let next = (t + step).min(t1);
+            let next_above = height(next)? > 0.0;
+            if above && !next_above {
+                let (mut lo, mut hi) = (t, next);
+                for _ in 0..REFINEMENT_STEPS {
+                    let mid = 0.5 * (lo + hi);
+                    if height(mid)? > 0.0 {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                // Crossings outside of this node are found while searching its neighbors.
+                if in_node(hi) {
+                    return self.hit(origin, direction, hi, c.data_level, spacing);
+                }
+            }
+            t = next;
+            above = next_above;
+        }
+        None
+    }
+
+    fn hit(
+        &self,
+        origin: Vector3<f64>,
+        direction: Vector3<f64>,
+        t: f64,
+        level: u8,
+        spacing: f64,
+    ) -> Option<RaycastHit> {
+        let position = origin + direction * t;
+
+        // Estimate the normal with central differences over one heightmap sample.
+        let up = position.normalize();
+        let tangent =
+            up.cross(if up.z.abs() < 0.9 { Vector3::unit_z() } else { Vector3::unit_x() });
+        let tangent = tangent.normalize();
+        let bitangent = up.cross(tangent);
+        let dx = self.terrain_point(position + tangent * spacing, level)?
+            - self.terrain_point(position - tangent * spacing, level)?;
+        let dy = self.terrain_point(position + bitangent * spacing, level)?
+            - self.terrain_point(position - bitangent * spacing, level)?;
+        let mut normal = dx.cross(dy).normalize();
+        if normal.dot(up) < 0.0 {
+            normal = -normal;
+        }
+
+        Some(RaycastHit {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            distance: t,
+            normal: normal.into(),
+            level,
+        })
+    }
+}
+
+impl HeightSource for TileCache {
+    fn heightmap_range(&self, node: VNode) -> Option<(f32, f32)> {
+        self.get_heightmap_range(node)
+    }
+    fn height(&self, cspace: Vector3<f64>, level: u8) -> Option<f32> {
+        self.get_height_cspace(cspace, level)
+    }
+    fn extend_range(&self, range: (f32, f32)) -> (f32, f32) {
+        self.deformations.extend_range(range)
+    }
+}
+
+impl TileCache {
+    /// Height of the terrain in the direction of `cspace`, using the most detailed resident
+    /// heightmap no deeper than `max_level`.
+    pub(crate) fn terrain_height(&self, cspace: Vector3<f64>, max_level: u8) -> Option<f32> {
+        HeightSource::terrain_height(self, cspace, max_level)
+    }
+
+    /// Height of an ECEF position above the terrain directly below it, using heightmaps no more
+    /// detailed than `max_level`. Negative if the position is underground.
+    pub fn height_above_terrain(&self, position: Vector3<f64>, max_level: u8) -> Option<f64> {
+        HeightSource::height_above_terrain(self, position, max_level)
+    }
+
+    /// Point on the terrain surface in the direction of `position`.
+    pub(crate) fn terrain_point(
+        &self,
+        position: Vector3<f64>,
+        max_level: u8,
+    ) -> Option<Vector3<f64>> {
+        HeightSource::terrain_point(self, position, max_level)
+    }
+
+    /// Find the first intersection of a ray with the terrain, using the CPU copies of resident
+    /// heightmaps. Nodes whose bounding spheres the ray misses are skipped along with all their
+    /// descendants, and only the most detailed heightmap available for each node is sampled.
+    pub fn raycast(&self, origin: Vector3<f64>, direction: Vector3<f64>) -> Option<RaycastHit> {
+        self.raycast_within(origin, direction, f64::INFINITY)
+    }
+
+    /// Whether the straight line between `a` and `b` is unobstructed by terrain. Terrain that
+    /// isn't resident in the cache is treated as transparent.
+    pub fn line_of_sight(&self, a: Vector3<f64>, b: Vector3<f64>) -> bool {
+        HeightSource::line_of_sight(self, a, b)
+    }
+
+    /// Compute which points on the terrain within `radius` meters of `observer` are visible
+    /// from it, sampled on a grid with `resolution` samples per side. Each sample is tested
+    /// `target_height` meters above the ground, which allows modeling sensors that look for
+    /// vehicles or buildings rather than the ground itself.
+    pub fn viewshed(
+        &self,
+        observer: Vector3<f64>,
+        radius: f64,
+        resolution: usize,
+        target_height: f64,
+    ) -> Viewshed {
+        HeightSource::viewshed(self, observer, radius, resolution, target_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Level of the most detailed heightmaps of the synthetic terrain.
+    const LEVEL: u8 = 10;
+    const BASE_HEIGHT: f32 = 100.0;
+    /// Distance in meters east of zero longitude along the equator of the crest of the ridge.
+    const RIDGE_EAST: f64 = 1500.0;
+    const RIDGE_WIDTH: f64 = 200.0;
+
+    /// Terrain at a constant height, except for an optional ridge running north to south near
+    /// the point on the equator at zero longitude. Heightmaps are resident down to `LEVEL`.
+    struct SyntheticTerrain {
+        ridge_height: f32,
+    }
+    impl HeightSource for SyntheticTerrain {
+        fn heightmap_range(&self, node: VNode) -> Option<(f32, f32)> {
+            (node.level() <= LEVEL).then_some((BASE_HEIGHT, BASE_HEIGHT + self.ridge_height))
+        }
+        fn height(&self, cspace: Vector3<f64>, level: u8) -> Option<f32> {
+            if level > LEVEL {
+                return None;
+            }
+            // Near zero longitude on the equator, the y axis points east.
+            let east = ellipsoid_point(cspace).y;
+            let ridge = (-((east - RIDGE_EAST) / RIDGE_WIDTH).powi(2)).exp() as f32;
+            Some(BASE_HEIGHT + self.ridge_height * ridge)
+        }
+        fn extend_range(&self, range: (f32, f32)) -> (f32, f32) {
+            range
+        }
+    }
+
+    /// Point `height` meters above the ellipsoid and `east` meters east of zero longitude along
+    /// the equator.
+    fn point(east: f64, height: f64) -> Vector3<f64> {
+        let direction = Vector3::new(PLANET_SEMIMAJOR_AXIS, east, 0.0).normalize();
+        direction * (PLANET_SEMIMAJOR_AXIS + height)
+    }
+
+    #[test]
+    fn raycast_hits_terrain_below() {
+        let terrain = SyntheticTerrain { ridge_height: 0.0 };
+        let hit = terrain.raycast_within(point(0.0, 1000.0), -Vector3::unit_x(), f64::INFINITY);
+        let hit = hit.unwrap();
+        assert!((hit.distance - 900.0).abs() < 0.01, "{}", hit.distance);
+        assert_eq!(hit.level, LEVEL);
+        assert!(Vector3::from(hit.normal).dot(Vector3::unit_x()) > 0.999);
+
+        // Stopping short of the terrain misses it.
+        assert!(terrain.raycast_within(point(0.0, 1000.0), -Vector3::unit_x(), 850.0).is_none());
+    }
+
+    #[test]
+    fn raycast_misses_open_sky() {
+        let terrain = SyntheticTerrain { ridge_height: 0.0 };
+        let origin = point(0.0, 1000.0);
+        assert!(terrain.raycast_within(origin, Vector3::unit_x(), f64::INFINITY).is_none());
+        // Level rays rise away from the curved surface.
+        assert!(terrain.raycast_within(origin, Vector3::unit_y(), f64::INFINITY).is_none());
+        assert!(terrain.raycast_within(origin, Vector3::unit_z(), f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn raycast_grazing_ray() {
+        let terrain = SyntheticTerrain { ridge_height: 0.0 };
+
+        // Descend by one meter every hundred, so that the ray crosses several nodes before
+        // reaching the ground about ten kilometers away.
+        let origin = point(0.0, 200.0);
+        let angle = 0.01f64;
+        let direction = Vector3::new(-angle.sin(), angle.cos(), 0.0);
+        let hit = terrain.raycast_within(origin, direction, f64::INFINITY).unwrap();
+
+        // Along the equator the terrain is a circle of radius a + 100 m.
+        let radius = PLANET_SEMIMAJOR_AXIS + BASE_HEIGHT as f64;
+        let b = origin.dot(direction);
+        let c = origin.magnitude2() - radius * radius;
+        let expected = -b - (b * b - c).sqrt();
+        assert!((hit.distance - expected).abs() < 0.1, "{} != {}", hit.distance, expected);
+        assert!(hit.distance > 9000.0);
+    }
+
+    #[test]
+    fn line_of_sight_over_ridge() {
+        let terrain = SyntheticTerrain { ridge_height: 500.0 };
+        let ground = |east| terrain.terrain_point(point(east, 0.0), LEVEL).unwrap();
+        let observer = point(0.0, BASE_HEIGHT as f64 + 2.0);
+
+        assert!(terrain.line_of_sight(observer, ground(1000.0)));
+        assert!(!terrain.line_of_sight(observer, ground(2000.0)));
+
+        // Seen from high above, the ridge no longer hides the ground behind it.
+        assert!(terrain.line_of_sight(point(0.0, 5000.0), ground(2000.0)));
+    }
+
+    #[test]
+    fn viewshed_behind_ridge() {
+        let terrain = SyntheticTerrain { ridge_height: 500.0 };
+        let observer = point(0.0, BASE_HEIGHT as f64 + 2.0);
+        let viewshed = terrain.viewshed(observer, 2000.0, 5, 0.0);
+        assert_eq!(viewshed.resolution, 5);
+        assert!((viewshed.spacing - 1000.0).abs() < 1e-9);
+
+        // Columns run from 2 km west to 2 km east, so only the last one is behind the ridge.
+        for y in 0..5 {
+            for x in 0..5 {
+                assert_eq!(viewshed.is_visible(x, y), x < 4, "sample ({}, {})", x, y);
+            }
+        }
+
+        // Targets tall enough to stick out above the ridge are visible again.
+        let viewshed = terrain.viewshed(observer, 2000.0, 5, 2000.0);
+        assert!(viewshed.visible.iter().all(|&v| v));
+    }
+
+    #[test]
+    fn test_ray_sphere() {
+        let center = Vector3::new(0.0, 0.0, 10.0);
+        let (t0, t1) =
+            ray_sphere(Vector3::new(0.0, 0.0, 0.0), Vector3::unit_z(), center, 2.0).unwrap();
+        assert!((t0 - 8.0).abs() < 1e-9 && (t1 - 12.0).abs() < 1e-9);
+
+        // Origins inside the sphere start at zero, and spheres behind the origin are missed.
+        assert_eq!(ray_sphere(center, Vector3::unit_z(), center, 2.0), Some((0.0, 2.0)));
+        assert_eq!(ray_sphere(Vector3::new(0.0, 0.0, 20.0), Vector3::unit_z(), center, 2.0), None);
+        assert_eq!(ray_sphere(Vector3::new(5.0, 0.0, 0.0), Vector3::unit_z(), center, 2.0), None);
+    }
+}
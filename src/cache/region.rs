@@ -0,0 +1,292 @@
+use crate::Error;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use terra_types::{VNode, MAX_QUADTREE_LEVEL};
+
+/// Maximum number of vertices in the polygon bounding a region of interest.
+pub const MAX_REGION_VERTICES: usize = 32;
+
+/// Number of segments each edge of a node is split into when testing it against the polygon.
+const EDGE_SEGMENTS: i32 = 8;
+
+/// Restricts streaming to a region of the planet, which saves bandwidth and disk space for
+/// simulators that only cover a limited area. Tiles entirely outside of the region are never
+/// loaded past `outside_max_level`, and the terrain fades to `backdrop_color` beyond its boundary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionOfInterest {
+    /// Vertices of the bounding polygon as latitude/longitude pairs in degrees. The polygon may
+    /// not enclose a pole or span more than 180 degrees of longitude.
+    pub polygon: Vec<(f64, f64)>,
+    /// Deepest quadtree level to load tiles for outside of the polygon.
+    pub outside_max_level: u8,
+    /// Tone mapped color that terrain outside of the polygon fades to.
+    pub backdrop_color: [f32; 3],
+    /// Distance in meters beyond the boundary of the polygon over which the terrain fades out.
+    pub fade_distance: f32,
+}
+impl RegionOfInterest {
+    /// Region bounded by `polygon`, given as latitude/longitude pairs in degrees.
+    pub fn new(polygon: Vec<(f64, f64)>) -> Self {
+        Self {
+            polygon,
+            outside_max_level: 3,
+            backdrop_color: [0.5, 0.5, 0.5],
+            fade_distance: 5000.0,
+        }
+    }
+}
+
+/// Vertices of a high detail inset, such as an airport or city. Inset regions may be loaded
+/// deeper than the overall maximum level, and their tiles take precedence over others when the
+/// tile cache is full.
+///
+/// Insets don't reserve any part of the tile cache for themselves. Their larger share of it comes
+/// only from the higher priority of their tiles, so an inset that needs more tiles than the cache
+/// holds around the camera still falls back to coarser detail at its edges.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsetRegion {
+    /// Vertices of the bounding polygon as latitude/longitude pairs in degrees, with the same
+    /// restrictions as for a `RegionOfInterest`.
+    pub polygon: Vec<(f64, f64)>,
+    /// Deepest quadtree level to load tiles for inside of the polygon. This may exceed the limit
+    /// set with `TerrainBuilder::max_level`, but not level 22, past which there are no tiles.
+    pub max_level: u8,
+    /// Factor by which the tolerated on-screen error is divided inside of the polygon.
+    pub detail_scale: f32,
+}
+impl InsetRegion {
+    /// Inset bounded by `polygon`, given as latitude/longitude pairs in degrees.
+    pub fn new(polygon: Vec<(f64, f64)>, max_level: u8) -> Self {
+        Self { polygon, max_level, detail_scale: 2.0 }
+    }
+}
+
+/// Region of interest along with its polygon.
+#[derive(Clone, Debug)]
+pub(crate) struct Region {
+    desc: RegionOfInterest,
+    polygon: Polygon,
+}
+impl Region {
+    pub fn new(desc: RegionOfInterest) -> Result<Self, Error> {
+        let polygon =
+            Polygon::new(&desc.polygon).map_err(|e| e.context("Invalid region of interest"))?;
+        Ok(Self { desc, polygon })
+    }
+
+    pub fn desc(&self) -> &RegionOfInterest {
+        &self.desc
+    }
+
+    pub fn polygon(&self) -> &Polygon {
+        &self.polygon
+    }
+
+    pub fn outside_max_level(&self) -> u8 {
+        self.desc.outside_max_level
+    }
+}
+
+/// Inset region along with its polygon.
+#[derive(Clone, Debug)]
+pub(crate) struct Inset {
+    desc: InsetRegion,
+    polygon: Polygon,
+}
+impl Inset {
+    pub fn new(desc: InsetRegion) -> Result<Self, Error> {
+        let polygon = Polygon::new(&desc.polygon).map_err(|e| e.context("Invalid inset region"))?;
+        if desc.max_level > MAX_QUADTREE_LEVEL {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Inset maximum level must be at most {}, got {}",
+                MAX_QUADTREE_LEVEL,
+                desc.max_level
+            )));
+        }
+        Ok(Self { desc, polygon })
+    }
+
+    pub fn max_level(&self) -> u8 {
+        self.desc.max_level
+    }
+
+    pub fn detail_scale(&self) -> f64 {
+        self.desc.detail_scale.max(1.0) as f64
+    }
+
+    pub fn overlaps(&self, node: VNode) -> bool {
+        self.polygon.overlaps(node)
+    }
+}
+
+/// Polygon on the surface of the planet, converted into a form that nodes can be efficiently
+/// tested against.
+///
+/// Vertices are stored with longitudes relative to the middle of the polygon, so that polygons
+/// crossing the antimeridian don't need special handling.
+#[derive(Clone, Debug)]
+pub(crate) struct Polygon {
+    center_longitude: f64,
+    /// Longitude and latitude of each vertex in degrees.
+    vertices: Vec<Vector2<f64>>,
+    /// Position of each vertex on the unit cube.
+    vertices_cspace: Vec<Vector3<f64>>,
+}
+impl Polygon {
+    pub fn new(polygon: &[(f64, f64)]) -> Result<Self, Error> {
+        if polygon.len() < 3 || polygon.len() > MAX_REGION_VERTICES {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Polygon must have between 3 and {} vertices",
+                MAX_REGION_VERTICES
+            )));
+        }
+        if polygon.iter().any(|&(lat, lon)| !lat.is_finite() || !lon.is_finite()) {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Polygon has non-finite vertices"
+            )));
+        }
+
+        // Unwrap longitudes so that each vertex is within 180 degrees of the one before it.
+        let mut longitudes = vec![polygon[0].1];
+        for &(_, lon) in &polygon[1..] {
+            let previous = *longitudes.last().unwrap();
+            longitudes.push(previous + wrap_degrees(lon - previous));
+        }
+        let (first, last) = (longitudes[0], *longitudes.last().unwrap());
+        let min = longitudes.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = longitudes.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if (last + wrap_degrees(first - last) - first).abs() > 1.0 || max - min >= 180.0 {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Polygon may not enclose a pole or span 180 degrees of longitude"
+            )));
+        }
+
+        let center_longitude = 0.5 * (min + max);
+        let vertices = polygon
+            .iter()
+            .zip(&longitudes)
+            .map(|(&(lat, _), &lon)| Vector2::new(lon - center_longitude, lat))
+            .collect();
+        let vertices_cspace = polygon
+            .iter()
+            .map(|&(lat, lon)| {
+                let (lat, lon) = (lat.to_radians(), lon.to_radians());
+                let d = Vector3::new(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin());
+                d / d.x.abs().max(d.y.abs()).max(d.z.abs())
+            })
+            .collect();
+
+        Ok(Self { center_longitude, vertices, vertices_cspace })
+    }
+
+    pub fn center_longitude(&self) -> f64 {
+        self.center_longitude
+    }
+
+    /// Vertices as longitude/latitude pairs in degrees, with longitudes relative to
+    /// `center_longitude`.
+    pub fn vertices(&self) -> &[Vector2<f64>] {
+        &self.vertices
+    }
+
+    /// Position of a point on the unit cube in the same coordinates as `vertices`.
+    fn to_local(&self, cspace: Vector3<f64>) -> Vector2<f64> {
+        let d = cspace.normalize();
+        let longitude = d.y.atan2(d.x).to_degrees();
+        Vector2::new(wrap_degrees(longitude - self.center_longitude), d.z.asin().to_degrees())
+    }
+
+    fn contains(&self, p: Vector2<f64>) -> bool {
+        let mut inside = false;
+        for (i, &a) in self.vertices.iter().enumerate() {
+            let b = self.vertices[(i + 1) % self.vertices.len()];
+            if (a.y > p.y) != (b.y > p.y) && a.x + (b.x - a.x) * (p.y - a.y) / (b.y - a.y) > p.x {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// Whether any part of `node` may be inside of the polygon. Node edges are approximated by
+    /// short straight segments in latitude/longitude space.
+    pub fn overlaps(&self, node: VNode) -> bool {
+        if self.vertices_cspace.iter().any(|&v| VNode::from_cspace(v, node.level()).0 == node) {
+            return true;
+        }
+
+        let n = EDGE_SEGMENTS;
+        let boundary: Vec<_> = (0..n)
+            .map(|i| (i, 0))
+            .chain((0..n).map(|i| (n, i)))
+            .chain((0..n).map(|i| (n - i, n)))
+            .chain((0..n).map(|i| (0, n - i)))
+            .map(|(x, y)| self.to_local(node.grid_position_cspace(x, y, 0, n as u32 + 1)))
+            .collect();
+        if boundary.iter().any(|&p| self.contains(p)) {
+            return true;
+        }
+
+        for (i, &a) in boundary.iter().enumerate() {
+            // Keep each segment short even if it crosses the antimeridian.
+            let b = boundary[(i + 1) % boundary.len()];
+            let b = Vector2::new(a.x + wrap_degrees(b.x - a.x), b.y);
+            for (j, &c) in self.vertices.iter().enumerate() {
+                let d = self.vertices[(j + 1) % self.vertices.len()];
+                if segments_intersect(a, b, c, d) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Wrap an angle in degrees to the range [-180, 180).
+fn wrap_degrees(angle: f64) -> f64 {
+    (angle + 180.0).rem_euclid(360.0) - 180.0
+}
+
+fn segments_intersect(a: Vector2<f64>, b: Vector2<f64>, c: Vector2<f64>, d: Vector2<f64>) -> bool {
+    let orientation = |p: Vector2<f64>, q: Vector2<f64>, r: Vector2<f64>| {
+        ((q.x - p.x) * (r.y - p.y) - (q.y - p.y) * (r.x - p.x)).signum()
+    };
+    orientation(a, b, c) != orientation(a, b, d) && orientation(c, d, a) != orientation(c, d, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_overlaps() {
+        // Roughly the Alps.
+        let region = Polygon::new(&[(44.0, 5.0), (44.0, 16.0), (48.0, 16.0), (48.0, 5.0)]).unwrap();
+        let (alps, _, _) = VNode::from_cspace(Vector3::new(0.95, 0.176, 1.0), 10);
+        assert!(region.contains(region.to_local(alps.cell_position_cspace(0, 0, 0, 1))));
+        let mut node = alps;
+        while let Some((parent, _)) = node.parent() {
+            assert!(region.overlaps(node));
+            node = parent;
+        }
+        assert!(region.overlaps(node));
+
+        let (pacific, _, _) = VNode::from_cspace(Vector3::new(-1.0, 0.2, 0.1), 5);
+        assert!(!region.overlaps(pacific));
+
+        // Polygons may cross the antimeridian but not enclose a pole.
+        let fiji =
+            Polygon::new(&[(-20.0, 176.0), (-20.0, -178.0), (-15.0, -178.0), (-15.0, 176.0)])
+                .unwrap();
+        assert!((fiji.center_longitude() - 179.0).abs() < 1e-9);
+        assert!(fiji.contains(fiji.to_local(Vector3::new(-0.94, 0.0, -0.32))));
+        assert!(Polygon::new(&[(80.0, 0.0), (80.0, 120.0), (80.0, -120.0)]).is_err());
+        assert!(Region::new(RegionOfInterest::new(vec![(0.0, 0.0), (1.0, 1.0)])).is_err());
+    }
+
+    #[test]
+    fn test_inset_levels() {
+        let polygon = vec![(44.0, 5.0), (44.0, 16.0), (48.0, 16.0), (48.0, 5.0)];
+        let inset = Inset::new(InsetRegion::new(polygon.clone(), MAX_QUADTREE_LEVEL)).unwrap();
+        assert_eq!(inset.max_level(), MAX_QUADTREE_LEVEL);
+        assert!(Inset::new(InsetRegion::new(polygon, MAX_QUADTREE_LEVEL + 1)).is_err());
+    }
+}
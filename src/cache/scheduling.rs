@@ -0,0 +1,257 @@
+//! Measuring the GPU time taken by each tile generator, and deciding how many tiles each of them
+//! may generate in a frame.
+//!
+//! The dispatches of every generator are bracketed with timestamp queries, and the time per tile
+//! of each timed frame is added to a histogram kept for that generator. A high percentile of the
+//! histogram serves as the expected cost of generating one more tile. Each frame, generators are
+//! granted tiles in order of the most important node they have work for, until the generation
+//! budget is used up. Without support for timestamp queries nothing is measured, and every
+//! generator may run for its full `tiles_per_frame`.
+
+use crate::profiler::{GpuProfiler, PassTiming};
+use std::time::Duration;
+use terra_types::Priority;
+
+/// GPU time per frame that tile generation is scheduled to fit into by default.
+pub(crate) const DEFAULT_GENERATION_BUDGET: Duration = Duration::from_millis(4);
+
+/// Histogram buckets per doubling of the cost, so estimates are at most ~19% too high.
+const BUCKETS_PER_OCTAVE: f64 = 4.0;
+/// Number of histogram buckets, covering costs from one microsecond to about a second.
+const BUCKETS: usize = 80;
+/// Weight that older samples keep each time a new one is recorded, so that the histogram follows
+/// changes in cost like those from a switch of quality settings.
+const DECAY: f32 = 0.95;
+/// Percentile of the recorded costs used as the expected cost of a tile.
+const COST_PERCENTILE: f32 = 0.9;
+
+/// Distribution of the GPU time taken to generate a single tile, with recent samples weighted
+/// more heavily.
+#[derive(Clone)]
+struct CostHistogram {
+    weights: [f32; BUCKETS],
+    total: f32,
+}
+impl CostHistogram {
+    fn new() -> Self {
+        Self { weights: [0.0; BUCKETS], total: 0.0 }
+    }
+
+    fn record(&mut self, cost: Duration) {
+        let micros = (cost.as_secs_f64() * 1e6).max(1.0);
+        let bucket = ((micros.log2() * BUCKETS_PER_OCTAVE) as usize).min(BUCKETS - 1);
+        for weight in &mut self.weights {
+            *weight *= DECAY;
+        }
+        self.weights[bucket] += 1.0;
+        self.total = self.total * DECAY + 1.0;
+    }
+
+    /// Upper bound of the bucket containing the `p`th percentile, or `None` if nothing has been
+    /// recorded.
+    fn percentile(&self, p: f32) -> Option<Duration> {
+        if self.total == 0.0 {
+            return None;
+        }
+        let mut sum = 0.0;
+        let bucket = self
+            .weights
+            .iter()
+            .position(|w| {
+                sum += w;
+                sum >= p * self.total
+            })
+            .unwrap_or(BUCKETS - 1);
+        let micros = 2f64.powf((bucket + 1) as f64 / BUCKETS_PER_OCTAVE);
+        Some(Duration::from_secs_f64(micros * 1e-6))
+    }
+}
+
+/// Work that a generator could do this frame.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct PendingWork {
+    /// Number of tiles the generator has all inputs for, up to `max_tiles`.
+    pub tiles: usize,
+    /// Most tiles the generator may generate in a frame.
+    pub max_tiles: usize,
+    /// Highest priority among the nodes of the pending tiles.
+    pub priority: Priority,
+    /// Expected cost of generating one tile, if it has been measured.
+    pub cost: Option<Duration>,
+}
+
+/// Decide how many tiles each generator may generate within `budget`.
+///
+/// Generators are considered from the most important pending node down, each taking as many
+/// tiles as the remaining budget pays for. Any budget left over goes to generators that have
+/// nothing to do yet, since earlier generators may produce their inputs during the frame.
+/// Generators whose cost is unknown are never limited, and the most important one is always
+/// granted a tile so that generation can't stall.
+pub(super) fn schedule(budget: Duration, work: &[PendingWork]) -> Vec<usize> {
+    let mut grants = vec![0; work.len()];
+    let mut order: Vec<usize> = (0..work.len()).collect();
+    order.sort_by(|&a, &b| {
+        (work[b].tiles > 0).cmp(&(work[a].tiles > 0)).then(work[b].priority.cmp(&work[a].priority))
+    });
+
+    let mut remaining = budget;
+    let mut granted_any = false;
+    for i in order {
+        let cost = match work[i].cost {
+            Some(cost) if !cost.is_zero() => cost,
+            _ => {
+                grants[i] = work[i].max_tiles;
+                continue;
+            }
+        };
+        let affordable = (remaining.as_nanos() / cost.as_nanos()) as usize;
+        grants[i] = work[i].max_tiles.min(affordable);
+        if grants[i] == 0 && !granted_any && work[i].tiles > 0 {
+            grants[i] = 1;
+        }
+        granted_any |= grants[i] > 0;
+        remaining = remaining.saturating_sub(cost * grants[i].min(work[i].tiles) as u32);
+    }
+    grants
+}
+
+/// Per-generator cost tracking. See the module documentation.
+pub(super) struct GeneratorCosts {
+    names: Vec<&'static str>,
+    histograms: Vec<CostHistogram>,
+    /// Created on first use, since the timestamp period comes from the queue.
+    profiler: Option<GpuProfiler>,
+    /// Generators timed so far this frame, with the number of tiles each of them generated.
+    recording: Vec<(usize, usize)>,
+    /// Generators timed in the frame whose timestamps are being read back.
+    inflight: Vec<(usize, usize)>,
+    budget: Duration,
+}
+impl GeneratorCosts {
+    pub fn new(names: Vec<&'static str>) -> Self {
+        Self {
+            histograms: vec![CostHistogram::new(); names.len()],
+            names,
+            profiler: None,
+            recording: Vec::new(),
+            inflight: Vec::new(),
+            budget: DEFAULT_GENERATION_BUDGET,
+        }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    /// Expected GPU time for `generator` to generate one tile, if it has been measured.
+    pub fn tile_cost(&self, generator: usize) -> Option<Duration> {
+        self.histograms[generator].percentile(COST_PERCENTILE)
+    }
+
+    /// Expected GPU time per tile of every generator that has been measured.
+    pub fn tile_costs(&self) -> Vec<PassTiming> {
+        (0..self.names.len())
+            .filter_map(|i| {
+                self.tile_cost(i).map(|duration| PassTiming { name: self.names[i], duration })
+            })
+            .collect()
+    }
+
+    /// Record the timings of an earlier frame if they have been read back, and start timing a
+    /// new one.
+    pub fn begin_frame(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let profiler = self.profiler.get_or_insert_with(|| GpuProfiler::new(device, queue));
+        if profiler.begin_frame(device) {
+            for ((generator, tiles), timing) in self.inflight.drain(..).zip(profiler.timings()) {
+                self.histograms[generator].record(timing.duration / tiles as u32);
+            }
+        }
+        self.recording.clear();
+    }
+
+    /// Start timing the dispatches of `generator`, which is about to generate `tiles` tiles.
+    pub fn begin(&mut self, encoder: &mut wgpu::CommandEncoder, generator: usize, tiles: usize) {
+        let profiler = self.profiler.as_mut().unwrap();
+        if profiler.begin_scope(encoder, self.names[generator]) {
+            self.recording.push((generator, tiles));
+        }
+    }
+
+    pub fn end(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        self.profiler.as_mut().unwrap().end_scope(encoder);
+    }
+
+    /// Copy the timestamps of this frame for readback. `map_results` must be called once
+    /// `encoder` has been submitted.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        self.profiler.as_mut().unwrap().resolve(encoder);
+        if !self.recording.is_empty() {
+            self.inflight = std::mem::take(&mut self.recording);
+        }
+    }
+
+    pub fn map_results(&mut self) {
+        self.profiler.as_mut().unwrap().map_results();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn work(tiles: usize, priority: f32, cost_micros: Option<u64>) -> PendingWork {
+        PendingWork {
+            tiles,
+            max_tiles: 16,
+            priority: Priority::from_f32(priority),
+            cost: cost_micros.map(Duration::from_micros),
+        }
+    }
+
+    #[test]
+    fn histogram_percentile_bounds_samples() {
+        let mut histogram = CostHistogram::new();
+        assert_eq!(histogram.percentile(0.9), None);
+        for _ in 0..100 {
+            histogram.record(Duration::from_micros(100));
+        }
+        let estimate = histogram.percentile(0.9).unwrap();
+        assert!(estimate >= Duration::from_micros(100));
+        assert!(estimate <= Duration::from_micros(120));
+
+        // Recent samples outweigh old ones.
+        for _ in 0..100 {
+            histogram.record(Duration::from_millis(1));
+        }
+        assert!(histogram.percentile(0.5).unwrap() >= Duration::from_millis(1));
+    }
+
+    #[test]
+    fn budget_goes_to_most_important_work() {
+        let grants = schedule(
+            Duration::from_millis(1),
+            &[work(16, 2.0, Some(100)), work(16, 5.0, Some(200)), work(4, 1.5, None)],
+        );
+        assert_eq!(grants, vec![0, 5, 16]);
+    }
+
+    #[test]
+    fn leftover_budget_goes_to_idle_generators() {
+        let grants =
+            schedule(Duration::from_millis(1), &[work(2, 2.0, Some(100)), work(0, 0.0, Some(100))]);
+        assert_eq!(grants, vec![10, 8]);
+    }
+
+    #[test]
+    fn always_makes_progress() {
+        let grants = schedule(
+            Duration::from_millis(1),
+            &[work(1, 3.0, Some(5000)), work(1, 2.0, Some(5000))],
+        );
+        assert_eq!(grants, vec![1, 0]);
+    }
+}
@@ -0,0 +1,77 @@
+/// Model of where snow lies on the terrain, which the material generator uses to cover high
+/// ground in snow. The snow line is highest at the equator and falls towards the poles, and
+/// steep slopes stay bare regardless of altitude.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SnowLine {
+    /// Altitude of the snow line at the equator in meters.
+    pub equator_altitude: f32,
+    /// Altitude of the snow line at the poles in meters. May be negative so that snow reaches
+    /// sea level before the poles.
+    pub polar_altitude: f32,
+    /// Height in meters above the snow line over which snow fades in.
+    pub transition: f32,
+    /// Slopes steeper than this many degrees shed their snow.
+    pub max_slope: f32,
+}
+impl Default for SnowLine {
+    fn default() -> Self {
+        Self {
+            equator_altitude: 5000.0,
+            polar_altitude: -500.0,
+            transition: 300.0,
+            max_slope: 50.0,
+        }
+    }
+}
+impl SnowLine {
+    /// Altitude of the snow line at the given latitude in degrees. Matches the material
+    /// generator.
+    pub fn altitude(&self, latitude: f64) -> f32 {
+        let c = latitude.to_radians().cos() as f32;
+        self.polar_altitude + (self.equator_altitude - self.polar_altitude) * c * c
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct SnowLineUniformBlock {
+    equator_altitude: f32,
+    polar_altitude: f32,
+    transition: f32,
+    /// Smallest vertical component of the surface normal that can hold snow.
+    min_normal_y: f32,
+    /// Zero if snow is disabled.
+    enabled: u32,
+    _padding: [u32; 3],
+}
+unsafe impl bytemuck::Pod for SnowLineUniformBlock {}
+unsafe impl bytemuck::Zeroable for SnowLineUniformBlock {}
+impl SnowLineUniformBlock {
+    pub fn new(snow_line: Option<&SnowLine>) -> Self {
+        match snow_line {
+            Some(s) => Self {
+                equator_altitude: s.equator_altitude,
+                polar_altitude: s.polar_altitude,
+                transition: s.transition.max(1.0),
+                min_normal_y: s.max_slope.clamp(0.0, 90.0).to_radians().cos(),
+                enabled: 1,
+                _padding: [0; 3],
+            },
+            None => bytemuck::Zeroable::zeroed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snow_line_falls_towards_poles() {
+        let snow_line = SnowLine::default();
+        assert_eq!(snow_line.altitude(0.0), 5000.0);
+        assert!((snow_line.altitude(90.0) - -500.0).abs() < 1e-3);
+        assert!(snow_line.altitude(45.0) < snow_line.altitude(30.0));
+        assert_eq!(snow_line.altitude(-45.0), snow_line.altitude(45.0));
+    }
+}
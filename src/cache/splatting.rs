@@ -0,0 +1,221 @@
+use anyhow::Error;
+use std::num::NonZeroU32;
+
+use crate::gpu_state::GpuState;
+
+/// Maximum number of detail materials that can be splatted onto the terrain.
+pub const MAX_SPLAT_MATERIALS: usize = 8;
+
+/// Resolution that detail textures are resampled to.
+pub(crate) const SPLAT_TEXTURE_RESOLUTION: u32 = 512;
+/// Number of mip levels of the detail textures.
+pub(crate) const SPLAT_TEXTURE_MIPS: u32 = 10;
+/// Resolution that splat maps are resampled to.
+pub(crate) const SPLAT_MAP_RESOLUTION: u32 = 2048;
+
+/// A detail texture that replaces the generated ground material wherever the splat map holds
+/// its landcover class and the slope is in range.
+#[derive(Clone, Debug)]
+pub struct SplatMaterial {
+    /// Value of the splat map where this material may appear.
+    pub class: u8,
+    /// Linear albedo in the color channels and roughness in alpha. Resampled to 512x512, so
+    /// it should be square and tile seamlessly.
+    pub albedo_roughness: image::RgbaImage,
+    /// Size in meters covered by one repetition of the texture.
+    pub scale: f32,
+    /// Range of slopes in degrees that the material covers. Materials sharing a class can use
+    /// this to put rock on cliffs and grass on flat ground.
+    pub slope_range: (f32, f32),
+}
+
+/// Application supplied materials, blended over the generated ones by landcover class and slope.
+#[derive(Clone, Debug)]
+pub struct MaterialSplatting {
+    /// Landcover class of each pixel, covering `bounds` in an equirectangular projection with
+    /// north at the top. Resampled to 2048x2048 with nearest neighbor filtering.
+    pub splat_map: image::GrayImage,
+    /// Latitude and longitude in degrees of the southwest and northeast corners of the splat map.
+    pub bounds: ((f64, f64), (f64, f64)),
+    pub materials: Vec<SplatMaterial>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GpuSplatMaterial {
+    class: u32,
+    min_normal_y: f32,
+    max_normal_y: f32,
+    scale: f32,
+}
+unsafe impl bytemuck::Pod for GpuSplatMaterial {}
+unsafe impl bytemuck::Zeroable for GpuSplatMaterial {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct SplattingUniformBlock {
+    /// Latitude and longitude in radians of the southwest and northeast corners of the splat map.
+    bounds: [f32; 4],
+    /// Zero if splatting is disabled.
+    material_count: u32,
+    _padding: [u32; 3],
+    materials: [GpuSplatMaterial; MAX_SPLAT_MATERIALS],
+}
+unsafe impl bytemuck::Pod for SplattingUniformBlock {}
+unsafe impl bytemuck::Zeroable for SplattingUniformBlock {}
+impl SplattingUniformBlock {
+    fn new(splatting: Option<&MaterialSplatting>) -> Self {
+        let mut block: Self = bytemuck::Zeroable::zeroed();
+        if let Some(splatting) = splatting {
+            let ((south, west), (north, east)) = splatting.bounds;
+            block.bounds = [
+                south.to_radians() as f32,
+                west.to_radians() as f32,
+                north.to_radians() as f32,
+                east.to_radians() as f32,
+            ];
+            block.material_count = splatting.materials.len() as u32;
+            for (gpu, material) in block.materials.iter_mut().zip(&splatting.materials) {
+                let (min_slope, max_slope) = material.slope_range;
+                *gpu = GpuSplatMaterial {
+                    class: material.class as u32,
+                    min_normal_y: max_slope.clamp(0.0, 90.0).to_radians().cos(),
+                    max_normal_y: min_slope.clamp(0.0, 90.0).to_radians().cos(),
+                    scale: material.scale,
+                };
+            }
+        }
+        block
+    }
+}
+
+impl MaterialSplatting {
+    fn validate(&self) -> Result<(), Error> {
+        let ((south, west), (north, east)) = self.bounds;
+        if south >= north || west >= east {
+            return Err(anyhow::format_err!("Splat map bounds must have positive size"));
+        }
+        if self.splat_map.width() == 0 || self.splat_map.height() == 0 {
+            return Err(anyhow::format_err!("Splat map must not be empty"));
+        }
+        if self.materials.len() > MAX_SPLAT_MATERIALS {
+            return Err(anyhow::format_err!(
+                "At most {} splat materials are supported",
+                MAX_SPLAT_MATERIALS
+            ));
+        }
+        for material in &self.materials {
+            if material.albedo_roughness.width() == 0 || material.albedo_roughness.height() == 0 {
+                return Err(anyhow::format_err!("Splat material textures must not be empty"));
+            }
+            if material.scale.is_nan() || material.scale <= 0.0 {
+                return Err(anyhow::format_err!("Splat material scale must be positive"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upload `splatting` to the GPU, or disable splatting if it is `None`.
+pub(crate) fn upload(
+    queue: &wgpu::Queue,
+    gpu_state: &GpuState,
+    splatting: Option<&MaterialSplatting>,
+) -> Result<(), Error> {
+    if let Some(splatting) = splatting {
+        splatting.validate()?;
+
+        let splat_map = image::imageops::resize(
+            &splatting.splat_map,
+            SPLAT_MAP_RESOLUTION,
+            SPLAT_MAP_RESOLUTION,
+            image::imageops::FilterType::Nearest,
+        );
+        write_texture_level(
+            queue,
+            &gpu_state.splat_map.0,
+            0,
+            0,
+            SPLAT_MAP_RESOLUTION,
+            1,
+            &splat_map,
+        );
+
+        for (layer, material) in splatting.materials.iter().enumerate() {
+            for mip in 0..SPLAT_TEXTURE_MIPS {
+                let resolution = SPLAT_TEXTURE_RESOLUTION >> mip;
+                let image = image::imageops::resize(
+                    &material.albedo_roughness,
+                    resolution,
+                    resolution,
+                    image::imageops::FilterType::Triangle,
+                );
+                write_texture_level(
+                    queue,
+                    &gpu_state.splat_textures.0,
+                    mip,
+                    layer as u32,
+                    resolution,
+                    4,
+                    &image,
+                );
+            }
+        }
+    }
+
+    let block = SplattingUniformBlock::new(splatting);
+    queue.write_buffer(&gpu_state.splatting, 0, bytemuck::bytes_of(&block));
+    Ok(())
+}
+
+fn write_texture_level(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    mip_level: u32,
+    layer: u32,
+    resolution: u32,
+    bytes_per_texel: u32,
+    data: &[u8],
+) {
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level,
+            origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+            aspect: wgpu::TextureAspect::All,
+        },
+        data,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(NonZeroU32::new(resolution * bytes_per_texel).unwrap()),
+            rows_per_image: None,
+        },
+        wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slope_range_maps_to_normal_range() {
+        let splatting = MaterialSplatting {
+            splat_map: image::GrayImage::new(1, 1),
+            bounds: ((-10.0, -10.0), (10.0, 10.0)),
+            materials: vec![SplatMaterial {
+                class: 3,
+                albedo_roughness: image::RgbaImage::new(1, 1),
+                scale: 4.0,
+                slope_range: (0.0, 60.0),
+            }],
+        };
+        assert!(splatting.validate().is_ok());
+
+        let block = SplattingUniformBlock::new(Some(&splatting));
+        assert_eq!(block.material_count, 1);
+        assert_eq!(block.materials[0].class, 3);
+        assert_eq!(block.materials[0].max_normal_y, 1.0);
+        assert!((block.materials[0].min_normal_y - 0.5).abs() < 1e-6);
+    }
+}
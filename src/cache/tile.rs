@@ -1,12 +1,17 @@
+use crate::cache::events::TileEvent;
 use crate::cache::layer::{LayerMask, LayerType};
-use crate::cache::{GeneratorMask, Levels, PriorityCacheEntry, TileCache};
-use crate::gpu_state::GpuState;
+use crate::cache::scheduling::{self, PendingWork};
+use crate::cache::{compress, DetailLimits, GeneratorMask, Levels, PriorityCacheEntry, TileCache};
+use crate::gpu_state::{map_buffer, GpuState};
+use crate::profiler::GpuProfiler;
+use crate::resources::{ResourceKind, Tracked};
+use crate::telemetry;
 use cgmath::Vector3;
 use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
 use std::{num::NonZeroU32, sync::Arc};
 use terra_types::{
-    Priority, VNode, EARTH_SEMIMAJOR_AXIS, EARTH_SEMIMINOR_AXIS, MAX_QUADTREE_LEVEL,
+    Priority, VNode, MAX_TERRAIN_HEIGHT, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS,
 };
 use vec_map::VecMap;
 
@@ -27,7 +32,10 @@ pub(crate) struct NodeSlot {
     pub(super) level: u32,
     pub(super) coords: [u32; 2],
 
-    pub(super) padding: [u32; 48],
+    pub(super) shadow_mesh_valid_mask: [u32; 4],
+    pub(super) probe_mesh_valid_mask: [u32; 4],
+
+    pub(super) padding: [u32; 40],
 }
 unsafe impl bytemuck::Pod for NodeSlot {}
 unsafe impl bytemuck::Zeroable for NodeSlot {}
@@ -39,9 +47,86 @@ pub(crate) struct ByteRange {
 }
 
 #[derive(Clone)]
-pub(super) enum CpuHeightmap {
-    U16 { min: f32, max: f32, heights: Vec<u16> },
-    F32 { min: f32, max: f32, heights: Arc<Vec<f32>> },
+pub(crate) enum CpuHeightmap {
+    U16 { min: f32, max: f32, errors: [f32; 4], heights: Vec<u16> },
+    F32 { min: f32, max: f32, errors: [f32; 4], heights: Arc<Vec<f32>> },
+}
+impl CpuHeightmap {
+    /// Wrap the raw contents of a streamed heightmap tile.
+    pub(crate) fn from_streamed(heights: Vec<u16>) -> Self {
+        let min = *heights.iter().min().unwrap() as f32 * 0.25 + 1024.0;
+        let max = *heights.iter().max().unwrap() as f32 * 0.25 + 1024.0;
+        let errors = geometric_errors(|i| heights[i] as f32 * 0.25);
+        CpuHeightmap::U16 { min, max, errors, heights }
+    }
+
+    /// Lowest and highest heights in the tile.
+    pub(super) fn height_range(&self) -> (f32, f32) {
+        match self {
+            CpuHeightmap::U16 { min, max, .. } | CpuHeightmap::F32 { min, max, .. } => (*min, *max),
+        }
+    }
+
+    /// Bilinearly interpolate the height at position `(x, y)` within the node, where both
+    /// coordinates range from zero to one. Heights are clamped to sea level.
+    pub(super) fn sample(&self, x: f32, y: f32) -> f32 {
+        let border = LayerType::BaseHeightmaps.texture_border_size() as usize;
+        let resolution = LayerType::BaseHeightmaps.texture_resolution() as usize;
+        let x = (x * (resolution - 2 * border - 1) as f32) + border as f32;
+        let y = (y * (resolution - 2 * border - 1) as f32) + border as f32;
+
+        let w00 = (1.0 - x.fract()) * (1.0 - y.fract());
+        let w10 = x.fract() * (1.0 - y.fract());
+        let w01 = (1.0 - x.fract()) * y.fract();
+        let w11 = x.fract() * y.fract();
+
+        let i00 = x.floor() as usize + y.floor() as usize * resolution;
+        let i10 = x.ceil() as usize + y.floor() as usize * resolution;
+        let i01 = x.floor() as usize + y.ceil() as usize * resolution;
+        let i11 = x.ceil() as usize + y.ceil() as usize * resolution;
+
+        match self {
+            CpuHeightmap::U16 { heights: h, .. } => ((h[i00] as f32 * w00
+                + h[i10] as f32 * w10
+                + h[i01] as f32 * w01
+                + h[i11] as f32 * w11)
+                * 0.25
+                - 1024.0)
+                .max(0.0),
+            CpuHeightmap::F32 { heights: h, .. } => {
+                (h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11).max(0.0)
+            }
+        }
+    }
+}
+
+/// Spacing between representable heights in streamed heightmaps. Differences smaller than this
+/// are quantization noise rather than terrain detail.
+const HEIGHT_QUANTIZATION: f32 = 0.25;
+
+/// Compute the geometric error of each quadrant of a heightmap: the maximum difference between
+/// its heights and those bilinearly interpolated from every other sample. This approximates how
+/// far the node deviates from its parent, and quadrants are ordered the same as child indices.
+fn geometric_errors(height: impl Fn(usize) -> f32) -> [f32; 4] {
+    let resolution = LayerType::BaseHeightmaps.texture_resolution() as usize;
+    let border = LayerType::BaseHeightmaps.texture_border_size() as usize;
+    let size = resolution - 2 * border;
+    let at = |x: usize, y: usize| height((x + border) + (y + border) * resolution);
+
+    let mut errors = [0.0f32; 4];
+    for y in 0..size {
+        let (y0, y1) = if y % 2 == 0 { (y, y) } else { (y - 1, (y + 1).min(size - 1)) };
+        for x in 0..size {
+            if x % 2 == 0 && y % 2 == 0 {
+                continue;
+            }
+            let (x0, x1) = if x % 2 == 0 { (x, x) } else { (x - 1, (x + 1).min(size - 1)) };
+            let interpolated = 0.25 * (at(x0, y0) + at(x1, y0) + at(x0, y1) + at(x1, y1));
+            let quadrant = (x * 2 / size) + 2 * (y * 2 / size);
+            errors[quadrant] = errors[quadrant].max((at(x, y) - interpolated).abs());
+        }
+    }
+    errors.map(|e| (e - HEIGHT_QUANTIZATION).max(0.0))
 }
 
 #[derive(Clone)]
@@ -55,9 +140,13 @@ pub(super) struct Entry {
     /// bitmask of whether the tile for each layer is currently being streamed.
     streaming: bool,
     /// A CPU copy of the heightmap tile, useful for collision detection and such.
-    heightmap: Option<CpuHeightmap>,
+    pub(super) heightmap: Option<CpuHeightmap>,
+    /// Whether a `NodeEventKind::Loaded` event has been reported for this entry.
+    pub(super) loaded: bool,
     /// Map from layer to the generators that were used (perhaps indirectly) to produce it.
     pub(super) generators: VecMap<GeneratorMask>,
+    /// Whether the dynamic layers have been generated at least once for this entry.
+    dynamic_generated: bool,
 }
 impl Entry {
     pub(super) fn new(node: VNode, priority: Priority) -> Self {
@@ -67,7 +156,9 @@ impl Entry {
             valid: LayerMask::empty(),
             streaming: false,
             heightmap: None,
+            loaded: false,
             generators: VecMap::new(),
+            dynamic_generated: false,
         }
     }
 }
@@ -81,6 +172,57 @@ impl PriorityCacheEntry for Entry {
     }
 }
 
+/// Whether a generator with `inputs` and `outputs` can run for `entry` at a level whose layers
+/// are `level_mask`: the node is needed, is missing some of the outputs, and has every input.
+fn can_generate(
+    levels: &Levels,
+    detail_limits: &DetailLimits,
+    level_mask: LayerMask,
+    inputs: LayerMask,
+    outputs: LayerMask,
+    entry: &Entry,
+) -> bool {
+    let peer_inputs = inputs & level_mask;
+    let ancestor_inputs = inputs & !level_mask;
+
+    if entry.priority() < Priority::cutoff() {
+        return false;
+    }
+    if outputs & (!entry.valid) & level_mask == LayerMask::empty() {
+        return false; // nothing to do
+    }
+    if peer_inputs & !entry.valid != LayerMask::empty() {
+        return false; // missing peer inputs
+    }
+    ancestor_inputs == LayerMask::empty()
+        || LayerType::iter().filter(|layer| ancestor_inputs.contains_layer(*layer)).all(|layer| {
+            if entry.node.level() < layer.min_level() {
+                true
+            } else if entry.node.level() <= detail_limits.layer_max_level(layer) {
+                levels.contains_layer(entry.node, layer)
+            } else {
+                let max_level = detail_limits.layer_max_level(layer);
+                match entry.node.find_ancestor(|node| node.level() == max_level) {
+                    Some((ancestor, ..)) => levels.contains_layer(ancestor, layer),
+                    None => false,
+                }
+            }
+        })
+}
+
+/// Most streamed tiles to upload per frame.
+const MAX_TILE_UPLOADS_PER_FRAME: usize = 32;
+
+/// Allocations that the tile cache fills and empties every frame, kept around so that their
+/// capacity is reused rather than allocated anew each time.
+#[derive(Default)]
+pub(super) struct Scratch {
+    queued_slots: Vec<(VNode, usize)>,
+    planned_heightmap_downloads: Vec<(VNode, Tracked<wgpu::Buffer>)>,
+    dynamic_nodes: Vec<u32>,
+    pub(super) node_slots: Vec<NodeSlot>,
+}
+
 impl TileCache {
     pub(super) fn generate_tiles(
         &mut self,
@@ -88,86 +230,52 @@ impl TileCache {
         queue: &wgpu::Queue,
         gpu_state: &GpuState,
         camera: mint::Point3<f64>,
+        profiler: &mut GpuProfiler,
     ) {
+        let _span = tracing::debug_span!("generate_tiles").entered();
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("encoder.tiles.generate"),
         });
+        profiler.begin_scope(&mut encoder, "generate_tiles");
 
-        let mut uniform_data = Vec::new();
+        self.generate_uniforms.reset();
+        self.generator_costs.begin_frame(device, queue);
+        let grants = self.schedule_generators();
+
+        let mut queued_slots = std::mem::take(&mut self.scratch.queued_slots);
         for (generator_index, generator) in self.generators.iter_mut().enumerate() {
             let inputs = generator.inputs();
             let outputs = generator.outputs();
-            let max_tiles = generator.tiles_per_frame();
+            let max_tiles = grants[generator_index];
 
-            let mut queued_slots = Vec::new();
+            queued_slots.clear();
             for level in 0..self.levels.0.len() {
                 let level_mask = self.level_masks[level];
                 let peer_inputs = inputs & level_mask;
                 let ancestor_inputs = inputs & !level_mask;
                 for i in 0..self.levels.0[level].slots().len() {
-                    if queued_slots.len() > max_tiles {
+                    if queued_slots.len() >= max_tiles {
                         break;
                     }
 
                     let entry = &self.levels.0[level].slots()[i];
-                    // let parent_slot = entry.node.parent().and_then(|p| self.levels.get_slot(p.0));
-                    // let parent_entry = entry.node.parent().and_then(|p| self.levels.get(p.0));
-
-                    if entry.priority() < Priority::cutoff() {
+                    if !can_generate(
+                        &self.levels,
+                        &self.detail_limits,
+                        level_mask,
+                        inputs,
+                        outputs,
+                        entry,
+                    ) {
                         continue;
                     }
-                    if outputs & (!entry.valid) & level_mask == LayerMask::empty() {
-                        continue; // nothing to do
-                    }
-                    if peer_inputs & !entry.valid != LayerMask::empty() {
-                        continue; // missing peer inputs
-                    }
-                    // if level == 0 && generator.parent_inputs() != LayerMask::empty() {
-                    //     continue; // generator doesn't work on root nodes
-                    // }
-                    // if level > 0
-                    //     && (parent_entry.is_none()
-                    //         || parent_inputs & !parent_entry.as_ref().unwrap().valid
-                    //             != LayerMask::empty())
-                    // {
-                    //     continue; // missing parent inputs
-                    // }
-                    if ancestor_inputs != LayerMask::empty()
-                        && !LayerType::iter()
-                            .filter(|layer| ancestor_inputs.contains_layer(*layer))
-                            .all(|layer| {
-                                if entry.node.level() < layer.min_level() {
-                                    true
-                                } else if entry.node.level() <= layer.max_level() {
-                                    self.levels.contains_layer(entry.node, layer)
-                                } else {
-                                    let ancestor = entry
-                                        .node
-                                        .find_ancestor(|node| node.level() == layer.max_level())
-                                        .unwrap()
-                                        .0;
-                                    self.levels.contains_layer(ancestor, layer)
-                                }
-                            })
-                    {
-                        continue; // missing ancestor inputs
-                    }
 
                     // Queue the generator to run
-                    queued_slots.push((
-                        entry.node,
-                        i + Levels::base_slot(level as u8),
-                        // parent_slot,
-                    ));
+                    queued_slots.push((entry.node, i + Levels::base_slot(level as u8)));
 
                     // Record which generators were used to generate this tile
                     let mut generators_used = GeneratorMask::from_index(generator_index);
                     generators_used |= self.levels.generator_dependencies(entry.node, peer_inputs);
-                    // if parent_entry.is_some() {
-                    //     generators_used |= self
-                    //         .levels
-                    //         .generator_dependencies(entry.node.parent().unwrap().0, parent_inputs);
-                    // }
                     if ancestor_inputs != LayerMask::empty() {
                         generators_used |= GeneratorMask::all();
                     }
@@ -180,44 +288,132 @@ impl TileCache {
                         LayerType::iter().filter(|&layer| output_mask.contains_layer(layer))
                     {
                         entry.generators.insert(layer.index(), generators_used);
+                        if let Some(validation) = self.validation.as_mut() {
+                            validation.enqueue(generator.name(), entry.node, layer);
+                        }
+                    }
+                    if self.tile_events.is_active() {
+                        self.tile_events.send(TileEvent::Generated {
+                            node: entry.node,
+                            generator: generator.name(),
+                            layers: output_mask.names(),
+                        });
                     }
                 }
             }
 
-            if !queued_slots.is_empty() {
+            if queued_slots.is_empty() {
+                continue;
+            }
+
+            // Weaker devices get generation split into smaller passes.
+            let tiles_per_pass = self.downlevel.map(|d| d.tiles_per_pass).unwrap_or(usize::MAX);
+            self.tiles_generated += queued_slots.len() as u64;
+            metrics::counter!(
+                telemetry::TILES_GENERATED,
+                queued_slots.len() as u64,
+                "generator" => generator.name()
+            );
+            self.generator_costs.begin(&mut encoder, generator_index, queued_slots.len());
+            for batch in queued_slots.chunks(tiles_per_pass) {
                 generator.generate(
                     device,
                     &mut encoder,
                     gpu_state,
-                    &queued_slots,
-                    &mut uniform_data,
+                    batch,
+                    &mut self.generate_uniforms,
                 );
             }
+            self.generator_costs.end(&mut encoder);
         }
+        self.scratch.queued_slots = queued_slots;
+        self.generator_costs.resolve(&mut encoder);
+
+        profiler.end_scope(&mut encoder);
+
+        // Uniforms are only known once every generator has recorded its commands, so they are
+        // uploaded by a separate command buffer that runs first.
+        let mut uploads = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.tiles.upload"),
+        });
+        self.generate_uniforms.upload(device, &mut uploads, gpu_state);
+        self.write_nodes(device, &mut uploads, gpu_state, camera);
+        gpu_state.submit(queue, [uploads.finish(), encoder.finish()]);
+        self.generator_costs.map_results();
+    }
 
-        assert!(uniform_data.len() <= 256 * 1024);
-        queue.write_buffer(&gpu_state.generate_uniforms, 0, &uniform_data);
-        let command_buffer = encoder.finish();
-        self.write_nodes(queue, gpu_state, camera);
-        queue.submit(Some(command_buffer));
+    /// Decide how many tiles each generator may generate this frame, based on the work it has
+    /// pending and how long it took to generate tiles before.
+    fn schedule_generators(&self) -> Vec<usize> {
+        let work: Vec<_> = self
+            .generators
+            .iter()
+            .enumerate()
+            .map(|(generator_index, generator)| {
+                let max_tiles = match self.downlevel {
+                    Some(downlevel) => generator.tiles_per_frame().min(downlevel.tiles_per_frame),
+                    None => generator.tiles_per_frame(),
+                };
+                let mut work = PendingWork {
+                    tiles: 0,
+                    max_tiles,
+                    priority: Priority::none(),
+                    cost: self.generator_costs.tile_cost(generator_index),
+                };
+                'levels: for level in 0..self.levels.0.len() {
+                    for entry in self.levels.0[level].slots() {
+                        if work.tiles >= max_tiles {
+                            break 'levels;
+                        }
+                        if can_generate(
+                            &self.levels,
+                            &self.detail_limits,
+                            self.level_masks[level],
+                            generator.inputs(),
+                            generator.outputs(),
+                            entry,
+                        ) {
+                            work.tiles += 1;
+                            work.priority = work.priority.max(entry.priority);
+                        }
+                    }
+                }
+                work
+            })
+            .collect();
+        scheduling::schedule(self.generator_costs.budget(), &work)
     }
 
+    /// Regenerate the dynamic layers of visible nodes. Nodes that already have them are only
+    /// updated as often as the aerial perspective quality asks for, staggered across frames.
     pub fn run_dynamic_generators(
-        &self,
-        queue: &wgpu::Queue,
+        &mut self,
+        device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &GpuState,
     ) {
-        let mut uniform_data = Vec::new();
+        // Allocate after the uniforms of the static generators, unless there isn't enough room left
+        // for every dynamic generator. Those uniforms were uploaded with an earlier submission.
+        if self.generate_uniforms.remaining() < self.dynamic_generators.len() * 4096 {
+            self.generate_uniforms.reset();
+        }
+
+        let mut dispatches = Vec::new();
+        let mut nodes = std::mem::take(&mut self.scratch.dynamic_nodes);
+        let frame = self.dynamic_frame;
+        self.dynamic_frame += 1;
 
         for g in &self.dynamic_generators {
-            let mut nodes = Vec::new();
+            nodes.clear();
             for level in g.min_level..=g.max_level {
                 let base = Levels::base_slot(level);
-                for (i, slot) in self.levels.0[level as usize].slots().iter().enumerate() {
+                let interval = self.aerial_perspective_quality.update_interval(level);
+                for (i, slot) in self.levels.0[level as usize].slots_mut().iter_mut().enumerate() {
                     if slot.priority >= Priority::cutoff()
                         && g.dependency_mask & !slot.valid == LayerMask::empty()
+                        && (!slot.dynamic_generated || (frame + i as u64) % interval == 0)
                     {
+                        slot.dynamic_generated = true;
                         nodes.push((base + i) as u32);
                     }
                 }
@@ -225,23 +421,25 @@ impl TileCache {
 
             if !nodes.is_empty() {
                 assert!(nodes.len() <= 1024);
-                let uniform_offset = uniform_data.len();
-                uniform_data.extend_from_slice(bytemuck::cast_slice(&nodes));
-                uniform_data.resize(uniform_offset + 4096, 0);
-
-                let mut cpass =
-                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-                cpass.set_pipeline(&g.bindgroup_pipeline.as_ref().unwrap().1);
-                cpass.set_bind_group(
-                    0,
-                    &g.bindgroup_pipeline.as_ref().unwrap().0,
-                    &[uniform_offset as u32],
-                );
-                cpass.dispatch_workgroups(g.resolution.0, g.resolution.1, nodes.len() as u32);
+                let (uniform_offset, data) = self.generate_uniforms.allocate(4096);
+                data[..nodes.len() * 4].copy_from_slice(bytemuck::cast_slice(&nodes));
+
+                let resolution =
+                    (self.aerial_perspective_textures.texture_resolution(g.layer) + 7) / 8;
+                dispatches.push((g, uniform_offset, (resolution, resolution, nodes.len() as u32)));
             }
         }
-
-        queue.write_buffer(&gpu_state.generate_uniforms, 0, &uniform_data);
+        self.scratch.dynamic_nodes = nodes;
+
+        // The uniforms must be in place before any of the dispatches execute.
+        self.generate_uniforms.upload(device, encoder, gpu_state);
+        for (g, uniform_offset, (x, y, z)) in dispatches {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(&g.bindgroup_pipeline.as_ref().unwrap().1);
+            cpass.set_bind_group(0, &g.bindgroup_pipeline.as_ref().unwrap().0, &[uniform_offset]);
+            cpass.dispatch_workgroups(x, y, z);
+        }
     }
 
     pub(crate) fn upload_tiles(
@@ -252,29 +450,38 @@ impl TileCache {
         for layer in LayerType::iter() {
             for level in layer.min_level()..layer.min_level() + layer.streamed_levels() {
                 for ref mut entry in self.levels.0[level as usize].slots_mut() {
+                    // Root nodes are still requested while paused, since nothing can be rendered
+                    // without them.
                     if self.streamer.num_inflight() < 128
+                        && (!self.streaming_paused || level == 0)
                         && entry.priority() >= Priority::cutoff()
                         && !entry.valid.contains_layer(layer)
                         && !entry.streaming
                     {
                         entry.streaming = true;
                         self.streamer.request_tile(entry.node);
+                        self.tile_events.send(TileEvent::Requested { node: entry.node });
                     }
                 }
             }
         }
 
-        while let Some(tile) = self.streamer.try_complete() {
+        // Decoded tiles can arrive in bursts, so spread their uploads over several frames.
+        for _ in 0..MAX_TILE_UPLOADS_PER_FRAME {
+            let tile = match self.streamer.try_complete() {
+                Some(tile) => tile,
+                None => break,
+            };
+            self.tiles_streamed += 1;
+            if let Some(error) = tile.error {
+                self.tile_events.send(TileEvent::StreamingFailed { node: tile.node, error });
+            }
             if let Some(entry) = self.levels.0[tile.node.level() as usize].entry_mut(&tile.node) {
-                // Extract heightmap
-                let mut heights = vec![0u16; 521 * 521];
-                bytemuck::cast_slice_mut(&mut heights)
-                    .copy_from_slice(&tile.layers[LayerType::BaseHeightmaps.index()]);
-                let min = *heights.iter().min().unwrap() as f32 * 0.25 + 1024.0;
-                let max = *heights.iter().max().unwrap() as f32 * 0.25 + 1024.0;
+                self.tile_events.send(TileEvent::Streamed { node: tile.node });
 
                 // Update entry
-                entry.heightmap = Some(CpuHeightmap::U16 { min, max, heights });
+                entry.heightmap = Some(tile.heightmap);
+                self.heightmap_generation += 1;
                 entry.streaming = false;
                 for layer in tile.layers.keys().map(LayerType::from_index) {
                     if layer.level_range().contains(&tile.node.level()) {
@@ -284,38 +491,19 @@ impl TileCache {
 
                 // Upload layers
                 let index = self.levels.get_slot(tile.node).unwrap();
-                for (layer_index, mut data) in tile.layers {
+                for (layer_index, data) in tile.layers {
                     let layer = LayerType::from_index(layer_index);
                     let index = index - Levels::base_slot(layer.min_level());
                     assert_eq!(layer.texture_formats().len(), 1);
                     let resolution = layer.texture_resolution() as usize;
                     let block_size = layer.texture_formats()[0].block_size() as usize;
-                    assert_eq!(resolution % block_size, 0);
-                    let resolution_blocks = resolution / block_size;
-                    let bytes_per_block = layer.texture_formats()[0].bytes_per_block();
-                    let row_bytes = resolution_blocks * bytes_per_block;
+                    let row_bytes =
+                        resolution / block_size * layer.texture_formats()[0].bytes_per_block();
 
                     if !layer.level_range().contains(&tile.node.level()) {
                         continue;
                     }
 
-                    if data.is_empty() {
-                        data.resize(row_bytes * resolution_blocks, 0);
-                    }
-
-                    if cfg!(feature = "small-trace") {
-                        for y in 0..resolution_blocks {
-                            for x in 0..resolution_blocks {
-                                if x % 16 == 0 && y % 16 == 0 {
-                                    continue;
-                                }
-                                let src =
-                                    ((x & !15) + (y & !15) * resolution_blocks) * bytes_per_block;
-                                let dst = (x + y * resolution_blocks) * bytes_per_block;
-                                data.copy_within(src..src + bytes_per_block, dst);
-                            }
-                        }
-                    }
                     assert_eq!(textures[layer].len(), 1);
                     queue.write_texture(
                         wgpu::ImageCopyTexture {
@@ -351,7 +539,8 @@ impl TileCache {
             label: Some("encoder.tiles.readback"),
         });
 
-        let mut planned_heightmap_downloads = Vec::new();
+        let mut planned_heightmap_downloads =
+            std::mem::take(&mut self.scratch.planned_heightmap_downloads);
         for level in (LayerType::BaseHeightmaps.streamed_levels() + 1)..=VNode::LEVEL_CELL_1M {
             for (i, entry) in self.levels.0[level as usize].slots().iter().enumerate() {
                 if self.free_download_buffers.is_empty() && self.total_download_buffers == 64 {
@@ -369,7 +558,7 @@ impl TileCache {
 
                     let buffer = self.free_download_buffers.pop().unwrap_or_else(|| {
                         self.total_download_buffers += 1;
-                        device.create_buffer(&wgpu::BufferDescriptor {
+                        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
                             size: row_pitch * resolution,
                             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
                             label: Some(&format!(
@@ -377,7 +566,13 @@ impl TileCache {
                                 self.total_download_buffers - 1
                             )),
                             mapped_at_creation: false,
-                        })
+                        });
+                        let token = gpu_state.resources.track(
+                            ResourceKind::Buffer,
+                            "tiles.download",
+                            buffer.size(),
+                        );
+                        Tracked::new(buffer, token)
                     });
                     encoder.copy_texture_to_buffer(
                         wgpu::ImageCopyTexture {
@@ -410,7 +605,7 @@ impl TileCache {
             }
         }
 
-        queue.submit(Some(encoder.finish()));
+        gpu_state.submit(queue, Some(encoder.finish()));
 
         let heightmap_resolution = LayerType::BaseHeightmaps.texture_resolution() as usize;
         let heightmap_bytes_per_pixel =
@@ -448,32 +643,51 @@ impl TileCache {
                         max = h;
                     }
                 }
+                let errors = geometric_errors(|i| heights[i]);
 
                 let _ = completed_downloads_tx.send((
                     node,
                     Arc::try_unwrap(buffer).unwrap(),
-                    CpuHeightmap::F32 { min, max, heights: Arc::new(heights) },
+                    CpuHeightmap::F32 { min, max, errors, heights: Arc::new(heights) },
                 ));
             });
         }
+        self.scratch.planned_heightmap_downloads = planned_heightmap_downloads;
 
         while let Ok((node, buffer, heightmap)) = self.completed_downloads_rx.try_recv() {
             self.free_download_buffers.push(buffer);
             if let Some(entry) = self.levels.get_mut(node) {
                 entry.heightmap = Some(heightmap);
+                self.heightmap_generation += 1;
             }
         }
     }
 
-    pub fn compute_visible(&self, layer_mask: LayerMask) -> Vec<(VNode, u8)> {
+    /// Select the nodes to render for the given layers. Only nodes with at least the given
+    /// priority are used, so a higher cutoff selects a coarser subset of the resident nodes.
+    ///
+    /// Priorities are those assigned by `viewpoint`, where viewpoint zero is the main camera, or
+    /// the highest from any viewpoint if it is `None` or unknown.
+    pub fn compute_visible(
+        &self,
+        layer_mask: LayerMask,
+        cutoff: Priority,
+        viewpoint: Option<usize>,
+    ) -> Vec<(VNode, u8)> {
+        let priorities = viewpoint.and_then(|v| self.viewpoint_priorities.get(v));
+
         // Any node with all needed layers in cache is visible...
         let mut node_visibilities: FnvHashMap<VNode, bool> = FnvHashMap::default();
         VNode::breadth_first(|node| match self.levels.0[node.level() as usize].entry(&node) {
             Some(entry) => {
-                let visible = (node.level() == 0 || entry.priority >= Priority::cutoff())
+                let priority = match priorities {
+                    Some(priorities) => *priorities.get(&node).unwrap_or(&Priority::none()),
+                    None => entry.priority,
+                };
+                let visible = (node.level() == 0 || priority >= cutoff)
                     && layer_mask & !entry.valid == LayerMask::empty();
                 node_visibilities.insert(node, visible);
-                visible && node.level() < MAX_QUADTREE_LEVEL
+                visible && node.level() < self.detail_limits.max_level
             }
             None => {
                 node_visibilities.insert(node, false);
@@ -484,7 +698,7 @@ impl TileCache {
         // ...Except if all its children are visible instead.
         let mut visible_nodes = Vec::new();
         VNode::breadth_first(|node| {
-            if node.level() < MAX_QUADTREE_LEVEL && node_visibilities[&node] {
+            if node.level() < self.detail_limits.max_level && node_visibilities[&node] {
                 let mut mask = 0;
                 for (i, c) in node.children().iter().enumerate() {
                     if !node_visibilities[c] {
@@ -510,44 +724,107 @@ impl TileCache {
 
     pub fn get_height(&self, latitude: f64, longitude: f64, level: u8) -> Option<f32> {
         let ecef = Vector3::new(
-            EARTH_SEMIMAJOR_AXIS * f64::cos(latitude) * f64::cos(longitude),
-            EARTH_SEMIMAJOR_AXIS * f64::cos(latitude) * f64::sin(longitude),
-            EARTH_SEMIMINOR_AXIS * f64::sin(latitude),
+            PLANET_SEMIMAJOR_AXIS * f64::cos(latitude) * f64::cos(longitude),
+            PLANET_SEMIMAJOR_AXIS * f64::cos(latitude) * f64::sin(longitude),
+            PLANET_SEMIMINOR_AXIS * f64::sin(latitude),
         );
-        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        self.get_height_cspace(ecef, level)
+    }
 
-        let (node, x, y) = VNode::from_cspace(cspace, level);
+    /// Returns the height of the terrain in the direction of `cspace`, using the heightmap of the
+    /// node at `level` if it is resident.
+    pub fn get_height_cspace(&self, cspace: Vector3<f64>, level: u8) -> Option<f32> {
+        let cspace = cspace / cspace.x.abs().max(cspace.y.abs()).max(cspace.z.abs());
 
-        let border = LayerType::BaseHeightmaps.texture_border_size() as usize;
-        let resolution = LayerType::BaseHeightmaps.texture_resolution() as usize;
-        let x = (x * (resolution - 2 * border - 1) as f32) + border as f32;
-        let y = (y * (resolution - 2 * border - 1) as f32) + border as f32;
+        let (node, x, y) = VNode::from_cspace(cspace, level);
+        let height = self.get_cpu_heightmap(node)?.sample(x, y);
+        Some(self.deformations.apply(cspace, height))
+    }
 
-        let w00 = (1.0 - x.fract()) * (1.0 - y.fract());
-        let w10 = x.fract() * (1.0 - y.fract());
-        let w01 = (1.0 - x.fract()) * y.fract();
-        let w11 = x.fract() * y.fract();
+    /// Returns the CPU copy of the heightmap for `node` if it is resident.
+    pub(super) fn get_cpu_heightmap(&self, node: VNode) -> Option<&CpuHeightmap> {
+        self.levels.0[node.level() as usize].entry(&node)?.heightmap.as_ref()
+    }
 
-        let i00 = x.floor() as usize + y.floor() as usize * resolution;
-        let i10 = x.ceil() as usize + y.floor() as usize * resolution;
-        let i01 = x.floor() as usize + y.ceil() as usize * resolution;
-        let i11 = x.ceil() as usize + y.ceil() as usize * resolution;
+    /// Copy a single tile back from the GPU, blocking until the copy completes.
+    ///
+    /// Returns `None` if the node isn't currently resident or the layer isn't valid for it. The
+    /// returned texels are tightly packed, without any row padding.
+    pub(crate) async fn readback_layer(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        layer: LayerType,
+        node: VNode,
+    ) -> Option<Vec<u8>> {
+        if !layer.level_range().contains(&node.level())
+            || !self.levels.contains_layer(node, layer)
+            || layer.texture_formats()[0].is_compressed()
+        {
+            return None;
+        }
+        let slot = self.levels.get_slot(node)? - Levels::base_slot(layer.min_level());
+
+        // Block compressed tiles are read back as rows of blocks and decoded afterwards.
+        let compressed_format = layer.compressed_format(device.features());
+        let resolution = layer.texture_resolution() as usize;
+        let (row_bytes, rows) = match compressed_format {
+            Some(_) => ((resolution + 3) / 4 * 16, (resolution + 3) / 4),
+            None => (resolution * layer.texture_formats()[0].bytes_per_block(), resolution),
+        };
+        let row_pitch = (row_bytes + 255) & !255;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (row_pitch * rows) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: Some("buffer.tiles.export"),
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.tiles.export"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &gpu_state.tile_cache[layer][0].0,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: 0, y: 0, z: slot as u32 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(NonZeroU32::new(row_pitch as u32).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: resolution as u32,
+                height: resolution as u32,
+                depth_or_array_layers: 1,
+            },
+        );
+        gpu_state.submit(queue, Some(encoder.finish()));
+        map_buffer(device, &buffer).await.ok()?;
+
+        if let Some(format) = compressed_format {
+            let data = compress::decode_blocks(
+                format,
+                &buffer.slice(..).get_mapped_range(),
+                resolution,
+                row_pitch,
+            );
+            buffer.unmap();
+            return Some(data);
+        }
 
-        self.levels.0[node.level() as usize]
-            .entry(&node)
-            .and_then(|entry| Some(entry.heightmap.as_ref()?))
-            .map(|h| match h {
-                CpuHeightmap::U16 { heights: h, .. } => ((h[i00] as f32 * w00
-                    + h[i10] as f32 * w10
-                    + h[i01] as f32 * w01
-                    + h[i11] as f32 * w11)
-                    * 0.25
-                    - 1024.0)
-                    .max(0.0),
-                CpuHeightmap::F32 { heights: h, .. } => {
-                    (h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11).max(0.0)
-                }
-            })
+        let mut data = Vec::with_capacity(row_bytes * resolution);
+        for row in buffer.slice(..).get_mapped_range().chunks_exact(row_pitch) {
+            data.extend_from_slice(&row[..row_bytes]);
+        }
+        buffer.unmap();
+        Some(data)
     }
 
     /// Returns a conservative estimate of the minimum and maximum heights in the given node.
@@ -563,6 +840,40 @@ impl TileCache {
             }
             node = n.parent().map(|p| p.0);
         }
-        (0.0, 9000.0)
+        (0.0, MAX_TERRAIN_HEIGHT as f32)
+    }
+
+    /// Returns the exact range of heights in the node's heightmap, if it is resident.
+    pub(super) fn get_heightmap_range(&self, node: VNode) -> Option<(f32, f32)> {
+        Some(self.levels.0[node.level() as usize].entry(&node)?.heightmap.as_ref()?.height_range())
+    }
+
+    /// Returns an estimate of how far the terrain in the given node deviates from its parent, in
+    /// meters. If the node's heightmap isn't loaded, this is extrapolated from the closest
+    /// ancestor assuming that the error halves with each level.
+    pub fn get_geometric_error(&self, node: VNode) -> Option<f32> {
+        let errors = |n: VNode| match self.levels.0[n.level() as usize]
+            .entry(&n)
+            .and_then(|entry| Some(entry.heightmap.as_ref()?))
+        {
+            Some(CpuHeightmap::U16 { errors, .. } | CpuHeightmap::F32 { errors, .. }) => {
+                Some(*errors)
+            }
+            None => None,
+        };
+
+        if let Some(e) = errors(node) {
+            return Some(e.into_iter().fold(0.0, f32::max));
+        }
+        let mut scale = 0.5;
+        let mut child = node;
+        while let Some((parent, index)) = child.parent() {
+            if let Some(e) = errors(parent) {
+                return Some(e[index as usize] * scale);
+            }
+            scale *= 0.5;
+            child = parent;
+        }
+        None
     }
 }
@@ -0,0 +1,83 @@
+use crate::gpu_state::GpuState;
+
+/// Size of `GpuState::generate_uniforms`.
+pub(crate) const GENERATE_UNIFORMS_SIZE: usize = 256 * 1024;
+
+/// Layout of `GpuState::generate_uniforms` for the current frame.
+///
+/// Every generate pass of a frame gets its own slice of the buffer, bound with a dynamic offset,
+/// so passes recorded into different command buffers never overwrite each other's uniforms. The
+/// slices handed out since the last upload are written to the GPU with a single copy.
+#[derive(Default)]
+pub(crate) struct GenerateUniforms {
+    data: Vec<u8>,
+    /// Length of `data` that has already been uploaded.
+    uploaded: usize,
+}
+impl GenerateUniforms {
+    /// Dynamic uniform buffer offsets must be multiples of this.
+    const ALIGNMENT: usize = 256;
+
+    /// Start laying out the buffer from the beginning again.
+    ///
+    /// Uploads are ordered with the commands that use them, so this is safe even while earlier
+    /// passes are still executing.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.uploaded = 0;
+    }
+
+    /// Number of bytes still available for allocations.
+    pub fn remaining(&self) -> usize {
+        GENERATE_UNIFORMS_SIZE - self.data.len()
+    }
+
+    /// Allocate a zeroed slice of `size` bytes, returning its offset within the buffer.
+    pub fn allocate(&mut self, size: usize) -> (u32, &mut [u8]) {
+        debug_assert_eq!(self.data.len() % Self::ALIGNMENT, 0);
+        let offset = self.data.len();
+        assert!(size <= self.remaining(), "Out of space for generate uniforms");
+        self.data
+            .resize(offset + (size + Self::ALIGNMENT - 1) / Self::ALIGNMENT * Self::ALIGNMENT, 0);
+        (offset as u32, &mut self.data[offset..][..size])
+    }
+
+    /// Record a copy of everything allocated since the last upload into the buffer.
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+    ) {
+        gpu_state.upload(
+            device,
+            encoder,
+            &gpu_state.generate_uniforms,
+            self.uploaded as u64,
+            &self.data[self.uploaded..],
+        );
+        self.uploaded = self.data.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_aligned_and_disjoint() {
+        let mut uniforms = GenerateUniforms::default();
+        let (a, slice) = uniforms.allocate(16);
+        assert_eq!(slice.len(), 16);
+        slice.fill(1);
+        let (b, _) = uniforms.allocate(4096);
+        let (c, _) = uniforms.allocate(4);
+        assert_eq!((a, b, c), (0, 256, 4352));
+        assert_eq!(uniforms.remaining(), GENERATE_UNIFORMS_SIZE - 4608);
+
+        uniforms.reset();
+        let (a, slice) = uniforms.allocate(16);
+        assert_eq!(a, 0);
+        assert!(slice.iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::cache::layer::{LayerType, TextureFormat};
+use std::collections::VecDeque;
+use terra_types::{VNode, MAX_TERRAIN_HEIGHT};
+
+/// Maximum number of generated tiles waiting to be validated. Tiles generated while the queue
+/// is full are skipped, so only a sample of tiles is checked when many are generated at once.
+const MAX_QUEUED_TILES: usize = 64;
+
+/// Heights above this many meters indicate a broken heightmap generator.
+const MAX_PLAUSIBLE_HEIGHT: f32 = MAX_TERRAIN_HEIGHT as f32;
+
+/// Kind of problem detected in a generated tile.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationProblem {
+    /// The tile contains NaN or infinite values.
+    NonFinite,
+    /// The tile contains heights outside of the plausible range.
+    HeightOutOfRange { min: f32, max: f32 },
+    /// Every texel in a color tile is black.
+    Black,
+}
+
+/// Problem found in a tile produced by one of the tile generators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationIssue {
+    /// Name of the generator that produced the tile.
+    pub generator: String,
+    pub node: VNode,
+    /// Name of the layer that failed validation.
+    pub layer: &'static str,
+    pub problem: ValidationProblem,
+}
+
+/// Checks a sample of generated tiles to catch shader regressions.
+#[derive(Default)]
+pub(crate) struct Validator {
+    queue: VecDeque<(String, VNode, LayerType)>,
+    issues: Vec<ValidationIssue>,
+}
+impl Validator {
+    pub fn enqueue(&mut self, generator: &str, node: VNode, layer: LayerType) {
+        if self.queue.len() < MAX_QUEUED_TILES && check_layer(layer, &[]).is_none() {
+            self.queue.push_back((generator.to_owned(), node, layer));
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<(String, VNode, LayerType)> {
+        self.queue.pop_front()
+    }
+
+    /// Check a tile read back from the GPU, returning the issue found, if any.
+    pub fn report(
+        &mut self,
+        generator: String,
+        node: VNode,
+        layer: LayerType,
+        data: &[u8],
+    ) -> Option<ValidationIssue> {
+        let problem = check_layer(layer, data).flatten()?;
+        log::warn!(
+            "Generator '{}' produced invalid {} tile for {}: {:?}",
+            generator,
+            layer.name(),
+            node,
+            problem
+        );
+        let issue = ValidationIssue { generator, node, layer: layer.name(), problem };
+        self.issues.push(issue.clone());
+        Some(issue)
+    }
+
+    pub fn take_issues(&mut self) -> Vec<ValidationIssue> {
+        std::mem::take(&mut self.issues)
+    }
+}
+
+/// Check the contents of a tile for the given layer. Returns `None` if tiles of this layer
+/// can't be validated.
+fn check_layer(layer: LayerType, data: &[u8]) -> Option<Option<ValidationProblem>> {
+    Some(match (layer, layer.texture_formats()[0]) {
+        (LayerType::Heightmaps, TextureFormat::R16) => {
+            let (mut min, mut max) = (f32::MAX, f32::MIN);
+            for h in data.chunks_exact(2) {
+                let h = u16::from_le_bytes([h[0], h[1]]) as f32 * 0.25 - 1024.0;
+                min = min.min(h);
+                max = max.max(h);
+            }
+            (max > MAX_PLAUSIBLE_HEIGHT).then_some(ValidationProblem::HeightOutOfRange { min, max })
+        }
+        (LayerType::AlbedoRoughness, TextureFormat::RGBA8) => (!data.is_empty()
+            && data.chunks_exact(4).all(|t| t[..3] == [0, 0, 0]))
+        .then_some(ValidationProblem::Black),
+        (_, TextureFormat::R32F | TextureFormat::RG32F | TextureFormat::RGBA32F) => data
+            .chunks_exact(4)
+            .any(|v| !f32::from_le_bytes([v[0], v[1], v[2], v[3]]).is_finite())
+            .then_some(ValidationProblem::NonFinite),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_problems() {
+        let nan: Vec<u8> = [1.0f32, f32::NAN].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(
+            check_layer(LayerType::Displacements, &nan),
+            Some(Some(ValidationProblem::NonFinite))
+        );
+        assert_eq!(check_layer(LayerType::Displacements, &1.0f32.to_le_bytes()), Some(None));
+
+        let black = vec![0, 0, 0, 255, 0, 0, 0, 128];
+        assert_eq!(
+            check_layer(LayerType::AlbedoRoughness, &black),
+            Some(Some(ValidationProblem::Black))
+        );
+
+        let tall = ((10000.0f32 + 1024.0) * 4.0) as u16;
+        assert!(matches!(
+            check_layer(LayerType::Heightmaps, &tall.to_le_bytes()),
+            Some(Some(ValidationProblem::HeightOutOfRange { .. }))
+        ));
+
+        assert_eq!(check_layer(LayerType::Normals, &[]), None);
+    }
+}
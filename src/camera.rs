@@ -0,0 +1,134 @@
+//! Cameras positioned in double precision.
+//!
+//! Positions on the planet are millions of meters from its center, which leaves single precision
+//! floats with a resolution of half a meter or worse. Terra therefore works in coordinates
+//! relative to the camera, and expects view projection matrices that are too. `Camera` does that
+//! conversion in double precision, so that embedders don't have to.
+
+use cgmath::{InnerSpace, Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+
+/// Camera placed with a double precision view matrix in ECEF coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    /// Transform from ECEF coordinates to view space. Must be a rigid transform, without any
+    /// scaling.
+    pub view: mint::ColumnMatrix4<f64>,
+    /// Projection from view space to clip space, like the one returned by
+    /// `DepthConfig::projection_matrix`.
+    pub projection: mint::ColumnMatrix4<f32>,
+}
+impl Camera {
+    /// Camera at `eye` looking towards `target`, with `up` pointing towards the top of the view.
+    pub fn look_at(
+        eye: mint::Point3<f64>,
+        target: mint::Point3<f64>,
+        up: mint::Vector3<f64>,
+        projection: mint::ColumnMatrix4<f32>,
+    ) -> Self {
+        let view = Matrix4::look_at_rh(Point3::from(eye), Point3::from(target), up.into());
+        Self { view: view.into(), projection }
+    }
+
+    /// Position of the camera in ECEF coordinates.
+    pub fn position(&self) -> mint::Point3<f64> {
+        let inverse = Matrix4::from(self.view).invert().expect("view matrix must be invertible");
+        Point3::from_homogeneous(inverse.w).into()
+    }
+
+    /// Direction the camera is looking in, in ECEF coordinates.
+    pub fn forward(&self) -> mint::Vector3<f64> {
+        let view = Matrix4::from(self.view);
+        // The rows of the rotation are the view space axes, and the camera looks down -Z.
+        (-Vector3::new(view.x.z, view.y.z, view.z.z).normalize()).into()
+    }
+
+    /// View projection matrix relative to the camera position, as expected by
+    /// `Terrain::update` and `Terrain::render`.
+    ///
+    /// The translation of a rigid view matrix is the rotated camera position, so removing it
+    /// leaves a matrix that maps positions relative to the camera into view space. That matrix
+    /// only holds a rotation, so converting it to single precision loses nothing that matters.
+    pub fn relative_view_proj(&self) -> mint::ColumnMatrix4<f32> {
+        let mut view = Matrix4::from(self.view);
+        view.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+        (Matrix4::from(self.projection) * view.cast::<f32>().unwrap()).into()
+    }
+}
+
+/// Ray through `screen_xy` for a view projection matrix relative to the camera, where both
+/// screen coordinates range from zero to one with the origin in the top left corner. Returns the
+/// point on the near plane relative to the camera, and the unit direction of the ray.
+pub(crate) fn screen_ray(
+    screen_xy: (f32, f32),
+    view_proj: mint::ColumnMatrix4<f32>,
+    near_depth: f32,
+) -> Option<(Vector3<f64>, Vector3<f64>)> {
+    let inverse = Matrix4::from(view_proj).cast::<f64>()?.invert()?;
+    let (x, y) = (screen_xy.0 as f64 * 2.0 - 1.0, 1.0 - screen_xy.1 as f64 * 2.0);
+    let unproject = |depth: f64| {
+        let p = inverse * Vector4::new(x, y, depth, 1.0);
+        p.truncate() / p.w
+    };
+
+    // Halfway through the depth range is always a finite distance beyond the near plane, even
+    // for projections with the far plane at infinity.
+    let near = unproject(near_depth as f64);
+    Some((near, (unproject(0.5) - near).normalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_view_proj_matches_absolute_projection() {
+        let eye = mint::Point3 { x: 6378137.0, y: 1234.5, z: -2000.25 };
+        let target = mint::Point3 { x: 6378000.0, y: 1500.0, z: -1900.0 };
+        let up = mint::Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        let projection = crate::DepthConfig::default().projection_matrix(1.0, 1.5);
+        let camera = Camera::look_at(eye, target, up, projection);
+
+        let position = camera.position();
+        assert!((position.x - eye.x).abs() < 1e-6);
+        assert!((position.y - eye.y).abs() < 1e-6);
+        assert!((position.z - eye.z).abs() < 1e-6);
+
+        let point = Vector3::new(6377990.0, 1480.0, -1910.0);
+        let absolute = Matrix4::from(projection).cast::<f64>().unwrap()
+            * Matrix4::from(camera.view)
+            * point.extend(1.0);
+        let relative = Matrix4::from(camera.relative_view_proj())
+            * (point - Vector3::new(eye.x, eye.y, eye.z)).cast::<f32>().unwrap().extend(1.0);
+        for i in 0..4 {
+            assert!((absolute[i] - relative[i] as f64).abs() < 1e-3 * absolute.w.abs());
+        }
+
+        let forward = camera.forward();
+        let expected = (Vector3::new(target.x, target.y, target.z)
+            - Vector3::new(eye.x, eye.y, eye.z))
+        .normalize();
+        assert!((Vector3::from(forward) - expected).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn screen_ray_through_center_looks_forward() {
+        let eye = mint::Point3 { x: 6378137.0, y: 0.0, z: 0.0 };
+        let target = mint::Point3 { x: 6378000.0, y: 100.0, z: 50.0 };
+        let up = mint::Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        for reverse_z in [false, true] {
+            let depth = crate::DepthConfig { reverse_z, near: 0.1, far: None };
+            let camera = Camera::look_at(eye, target, up, depth.projection_matrix(1.0, 1.5));
+            let near_depth = if reverse_z { 1.0 } else { 0.0 };
+
+            let (near, direction) =
+                screen_ray((0.5, 0.5), camera.relative_view_proj(), near_depth).unwrap();
+            let forward = Vector3::from(camera.forward());
+            assert!((direction - forward).magnitude() < 1e-4);
+            assert!((near - forward * 0.1).magnitude() < 1e-4);
+
+            // Rays toward the top of the screen tilt toward the up vector.
+            let (_, top) = screen_ray((0.5, 0.0), camera.relative_view_proj(), near_depth).unwrap();
+            assert!(top.dot(Vector3::from(up)) > direction.dot(Vector3::from(up)));
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use maplit::hashmap;
 
+use crate::resources::Tracked;
 use crate::GpuState;
 use std::{collections::HashMap, mem};
 
 pub(crate) struct ComputeShader<U> {
     shader: rshader::ShaderSet,
-    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::ComputePipeline)>,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::ComputePipeline)>,
     uniforms: Option<wgpu::Buffer>,
     name: String,
     _phantom: std::marker::PhantomData<U>,
@@ -74,29 +75,12 @@ impl<U: bytemuck::Pod> ComputeShader<U> {
         &self,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
-        _state: &GpuState,
+        state: &GpuState,
         dimensions: (u32, u32, u32),
         uniforms: &U,
     ) {
-        if self.uniforms.is_some() {
-            let staging = device.create_buffer(&wgpu::BufferDescriptor {
-                size: mem::size_of::<U>() as u64,
-                usage: wgpu::BufferUsages::COPY_SRC,
-                label: Some(&format!("buffer.temporary.{}.upload", self.name)),
-                mapped_at_creation: true,
-            });
-            let mut buffer_view = staging.slice(..).get_mapped_range_mut();
-            buffer_view[..mem::size_of::<U>()].copy_from_slice(bytemuck::bytes_of(uniforms));
-            drop(buffer_view);
-            staging.unmap();
-
-            encoder.copy_buffer_to_buffer(
-                &staging,
-                0,
-                self.uniforms.as_ref().unwrap(),
-                0,
-                mem::size_of::<U>() as u64,
-            );
+        if let Some(ref buffer) = self.uniforms {
+            state.upload(device, encoder, buffer, 0, bytemuck::bytes_of(uniforms));
         }
 
         let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
@@ -0,0 +1,184 @@
+//! Errors returned by the public API.
+
+use std::fmt;
+
+/// Error returned by Terra, split into categories that applications can branch on. Network
+/// errors are usually transient and worth retrying, while the others generally aren't.
+///
+/// Each variant holds the underlying error along with any context that was added to it. The
+/// outermost context is part of the message, and the rest is reported through `source`.
+#[derive(Debug)]
+pub enum Error {
+    /// Downloading tiles or assets from a tile server failed.
+    Network(anyhow::Error),
+    /// Tiles, assets or other data were malformed and couldn't be decoded.
+    Decode(anyhow::Error),
+    /// The GPU failed to complete an operation, such as reading back a buffer.
+    Gpu(anyhow::Error),
+    /// Reading or writing tile sources, the local cache, or other files failed.
+    MapFile(anyhow::Error),
+    /// A shader failed to compile.
+    Shader(anyhow::Error),
+    /// An argument was out of range or otherwise unusable.
+    InvalidArgument(anyhow::Error),
+}
+impl Error {
+    /// Whether the operation may succeed if tried again later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::Network(_))
+    }
+
+    /// The underlying error, without its category.
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            Error::Network(e)
+            | Error::Decode(e)
+            | Error::Gpu(e)
+            | Error::MapFile(e)
+            | Error::Shader(e)
+            | Error::InvalidArgument(e) => e,
+        }
+    }
+
+    /// Add context to the underlying error, keeping its category.
+    pub(crate) fn context<C>(self, context: C) -> Error
+    where
+        C: fmt::Display + Send + Sync + 'static,
+    {
+        let category = self.category();
+        category(self.into_inner().context(context))
+    }
+
+    fn into_inner(self) -> anyhow::Error {
+        match self {
+            Error::Network(e)
+            | Error::Decode(e)
+            | Error::Gpu(e)
+            | Error::MapFile(e)
+            | Error::Shader(e)
+            | Error::InvalidArgument(e) => e,
+        }
+    }
+
+    /// Constructor for the variant of this error, used to give the same category to other
+    /// errors.
+    fn category(&self) -> fn(anyhow::Error) -> Error {
+        match self {
+            Error::Network(_) => Error::Network,
+            Error::Decode(_) => Error::Decode,
+            Error::Gpu(_) => Error::Gpu,
+            Error::MapFile(_) => Error::MapFile,
+            Error::Shader(_) => Error::Shader,
+            Error::InvalidArgument(_) => Error::InvalidArgument,
+        }
+    }
+
+    /// Tag an internal error with a category where it happens. The category is kept when
+    /// context is added to the error, and recovered by `categorize`.
+    pub(crate) fn tag(category: fn(anyhow::Error) -> Error, error: anyhow::Error) -> anyhow::Error {
+        Tagged { category, error }.into()
+    }
+
+    /// Categorize an internal error. Errors that were tagged where they happened keep their
+    /// category, and otherwise the category is inferred from the underlying error types, falling
+    /// back to `fallback` if none of them are recognized. Errors that already are an `Error` are
+    /// returned unchanged.
+    pub(crate) fn categorize(error: anyhow::Error, fallback: fn(anyhow::Error) -> Error) -> Error {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let category = error.chain().find_map(|cause| -> Option<fn(anyhow::Error) -> Error> {
+            if let Some(tagged) = cause.downcast_ref::<Tagged>() {
+                Some(tagged.category)
+            } else if let Some(e) = cause.downcast_ref::<Error>() {
+                Some(e.category())
+            } else if cause.is::<wgpu::BufferAsyncError>()
+                || cause.is::<futures::channel::oneshot::Canceled>()
+            {
+                Some(Error::Gpu)
+            } else if cause.is::<image::ImageError>()
+                || cause.is::<ktx2::ParseError>()
+                || cause.is::<quick_xml::DeError>()
+                || cause.is::<serde_json::Error>()
+                || cause.is::<tiff::TiffError>()
+                || cause.is::<zip::result::ZipError>()
+            {
+                Some(Error::Decode)
+            } else if cause.is::<std::io::Error>() {
+                Some(Error::MapFile)
+            } else {
+                None
+            }
+        });
+        category.unwrap_or(fallback)(error)
+    }
+}
+
+/// Error tagged with a category by `Error::tag`. Displays exactly like the error it wraps, so
+/// tagging doesn't change any messages.
+#[derive(Debug)]
+struct Tagged {
+    category: fn(anyhow::Error) -> Error,
+    error: anyhow::Error,
+}
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+impl std::error::Error for Tagged {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let category = match self {
+            Error::Network(_) => "Network error",
+            Error::Decode(_) => "Decode error",
+            Error::Gpu(_) => "GPU error",
+            Error::MapFile(_) => "Map file error",
+            Error::Shader(_) => "Shader error",
+            Error::InvalidArgument(_) => "Invalid argument",
+        };
+        write!(f, "{}: {}", category, self.inner())
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories_survive_context() {
+        let network = Error::tag(Error::Network, anyhow::format_err!("timed out"));
+        let error = Error::categorize(network.context("loading tile list"), Error::MapFile);
+        assert!(matches!(error, Error::Network(_)));
+        assert!(error.is_retryable());
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(error)),
+            "Network error: loading tile list: timed out"
+        );
+
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+        assert!(matches!(Error::categorize(io.into(), Error::Decode), Error::MapFile(_)));
+
+        let unknown = Error::categorize(anyhow::format_err!("bad region"), Error::InvalidArgument);
+        assert!(matches!(unknown, Error::InvalidArgument(_)));
+        assert!(!unknown.is_retryable());
+
+        let invalid = Error::InvalidArgument(anyhow::format_err!("too many vertices"));
+        let invalid = invalid.context("Invalid inset region");
+        assert_eq!(invalid.to_string(), "Invalid argument: Invalid inset region");
+        let invalid = Error::categorize(invalid.into(), Error::MapFile);
+        assert!(matches!(invalid, Error::InvalidArgument(_)));
+        assert_eq!(invalid.to_string(), "Invalid argument: Invalid inset region");
+    }
+}
@@ -0,0 +1,300 @@
+//! Exporting terrain layers as georeferenced images.
+
+use crate::cache::layer::LayerType;
+use anyhow::Error;
+use cgmath::Vector3;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+use terra_types::{VNode, PLANET_RADIUS, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Largest number of pixels that a single export may contain.
+const MAX_EXPORT_PIXELS: usize = 1 << 28;
+
+/// Terrain attribute that can be exported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportLayer {
+    /// Elevation in meters above sea level.
+    Heightmap,
+    /// Surface color, without lighting applied.
+    Albedo,
+}
+impl ExportLayer {
+    /// The tile layer holding this attribute at the given level.
+    pub(crate) fn layer_type(&self, level: u8) -> Option<LayerType> {
+        let layer = match *self {
+            ExportLayer::Heightmap if level <= LayerType::BaseHeightmaps.max_level() => {
+                LayerType::BaseHeightmaps
+            }
+            ExportLayer::Heightmap => LayerType::Heightmaps,
+            ExportLayer::Albedo => LayerType::AlbedoRoughness,
+        };
+        layer.level_range().contains(&level).then_some(layer)
+    }
+}
+
+/// File format to export to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A GeoTIFF in WGS84 coordinates. Heights are stored as 32-bit floats with NaN marking
+    /// missing samples.
+    GeoTiff,
+    /// A PNG file along with an ESRI world file. Heights are stored as 16-bit integers in units
+    /// of 0.25 meters offset by -1024 meters, with zero marking missing samples.
+    Png,
+}
+
+/// Latitude/longitude bounds of an export, in degrees.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ExportRegion {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+/// Resample tiles into a latitude/longitude grid covering `region` and write the result to
+/// `path`. Tiles are fetched with `read_tile`, which should return `None` for any tile that isn't
+/// available.
+pub(crate) fn export_layer<F: FnMut(LayerType, VNode) -> Option<Vec<u8>>>(
+    mut read_tile: F,
+    layer: ExportLayer,
+    region: ExportRegion,
+    level: u8,
+    format: ExportFormat,
+    path: &Path,
+) -> Result<(), Error> {
+    let (width, height) = export_size(layer, region, level)?;
+    let dlon = (region.max_longitude - region.min_longitude) / width as f64;
+    let dlat = (region.max_latitude - region.min_latitude) / height as f64;
+
+    let mut tiles: HashMap<(LayerType, VNode), Option<Vec<u8>>> = HashMap::new();
+    // Returns the texel nearest to the given point, copied into a fixed size array.
+    let mut sample = |latitude: f64, longitude: f64| -> Option<[u8; 4]> {
+        let cspace = cspace(latitude, longitude);
+
+        // Use the requested level where possible, and otherwise fall back to coarser tiles.
+        for l in (0..=level).rev() {
+            let tile_layer = match layer.layer_type(l) {
+                Some(tile_layer) => tile_layer,
+                None => break,
+            };
+            let (node, x, y) = VNode::from_cspace(cspace, l);
+            let tile =
+                tiles.entry((tile_layer, node)).or_insert_with(|| read_tile(tile_layer, node));
+            if let Some(tile) = tile {
+                let bytes = tile_layer.texture_formats()[0].bytes_per_block();
+                let index = texel_index(tile_layer, x, y) * bytes;
+                let mut texel = [0; 4];
+                texel[..bytes].copy_from_slice(&tile[index..][..bytes]);
+                return Some(texel);
+            }
+        }
+        None
+    };
+
+    let pixel_center = |i: usize, j: usize| {
+        (
+            region.max_latitude - (j as f64 + 0.5) * dlat,
+            region.min_longitude + (i as f64 + 0.5) * dlon,
+        )
+    };
+
+    match layer {
+        ExportLayer::Heightmap => {
+            let mut heights = vec![0u16; width * height];
+            for j in 0..height {
+                for i in 0..width {
+                    let (latitude, longitude) = pixel_center(i, j);
+                    if let Some(texel) = sample(latitude, longitude) {
+                        heights[i + j * width] = u16::from_le_bytes([texel[0], texel[1]]).max(1);
+                    }
+                }
+            }
+            match format {
+                ExportFormat::GeoTiff => {
+                    let heights: Vec<f32> = heights
+                        .into_iter()
+                        .map(|h| if h == 0 { f32::NAN } else { h as f32 * 0.25 - 1024.0 })
+                        .collect();
+                    let mut encoder =
+                        tiff::encoder::TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+                    let mut image = encoder.new_image::<tiff::encoder::colortype::Gray32Float>(
+                        width as u32,
+                        height as u32,
+                    )?;
+                    write_geotiff_tags(image.encoder(), &region, dlat, dlon)?;
+                    image.write_data(&heights)?;
+                }
+                ExportFormat::Png => {
+                    image::ImageBuffer::<image::Luma<u16>, _>::from_raw(
+                        width as u32,
+                        height as u32,
+                        heights,
+                    )
+                    .unwrap()
+                    .save_with_format(path, image::ImageFormat::Png)?;
+                    write_world_file(path, &region, dlat, dlon)?;
+                }
+            }
+        }
+        ExportLayer::Albedo => {
+            let mut colors = vec![0u8; width * height * 3];
+            for j in 0..height {
+                for i in 0..width {
+                    let (latitude, longitude) = pixel_center(i, j);
+                    if let Some(texel) = sample(latitude, longitude) {
+                        colors[(i + j * width) * 3..][..3].copy_from_slice(&texel[..3]);
+                    }
+                }
+            }
+            match format {
+                ExportFormat::GeoTiff => {
+                    let mut encoder =
+                        tiff::encoder::TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+                    let mut image = encoder
+                        .new_image::<tiff::encoder::colortype::RGB8>(width as u32, height as u32)?;
+                    write_geotiff_tags(image.encoder(), &region, dlat, dlon)?;
+                    image.write_data(&colors)?;
+                }
+                ExportFormat::Png => {
+                    image::RgbImage::from_raw(width as u32, height as u32, colors)
+                        .unwrap()
+                        .save_with_format(path, image::ImageFormat::Png)?;
+                    write_world_file(path, &region, dlat, dlon)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Width and height in pixels of an export, after checking that it is possible.
+pub(crate) fn export_size(
+    layer: ExportLayer,
+    region: ExportRegion,
+    level: u8,
+) -> Result<(usize, usize), Error> {
+    let top_layer = layer
+        .layer_type(level)
+        .ok_or_else(|| anyhow::format_err!("{:?} is not available at level {}", layer, level))?;
+    if region.min_latitude >= region.max_latitude || region.min_longitude >= region.max_longitude {
+        return Err(anyhow::format_err!("Export region is empty: {:?}", region));
+    }
+
+    // Pick an output resolution that roughly matches the texel spacing at the requested level.
+    let samples_per_tile = (top_layer.texture_resolution()
+        - 2 * top_layer.texture_border_size()
+        - top_layer.grid_registration() as u32) as f64;
+    let spacing = (node_angle(level) / samples_per_tile).to_degrees();
+    let width = ((region.max_longitude - region.min_longitude) / spacing).ceil() as usize;
+    let height = ((region.max_latitude - region.min_latitude) / spacing).ceil() as usize;
+    if width * height > MAX_EXPORT_PIXELS {
+        return Err(anyhow::format_err!(
+            "Export of {}x{} pixels is too large, try a smaller region or a coarser level",
+            width,
+            height
+        ));
+    }
+    Ok((width, height))
+}
+
+/// Nodes at `level` that cover some part of `region`.
+pub(crate) fn region_nodes(region: ExportRegion, level: u8) -> Vec<VNode> {
+    // Nodes near the corners of the cube faces span about a third of the angle of those at the
+    // center, so sampling at an eighth of the nominal spacing can't skip over any of them.
+    let step = (node_angle(level) / 8.0).to_degrees();
+    let steps = |min: f64, max: f64| ((max - min) / step).ceil().max(1.0) as usize;
+    let (lat_steps, lon_steps) = (
+        steps(region.min_latitude, region.max_latitude),
+        steps(region.min_longitude, region.max_longitude),
+    );
+
+    let mut nodes = Vec::new();
+    let mut seen = HashSet::new();
+    for j in 0..=lat_steps {
+        for i in 0..=lon_steps {
+            let latitude = region.min_latitude
+                + (region.max_latitude - region.min_latitude) * j as f64 / lat_steps as f64;
+            let longitude = region.min_longitude
+                + (region.max_longitude - region.min_longitude) * i as f64 / lon_steps as f64;
+            let node = VNode::from_cspace(cspace(latitude, longitude), level).0;
+            if seen.insert(node) {
+                nodes.push(node);
+            }
+        }
+    }
+    nodes
+}
+
+/// Nominal angle spanned by the side of a node at `level`, in radians.
+fn node_angle(level: u8) -> f64 {
+    terra_types::ROOT_SIDE_LENGTH as f64 / (1u64 << level) as f64 / PLANET_RADIUS
+}
+
+/// Position on the unit cube that the point at the given latitude and longitude, in degrees,
+/// projects onto.
+fn cspace(latitude: f64, longitude: f64) -> Vector3<f64> {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    let ecef = Vector3::new(
+        PLANET_SEMIMAJOR_AXIS * latitude.cos() * longitude.cos(),
+        PLANET_SEMIMAJOR_AXIS * latitude.cos() * longitude.sin(),
+        PLANET_SEMIMINOR_AXIS * latitude.sin(),
+    );
+    ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs())
+}
+
+/// Index of the texel nearest to position (x, y) within a tile, where both coordinates range
+/// from zero to one.
+pub(crate) fn texel_index(layer: LayerType, x: f32, y: f32) -> usize {
+    let resolution = layer.texture_resolution() as usize;
+    let border = layer.texture_border_size() as f32;
+    let (scale, offset) = if layer.grid_registration() {
+        ((resolution as f32 - 2.0 * border - 1.0), border)
+    } else {
+        ((resolution as f32 - 2.0 * border), border - 0.5)
+    };
+    let x = ((x * scale + offset).round() as usize).min(resolution - 1);
+    let y = ((y * scale + offset).round() as usize).min(resolution - 1);
+    x + y * resolution
+}
+
+/// Write the tags georeferencing an image in WGS84 latitude/longitude coordinates.
+fn write_geotiff_tags<W: Write + Seek, K: tiff::encoder::TiffKind>(
+    directory: &mut tiff::encoder::DirectoryEncoder<W, K>,
+    region: &ExportRegion,
+    dlat: f64,
+    dlon: f64,
+) -> Result<(), Error> {
+    const MODEL_PIXEL_SCALE: u16 = 33550;
+    const MODEL_TIEPOINT: u16 = 33922;
+    const GEO_KEY_DIRECTORY: u16 = 34735;
+
+    directory.write_tag(tiff::tags::Tag::Unknown(MODEL_PIXEL_SCALE), &[dlon, dlat, 0.0][..])?;
+    directory.write_tag(
+        tiff::tags::Tag::Unknown(MODEL_TIEPOINT),
+        &[0.0, 0.0, 0.0, region.min_longitude, region.max_latitude, 0.0][..],
+    )?;
+    #[rustfmt::skip]
+    directory.write_tag(tiff::tags::Tag::Unknown(GEO_KEY_DIRECTORY), &[
+        1, 1, 0, 3,        // Version 1.1.0, with 3 keys
+        1024, 0, 1, 2,     // GTModelTypeGeoKey = ModelTypeGeographic
+        1025, 0, 1, 1,     // GTRasterTypeGeoKey = RasterPixelIsArea
+        2048, 0, 1, 4326,  // GeographicTypeGeoKey = WGS84
+    ][..])?;
+    Ok(())
+}
+
+/// Write an ESRI world file next to the image at `path`.
+fn write_world_file(path: &Path, region: &ExportRegion, dlat: f64, dlon: f64) -> Result<(), Error> {
+    let mut file = File::create(path.with_extension("pgw"))?;
+    writeln!(file, "{}", dlon)?;
+    writeln!(file, "0.0")?;
+    writeln!(file, "0.0")?;
+    writeln!(file, "{}", -dlat)?;
+    writeln!(file, "{}", region.min_longitude + 0.5 * dlon)?;
+    writeln!(file, "{}", region.max_latitude - 0.5 * dlat)?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Finite flat maps defined by a heightmap, for game worlds that aren't whole planets.
+//!
+//! Rather than adding a second kind of geometry, a flat map is laid out on the plane tangent to
+//! the planet at zero latitude and longitude, which is the center of the first cube face. The
+//! heights of streamed tiles covering the map are replaced by the distance from the ellipsoid to
+//! that plane plus the height from the map, so the quadtree, tile cache and mesh layers all work
+//! unmodified while the rendered surface has no curvature at all.
+
+use crate::cache::layer::LayerType;
+use crate::Error;
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4};
+use std::fmt;
+use terra_types::{VNode, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Furthest distance in meters from the center of a flat map to any of its corners. The planet
+/// falls away from the tangent plane by the square of the distance, and beyond this the heights
+/// needed to reach the plane no longer fit in a heightmap tile.
+pub const MAX_FLAT_MAP_RADIUS: f64 = 250_000.0;
+
+/// Range of heights that streamed heightmap tiles can represent.
+const MIN_TILE_HEIGHT: f64 = -1024.0;
+const MAX_TILE_HEIGHT: f64 = 15359.75;
+
+/// A rectangular heightmap rendered without curvature, in place of the terrain it covers.
+///
+/// Positions on the map use local coordinates in meters: `x` points east, `y` north and `z` up,
+/// with the origin at the center of the heightmap at zero height. Use `ecef_from_local` and
+/// `local_from_ecef` to move between them and the ECEF coordinates used everywhere else.
+///
+/// Only the heights of streamed tiles are replaced, so detail finer than the streamed
+/// heightmaps (about 76 m between samples) is synthesized just like elsewhere on the planet.
+/// Outside of the map the terrain from the tile server remains, which a `RegionOfInterest` around
+/// the map can fade out.
+#[derive(Clone)]
+pub struct FlatMap {
+    width: usize,
+    height: usize,
+    spacing: f64,
+    heights: Vec<f32>,
+}
+impl FlatMap {
+    /// Map with `width` by `height` samples spaced `spacing` meters apart. `heights` holds the
+    /// height of each sample in meters, in rows from south to north.
+    pub fn new(
+        width: usize,
+        height: usize,
+        spacing: f64,
+        heights: Vec<f32>,
+    ) -> Result<Self, Error> {
+        if width < 2 || height < 2 {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Flat maps must be at least 2x2 samples"
+            )));
+        }
+        if heights.len() != width * height {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Expected {} heights for a {}x{} flat map, got {}",
+                width * height,
+                width,
+                height,
+                heights.len()
+            )));
+        }
+        if spacing.is_nan() || spacing <= 0.0 {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Flat map spacing must be positive"
+            )));
+        }
+
+        let map = Self { width, height, spacing, heights };
+        let (half_width, half_height) = map.half_extents();
+        if half_width.hypot(half_height) > MAX_FLAT_MAP_RADIUS {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Flat maps may extend at most {} km from their center",
+                MAX_FLAT_MAP_RADIUS / 1000.0
+            )));
+        }
+        Ok(map)
+    }
+
+    /// Half the size of the map in meters along `x` and `y`.
+    pub fn half_extents(&self) -> (f64, f64) {
+        (
+            (self.width - 1) as f64 * self.spacing * 0.5,
+            (self.height - 1) as f64 * self.spacing * 0.5,
+        )
+    }
+
+    /// Bilinearly interpolated height of the map at local position `(x, y)`, or `None` outside of
+    /// the map.
+    pub fn height_at(&self, x: f64, y: f64) -> Option<f32> {
+        let (half_width, half_height) = self.half_extents();
+        if x.abs() > half_width || y.abs() > half_height {
+            return None;
+        }
+
+        let fx = ((x + half_width) / self.spacing).min((self.width - 1) as f64);
+        let fy = ((y + half_height) / self.spacing).min((self.height - 1) as f64);
+        let (x0, y0) = ((fx as usize).min(self.width - 2), (fy as usize).min(self.height - 2));
+        let (tx, ty) = ((fx - x0 as f64) as f32, (fy - y0 as f64) as f32);
+
+        let h = |x: usize, y: usize| self.heights[x + y * self.width];
+        Some(
+            (h(x0, y0) * (1.0 - tx) + h(x0 + 1, y0) * tx) * (1.0 - ty)
+                + (h(x0, y0 + 1) * (1.0 - tx) + h(x0 + 1, y0 + 1) * tx) * ty,
+        )
+    }
+
+    /// ECEF position of a point given in local coordinates.
+    pub fn local_to_ecef(&self, local: mint::Point3<f64>) -> mint::Point3<f64> {
+        mint::Point3 { x: PLANET_SEMIMAJOR_AXIS + local.z, y: local.x, z: local.y }
+    }
+
+    /// Local coordinates of a point given in ECEF coordinates.
+    pub fn ecef_to_local(&self, ecef: mint::Point3<f64>) -> mint::Point3<f64> {
+        mint::Point3 { x: ecef.y, y: ecef.z, z: ecef.x - PLANET_SEMIMAJOR_AXIS }
+    }
+
+    /// Transform from local coordinates to ECEF coordinates.
+    pub fn ecef_from_local(&self) -> mint::ColumnMatrix4<f64> {
+        Matrix4::from_cols(
+            Vector4::new(0.0, 1.0, 0.0, 0.0),
+            Vector4::new(0.0, 0.0, 1.0, 0.0),
+            Vector4::new(1.0, 0.0, 0.0, 0.0),
+            Vector4::new(PLANET_SEMIMAJOR_AXIS, 0.0, 0.0, 1.0),
+        )
+        .into()
+    }
+
+    /// Transform from ECEF coordinates to local coordinates. Multiplying a view matrix in local
+    /// coordinates by this gives one usable with `Camera`.
+    pub fn local_from_ecef(&self) -> mint::ColumnMatrix4<f64> {
+        Matrix4::from_cols(
+            Vector4::new(0.0, 0.0, 1.0, 0.0),
+            Vector4::new(1.0, 0.0, 0.0, 0.0),
+            Vector4::new(0.0, 1.0, 0.0, 0.0),
+            Vector4::new(0.0, 0.0, -PLANET_SEMIMAJOR_AXIS, 1.0),
+        )
+        .into()
+    }
+
+    /// Height above the ellipsoid that puts the sample at `cspace` onto the surface of the map,
+    /// or `None` if the sample doesn't lie on the map.
+    ///
+    /// Displaced vertices are offset from the ellipsoid along its normal, which also moves them
+    /// sideways a little, so the height is refined a few times against the map position the
+    /// vertex actually ends up at.
+    fn ellipsoid_height(&self, cspace: Vector3<f64>) -> Option<f64> {
+        let d = cspace.normalize();
+        let point = Vector3::new(
+            d.x * PLANET_SEMIMAJOR_AXIS,
+            d.y * PLANET_SEMIMAJOR_AXIS,
+            d.z * PLANET_SEMIMINOR_AXIS,
+        );
+        let latitude = f64::atan2(
+            point.z * PLANET_SEMIMAJOR_AXIS.powi(2) / PLANET_SEMIMINOR_AXIS.powi(2),
+            point.x.hypot(point.y),
+        );
+        let longitude = f64::atan2(point.y, point.x);
+        let normal = Vector3::new(
+            latitude.cos() * longitude.cos(),
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        );
+
+        let mut height = 0.0;
+        for _ in 0..3 {
+            let position = point + normal * height;
+            let z = self.height_at(position.y, position.z)? as f64;
+            height = (PLANET_SEMIMAJOR_AXIS + z - point.x) / normal.x;
+        }
+        Some(height)
+    }
+
+    /// Replace the heights of a streamed heightmap tile wherever it overlaps the map.
+    pub(crate) fn apply(&self, node: VNode, heights: &mut [u16]) {
+        if node.face() != 0 || !self.may_overlap(node) {
+            return;
+        }
+
+        let resolution = LayerType::BaseHeightmaps.texture_resolution();
+        let border = LayerType::BaseHeightmaps.texture_border_size();
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let cspace = node.grid_position_cspace(x as i32, y as i32, border, resolution);
+                if let Some(height) = self.ellipsoid_height(cspace) {
+                    let encoded =
+                        (height.clamp(MIN_TILE_HEIGHT, MAX_TILE_HEIGHT) - MIN_TILE_HEIGHT) * 4.0;
+                    heights[(x + y * resolution) as usize] = encoded.round() as u16;
+                }
+            }
+        }
+    }
+
+    /// Conservative test of whether any sample of `node` could lie on the map, which avoids
+    /// computing positions for every sample of tiles far away.
+    fn may_overlap(&self, node: VNode) -> bool {
+        let (half_width, half_height) = self.half_extents();
+        let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(x, y)| {
+            node.grid_position_cspace(x, y, 0, 2).normalize() * PLANET_SEMIMAJOR_AXIS
+        });
+
+        // Leave room for the sideways offset of displaced vertices.
+        let margin = MAX_TILE_HEIGHT;
+        let (min_y, max_y) =
+            corners.iter().fold((f64::MAX, f64::MIN), |(lo, hi), c| (lo.min(c.y), hi.max(c.y)));
+        let (min_z, max_z) =
+            corners.iter().fold((f64::MAX, f64::MIN), |(lo, hi), c| (lo.min(c.z), hi.max(c.z)));
+        min_y - margin <= half_width
+            && max_y + margin >= -half_width
+            && min_z - margin <= half_height
+            && max_z + margin >= -half_height
+    }
+}
+impl fmt::Debug for FlatMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlatMap")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("spacing", &self.spacing)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_are_displaced_onto_the_plane() {
+        let (width, height) = (201, 101);
+        let heights =
+            (0..width * height).map(|i| ((i % width) as f32 * 0.5).sin() * 50.0).collect();
+        let map = FlatMap::new(width, height, 500.0, heights).unwrap();
+
+        // A node near the edge of the map, where the planet has fallen away the furthest.
+        let (node, _, _) = VNode::from_cspace(Vector3::new(1.0, 0.006, -0.003), 8);
+        let resolution = LayerType::BaseHeightmaps.texture_resolution();
+        let border = LayerType::BaseHeightmaps.texture_border_size();
+        let mut tile = vec![0u16; (resolution * resolution) as usize];
+        map.apply(node, &mut tile);
+
+        let mut checked = 0;
+        for y in (0..resolution as i32).step_by(13) {
+            for x in (0..resolution as i32).step_by(13) {
+                let cspace = node.grid_position_cspace(x, y, border, resolution);
+                let expected = match map.ellipsoid_height(cspace) {
+                    Some(height) => height,
+                    None => continue,
+                };
+                let encoded = tile[(x + y * resolution as i32) as usize];
+                assert!((encoded as f64 * 0.25 + MIN_TILE_HEIGHT - expected).abs() <= 0.125);
+
+                let d = cspace.normalize();
+                let point = Vector3::new(
+                    d.x * PLANET_SEMIMAJOR_AXIS,
+                    d.y * PLANET_SEMIMAJOR_AXIS,
+                    d.z * PLANET_SEMIMINOR_AXIS,
+                );
+                let latitude = f64::atan2(
+                    point.z * PLANET_SEMIMAJOR_AXIS.powi(2) / PLANET_SEMIMINOR_AXIS.powi(2),
+                    point.x.hypot(point.y),
+                );
+                let longitude = f64::atan2(point.y, point.x);
+                let normal = Vector3::new(
+                    latitude.cos() * longitude.cos(),
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                );
+                let local = map.ecef_to_local((point + normal * expected).into());
+                let z = map.height_at(local.x, local.y).unwrap() as f64;
+                assert!((local.z - z).abs() < 0.01, "{} != {}", local.z, z);
+                checked += 1;
+            }
+        }
+        assert!(checked > 100);
+    }
+
+    #[test]
+    fn local_transforms_round_trip() {
+        let map = FlatMap::new(2, 2, 1.0, vec![0.0; 4]).unwrap();
+        let local = mint::Point3 { x: 120.0, y: -35.5, z: 12.25 };
+        let ecef = map.local_to_ecef(local);
+        assert_eq!(map.ecef_to_local(ecef), local);
+
+        let transformed = Matrix4::from(map.ecef_from_local())
+            * Vector3::new(local.x, local.y, local.z).extend(1.0);
+        assert_eq!(transformed.truncate(), Vector3::new(ecef.x, ecef.y, ecef.z));
+        let back = Matrix4::from(map.local_from_ecef()) * transformed;
+        assert_eq!(back.truncate(), Vector3::new(local.x, local.y, local.z));
+    }
+}
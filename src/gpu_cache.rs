@@ -0,0 +1,186 @@
+//! Keyed caches of texture views, bind groups and compute pipelines.
+//!
+//! Passes look these objects up by name and by the texture views they bind rather than creating
+//! them on the spot, so a pass that binds a different view from one frame to the next (like the
+//! depth buffer the Hi-Z pyramid is built from) only creates a bind group the first time it sees
+//! each view. Once every combination in use has been seen, frames create no views, bind groups or
+//! pipelines at all.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+};
+
+use crate::resources::Tracked;
+
+/// Number of frames that a cached view or bind group may go unused before it is dropped. Bind
+/// groups hold on to the views they bind, so without this a resized depth buffer would stay alive
+/// for as long as the terrain does.
+const MAX_IDLE_FRAMES: u64 = 120;
+
+/// Map whose entries are dropped once they haven't been used for `MAX_IDLE_FRAMES`.
+struct ExpiringMap<K, V> {
+    entries: HashMap<K, (V, u64)>,
+}
+impl<K: Hash + Eq, V: Clone> ExpiringMap<K, V> {
+    fn new() -> Self {
+        Self { entries: HashMap::new() }
+    }
+
+    fn get_or_insert_with(&mut self, key: K, frame: u64, create: impl FnOnce() -> V) -> V {
+        let entry = self.entries.entry(key).or_insert_with(|| (create(), frame));
+        entry.1 = frame;
+        entry.0.clone()
+    }
+
+    fn trim(&mut self, frame: u64) {
+        self.entries.retain(|_, (_, last_used)| frame - *last_used <= MAX_IDLE_FRAMES);
+    }
+}
+
+/// Fields of a `wgpu::TextureViewDescriptor` that views are cached by. The label is left out, so
+/// requesting the same view under another label returns the existing one.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ViewKey {
+    texture: wgpu::Id,
+    format: Option<wgpu::TextureFormat>,
+    dimension: Option<wgpu::TextureViewDimension>,
+    aspect: wgpu::TextureAspect,
+    base_mip_level: u32,
+    mip_level_count: Option<NonZeroU32>,
+    base_array_layer: u32,
+    array_layer_count: Option<NonZeroU32>,
+}
+
+/// Objects cached under a single bind group name. Every bind group in a group is created from the
+/// same shader, so they all share one layout and one pipeline.
+struct Group {
+    layout: wgpu::BindGroupLayout,
+    pipeline: Option<Arc<wgpu::ComputePipeline>>,
+    /// Bind group that only binds the default resources of `GpuState`, which is never dropped.
+    default_bind_group: Option<Arc<Tracked<wgpu::BindGroup>>>,
+    /// Bind groups keyed by the views the caller supplied, sorted by binding name.
+    bind_groups: ExpiringMap<Vec<wgpu::Id>, Arc<Tracked<wgpu::BindGroup>>>,
+}
+
+struct CacheState {
+    frame: u64,
+    views: ExpiringMap<ViewKey, Arc<wgpu::TextureView>>,
+    groups: HashMap<String, Group>,
+}
+
+pub(crate) struct GpuCache(Mutex<CacheState>);
+impl GpuCache {
+    pub fn new() -> Self {
+        Self(Mutex::new(CacheState { frame: 0, views: ExpiringMap::new(), groups: HashMap::new() }))
+    }
+
+    /// Returns a view of `texture` matching `desc`, only creating one if none is cached.
+    pub fn texture_view(
+        &self,
+        texture: &wgpu::Texture,
+        desc: &wgpu::TextureViewDescriptor,
+    ) -> Arc<wgpu::TextureView> {
+        let key = ViewKey {
+            texture: texture.global_id(),
+            format: desc.format,
+            dimension: desc.dimension,
+            aspect: desc.aspect,
+            base_mip_level: desc.base_mip_level,
+            mip_level_count: desc.mip_level_count,
+            base_array_layer: desc.base_array_layer,
+            array_layer_count: desc.array_layer_count,
+        };
+        let mut state = self.0.lock().unwrap();
+        let frame = state.frame;
+        state.views.get_or_insert_with(key, frame, || Arc::new(texture.create_view(desc)))
+    }
+
+    /// Returns the bind group and pipeline cached under `group_name` for `views`, calling
+    /// `create_bind_group` and `create_pipeline` for whichever of the two is missing.
+    pub fn bind_group_pipeline(
+        &self,
+        group_name: &str,
+        views: Vec<wgpu::Id>,
+        create_bind_group: impl FnOnce() -> (Tracked<wgpu::BindGroup>, wgpu::BindGroupLayout),
+        create_pipeline: impl FnOnce(&wgpu::BindGroupLayout) -> wgpu::ComputePipeline,
+    ) -> (Arc<Tracked<wgpu::BindGroup>>, Arc<wgpu::ComputePipeline>) {
+        let mut state = self.0.lock().unwrap();
+        let frame = state.frame;
+
+        let mut create_bind_group = Some(create_bind_group);
+        let mut new_bind_group = None;
+        if !state.groups.contains_key(group_name) {
+            let (bind_group, layout) = (create_bind_group.take().unwrap())();
+            new_bind_group = Some(Arc::new(bind_group));
+            state.groups.insert(
+                group_name.to_owned(),
+                Group {
+                    layout,
+                    pipeline: None,
+                    default_bind_group: None,
+                    bind_groups: ExpiringMap::new(),
+                },
+            );
+        }
+        let group = state.groups.get_mut(group_name).unwrap();
+
+        let create = || match new_bind_group.take() {
+            Some(bind_group) => bind_group,
+            None => Arc::new((create_bind_group.take().unwrap())().0),
+        };
+        let bind_group = if views.is_empty() {
+            Arc::clone(group.default_bind_group.get_or_insert_with(create))
+        } else {
+            group.bind_groups.get_or_insert_with(views, frame, create)
+        };
+        let pipeline = Arc::clone(
+            group.pipeline.get_or_insert_with(|| Arc::new(create_pipeline(&group.layout))),
+        );
+        (bind_group, pipeline)
+    }
+
+    /// Drop everything cached under `group_name`, so that it is recreated on next use. Must be
+    /// called whenever the shader of the group is reloaded.
+    pub fn invalidate(&self, group_name: &str) {
+        self.0.lock().unwrap().groups.remove(group_name);
+    }
+
+    /// Advance to the next frame, dropping views and bind groups that have gone unused for too
+    /// long.
+    pub fn end_frame(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.frame += 1;
+        let frame = state.frame;
+        state.views.trim(frame);
+        for group in state.groups.values_mut() {
+            group.bind_groups.trim(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_expire_when_unused() {
+        let mut map = ExpiringMap::new();
+        let mut created = 0;
+        for frame in 0..=MAX_IDLE_FRAMES * 2 {
+            map.get_or_insert_with("used", frame, || {
+                created += 1;
+                0
+            });
+            if frame == 0 {
+                map.get_or_insert_with("idle", frame, || 1);
+            }
+            map.trim(frame);
+        }
+        assert_eq!(created, 1);
+        assert!(map.entries.contains_key("used"));
+        assert!(!map.entries.contains_key("idle"));
+    }
+}
@@ -1,12 +1,34 @@
-use std::{borrow::Cow, collections::HashMap, io::Cursor, num::NonZeroU8};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Cursor,
+    num::NonZeroU8,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
     billboards::Models,
     cache::{
+        compress::{tile_compression_buffer_size, STAGING_LAYERS},
+        debug::DEBUG_BOXES_BUFFER_SIZE,
+        deformation::DEFORMATIONS_BUFFER_SIZE,
         layer::{LayerType, MeshType, LAYERS_BY_NAME},
+        region::{Region, MAX_REGION_VERTICES},
+        snow::SnowLineUniformBlock,
+        splatting::{
+            SplattingUniformBlock, MAX_SPLAT_MATERIALS, SPLAT_MAP_RESOLUTION, SPLAT_TEXTURE_MIPS,
+            SPLAT_TEXTURE_RESOLUTION,
+        },
+        uniforms::GENERATE_UNIFORMS_SIZE,
         Levels, TileCache,
     },
+    gpu_cache::GpuCache,
+    hiz::{HIZ_MIP_LEVELS, HIZ_SIZE},
     mapfile::MapFile,
+    postprocess::{ExposureState, HISTOGRAM_BINS},
+    resources::{texture_bytes, ResourceKind, ResourceRegistry, ResourceToken, Tracked},
+    weather::{GROUND_SAMPLES, MAX_PARTICLES, PARTICLE_SIZE},
+    GrassParameters, HeightFog,
 };
 use terra_types::MAX_QUADTREE_LEVEL;
 use vec_map::VecMap;
@@ -37,11 +59,136 @@ pub(crate) struct GlobalUniformBlock {
     pub screen_height: f32,
     pub sidereal_time: f32,
     pub exposure: f32,
-    pub _padding: [f32; 2],
+    pub simplified_shading: u32,
+    pub water_quality: u32,
+    pub moon_direction: [f32; 3],
+    pub moon_angular_radius: f32,
+    /// Depth values of the near and far planes, which are swapped when using reversed Z.
+    pub near_depth: f32,
+    pub far_depth: f32,
+    /// Which `DebugView` the terrain is drawn with, or zero for regular shading.
+    pub debug_view: u32,
+    /// Zero if the atmosphere is disabled, in which case the sky is black and there is no aerial
+    /// perspective.
+    pub atmosphere: u32,
 }
 unsafe impl bytemuck::Pod for GlobalUniformBlock {}
 unsafe impl bytemuck::Zeroable for GlobalUniformBlock {}
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct RegionUniformBlock {
+    pub backdrop_color: [f32; 3],
+    pub fade_distance: f32,
+    pub center_longitude: f32,
+    /// Zero if there is no region of interest.
+    pub num_vertices: u32,
+    pub _padding: [u32; 2],
+    /// Longitude/latitude pairs in radians, packed two to a vector.
+    pub vertices: [[f32; 4]; MAX_REGION_VERTICES / 2],
+}
+unsafe impl bytemuck::Pod for RegionUniformBlock {}
+unsafe impl bytemuck::Zeroable for RegionUniformBlock {}
+impl RegionUniformBlock {
+    fn new(region: Option<&Region>) -> Self {
+        let mut block: Self = bytemuck::Zeroable::zeroed();
+        if let Some(region) = region {
+            block.backdrop_color = region.desc().backdrop_color;
+            block.fade_distance = region.desc().fade_distance;
+            block.center_longitude = region.polygon().center_longitude().to_radians() as f32;
+            block.num_vertices = region.polygon().vertices().len() as u32;
+            for (i, v) in region.polygon().vertices().iter().enumerate() {
+                block.vertices[i / 2][i % 2 * 2] = v.x.to_radians() as f32;
+                block.vertices[i / 2][i % 2 * 2 + 1] = v.y.to_radians() as f32;
+            }
+        }
+        block
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct FogUniformBlock {
+    pub color: [f32; 3],
+    /// Zero if height fog is disabled.
+    pub density: f32,
+    pub falloff: f32,
+    pub base_height: f32,
+    pub _padding: [f32; 2],
+}
+unsafe impl bytemuck::Pod for FogUniformBlock {}
+unsafe impl bytemuck::Zeroable for FogUniformBlock {}
+impl FogUniformBlock {
+    pub fn new(fog: Option<&HeightFog>) -> Self {
+        match fog {
+            Some(fog) => Self {
+                color: fog.color,
+                density: fog.density.max(0.0),
+                falloff: fog.falloff.max(0.0),
+                base_height: fog.base_height,
+                _padding: [0.0; 2],
+            },
+            None => bytemuck::Zeroable::zeroed(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GrassUniformBlock {
+    pub density: f32,
+    pub min_height: f32,
+    pub max_height: f32,
+    pub color_variation: f32,
+    /// Wind velocity towards the east and north in meters per second.
+    pub wind: [f32; 2],
+    /// Seconds since the terrain was created, wrapped to keep precision.
+    pub time: f32,
+    pub _padding: f32,
+}
+unsafe impl bytemuck::Pod for GrassUniformBlock {}
+unsafe impl bytemuck::Zeroable for GrassUniformBlock {}
+impl GrassUniformBlock {
+    pub fn new(grass: &GrassParameters, time: f32) -> Self {
+        let heading = grass.wind_heading.to_radians();
+        Self {
+            density: grass.density.clamp(0.0, 1.0),
+            min_height: grass.height_range.0.max(0.0),
+            max_height: grass.height_range.1.max(grass.height_range.0).max(0.0),
+            color_variation: grass.color_variation.clamp(0.0, 1.0),
+            wind: [grass.wind_speed * heading.sin(), grass.wind_speed * heading.cos()],
+            time,
+            _padding: 0.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct WeatherUniformBlock {
+    pub wind: [f32; 3],
+    /// Zero if there is no precipitation.
+    pub intensity: f32,
+    pub up: [f32; 3],
+    pub fall_speed: f32,
+    pub east: [f32; 3],
+    pub wetness: f32,
+    pub north: [f32; 3],
+    pub snow_cover: f32,
+    pub camera_delta: [f32; 3],
+    pub time_step: f32,
+    pub kind: u32,
+    pub reset: u32,
+    pub time: f32,
+    pub box_radius: f32,
+    pub box_height: f32,
+    pub ground_resolution: u32,
+    pub _padding: [f32; 2],
+    pub ground: [[f32; 4]; GROUND_SAMPLES * GROUND_SAMPLES / 4],
+}
+unsafe impl bytemuck::Pod for WeatherUniformBlock {}
+unsafe impl bytemuck::Zeroable for WeatherUniformBlock {}
+
 pub(crate) fn texture_from_ktx2_bytes(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
@@ -108,18 +255,56 @@ pub(crate) fn texture_from_ktx2_bytes(
     ))
 }
 
+/// Map all of `buffer` for reading, resolving once its contents are available.
+///
+/// Native backends only make progress on mapping while the device is polled, so this waits for the
+/// GPU there. On the web polling does nothing, and the mapping instead completes in the background
+/// without blocking the browser.
+pub(crate) async fn map_buffer(
+    device: &wgpu::Device,
+    buffer: &wgpu::Buffer,
+) -> Result<(), anyhow::Error> {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    buffer.slice(..).map_async(wgpu::MapMode::Read, move |r| {
+        let _ = tx.send(r);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    Ok(rx.await??)
+}
+
 pub(crate) struct GpuState {
     pub tile_cache: VecMap<Vec<(wgpu::Texture, wgpu::TextureView)>>,
+    /// Uncompressed arrays that generated tiles of compressed layers are written into first.
+    pub material_staging: VecMap<(wgpu::Texture, wgpu::TextureView)>,
+    /// Blocks encoded from `material_staging`, to be copied into the tile cache.
+    pub tile_compression: wgpu::Buffer,
 
     pub mesh_index: wgpu::Buffer,
     pub mesh_storage: VecMap<wgpu::Buffer>,
     pub mesh_indirect: wgpu::Buffer,
+    /// Draws that survived culling, packed at the start of each mesh's range of entries.
+    pub mesh_indirect_compacted: wgpu::Buffer,
+    /// Number of draws in `mesh_indirect_compacted` for each mesh type.
+    pub mesh_draw_count: wgpu::Buffer,
     pub mesh_bounding: wgpu::Buffer,
 
     pub model_storage: wgpu::Buffer,
     pub model_indices: wgpu::Buffer,
+    /// Instanced draws of each tree species, one per tree billboard entry.
+    pub tree_model_indirect: wgpu::Buffer,
 
     pub globals: wgpu::Buffer,
+    pub region: wgpu::Buffer,
+    pub fog: wgpu::Buffer,
+    pub grass: wgpu::Buffer,
+    pub weather: wgpu::Buffer,
+    pub snow_line: wgpu::Buffer,
+    pub splatting: wgpu::Buffer,
+    pub precipitation_particles: wgpu::Buffer,
+    pub deformations: wgpu::Buffer,
+    pub debug_boxes: wgpu::Buffer,
+    pub exposure_state: wgpu::Buffer,
+    pub luminance_histogram: wgpu::Buffer,
     pub generate_uniforms: wgpu::Buffer,
     pub starfield: wgpu::Buffer,
 
@@ -145,12 +330,26 @@ pub(crate) struct GpuState {
     pub topdown_ao: (wgpu::Texture, wgpu::TextureView),
 
     pub shadowmap: (wgpu::Texture, wgpu::TextureView),
+    /// Mip chain of the farthest depth in the previous frame, used for occlusion culling.
+    pub hiz: (wgpu::Texture, wgpu::TextureView),
+
+    pub splat_map: (wgpu::Texture, wgpu::TextureView),
+    pub splat_textures: (wgpu::Texture, wgpu::TextureView),
 
     ground_albedo: (wgpu::Texture, wgpu::TextureView),
     nearest: wgpu::Sampler,
     linear: wgpu::Sampler,
     linear_wrap: wgpu::Sampler,
     shadow_sampler: wgpu::Sampler,
+
+    /// Reusable mappable buffers that uploads are staged through.
+    staging: Mutex<StagingBelt>,
+
+    /// Views, bind groups and pipelines that are reused across frames.
+    pub objects: GpuCache,
+
+    pub resources: ResourceRegistry,
+    _resource_tokens: Vec<ResourceToken>,
 }
 impl GpuState {
     pub(crate) async fn new(
@@ -188,7 +387,7 @@ impl GpuState {
             async { from_ktx2(download(mapfile, "ground_albedo.ktx2").await) },
         )?;
 
-        Ok(GpuState {
+        let mut state = GpuState {
             noise,
             sky,
             cloudcover,
@@ -240,10 +439,65 @@ impl GpuState {
                     view_formats: &[],
                 }),
             ),
+            hiz: with_view(
+                "hiz",
+                device.create_texture(&wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: HIZ_SIZE.0,
+                        height: HIZ_SIZE.1,
+                        depth_or_array_layers: 1,
+                    },
+                    format: wgpu::TextureFormat::R32Float,
+                    mip_level_count: HIZ_MIP_LEVELS,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    usage: wgpu::TextureUsages::STORAGE_BINDING
+                        | wgpu::TextureUsages::TEXTURE_BINDING,
+                    label: Some("texture.hiz"),
+                    view_formats: &[],
+                }),
+            ),
+
+            splat_map: with_view(
+                "splat_map",
+                device.create_texture(&wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: SPLAT_MAP_RESOLUTION,
+                        height: SPLAT_MAP_RESOLUTION,
+                        depth_or_array_layers: 1,
+                    },
+                    format: wgpu::TextureFormat::R8Unorm,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+                    label: Some("texture.splat_map"),
+                    view_formats: &[],
+                }),
+            ),
+            splat_textures: with_view(
+                "splat_textures",
+                device.create_texture(&wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: SPLAT_TEXTURE_RESOLUTION,
+                        height: SPLAT_TEXTURE_RESOLUTION,
+                        depth_or_array_layers: MAX_SPLAT_MATERIALS as u32,
+                    },
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                    mip_level_count: SPLAT_TEXTURE_MIPS,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+                    label: Some("texture.splat_textures"),
+                    view_formats: &[],
+                }),
+            ),
 
             tile_cache: LayerType::iter()
                 .map(|layer| {
                     assert!(layer.min_level() <= layer.max_level());
+                    let resolution = cache.aerial_perspective_textures().texture_resolution(layer);
+                    let compressed_format = layer.compressed_format(device.features());
                     let textures = layer
                         .texture_formats()
                         .iter()
@@ -251,23 +505,20 @@ impl GpuState {
                         .map(|(i, format)| {
                             let texture = device.create_texture(&wgpu::TextureDescriptor {
                                 size: wgpu::Extent3d {
-                                    width: layer.texture_resolution(),
-                                    height: layer.texture_resolution(),
-                                    depth_or_array_layers: (Levels::base_slot(
-                                        layer.max_level() + 1,
-                                    ) - Levels::base_slot(
-                                        layer.min_level(),
-                                    ))
-                                        as u32,
+                                    width: resolution,
+                                    height: resolution,
+                                    depth_or_array_layers: cache.tile_cache_layers(layer),
                                 },
-                                format: format.to_wgpu(device.features()),
+                                format: compressed_format
+                                    .filter(|_| i == 0)
+                                    .unwrap_or_else(|| format.to_wgpu(device.features())),
                                 mip_level_count: 1,
                                 sample_count: 1,
                                 dimension: wgpu::TextureDimension::D2,
                                 usage: wgpu::TextureUsages::COPY_SRC
                                     | wgpu::TextureUsages::COPY_DST
                                     | wgpu::TextureUsages::TEXTURE_BINDING
-                                    | if !format.is_compressed() {
+                                    | if !format.is_compressed() && compressed_format.is_none() {
                                         wgpu::TextureUsages::STORAGE_BINDING
                                     } else {
                                         wgpu::TextureUsages::empty()
@@ -285,6 +536,39 @@ impl GpuState {
                     (layer.index(), textures)
                 })
                 .collect(),
+            material_staging: [LayerType::AlbedoRoughness, LayerType::Normals]
+                .into_iter()
+                .map(|layer| {
+                    let texture = device.create_texture(&wgpu::TextureDescriptor {
+                        size: wgpu::Extent3d {
+                            width: layer.texture_resolution(),
+                            height: layer.texture_resolution(),
+                            depth_or_array_layers: STAGING_LAYERS,
+                        },
+                        format: layer.texture_formats()[0].to_wgpu(device.features()),
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        usage: wgpu::TextureUsages::COPY_SRC
+                            | wgpu::TextureUsages::TEXTURE_BINDING
+                            | wgpu::TextureUsages::STORAGE_BINDING,
+                        label: Some(&format!("texture.tiles.{}.staging", layer.name())),
+                        view_formats: &[],
+                    });
+                    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+                        label: Some(&format!("texture.tiles.{}.staging.view", layer.name())),
+                        ..Default::default()
+                    });
+                    (layer.index(), (texture, view))
+                })
+                .collect(),
+            tile_compression: device.create_buffer(&wgpu::BufferDescriptor {
+                size: tile_compression_buffer_size(LayerType::AlbedoRoughness.texture_resolution())
+                    .max(tile_compression_buffer_size(LayerType::Normals.texture_resolution())),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                label: Some("buffer.tile_compression"),
+                mapped_at_creation: false,
+            }),
             mesh_index: cache.make_gpu_mesh_index(device),
             mesh_storage: cache.make_gpu_mesh_storage(device),
             mesh_indirect: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -298,6 +582,21 @@ impl GpuState {
                     | wgpu::BufferUsages::COPY_DST,
                 label: Some("buffer.mesh_indirect"),
             }),
+            mesh_indirect_compacted: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (std::mem::size_of::<DrawIndexedIndirect>() * cache.total_mesh_entries())
+                    as u64,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+                mapped_at_creation: false,
+                label: Some("buffer.mesh_indirect_compacted"),
+            }),
+            mesh_draw_count: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (std::mem::size_of::<u32>() * MeshType::iter().count()) as u64,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::INDIRECT
+                    | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+                label: Some("buffer.mesh_draw_count"),
+            }),
             mesh_bounding: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 contents: &vec![0; 16 * cache.total_mesh_entries()],
                 usage: wgpu::BufferUsages::STORAGE
@@ -307,6 +606,16 @@ impl GpuState {
             }),
             model_storage,
             model_indices,
+            tree_model_indirect: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                contents: &vec![
+                    0;
+                    std::mem::size_of::<DrawIndexedIndirect>()
+                        * cache.mesh_entries(MeshType::TreeBillboards).len()
+                        * models.species()
+                ],
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+                label: Some("buffer.tree_model_indirect"),
+            }),
             starfield: {
                 let mut stars = vec![0.0f32; 4 * 9096];
                 bytemuck::cast_slice_mut(&mut stars)
@@ -328,8 +637,73 @@ impl GpuState {
                 label: Some("buffer.globals"),
                 mapped_at_creation: false,
             }),
+            region: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("buffer.region"),
+                contents: bytemuck::bytes_of(&RegionUniformBlock::new(cache.region())),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }),
+            fog: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("buffer.fog"),
+                contents: bytemuck::bytes_of(&FogUniformBlock::new(None)),
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+            }),
+            grass: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<GrassUniformBlock>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                label: Some("buffer.grass"),
+                mapped_at_creation: false,
+            }),
+            weather: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<WeatherUniformBlock>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                label: Some("buffer.weather"),
+                mapped_at_creation: false,
+            }),
+            snow_line: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<SnowLineUniformBlock>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                label: Some("buffer.snow_line"),
+                mapped_at_creation: false,
+            }),
+            splatting: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<SplattingUniformBlock>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                label: Some("buffer.splatting"),
+                mapped_at_creation: false,
+            }),
+            precipitation_particles: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (MAX_PARTICLES * PARTICLE_SIZE) as u64,
+                usage: wgpu::BufferUsages::STORAGE,
+                label: Some("buffer.precipitation_particles"),
+                mapped_at_creation: false,
+            }),
+            deformations: device.create_buffer(&wgpu::BufferDescriptor {
+                size: DEFORMATIONS_BUFFER_SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                label: Some("buffer.deformations"),
+                mapped_at_creation: false,
+            }),
+            debug_boxes: device.create_buffer(&wgpu::BufferDescriptor {
+                size: DEBUG_BOXES_BUFFER_SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+                label: Some("buffer.debug_boxes"),
+                mapped_at_creation: false,
+            }),
+            exposure_state: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("buffer.exposure_state"),
+                contents: bytemuck::bytes_of(&ExposureState::default()),
+                usage: wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::STORAGE,
+            }),
+            luminance_histogram: device.create_buffer(&wgpu::BufferDescriptor {
+                size: (HISTOGRAM_BINS * 4) as u64,
+                usage: wgpu::BufferUsages::STORAGE,
+                label: Some("buffer.luminance_histogram"),
+                mapped_at_creation: false,
+            }),
             generate_uniforms: device.create_buffer(&wgpu::BufferDescriptor {
-                size: 256 * 1024,
+                size: GENERATE_UNIFORMS_SIZE as u64,
                 usage: wgpu::BufferUsages::COPY_DST
                     | wgpu::BufferUsages::UNIFORM
                     | wgpu::BufferUsages::STORAGE,
@@ -394,7 +768,194 @@ impl GpuState {
                 compare: Some(wgpu::CompareFunction::GreaterEqual),
                 ..Default::default()
             }),
-        })
+            staging: Mutex::new(StagingBelt::new(STAGING_CHUNK_SIZE)),
+            objects: GpuCache::new(),
+            resources: ResourceRegistry::default(),
+            _resource_tokens: Vec::new(),
+        };
+        state.track_static_resources();
+        Ok(state)
+    }
+
+    /// Register all resources that live for as long as the `GpuState` itself.
+    fn track_static_resources(&mut self) {
+        self.resources.set_limit(ResourceKind::Buffer, "tiles.download", 64);
+        // One bind group is cached for every depth buffer that frames are rendered into, and one
+        // for every level of the pyramid after the first.
+        self.resources.set_limit(ResourceKind::BindGroup, "hiz-depth", 8);
+        self.resources.set_limit(
+            ResourceKind::BindGroup,
+            "hiz-downsample",
+            HIZ_MIP_LEVELS as usize - 1,
+        );
+
+        let mut tokens = Vec::new();
+        for (layer, textures) in &self.tile_cache {
+            for (texture, _) in textures {
+                tokens.push(self.resources.track(
+                    ResourceKind::Texture,
+                    &format!("tiles.{}", LayerType::from_index(layer).name()),
+                    texture_bytes(texture),
+                ));
+            }
+        }
+        for (_, (texture, _)) in &self.material_staging {
+            tokens.push(self.resources.track(
+                ResourceKind::Texture,
+                "tiles.staging",
+                texture_bytes(texture),
+            ));
+        }
+        for (category, texture) in [
+            ("noise", &self.noise.0),
+            ("sky", &self.sky.0),
+            ("cloudcover", &self.cloudcover.0),
+            ("transmittance", &self.transmittance.0),
+            ("inscattering", &self.inscattering.0),
+            ("skyview", &self.skyview.0),
+            ("ground_albedo", &self.ground_albedo.0),
+            ("models", &self.models_albedo.0),
+            ("billboards", &self.billboards_albedo.0),
+            ("billboards", &self.billboards_normals.0),
+            ("billboards", &self.billboards_depth.0),
+            ("billboards", &self.billboards_ao.0),
+            ("billboards", &self.topdown_albedo.0),
+            ("billboards", &self.topdown_normals.0),
+            ("billboards", &self.topdown_depth.0),
+            ("billboards", &self.topdown_ao.0),
+            ("shadowmap", &self.shadowmap.0),
+            ("hiz", &self.hiz.0),
+            ("splatting", &self.splat_map.0),
+            ("splatting", &self.splat_textures.0),
+        ] {
+            tokens.push(self.resources.track(
+                ResourceKind::Texture,
+                category,
+                texture_bytes(texture),
+            ));
+        }
+        for (category, buffer) in self.mesh_storage.values().map(|b| ("mesh_storage", b)).chain([
+            ("mesh_index", &self.mesh_index),
+            ("mesh_indirect", &self.mesh_indirect),
+            ("mesh_indirect", &self.mesh_indirect_compacted),
+            ("mesh_indirect", &self.mesh_draw_count),
+            ("mesh_bounding", &self.mesh_bounding),
+            ("models", &self.model_storage),
+            ("models", &self.model_indices),
+            ("models", &self.tree_model_indirect),
+            ("globals", &self.globals),
+            ("region", &self.region),
+            ("fog", &self.fog),
+            ("weather", &self.weather),
+            ("weather", &self.precipitation_particles),
+            ("grass", &self.grass),
+            ("snow_line", &self.snow_line),
+            ("splatting", &self.splatting),
+            ("deformations", &self.deformations),
+            ("debug_boxes", &self.debug_boxes),
+            ("exposure", &self.exposure_state),
+            ("exposure", &self.luminance_histogram),
+            ("generate_uniforms", &self.generate_uniforms),
+            ("tiles.staging", &self.tile_compression),
+            ("starfield", &self.starfield),
+            ("nodes", &self.nodes),
+            ("nodes", &self.frame_nodes),
+        ]) {
+            tokens.push(self.resources.track(ResourceKind::Buffer, category, buffer.size()));
+        }
+        self._resource_tokens = tokens;
+    }
+
+    /// Write `size` bytes into staging memory with `write`, returning the staging buffer and the
+    /// offset within it that the caller should record copies from. The offset is aligned for
+    /// copies into both buffers and textures.
+    ///
+    /// Command buffers that copy from staging memory must be submitted with [`GpuState::submit`].
+    pub(crate) fn stage(
+        &self,
+        device: &wgpu::Device,
+        size: u64,
+        write: impl FnOnce(&mut [u8]),
+    ) -> (Arc<Tracked<wgpu::Buffer>>, u64) {
+        self.staging.lock().unwrap().stage(device, &self.resources, size, write)
+    }
+
+    /// Record a copy of `data` into `target` at `offset`, to happen when `encoder` executes.
+    pub(crate) fn upload(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::Buffer,
+        offset: u64,
+        data: &[u8],
+    ) {
+        if data.is_empty() {
+            return;
+        }
+        let (buffer, staging_offset) =
+            self.stage(device, data.len() as u64, |bytes| bytes.copy_from_slice(data));
+        encoder.copy_buffer_to_buffer(&buffer, staging_offset, target, offset, data.len() as u64);
+    }
+
+    /// Submit command buffers to the queue, making staging memory used by them available to the
+    /// GPU beforehand and reclaiming it for later uploads once they finish.
+    pub(crate) fn submit<I: IntoIterator<Item = wgpu::CommandBuffer>>(
+        &self,
+        queue: &wgpu::Queue,
+        command_buffers: I,
+    ) {
+        let mut staging = self.staging.lock().unwrap();
+        staging.finish();
+        queue.submit(command_buffers);
+        staging.recall();
+    }
+
+    /// Whether shaders can bind every tile cache texture at once as the `tile_layers` binding
+    /// array, indexed by layer type. Elements are bound as unfilterable, so shaders must load
+    /// texels rather than sampling with a filtering sampler.
+    pub(crate) fn tile_layer_array_supported(device: &wgpu::Device) -> bool {
+        device.features().contains(
+            wgpu::Features::TEXTURE_BINDING_ARRAY
+                | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+        )
+    }
+
+    /// Returns the bind group for the compute shader `shader` along with its pipeline, taking
+    /// both from `self.objects` if they were created before. Bind groups are cached by
+    /// `group_name` and the views in `image_views`, so `buffers` must be the same for every call
+    /// with the same name.
+    pub(crate) fn compute_bind_group_pipeline(
+        &self,
+        device: &wgpu::Device,
+        shader: &rshader::ShaderSet,
+        buffers: HashMap<Cow<str>, (bool, wgpu::BindingResource)>,
+        image_views: HashMap<Cow<str>, &wgpu::TextureView>,
+        group_name: &str,
+    ) -> (Arc<Tracked<wgpu::BindGroup>>, Arc<wgpu::ComputePipeline>) {
+        let mut views: Vec<_> = image_views.iter().collect();
+        views.sort_by(|a, b| a.0.cmp(b.0));
+        let views = views.into_iter().map(|(_, view)| view.global_id()).collect();
+
+        self.objects.bind_group_pipeline(
+            group_name,
+            views,
+            || self.bind_group_for_shader(device, shader, buffers, image_views, group_name),
+            |layout| {
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: [layout][..].into(),
+                        push_constant_ranges: &[],
+                        label: Some(&format!("pipeline.{}.layout", group_name)),
+                    })),
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some(&format!("shader.{}", group_name)),
+                        source: shader.compute(),
+                    }),
+                    entry_point: "main",
+                    label: Some(&format!("pipeline.{}", group_name)),
+                })
+            },
+        )
     }
 
     pub(crate) fn bind_group_for_shader(
@@ -404,15 +965,18 @@ impl GpuState {
         buffers: HashMap<Cow<str>, (bool, wgpu::BindingResource)>,
         image_views: HashMap<Cow<str>, &wgpu::TextureView>,
         group_name: &str,
-    ) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
+    ) -> (Tracked<wgpu::BindGroup>, wgpu::BindGroupLayout) {
         let mut layout_descriptor_entries = shader.layout_descriptor().entries.to_vec();
 
         let mut buffers = buffers;
         let mut image_views = image_views;
+        let tile_layers: Vec<&wgpu::TextureView> =
+            self.tile_cache.values().map(|textures| &textures[0].1).collect();
         //let mut samplers = HashMap::new();
         for (name, layout) in shader.desc_names().iter().zip(layout_descriptor_entries.iter()) {
             let name = &**name.as_ref().unwrap();
             match layout.ty {
+                wgpu::BindingType::Texture { .. } if name == "tile_layers" => {}
                 wgpu::BindingType::StorageTexture { .. } | wgpu::BindingType::Texture { .. } => {
                     if !image_views.contains_key(name) {
                         image_views.insert(
@@ -432,7 +996,14 @@ impl GpuState {
                                 "topdown_albedo" => &self.topdown_albedo.1,
                                 "topdown_normals" => &self.topdown_normals.1,
                                 "shadowmap" => &self.shadowmap.1,
+                                "hiz" => &self.hiz.1,
                                 "ground_albedo" => &self.ground_albedo.1,
+                                "splat_map" => &self.splat_map.1,
+                                "splat_textures" => &self.splat_textures.1,
+                                "albedo_staging" => {
+                                    &self.material_staging[LayerType::AlbedoRoughness].1
+                                }
+                                "normals_staging" => &self.material_staging[LayerType::Normals].1,
                                 _ => match name.rsplit_once(char::is_numeric) {
                                     Some((name, suffix)) => {
                                         &self.tile_cache[LAYERS_BY_NAME[name]]
@@ -449,16 +1020,32 @@ impl GpuState {
                     if !buffers.contains_key(name) {
                         let buffer = match name {
                             "mesh_indirect" => &self.mesh_indirect,
+                            "mesh_indirect_compacted" => &self.mesh_indirect_compacted,
+                            "mesh_draw_count" => &self.mesh_draw_count,
                             "mesh_bounding" => &self.mesh_bounding,
                             "model_storage" => &self.model_storage,
+                            "tree_model_indirect" => &self.tree_model_indirect,
                             "grass_storage" => &self.mesh_storage[MeshType::Grass],
                             "tree_billboards_storage" => {
                                 &self.mesh_storage[MeshType::TreeBillboards]
                             }
+                            "rocks_storage" => &self.mesh_storage[MeshType::Rocks],
                             "globals" => &self.globals,
+                            "region" => &self.region,
+                            "fog" => &self.fog,
+                            "weather" => &self.weather,
+                            "precipitation_particles" => &self.precipitation_particles,
+                            "grass" => &self.grass,
+                            "snow_line" => &self.snow_line,
+                            "splatting" => &self.splatting,
+                            "deformations" => &self.deformations,
+                            "debug_boxes" => &self.debug_boxes,
+                            "exposure_state" => &self.exposure_state,
+                            "luminance_histogram" => &self.luminance_histogram,
                             "frame_nodes" => &self.frame_nodes,
                             "nodes" => &self.nodes,
                             "starfield" => &self.starfield,
+                            "tile_compression" => &self.tile_compression,
                             _ => unreachable!("unrecognized storage buffer: {}", name),
                         };
                         let resource = wgpu::BindingResource::Buffer(wgpu::BufferBinding {
@@ -494,9 +1081,17 @@ impl GpuState {
                     wgpu::BindingType::StorageTexture { .. } => {
                         wgpu::BindingResource::TextureView(&image_views[name])
                     }
+                    wgpu::BindingType::Texture { ref mut sample_type, .. }
+                        if name == "tile_layers" =>
+                    {
+                        assert_eq!(layout.count.map(|c| c.get() as usize), Some(tile_layers.len()));
+                        *sample_type = wgpu::TextureSampleType::Float { filterable: false };
+                        wgpu::BindingResource::TextureViewArray(&tile_layers)
+                    }
                     wgpu::BindingType::Texture { ref mut sample_type, .. } => {
                         match name {
-                            "transmittance" | "inscattering" | "displacements" => {
+                            "transmittance" | "inscattering" | "displacements" | "hiz"
+                            | "hiz_src" | "scene_depth" => {
                                 *sample_type = wgpu::TextureSampleType::Float { filterable: false }
                             }
                             "shadowmap" => {
@@ -525,6 +1120,118 @@ impl GpuState {
             label: Some(&format!("bindgroup.{}", group_name)),
         });
 
-        (bind_group, bind_group_layout)
+        let token = self.resources.track(ResourceKind::BindGroup, group_name, 0);
+        (Tracked::new(bind_group, token), bind_group_layout)
+    }
+}
+
+/// Size of the staging buffers that small uploads share.
+const STAGING_CHUNK_SIZE: u64 = 1 << 20;
+
+struct StagingChunk {
+    buffer: Arc<Tracked<wgpu::Buffer>>,
+    offset: u64,
+}
+
+/// Ring of staging buffers that uploads are written into while mapped, and then copied from on the
+/// GPU. Once the submission that used a chunk completes, the chunk is mapped again and handed back
+/// through `recalled` to be reused, so steady-state uploads don't allocate any new buffers.
+struct StagingBelt {
+    chunk_size: u64,
+    /// Chunks that are mapped and currently being written into.
+    active: Vec<StagingChunk>,
+    /// Chunks that have been unmapped to be used by the next submission.
+    closed: Vec<StagingChunk>,
+    /// Chunks that are mapped and empty.
+    free: Vec<StagingChunk>,
+    recalled_tx: crossbeam::channel::Sender<StagingChunk>,
+    recalled: crossbeam::channel::Receiver<StagingChunk>,
+    total_chunks: usize,
+}
+impl StagingBelt {
+    fn new(chunk_size: u64) -> Self {
+        let (recalled_tx, recalled) = crossbeam::channel::unbounded();
+        Self {
+            chunk_size,
+            active: Vec::new(),
+            closed: Vec::new(),
+            free: Vec::new(),
+            recalled_tx,
+            recalled,
+            total_chunks: 0,
+        }
+    }
+
+    fn stage(
+        &mut self,
+        device: &wgpu::Device,
+        resources: &ResourceRegistry,
+        size: u64,
+        write: impl FnOnce(&mut [u8]),
+    ) -> (Arc<Tracked<wgpu::Buffer>>, u64) {
+        // Copies into textures need offsets that are a multiple of the texel size, and mapped
+        // ranges need sizes that are a multiple of four bytes.
+        let aligned_size = wgpu::util::align_to(size, wgpu::COPY_BUFFER_ALIGNMENT);
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64;
+
+        self.free.extend(self.recalled.try_iter());
+        let index = match self.active.iter().position(|c| {
+            wgpu::util::align_to(c.offset, alignment) + aligned_size <= c.buffer.size()
+        }) {
+            Some(index) => index,
+            None => {
+                let chunk = match self.free.iter().position(|c| c.buffer.size() >= aligned_size) {
+                    Some(index) => self.free.swap_remove(index),
+                    None => {
+                        let size =
+                            wgpu::util::align_to(aligned_size.max(self.chunk_size), alignment);
+                        self.total_chunks += 1;
+                        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                            size,
+                            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+                            label: Some(&format!("buffer.staging{}", self.total_chunks - 1)),
+                            mapped_at_creation: true,
+                        });
+                        let token = resources.track(ResourceKind::Buffer, "staging", size);
+                        StagingChunk { buffer: Arc::new(Tracked::new(buffer, token)), offset: 0 }
+                    }
+                };
+                self.active.push(chunk);
+                self.active.len() - 1
+            }
+        };
+
+        let chunk = &mut self.active[index];
+        let offset = wgpu::util::align_to(chunk.offset, alignment);
+        chunk.offset = offset + aligned_size;
+        write(
+            &mut chunk.buffer.slice(offset..offset + aligned_size).get_mapped_range_mut()
+                [..size as usize],
+        );
+        (Arc::clone(&chunk.buffer), offset)
+    }
+
+    /// Unmap all chunks written since the last call, so that they can be used by the GPU.
+    fn finish(&mut self) {
+        for chunk in self.active.drain(..) {
+            chunk.buffer.unmap();
+            self.closed.push(chunk);
+        }
+    }
+
+    /// Map chunks that were used by the last submission again, returning them to the free list
+    /// once the GPU is done with them.
+    fn recall(&mut self) {
+        for mut chunk in self.closed.drain(..) {
+            chunk.offset = 0;
+            let buffer = Arc::clone(&chunk.buffer);
+            let recalled_tx = self.recalled_tx.clone();
+            buffer.slice(..).map_async(wgpu::MapMode::Write, move |r| {
+                // Chunks that fail to map are dropped, and replaced by new ones as needed.
+                if r.is_ok() {
+                    let _ = recalled_tx.send(chunk);
+                }
+            });
+        }
     }
 }
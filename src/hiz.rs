@@ -0,0 +1,165 @@
+//! Hierarchical depth buffer used to cull meshes hidden behind nearer terrain.
+//!
+//! After the main view is rendered, each texel of the first level of a fixed size pyramid is set
+//! to the farthest depth within its footprint on the depth buffer, and each following level holds
+//! the farthest depth of four texels of the level above. The next frame's culling pass projects
+//! the bounds of every mesh entry with the view that the pyramid was built from, and drops those
+//! that lie behind all the depths they overlap. Since the pyramid lags one frame behind, geometry
+//! coming out from behind an occluder can appear a frame late.
+
+use crate::gpu_state::GpuState;
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+/// Size of the first level of the pyramid, independent of the size of the frame.
+pub(crate) const HIZ_SIZE: (u32, u32) = (1024, 512);
+/// Number of levels of the pyramid, down to a size of 2x1.
+pub(crate) const HIZ_MIP_LEVELS: u32 = 10;
+
+/// Parameters that the culling pass needs to test bounds against the pyramid.
+#[derive(Copy, Clone)]
+pub(crate) struct OcclusionTest {
+    /// View projection matrix of the frame the pyramid was built from.
+    pub view_proj: mint::ColumnMatrix4<f32>,
+    /// Offset from the camera position of that frame to the current one.
+    pub camera_offset: [f32; 3],
+}
+
+pub(crate) struct HiZ {
+    depth_shader: rshader::ShaderSet,
+    downsample_shader: rshader::ShaderSet,
+    /// One view for each level of the pyramid.
+    level_views: Vec<Arc<wgpu::TextureView>>,
+    /// View projection matrix and camera position of the frame the pyramid was built from, or
+    /// `None` if its contents are stale.
+    source: Option<(mint::ColumnMatrix4<f32>, mint::Point3<f64>)>,
+}
+impl HiZ {
+    pub fn new(gpu_state: &GpuState) -> Self {
+        Self {
+            depth_shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "hiz-depth.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            downsample_shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "hiz-downsample.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            level_views: (0..HIZ_MIP_LEVELS)
+                .map(|level| {
+                    gpu_state.objects.texture_view(
+                        &gpu_state.hiz.0,
+                        &wgpu::TextureViewDescriptor {
+                            label: Some(&format!("texture.hiz.level{}.view", level)),
+                            base_mip_level: level,
+                            mip_level_count: NonZeroU32::new(1),
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            source: None,
+        }
+    }
+
+    /// Test that the culling pass should apply for a camera at `camera`, if the pyramid holds the
+    /// depth of a previous frame.
+    pub fn occlusion_test(&self, camera: mint::Point3<f64>) -> Option<OcclusionTest> {
+        self.source.map(|(view_proj, source_camera)| OcclusionTest {
+            view_proj,
+            camera_offset: [
+                (camera.x - source_camera.x) as f32,
+                (camera.y - source_camera.y) as f32,
+                (camera.z - source_camera.z) as f32,
+            ],
+        })
+    }
+
+    /// Mark the pyramid as stale, so that nothing is culled against it until it is built again.
+    pub fn invalidate(&mut self) {
+        self.source = None;
+    }
+
+    /// Build the pyramid from a single sampled depth buffer that the frame was just rendered
+    /// into with `view_proj` from `camera`.
+    pub fn build(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        depth_buffer: &wgpu::TextureView,
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        if self.depth_shader.refresh() {
+            gpu_state.objects.invalidate("hiz-depth");
+        }
+        if self.downsample_shader.refresh() {
+            gpu_state.objects.invalidate("hiz-downsample");
+        }
+
+        // The depth buffer may be a different one every frame, so its bind group is looked up by
+        // the view being bound.
+        let (depth_bind_group, depth_pipeline) = gpu_state.compute_bind_group_pipeline(
+            device,
+            &self.depth_shader,
+            HashMap::new(),
+            hashmap![
+                "scene_depth".into() => depth_buffer,
+                "hiz_dst".into() => &*self.level_views[0],
+            ],
+            "hiz-depth",
+        );
+        let downsample: Vec<_> = (1..HIZ_MIP_LEVELS as usize)
+            .map(|level| {
+                gpu_state.compute_bind_group_pipeline(
+                    device,
+                    &self.downsample_shader,
+                    HashMap::new(),
+                    hashmap![
+                        "hiz_src".into() => &*self.level_views[level - 1],
+                        "hiz_dst".into() => &*self.level_views[level],
+                    ],
+                    "hiz-downsample",
+                )
+            })
+            .collect();
+
+        let mut cpass = encoder
+            .begin_compute_pass(&wgpu::ComputePassDescriptor { label: Some("computepass.hiz") });
+        cpass.set_pipeline(&depth_pipeline);
+        cpass.set_bind_group(0, &depth_bind_group, &[]);
+        cpass.dispatch_workgroups((HIZ_SIZE.0 + 7) / 8, (HIZ_SIZE.1 + 7) / 8, 1);
+
+        for (i, (bind_group, pipeline)) in downsample.iter().enumerate() {
+            let (width, height) = ((HIZ_SIZE.0 >> (i + 1)).max(1), (HIZ_SIZE.1 >> (i + 1)).max(1));
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, bind_group, &[]);
+            cpass.dispatch_workgroups((width + 7) / 8, (height + 7) / 8, 1);
+        }
+        drop(cpass);
+
+        self.source = Some((view_proj, camera));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_halve_exactly() {
+        // Each level is downsampled from exactly 2x2 texels of the one above.
+        for level in 1..HIZ_MIP_LEVELS {
+            assert_eq!(HIZ_SIZE.0 >> level << level, HIZ_SIZE.0);
+            assert_eq!(HIZ_SIZE.1 >> level << level, HIZ_SIZE.1);
+        }
+        assert!(HIZ_SIZE.1 >> (HIZ_MIP_LEVELS - 1) >= 1);
+    }
+}
@@ -3,44 +3,379 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod anchor;
+mod annotation;
 mod astro;
+mod baker;
 mod billboards;
+mod builder;
 mod cache;
+mod camera;
 mod compute_shader;
+mod error;
+mod export;
+mod flat;
+mod gpu_cache;
 mod gpu_state;
+mod hiz;
 mod mapfile;
+mod overhead;
+mod overlay;
+mod postprocess;
+mod procedural;
+mod profiler;
+mod render_hooks;
+mod resources;
+mod session;
 mod speedtree_xml;
 mod stream;
+mod telemetry;
+mod trees;
+mod weather;
 
 use crate::cache::MeshCacheDesc;
 use crate::mapfile::MapFile;
-use anyhow::Error;
+use anchor::Anchors;
+use annotation::Annotations;
 use billboards::Models;
-use cache::layer::{LayerType, MeshType};
-use cache::TileCache;
-use cgmath::{SquareMatrix, Vector3, Zero};
+use cache::{CullView, TileCache, TileCacheOptions, Viewpoint};
+use cgmath::{InnerSpace, SquareMatrix, Vector3, Zero};
 use compute_shader::ComputeShader;
-use gpu_state::{GlobalUniformBlock, GpuState};
+use gpu_state::{map_buffer, FogUniformBlock, GlobalUniformBlock, GpuState, GrassUniformBlock};
+use hiz::HiZ;
+use instant::Instant;
+use overlay::Overlays;
+use postprocess::{PostProcess, TargetConfig};
+use profiler::GpuProfiler;
+use resources::Tracked;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use terra_types::{InfiniteFrustum, VNode};
+use trees::TreeModels;
+use weather::Weather;
+
+pub use anchor::{AnchorId, AnchorTransform};
+pub use annotation::{Annotation, AnnotationId, MAX_ANNOTATION_SIZE};
+pub use astro::julian_day;
+pub use baker::TerrainBaker;
+pub use builder::{TerrainBuilder, MAX_TILE_CACHE_SLOTS};
+pub use cache::deformation::{Deformation, DeformationKind, MAX_DEFORMATIONS};
+pub use cache::erosion::{Erosion, MAX_EROSION_ITERATIONS};
+pub use cache::events::{NodeEvent, NodeEventKind, TileEvent};
+pub use cache::heightfield::{CollisionHeightfield, HeightfieldRegion};
+pub use cache::layer::{LayerMask, LayerType, MeshType};
+pub use cache::navmesh::{NavigationArea, NavigationMesh, MAX_NAVIGATION_MESH_RESOLUTION};
+pub use cache::path::PathSample;
+pub use cache::raycast::{RaycastHit, Viewshed};
+pub use cache::region::{InsetRegion, RegionOfInterest, MAX_REGION_VERTICES};
+pub use cache::snow::SnowLine;
+pub use cache::splatting::{MaterialSplatting, SplatMaterial, MAX_SPLAT_MATERIALS};
+pub use cache::validation::{ValidationIssue, ValidationProblem};
+pub use cache::{
+    AerialPerspectiveQuality, DetailLayer, Foveation, LodTarget, Statistics, TerrainQuality,
+    Viewer, VisibleNode,
+};
+pub use camera::Camera;
+pub use error::Error;
+pub use export::{ExportFormat, ExportLayer, ExportRegion};
+pub use flat::{FlatMap, MAX_FLAT_MAP_RADIUS};
+pub use mapfile::MapFileBuilder;
+pub use overhead::MAX_OVERHEAD_RESOLUTION;
+pub use overlay::{DrapeMode, OverlayId, OverlayOptions, OverlayStyle};
+pub use postprocess::{Exposure, Tonemapper, HDR_FORMAT};
+pub use procedural::ProceduralPlanet;
+pub use profiler::PassTiming;
+pub use render_hooks::{RenderHook, RenderHookPoint, RenderHookTarget};
+pub use resources::{ResourceKind, ResourceUsage};
+pub use session::SessionState;
+pub use telemetry::describe_metrics;
+pub use terra_types::{clip_planes, horizon_distance, Body, BODY};
+pub use weather::{Precipitation, PrecipitationKind, SurfaceConditions};
 
 pub const DEFAULT_TILE_SERVER_URL: &str = "https://terra2.fintelia.io/";
 
+/// Mean radius of the moon in meters.
+const MOON_RADIUS: f64 = 1737400.0;
+
+/// How much effort to spend on rendering lakes, rivers and oceans.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum WaterQuality {
+    /// Water is shaded like any other surface.
+    Off = 0,
+    /// Water reflects the sky, with the strength of the reflection depending on the view angle.
+    Reflections = 1,
+    /// Like `Reflections`, but light is also absorbed along its refracted path through the
+    /// water, so that the bottom shows through in shallow areas near the shore.
+    #[default]
+    Full = 2,
+}
+
+/// Exponential height fog, which pools in valleys and thins out with altitude. This is added on
+/// top of the physically based aerial perspective, which on its own can't produce dense low-lying
+/// fog.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeightFog {
+    /// Extinction coefficient per meter at `base_height`. Values around 1e-4 give light haze,
+    /// while 1e-2 and above give thick fog.
+    pub density: f32,
+    /// How quickly the fog thins out with altitude, per meter. The density halves every
+    /// `ln(2) / falloff` meters above `base_height`.
+    pub falloff: f32,
+    /// Altitude in meters above the ellipsoid at which the fog has its nominal density.
+    pub base_height: f32,
+    /// Linear color of the fog in full sunlight. Fog darkens along with the sun as it sets.
+    pub color: [f32; 3],
+}
+impl Default for HeightFog {
+    fn default() -> Self {
+        Self { density: 1e-3, falloff: 1.0 / 200.0, base_height: 0.0, color: [0.8, 0.85, 0.9] }
+    }
+}
+
+/// Appearance of the grass drawn near the camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GrassParameters {
+    /// Fraction of the maximum number of blades to place, from zero to one. Changing this
+    /// regenerates the grass of every loaded tile.
+    pub density: f32,
+    /// Range of blade heights in meters.
+    pub height_range: (f32, f32),
+    /// How much the color of each blade may differ from the ground beneath it, as a fraction.
+    pub color_variation: f32,
+    /// Speed of the wind bending the grass in meters per second.
+    pub wind_speed: f32,
+    /// Direction the wind blows towards, in degrees clockwise from north.
+    pub wind_heading: f32,
+}
+impl Default for GrassParameters {
+    fn default() -> Self {
+        Self {
+            density: 1.0,
+            height_range: (0.08, 0.12),
+            color_variation: 0.25,
+            wind_speed: 0.0,
+            wind_heading: 0.0,
+        }
+    }
+}
+
+/// Alternate ways of coloring the terrain, to help diagnose problems with level of detail
+/// selection and the tile generators.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    /// Color by quadtree level, darkened where vertices morph toward their parent's grid.
+    Levels = 1,
+    /// Shading normals, mapped from [-1, 1] to [0, 1] per component.
+    Normals = 2,
+    /// Unlit albedo.
+    Albedo = 3,
+    /// Elevation as a color ramp, with contour lines every 100 meters.
+    Heights = 4,
+    /// Tree cover density, in grey where the layer isn't available.
+    TreeCover = 5,
+}
+
+/// Depth buffer convention used by the projection matrices passed to `update` and `render`.
+///
+/// Depths are always in the range zero to one, as wgpu expects.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DepthConfig {
+    /// Whether the near plane is at a depth of one and the far plane at zero. This gives much
+    /// better precision with floating point depth buffers, so it is the default.
+    pub reverse_z: bool,
+    /// Distance to the near plane, in meters.
+    pub near: f32,
+    /// Distance to the far plane in meters, or `None` for an infinite far plane.
+    pub far: Option<f32>,
+}
+impl Default for DepthConfig {
+    fn default() -> Self {
+        Self { reverse_z: true, near: 0.1, far: None }
+    }
+}
+impl DepthConfig {
+    /// Right handed perspective projection following this convention, with a vertical field of
+    /// view of `fovy` radians.
+    pub fn projection_matrix(&self, fovy: f32, aspect: f32) -> mint::ColumnMatrix4<f32> {
+        let f = 1.0 / (fovy * 0.5).tan();
+        let (a, b) = match (self.reverse_z, self.far) {
+            (true, Some(far)) => {
+                (self.near / (far - self.near), far * self.near / (far - self.near))
+            }
+            (true, None) => (0.0, self.near),
+            (false, Some(far)) => (far / (self.near - far), far * self.near / (self.near - far)),
+            (false, None) => (-1.0, -self.near),
+        };
+
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        cgmath::Matrix4::new(
+            f/aspect,  0.0,  0.0,   0.0,
+            0.0,       f,    0.0,   0.0,
+            0.0,       0.0,  a,    -1.0,
+            0.0,       0.0,  b,     0.0,
+        ).into()
+    }
+}
+
+/// Format of the depth buffers passed to `render`, and of the one that `render_to_texture`
+/// allocates. Depths follow the `DepthConfig` that the projection matrices were built with.
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Parameters for `Terrain::render_to_texture`.
+#[derive(Copy, Clone, Debug)]
+pub struct RenderParams {
+    /// Width and height of the target texture in pixels.
+    pub size: (u32, u32),
+    /// Format of the target texture.
+    pub format: wgpu::TextureFormat,
+    /// View projection matrix relative to the camera position passed to `Terrain::update`, like
+    /// the one passed to `Terrain::render`.
+    pub view_proj: mint::ColumnMatrix4<f32>,
+}
+
+/// Depth buffer owned by Terra for use by `Terrain::render_to_texture`.
+struct OffscreenDepth {
+    size: (u32, u32),
+    sample_count: u32,
+    view: wgpu::TextureView,
+    texture: Tracked<wgpu::Texture>,
+}
+impl OffscreenDepth {
+    fn new(
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        size: (u32, u32),
+        sample_count: u32,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d { width: size.0, height: size.1, depth_or_array_layers: 1 },
+            format: DEPTH_FORMAT,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+            label: Some("texture.offscreen_depth"),
+            view_formats: &[],
+        });
+        let token = gpu_state.resources.track(
+            ResourceKind::Texture,
+            "offscreen_depth",
+            resources::texture_bytes(&texture) * sample_count as u64,
+        );
+        Self {
+            size,
+            sample_count,
+            view: texture.create_view(&Default::default()),
+            texture: Tracked::new(texture, token),
+        }
+    }
+}
+
+/// View that the most recent frame was rendered with, needed to turn its depths back into
+/// positions.
+#[derive(Copy, Clone, Debug)]
+struct FrameView {
+    view_proj: mint::ColumnMatrix4<f32>,
+    camera: mint::Point3<f64>,
+    size: (u32, u32),
+}
+impl FrameView {
+    /// Position of the surface at the center of `pixel`, given the depth stored there.
+    fn unproject(&self, pixel: (u32, u32), depth: f32) -> mint::Point3<f64> {
+        let ndc = cgmath::Vector4::new(
+            (pixel.0 as f64 + 0.5) / self.size.0 as f64 * 2.0 - 1.0,
+            1.0 - (pixel.1 as f64 + 0.5) / self.size.1 as f64 * 2.0,
+            depth as f64,
+            1.0,
+        );
+        let view_proj: cgmath::Matrix4<f32> = self.view_proj.into();
+        let p = view_proj.cast::<f64>().unwrap().invert().unwrap() * ndc;
+        mint::Point3 {
+            x: self.camera.x + p.x / p.w,
+            y: self.camera.y + p.y / p.w,
+            z: self.camera.z + p.z / p.w,
+        }
+    }
+}
+
+/// Handle to a view returned by `Terrain::add_view`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ViewId(u64);
+
+/// Additional view rendered with `Terrain::render_view`, which needs its own HDR target since
+/// its surface may differ in size from the main one.
+struct View {
+    id: ViewId,
+    postprocess: PostProcess,
+    /// Viewer that the view was last rendered from, whose surroundings are loaded by `update`.
+    viewer: Option<Viewer>,
+    /// Index of the viewer among the viewpoints passed to the tile cache by the last update.
+    viewpoint: Option<usize>,
+}
+
 pub struct Terrain {
     sky_shader: rshader::ShaderSet,
-    sky_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    sky_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
     stars_shader: rshader::ShaderSet,
-    stars_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    stars_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
     gpu_state: GpuState,
-    _mapfile: Arc<MapFile>,
+    mapfile: Arc<MapFile>,
     cache: TileCache,
     generate_skyview: ComputeShader<()>,
+    postprocess: PostProcess,
+    profiler: GpuProfiler,
+    offscreen_depth: Option<OffscreenDepth>,
+    /// View that the most recent call to `render` or `render_to_texture` used. Views created with
+    /// `add_view` don't replace it, since `read_depth` only applies to the main depth buffer.
+    last_frame: Option<FrameView>,
     view_proj: mint::ColumnMatrix4<f32>,
     shadow_view_proj: mint::ColumnMatrix4<f32>,
     camera: mint::Point3<f64>,
+    /// Julian day passed to the last call to `update`.
+    julian_day: f64,
     sun_direction: Vector3<f32>,
+    /// Sun direction set by `set_sun_direction`, used instead of the astronomical one.
+    sun_direction_override: Option<Vector3<f32>>,
+    /// Position of the center of the moon in ECEF coordinates.
+    moon_position: Vector3<f64>,
     sidereal_time: f32,
+    wireframe: bool,
+    debug_view: Option<DebugView>,
+    /// Attachments passed to `render`.
+    target_config: TargetConfig,
+    water_quality: WaterQuality,
+    light_shafts: f32,
+    height_fog: Option<HeightFog>,
+    grass: GrassParameters,
+    /// Time that grass animations are measured from.
+    grass_epoch: Instant,
+    weather: Weather,
+    overlays: Overlays,
+    annotations: Annotations,
+    anchors: Anchors,
+    tree_models: TreeModels,
+    hiz: HiZ,
+    occlusion_culling: bool,
+    screen_space_reflections: bool,
+    /// View projection matrix and camera position that culling was frozen at, if any.
+    frozen_culling: Option<(mint::ColumnMatrix4<f32>, mint::Point3<f64>)>,
+    lod_target: LodTarget,
+    /// Preset applied with `set_quality`, if any.
+    quality: Option<TerrainQuality>,
+    /// Whether the atmosphere is simulated. See `TerrainBuilder::atmosphere`.
+    atmosphere: bool,
+    additional_viewers: Vec<Viewer>,
+    views: Vec<View>,
+    next_view_id: u64,
+    /// Whether dynamic layers still have to be regenerated for the current frame. They only
+    /// depend on the camera and sun passed to `update`, so every view of a frame shares them.
+    dynamic_layers_pending: bool,
+    resource_report_interval: Option<Duration>,
+    last_resource_report: Instant,
     _models: Models,
 }
 impl Terrain {
@@ -50,140 +385,300 @@ impl Terrain {
         queue: &wgpu::Queue,
         server: String,
     ) -> Result<Self, Error> {
-        let mapfile = Arc::new(MapFile::new(server).await?);
+        Self::with_mounts(device, queue, vec![server]).await
+    }
+
+    /// Create a new Terrain object which streams tiles from several servers.
+    ///
+    /// The first server must provide complete coverage of the planet along with all assets.
+    /// Subsequent servers may only cover some regions, and take precedence over earlier ones
+    /// for any tiles or assets they provide.
+    pub async fn with_mounts(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        servers: Vec<String>,
+    ) -> Result<Self, Error> {
+        let mut servers = servers.into_iter();
+        let mut builder = MapFileBuilder::new(servers.next().ok_or_else(|| {
+            Error::InvalidArgument(anyhow::format_err!("No tile servers provided"))
+        })?);
+        for server in servers {
+            builder = builder.mount(server);
+        }
+        Self::with_map_file(device, queue, builder).await
+    }
+
+    /// Create a new Terrain object using the tile sources and local cache settings from
+    /// `builder`. Use `TerrainBuilder` for more options.
+    pub async fn with_map_file(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        builder: MapFileBuilder,
+    ) -> Result<Self, Error> {
+        TerrainBuilder::new(builder).build(device, queue).await
+    }
+
+    async fn from_builder(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        builder: TerrainBuilder,
+    ) -> Result<Self, Error> {
+        let TerrainBuilder {
+            map_file: builder,
+            quality,
+            disabled_layers,
+            mut detail_limits,
+            aerial_perspective_quality,
+            region,
+            insets,
+            flat_map,
+            procedural_planet,
+            atmosphere,
+            tile_cache_slots,
+            sample_count,
+        } = builder;
+        detail_limits.layer_max_levels.extend(disabled_layers.into_iter().map(|l| (l, 0)));
+        let aerial_perspective_quality = aerial_perspective_quality
+            .or(quality.map(|q| q.aerial_perspective_quality()))
+            .unwrap_or_default();
+        let region = region.map(cache::region::Region::new).transpose()?;
+        let insets = insets.into_iter().map(cache::region::Inset::new).collect::<Result<_, _>>()?;
+
+        let mapfile =
+            Arc::new(builder.build().await.map_err(|e| Error::categorize(e, Error::MapFile))?);
 
         let mesh_layers = MeshType::iter()
-            .map(|ty| match ty {
-                MeshType::Terrain => MeshCacheDesc {
-                    ty,
-                    max_bytes_per_node: 0,
-                    entries_per_node: 4,
-                    min_level: 0,
-                    max_level: VNode::LEVEL_CELL_5MM,
-                    index_buffer: {
-                        let mut data = Vec::new();
-                        let resolution = 64;
-                        let half_resolution = resolution / 2;
-                        let width = resolution + 1;
-                        for k in 0..2 {
-                            for h in 0..2 {
-                                for y in 0..half_resolution {
-                                    for x in 0..half_resolution {
-                                        for offset in [0, 1, width, 1, width + 1, width].iter() {
-                                            data.push(
-                                                offset
-                                                    + ((h * half_resolution + x)
-                                                        + (k * half_resolution + y) * width),
-                                            );
+            .map(|ty| {
+                Ok(match ty {
+                    MeshType::Terrain => MeshCacheDesc {
+                        ty,
+                        max_bytes_per_node: 0,
+                        entries_per_node: 4,
+                        min_level: 0,
+                        max_level: VNode::LEVEL_CELL_5MM,
+                        index_buffer: {
+                            let mut data = Vec::new();
+                            let resolution = 64;
+                            let half_resolution = resolution / 2;
+                            let width = resolution + 1;
+                            for k in 0..2 {
+                                for h in 0..2 {
+                                    for y in 0..half_resolution {
+                                        for x in 0..half_resolution {
+                                            for offset in [0, 1, width, 1, width + 1, width].iter()
+                                            {
+                                                data.push(
+                                                    offset
+                                                        + ((h * half_resolution + x)
+                                                            + (k * half_resolution + y) * width),
+                                                );
+                                            }
                                         }
                                     }
                                 }
                             }
-                        }
-                        data
-                    },
-                    render_overlapping_levels: false,
-                    cull_mode: Some(wgpu::Face::Front),
-                    render: rshader::ShaderSet::simple(
-                        rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
-                        rshader::shader_source!(
-                            "shaders",
-                            "terrain.frag",
-                            "declarations.glsl",
-                            "pbr.glsl"
-                        ),
-                    )
-                    .unwrap(),
-                    render_shadow: None, /*Some(
-                                             rshader::ShaderSet::simple(
-                                                 rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
-                                                 rshader::shader_source!("shaders", "shadowpass.frag"),
-                                             )
-                                             .unwrap(),
-                                         )*/
-                },
-                MeshType::Grass => MeshCacheDesc {
-                    ty,
-                    max_bytes_per_node: 128 * 128 * 64,
-                    entries_per_node: 16,
-                    min_level: VNode::LEVEL_SIDE_19M,
-                    max_level: VNode::LEVEL_SIDE_5M,
-                    cull_mode: None,
-                    render_overlapping_levels: true,
-                    index_buffer: (0..32 * 32)
-                        .flat_map(|i| {
-                            IntoIterator::into_iter([
-                                0u32, 1, 2, 3, 2, 1, 2, 3, 4, 5, 4, 3, 4, 5, 6,
-                            ])
-                            .map(move |j| j + i * 7)
-                        })
-                        .collect::<Vec<u32>>(),
-                    render: rshader::ShaderSet::simple(
-                        rshader::shader_source!("shaders", "grass.vert", "declarations.glsl"),
-                        rshader::shader_source!(
-                            "shaders",
-                            "grass.frag",
-                            "declarations.glsl",
-                            "pbr.glsl"
-                        ),
-                    )
-                    .unwrap(),
-                    render_shadow: None,
-                },
-                MeshType::TreeBillboards => MeshCacheDesc {
-                    ty,
-                    max_bytes_per_node: 128 * 128 * 64,
-                    entries_per_node: 16,
-                    min_level: VNode::LEVEL_SIDE_1KM,
-                    max_level: VNode::LEVEL_SIDE_1KM,
-                    cull_mode: None,
-                    render_overlapping_levels: true,
-                    index_buffer: (0..32 * 32)
-                        .flat_map(|i| {
-                            IntoIterator::into_iter([0u32, 1, 2, 3, 2, 1]).map(move |j| j + i * 4)
-                        })
-                        .collect::<Vec<u32>>(),
-                    render: rshader::ShaderSet::simple(
-                        rshader::shader_source!(
-                            "shaders",
-                            "tree-billboards.vert",
-                            "declarations.glsl"
+                            data
+                        },
+                        render_overlapping_levels: false,
+                        cull_mode: Some(wgpu::Face::Front),
+                        render: rshader::ShaderSet::simple(
+                            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+                            rshader::shader_source!(
+                                "shaders",
+                                "terrain.frag",
+                                "declarations.glsl",
+                                "pbr.glsl",
+                                "fog.glsl"
+                            ),
+                        )
+                        .map_err(Error::Shader)?,
+                        render_shadow: None, /*Some(
+                                                 rshader::ShaderSet::simple(
+                                                     rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+                                                     rshader::shader_source!("shaders", "shadowpass.frag"),
+                                                 )
+                                                 .unwrap(),
+                                             )*/
+                        render_wireframe: Some(
+                            rshader::ShaderSet::simple(
+                                rshader::shader_source!(
+                                    "shaders",
+                                    "terrain.vert",
+                                    "declarations.glsl"
+                                ),
+                                rshader::shader_source!(
+                                    "shaders",
+                                    "wireframe.frag",
+                                    "declarations.glsl"
+                                ),
+                            )
+                            .map_err(Error::Shader)?,
                         ),
-                        rshader::shader_source!(
-                            "shaders",
-                            "tree-billboards.frag",
-                            "declarations.glsl",
-                            "pbr.glsl"
+                        render_debug: Some(
+                            rshader::ShaderSet::simple(
+                                rshader::shader_source!(
+                                    "shaders",
+                                    "terrain.vert",
+                                    "declarations.glsl"
+                                ),
+                                rshader::shader_source!(
+                                    "shaders",
+                                    "terrain.frag",
+                                    "declarations.glsl",
+                                    "pbr.glsl",
+                                    "fog.glsl";
+                                    "DEBUG_VIEW" = "1"
+                                ),
+                            )
+                            .map_err(Error::Shader)?,
                         ),
-                    )
-                    .unwrap(),
-                    render_shadow: None, /*Some(
-                                             rshader::ShaderSet::simple(
-                                                 rshader::shader_source!(
-                                                     "shaders",
-                                                     "tree-billboards.vert",
-                                                     "declarations.glsl";
-                                                     "SHADOWPASS" = "1"
-                                                 ),
-                                                 rshader::shader_source!(
-                                                     "shaders",
-                                                     "tree-billboards.frag",
-                                                     "declarations.glsl",
-                                                     "pbr.glsl";
-                                                     "SHADOWPASS" = "1"
-                                                 ),
-                                             )
-                                             .unwrap(),
-                                         )*/
-                },
+                    },
+                    MeshType::Grass => MeshCacheDesc {
+                        ty,
+                        max_bytes_per_node: 128 * 128 * 64,
+                        entries_per_node: 16,
+                        min_level: VNode::LEVEL_SIDE_19M,
+                        max_level: VNode::LEVEL_SIDE_5M,
+                        cull_mode: None,
+                        render_overlapping_levels: true,
+                        index_buffer: (0..32 * 32)
+                            .flat_map(|i| {
+                                IntoIterator::into_iter([
+                                    0u32, 1, 2, 3, 2, 1, 2, 3, 4, 5, 4, 3, 4, 5, 6,
+                                ])
+                                .map(move |j| j + i * 7)
+                            })
+                            .collect::<Vec<u32>>(),
+                        render: rshader::ShaderSet::simple(
+                            rshader::shader_source!("shaders", "grass.vert", "declarations.glsl"),
+                            rshader::shader_source!(
+                                "shaders",
+                                "grass.frag",
+                                "declarations.glsl",
+                                "pbr.glsl",
+                                "fog.glsl"
+                            ),
+                        )
+                        .map_err(Error::Shader)?,
+                        render_shadow: None,
+                        render_wireframe: None,
+                        render_debug: None,
+                    },
+                    MeshType::TreeBillboards => MeshCacheDesc {
+                        ty,
+                        max_bytes_per_node: 128 * 128 * 64,
+                        entries_per_node: 16,
+                        min_level: VNode::LEVEL_SIDE_1KM,
+                        max_level: VNode::LEVEL_SIDE_1KM,
+                        cull_mode: None,
+                        render_overlapping_levels: true,
+                        index_buffer: (0..32 * 32)
+                            .flat_map(|i| {
+                                IntoIterator::into_iter([0u32, 1, 2, 3, 2, 1])
+                                    .map(move |j| j + i * 4)
+                            })
+                            .collect::<Vec<u32>>(),
+                        render: rshader::ShaderSet::simple(
+                            rshader::shader_source!(
+                                "shaders",
+                                "tree-billboards.vert",
+                                "declarations.glsl"
+                            ),
+                            rshader::shader_source!(
+                                "shaders",
+                                "tree-billboards.frag",
+                                "declarations.glsl",
+                                "pbr.glsl",
+                                "fog.glsl"
+                            ),
+                        )
+                        .map_err(Error::Shader)?,
+                        render_shadow: None, /*Some(
+                                                 rshader::ShaderSet::simple(
+                                                     rshader::shader_source!(
+                                                         "shaders",
+                                                         "tree-billboards.vert",
+                                                         "declarations.glsl";
+                                                         "SHADOWPASS" = "1"
+                                                     ),
+                                                     rshader::shader_source!(
+                                                         "shaders",
+                                                         "tree-billboards.frag",
+                                                         "declarations.glsl",
+                                                         "pbr.glsl",
+                                                         "fog.glsl";
+                                                         "SHADOWPASS" = "1"
+                                                     ),
+                                                 )
+                                                 .unwrap(),
+                                             )*/
+                        render_wireframe: None,
+                        render_debug: None,
+                    },
+                    MeshType::Rocks => MeshCacheDesc {
+                        ty,
+                        max_bytes_per_node: 128 * 128 * 64,
+                        entries_per_node: 16,
+                        min_level: VNode::LEVEL_SIDE_152M,
+                        max_level: VNode::LEVEL_SIDE_152M,
+                        cull_mode: None,
+                        render_overlapping_levels: true,
+                        index_buffer: (0..32 * 32)
+                            .flat_map(|i| {
+                                IntoIterator::into_iter([
+                                    4u32, 0, 2, 4, 2, 1, 4, 1, 3, 4, 3, 0, 5, 2, 0, 5, 1, 2, 5, 3,
+                                    1, 5, 0, 3,
+                                ])
+                                .map(move |j| j + i * 6)
+                            })
+                            .collect::<Vec<u32>>(),
+                        render: rshader::ShaderSet::simple(
+                            rshader::shader_source!(
+                                "shaders",
+                                "rocks.vert",
+                                "declarations.glsl",
+                                "hash.glsl"
+                            ),
+                            rshader::shader_source!(
+                                "shaders",
+                                "rocks.frag",
+                                "declarations.glsl",
+                                "pbr.glsl",
+                                "fog.glsl"
+                            ),
+                        )
+                        .map_err(Error::Shader)?,
+                        render_shadow: None,
+                        render_wireframe: None,
+                        render_debug: None,
+                    },
+                })
             })
-            .collect();
+            .collect::<Result<_, Error>>()?;
 
-        let models = Models::new(&mapfile).await?;
-        let cache = TileCache::new(device, Arc::clone(&mapfile), mesh_layers);
-        let gpu_state = GpuState::new(device, queue, &mapfile, &cache, &models).await?;
+        let models =
+            Models::new(&mapfile).await.map_err(|e| Error::categorize(e, Error::MapFile))?;
+        let cache = TileCache::new(
+            device,
+            Arc::clone(&mapfile),
+            mesh_layers,
+            TileCacheOptions {
+                detail_limits,
+                aerial_perspective_quality,
+                region,
+                insets,
+                flat_map,
+                procedural_planet,
+            },
+        );
+        let gpu_state = GpuState::new(device, queue, &mapfile, &cache, &models)
+            .await
+            .map_err(|e| Error::categorize(e, Error::Gpu))?;
 
         models.render_billboards(device, queue, &gpu_state);
+        let tree_models = TreeModels::new(device, queue, &gpu_state, &cache, &models)
+            .map_err(|e| Error::categorize(e, Error::Gpu))?;
+        let hiz = HiZ::new(&gpu_state);
 
         let sky_shader = rshader::ShaderSet::simple(
             rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
@@ -191,12 +686,11 @@ impl Terrain {
                 "shaders",
                 "sky.frag",
                 "declarations.glsl",
-                "pbr.glsl",
                 "atmosphere.glsl",
-                "hash.glsl"
+                "fog.glsl"
             ),
         )
-        .unwrap();
+        .map_err(Error::Shader)?;
 
         let stars_shader = rshader::ShaderSet::simple(
             rshader::shader_source!("shaders", "stars.vert", "declarations.glsl"),
@@ -208,7 +702,7 @@ impl Terrain {
                 "atmosphere.glsl"
             ),
         )
-        .unwrap();
+        .map_err(Error::Shader)?;
 
         let generate_skyview = ComputeShader::new(
             rshader::shader_source!(
@@ -220,22 +714,59 @@ impl Terrain {
             "gen-skyview".to_string(),
         );
 
-        Ok(Self {
+        let mut terrain = Self {
             sky_shader,
             sky_bindgroup_pipeline: None,
             stars_shader,
             stars_bindgroup_pipeline: None,
             gpu_state,
-            _mapfile: mapfile,
+            mapfile,
             cache,
             generate_skyview,
+            postprocess: PostProcess::new(),
+            profiler: GpuProfiler::new(device, queue),
+            offscreen_depth: None,
+            last_frame: None,
             view_proj: cgmath::Matrix4::zero().into(),
             shadow_view_proj: cgmath::Matrix4::zero().into(),
             camera: mint::Point3::from_slice(&[0.0, 0.0, 0.0]),
+            julian_day: 2451545.0,
             sun_direction: cgmath::Vector3::new(0.4, 0.7, 0.2),
+            sun_direction_override: None,
+            moon_position: astro::moon_position(2451545.0),
             sidereal_time: 0.0,
+            wireframe: false,
+            debug_view: None,
+            target_config: TargetConfig::default(),
+            water_quality: WaterQuality::default(),
+            light_shafts: 0.0,
+            height_fog: None,
+            grass: GrassParameters::default(),
+            grass_epoch: Instant::now(),
+            weather: Weather::new(),
+            overlays: Overlays::new(),
+            annotations: Annotations::new(),
+            anchors: Anchors::new(),
+            tree_models,
+            hiz,
+            occlusion_culling: false,
+            screen_space_reflections: false,
+            frozen_culling: None,
+            dynamic_layers_pending: false,
+            resource_report_interval: None,
+            last_resource_report: Instant::now(),
+            lod_target: LodTarget::default(),
+            quality: None,
+            atmosphere,
+            additional_viewers: Vec::new(),
+            views: Vec::new(),
+            next_view_id: 0,
             _models: models,
-        })
+        };
+        terrain.cache.set_slot_budget(tile_cache_slots);
+        terrain.set_quality(quality);
+        terrain.set_target_config(TargetConfig { sample_count, ..terrain.target_config });
+        Ok(terrain)
     }
 
     /// Continuously polls until file streaming has completed for tiles in the vicinity of
@@ -244,6 +775,9 @@ impl Terrain {
     /// Terra cannot render any terrain until all root tiles have been downloaded and streamed to
     /// the GPU. This function returns whether those tiles have been streamed, and also initiates
     /// streaming of more detailed tiles for the indicated camera position.
+    ///
+    /// On the web this only reports progress once rather than waiting, and should be called again
+    /// on later frames until it reports completion.
     pub fn poll_loading_status<F: FnMut(f32)>(
         &mut self,
         device: &wgpu::Device,
@@ -251,14 +785,18 @@ impl Terrain {
         camera: mint::Point3<f64>,
         progress_callback: F,
     ) {
-        self.cache.wait_for_uploads(device, queue, &self.gpu_state, camera, progress_callback)
+        let viewpoints = self.viewpoints(self.view_proj, camera);
+        self.cache.wait_for_uploads(device, queue, &self.gpu_state, &viewpoints, progress_callback)
     }
 
     /// Update the terrain.
     ///
+    /// `julian_day` sets the time used to position the sun, moon and stars, and can be computed
+    /// from a UTC time with `julian_day`. See also `set_sun_direction`.
+    ///
     /// This function will block if the root tiles haven't been downloaded/loaded from disk. If
     /// you want to avoid this, call `poll_loading_status` first to see whether this function will
-    /// block.
+    /// block. On the web it never blocks, and instead returns early until the root tiles arrive.
     pub fn update(
         &mut self,
         device: &wgpu::Device,
@@ -267,12 +805,41 @@ impl Terrain {
         camera: mint::Point3<f64>,
         julian_day: f64,
     ) {
+        let _span = tracing::debug_span!("update").entered();
+        // Everything rendered since the previous update belongs to the previous frame, however
+        // many views it had.
+        self.gpu_state.objects.end_frame();
+        self.dynamic_layers_pending = true;
         self.view_proj = view_proj;
-        let shadow_view = cgmath::Matrix4::look_to_rh(
-            cgmath::Point3::new(0., 0., 0.),
-            cgmath::Vector3::new(-0.4, -0.7, -0.2),
-            cgmath::Vector3::unit_z(),
+        self.julian_day = julian_day;
+        self.sun_direction = self
+            .sun_direction_override
+            .unwrap_or_else(|| astro::sun_direction(julian_day).cast().unwrap());
+        self.moon_position = astro::moon_position(julian_day);
+        self.sidereal_time = astro::mn_sidr(julian_day) as f32;
+        queue.write_buffer(
+            &self.gpu_state.fog,
+            0,
+            bytemuck::bytes_of(&FogUniformBlock::new(self.height_fog.as_ref())),
         );
+        queue.write_buffer(
+            &self.gpu_state.grass,
+            0,
+            bytemuck::bytes_of(&GrassUniformBlock::new(
+                &self.grass,
+                self.grass_epoch.elapsed().as_secs_f32() % 3600.0,
+            )),
+        );
+
+        // The shadow map looks along the sunlight, so any up vector that isn't parallel to it will
+        // do.
+        let up = if self.sun_direction.z.abs() < 0.9 {
+            cgmath::Vector3::unit_z()
+        } else {
+            cgmath::Vector3::unit_x()
+        };
+        let shadow_view =
+            cgmath::Matrix4::look_to_rh(cgmath::Point3::new(0., 0., 0.), -self.sun_direction, up);
         let shadow_proj = cgmath::Matrix4::new(
             1.0 / 8192.0,
             0.0,
@@ -294,6 +861,27 @@ impl Terrain {
         self.shadow_view_proj = (shadow_proj * shadow_view).into();
         self.camera = camera;
 
+        let viewpoints = self.viewpoints(view_proj, camera);
+        self.cache.set_shadow_priority_cutoff(self.lod_target.shadow_priority_cutoff());
+        self.cache.set_probe_priority_cutoff(self.lod_target.probe_priority_cutoff());
+
+        if let Some(interval) = self.resource_report_interval {
+            if self.last_resource_report.elapsed() >= interval {
+                self.last_resource_report = Instant::now();
+                for usage in self.resource_usage() {
+                    log::info!(
+                        "{:?} {}: {} live ({} peak, {} created), {} KiB",
+                        usage.kind,
+                        usage.category,
+                        usage.live,
+                        usage.peak,
+                        usage.created,
+                        usage.bytes / 1024
+                    );
+                }
+            }
+        }
+
         if self._models.refresh() {
             self._models.render_billboards(device, queue, &self.gpu_state);
         }
@@ -334,7 +922,7 @@ impl Terrain {
                         }),
                         entry_point: "main",
                         targets: &[Some(wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                            format: HDR_FORMAT,
                             blend: Some(wgpu::BlendState {
                                 color: wgpu::BlendComponent::REPLACE,
                                 alpha: wgpu::BlendComponent::REPLACE,
@@ -345,12 +933,12 @@ impl Terrain {
                     primitive: Default::default(),
                     depth_stencil: Some(wgpu::DepthStencilState {
                         format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
+                        depth_compare: self.target_config.depth_compare(true),
                         depth_write_enabled: false,
                         bias: Default::default(),
                         stencil: Default::default(),
                     }),
-                    multisample: Default::default(),
+                    multisample: self.target_config.multisample(),
                     multiview: None,
                     label: Some("pipeline.sky"),
                 }),
@@ -393,60 +981,79 @@ impl Terrain {
                         }),
                         entry_point: "main",
                         targets: &[Some(wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                            format: HDR_FORMAT,
                             blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                            write_mask: wgpu::ColorWrites::ALL,
+                            // Leave alpha alone so it still marks where the sky is visible.
+                            write_mask: wgpu::ColorWrites::COLOR,
                         })],
                     }),
                     primitive: Default::default(),
                     depth_stencil: Some(wgpu::DepthStencilState {
                         format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
+                        depth_compare: self.target_config.depth_compare(true),
                         depth_write_enabled: false,
                         bias: Default::default(),
                         stencil: Default::default(),
                     }),
-                    multisample: Default::default(),
+                    multisample: self.target_config.multisample(),
                     multiview: None,
                     label: Some("pipeline.stars"),
                 }),
             ));
         }
 
-        self.cache.update(device, queue, &self.gpu_state, camera);
+        self.profiler.begin_frame(device);
+        self.cache.update(device, queue, &self.gpu_state, &viewpoints, &mut self.profiler);
 
-        // Block until root tiles have been downloaded and streamed to the GPU.
+        // Block until root tiles have been downloaded and streamed to the GPU. Browsers only make
+        // progress on downloads once control returns to them, so there the update is skipped.
         while !VNode::roots().iter().copied().all(|root| {
             self.cache.contains_layers(
                 root,
                 LayerType::BaseHeightmaps.bit_mask() | LayerType::BaseAlbedo.bit_mask(),
             )
         }) {
+            if cfg!(target_arch = "wasm32") {
+                return;
+            }
             std::thread::sleep(std::time::Duration::from_millis(10));
-            self.cache.update(device, queue, &self.gpu_state, camera);
+            self.cache.update(device, queue, &self.gpu_state, &viewpoints, &mut self.profiler);
         }
+        self.anchors.update(&self.cache);
 
         self.generate_skyview.refresh(device, &self.gpu_state);
         self.cache.update_meshes(device, &self.gpu_state);
+        self.tree_models.update(device, &self.gpu_state, self.target_config);
+        self.weather.update(
+            device,
+            queue,
+            &self.gpu_state,
+            &self.cache,
+            Vector3::new(camera.x, camera.y, camera.z),
+            self.target_config,
+        );
+        self.overlays.update(
+            device,
+            queue,
+            &self.gpu_state,
+            &self.cache,
+            Vector3::new(camera.x, camera.y, camera.z),
+            self.target_config,
+        );
+    }
 
-        let sidereal_time = astro::mn_sidr(julian_day);
-        self.sun_direction = {
-            let n = julian_day - 2451545.0;
-            let l: f64 = (280.460 + 0.9856474 * n).to_radians();
-            let g: f64 = (357.528 + 0.9856003 * n).to_radians();
-            let oblq_eclip = (23.439 - 0.0000004 * n).to_radians();
-            let lambda = l + 1.915 * f64::sin(g) + 0.02 * f64::sin(2.0 * g);
-            let declination = astro::dec_frm_ecl(lambda, 0.0, oblq_eclip);
-            let ascension = astro::asc_frm_ecl(lambda, 0.0, oblq_eclip);
-            cgmath::Vector3::new(
-                f64::cos(declination) * f64::cos(ascension - sidereal_time),
-                f64::cos(declination) * f64::sin(ascension - sidereal_time),
-                f64::sin(declination),
-            )
-            .cast()
-            .unwrap()
-        };
-        self.sidereal_time = sidereal_time as f32;
+    /// Like `update`, but with the camera given as a double precision view matrix in ECEF
+    /// coordinates. The camera position and the relative view projection are derived from it,
+    /// so there is no need to subtract the camera position from the view matrix beforehand. Pass
+    /// `camera.relative_view_proj()` to `render`.
+    pub fn update_camera(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &Camera,
+        julian_day: f64,
+    ) {
+        self.update(device, queue, camera.relative_view_proj(), camera.position(), julian_day);
     }
 
     pub fn render_shadows(&self, device: &wgpu::Device, queue: &wgpu::Queue) {
@@ -473,10 +1080,17 @@ impl Terrain {
                 camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
                 screen_width: 2048.0,
                 sun_direction: self.sun_direction.into(),
+                moon_direction: self.moon_direction().into(),
+                moon_angular_radius: self.moon_angular_radius(),
+                near_depth: self.target_config.near_depth(),
+                far_depth: self.target_config.far_depth(),
+                debug_view: self.debug_view.map_or(0, |v| v as u32),
+                atmosphere: self.atmosphere as u32,
                 screen_height: 2048.0,
                 sidereal_time: self.sidereal_time,
                 exposure: 1.0,
-                _padding: [0.0; 2],
+                simplified_shading: 0,
+                water_quality: self.water_quality as u32,
             }),
         );
 
@@ -485,7 +1099,7 @@ impl Terrain {
         });
 
         {
-            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state);
+            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state, CullView::Shadows, None);
 
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[],
@@ -502,24 +1116,246 @@ impl Terrain {
             self.cache.render_mesh_shadows(device, &mut rpass, &self.gpu_state);
         }
 
-        queue.submit(Some(encoder.finish()));
+        self.gpu_state.submit(queue, Some(encoder.finish()));
     }
 
     /// Render the terrain.
     ///
+    /// The scene is rendered into an internal HDR target and then tonemapped into `color_buffer`,
+    /// which must use `Bgra8UnormSrgb`. See `set_exposure` and `set_tonemapper`. The depth buffer
+    /// must use `DEPTH_FORMAT` with the sample count passed to `set_sample_count`, and keeps the
+    /// depth of the terrain after rendering (see `read_depth`). When MSAA is enabled, the HDR
+    /// target is resolved before tonemapping, so `color_buffer` is always single sampled.
+    ///
     /// Terrain::update must be called first.
     pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        render_view_proj: mint::ColumnMatrix4<f32>,
+    ) {
+        self.render_frame(
+            device,
+            queue,
+            color_buffer,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            depth_buffer,
+            frame_size,
+            render_view_proj,
+            true,
+            None,
+        );
+    }
+
+    /// Render the terrain like `render`, calling `hooks` at each `RenderHookPoint` so that the
+    /// application can record its own passes into the frame. Hooks run in the order of
+    /// `RenderHookPoint::ALL`, and see the depth of everything Terra has drawn before them.
+    ///
+    /// Terrain::update must be called first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_with_hooks(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        render_view_proj: mint::ColumnMatrix4<f32>,
+        hooks: &mut RenderHook,
+    ) {
+        self.render_frame(
+            device,
+            queue,
+            color_buffer,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            depth_buffer,
+            frame_size,
+            render_view_proj,
+            true,
+            Some(hooks),
+        );
+    }
+
+    /// Render the terrain into an arbitrary texture, without needing a window or swapchain.
+    ///
+    /// This works like `render`, except that Terra allocates the depth buffer itself and the
+    /// target may use any color format that can be rendered to. `target` must be a single sampled
+    /// 2D view with the size given in `params`, so a single face or layer of a cubemap or texture
+    /// array can be rendered by creating a view of just that layer. Since tonemapped colors are
+    /// written without any encoding, the format should normally be an sRGB one like
+    /// `Rgba8UnormSrgb` unless the output is meant to be displayed as linear values.
+    ///
+    /// Terrain::update must be called first.
+    pub fn render_to_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target: &wgpu::TextureView,
+        params: &RenderParams,
+    ) {
+        let sample_count = self.target_config.sample_count;
+        let depth = match self.offscreen_depth.take() {
+            Some(depth) if depth.size == params.size && depth.sample_count == sample_count => depth,
+            _ => OffscreenDepth::new(device, &self.gpu_state, params.size, sample_count),
+        };
+        self.render_frame(
+            device,
+            queue,
+            target,
+            params.format,
+            &depth.view,
+            params.size,
+            params.view_proj,
+            true,
+            None,
+        );
+        self.offscreen_depth = Some(depth);
+    }
+
+    /// Read back the frame most recently drawn by `render` or `render_to_texture`, as it appears
+    /// in the color buffer. Colors are returned sRGB encoded with the channels in RGBA order
+    /// regardless of the format of the color buffer, which must have 8 bits per channel.
+    ///
+    /// The frame is tonemapped again from Terra's HDR target, so the color buffer itself doesn't
+    /// need to support being copied from. Completes once the GPU has finished rendering.
+    pub async fn capture_frame(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<image::RgbaImage, Error> {
+        self.postprocess.capture(device, queue).await.map_err(|e| Error::categorize(e, Error::Gpu))
+    }
+
+    /// Depth buffer that `render_to_texture` most recently drew into, in `DEPTH_FORMAT`. It can
+    /// be bound as a texture or copied from, for instance to composite other geometry against the
+    /// terrain. `None` if `render_to_texture` hasn't been called yet.
+    pub fn offscreen_depth(&self) -> Option<&wgpu::Texture> {
+        self.offscreen_depth.as_ref().map(|depth| &*depth.texture)
+    }
+
+    /// Read back the depth at `pixel` from the depth buffer of the most recent frame of the main
+    /// view, and convert it to the position of the surface seen there. Returns `None` if nothing
+    /// was drawn at that pixel. Frames drawn with `render_view` aren't considered.
+    ///
+    /// `depth_buffer` must be the texture that frame was rendered with: either the one passed to
+    /// `render`, which then needs `COPY_SRC` usage, or the one returned by `offscreen_depth`. It
+    /// can't be multisampled. Completes once the GPU has finished rendering.
+    pub async fn read_depth(
         &self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        depth_buffer: &wgpu::Texture,
+        pixel: (u32, u32),
+    ) -> Result<Option<mint::Point3<f64>>, Error> {
+        let frame = match self.last_frame {
+            Some(frame) => frame,
+            None => {
+                return Err(Error::InvalidArgument(anyhow::format_err!(
+                    "No frame has been rendered yet"
+                )))
+            }
+        };
+        if depth_buffer.format() != DEPTH_FORMAT {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Can't read depth from {:?} textures",
+                depth_buffer.format()
+            )));
+        }
+        if depth_buffer.sample_count() > 1 {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Can't read depth from multisampled textures"
+            )));
+        }
+        if pixel.0 >= frame.size.0 || pixel.1 >= frame.size.1 {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Pixel {:?} is outside of the frame",
+                pixel
+            )));
+        }
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: 4,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: Some("buffer.read_depth"),
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.read_depth"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: depth_buffer,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: pixel.0, y: pixel.1, z: 0 },
+                aspect: wgpu::TextureAspect::DepthOnly,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+        );
+        self.gpu_state.submit(queue, Some(encoder.finish()));
+        map_buffer(device, &buffer).await.map_err(|e| Error::categorize(e, Error::Gpu))?;
+        let depth = bytemuck::cast_slice::<u8, f32>(&buffer.slice(..).get_mapped_range())[0];
+        buffer.unmap();
+
+        if depth == self.target_config.far_depth() {
+            return Ok(None);
+        }
+        Ok(Some(frame.unproject(pixel, depth)))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render_frame(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
         color_buffer: &wgpu::TextureView,
+        color_format: wgpu::TextureFormat,
         depth_buffer: &wgpu::TextureView,
         frame_size: (u32, u32),
         render_view_proj: mint::ColumnMatrix4<f32>,
+        main_view: bool,
+        hooks: Option<&mut RenderHook>,
     ) {
-        let relative_frustum = InfiniteFrustum::from_matrix(
-            cgmath::Matrix4::<f32>::from(self.view_proj).cast().unwrap(),
+        let _span = tracing::debug_span!("render").entered();
+        metrics::increment_counter!(telemetry::FRAMES_RENDERED);
+        if main_view {
+            self.last_frame = Some(FrameView {
+                view_proj: render_view_proj,
+                camera: self.camera,
+                size: frame_size,
+            });
+        }
+
+        let (sun_position, fade) = self.sun_screen_position(render_view_proj);
+        self.postprocess.set_light_shafts(sun_position, self.light_shafts * fade);
+        self.postprocess.prepare(
+            device,
+            queue,
+            &self.gpu_state,
+            frame_size,
+            self.target_config.sample_count,
+            color_format,
         );
+
+        if main_view {
+            self.cache.select_viewpoint(queue, &self.gpu_state, Some(0));
+        }
+        let relative_frustum = match main_view {
+            true => self.culling_frustum(),
+            false => InfiniteFrustum::from_matrix(
+                cgmath::Matrix4::<f32>::from(render_view_proj).cast().unwrap(),
+            ),
+        };
         queue.write_buffer(
             &self.gpu_state.globals,
             0,
@@ -537,10 +1373,18 @@ impl Terrain {
                 camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
                 screen_width: frame_size.0 as f32,
                 sun_direction: self.sun_direction.into(),
+                moon_direction: self.moon_direction().into(),
+                moon_angular_radius: self.moon_angular_radius(),
+                near_depth: self.target_config.near_depth(),
+                far_depth: self.target_config.far_depth(),
+                debug_view: self.debug_view.map_or(0, |v| v as u32),
+                atmosphere: self.atmosphere as u32,
                 screen_height: frame_size.1 as f32,
                 sidereal_time: self.sidereal_time,
-                exposure: 1.0 / (f32::powf(2.0, 17.0) * 1.2),
-                _padding: [0.0; 2],
+                // Overwritten with the current exposure by `PostProcess::apply_exposure`.
+                exposure: 0.0,
+                simplified_shading: 0,
+                water_quality: self.water_quality as u32,
             }),
         );
 
@@ -549,57 +1393,1255 @@ impl Terrain {
         });
 
         {
-            self.cache.run_dynamic_generators(queue, &mut encoder, &self.gpu_state);
-            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state);
+            self.postprocess.apply_exposure(&mut encoder, &self.gpu_state);
+            // The generators read the globals written above, so they run with the first view
+            // rendered each frame rather than during `update`.
+            if std::mem::take(&mut self.dynamic_layers_pending) {
+                self.profiler.begin_scope(&mut encoder, "dynamic_generators");
+                self.cache.run_dynamic_generators(device, &mut encoder, &self.gpu_state);
+                self.profiler.end_scope(&mut encoder);
+            }
+
+            self.profiler.begin_scope(&mut encoder, "cull_meshes");
+            let occlusion = match self.frozen_culling {
+                None if self.occlusion_culling && main_view => self.hiz.occlusion_test(self.camera),
+                _ => None,
+            };
+            self.cache.cull_meshes(
+                device,
+                &mut encoder,
+                &self.gpu_state,
+                CullView::Main,
+                occlusion,
+            );
+            self.tree_models.prepare(device, &mut encoder, &self.gpu_state);
+            self.profiler.end_scope(&mut encoder);
 
+            self.profiler.begin_scope(&mut encoder, "skyview");
             self.generate_skyview.run(device, &mut encoder, &self.gpu_state, (16, 16, 1), &());
+            self.profiler.end_scope(&mut encoder);
 
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: color_buffer,
-                    resolve_target: None,
-                    ops: wgpu::Operations::default(),
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: depth_buffer,
-                    depth_ops: Some(wgpu::Operations::default()),
-                    stencil_ops: None,
-                }),
-                label: Some("renderpass"),
-            });
-            self.cache.render_meshes(device, &mut rpass, &self.gpu_state);
+            self.weather.simulate(device, &mut encoder, &self.gpu_state);
 
-            rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..3, 0..1);
+            self.profiler.begin_scope(&mut encoder, "render");
+            match hooks {
+                None => {
+                    let mut rpass = self.begin_main_pass(&mut encoder, depth_buffer, true, true);
+                    self.render_opaque(device, &mut rpass);
+                    self.render_sky(&mut rpass);
+                    self.weather.render(&mut rpass);
+                }
+                Some(hooks) => {
+                    // Each group of draws gets its own pass so that the hooks can record theirs
+                    // in between. The first pass only clears the attachments and the last only
+                    // resolves them.
+                    let target = RenderHookTarget {
+                        color: self.postprocess.render_view(),
+                        depth: depth_buffer,
+                        sample_count: self.target_config.sample_count,
+                        depth_compare: self.target_config.depth_compare(false),
+                        view_proj: render_view_proj,
+                    };
+                    drop(self.begin_main_pass(&mut encoder, depth_buffer, true, false));
+                    for point in RenderHookPoint::ALL {
+                        hooks(point, &mut encoder, &target);
+                        let last = point == RenderHookPoint::AfterTransparent;
+                        let mut rpass =
+                            self.begin_main_pass(&mut encoder, depth_buffer, false, last);
+                        match point {
+                            RenderHookPoint::BeforeOpaque => self.render_opaque(device, &mut rpass),
+                            RenderHookPoint::AfterOpaque => self.render_sky(&mut rpass),
+                            RenderHookPoint::AfterSky => self.weather.render(&mut rpass),
+                            RenderHookPoint::AfterTransparent => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        self.profiler.end_scope(&mut encoder);
 
-            rpass.set_pipeline(&self.stars_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.stars_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..9096 * 6, 0..1);
+        // Other views don't use the depth pyramid, and mustn't replace the one from the main view.
+        if main_view && self.occlusion_culling && self.target_config.sample_count == 1 {
+            self.profiler.begin_scope(&mut encoder, "hiz");
+            self.hiz.build(
+                device,
+                &mut encoder,
+                &self.gpu_state,
+                depth_buffer,
+                render_view_proj,
+                self.camera,
+            );
+            self.profiler.end_scope(&mut encoder);
+        } else if main_view {
+            self.hiz.invalidate();
         }
 
-        queue.submit(Some(encoder.finish()));
+        let reflections_depth = (self.screen_space_reflections
+            && self.water_quality >= WaterQuality::Reflections
+            && self.target_config.sample_count == 1)
+            .then_some(depth_buffer);
+
+        self.profiler.begin_scope(&mut encoder, "postprocess");
+        self.postprocess.run(
+            device,
+            &mut encoder,
+            &self.gpu_state,
+            color_buffer,
+            reflections_depth,
+        );
+        self.profiler.end_scope(&mut encoder);
+
+        self.annotations.render(
+            device,
+            queue,
+            &mut encoder,
+            &self.gpu_state,
+            &self.cache,
+            Vector3::new(self.camera.x, self.camera.y, self.camera.z),
+            render_view_proj.into(),
+            frame_size,
+            color_buffer,
+            color_format,
+        );
+
+        self.profiler.resolve(&mut encoder);
+        self.gpu_state.submit(queue, Some(encoder.finish()));
+        self.profiler.map_results();
     }
 
-    pub fn get_height(&self, latitude: f64, longitude: f64) -> f32 {
-        for level in (0..=VNode::LEVEL_CELL_1M).rev() {
-            if let Some(height) = self.cache.get_height(latitude, longitude, level) {
-                return height;
+    /// Begin a pass drawing into the HDR target and `depth_buffer`, clearing both if `clear` is
+    /// set. `last` must be set for the final pass of the frame.
+    fn begin_main_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth_buffer: &'a wgpu::TextureView,
+        clear: bool,
+        last: bool,
+    ) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[Some(self.postprocess.color_attachment(clear, last))],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_buffer,
+                depth_ops: Some(wgpu::Operations {
+                    load: if clear {
+                        wgpu::LoadOp::Clear(self.target_config.far_depth())
+                    } else {
+                        wgpu::LoadOp::Load
+                    },
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+            label: Some("renderpass"),
+        })
+    }
+
+    /// Draw the terrain, vegetation and overlays.
+    fn render_opaque<'a>(&'a self, device: &wgpu::Device, rpass: &mut wgpu::RenderPass<'a>) {
+        match self.debug_view {
+            Some(_) => self.cache.render_debug_meshes(device, rpass, &self.gpu_state),
+            None => {
+                self.cache.render_meshes(device, rpass, &self.gpu_state);
+                self.tree_models.render(device, rpass, &self.gpu_state);
             }
         }
-        0.0
+        if self.wireframe {
+            self.cache.render_mesh_wireframes(device, rpass, &self.gpu_state);
+        }
+        self.cache.render_bounds_overlay(rpass);
+        self.overlays.render(rpass);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn check_send() {
-        struct Helper<T>(T);
-        trait AssertImpl {
-            fn assert() {}
+    fn render_sky<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
+        rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+        rpass.draw(0..3, 0..1);
+
+        rpass.set_pipeline(&self.stars_bindgroup_pipeline.as_ref().unwrap().1);
+        rpass.set_bind_group(0, &self.stars_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+        rpass.draw(0..9096 * 6, 0..1);
+    }
+
+    /// Frustum used to cull meshes for the main view, relative to the current camera.
+    fn culling_frustum(&self) -> InfiniteFrustum {
+        let (view_proj, camera) = self.frozen_culling.unwrap_or((self.view_proj, self.camera));
+        let mut frustum =
+            InfiniteFrustum::from_matrix(cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap());
+
+        // Shift the planes from being relative to the frozen camera to the current one.
+        let offset = cgmath::Point3::from(self.camera) - cgmath::Point3::from(camera);
+        for plane in &mut frustum.planes {
+            plane.w += plane.truncate().dot(offset);
+        }
+        frustum
+    }
+
+    /// Render the terrain into a reflection probe, such as one face of a cubemap.
+    ///
+    /// This is a cheap rendering path that shares the tile cache with the main view: only coarse
+    /// terrain is drawn (see `LodTarget::probe_max_pixel_error`), vegetation and stars are
+    /// skipped, and shading is simplified. Like `render`, `view_proj` must be relative to the
+    /// camera position passed to `update`. No tonemapping is applied, so the color buffer must
+    /// use `Rgba16Float` and receives linear radiance scaled by the current exposure. The depth
+    /// buffer must use `Depth32Float`. Both buffers must have the sample count passed to
+    /// `set_sample_count`, and are cleared before rendering.
+    ///
+    /// Terrain::update must be called first.
+    pub fn render_probe(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+    ) {
+        let relative_frustum =
+            InfiniteFrustum::from_matrix(cgmath::Matrix4::<f32>::from(view_proj).cast().unwrap());
+        queue.write_buffer(
+            &self.gpu_state.globals,
+            0,
+            bytemuck::bytes_of(&GlobalUniformBlock {
+                view_proj,
+                view_proj_inverse: cgmath::Matrix4::from(view_proj).invert().unwrap().into(),
+                shadow_view_proj: self.shadow_view_proj,
+                frustum_planes: [
+                    relative_frustum.planes[0].cast().unwrap().into(),
+                    relative_frustum.planes[1].cast().unwrap().into(),
+                    relative_frustum.planes[2].cast().unwrap().into(),
+                    relative_frustum.planes[3].cast().unwrap().into(),
+                    relative_frustum.planes[4].cast().unwrap().into(),
+                ],
+                camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
+                screen_width: frame_size.0 as f32,
+                sun_direction: self.sun_direction.into(),
+                moon_direction: self.moon_direction().into(),
+                moon_angular_radius: self.moon_angular_radius(),
+                near_depth: self.target_config.near_depth(),
+                far_depth: self.target_config.far_depth(),
+                debug_view: self.debug_view.map_or(0, |v| v as u32),
+                atmosphere: self.atmosphere as u32,
+                screen_height: frame_size.1 as f32,
+                sidereal_time: self.sidereal_time,
+                // Overwritten with the current exposure by `PostProcess::apply_exposure`.
+                exposure: 0.0,
+                simplified_shading: 1,
+                water_quality: self.water_quality.min(WaterQuality::Reflections) as u32,
+            }),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.render_probe"),
+        });
+
+        {
+            self.postprocess.apply_exposure(&mut encoder, &self.gpu_state);
+            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state, CullView::Probe, None);
+
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: color_buffer,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(self.target_config.far_depth()),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+                label: Some("renderpass.probe"),
+            });
+            self.cache.render_probe_meshes(device, &mut rpass, &self.gpu_state);
+
+            // The sky reuses the sky view generated by the last call to `render`.
+            rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
+            rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+
+        self.gpu_state.submit(queue, Some(encoder.finish()));
+    }
+
+    /// Stop requesting new tiles from the tile servers and local cache, such as to free up
+    /// bandwidth during a cutscene. Tiles already requested still arrive, and tiles that are
+    /// streamed in can still be generated, but more detailed ones won't load as the camera moves.
+    ///
+    /// The root tiles are requested regardless, since nothing can be rendered without them, and
+    /// `poll_loading_status` streams everything it waits for.
+    pub fn pause_streaming(&mut self) {
+        self.cache.set_streaming_paused(true);
+    }
+
+    /// Undo `pause_streaming`. Missing tiles are requested on the next call to `update`.
+    pub fn resume_streaming(&mut self) {
+        self.cache.set_streaming_paused(false);
+    }
+
+    /// Returns whether streaming is currently paused.
+    pub fn is_streaming_paused(&self) -> bool {
+        self.cache.streaming_paused()
+    }
+
+    /// Cancel any downloads in progress and wait for the background streaming thread to exit,
+    /// then drop the terrain. Dropping a `Terrain` without calling this also stops the thread,
+    /// but doesn't wait for it, so it may still be running while the application exits.
+    pub async fn shutdown(mut self) {
+        self.cache.shutdown_streaming().await;
+    }
+
+    /// Freeze or unfreeze level of detail selection.
+    ///
+    /// While frozen, the set of nodes selected for rendering stays fixed no matter where the
+    /// camera passed to `update` moves. This makes it possible to fly a detached debug camera
+    /// around and inspect the culling and LOD decisions made for the original viewpoint.
+    pub fn set_lod_frozen(&mut self, frozen: bool) {
+        self.cache.set_lod_frozen(frozen);
+    }
+
+    /// Returns whether level of detail selection is currently frozen.
+    pub fn is_lod_frozen(&self) -> bool {
+        self.cache.lod_frozen()
+    }
+
+    /// While level of detail selection is frozen, recompute it a single time using the camera
+    /// position passed to the next call to `update`.
+    pub fn step_lod(&mut self) {
+        self.cache.step_lod();
+    }
+
+    /// Freeze or unfreeze the frustum used to cull terrain for the main view.
+    ///
+    /// While frozen, meshes are culled against the view from the last call to `update` before
+    /// freezing, while rendering continues to use the matrix passed to `render`. Combined with
+    /// `set_lod_frozen` this allows flying around to inspect which nodes were kept or dropped.
+    pub fn set_culling_frozen(&mut self, frozen: bool) {
+        if frozen != self.frozen_culling.is_some() {
+            self.frozen_culling = frozen.then(|| (self.view_proj, self.camera));
+        }
+    }
+
+    /// Cull meshes in the main view that were hidden behind nearer geometry in the previous frame.
+    ///
+    /// Visibility is tested against a depth pyramid built from the previous frame, so geometry
+    /// that comes into view from behind a ridge may appear a frame late. The depth buffer passed
+    /// to `render` must be created with `wgpu::TextureUsages::TEXTURE_BINDING`, which the one
+    /// allocated by `render_to_texture` always is. Occlusion culling is skipped while MSAA is
+    /// enabled or the culling frustum is frozen. Disabled by default.
+    pub fn set_occlusion_culling(&mut self, enabled: bool) {
+        self.occlusion_culling = enabled;
+        if !enabled {
+            self.hiz.invalidate();
+        }
+    }
+
+    /// Returns whether the culling frustum is currently frozen.
+    pub fn is_culling_frozen(&self) -> bool {
+        self.frozen_culling.is_some()
+    }
+
+    /// Set the quality target used to decide which tiles to load and render.
+    ///
+    /// The viewport height should match the height of the color buffer passed to `render`.
+    pub fn set_lod_target(&mut self, target: LodTarget) {
+        self.lod_target = target;
+    }
+
+    /// Apply a quality preset, which limits the tile cache sizes and the deepest level loaded,
+    /// turns grass and trees on or off, and sets the shadow level of detail and the update rate of
+    /// the aerial perspective. `None` goes back to the settings from the `TerrainBuilder`.
+    ///
+    /// Presets never load more detail than the `TerrainBuilder` allows. The shadow level of detail
+    /// is stored in the `LodTarget`, where it is left as is when going back to `None`, and a later
+    /// call to `set_lod_target` overrides it. Takes effect on the next call to `update`.
+    pub fn set_quality(&mut self, quality: Option<TerrainQuality>) {
+        if quality == self.quality {
+            return;
+        }
+        self.quality = quality;
+        self.cache.set_quality(quality);
+        if let Some(quality) = quality {
+            self.lod_target.shadow_max_pixel_error = quality.shadow_max_pixel_error();
+        }
+    }
+
+    /// Returns the preset applied with `set_quality`, if any.
+    pub fn quality(&self) -> Option<TerrainQuality> {
+        self.quality
+    }
+
+    /// Capture the view, time of day and quality settings, along with the nodes resident in the
+    /// tile cache, so that a later session can pick up where this one left off.
+    pub fn session_state(&self) -> SessionState {
+        SessionState {
+            camera: self.camera.into(),
+            view_proj: self.view_proj.into(),
+            julian_day: self.julian_day,
+            sun_direction: self.sun_direction_override.map(Into::into),
+            quality: self.quality,
+            water_quality: self.water_quality,
+            resident_nodes: self.cache.resident_nodes(),
+        }
+    }
+
+    /// Restore the settings from a `SessionState`, then stream and generate the nodes that were
+    /// resident so that the first frames don't start from an empty cache. Blocks until they have
+    /// all loaded, calling `progress_callback` with the percentage done. On the web, where
+    /// blocking isn't possible, the nodes are instead loaded as usual once `update` is called.
+    ///
+    /// Returns the camera position, view projection matrix and Julian day to pass to the next
+    /// call to `update`.
+    pub fn restore_session<F: FnMut(f32)>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        state: &SessionState,
+        mut progress_callback: F,
+    ) -> (mint::Point3<f64>, mint::ColumnMatrix4<f32>, f64) {
+        self.set_quality(state.quality);
+        self.set_water_quality(state.water_quality);
+        self.set_sun_direction(state.sun_direction.map(Into::into));
+        self.camera = state.camera.into();
+        self.view_proj = state.view_proj.into();
+        self.julian_day = state.julian_day;
+
+        if !cfg!(target_arch = "wasm32") && !state.resident_nodes.is_empty() {
+            loop {
+                self.profiler.begin_frame(device);
+                let loaded = self.cache.update_for_nodes(
+                    device,
+                    queue,
+                    &self.gpu_state,
+                    &state.resident_nodes,
+                    &mut self.profiler,
+                );
+                self.gpu_state.objects.end_frame();
+                progress_callback(loaded as f32 * 100.0 / state.resident_nodes.len() as f32);
+                if loaded == state.resident_nodes.len() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        (self.camera, self.view_proj, self.julian_day)
+    }
+
+    /// Load terrain around additional cameras besides the one passed to `update`.
+    ///
+    /// Tiles are prioritized by whichever camera needs them most, so split-screen views or remote
+    /// players share a single tile cache. Takes effect on the next call to `update`.
+    pub fn set_additional_viewers(&mut self, viewers: Vec<Viewer>) {
+        self.additional_viewers = viewers;
+    }
+
+    /// Create a view for rendering into another surface from its own camera, such as an overhead
+    /// view shown beside the main one. Views share the tile cache with the main view, but each
+    /// has its own HDR target so that surfaces of different sizes can be rendered every frame.
+    /// See `render_view`.
+    pub fn add_view(&mut self) -> ViewId {
+        let id = ViewId(self.next_view_id);
+        self.next_view_id += 1;
+        let mut postprocess = PostProcess::new();
+        postprocess.set_tonemapper(self.postprocess.tonemapper());
+        postprocess.set_exposure(self.postprocess.exposure());
+        self.views.push(View { id, postprocess, viewer: None, viewpoint: None });
+        id
+    }
+
+    /// Remove a view created with `add_view`, freeing its HDR target. Returns whether the view
+    /// existed.
+    pub fn remove_view(&mut self, id: ViewId) -> bool {
+        let len = self.views.len();
+        self.views.retain(|view| view.id != id);
+        self.views.len() != len
+    }
+
+    /// Render the terrain as seen by `viewer` into the surface of a view created with
+    /// `add_view`. `color_buffer` may use any format that can be rendered to, given by
+    /// `color_format`, such as the preferred format of the view's surface. Otherwise the buffers
+    /// have the same requirements as for `render`.
+    ///
+    /// Unlike for `render`, `viewer.view_proj` is relative to `viewer.position` rather than to
+    /// the camera passed to `update`. Later calls to `update` load terrain around the viewer as if
+    /// it had been passed to `set_additional_viewers`, and the nodes selected for the viewer are
+    /// drawn from then on. The tile cache is shared by all views, the sky and atmosphere are
+    /// computed for the camera passed to `update`, and occlusion culling only applies to the main
+    /// view. Exposure adapts to all views together.
+    ///
+    /// Terrain::update must be called first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_view(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        id: ViewId,
+        color_buffer: &wgpu::TextureView,
+        color_format: wgpu::TextureFormat,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        viewer: Viewer,
+    ) -> Result<(), Error> {
+        let index = match self.views.iter().position(|view| view.id == id) {
+            Some(index) => index,
+            None => {
+                return Err(Error::InvalidArgument(anyhow::format_err!("Unknown view {:?}", id)))
+            }
+        };
+        self.views[index].viewer = Some(viewer);
+
+        // Node positions are relative to the camera passed to `update`, so the view projection
+        // has to be as well.
+        let offset = cgmath::Point3::from(self.camera) - cgmath::Point3::from(viewer.position);
+        let render_view_proj = cgmath::Matrix4::from(viewer.view_proj)
+            * cgmath::Matrix4::from_translation(offset.cast::<f32>().unwrap());
+
+        // Draw the nodes selected for the viewer, or for any viewpoint if it hasn't been part of
+        // an update yet.
+        self.cache.select_viewpoint(queue, &self.gpu_state, self.views[index].viewpoint);
+
+        // Render through the view's HDR target in place of the main one.
+        std::mem::swap(&mut self.postprocess, &mut self.views[index].postprocess);
+        self.render_frame(
+            device,
+            queue,
+            color_buffer,
+            color_format,
+            depth_buffer,
+            frame_size,
+            render_view_proj.into(),
+            false,
+            None,
+        );
+        std::mem::swap(&mut self.postprocess, &mut self.views[index].postprocess);
+        Ok(())
+    }
+
+    /// Replace the inset regions configured with `TerrainBuilder::inset_region`. Takes effect on
+    /// the next call to `update`.
+    pub fn set_inset_regions(&mut self, insets: Vec<InsetRegion>) -> Result<(), Error> {
+        let insets = insets.into_iter().map(cache::region::Inset::new).collect::<Result<_, _>>()?;
+        self.cache.set_insets(insets);
+        Ok(())
+    }
+
+    /// Deform the terrain, such as to dig a crater or flatten a building pad. Affected tiles are
+    /// regenerated on the next call to `update`, and height and collision queries reflect the
+    /// change immediately.
+    pub fn add_deformation(&mut self, deformation: Deformation) -> Result<(), Error> {
+        self.cache.add_deformation(deformation)
+    }
+
+    /// Remove all deformations added with `add_deformation`.
+    pub fn clear_deformations(&mut self) {
+        self.cache.clear_deformations();
+    }
+
+    /// Viewpoints for the main camera followed by any additional viewers and views. The index of
+    /// each view's viewpoint is recorded so that `render_view` can draw the nodes selected for it.
+    fn viewpoints(
+        &mut self,
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) -> Vec<Viewpoint> {
+        let mut index = self.additional_viewers.len();
+        for view in &mut self.views {
+            view.viewpoint = view.viewer.map(|_| {
+                index += 1;
+                index
+            });
+        }
+
+        std::iter::once(Viewpoint::new(view_proj, camera, &self.lod_target))
+            .chain(
+                self.additional_viewers
+                    .iter()
+                    .chain(self.views.iter().filter_map(|view| view.viewer.as_ref()))
+                    .map(|v| Viewpoint::new(v.view_proj, v.position, &v.lod_target)),
+            )
+            .collect()
+    }
+
+    /// Overlay a wireframe of the terrain patches, colored by quadtree level.
+    ///
+    /// Patch boundaries are highlighted and vertices are darkened as they morph toward their
+    /// parent's grid. This has no effect unless the device was created with
+    /// `wgpu::Features::POLYGON_MODE_LINE`.
+    pub fn set_wireframe(&mut self, enabled: bool) {
+        self.wireframe = enabled;
+    }
+
+    /// Replace the regular shading of the terrain with one of the debug views, or restore it by
+    /// passing `None`. Grass and trees are hidden while a debug view is active, and reflection
+    /// probes are unaffected. Can be combined with `set_wireframe`.
+    pub fn set_debug_view(&mut self, view: Option<DebugView>) {
+        self.debug_view = view;
+    }
+
+    /// Direction towards the sun in ECEF coordinates, as of the last call to `update`.
+    pub fn sun_direction(&self) -> mint::Vector3<f32> {
+        self.sun_direction.into()
+    }
+
+    /// Fix the direction towards the sun in ECEF coordinates, rather than computing it from the
+    /// Julian day passed to `update`. Pass `None` to go back to the astronomical sun position.
+    ///
+    /// The sun direction drives lighting, the sky and aerial perspective, and the direction of
+    /// shadows, all of which pick up the change on the next call to `update`. The moon, stars and
+    /// phase of the moon keep following the Julian day, so use `julian_day` instead where a
+    /// consistent sky matters.
+    pub fn set_sun_direction(&mut self, direction: Option<mint::Vector3<f32>>) {
+        self.sun_direction_override = direction.map(|d| Vector3::from(d).normalize());
+    }
+
+    /// Direction from the camera towards the center of the moon in ECEF coordinates, as of the
+    /// last call to `update`. This includes the parallax from the camera not being at the
+    /// center of the planet, which can shift the moon by up to a degree.
+    pub fn moon_direction(&self) -> mint::Vector3<f32> {
+        let camera = Vector3::new(self.camera.x, self.camera.y, self.camera.z);
+        (self.moon_position - camera).normalize().cast().unwrap().into()
+    }
+
+    /// Fraction of the moon's disk that is lit by the sun, between 0 at new moon and 1 at full
+    /// moon.
+    pub fn moon_illumination(&self) -> f32 {
+        let elongation = self.sun_direction.dot(Vector3::from(self.moon_direction()));
+        0.5 * (1.0 - elongation)
+    }
+
+    /// Position of the sun in texture coordinates for `view_proj`, along with a factor that fades
+    /// out light shafts as the sun leaves the screen.
+    fn sun_screen_position(&self, view_proj: mint::ColumnMatrix4<f32>) -> ([f32; 2], f32) {
+        let clip = cgmath::Matrix4::from(view_proj) * self.sun_direction.extend(0.0);
+        if clip.w <= 0.0 {
+            return ([0.0; 2], 0.0);
+        }
+        let uv = [clip.x / clip.w * 0.5 + 0.5, 0.5 - clip.y / clip.w * 0.5];
+        let outside = uv.iter().map(|&c| (-c).max(c - 1.0)).fold(0.0f32, f32::max);
+        (uv, (1.0 - outside * 2.0).clamp(0.0, 1.0))
+    }
+
+    fn moon_angular_radius(&self) -> f32 {
+        let camera = Vector3::new(self.camera.x, self.camera.y, self.camera.z);
+        (MOON_RADIUS / (self.moon_position - camera).magnitude()).asin() as f32
+    }
+
+    /// Select how water is rendered. Takes effect on the next call to `render`. Reflection probes
+    /// never render more than `WaterQuality::Reflections`.
+    pub fn set_water_quality(&mut self, quality: WaterQuality) {
+        self.water_quality = quality;
+    }
+
+    /// Reflect terrain and models visible on screen off water, rather than only the sky.
+    ///
+    /// Reflections are found by marching the depth buffer along the reflected rays, so anything
+    /// off screen or hidden behind nearer geometry is still replaced by the sky. Like occlusion
+    /// culling, this needs the depth buffer passed to `render` to be created with
+    /// `wgpu::TextureUsages::TEXTURE_BINDING`, and is skipped while MSAA is enabled or water
+    /// quality is `WaterQuality::Off`. Disabled by default.
+    pub fn set_screen_space_reflections(&mut self, enabled: bool) {
+        self.screen_space_reflections = enabled;
+    }
+
+    /// Draw light shafts radiating from the sun where terrain partially blocks it, with the given
+    /// strength. Zero, the default, disables them, while values around one give a subtle effect.
+    /// Only applies to `render` and `render_to_texture`, since reflection probes aren't
+    /// post-processed.
+    pub fn set_light_shafts(&mut self, intensity: f32) {
+        self.light_shafts = intensity.max(0.0);
+    }
+
+    /// Blend exponential height fog into the terrain, vegetation and sky, or disable it with
+    /// `None`, the default. Takes effect on the next call to `update`.
+    pub fn set_height_fog(&mut self, fog: Option<HeightFog>) {
+        self.height_fog = fog;
+    }
+
+    /// Set the density, height, coloring and wind response of grass. Takes effect on the next call
+    /// to `update`.
+    pub fn set_grass_parameters(&mut self, grass: GrassParameters) {
+        if grass.density != self.grass.density {
+            self.cache.invalidate_grass();
+        }
+        self.grass = grass;
+    }
+
+    /// Set the altitudes at which mountains are capped with snow, or `None` to leave them bare.
+    /// Defaults to `SnowLine::default()`. Changing this regenerates the materials of every loaded
+    /// tile.
+    pub fn set_snow_line(&mut self, snow_line: Option<SnowLine>) {
+        self.cache.set_snow_line(snow_line);
+    }
+
+    /// Carve generated terrain with hydraulic erosion where it is upsampled beyond the resolution
+    /// of the source elevation data, or go back to plain fractal detail with `None`. Off by
+    /// default. Changing this regenerates the heightmaps of every loaded tile.
+    pub fn set_erosion(&mut self, erosion: Option<Erosion>) {
+        self.cache.set_erosion(erosion);
+    }
+
+    /// Blend application supplied detail materials over the generated ones, or go back to just
+    /// the generated materials with `None`. Changing this regenerates the materials of every
+    /// loaded tile.
+    pub fn set_material_splatting(
+        &mut self,
+        queue: &wgpu::Queue,
+        splatting: Option<&MaterialSplatting>,
+    ) -> Result<(), Error> {
+        cache::splatting::upload(queue, &self.gpu_state, splatting)
+            .map_err(Error::InvalidArgument)?;
+        self.cache.invalidate_materials();
+        Ok(())
+    }
+
+    /// Simulate falling rain or snow around the camera, or stop with `None`, the default.
+    /// Particles disappear where they reach the terrain, and are hidden behind it when drawn.
+    /// Only applies to `render` and `render_to_texture`.
+    pub fn set_precipitation(&mut self, precipitation: Option<Precipitation>) {
+        self.weather.set_precipitation(precipitation);
+    }
+
+    /// Drape the lines and polygons of a GeoJSON document over the terrain, such as borders,
+    /// routes or measurements. Features may override `options.default_style` with the
+    /// simplestyle `stroke`, `stroke-opacity`, `stroke-width`, `fill` and `fill-opacity`
+    /// properties, with widths in meters. Points are ignored, and polygon holes are outlined but
+    /// not cut out of the fill.
+    ///
+    /// Overlays are draped over whatever heightmaps are loaded, and draped again every so often
+    /// as more detailed ones arrive. They only apply to `render` and `render_to_texture`.
+    pub fn add_geojson_overlay(
+        &mut self,
+        geojson: &str,
+        options: &OverlayOptions,
+    ) -> Result<OverlayId, Error> {
+        self.overlays
+            .add_geojson(geojson, options, &self.cache)
+            .map_err(|e| Error::categorize(e, Error::InvalidArgument))
+    }
+
+    /// Remove an overlay added with `add_geojson_overlay`, returning whether it existed.
+    pub fn remove_overlay(&mut self, id: OverlayId) -> bool {
+        self.overlays.remove(id)
+    }
+
+    /// Remove all overlays.
+    pub fn clear_overlays(&mut self) {
+        self.overlays.clear();
+    }
+
+    /// Mark a point on the planet with a label or icon that faces the screen, such as a city
+    /// name or point of interest. Annotations are drawn over the tonemapped frame by `render` and
+    /// `render_to_texture`, so they aren't included in `capture_frame`.
+    ///
+    /// Annotations may be hidden while terrain is in the way, and when they overlap on screen only
+    /// the one with the highest priority is drawn.
+    pub fn add_annotation(&mut self, annotation: Annotation) -> Result<AnnotationId, Error> {
+        self.annotations.add(annotation).map_err(Error::InvalidArgument)
+    }
+
+    /// Remove an annotation added with `add_annotation`, returning whether it existed.
+    pub fn remove_annotation(&mut self, id: AnnotationId) -> bool {
+        self.annotations.remove(id)
+    }
+
+    /// Remove all annotations.
+    pub fn clear_annotations(&mut self) {
+        self.annotations.clear();
+    }
+
+    /// Annotations drawn in the most recent frame, from highest to lowest priority.
+    pub fn visible_annotations(&self) -> &[AnnotationId] {
+        self.annotations.visible()
+    }
+
+    /// Set how wet and snow covered the terrain appears. Takes effect on the next call to
+    /// `update`.
+    pub fn set_surface_conditions(&mut self, conditions: SurfaceConditions) {
+        self.weather.set_surface_conditions(conditions);
+    }
+
+    /// Set the number of samples per pixel used for MSAA. Must be 1, 2, 4 or 8, and the adapter
+    /// must support that count for the `Rgba16Float` and `Depth32Float` formats (only 1 and 4 are
+    /// guaranteed). Takes effect on the next call to `update`.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<(), Error> {
+        if ![1, 2, 4, 8].contains(&sample_count) {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Unsupported sample count: {}",
+                sample_count
+            )));
+        }
+        self.set_target_config(TargetConfig { sample_count, ..self.target_config });
+        Ok(())
+    }
+
+    /// Set the depth convention used by the projection matrices passed to `update` and
+    /// `render`, so that terra can be composed with engines that use conventional depth. Only
+    /// `reverse_z` affects rendering; `near` and `far` are used by `DepthConfig::projection_matrix`.
+    /// Takes effect on the next call to `update`.
+    pub fn set_depth_config(&mut self, config: DepthConfig) {
+        self.set_target_config(TargetConfig { reverse_z: config.reverse_z, ..self.target_config });
+    }
+
+    fn set_target_config(&mut self, target_config: TargetConfig) {
+        if target_config != self.target_config {
+            self.target_config = target_config;
+            self.cache.set_target_config(target_config);
+            self.sky_bindgroup_pipeline = None;
+            self.stars_bindgroup_pipeline = None;
+        }
+    }
+
+    /// Set how bright the scene is rendered. Defaults to a fixed exposure suited to sunlit
+    /// scenes.
+    pub fn set_exposure(&mut self, exposure: Exposure) {
+        self.postprocess.set_exposure(exposure);
+        for view in &mut self.views {
+            view.postprocess.set_exposure(exposure);
+        }
+    }
+
+    /// Set the operator used to map the HDR scene to the color buffer passed to `render`.
+    pub fn set_tonemapper(&mut self, tonemapper: Tonemapper) {
+        self.postprocess.set_tonemapper(tonemapper);
+        for view in &mut self.views {
+            view.postprocess.set_tonemapper(tonemapper);
+        }
+    }
+
+    /// Overlay boxes around the nodes selected for rendering, to help diagnose streaming and
+    /// level of detail problems.
+    ///
+    /// Boxes use the same colors per quadtree level as `set_wireframe`. They are dimmed while the
+    /// node is still missing tiles, and drawn translucent if more detailed nodes cover part of it.
+    pub fn set_bounds_overlay(&mut self, enabled: bool) {
+        self.cache.set_bounds_overlay(enabled);
+    }
+
+    /// Garbage collect the local tile cache, returning the number of bytes reclaimed.
+    ///
+    /// This also happens automatically every so often when a `Terrain` is constructed.
+    pub fn compact_cache(&self) -> Result<u64, Error> {
+        self.mapfile.compact().map_err(|e| Error::categorize(e, Error::MapFile))
+    }
+
+    /// Write a georeferenced mosaic of `layer` covering `region` to `path`.
+    ///
+    /// Only tiles that are currently resident in the cache can be exported, so the camera should
+    /// have recently been near the region. Wherever tiles at `level` aren't available, the
+    /// closest coarser tiles are used instead. `TerrainBaker` can export regions without a camera.
+    #[allow(clippy::too_many_arguments)]
+    pub fn export_layer<P: AsRef<std::path::Path>>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layer: ExportLayer,
+        region: ExportRegion,
+        level: u8,
+        format: ExportFormat,
+        path: P,
+    ) -> Result<(), Error> {
+        if cfg!(target_arch = "wasm32") {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Exporting layers isn't supported on the web"
+            )));
+        }
+        export::export_layer(
+            |tile_layer, node| {
+                futures::executor::block_on(self.cache.readback_layer(
+                    device,
+                    queue,
+                    &self.gpu_state,
+                    tile_layer,
+                    node,
+                ))
+            },
+            layer,
+            region,
+            level,
+            format,
+            path.as_ref(),
+        )
+        .map_err(|e| Error::categorize(e, Error::InvalidArgument))
+    }
+
+    /// Render an orthographic top-down view of the terrain for use as a minimap, independently of
+    /// the main camera. The view covers a square `extent` meters across centered on the given
+    /// latitude and longitude in degrees, with north up and east to the right. Pixels hold the
+    /// albedo of the terrain shaded by its slopes, with no atmosphere or time of day applied.
+    ///
+    /// Returns an `Rgba8UnormSrgb` texture with `resolution` pixels along each side, usable as a
+    /// texture binding or copy source. Only tiles already in the tile cache are used, falling
+    /// back to coarser ones where needed, and reading them back blocks until the GPU is done, so
+    /// this isn't available on the web.
+    pub fn render_overhead(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        center: (f64, f64),
+        extent: f64,
+        resolution: u32,
+    ) -> Result<wgpu::Texture, Error> {
+        if cfg!(target_arch = "wasm32") {
+            return Err(Error::InvalidArgument(anyhow::format_err!(
+                "Overhead maps aren't supported on the web"
+            )));
+        }
+        let pixels = overhead::render_overhead(
+            center,
+            extent,
+            resolution,
+            |node| {
+                futures::executor::block_on(self.cache.readback_layer(
+                    device,
+                    queue,
+                    &self.gpu_state,
+                    LayerType::AlbedoRoughness,
+                    node,
+                ))
+            },
+            |cspace| self.cache.terrain_height(cspace, VNode::LEVEL_CELL_1M).unwrap_or(0.0),
+        )
+        .map_err(Error::InvalidArgument)?;
+
+        let size =
+            wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST,
+            label: Some("texture.overhead"),
+            view_formats: &[],
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(std::num::NonZeroU32::new(resolution * 4).unwrap()),
+                rows_per_image: None,
+            },
+            size,
+        );
+        Ok(texture)
+    }
+
+    /// Returns the terrain nodes selected for the main camera by the last call to `update`, so that
+    /// other spatial systems like audio occlusion or AI sectors, or custom render passes, can
+    /// reuse the same level of detail decisions.
+    pub fn visible_nodes(&self) -> impl Iterator<Item = VisibleNode> + '_ {
+        self.cache.visible_nodes()
+    }
+
+    /// Returns a snapshot of the tile cache and streaming state, along with GPU timings of the
+    /// passes making up a recent frame.
+    pub fn statistics(&self) -> Statistics {
+        Statistics { gpu_timings: self.profiler.timings().to_vec(), ..self.cache.statistics() }
+    }
+
+    /// Whether the device's limits are too low for full quality, as on many integrated GPUs and
+    /// with the GL backend. Terrain is then only loaded up to a coarser level of detail, which
+    /// also leaves out grass and the finest heightmaps, and tiles are generated more slowly.
+    pub fn reduced_quality(&self) -> bool {
+        self.cache.is_downlevel()
+    }
+
+    /// Returns the number of live GPU textures, buffers and bind groups, grouped by category.
+    pub fn resource_usage(&self) -> Vec<ResourceUsage> {
+        self.gpu_state.resources.report()
+    }
+
+    /// Periodically log a summary of live GPU resources from within `update`, or stop doing so
+    /// if `interval` is `None`.
+    pub fn set_resource_report_interval(&mut self, interval: Option<Duration>) {
+        self.resource_report_interval = interval;
+        self.last_resource_report = Instant::now();
+    }
+
+    /// Panic as soon as any category of GPU resources grows beyond its expected size.
+    ///
+    /// Intended for debugging leaks during long sessions.
+    pub fn set_resource_assertions(&mut self, enabled: bool) {
+        self.gpu_state.resources.set_assertions(enabled);
+    }
+
+    /// Limit how much GPU time tile generation may take per frame. Generators are given tiles in
+    /// order of the most important node they have work for, until their measured costs add up to
+    /// the budget. The most urgent tile is always generated, even when it costs more than that.
+    ///
+    /// Costs can only be measured if the device was created with
+    /// `wgpu::Features::TIMESTAMP_QUERY`. Otherwise the budget has no effect. Defaults to 4ms.
+    pub fn set_generation_budget(&mut self, budget: Duration) {
+        self.cache.set_generation_budget(budget);
+    }
+
+    /// Returns the GPU time per frame that tile generation is limited to.
+    pub fn generation_budget(&self) -> Duration {
+        self.cache.generation_budget()
+    }
+
+    /// Check a sample of generated tiles for NaN or infinite values, implausible heights, and
+    /// fully black colors. Problems are logged and can be retrieved with `validation_issues`.
+    ///
+    /// Each check reads a tile back from the GPU, so this slows down rendering noticeably. It isn't
+    /// available on the web, where waiting for readbacks would block the browser.
+    pub fn set_validation(&mut self, enabled: bool) {
+        self.cache.set_validation(enabled);
+    }
+
+    /// Returns the problems found by validation since the last call.
+    pub fn validation_issues(&mut self) -> Vec<ValidationIssue> {
+        self.cache.take_validation_issues()
+    }
+
+    /// Start or stop recording when nodes are loaded into or evicted from the tile cache, so
+    /// that applications can manage their own content like buildings or colliders alongside the
+    /// terrain. Nodes that are already loaded are reported on the next call to `update`.
+    pub fn set_node_events(&mut self, enabled: bool) {
+        self.cache.set_node_events(enabled);
+    }
+
+    /// Returns the node events recorded since the last call, oldest first.
+    pub fn node_events(&mut self) -> Vec<NodeEvent> {
+        self.cache.take_node_events()
+    }
+
+    /// Returns a channel that receives a `TileEvent` for every step in the lifecycle of the tiles
+    /// in the cache: requests to the streamer, streamed and generated tiles, evictions, and
+    /// failures. Events are sent during `update`, and stop being built once every receiver has
+    /// been dropped. May be called several times to get independent subscriptions.
+    pub fn subscribe_tile_events(&mut self) -> std::sync::mpsc::Receiver<TileEvent> {
+        self.cache.subscribe_tile_events()
+    }
+
+    /// Recommended near and far plane distances for a camera at the given ECEF position, based
+    /// on its altitude, the height of the terrain below it and the curvature of the planet.
+    ///
+    /// Tiles beyond the far plane are never loaded, so using these planes rather than hardcoded
+    /// constants avoids clipping visible terrain while keeping depth precision high.
+    pub fn recommended_clip_planes(&self, camera: mint::Point3<f64>) -> (f64, f64) {
+        let position = Vector3::new(camera.x, camera.y, camera.z);
+        let altitude = terra_types::altitude(position);
+        let height_above_terrain =
+            self.cache.height_above_terrain(position, VNode::LEVEL_CELL_1M).unwrap_or(altitude);
+        clip_planes(altitude, height_above_terrain)
+    }
+
+    /// Find where a ray first hits the terrain, or `None` if it misses.
+    ///
+    /// The intersection is computed on the CPU against the most detailed heightmaps currently in
+    /// the tile cache, without any GPU readbacks, so this is suitable for mouse picking,
+    /// projectile impacts and camera collision. Terrain far from the camera is only resident at
+    /// coarse levels of detail, so distant hits are correspondingly approximate.
+    pub fn raycast(
+        &self,
+        origin: mint::Point3<f64>,
+        direction: mint::Vector3<f64>,
+    ) -> Option<RaycastHit> {
+        self.cache.raycast(Vector3::new(origin.x, origin.y, origin.z), Vector3::from(direction))
+    }
+
+    /// Build a heightfield collider for `region` from the most detailed heightmaps currently in
+    /// the tile cache, with `resolution` samples along each side. See `CollisionHeightfield` for
+    /// how to hand the result to a physics engine.
+    ///
+    /// Heights improve as more detailed tiles stream in, so applications should enable
+    /// `set_node_events` and build the heightfield again whenever
+    /// `CollisionHeightfield::affected_by` reports that a node event changed it. Deformations
+    /// don't produce node events, so heightfields also need to be rebuilt after adding them.
+    pub fn collision_heightfield(
+        &self,
+        region: HeightfieldRegion,
+        resolution: usize,
+    ) -> Result<CollisionHeightfield, Error> {
+        self.cache.collision_heightfield(region, resolution).map_err(Error::InvalidArgument)
+    }
+
+    /// Extract triangles covering `region` for building navigation meshes, sampled from resident
+    /// heightmaps no more detailed than `level` and spaced like the samples of heightmaps at that
+    /// level. Triangles steeper than `max_slope` degrees or lying at or below sea level are
+    /// marked as such, so they can be given their own area types.
+    ///
+    /// Only heights are available on the CPU, so lakes and rivers above sea level are not
+    /// detected as water.
+    pub fn navigation_mesh(
+        &self,
+        region: HeightfieldRegion,
+        level: u8,
+        max_slope: f32,
+    ) -> Result<NavigationMesh, Error> {
+        self.cache.navigation_mesh(region, level, max_slope).map_err(Error::InvalidArgument)
+    }
+
+    /// Find the latitude and longitude in degrees and the height in meters of the terrain under
+    /// a point on the screen, or `None` if the point shows sky. Screen coordinates range from
+    /// zero to one with the origin in the top left corner, and `view_proj` must be relative to
+    /// the camera position passed to `update`, like the one passed to `render`.
+    ///
+    /// This casts a ray on the CPU like `raycast`, so it needs no GPU readback and works on
+    /// frames that haven't been rendered yet.
+    pub fn pick(
+        &self,
+        screen_xy: (f32, f32),
+        view_proj: mint::ColumnMatrix4<f32>,
+    ) -> Option<(f64, f64, f64)> {
+        let (near, direction) =
+            camera::screen_ray(screen_xy, view_proj, self.target_config.near_depth())?;
+        let origin = Vector3::new(self.camera.x, self.camera.y, self.camera.z) + near;
+        let hit = self.cache.raycast(origin, direction)?;
+
+        let position = Vector3::new(hit.position.x, hit.position.y, hit.position.z);
+        let d = cache::raycast::to_cspace(position).normalize();
+        let surface = cache::raycast::ellipsoid_point(d);
+        let height = position.magnitude() - surface.magnitude();
+        Some((d.z.asin().to_degrees(), d.y.atan2(d.x).to_degrees(), height))
+    }
+
+    /// Anchor an object to the terrain at the given latitude and longitude in degrees. The anchor
+    /// starts out on the most detailed heightmap currently loaded, and is moved by `update`
+    /// whenever more detailed heights arrive or deformations change the terrain, so that objects
+    /// placed with `anchor_transform` don't float or sink as the level of detail improves.
+    pub fn anchor(&mut self, latitude: f64, longitude: f64) -> AnchorId {
+        self.anchors.add(latitude, longitude, &self.cache)
+    }
+
+    /// Current placement of an anchor, or `None` if it has been removed.
+    pub fn anchor_transform(&self, id: AnchorId) -> Option<AnchorTransform> {
+        self.anchors.get(id)
+    }
+
+    /// Remove an anchor created with `anchor`, returning whether it existed.
+    pub fn remove_anchor(&mut self, id: AnchorId) -> bool {
+        self.anchors.remove(id)
+    }
+
+    /// Anchors that have moved since the last call to this function.
+    pub fn moved_anchors(&mut self) -> Vec<AnchorId> {
+        self.anchors.take_moved()
+    }
+
+    /// Whether the straight line between two ECEF positions is unobstructed by terrain. Like
+    /// `raycast`, this only considers heightmaps already in the tile cache.
+    pub fn line_of_sight(&self, a: mint::Point3<f64>, b: mint::Point3<f64>) -> bool {
+        self.cache.line_of_sight(Vector3::new(a.x, a.y, a.z), Vector3::new(b.x, b.y, b.z))
+    }
+
+    /// Compute which terrain within `radius` meters of `observer` is visible from it, for
+    /// modeling sensor coverage. See `Viewshed` for how the result is laid out.
+    ///
+    /// Every sample casts a ray on the CPU, so the cost grows with the square of `resolution`.
+    pub fn viewshed(
+        &self,
+        observer: mint::Point3<f64>,
+        radius: f64,
+        resolution: usize,
+        target_height: f64,
+    ) -> Viewshed {
+        let observer = Vector3::new(observer.x, observer.y, observer.z);
+        self.cache.viewshed(observer, radius, resolution, target_height)
+    }
+
+    /// Sample the terrain height along the great circle between two latitude/longitude pairs
+    /// given in degrees, with samples at most `spacing` meters apart. This is intended for route
+    /// profiles and flight planning, where the path may extend far from the camera.
+    ///
+    /// Any heightmaps along the path that aren't in the tile cache are downloaded (or read from
+    /// the local cache directory) as needed. Only streamed heightmaps are used, so the result
+    /// never has more detail than about 76 meters between samples of the underlying data.
+    pub async fn sample_path(
+        &self,
+        start: (f64, f64),
+        end: (f64, f64),
+        spacing: f64,
+    ) -> Result<Vec<PathSample>, Error> {
+        self.cache
+            .sample_path(start, end, spacing)
+            .await
+            .map_err(|e| Error::categorize(e, Error::MapFile))
+    }
+
+    pub fn get_height(&self, latitude: f64, longitude: f64) -> f32 {
+        for level in (0..=VNode::LEVEL_CELL_1M).rev() {
+            if let Some(height) = self.cache.get_height(latitude, longitude, level) {
+                return height;
+            }
+        }
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn check_send() {
+        struct Helper<T>(T);
+        trait AssertImpl {
+            fn assert() {}
+        }
+        impl<T: Send> AssertImpl for Helper<T> {}
+        Helper::<super::Terrain>::assert();
+    }
+
+    #[test]
+    fn frame_view_unproject() {
+        use super::{DepthConfig, FrameView};
+        use cgmath::{Matrix4, Point3, Vector3};
+
+        let camera = mint::Point3 { x: 1000.0, y: -2000.0, z: 6.4e6 };
+        let look = Matrix4::look_to_rh(
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::unit_z(),
+        );
+        for reverse_z in [false, true] {
+            let config = DepthConfig { reverse_z, near: 0.5, far: Some(10000.0) };
+            let view_proj = Matrix4::from(config.projection_matrix(1.0, 2.0)) * look;
+            let frame = FrameView { view_proj: view_proj.into(), camera, size: (201, 101) };
+
+            // The center of the frame looks straight ahead, 100 meters away.
+            let clip = view_proj * cgmath::Vector4::new(0.0, 100.0, 0.0, 1.0);
+            let p = frame.unproject((100, 50), clip.z / clip.w);
+            assert!((p.x - camera.x).abs() < 0.1, "{:?}", p);
+            assert!((p.y - camera.y - 100.0).abs() < 0.1, "{:?}", p);
+            assert!((p.z - camera.z).abs() < 0.1, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn depth_config_projection() {
+        use super::DepthConfig;
+        use cgmath::{Matrix4, Vector4};
+
+        let depth = |config: DepthConfig, distance: f32| {
+            let p = Matrix4::from(config.projection_matrix(1.0, 1.5))
+                * Vector4::new(0.0, 0.0, -distance, 1.0);
+            p.z / p.w
+        };
+        for reverse_z in [false, true] {
+            let (near, far) = if reverse_z { (1.0, 0.0) } else { (0.0, 1.0) };
+
+            let config = DepthConfig { reverse_z, near: 0.5, far: Some(1000.0) };
+            assert!((depth(config, 0.5) - near).abs() < 1e-5);
+            assert!((depth(config, 1000.0) - far).abs() < 1e-5);
+
+            let config = DepthConfig { reverse_z, near: 0.5, far: None };
+            assert!((depth(config, 0.5) - near).abs() < 1e-5);
+            assert!((depth(config, 1e9) - far).abs() < 1e-5);
         }
-        impl<T: Send> AssertImpl for Helper<T> {}
-        Helper::<super::Terrain>::assert();
     }
 }
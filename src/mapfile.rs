@@ -1,109 +1,789 @@
+use crate::telemetry;
 use anyhow::Error;
+#[cfg(not(target_arch = "wasm32"))]
 use atomicwrites::{AtomicFile, OverwriteBehavior};
+#[cfg(not(target_arch = "wasm32"))]
+use fs2::FileExt;
 use std::collections::HashSet;
 use std::fs;
-use std::io::{Cursor, Write};
-use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Write;
+use std::io::{Cursor, ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use terra_types::VNode;
 
+/// How often cached tiles are automatically garbage collected.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Fraction of the disk quota that remains in use after purging old files.
+const QUOTA_PURGE_TARGET: f64 = 0.9;
+
+/// Whether downloaded files are cached on disk. Browsers have no file system to cache them in, but
+/// already cache downloads themselves.
+const LOCAL_CACHE: bool = cfg!(not(target_arch = "wasm32"));
+
 lazy_static! {
     static ref TERRA_DIRECTORY: PathBuf =
         dirs::cache_dir().unwrap_or(PathBuf::from(".")).join("terra");
 }
 
-pub(crate) struct MapFile {
+/// Configures where tiles are streamed from and how they are cached locally. Everything else about
+/// the terrain is set through `TerrainBuilder`.
+///
+/// ```no_run
+/// # async fn f(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), anyhow::Error> {
+/// let builder = terra::MapFileBuilder::new(terra::DEFAULT_TILE_SERVER_URL.to_string())
+///     .cache_directory("/mnt/bulk/terra")
+///     .max_disk_usage(20 << 30);
+/// let terrain = terra::Terrain::with_map_file(device, queue, builder).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MapFileBuilder {
+    servers: Vec<String>,
+    cache_directory: Option<PathBuf>,
+    dataset_directory: Option<PathBuf>,
+    max_disk_usage: Option<u64>,
+}
+impl MapFileBuilder {
+    /// Stream tiles and assets from `server`, which must cover the entire planet.
+    pub fn new(server: String) -> Self {
+        Self {
+            servers: vec![server],
+            cache_directory: None,
+            dataset_directory: None,
+            max_disk_usage: None,
+        }
+    }
+
+    /// Layer tiles from an additional server on top of those from previously added ones.
+    pub fn mount(mut self, server: String) -> Self {
+        self.servers.push(server);
+        self
+    }
+
+    /// Directory to cache downloaded files in. Defaults to a `terra` subdirectory of the
+    /// platform's cache directory.
+    pub fn cache_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.cache_directory = Some(directory.into());
+        self
+    }
+
+    /// Directory passed to `terra-generate` when building tiles from source datasets. The raw
+    /// USGS, NASA and other files it downloads into the `download` subdirectory then count toward
+    /// `max_disk_usage` and are purged along with cached tiles. Generation holds the lock on that
+    /// subdirectory while it runs, and purges leave it alone until then. Purged files are
+    /// downloaded again the next time tiles are generated with downloading enabled.
+    pub fn dataset_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.dataset_directory = Some(directory.into());
+        self
+    }
+
+    /// Maximum number of bytes of downloaded tiles, assets and raw datasets to keep on disk. Once
+    /// exceeded, the least recently used files are deleted. Unlimited by default.
+    pub fn max_disk_usage(mut self, bytes: u64) -> Self {
+        self.max_disk_usage = Some(bytes);
+        self
+    }
+
+    pub(crate) async fn build(self) -> Result<MapFile, Error> {
+        if self.servers.is_empty() {
+            return Err(crate::Error::tag(
+                crate::Error::InvalidArgument,
+                anyhow::format_err!("At least one tile server must be provided"),
+            ));
+        }
+        let cache_directory = self.cache_directory.unwrap_or_else(|| TERRA_DIRECTORY.clone());
+
+        // The first mount uses the top level cache directory so that existing caches remain
+        // valid. Every other mount gets its own subdirectory.
+        let mut mounts = Vec::new();
+        for (i, server) in self.servers.into_iter().enumerate() {
+            let directory = if i == 0 {
+                cache_directory.clone()
+            } else {
+                cache_directory.join("mounts").join(MapFile::sanitize_server(&server))
+            };
+            mounts.push(Mount::new(server, directory).await?);
+        }
+
+        let quota = DiskQuota::new(
+            mounts.iter().map(|m| m.directory.clone()).collect(),
+            self.dataset_directory.map(|d| d.join("download")),
+            self.max_disk_usage,
+        );
+        let mapfile = MapFile { mounts, cache_directory, quota: Arc::new(quota) };
+
+        if !LOCAL_CACHE {
+            return Ok(mapfile);
+        }
+        mapfile.prepare_cache().await
+    }
+}
+
+/// Exclusive lock on a cache directory, shared between all processes using that directory.
+///
+/// Individual files are always written atomically, but compaction deletes files and leftover
+/// temporary directories, so writes and compaction must not overlap. The lock is an advisory lock
+/// on a `.lock` file in the directory, which the operating system releases if the owning process
+/// exits without doing so itself.
+struct DirectoryLock {
+    _file: fs::File,
+}
+impl DirectoryLock {
+    /// Acquire the lock on `directory`, blocking the current thread until it is available.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn acquire_blocking(directory: &Path) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(directory.join(".lock"))?;
+        file.lock_exclusive()?;
+        Ok(Self { _file: file })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn acquire_blocking(_directory: &Path) -> Result<Self, Error> {
+        unreachable!("Files are never cached on the web")
+    }
+
+    /// Acquire the lock on `directory` if no one else holds it. Returns `None` if the lock is
+    /// taken or the directory doesn't exist.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn try_acquire(directory: &Path) -> Result<Option<Self>, Error> {
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(directory.join(".lock"))
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn try_acquire(_directory: &Path) -> Result<Option<Self>, Error> {
+        unreachable!("Files are never cached on the web")
+    }
+
+    /// Acquire the lock on `directory` without blocking the async runtime while waiting for
+    /// another process to release it.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn acquire(directory: &Path) -> Result<Self, Error> {
+        let directory = directory.to_owned();
+        tokio::task::spawn_blocking(move || Self::acquire_blocking(&directory)).await?
+    }
+}
+
+/// Limit on the space used by cached tiles, assets and raw datasets.
+///
+/// Writes only update the running total. Once that exceeds the quota, the least recently used
+/// files are purged on a blocking thread, so that reading tiles never waits on the purge or on
+/// other processes holding the directory locks.
+struct DiskQuota {
+    /// Cache directories of every mount.
+    mount_directories: Vec<PathBuf>,
+    /// Where `terra-generate` keeps the raw datasets it downloads, if they count toward the quota.
+    raw_download_directory: Option<PathBuf>,
+    max_disk_usage: Option<u64>,
+    /// Approximate number of bytes used by cached tiles, assets and raw datasets.
+    disk_usage: AtomicU64,
+    /// Usage above which the next purge starts. Raised past the quota when a purge can't get
+    /// below the target, so that later writes don't rescan the cache on every tile.
+    purge_threshold: AtomicU64,
+    /// Whether a purge is running or scheduled.
+    purging: AtomicBool,
+}
+impl DiskQuota {
+    fn new(
+        mount_directories: Vec<PathBuf>,
+        raw_download_directory: Option<PathBuf>,
+        max_disk_usage: Option<u64>,
+    ) -> Self {
+        Self {
+            mount_directories,
+            raw_download_directory,
+            max_disk_usage,
+            disk_usage: AtomicU64::new(0),
+            purge_threshold: AtomicU64::new(max_disk_usage.unwrap_or(u64::MAX)),
+            purging: AtomicBool::new(false),
+        }
+    }
+
+    /// Count `bytes` newly written to the cache, and start a purge in the background if that puts
+    /// usage over the threshold.
+    fn record_write(self: &Arc<Self>, bytes: u64) {
+        let disk_usage = self.disk_usage.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if disk_usage > self.purge_threshold.load(Ordering::SeqCst)
+            && !self.purging.swap(true, Ordering::SeqCst)
+        {
+            self.spawn_purge();
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_purge(self: &Arc<Self>) {
+        let quota = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = quota.enforce() {
+                log::warn!("Failed to purge the tile cache: {:?}", e);
+            }
+            quota.purging.store(false, Ordering::SeqCst);
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn spawn_purge(self: &Arc<Self>) {
+        unreachable!("Files are never cached on the web")
+    }
+
+    /// If the cache is larger than the configured quota, delete the least recently used files
+    /// until it is comfortably below the limit again. Blocks until the directory locks are free.
+    fn enforce(&self) -> Result<(), Error> {
+        let max_disk_usage = match self.max_disk_usage {
+            Some(max) if self.disk_usage.load(Ordering::SeqCst) > max => max,
+            _ => return Ok(()),
+        };
+
+        let _locks = self
+            .mount_directories
+            .iter()
+            .map(|d| DirectoryLock::acquire_blocking(d))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // `terra-generate` holds the lock on the raw datasets while it is using them, and those
+        // are left alone until it is done rather than waiting for it.
+        let raw_lock = match &self.raw_download_directory {
+            Some(directory) => DirectoryLock::try_acquire(directory)?,
+            None => None,
+        };
+
+        // Recompute usage from scratch while holding the locks, since other processes may also
+        // have been adding or removing files.
+        let mut files = self.cached_files()?;
+        let mut disk_usage: u64 = files.iter().map(|f| f.1).sum();
+
+        if raw_lock.is_none() {
+            if let Some(directory) = &self.raw_download_directory {
+                files.retain(|f| !f.2.starts_with(directory));
+            }
+        }
+        files.sort_by_key(|f| f.0);
+        let target = (max_disk_usage as f64 * QUOTA_PURGE_TARGET) as u64;
+        for (_, size, path) in files {
+            if disk_usage <= target {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => disk_usage -= size,
+                Err(e) if e.kind() == ErrorKind::NotFound => disk_usage -= size,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Skipped raw datasets stay counted, so wait for as much new data as a
+        // successful purge would have made room for before trying again.
+        let threshold = max_disk_usage.max(disk_usage + (max_disk_usage - target));
+        self.disk_usage.store(disk_usage, Ordering::SeqCst);
+        self.purge_threshold.store(threshold, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// List all files counted toward the quota, along with the time they were last used and their
+    /// size.
+    fn cached_files(&self) -> Result<Vec<(SystemTime, u64, PathBuf)>, Error> {
+        let mut directories = self.raw_download_directory.iter().cloned().collect::<Vec<_>>();
+        for directory in &self.mount_directories {
+            directories.push(directory.join("tiles"));
+            directories.push(directory.join("assets"));
+        }
+
+        let mut files = Vec::new();
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    if !entry.file_name().to_string_lossy().starts_with(".atomicwrite") {
+                        directories.push(entry.path());
+                    }
+                } else if entry.file_name() != ".lock" {
+                    // Reads from the cache touch the modification time, see `Mount::touch`.
+                    let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((used, metadata.len(), entry.path()));
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// A single tile source mounted into a `MapFile`.
+struct Mount {
     server: String,
+    /// Local directory where files downloaded from `server` are cached.
+    directory: PathBuf,
     remote_tiles: Arc<Mutex<HashSet<VNode>>>,
 }
-impl MapFile {
-    pub(crate) async fn new(server: String) -> Result<Self, Error> {
-        // Create cache directory if necessary.
-        fs::create_dir_all(&*TERRA_DIRECTORY)?;
 
-        // Download file list if necessary.
-        let file_list_path = TERRA_DIRECTORY.join("tile_list.txt.zstd");
-        let file_list_encoded = if !file_list_path.exists() {
-            let contents = Self::download(&server, "tile_list.txt.zstd").await?;
-            if server.starts_with("http://") || server.starts_with("https://") {
-                tokio::fs::write(&file_list_path, &contents).await?;
+/// Collection of tile sources that together cover the planet.
+///
+/// Mounts are listed in increasing order of priority: a tile is read from the last mount whose
+/// tile list contains it. This makes it possible to layer regional high resolution packs on top
+/// of a global low resolution base and to ship each of them separately.
+pub(crate) struct MapFile {
+    mounts: Vec<Mount>,
+    cache_directory: PathBuf,
+    quota: Arc<DiskQuota>,
+}
+impl MapFile {
+    /// Compact the cache if it is due, measure how much space it uses, and purge files if that is
+    /// over the quota. All of this walks the cache directories, so it runs on a thread where
+    /// blocking is allowed rather than holding up the async runtime.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn prepare_cache(self) -> Result<Self, Error> {
+        tokio::task::spawn_blocking(move || {
+            // Periodically reclaim space used by stale or partially written tiles.
+            let last_compaction = fs::metadata(self.cache_directory.join("last_compaction"))
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| SystemTime::now().duration_since(t).ok());
+            if last_compaction.map(|t| t > COMPACTION_INTERVAL).unwrap_or(true) {
+                self.compact()?;
             }
-            contents
-        } else {
-            tokio::fs::read(file_list_path).await?
-        };
 
-        // Parse file list to learn all files available from the remote.
-        let remote_files = String::from_utf8(zstd::decode_all(Cursor::new(&file_list_encoded))?)?;
-        let remote_tiles = remote_files
-            .split('\n')
-            .filter_map(|f| f.strip_suffix(".zip"))
-            .map(VNode::from_str)
-            .collect::<Result<HashSet<VNode>, Error>>()?;
+            let disk_usage = self.quota.cached_files()?.iter().map(|f| f.1).sum();
+            self.quota.disk_usage.store(disk_usage, Ordering::SeqCst);
+            self.quota.enforce()?;
+            Ok(self)
+        })
+        .await?
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn prepare_cache(self) -> Result<Self, Error> {
+        unreachable!("Files are never cached on the web")
+    }
+
+    /// Remove cached tiles that are no longer needed, returning the number of bytes reclaimed.
+    ///
+    /// This deletes tiles that the server no longer lists, files that were left behind by
+    /// interrupted writes, and tiles that are empty or otherwise obviously corrupt. Any tile
+    /// removed this way will simply be downloaded again if it is ever needed.
+    pub(crate) fn compact(&self) -> Result<u64, Error> {
+        if !LOCAL_CACHE {
+            return Ok(0);
+        }
+
+        let mut reclaimed = 0;
+        for mount in &self.mounts {
+            reclaimed += mount.compact()?;
+        }
 
-        Ok(Self { server, remote_tiles: Arc::new(Mutex::new(remote_tiles)) })
+        fs::create_dir_all(&self.cache_directory)?;
+        fs::write(self.cache_directory.join("last_compaction"), b"")?;
+        self.quota
+            .disk_usage
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |u| Some(u.saturating_sub(reclaimed)))
+            .unwrap();
+        Ok(reclaimed)
+    }
+
+    fn has_zip_signature(path: &Path) -> Result<bool, Error> {
+        let mut signature = [0u8; 4];
+        let mut file = fs::File::open(path)?;
+        Ok(file.read_exact(&mut signature).is_ok()
+            && (signature == *b"PK\x03\x04" || signature == *b"PK\x05\x06"))
+    }
+
+    fn directory_size(path: &Path) -> Result<u64, Error> {
+        let mut size = 0;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            size += if metadata.is_dir() {
+                Self::directory_size(&entry.path())?
+            } else {
+                metadata.len()
+            };
+        }
+        Ok(size)
+    }
+
+    /// Turn a server URL into a name usable as a directory.
+    fn sanitize_server(server: &str) -> String {
+        server
+            .split_once("//")
+            .map(|(_, s)| s)
+            .unwrap_or(server)
+            .trim_end_matches('/')
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect()
     }
 
     pub(crate) async fn read_tile(&self, node: VNode) -> Result<Option<Vec<u8>>, Error> {
-        let filename = TERRA_DIRECTORY.join("tiles").join(&format!("{}.zip", node));
-        if filename.exists() {
-            Ok(Some(tokio::fs::read(&filename).await?))
-        } else {
-            if !self.remote_tiles.lock().unwrap().contains(&node) {
-                return Ok(None);
-            }
-            let contents = Self::download(&self.server, &format!("tiles/{}.zip", node)).await?;
-            if self.server.starts_with("http://") || self.server.starts_with("https://") {
-                if let Some(parent) = filename.parent() {
-                    fs::create_dir_all(parent)?;
+        for mount in self.mounts.iter().rev() {
+            if let Some((contents, written)) = mount.read_tile(node).await? {
+                if written {
+                    self.quota.record_write(contents.len() as u64);
                 }
-                AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                    .write(|f| f.write_all(&contents))?;
+                return Ok(Some(contents));
             }
-            Ok(Some(contents))
         }
+        Ok(None)
     }
 
+    /// Read an asset from the highest priority mount that provides it.
     pub(crate) async fn read_asset(&self, name: &str) -> Result<Vec<u8>, Error> {
-        let filename = TERRA_DIRECTORY.join("assets").join(name);
-        if filename.exists() {
-            Ok(tokio::fs::read(&filename).await?)
-        } else {
-            let contents = Self::download(&self.server, &format!("assets/{}", name)).await?;
-            if self.server.starts_with("http://") || self.server.starts_with("https://") {
-                if let Some(parent) = filename.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                    .write(|f| f.write_all(&contents))?;
+        let mut result = None;
+        for mount in self.mounts.iter().rev() {
+            if let Some(r) = mount.read_asset(name).await? {
+                result = Some(r);
+                break;
             }
-            Ok(contents)
         }
+        let (contents, written) = result
+            .ok_or_else(|| Self::missing(&self.mounts[0].server, &format!("assets/{}", name)))?;
+        if written {
+            self.quota.record_write(contents.len() as u64);
+        }
+        Ok(contents)
+    }
+
+    /// Whether files downloaded from `server` should be written to the local cache. Files from
+    /// local servers are already on disk, so there is no need to copy them.
+    fn is_cacheable(server: &str) -> bool {
+        LOCAL_CACHE && (server.starts_with("http://") || server.starts_with("https://"))
     }
 
-    async fn download(server: &str, path: &str) -> Result<Vec<u8>, Error> {
+    /// Error for a file that `server` was expected to provide but doesn't have.
+    fn missing(server: &str, path: &str) -> Error {
+        crate::Error::tag(
+            crate::Error::MapFile,
+            anyhow::format_err!("'{}' not found on tile server {}", path, server),
+        )
+    }
+
+    /// Download `path` from `server`, returning `None` if the server doesn't have it.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn download(server: &str, path: &str) -> Result<Option<Vec<u8>>, Error> {
         match server.split_once("//") {
             Some(("file:", base_path)) => {
                 let full_path = PathBuf::from(base_path).join(path);
-                Ok(tokio::fs::read(&full_path).await?)
+                match tokio::fs::read(&full_path).await {
+                    Ok(contents) => Ok(Some(contents)),
+                    Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
             }
             Some(("http:", ..)) | Some(("https:", ..)) => {
                 let url = format!("{}{}", server, path);
                 let client = hyper::Client::builder()
                     .build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
-                let resp = client.get(url.parse()?).await?;
-                if resp.status().is_success() {
-                    Ok(hyper::body::to_bytes(resp.into_body()).await?.to_vec())
+                let network = |e: hyper::Error| crate::Error::tag(crate::Error::Network, e.into());
+                let resp = client.get(url.parse()?).await.map_err(network)?;
+                if resp.status() == hyper::StatusCode::NOT_FOUND {
+                    Ok(None)
+                } else if resp.status().is_success() {
+                    let bytes = hyper::body::to_bytes(resp.into_body()).await.map_err(network)?;
+                    metrics::counter!(telemetry::BYTES_DOWNLOADED, bytes.len() as u64);
+                    Ok(Some(bytes.to_vec()))
                 } else {
-                    Err(anyhow::format_err!(
-                        "Tile download failed with {:?} for URL '{}'",
-                        resp.status(),
-                        url
+                    Err(crate::Error::tag(
+                        crate::Error::Network,
+                        anyhow::format_err!(
+                            "Tile download failed with {:?} for URL '{}'",
+                            resp.status(),
+                            url
+                        ),
                     ))
                 }
             }
             _ => Err(anyhow::format_err!("Invalid server URL {}", server)),
         }
     }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn download(server: &str, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match server.split_once("//") {
+            Some(("http:", ..)) | Some(("https:", ..)) => {
+                let url = format!("{}{}", server, path);
+                let network =
+                    |e: gloo_net::Error| crate::Error::tag(crate::Error::Network, e.into());
+                let resp = gloo_net::http::Request::get(&url).send().await.map_err(network)?;
+                if resp.status() == 404 {
+                    Ok(None)
+                } else if resp.ok() {
+                    let bytes = resp.binary().await.map_err(network)?;
+                    metrics::counter!(telemetry::BYTES_DOWNLOADED, bytes.len() as u64);
+                    Ok(Some(bytes))
+                } else {
+                    Err(crate::Error::tag(
+                        crate::Error::Network,
+                        anyhow::format_err!(
+                            "Tile download failed with {} for URL '{}'",
+                            resp.status(),
+                            url
+                        ),
+                    ))
+                }
+            }
+            Some(("file:", ..)) => {
+                Err(anyhow::format_err!("Local servers aren't supported on the web: {}", server))
+            }
+            _ => Err(anyhow::format_err!("Invalid server URL {}", server)),
+        }
+    }
+}
+impl Mount {
+    async fn new(server: String, directory: PathBuf) -> Result<Self, Error> {
+        // Create cache directory if necessary.
+        if LOCAL_CACHE {
+            fs::create_dir_all(&directory)?;
+        }
+
+        // Download file list if necessary.
+        let file_list_path = directory.join("tile_list.txt.zstd");
+        let file_list_encoded = match Self::read_cached(&file_list_path).await? {
+            Some(contents) => contents,
+            None => {
+                let contents = MapFile::download(&server, "tile_list.txt.zstd")
+                    .await?
+                    .ok_or_else(|| MapFile::missing(&server, "tile_list.txt.zstd"))?;
+                if MapFile::is_cacheable(&server) {
+                    Self::write_file(&directory, &file_list_path, &contents).await?;
+                }
+                contents
+            }
+        };
+
+        // Parse file list to learn all files available from the remote.
+        let remote_files = String::from_utf8(zstd::decode_all(Cursor::new(&file_list_encoded))?)?;
+        let remote_tiles = remote_files
+            .split('\n')
+            .filter_map(|f| f.strip_suffix(".zip"))
+            .map(VNode::from_str)
+            .collect::<Result<HashSet<VNode>, Error>>()?;
+
+        Ok(Self { server, directory, remote_tiles: Arc::new(Mutex::new(remote_tiles)) })
+    }
+
+    fn compact(&self) -> Result<u64, Error> {
+        let tiles_directory = self.directory.join("tiles");
+        let mut reclaimed = 0;
+        if tiles_directory.exists() {
+            // Holding the lock guarantees that any temporary directories were left behind by
+            // interrupted writes rather than belonging to writes still in progress.
+            let _lock = DirectoryLock::acquire_blocking(&self.directory)?;
+            let remote_tiles = self.remote_tiles.lock().unwrap();
+            for entry in fs::read_dir(&tiles_directory)? {
+                let entry = entry?;
+                let path = entry.path();
+                let metadata = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+
+                if metadata.is_dir() {
+                    if name.starts_with(".atomicwrite") {
+                        reclaimed += MapFile::directory_size(&path)?;
+                        fs::remove_dir_all(&path)?;
+                    }
+                    continue;
+                }
+
+                let valid = name
+                    .strip_suffix(".zip")
+                    .and_then(|n| VNode::from_str(n).ok())
+                    .map(|node| remote_tiles.contains(&node))
+                    .unwrap_or(false)
+                    && MapFile::has_zip_signature(&path)?;
+
+                if !valid {
+                    reclaimed += metadata.len();
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Read a tile, downloading it if necessary. Also returns whether the tile was added to the
+    /// local cache.
+    async fn read_tile(&self, node: VNode) -> Result<Option<(Vec<u8>, bool)>, Error> {
+        if !self.remote_tiles.lock().unwrap().contains(&node) {
+            return Ok(None);
+        }
+
+        let filename = self.directory.join("tiles").join(&format!("{}.zip", node));
+        if let Some(contents) = Self::read_cached(&filename).await? {
+            return Ok(Some((contents, false)));
+        }
+
+        let path = format!("tiles/{}.zip", node);
+        let contents = MapFile::download(&self.server, &path)
+            .await?
+            .ok_or_else(|| MapFile::missing(&self.server, &path))?;
+        let cacheable = MapFile::is_cacheable(&self.server);
+        if cacheable {
+            Self::write_file(&self.directory, &filename, &contents).await?;
+        }
+        Ok(Some((contents, cacheable)))
+    }
+
+    /// Read an asset, downloading it if necessary, or return `None` if the server doesn't provide
+    /// it. Also returns whether the asset was added to the local cache.
+    async fn read_asset(&self, name: &str) -> Result<Option<(Vec<u8>, bool)>, Error> {
+        let filename = self.directory.join("assets").join(name);
+        if let Some(contents) = Self::read_cached(&filename).await? {
+            return Ok(Some((contents, false)));
+        }
+
+        let contents = match MapFile::download(&self.server, &format!("assets/{}", name)).await? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+        let cacheable = MapFile::is_cacheable(&self.server);
+        if cacheable {
+            Self::write_file(&self.directory, &filename, &contents).await?;
+        }
+        Ok(Some((contents, cacheable)))
+    }
+
+    /// Mark a cached file as recently used for `DiskQuota::enforce` by setting its
+    /// modification time. Access times can't be used instead, since many file systems are
+    /// mounted with `noatime` or `relatime` and don't update them on every read.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn touch(filename: &Path) {
+        // A file whose time couldn't be updated is merely purged sooner than it should be.
+        let _ = filetime::set_file_mtime(filename, filetime::FileTime::now());
+    }
+
+    /// Read a file from the cache, returning `None` if it isn't present. Another process may
+    /// remove files at any time, so this doesn't rely on a prior existence check.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn read_cached(filename: &Path) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(filename).await {
+            Ok(contents) => {
+                Self::touch(filename);
+                Ok(Some(contents))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn read_cached(_filename: &Path) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /// Atomically write a file within `directory` while holding its lock.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn write_file(directory: &Path, filename: &Path, contents: &[u8]) -> Result<(), Error> {
+        if let Some(parent) = filename.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _lock = DirectoryLock::acquire(directory).await?;
+        AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
+            .write(|f| f.write_all(contents))?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn write_file(
+        _directory: &Path,
+        _filename: &Path,
+        _contents: &[u8],
+    ) -> Result<(), Error> {
+        unreachable!("Files are never cached on the web")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_lock() {
+        let directory = std::env::temp_dir().join(format!("terra-lock-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let other = || fs::OpenOptions::new().write(true).open(directory.join(".lock")).unwrap();
+
+        // Other handles can't take the lock until it is released.
+        let lock = DirectoryLock::acquire_blocking(&directory).unwrap();
+        assert!(other().try_lock_exclusive().is_err());
+        drop(lock);
+        assert!(other().try_lock_exclusive().is_ok());
+
+        // A lock file left behind by a process that exited doesn't hold the lock.
+        fs::write(directory.join(".lock"), "").unwrap();
+        drop(DirectoryLock::acquire_blocking(&directory).unwrap());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn quota_purges_least_recently_used() {
+        let root = std::env::temp_dir().join(format!("terra-quota-{}", std::process::id()));
+        let tile = root.join("cache").join("tiles").join("0_0_0.zip");
+        let asset = root.join("cache").join("assets").join("sky.ktx2");
+        let raw = root.join("dataset").join("download").join("nasadem").join("n00e000.zip");
+        for (i, path) in [&raw, &tile, &asset].into_iter().enumerate() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, [0u8; 100]).unwrap();
+            let time = filetime::FileTime::from_unix_time(1_000_000 + i as i64, 0);
+            filetime::set_file_mtime(path, time).unwrap();
+        }
+
+        // Reading the raw download makes it the most recently used file, so the tile goes first.
+        Mount::touch(&raw);
+        let quota = DiskQuota::new(
+            vec![root.join("cache")],
+            Some(root.join("dataset").join("download")),
+            Some(250),
+        );
+        quota.disk_usage.store(300, Ordering::SeqCst);
+        quota.enforce().unwrap();
+        assert!(!tile.exists());
+        assert!(asset.exists() && raw.exists());
+        assert_eq!(quota.disk_usage.load(Ordering::SeqCst), 200);
+        assert_eq!(quota.purge_threshold.load(Ordering::SeqCst), 250);
+
+        // Raw datasets that `terra-generate` is using are skipped even if they are the oldest
+        // files, and the purge then can't reach its target so the threshold is raised.
+        filetime::set_file_mtime(&raw, filetime::FileTime::zero()).unwrap();
+        let generating =
+            DirectoryLock::acquire_blocking(&root.join("dataset").join("download")).unwrap();
+        let quota = DiskQuota::new(
+            vec![root.join("cache")],
+            Some(root.join("dataset").join("download")),
+            Some(50),
+        );
+        quota.disk_usage.store(200, Ordering::SeqCst);
+        quota.enforce().unwrap();
+        assert!(!asset.exists() && raw.exists());
+        assert_eq!(quota.disk_usage.load(Ordering::SeqCst), 100);
+        assert_eq!(quota.purge_threshold.load(Ordering::SeqCst), 105);
+        drop(generating);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
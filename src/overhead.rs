@@ -0,0 +1,140 @@
+//! Top-down maps of the terrain, for in-game minimaps.
+
+use crate::cache::layer::LayerType;
+use crate::cache::raycast::to_cspace;
+use crate::export::texel_index;
+use crate::overlay::ellipsoid_point;
+use crate::weather::local_frame;
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+use terra_types::{VNode, ROOT_SIDE_LENGTH};
+
+/// Largest number of pixels along each side of an overhead map.
+pub const MAX_OVERHEAD_RESOLUTION: u32 = 4096;
+
+/// Color used wherever no albedo tile is resident, so that the hillshade is still visible.
+const MISSING_ALBEDO: [u8; 3] = [128, 128, 128];
+
+/// Render an orthographic top-down view of the square `extent` meters across centered on the
+/// given latitude and longitude, as sRGB pixels in row-major order with north up and east to the
+/// right. Albedo tiles are fetched with `albedo_tile`, which should return `None` for any tile
+/// that isn't available, and `height` returns the terrain height in the direction of a position
+/// in cube space.
+pub(crate) fn render_overhead<A, H>(
+    center: (f64, f64),
+    extent: f64,
+    resolution: u32,
+    mut albedo_tile: A,
+    height: H,
+) -> Result<Vec<u8>, Error>
+where
+    A: FnMut(VNode) -> Option<Vec<u8>>,
+    H: Fn(Vector3<f64>) -> f32,
+{
+    if resolution == 0 || resolution > MAX_OVERHEAD_RESOLUTION {
+        return Err(anyhow::format_err!(
+            "Overhead map resolution must be between 1 and {}",
+            MAX_OVERHEAD_RESOLUTION
+        ));
+    }
+    if !extent.is_finite() || extent <= 0.0 {
+        return Err(anyhow::format_err!("Overhead map extent must be positive"));
+    }
+
+    let origin = ellipsoid_point(center);
+    let (east, north, _) = local_frame(origin);
+    let resolution = resolution as usize;
+    let spacing = extent / resolution as f64;
+    let half = extent * 0.5;
+    // Position on the unit cube below the point `i` pixels east and `j` pixels south of the
+    // northwest corner.
+    let cspace_at = |i: f64, j: f64| {
+        let cspace = to_cspace(origin + east * (i * spacing - half) + north * (half - j * spacing));
+        cspace / cspace.x.abs().max(cspace.y.abs()).max(cspace.z.abs())
+    };
+
+    // Heights at the center of every pixel, plus a ring of samples around the edges so that
+    // gradients can be computed everywhere.
+    let n = resolution + 2;
+    let heights: Vec<f64> = (0..n * n)
+        .map(|k| height(cspace_at((k % n) as f64 - 0.5, (k / n) as f64 - 0.5)) as f64)
+        .collect();
+
+    let layer = LayerType::AlbedoRoughness;
+    let level = albedo_level(spacing);
+    let mut tiles: HashMap<VNode, Option<Vec<u8>>> = HashMap::new();
+    let mut pixels = vec![0u8; resolution * resolution * 4];
+    for j in 0..resolution {
+        for i in 0..resolution {
+            // Use the chosen level where possible, and otherwise fall back to coarser tiles.
+            let cspace = cspace_at(i as f64 + 0.5, j as f64 + 0.5);
+            let mut color = MISSING_ALBEDO;
+            for l in (layer.min_level()..=level).rev() {
+                let (node, x, y) = VNode::from_cspace(cspace, l);
+                if let Some(tile) = tiles.entry(node).or_insert_with(|| albedo_tile(node)) {
+                    color.copy_from_slice(&tile[texel_index(layer, x, y) * 4..][..3]);
+                    break;
+                }
+            }
+
+            let h = |di: usize, dj: usize| heights[(i + di) + (j + dj) * n];
+            let shade = hillshade(
+                (h(2, 1) - h(0, 1)) / (2.0 * spacing),
+                (h(1, 0) - h(1, 2)) / (2.0 * spacing),
+            );
+            let pixel = &mut pixels[(i + j * resolution) * 4..][..4];
+            for (p, c) in pixel.iter_mut().zip(color) {
+                *p = (c as f32 * shade).round().min(255.0) as u8;
+            }
+            pixel[3] = 255;
+        }
+    }
+    Ok(pixels)
+}
+
+/// Coarsest albedo level with texels no larger than `spacing` meters, or the most detailed level
+/// if none are that fine.
+fn albedo_level(spacing: f64) -> u8 {
+    let layer = LayerType::AlbedoRoughness;
+    let samples = (layer.texture_resolution() - 2 * layer.texture_border_size()) as f64;
+    (layer.min_level()..=layer.max_level())
+        .find(|&level| ROOT_SIDE_LENGTH as f64 / (1u64 << level) as f64 / samples <= spacing)
+        .unwrap_or(layer.max_level())
+}
+
+/// Brightness of a slope with the given height gradients toward the east and north, relative to
+/// flat ground. Light comes from the northwest 45 degrees above the horizon, as is conventional
+/// for maps.
+fn hillshade(east_gradient: f64, north_gradient: f64) -> f32 {
+    let normal = Vector3::new(-east_gradient, -north_gradient, 1.0).normalize();
+    let light = Vector3::new(-0.5, 0.5, std::f64::consts::FRAC_1_SQRT_2);
+    (normal.dot(light) / light.z).clamp(0.0, 1.5) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_terrain_shows_albedo() {
+        let layer = LayerType::AlbedoRoughness;
+        let tile_size = (layer.texture_resolution() * layer.texture_resolution()) as usize;
+        let tile: Vec<u8> = [100, 150, 200, 255].repeat(tile_size);
+        let pixels =
+            render_overhead((46.5, 7.9), 5000.0, 16, |_| Some(tile.clone()), |_| 0.0).unwrap();
+        assert_eq!(pixels.len(), 16 * 16 * 4);
+        assert!(pixels.chunks(4).all(|p| p == [100, 150, 200, 255]));
+
+        let missing = render_overhead((46.5, 7.9), 5000.0, 4, |_| None, |_| 0.0).unwrap();
+        assert!(missing.chunks(4).all(|p| p[..3] == MISSING_ALBEDO));
+    }
+
+    #[test]
+    fn slopes_facing_the_light_are_brighter() {
+        assert_eq!(hillshade(0.0, 0.0), 1.0);
+        assert!(hillshade(0.5, -0.5) > 1.0);
+        assert!(hillshade(-0.5, 0.5) < 1.0);
+        assert!(render_overhead((0.0, 0.0), 1.0, 0, |_| None, |_| 0.0).is_err());
+    }
+}
@@ -0,0 +1,798 @@
+//! Vector overlays such as borders and routes, loaded from GeoJSON and draped over the terrain.
+
+use crate::cache::TileCache;
+use crate::gpu_state::GpuState;
+use crate::postprocess::{TargetConfig, HDR_FORMAT};
+use crate::resources::{ResourceKind, Tracked};
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use instant::Instant;
+use maplit::hashmap;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use terra_types::{VNode, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+
+/// Spacing in meters that lines are split at in `DrapeMode::DepthOffset`, which keeps straight
+/// segments from cutting below the curve of the planet.
+const DEPTH_OFFSET_SPACING: f64 = 1000.0;
+/// Depth offset used in `DrapeMode::HeightConforming`, just enough to avoid z-fighting.
+const CONFORMING_DEPTH_OFFSET: f32 = 1e-4;
+/// Maximum number of times each triangle of a polygon fill is split in four.
+const MAX_FILL_SUBDIVISIONS: u32 = 6;
+/// Minimum time between draping overlays again as more detailed heightmaps arrive.
+const REDRAPE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How an overlay is styled. Colors are linear RGB with straight alpha, and are drawn without
+/// lighting or exposure.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OverlayStyle {
+    /// Color of lines and polygon outlines.
+    pub stroke_color: [f32; 4],
+    /// Width of lines and polygon outlines in meters. Outlines are skipped if this is zero.
+    pub stroke_width: f32,
+    /// Color of polygon interiors, which are left empty if this is `None`.
+    pub fill_color: Option<[f32; 4]>,
+}
+impl Default for OverlayStyle {
+    fn default() -> Self {
+        Self { stroke_color: [1.0, 0.5, 0.0, 1.0], stroke_width: 10.0, fill_color: None }
+    }
+}
+
+/// How an overlay follows the shape of the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DrapeMode {
+    /// Vertices are placed on the terrain, and the overlay is drawn pulled towards the camera
+    /// by the given fraction of its distance so that it stays visible where the terrain rises
+    /// between them. Cheap, but the overlay can show through hills in front of it.
+    DepthOffset(f32),
+    /// Geometry is split so that no edge is longer than the given number of meters, and every
+    /// vertex is placed on the terrain. Follows the terrain closely, but large polygons and long
+    /// lines produce many triangles.
+    HeightConforming(f32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OverlayOptions {
+    /// Style of features that don't override it with their properties.
+    pub default_style: OverlayStyle,
+    pub drape: DrapeMode,
+}
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self { default_style: OverlayStyle::default(), drape: DrapeMode::DepthOffset(0.002) }
+    }
+}
+
+/// Handle to an overlay returned by `Terrain::add_geojson_overlay`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OverlayId(u64);
+
+/// Geometry of a single GeoJSON feature, with coordinates as latitude/longitude pairs in degrees.
+#[derive(Clone, Debug, Default)]
+struct Feature {
+    style: OverlayStyle,
+    lines: Vec<Vec<(f64, f64)>>,
+    /// Rings of each polygon, with the exterior ring first.
+    polygons: Vec<Vec<Vec<(f64, f64)>>>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct OverlayVertex {
+    /// Position relative to the origin of the overlay.
+    position: [f32; 3],
+    /// Index of the overlay in the origins buffer.
+    overlay: u32,
+    color: [f32; 4],
+}
+unsafe impl bytemuck::Pod for OverlayVertex {}
+unsafe impl bytemuck::Zeroable for OverlayVertex {}
+
+/// Parse an sRGB color in the `#rrggbb` or `#rgb` forms used by simplestyle, returning it in
+/// linear RGB.
+fn parse_color(s: &str) -> Result<[f32; 3], Error> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    let digits: Vec<u32> = hex
+        .chars()
+        .map(|c| c.to_digit(16))
+        .collect::<Option<_>>()
+        .ok_or_else(|| anyhow::format_err!("Invalid color: {}", s))?;
+    let channels = match digits[..] {
+        [r, g, b] => [r * 17, g * 17, b * 17],
+        [r1, r0, g1, g0, b1, b0] => [r1 * 16 + r0, g1 * 16 + g0, b1 * 16 + b0],
+        _ => return Err(anyhow::format_err!("Invalid color: {}", s)),
+    };
+    Ok(channels.map(|c| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }))
+}
+
+/// Apply the simplestyle properties of a feature on top of `default`. Widths are in meters
+/// rather than pixels.
+fn feature_style(properties: &Value, default: &OverlayStyle) -> Result<OverlayStyle, Error> {
+    let mut style = *default;
+    let number = |key: &str| properties.get(key).and_then(Value::as_f64).map(|v| v as f32);
+    if let Some(stroke) = properties.get("stroke").and_then(Value::as_str) {
+        let [r, g, b] = parse_color(stroke)?;
+        style.stroke_color = [r, g, b, style.stroke_color[3]];
+    }
+    if let Some(opacity) = number("stroke-opacity") {
+        style.stroke_color[3] = opacity.clamp(0.0, 1.0);
+    }
+    if let Some(width) = number("stroke-width") {
+        style.stroke_width = width.max(0.0);
+    }
+    if let Some(fill) = properties.get("fill").and_then(Value::as_str) {
+        let [r, g, b] = parse_color(fill)?;
+        style.fill_color = Some([r, g, b, style.fill_color.map(|c| c[3]).unwrap_or(0.6)]);
+    }
+    if let (Some(opacity), Some(fill)) = (number("fill-opacity"), style.fill_color.as_mut()) {
+        fill[3] = opacity.clamp(0.0, 1.0);
+    }
+    Ok(style)
+}
+
+fn position(value: &Value) -> Result<(f64, f64), Error> {
+    match value.as_array().map(|a| &a[..]) {
+        Some([longitude, latitude, ..]) => match (latitude.as_f64(), longitude.as_f64()) {
+            (Some(latitude), Some(longitude)) if (-90.0..=90.0).contains(&latitude) => {
+                Ok((latitude, longitude))
+            }
+            _ => Err(anyhow::format_err!("Invalid GeoJSON position: {}", value)),
+        },
+        _ => Err(anyhow::format_err!("Invalid GeoJSON position: {}", value)),
+    }
+}
+
+fn positions(value: &Value) -> Result<Vec<(f64, f64)>, Error> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow::format_err!("Expected an array of GeoJSON positions"))?
+        .iter()
+        .map(position)
+        .collect()
+}
+
+fn rings(value: &Value) -> Result<Vec<Vec<(f64, f64)>>, Error> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow::format_err!("Expected an array of GeoJSON rings"))?
+        .iter()
+        .map(positions)
+        .collect()
+}
+
+/// Add the lines and polygons of a GeoJSON geometry object to `feature`. Points are ignored.
+fn add_geometry(geometry: &Value, feature: &mut Feature) -> Result<(), Error> {
+    let coordinates = &geometry["coordinates"];
+    let arrays = || {
+        coordinates
+            .as_array()
+            .ok_or_else(|| anyhow::format_err!("Expected an array of GeoJSON coordinates"))
+    };
+    match geometry["type"].as_str() {
+        Some("Point") | Some("MultiPoint") => {}
+        Some("LineString") => feature.lines.push(positions(coordinates)?),
+        Some("MultiLineString") => {
+            feature.lines.extend(arrays()?.iter().map(positions).collect::<Result<Vec<_>, _>>()?)
+        }
+        Some("Polygon") => feature.polygons.push(rings(coordinates)?),
+        Some("MultiPolygon") => {
+            feature.polygons.extend(arrays()?.iter().map(rings).collect::<Result<Vec<_>, _>>()?)
+        }
+        Some("GeometryCollection") => {
+            for geometry in geometry["geometries"]
+                .as_array()
+                .ok_or_else(|| anyhow::format_err!("GeometryCollection without geometries"))?
+            {
+                add_geometry(geometry, feature)?;
+            }
+        }
+        _ => return Err(anyhow::format_err!("Unsupported GeoJSON geometry: {}", geometry["type"])),
+    }
+    Ok(())
+}
+
+/// Parse a GeoJSON document, which may be a feature collection, a single feature or a bare
+/// geometry.
+fn parse_geojson(geojson: &str, default_style: &OverlayStyle) -> Result<Vec<Feature>, Error> {
+    let value: Value = serde_json::from_str(geojson)?;
+    let parse_feature = |feature: &Value| -> Result<Feature, Error> {
+        let mut parsed = Feature {
+            style: feature_style(&feature["properties"], default_style)?,
+            ..Default::default()
+        };
+        if !feature["geometry"].is_null() {
+            add_geometry(&feature["geometry"], &mut parsed)?;
+        }
+        Ok(parsed)
+    };
+    match value["type"].as_str() {
+        Some("FeatureCollection") => value["features"]
+            .as_array()
+            .ok_or_else(|| anyhow::format_err!("FeatureCollection without features"))?
+            .iter()
+            .map(parse_feature)
+            .collect(),
+        Some("Feature") => Ok(vec![parse_feature(&value)?]),
+        _ => {
+            let mut feature = Feature { style: *default_style, ..Default::default() };
+            add_geometry(&value, &mut feature)?;
+            Ok(vec![feature])
+        }
+    }
+}
+
+/// Point on the ellipsoid at the given latitude and longitude in degrees.
+pub(crate) fn ellipsoid_point((latitude, longitude): (f64, f64)) -> Vector3<f64> {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    Vector3::new(
+        PLANET_SEMIMAJOR_AXIS * latitude.cos() * longitude.cos(),
+        PLANET_SEMIMAJOR_AXIS * latitude.cos() * longitude.sin(),
+        PLANET_SEMIMINOR_AXIS * latitude.sin(),
+    )
+}
+
+/// Split the segments of `points` so that none are longer than `spacing` meters. Segments are
+/// straight in latitude and longitude, as GeoJSON specifies.
+fn densify(points: &[(f64, f64)], spacing: f64) -> Vec<(f64, f64)> {
+    let mut output = Vec::with_capacity(points.len());
+    for (i, &b) in points.iter().enumerate() {
+        if i > 0 {
+            let a = points[i - 1];
+            let extent = (ellipsoid_point(b) - ellipsoid_point(a)).magnitude();
+            let segments = (extent / spacing.max(1.0)).ceil().max(1.0) as usize;
+            for j in 1..segments {
+                let t = j as f64 / segments as f64;
+                output.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+            }
+        }
+        output.push(b);
+    }
+    output
+}
+
+/// Triangulate a simple polygon by ear clipping, returning indices into `polygon`. Degenerate
+/// or self-intersecting polygons may be left partially filled.
+fn triangulate(polygon: &[Vector2<f64>]) -> Vec<[usize; 3]> {
+    let cross = |a: Vector2<f64>, b: Vector2<f64>, c: Vector2<f64>| {
+        (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+    };
+
+    let mut indices: Vec<usize> = (0..polygon.len()).collect();
+    let area: f64 = (0..polygon.len())
+        .map(|i| {
+            let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum();
+    if area < 0.0 {
+        indices.reverse();
+    }
+
+    let mut triangles = Vec::new();
+    let (mut i, mut attempts) = (0, 0);
+    while indices.len() > 3 && attempts <= indices.len() {
+        let n = indices.len();
+        i %= n;
+        let (a, b, c) = (indices[(i + n - 1) % n], indices[i], indices[(i + 1) % n]);
+        let (pa, pb, pc) = (polygon[a], polygon[b], polygon[c]);
+        let is_ear = cross(pa, pb, pc) > 0.0
+            && indices.iter().all(|&j| {
+                j == a
+                    || j == b
+                    || j == c
+                    || cross(pa, pb, polygon[j]) < 0.0
+                    || cross(pb, pc, polygon[j]) < 0.0
+                    || cross(pc, pa, polygon[j]) < 0.0
+            });
+        if is_ear {
+            triangles.push([a, b, c]);
+            indices.remove(i);
+            attempts = 0;
+        } else {
+            i += 1;
+            attempts += 1;
+        }
+    }
+    if indices.len() == 3 {
+        triangles.push([indices[0], indices[1], indices[2]]);
+    }
+    triangles
+}
+
+/// Split a triangle given by latitude/longitude corners until no edge is longer than `spacing`
+/// meters, or the subdivision limit is reached.
+fn subdivide(
+    corners: [(f64, f64); 3],
+    spacing: f64,
+    depth: u32,
+    output: &mut Vec<[(f64, f64); 3]>,
+) {
+    let longest = (0..3)
+        .map(|i| (ellipsoid_point(corners[i]) - ellipsoid_point(corners[(i + 1) % 3])).magnitude())
+        .fold(0.0, f64::max);
+    if depth >= MAX_FILL_SUBDIVISIONS || longest <= spacing {
+        output.push(corners);
+        return;
+    }
+    let mid = |a: (f64, f64), b: (f64, f64)| ((a.0 + b.0) * 0.5, (a.1 + b.1) * 0.5);
+    let [a, b, c] = corners;
+    let (ab, bc, ca) = (mid(a, b), mid(b, c), mid(c, a));
+    for triangle in [[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]] {
+        subdivide(triangle, spacing, depth + 1, output);
+    }
+}
+
+/// Geometry of an overlay, tessellated and placed on the terrain.
+struct Tessellation<'a> {
+    origin: Vector3<f64>,
+    surface: &'a dyn Fn((f64, f64)) -> Vector3<f64>,
+    vertices: Vec<OverlayVertex>,
+}
+impl Tessellation<'_> {
+    fn push(&mut self, position: Vector3<f64>, color: [f32; 4]) {
+        self.vertices.push(OverlayVertex {
+            position: (position - self.origin).cast().unwrap().into(),
+            overlay: 0,
+            color,
+        });
+    }
+
+    /// Add a ribbon of the given width centered on a line, with mitered joints.
+    fn line(&mut self, points: &[(f64, f64)], closed: bool, width: f32, color: [f32; 4]) {
+        let mut points: Vec<Vector3<f64>> = points.iter().map(|&p| (self.surface)(p)).collect();
+        points.dedup_by(|a, b| (*a - *b).magnitude2() < 1e-6);
+        if closed && points.len() > 1 && (points[0] - points[points.len() - 1]).magnitude2() < 1e-6
+        {
+            points.pop();
+        }
+        if points.len() < 2 || width <= 0.0 {
+            return;
+        }
+
+        let n = points.len();
+        let segments = if closed { n } else { n - 1 };
+        let side = |i: usize| {
+            let (a, b) = (points[i % n], points[(i + 1) % n]);
+            (b - a).cross(a).normalize()
+        };
+        let half_width = width as f64 * 0.5;
+        let offsets: Vec<Vector3<f64>> = (0..n)
+            .map(|i| {
+                let previous = if i > 0 || closed { Some(side((i + n - 1) % n)) } else { None };
+                let next = if i < segments { Some(side(i)) } else { None };
+                match (previous, next) {
+                    (Some(p), Some(q)) => {
+                        let miter = (p + q).normalize();
+                        if miter.x.is_nan() {
+                            q * half_width
+                        } else {
+                            miter * (half_width / miter.dot(q).max(0.5))
+                        }
+                    }
+                    (Some(s), None) | (None, Some(s)) => s * half_width,
+                    (None, None) => unreachable!(),
+                }
+            })
+            .collect();
+
+        for i in 0..segments {
+            let j = (i + 1) % n;
+            let (a0, a1) = (points[i] - offsets[i], points[i] + offsets[i]);
+            let (b0, b1) = (points[j] - offsets[j], points[j] + offsets[j]);
+            for p in [a0, a1, b1, a0, b1, b0] {
+                self.push(p, color);
+            }
+        }
+    }
+
+    /// Fill the exterior ring of a polygon. Holes are not cut out of the fill.
+    fn fill(&mut self, ring: &[(f64, f64)], spacing: Option<f64>, color: [f32; 4]) {
+        let mut ring = ring.to_vec();
+        if ring.len() > 1 && ring[0] == ring[ring.len() - 1] {
+            ring.pop();
+        }
+        if ring.len() < 3 {
+            return;
+        }
+
+        // Triangulate in an equirectangular projection centered on the polygon.
+        let latitude = ring.iter().map(|p| p.0).sum::<f64>() / ring.len() as f64;
+        let scale = latitude.to_radians().cos();
+        let projected: Vec<Vector2<f64>> =
+            ring.iter().map(|&(lat, lon)| Vector2::new(lon * scale, lat)).collect();
+
+        let mut triangles = Vec::new();
+        for [a, b, c] in triangulate(&projected) {
+            let corners = [ring[a], ring[b], ring[c]];
+            match spacing {
+                Some(spacing) => subdivide(corners, spacing, 0, &mut triangles),
+                None => triangles.push(corners),
+            }
+        }
+        for triangle in triangles {
+            for corner in triangle {
+                let p = (self.surface)(corner);
+                self.push(p, color);
+            }
+        }
+    }
+}
+
+struct Overlay {
+    id: OverlayId,
+    features: Vec<Feature>,
+    drape: DrapeMode,
+    origin: Vector3<f64>,
+    vertices: Vec<OverlayVertex>,
+}
+impl Overlay {
+    fn depth_offset(&self) -> f32 {
+        match self.drape {
+            DrapeMode::DepthOffset(offset) => offset.clamp(0.0, 0.5),
+            DrapeMode::HeightConforming(_) => CONFORMING_DEPTH_OFFSET,
+        }
+    }
+
+    /// Tessellate the overlay again using the heights currently in the cache.
+    fn drape(&mut self, cache: &TileCache) {
+        let surface = |p: (f64, f64)| {
+            let p = ellipsoid_point(p);
+            cache.terrain_point(p, VNode::LEVEL_CELL_1M).unwrap_or(p)
+        };
+        let spacing = match self.drape {
+            DrapeMode::DepthOffset(_) => DEPTH_OFFSET_SPACING,
+            DrapeMode::HeightConforming(spacing) => spacing.max(1.0) as f64,
+        };
+        let fill_spacing = match self.drape {
+            DrapeMode::DepthOffset(_) => None,
+            DrapeMode::HeightConforming(_) => Some(spacing),
+        };
+
+        let origin = self
+            .features
+            .iter()
+            .flat_map(|f| f.lines.iter().chain(f.polygons.iter().flatten()))
+            .flatten()
+            .next()
+            .map(|&p| surface(p))
+            .unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0));
+        let mut tessellation = Tessellation { origin, surface: &surface, vertices: Vec::new() };
+
+        // Fills come first so that outlines are drawn over them.
+        for feature in &self.features {
+            if let Some(color) = feature.style.fill_color {
+                for polygon in &feature.polygons {
+                    tessellation.fill(&polygon[0], fill_spacing, color);
+                }
+            }
+        }
+        for feature in &self.features {
+            let (width, color) = (feature.style.stroke_width, feature.style.stroke_color);
+            for line in &feature.lines {
+                tessellation.line(&densify(line, spacing), false, width, color);
+            }
+            for ring in feature.polygons.iter().flatten() {
+                tessellation.line(&densify(ring, spacing), true, width, color);
+            }
+        }
+
+        self.origin = origin;
+        self.vertices = tessellation.vertices;
+    }
+}
+
+/// GeoJSON overlays draped over the terrain.
+pub(crate) struct Overlays {
+    overlays: Vec<Overlay>,
+    next_id: u64,
+    /// Whether the vertex buffer is out of date.
+    dirty: bool,
+    /// Heightmap generation and time that the overlays were last draped at.
+    last_drape: (u64, Instant),
+
+    vertices: Option<Tracked<wgpu::Buffer>>,
+    origins: Option<Tracked<wgpu::Buffer>>,
+    num_vertices: u32,
+
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    target_config: TargetConfig,
+}
+impl Overlays {
+    pub fn new() -> Self {
+        Self {
+            overlays: Vec::new(),
+            next_id: 0,
+            dirty: false,
+            last_drape: (0, Instant::now()),
+            vertices: None,
+            origins: None,
+            num_vertices: 0,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "overlay.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "overlay.frag"),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            target_config: TargetConfig::default(),
+        }
+    }
+
+    pub fn add_geojson(
+        &mut self,
+        geojson: &str,
+        options: &OverlayOptions,
+        cache: &TileCache,
+    ) -> Result<OverlayId, Error> {
+        if let DrapeMode::HeightConforming(spacing) = options.drape {
+            if spacing.is_nan() || spacing <= 0.0 {
+                return Err(crate::Error::tag(
+                    crate::Error::InvalidArgument,
+                    anyhow::format_err!("Overlay spacing must be positive"),
+                ));
+            }
+        }
+        let features = parse_geojson(geojson, &options.default_style)?;
+
+        let id = OverlayId(self.next_id);
+        self.next_id += 1;
+        let mut overlay = Overlay {
+            id,
+            features,
+            drape: options.drape,
+            origin: Vector3::new(0.0, 0.0, 0.0),
+            vertices: Vec::new(),
+        };
+        overlay.drape(cache);
+        self.overlays.push(overlay);
+        self.dirty = true;
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: OverlayId) -> bool {
+        let len = self.overlays.len();
+        self.overlays.retain(|overlay| overlay.id != id);
+        self.dirty |= self.overlays.len() != len;
+        self.overlays.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty |= !self.overlays.is_empty();
+        self.overlays.clear();
+    }
+
+    /// Drape the overlays again if more detailed heights have arrived, and upload their
+    /// positions relative to `camera`.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        cache: &TileCache,
+        camera: Vector3<f64>,
+        target_config: TargetConfig,
+    ) {
+        let generation = cache.heightmap_generation();
+        if generation != self.last_drape.0 && self.last_drape.1.elapsed() >= REDRAPE_INTERVAL {
+            for overlay in &mut self.overlays {
+                overlay.drape(cache);
+            }
+            self.last_drape = (generation, Instant::now());
+            self.dirty |= !self.overlays.is_empty();
+        }
+
+        if self.dirty {
+            let vertices: Vec<OverlayVertex> = self
+                .overlays
+                .iter()
+                .enumerate()
+                .flat_map(|(i, overlay)| {
+                    overlay.vertices.iter().map(move |v| OverlayVertex { overlay: i as u32, ..*v })
+                })
+                .collect();
+            self.num_vertices = vertices.len() as u32;
+            let bytes = bytemuck::cast_slice(&vertices);
+            if self.vertices.as_ref().map(|b| b.size() < bytes.len() as u64).unwrap_or(true) {
+                self.vertices = Some(create_buffer(
+                    device,
+                    gpu_state,
+                    (bytes.len() as u64).next_power_of_two().max(1024),
+                    "overlay.vertices",
+                ));
+                self.bindgroup_pipeline = None;
+            }
+            if !bytes.is_empty() {
+                queue.write_buffer(self.vertices.as_ref().unwrap(), 0, bytes);
+            }
+            self.dirty = false;
+        }
+
+        let origins: Vec<[f32; 4]> = self
+            .overlays
+            .iter()
+            .map(|overlay| {
+                let origin: [f32; 3] = (overlay.origin - camera).cast().unwrap().into();
+                [origin[0], origin[1], origin[2], overlay.depth_offset()]
+            })
+            .collect();
+        let bytes = bytemuck::cast_slice(&origins);
+        if self.origins.as_ref().map(|b| b.size() < bytes.len() as u64).unwrap_or(true) {
+            self.origins = Some(create_buffer(
+                device,
+                gpu_state,
+                (bytes.len() as u64).next_power_of_two().max(256),
+                "overlay.origins",
+            ));
+            self.bindgroup_pipeline = None;
+        }
+        if !bytes.is_empty() {
+            queue.write_buffer(self.origins.as_ref().unwrap(), 0, bytes);
+        }
+
+        if self.shader.refresh() || self.target_config != target_config {
+            self.bindgroup_pipeline = None;
+            self.target_config = target_config;
+        }
+        if self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                hashmap![
+                    "overlay_vertices".into() => (false, binding(&self.vertices)),
+                    "overlay_origins".into() => (false, binding(&self.origins)),
+                ],
+                HashMap::new(),
+                "overlay",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.overlay.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.overlay.vertex"),
+                            source: self.shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.overlay.fragment"),
+                            source: self.shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            write_mask: wgpu::ColorWrites::COLOR,
+                        })],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_compare: target_config.depth_compare(true),
+                        depth_write_enabled: false,
+                        bias: Default::default(),
+                        stencil: Default::default(),
+                    }),
+                    multisample: target_config.multisample(),
+                    multiview: None,
+                    label: Some("pipeline.overlay"),
+                }),
+            ));
+        }
+    }
+
+    /// Draw the overlays, which must come after the terrain in the pass.
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        if let Some((ref bind_group, ref pipeline)) = self.bindgroup_pipeline {
+            if self.num_vertices > 0 {
+                rpass.set_pipeline(pipeline);
+                rpass.set_bind_group(0, bind_group, &[]);
+                rpass.draw(0..self.num_vertices, 0..1);
+            }
+        }
+    }
+}
+
+fn binding(buffer: &Option<Tracked<wgpu::Buffer>>) -> wgpu::BindingResource {
+    wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+        buffer: buffer.as_ref().unwrap(),
+        offset: 0,
+        size: None,
+    })
+}
+
+fn create_buffer(
+    device: &wgpu::Device,
+    gpu_state: &GpuState,
+    size: u64,
+    category: &str,
+) -> Tracked<wgpu::Buffer> {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        size,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+        label: Some(&format!("buffer.{}", category)),
+    });
+    let token = gpu_state.resources.track(ResourceKind::Buffer, category, size);
+    Tracked::new(buffer, token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_styled_features() {
+        let features = parse_geojson(
+            r##"{
+                "type": "FeatureCollection",
+                "features": [
+                    {
+                        "type": "Feature",
+                        "properties": { "stroke": "#f00", "stroke-width": 4, "fill": "#ffffff" },
+                        "geometry": {
+                            "type": "Polygon",
+                            "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]]
+                        }
+                    },
+                    {
+                        "type": "Feature",
+                        "properties": null,
+                        "geometry": { "type": "LineString", "coordinates": [[0, 0], [2, 3]] }
+                    }
+                ]
+            }"##,
+            &OverlayStyle::default(),
+        )
+        .unwrap();
+
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].style.stroke_color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(features[0].style.stroke_width, 4.0);
+        assert_eq!(features[0].style.fill_color, Some([1.0, 1.0, 1.0, 0.6]));
+        assert_eq!(features[0].polygons[0][0].len(), 5);
+        assert_eq!(features[1].style, OverlayStyle::default());
+        assert_eq!(features[1].lines, vec![vec![(0.0, 0.0), (3.0, 2.0)]]);
+
+        assert!(parse_geojson(r#"{"type": "Circle"}"#, &OverlayStyle::default()).is_err());
+    }
+
+    #[test]
+    fn triangulates_concave_polygons() {
+        // An L shape, given clockwise.
+        let polygon: Vec<_> =
+            [(0.0, 0.0), (0.0, 2.0), (1.0, 2.0), (1.0, 1.0), (2.0, 1.0), (2.0, 0.0)]
+                .iter()
+                .map(|&(x, y)| Vector2::new(x, y))
+                .collect();
+        let triangles = triangulate(&polygon);
+        assert_eq!(triangles.len(), 4);
+
+        let area: f64 = triangles
+            .iter()
+            .map(|&[a, b, c]| {
+                let (a, b, c) = (polygon[a], polygon[b], polygon[c]);
+                ((b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)) * 0.5
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 1e-9);
+    }
+}
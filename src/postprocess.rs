@@ -0,0 +1,827 @@
+use crate::compute_shader::ComputeShader;
+use crate::gpu_state::{map_buffer, GlobalUniformBlock, GpuState};
+use crate::resources::{texture_bytes, ResourceKind, Tracked};
+use anyhow::Error;
+use instant::Instant;
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+/// Format of the intermediate target that the scene is rendered into before tonemapping.
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Properties of the color and depth attachments that the scene is rendered into, which all
+/// pipelines drawing into them must be built for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TargetConfig {
+    pub sample_count: u32,
+    /// Whether the near plane is at a depth of one and the far plane at zero.
+    pub reverse_z: bool,
+}
+impl Default for TargetConfig {
+    fn default() -> Self {
+        Self { sample_count: 1, reverse_z: true }
+    }
+}
+impl TargetConfig {
+    pub fn multisample(&self) -> wgpu::MultisampleState {
+        wgpu::MultisampleState { count: self.sample_count, ..Default::default() }
+    }
+
+    /// Comparison that passes fragments closer to the camera than the stored depth, and also
+    /// ones at the same depth if `inclusive` is set.
+    pub fn depth_compare(&self, inclusive: bool) -> wgpu::CompareFunction {
+        match (self.reverse_z, inclusive) {
+            (true, false) => wgpu::CompareFunction::Greater,
+            (true, true) => wgpu::CompareFunction::GreaterEqual,
+            (false, false) => wgpu::CompareFunction::Less,
+            (false, true) => wgpu::CompareFunction::LessEqual,
+        }
+    }
+
+    pub fn near_depth(&self) -> f32 {
+        if self.reverse_z {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn far_depth(&self) -> f32 {
+        1.0 - self.near_depth()
+    }
+}
+
+/// Number of bins in the luminance histogram used for auto exposure.
+pub(crate) const HISTOGRAM_BINS: usize = 256;
+
+/// Exposure value used unless another is requested, which suits sunlit scenes.
+const DEFAULT_EV100: f32 = 17.0;
+
+/// Operator used to map the high dynamic range scene to colors that can be displayed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Tonemapper {
+    /// Filmic curve fitted to the ACES reference rendering transform. Highlights such as sunlit
+    /// snow roll off smoothly to white.
+    #[default]
+    Aces = 0,
+    /// Reinhard operator applied to luminance, which preserves the hue of bright colors but
+    /// leaves the image looking flatter.
+    Reinhard = 1,
+    /// Filmic curve from Uncharted 2, which was used before terrain was rendered in HDR.
+    Uncharted2 = 2,
+}
+
+/// How the brightness of the scene is mapped to the display.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Exposure {
+    /// Fixed exposure value at ISO 100. Sunlit scenes are around 15 to 17, while moonlit ones
+    /// are around -2.
+    Manual { ev100: f32 },
+    /// Meter the average luminance of the scene with a histogram and gradually adapt to it.
+    Auto {
+        /// Stops to brighten (positive) or darken (negative) the image relative to the metered
+        /// exposure.
+        compensation: f32,
+        /// Lowest exposure value to adapt to, which limits how far dark scenes are brightened.
+        min_ev100: f32,
+        /// Highest exposure value to adapt to.
+        max_ev100: f32,
+        /// How quickly the exposure adapts to changes in luminance, in 1/seconds.
+        speed: f32,
+    },
+}
+impl Exposure {
+    /// Automatic exposure with reasonable limits and adaptation speed.
+    pub fn auto() -> Self {
+        Exposure::Auto { compensation: 0.0, min_ev100: -4.0, max_ev100: 18.0, speed: 1.5 }
+    }
+}
+impl Default for Exposure {
+    fn default() -> Self {
+        Exposure::Manual { ev100: DEFAULT_EV100 }
+    }
+}
+
+/// Convert an exposure value to the factor that scene radiance is scaled by.
+fn ev100_to_exposure(ev100: f32) -> f32 {
+    1.0 / (1.2 * f32::powf(2.0, ev100))
+}
+
+/// Current exposure, stored on the GPU so that auto exposure can update it without a readback.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct ExposureState {
+    pub exposure: f32,
+    pub ev100: f32,
+}
+unsafe impl bytemuck::Pod for ExposureState {}
+unsafe impl bytemuck::Zeroable for ExposureState {}
+impl Default for ExposureState {
+    fn default() -> Self {
+        Self { exposure: ev100_to_exposure(DEFAULT_EV100), ev100: DEFAULT_EV100 }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct AutoExposureUniforms {
+    compensation: f32,
+    adaptation: f32,
+    min_ev100: f32,
+    max_ev100: f32,
+    num_pixels: u32,
+}
+unsafe impl bytemuck::Pod for AutoExposureUniforms {}
+unsafe impl bytemuck::Zeroable for AutoExposureUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct TonemapUniforms {
+    tonemapper: u32,
+    _padding: [u32; 3],
+}
+unsafe impl bytemuck::Pod for TonemapUniforms {}
+unsafe impl bytemuck::Zeroable for TonemapUniforms {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct LightShaftUniforms {
+    /// Position of the sun in texture coordinates, which may be off screen.
+    sun_position: [f32; 2],
+    intensity: f32,
+    _padding: f32,
+}
+unsafe impl bytemuck::Pod for LightShaftUniforms {}
+unsafe impl bytemuck::Zeroable for LightShaftUniforms {}
+
+struct HdrTarget {
+    size: (u32, u32),
+    sample_count: u32,
+    /// Single sampled target that is metered and tonemapped.
+    view: wgpu::TextureView,
+    _texture: Tracked<wgpu::Texture>,
+    /// Multisampled target that the scene is rendered into and then resolved to `view`, if
+    /// MSAA is enabled.
+    multisampled: Option<(wgpu::TextureView, Tracked<wgpu::Texture>)>,
+    /// Half resolution target holding the light shafts, which are added during tonemapping.
+    light_shafts: (wgpu::TextureView, Tracked<wgpu::Texture>),
+    /// Correction from the sky reflected off water to what the screen space reflection pass
+    /// found along the reflected rays, which is also added during tonemapping.
+    reflections: (wgpu::TextureView, Tracked<wgpu::Texture>),
+}
+
+/// Renders the scene into an HDR target, then meters and tonemaps it into the caller's color
+/// buffer.
+///
+/// All shaders output radiance scaled by `globals.exposure`, which keeps values within the range
+/// of half floats. The exposure lives in a GPU buffer that is copied into the globals before
+/// each pass, so that auto exposure never has to wait on a readback.
+pub(crate) struct PostProcess {
+    target: Option<HdrTarget>,
+    histogram_shader: rshader::ShaderSet,
+    histogram_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::ComputePipeline)>,
+    auto_exposure: ComputeShader<AutoExposureUniforms>,
+    tonemap_shader: rshader::ShaderSet,
+    tonemap_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    tonemap_uniforms: Option<wgpu::Buffer>,
+    light_shafts_shader: rshader::ShaderSet,
+    light_shafts_bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    light_shafts_uniforms: Option<wgpu::Buffer>,
+    light_shafts: LightShaftUniforms,
+    reflections_shader: rshader::ShaderSet,
+    /// Format of the color buffer that the tonemap pipeline was built for.
+    output_format: wgpu::TextureFormat,
+    tonemapper: Tonemapper,
+    exposure: Exposure,
+    last_frame: Option<Instant>,
+}
+impl PostProcess {
+    pub fn new() -> Self {
+        Self {
+            target: None,
+            histogram_shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "luminance-histogram.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            histogram_bindgroup_pipeline: None,
+            auto_exposure: ComputeShader::new(
+                rshader::shader_source!("shaders", "auto-exposure.comp", "declarations.glsl"),
+                "auto-exposure".to_string(),
+            ),
+            tonemap_shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "fullscreen.vert"),
+                rshader::shader_source!(
+                    "shaders",
+                    "tonemap.frag",
+                    "declarations.glsl",
+                    "hash.glsl"
+                ),
+            )
+            .unwrap(),
+            tonemap_bindgroup_pipeline: None,
+            tonemap_uniforms: None,
+            light_shafts_shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "fullscreen.vert"),
+                rshader::shader_source!("shaders", "light-shafts.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            light_shafts_bindgroup_pipeline: None,
+            light_shafts_uniforms: None,
+            light_shafts: LightShaftUniforms::default(),
+            reflections_shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "screen-space-reflections.comp",
+                "declarations.glsl",
+                "atmosphere.glsl"
+            ))
+            .unwrap(),
+            output_format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            tonemapper: Tonemapper::default(),
+            exposure: Exposure::default(),
+            last_frame: None,
+        }
+    }
+
+    pub fn set_tonemapper(&mut self, tonemapper: Tonemapper) {
+        self.tonemapper = tonemapper;
+    }
+
+    pub fn set_exposure(&mut self, exposure: Exposure) {
+        self.exposure = exposure;
+    }
+
+    pub fn tonemapper(&self) -> Tonemapper {
+        self.tonemapper
+    }
+
+    pub fn exposure(&self) -> Exposure {
+        self.exposure
+    }
+
+    /// Set where the sun is in texture coordinates for the next frame, and how bright the light
+    /// shafts radiating from it should be. An intensity of zero skips the light shaft pass.
+    pub fn set_light_shafts(&mut self, sun_position: [f32; 2], intensity: f32) {
+        self.light_shafts = LightShaftUniforms { sun_position, intensity, _padding: 0.0 };
+    }
+
+    /// Attachment that the scene should be rendered into. Only valid after `prepare`.
+    /// Attachment for drawing the scene into the HDR target. `clear` clears it at the start of
+    /// the pass, while `last` marks the final pass of the frame, which resolves the multisampled
+    /// target if there is one.
+    pub fn color_attachment(&self, clear: bool, last: bool) -> wgpu::RenderPassColorAttachment {
+        let target = self.target.as_ref().unwrap();
+        let load = if clear { wgpu::LoadOp::Clear(Default::default()) } else { wgpu::LoadOp::Load };
+        match target.multisampled {
+            Some((ref view, _)) => wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: last.then_some(&target.view),
+                // Only the resolved target is needed after the last pass.
+                ops: wgpu::Operations { load, store: !last },
+            },
+            None => wgpu::RenderPassColorAttachment {
+                view: &target.view,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: true },
+            },
+        }
+    }
+
+    /// View that the scene is drawn into, which is multisampled if MSAA is enabled.
+    pub fn render_view(&self) -> &wgpu::TextureView {
+        let target = self.target.as_ref().unwrap();
+        match target.multisampled {
+            Some((ref view, _)) => view,
+            None => &target.view,
+        }
+    }
+
+    fn resolved_view(&self) -> &wgpu::TextureView {
+        &self.target.as_ref().unwrap().view
+    }
+
+    /// Resize the HDR target to match the frame and rebuild any pipelines that depend on it or
+    /// on the format of the color buffer that will be tonemapped into.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        frame_size: (u32, u32),
+        sample_count: u32,
+        output_format: wgpu::TextureFormat,
+    ) {
+        if self.output_format != output_format {
+            self.output_format = output_format;
+            self.tonemap_bindgroup_pipeline = None;
+        }
+
+        if self.target.as_ref().map(|t| (t.size, t.sample_count))
+            != Some((frame_size, sample_count))
+        {
+            let create_texture =
+                |size: (u32, u32), sample_count: u32, usage: wgpu::TextureUsages, label: &str| {
+                    let texture = device.create_texture(&wgpu::TextureDescriptor {
+                        size: wgpu::Extent3d {
+                            width: size.0,
+                            height: size.1,
+                            depth_or_array_layers: 1,
+                        },
+                        format: HDR_FORMAT,
+                        mip_level_count: 1,
+                        sample_count,
+                        dimension: wgpu::TextureDimension::D2,
+                        usage,
+                        label: Some(label),
+                        view_formats: &[],
+                    });
+                    let token = gpu_state.resources.track(
+                        ResourceKind::Texture,
+                        "hdr",
+                        texture_bytes(&texture) * sample_count as u64,
+                    );
+                    (texture.create_view(&Default::default()), Tracked::new(texture, token))
+                };
+
+            let (view, texture) = create_texture(
+                frame_size,
+                1,
+                wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                "texture.hdr_color",
+            );
+            self.target = Some(HdrTarget {
+                size: frame_size,
+                sample_count,
+                view,
+                _texture: texture,
+                multisampled: (sample_count > 1).then(|| {
+                    create_texture(
+                        frame_size,
+                        sample_count,
+                        wgpu::TextureUsages::RENDER_ATTACHMENT,
+                        "texture.hdr_color_multisampled",
+                    )
+                }),
+                light_shafts: create_texture(
+                    ((frame_size.0 + 1) / 2, (frame_size.1 + 1) / 2),
+                    1,
+                    wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+                    "texture.light_shafts",
+                ),
+                reflections: create_texture(
+                    frame_size,
+                    1,
+                    wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::STORAGE_BINDING,
+                    "texture.reflections",
+                ),
+            });
+            self.histogram_bindgroup_pipeline = None;
+            self.light_shafts_bindgroup_pipeline = None;
+            self.tonemap_bindgroup_pipeline = None;
+        }
+
+        if self.histogram_shader.refresh() {
+            self.histogram_bindgroup_pipeline = None;
+        }
+        if self.histogram_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.histogram_shader,
+                HashMap::new(),
+                hashmap!["hdr_color".into() => self.resolved_view()],
+                "luminance-histogram",
+            );
+            self.histogram_bindgroup_pipeline = Some((
+                bind_group,
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: [&bind_group_layout][..].into(),
+                        push_constant_ranges: &[],
+                        label: Some("pipeline.luminance-histogram.layout"),
+                    })),
+                    module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.luminance-histogram"),
+                        source: self.histogram_shader.compute(),
+                    }),
+                    entry_point: "main",
+                    label: Some("pipeline.luminance-histogram"),
+                }),
+            ));
+        }
+
+        self.auto_exposure.refresh(device, gpu_state);
+
+        if self.light_shafts_uniforms.is_none() {
+            self.light_shafts_uniforms = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<LightShaftUniforms>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                mapped_at_creation: false,
+                label: Some("buffer.light_shafts.uniforms"),
+            }));
+        }
+        if self.light_shafts_shader.refresh() {
+            self.light_shafts_bindgroup_pipeline = None;
+        }
+        if self.light_shafts_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.light_shafts_shader,
+                hashmap!["light_shafts".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: self.light_shafts_uniforms.as_ref().unwrap(),
+                    offset: 0,
+                    size: None,
+                }))],
+                hashmap!["hdr_color".into() => self.resolved_view()],
+                "light-shafts",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.light-shafts.layout"),
+                });
+            self.light_shafts_bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.light-shafts.vertex"),
+                            source: self.light_shafts_shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.light-shafts.fragment"),
+                            source: self.light_shafts_shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: None,
+                    multisample: Default::default(),
+                    multiview: None,
+                    label: Some("pipeline.light-shafts"),
+                }),
+            ));
+        }
+        queue.write_buffer(
+            self.light_shafts_uniforms.as_ref().unwrap(),
+            0,
+            bytemuck::bytes_of(&self.light_shafts),
+        );
+
+        if self.tonemap_uniforms.is_none() {
+            self.tonemap_uniforms = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<TonemapUniforms>() as u64,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+                mapped_at_creation: false,
+                label: Some("buffer.tonemap.uniforms"),
+            }));
+        }
+        if self.tonemap_shader.refresh() {
+            self.tonemap_bindgroup_pipeline = None;
+        }
+        if self.tonemap_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.tonemap_shader,
+                hashmap!["tonemapper".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: self.tonemap_uniforms.as_ref().unwrap(),
+                    offset: 0,
+                    size: None,
+                }))],
+                hashmap![
+                    "hdr_color".into() => self.resolved_view(),
+                    "light_shafts".into() => &self.target.as_ref().unwrap().light_shafts.0,
+                    "reflections".into() => &self.target.as_ref().unwrap().reflections.0,
+                ],
+                "tonemap",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.tonemap.layout"),
+                });
+            self.tonemap_bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tonemap.vertex"),
+                            source: self.tonemap_shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tonemap.fragment"),
+                            source: self.tonemap_shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: self.output_format,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: None,
+                    multisample: Default::default(),
+                    multiview: None,
+                    label: Some("pipeline.tonemap"),
+                }),
+            ));
+        }
+
+        queue.write_buffer(
+            self.tonemap_uniforms.as_ref().unwrap(),
+            0,
+            bytemuck::bytes_of(&TonemapUniforms {
+                tonemapper: self.tonemapper as u32,
+                _padding: [0; 3],
+            }),
+        );
+        if let Exposure::Manual { ev100 } = self.exposure {
+            queue.write_buffer(
+                &gpu_state.exposure_state,
+                0,
+                bytemuck::bytes_of(&ExposureState { exposure: ev100_to_exposure(ev100), ev100 }),
+            );
+        }
+    }
+
+    /// Copy the current exposure into the globals. Must be recorded after the globals are
+    /// written and before anything is rendered with them.
+    pub fn apply_exposure(&self, encoder: &mut wgpu::CommandEncoder, gpu_state: &GpuState) {
+        let globals = <GlobalUniformBlock as bytemuck::Zeroable>::zeroed();
+        encoder.copy_buffer_to_buffer(
+            &gpu_state.exposure_state,
+            0,
+            &gpu_state.globals,
+            bytemuck::offset_of!(globals, GlobalUniformBlock, exposure) as u64,
+            std::mem::size_of::<f32>() as u64,
+        );
+    }
+
+    /// Meter the HDR target to update the exposure for the next frame, render light shafts and
+    /// reflections from it, then tonemap all three into `color_buffer`. Screen space reflections
+    /// are only traced if given the single sampled `depth_buffer` that the scene was drawn with;
+    /// otherwise water only reflects the sky.
+    pub fn run(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: Option<&wgpu::TextureView>,
+    ) {
+        let now = Instant::now();
+        let elapsed = self.last_frame.map(|t| (now - t).as_secs_f32()).unwrap_or(0.0);
+        self.last_frame = Some(now);
+
+        if let Exposure::Auto { compensation, min_ev100, max_ev100, speed } = self.exposure {
+            let size = self.target.as_ref().unwrap().size;
+            {
+                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("computepass.luminance-histogram"),
+                });
+                cpass.set_pipeline(&self.histogram_bindgroup_pipeline.as_ref().unwrap().1);
+                cpass.set_bind_group(
+                    0,
+                    &self.histogram_bindgroup_pipeline.as_ref().unwrap().0,
+                    &[],
+                );
+                cpass.dispatch_workgroups((size.0 + 15) / 16, (size.1 + 15) / 16, 1);
+            }
+            self.auto_exposure.run(
+                device,
+                encoder,
+                gpu_state,
+                (1, 1, 1),
+                &AutoExposureUniforms {
+                    compensation,
+                    adaptation: 1.0 - f32::exp(-elapsed * speed),
+                    min_ev100,
+                    max_ev100,
+                    num_pixels: size.0 * size.1,
+                },
+            );
+        }
+
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.target.as_ref().unwrap().light_shafts.0,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                label: Some("renderpass.light-shafts"),
+            });
+            if self.light_shafts.intensity > 0.0 {
+                let (bind_group, pipeline) = self.light_shafts_bindgroup_pipeline.as_ref().unwrap();
+                rpass.set_pipeline(pipeline);
+                rpass.set_bind_group(0, bind_group, &[]);
+                rpass.draw(0..3, 0..1);
+            }
+        }
+
+        let target = self.target.as_ref().unwrap();
+        match depth_buffer {
+            Some(depth_buffer) => {
+                if self.reflections_shader.refresh() {
+                    gpu_state.objects.invalidate("screen-space-reflections");
+                }
+                // The depth buffer may be a different one every frame, so the bind group is looked
+                // up by the views being bound like for the depth pyramid.
+                let (bind_group, pipeline) = gpu_state.compute_bind_group_pipeline(
+                    device,
+                    &self.reflections_shader,
+                    HashMap::new(),
+                    hashmap![
+                        "hdr_color".into() => &target.view,
+                        "scene_depth".into() => depth_buffer,
+                        "reflections".into() => &target.reflections.0,
+                    ],
+                    "screen-space-reflections",
+                );
+                let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("computepass.screen-space-reflections"),
+                });
+                cpass.set_pipeline(&pipeline);
+                cpass.set_bind_group(0, &bind_group, &[]);
+                cpass.dispatch_workgroups((target.size.0 + 7) / 8, (target.size.1 + 7) / 8, 1);
+            }
+            None => {
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &target.reflections.0,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    label: Some("renderpass.clear-reflections"),
+                });
+            }
+        }
+
+        self.tonemap(encoder, color_buffer);
+    }
+
+    fn tonemap(&self, encoder: &mut wgpu::CommandEncoder, color_buffer: &wgpu::TextureView) {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color_buffer,
+                resolve_target: None,
+                ops: wgpu::Operations::default(),
+            })],
+            depth_stencil_attachment: None,
+            label: Some("renderpass.tonemap"),
+        });
+        rpass.set_pipeline(&self.tonemap_bindgroup_pipeline.as_ref().unwrap().1);
+        rpass.set_bind_group(0, &self.tonemap_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    /// Tonemap the HDR target left by the last frame again, this time into a texture that is
+    /// read back to the CPU. Blocks until the readback completes.
+    pub async fn capture(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<image::RgbaImage, Error> {
+        let (width, height) = match self.target {
+            Some(ref target) => target.size,
+            None => {
+                return Err(crate::Error::tag(
+                    crate::Error::InvalidArgument,
+                    anyhow::format_err!("No frame has been rendered yet"),
+                ))
+            }
+        };
+        let (bgra, srgb) = match self.output_format {
+            wgpu::TextureFormat::Rgba8UnormSrgb => (false, true),
+            wgpu::TextureFormat::Bgra8UnormSrgb => (true, true),
+            wgpu::TextureFormat::Rgba8Unorm => (false, false),
+            wgpu::TextureFormat::Bgra8Unorm => (true, false),
+            format => {
+                return Err(crate::Error::tag(
+                    crate::Error::InvalidArgument,
+                    anyhow::format_err!("Can't capture frames in {:?} format", format),
+                ))
+            }
+        };
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            format: self.output_format,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            label: Some("texture.capture"),
+            view_formats: &[],
+        });
+
+        let row_bytes = width as usize * 4;
+        let row_pitch = (row_bytes + 255) & !255;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (row_pitch * height as usize) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            label: Some("buffer.capture"),
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.capture"),
+        });
+        self.tonemap(&mut encoder, &texture.create_view(&Default::default()));
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(NonZeroU32::new(row_pitch as u32).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        );
+        queue.submit(Some(encoder.finish()));
+        map_buffer(device, &buffer).await?;
+
+        let mut data = Vec::with_capacity(row_bytes * height as usize);
+        for row in buffer.slice(..).get_mapped_range().chunks_exact(row_pitch) {
+            data.extend_from_slice(&row[..row_bytes]);
+        }
+        buffer.unmap();
+
+        for pixel in data.chunks_exact_mut(4) {
+            if bgra {
+                pixel.swap(0, 2);
+            }
+            // Tonemapped colors are linear, and only get encoded when written to sRGB targets.
+            if !srgb {
+                for c in &mut pixel[..3] {
+                    *c = linear_to_srgb(*c);
+                }
+            }
+            pixel[3] = 255;
+        }
+        Ok(image::RgbaImage::from_raw(width, height, data).unwrap())
+    }
+}
+
+/// Encode an 8-bit linear color channel with the sRGB transfer function.
+fn linear_to_srgb(value: u8) -> u8 {
+    let v = value as f32 / 255.0;
+    let encoded = if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+    (encoded * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_exposure_matches_sunlit_scenes() {
+        let state = ExposureState::default();
+        assert_eq!(state.ev100, 17.0);
+        assert!((state.exposure - 1.0 / (f32::powf(2.0, 17.0) * 1.2)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn linear_to_srgb_endpoints() {
+        assert_eq!(linear_to_srgb(0), 0);
+        assert_eq!(linear_to_srgb(255), 255);
+        // Half intensity in linear space is about three quarters once encoded.
+        assert_eq!(linear_to_srgb(128), 188);
+    }
+}
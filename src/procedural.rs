@@ -0,0 +1,343 @@
+//! Planets generated entirely from a seed, for worlds that need no downloaded datasets.
+//!
+//! Every streamed layer of a tile is computed on the CPU from noise evaluated on the unit sphere,
+//! so neighboring tiles and tiles at different levels agree wherever they overlap. Continents come
+//! from low frequency fractal noise, mountains from ridged noise, and rivers are carved along the
+//! zero crossings of another noise field, which form long connected channels. Climate follows
+//! latitude, elevation and a moisture field, and picks the landcover class, tree cover and albedo.
+//!
+//! Generation only uses integer hashing along with addition, multiplication, division, square
+//! roots and rounding, all of which IEEE 754 defines exactly, so a seed produces bit-identical
+//! tiles on every run and every platform.
+
+use crate::cache::layer::LayerType;
+use cgmath::{InnerSpace, Vector3};
+use terra_types::VNode;
+use vec_map::VecMap;
+
+/// Settings for a procedurally generated planet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProceduralPlanet {
+    /// Seed that everything about the planet is derived from.
+    pub seed: u64,
+    /// Approximate fraction of the surface covered by oceans.
+    pub ocean_fraction: f64,
+    /// Height in meters of the tallest mountains.
+    pub max_elevation: f64,
+    /// How many rivers cross the land, from zero for none to one for a dense network.
+    pub river_density: f64,
+}
+impl ProceduralPlanet {
+    /// Earth-like planet generated from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self { seed, ocean_fraction: 0.65, max_elevation: 6000.0, river_density: 0.5 }
+    }
+
+    /// Height of the continent noise below which the surface is ocean. The noise is roughly
+    /// normally distributed around zero, so this is only approximately `ocean_fraction`.
+    fn sea_threshold(&self) -> f64 {
+        (self.ocean_fraction.clamp(0.0, 1.0) - 0.5) * 0.45
+    }
+
+    /// Properties of the surface in `direction`, a unit vector from the center of the planet.
+    /// Fractal detail is added up to `detail_octaves` octaves past the coarsest one.
+    fn surface(&self, direction: Vector3<f64>, detail_octaves: u32) -> Surface {
+        let seed = mix(self.seed);
+        let continents = fbm(seed, direction * 1.5, 5) - self.sea_threshold();
+        if continents < 0.0 {
+            return Surface::ocean(continents * 8000.0);
+        }
+
+        let mountain_mask = smoothstep(0.02, 0.25, continents);
+        let ridges = ridged(seed.wrapping_add(1), direction * 6.0, 6);
+        let hills = fbm(seed.wrapping_add(2), direction * 24.0, detail_octaves);
+        let mut elevation = continents * 1500.0
+            + mountain_mask * ridges * self.max_elevation
+            + hills * 200.0 * (0.3 + mountain_mask);
+
+        // Rivers run along the zero crossings of a noise field, in valleys carved out of the
+        // surrounding terrain. They fade out high up in the mountains.
+        let mut water_level = None;
+        let width = 0.02
+            * self.river_density.clamp(0.0, 1.0)
+            * (1.0 - smoothstep(0.3, 0.6, elevation / self.max_elevation));
+        if width > 0.0 {
+            let river = fbm(seed.wrapping_add(3), direction * 12.0, 4).abs();
+            let bed = elevation * 0.15;
+            elevation = bed + (elevation - bed) * smoothstep(0.0, 4.0 * width, river);
+            if river < width {
+                water_level = Some(bed + 2.0);
+            }
+        }
+
+        let latitude_cos2 = 1.0 - direction.z * direction.z;
+        let temperature = latitude_cos2 - elevation.max(0.0) / (self.max_elevation * 1.2);
+        let moisture = (0.5 + 0.9 * fbm(seed.wrapping_add(4), direction * 3.0, 4)).clamp(0.0, 1.0);
+        let (landcover, treecover) = if water_level.is_some() {
+            (80, 0)
+        } else if temperature < 0.15 {
+            (70, 0)
+        } else if moisture < 0.25 {
+            (60, 0)
+        } else if temperature < 0.35 {
+            if moisture > 0.5 {
+                (111, 40 + (moisture * 40.0) as u8)
+            } else {
+                (100, 0)
+            }
+        } else if moisture < 0.35 {
+            (20, 10)
+        } else if moisture < 0.45 {
+            (30, 0)
+        } else if temperature > 0.8 {
+            (112, 50 + (moisture * 50.0) as u8)
+        } else if moisture > 0.7 {
+            (115, 40 + (moisture * 60.0) as u8)
+        } else {
+            (114, 30 + (moisture * 60.0) as u8)
+        };
+
+        Surface { height: elevation, water_level, landcover, treecover }
+    }
+
+    /// Encoded base heightmap of `node`, laid out like a streamed tile.
+    pub(crate) fn heightmap(&self, node: VNode) -> Vec<u16> {
+        self.heights_and_water(node).0
+    }
+
+    /// Encoded heights and water levels of `node`. Like in generated tiles, water levels are
+    /// folded into the heights for coarse tiles, and only kept separately for detailed ones.
+    fn heights_and_water(&self, node: VNode) -> (Vec<u16>, Option<Vec<u16>>) {
+        let resolution = LayerType::BaseHeightmaps.texture_resolution();
+        let border = LayerType::BaseHeightmaps.texture_border_size();
+        let detail_octaves = detail_octaves(node.level());
+
+        let mut heights = Vec::with_capacity((resolution * resolution) as usize);
+        let mut water = Vec::with_capacity((resolution * resolution) as usize);
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let cspace = node.grid_position_cspace(x as i32, y as i32, border, resolution);
+                let surface = self.surface(cspace.normalize(), detail_octaves);
+                heights.push(encode_height(surface.height));
+                water.push(surface.water_level.map(encode_height).unwrap_or(0));
+            }
+        }
+
+        if node.level() < VNode::LEVEL_CELL_76M {
+            for (h, w) in heights.iter_mut().zip(&water) {
+                *h = (*h).max(*w);
+            }
+            (heights, None)
+        } else if water.iter().all(|&w| w == 0) {
+            (heights, None)
+        } else {
+            (heights, Some(water))
+        }
+    }
+
+    /// Contents of every streamed layer of `node`, in the same form as decoded tiles.
+    pub(crate) fn generate_tile(&self, node: VNode) -> VecMap<Vec<u8>> {
+        let mut layers = VecMap::new();
+        let (heights, water) = self.heights_and_water(node);
+        layers.insert(LayerType::BaseHeightmaps.index(), bytemuck::cast_slice(&heights).to_vec());
+        if let Some(water) = water {
+            layers.insert(LayerType::WaterLevel.index(), bytemuck::cast_slice(&water).to_vec());
+        }
+
+        // Climate only depends on coarse features, so these layers skip the fractal detail.
+        let resolution = LayerType::LandFraction.texture_resolution();
+        let border = LayerType::LandFraction.texture_border_size();
+        let with_albedo = node.level() < LayerType::BaseAlbedo.streamed_levels();
+        let samples = (resolution * resolution) as usize;
+        let mut land_fraction = Vec::with_capacity(samples);
+        let mut landcover = Vec::with_capacity(samples);
+        let mut treecover = Vec::with_capacity(samples);
+        let mut albedo = Vec::with_capacity(if with_albedo { samples * 4 } else { 0 });
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let cspace = node.cell_position_cspace(x as i32, y as i32, border, resolution);
+                let surface = self.surface(cspace.normalize(), 0);
+                land_fraction.push(if surface.is_water() { 0 } else { 255 });
+                landcover.push(surface.landcover);
+                treecover.push(surface.treecover);
+                if with_albedo {
+                    albedo.extend_from_slice(&surface.albedo());
+                }
+            }
+        }
+        layers.insert(LayerType::LandFraction.index(), land_fraction);
+        layers.insert(LayerType::Landcover.index(), landcover);
+        layers.insert(LayerType::TreeCover.index(), treecover);
+        if with_albedo {
+            layers.insert(LayerType::BaseAlbedo.index(), albedo);
+        }
+        layers
+    }
+}
+
+struct Surface {
+    /// Height of the ground in meters.
+    height: f64,
+    /// Height of the water surface, if the ground is under a river.
+    water_level: Option<f64>,
+    /// Class from the Copernicus Global Land Cover map, as used by streamed tiles.
+    landcover: u8,
+    /// Percentage of the ground covered by trees.
+    treecover: u8,
+}
+impl Surface {
+    fn ocean(height: f64) -> Self {
+        Self { height, water_level: Some(0.0), landcover: 200, treecover: 0 }
+    }
+
+    fn is_water(&self) -> bool {
+        self.water_level.is_some()
+    }
+
+    /// sRGB albedo of the landcover class.
+    fn albedo(&self) -> [u8; 4] {
+        match self.landcover {
+            200 => [10, 30, 60, 255],
+            80 => [20, 40, 60, 255],
+            70 => [230, 235, 240, 255],
+            60 => [190, 170, 130, 255],
+            100 => [120, 120, 100, 255],
+            20 => [120, 110, 70, 255],
+            30 => [130, 140, 80, 255],
+            111 => [30, 55, 35, 255],
+            _ => [40, 70, 30, 255],
+        }
+    }
+}
+
+/// Number of octaves of detail that heightmaps at `level` can resolve. Each level halves the
+/// spacing between samples, so gains one more octave.
+fn detail_octaves(level: u8) -> u32 {
+    (level as u32 + 3).min(20)
+}
+
+/// Encode a height the same way streamed heightmaps do.
+fn encode_height(height: f64) -> u16 {
+    ((height + 1024.0) * 4.0).round().clamp(0.0, u16::MAX as f64) as u16
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Scramble the bits of `x`, following SplitMix64.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Gradients along the edges of a cube, which avoid the directional bias of axis aligned ones.
+const GRADIENTS: [[f64; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Perlin gradient noise, ranging from roughly minus one to one.
+fn gradient_noise(seed: u64, p: Vector3<f64>) -> f64 {
+    let cell = [p.x.floor(), p.y.floor(), p.z.floor()];
+    let f = [p.x - cell[0], p.y - cell[1], p.z - cell[2]];
+    let fade = f.map(|t| t * t * t * (t * (t * 6.0 - 15.0) + 10.0));
+
+    let corner = |i: i64, j: i64, k: i64| {
+        let hash = mix(seed
+            ^ mix((cell[0] as i64 + i) as u64
+                ^ mix((cell[1] as i64 + j) as u64 ^ mix((cell[2] as i64 + k) as u64))));
+        let g = GRADIENTS[(hash % 12) as usize];
+        g[0] * (f[0] - i as f64) + g[1] * (f[1] - j as f64) + g[2] * (f[2] - k as f64)
+    };
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+
+    let x00 = lerp(corner(0, 0, 0), corner(1, 0, 0), fade[0]);
+    let x10 = lerp(corner(0, 1, 0), corner(1, 1, 0), fade[0]);
+    let x01 = lerp(corner(0, 0, 1), corner(1, 0, 1), fade[0]);
+    let x11 = lerp(corner(0, 1, 1), corner(1, 1, 1), fade[0]);
+    lerp(lerp(x00, x10, fade[1]), lerp(x01, x11, fade[1]), fade[2])
+}
+
+/// Fractal sum of `octaves` octaves of gradient noise, normalized to roughly minus one to one.
+fn fbm(seed: u64, p: Vector3<f64>, octaves: u32) -> f64 {
+    let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+    for i in 0..octaves {
+        sum += amplitude * gradient_noise(mix(seed.wrapping_add(i as u64)), p * frequency);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    if total > 0.0 {
+        sum / total
+    } else {
+        0.0
+    }
+}
+
+/// Ridged fractal noise from zero to one, with sharp crests like mountain ranges.
+fn ridged(seed: u64, p: Vector3<f64>, octaves: u32) -> f64 {
+    let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+    for i in 0..octaves {
+        let ridge = 1.0 - gradient_noise(mix(seed.wrapping_add(i as u64)), p * frequency).abs();
+        sum += amplitude * ridge * ridge;
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_is_deterministic_and_seamless() {
+        let planet = ProceduralPlanet::new(12345);
+        let [left, right, _, _] = VNode::roots()[0].children();
+
+        let heights = planet.heightmap(left);
+        assert_eq!(heights, planet.heightmap(left));
+        assert_ne!(heights, ProceduralPlanet::new(54321).heightmap(left));
+
+        // The right edge of one node lines up exactly with the left edge of the next.
+        let neighbor = planet.heightmap(right);
+        let resolution = LayerType::BaseHeightmaps.texture_resolution() as usize;
+        let border = LayerType::BaseHeightmaps.texture_border_size() as usize;
+        for y in border..resolution - border {
+            assert_eq!(
+                heights[(resolution - 1 - border) + y * resolution],
+                neighbor[border + y * resolution]
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_have_every_streamed_layer() {
+        let layers = ProceduralPlanet::new(7).generate_tile(VNode::roots()[0]);
+        for layer in [
+            LayerType::BaseHeightmaps,
+            LayerType::TreeCover,
+            LayerType::LandFraction,
+            LayerType::Landcover,
+            LayerType::BaseAlbedo,
+        ] {
+            let resolution = layer.texture_resolution() as usize;
+            let bytes = layer.texture_formats()[0].bytes_per_block();
+            assert_eq!(layers[layer.index()].len(), resolution * resolution * bytes);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Timing of GPU passes with timestamp queries.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Maximum number of passes timed per frame. Any beyond this are not timed.
+const MAX_SCOPES: usize = 16;
+
+/// Time the GPU spent on a single pass.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassTiming {
+    pub name: &'static str,
+    pub duration: Duration,
+}
+
+/// Convert a pair of timestamps to a duration, given the number of nanoseconds per tick.
+fn ticks_to_duration(start: u64, end: u64, period: f32) -> Duration {
+    Duration::from_nanos((end.wrapping_sub(start) as f64 * period as f64) as u64)
+}
+
+enum Readback {
+    Idle,
+    /// Timestamps for the listed passes have been copied to the readback buffer by a command
+    /// buffer that hasn't been submitted yet.
+    Resolved(Vec<&'static str>),
+    /// The readback buffer is being mapped. The flag is set once it can be read.
+    Mapping(Vec<&'static str>, Arc<AtomicBool>),
+}
+
+struct Queries {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+}
+
+/// Brackets passes with timestamp queries and reads the results back asynchronously, so
+/// timings lag a few frames behind. Does nothing unless the device supports
+/// `wgpu::Features::TIMESTAMP_QUERY`.
+///
+/// A frame consists of the passes recorded between `begin_frame` and `resolve`, which may span
+/// several command buffers as long as they are submitted to the same queue. Frames that start
+/// while the results of an earlier one are still being read back are not timed.
+pub(crate) struct GpuProfiler {
+    queries: Option<Queries>,
+    readback: Readback,
+    /// Passes timed so far this frame.
+    scopes: Vec<&'static str>,
+    recording: bool,
+    scope_open: bool,
+    timings: Vec<PassTiming>,
+}
+impl GpuProfiler {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let queries = device.features().contains(wgpu::Features::TIMESTAMP_QUERY).then(|| {
+            let size = (MAX_SCOPES * 2 * std::mem::size_of::<u64>()) as u64;
+            Queries {
+                query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("queryset.profiler"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: MAX_SCOPES as u32 * 2,
+                }),
+                resolve_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    size,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                    label: Some("buffer.profiler.resolve"),
+                }),
+                readback_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    size,
+                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                    mapped_at_creation: false,
+                    label: Some("buffer.profiler.readback"),
+                }),
+                period: queue.get_timestamp_period(),
+            }
+        });
+
+        Self {
+            queries,
+            readback: Readback::Idle,
+            scopes: Vec::new(),
+            recording: false,
+            scope_open: false,
+            timings: Vec::new(),
+        }
+    }
+
+    /// Start a new frame, first collecting the timings of an earlier one if they are ready.
+    /// Returns whether new timings were collected.
+    pub fn begin_frame(&mut self, device: &wgpu::Device) -> bool {
+        self.scopes.clear();
+        self.scope_open = false;
+        let queries = match self.queries {
+            Some(ref queries) => queries,
+            None => return false,
+        };
+
+        let mut collected = false;
+        if let Readback::Mapping(_, ref mapped) = self.readback {
+            device.poll(wgpu::Maintain::Poll);
+            if mapped.load(Ordering::Acquire) {
+                let names = match std::mem::replace(&mut self.readback, Readback::Idle) {
+                    Readback::Mapping(names, _) => names,
+                    _ => unreachable!(),
+                };
+                {
+                    let data = queries.readback_buffer.slice(..).get_mapped_range();
+                    let ticks: &[u64] = bytemuck::cast_slice(&data);
+                    self.timings = names
+                        .into_iter()
+                        .zip(ticks.chunks_exact(2))
+                        .map(|(name, t)| PassTiming {
+                            name,
+                            duration: ticks_to_duration(t[0], t[1], queries.period),
+                        })
+                        .collect();
+                }
+                queries.readback_buffer.unmap();
+                collected = true;
+            }
+        }
+
+        self.recording = matches!(self.readback, Readback::Idle);
+        collected
+    }
+
+    /// Record a timestamp marking the start of the pass called `name`. Returns whether the pass
+    /// is being timed.
+    pub fn begin_scope(&mut self, encoder: &mut wgpu::CommandEncoder, name: &'static str) -> bool {
+        if let Some(ref queries) = self.queries {
+            if self.recording && !self.scope_open && self.scopes.len() < MAX_SCOPES {
+                encoder.write_timestamp(&queries.query_set, self.scopes.len() as u32 * 2);
+                self.scopes.push(name);
+                self.scope_open = true;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Record a timestamp marking the end of the pass started by the last `begin_scope`.
+    pub fn end_scope(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if let (Some(ref queries), true) = (&self.queries, self.scope_open) {
+            encoder.write_timestamp(&queries.query_set, self.scopes.len() as u32 * 2 - 1);
+            self.scope_open = false;
+        }
+    }
+
+    /// End the frame by copying its timestamps to the readback buffer. `map_results` must be
+    /// called once `encoder` has been submitted.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if let Some(ref queries) = self.queries {
+            if self.recording && !self.scope_open && !self.scopes.is_empty() {
+                let count = self.scopes.len() as u32 * 2;
+                encoder.resolve_query_set(&queries.query_set, 0..count, &queries.resolve_buffer, 0);
+                encoder.copy_buffer_to_buffer(
+                    &queries.resolve_buffer,
+                    0,
+                    &queries.readback_buffer,
+                    0,
+                    count as u64 * std::mem::size_of::<u64>() as u64,
+                );
+                self.readback = Readback::Resolved(std::mem::take(&mut self.scopes));
+            }
+        }
+        self.recording = false;
+    }
+
+    /// Start reading back the timestamps copied by `resolve`.
+    pub fn map_results(&mut self) {
+        if let Some(ref queries) = self.queries {
+            if let Readback::Resolved(ref mut names) = self.readback {
+                let names = std::mem::take(names);
+                let mapped = Arc::new(AtomicBool::new(false));
+                let mapped2 = mapped.clone();
+                queries.readback_buffer.slice(..).map_async(wgpu::MapMode::Read, move |r| {
+                    if r.is_ok() {
+                        mapped2.store(true, Ordering::Release);
+                    }
+                });
+                self.readback = Readback::Mapping(names, mapped);
+            }
+        }
+    }
+
+    /// Timings of the most recent frame that has been read back.
+    pub fn timings(&self) -> &[PassTiming] {
+        &self.timings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_to_duration_handles_wraparound() {
+        assert_eq!(ticks_to_duration(100, 1100, 1.0), Duration::from_micros(1));
+        assert_eq!(ticks_to_duration(1000, 2000, 2.5), Duration::from_nanos(2500));
+        assert_eq!(ticks_to_duration(u64::MAX - 9, 10, 1.0), Duration::from_nanos(20));
+    }
+}
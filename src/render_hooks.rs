@@ -0,0 +1,49 @@
+//! Points in a frame at which applications can record their own rendering commands, so that their
+//! geometry is depth tested against the terrain and composited before post-processing.
+
+/// Where in the frame a hook passed to `Terrain::render_with_hooks` runs.
+///
+/// Terra has no separate depth-only pass: the depth buffer is filled while drawing opaque geometry,
+/// so `AfterOpaque` is the first point at which it holds the depth of the terrain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RenderHookPoint {
+    /// After the color and depth buffers have been cleared, before anything is drawn. Geometry
+    /// drawn here occludes the terrain wherever it passes the depth test.
+    BeforeOpaque,
+    /// After the terrain, vegetation and overlays, and before the sky.
+    AfterOpaque,
+    /// After the sky and stars, and before transparent geometry such as precipitation.
+    AfterSky,
+    /// After all of Terra's geometry, just before the HDR target is resolved and tonemapped.
+    AfterTransparent,
+}
+impl RenderHookPoint {
+    /// Every hook point, in the order they run.
+    pub const ALL: [Self; 4] =
+        [Self::BeforeOpaque, Self::AfterOpaque, Self::AfterSky, Self::AfterTransparent];
+}
+
+/// Attachments and state that a hook needs to draw into the frame.
+///
+/// Render passes begun by a hook must load rather than clear both attachments, and must store
+/// their results. Pipelines must match `sample_count`, use `HDR_FORMAT` for color and
+/// `Depth32Float` for depth, and test depth with `depth_compare`.
+pub struct RenderHookTarget<'a> {
+    /// HDR color attachment holding linear radiance scaled by the current exposure. When MSAA is
+    /// enabled this is the multisampled target, which Terra resolves after the last hook.
+    pub color: &'a wgpu::TextureView,
+    /// Depth attachment that Terra renders into.
+    pub depth: &'a wgpu::TextureView,
+    /// Number of samples per pixel of both attachments.
+    pub sample_count: u32,
+    /// Comparison that passes fragments closer to the camera than the stored depth, which depends
+    /// on whether reverse Z is in use.
+    pub depth_compare: wgpu::CompareFunction,
+    /// View projection matrix that the frame is rendered with, relative to the camera position.
+    pub view_proj: mint::ColumnMatrix4<f32>,
+}
+
+/// Callback passed to `Terrain::render_with_hooks`, which records commands into the frame's
+/// encoder at the given point.
+pub type RenderHook<'a> =
+    dyn FnMut(RenderHookPoint, &mut wgpu::CommandEncoder, &RenderHookTarget) + 'a;
@@ -0,0 +1,181 @@
+//! Bookkeeping of live GPU resources, used to track down leaks during long sessions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Kinds of GPU resources tracked by the resource registry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResourceKind {
+    Texture,
+    Buffer,
+    BindGroup,
+}
+
+/// Usage statistics for a single category of GPU resources.
+#[derive(Clone, Debug)]
+pub struct ResourceUsage {
+    pub kind: ResourceKind,
+    pub category: String,
+    /// Number of resources currently alive.
+    pub live: usize,
+    /// Largest number of resources that have been alive at the same time.
+    pub peak: usize,
+    /// Total number of resources ever created.
+    pub created: usize,
+    /// Approximate memory used by the live resources, in bytes.
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    usage: BTreeMap<(ResourceKind, String), ResourceUsage>,
+    limits: HashMap<(ResourceKind, String), usize>,
+    assertions: bool,
+}
+
+/// Counts live resources by kind and category.
+///
+/// Every resource that Terra creates more than once over its lifetime is registered here, so a
+/// category whose live count keeps growing points directly at the leak.
+#[derive(Clone, Default)]
+pub(crate) struct ResourceRegistry(Arc<Mutex<RegistryState>>);
+impl ResourceRegistry {
+    /// Number of bind groups allowed per category when assertions are enabled. Bind groups are
+    /// rebuilt in place, so the old and new one may briefly be alive at once.
+    const DEFAULT_BIND_GROUP_LIMIT: usize = 2;
+
+    pub fn track(&self, kind: ResourceKind, category: &str, bytes: u64) -> ResourceToken {
+        let mut state = self.0.lock().unwrap();
+        let key = (kind, category.to_owned());
+        let limit = state.limits.get(&key).copied().or(match kind {
+            ResourceKind::BindGroup => Some(Self::DEFAULT_BIND_GROUP_LIMIT),
+            _ => None,
+        });
+        let assertions = state.assertions;
+
+        let usage = state.usage.entry(key).or_insert_with(|| ResourceUsage {
+            kind,
+            category: category.to_owned(),
+            live: 0,
+            peak: 0,
+            created: 0,
+            bytes: 0,
+        });
+        usage.live += 1;
+        usage.created += 1;
+        usage.bytes += bytes;
+        usage.peak = usage.peak.max(usage.live);
+        let live = usage.live;
+        drop(state);
+
+        // Check after releasing the lock so the panic doesn't poison the registry.
+        if let Some(limit) = limit {
+            assert!(
+                !assertions || live <= limit,
+                "Resource leak: {} live {:?} resources in category '{}' (limit {})",
+                live,
+                kind,
+                category,
+                limit
+            );
+        }
+
+        ResourceToken { registry: self.clone(), kind, category: category.to_owned(), bytes }
+    }
+
+    /// Set the maximum number of live resources expected for a category.
+    pub fn set_limit(&self, kind: ResourceKind, category: &str, limit: usize) {
+        self.0.lock().unwrap().limits.insert((kind, category.to_owned()), limit);
+    }
+
+    /// When enabled, creating more resources in a category than its limit allows will panic.
+    pub fn set_assertions(&self, enabled: bool) {
+        self.0.lock().unwrap().assertions = enabled;
+    }
+
+    pub fn report(&self) -> Vec<ResourceUsage> {
+        self.0.lock().unwrap().usage.values().cloned().collect()
+    }
+}
+
+/// Keeps a resource counted as live until dropped.
+pub(crate) struct ResourceToken {
+    registry: ResourceRegistry,
+    kind: ResourceKind,
+    category: String,
+    bytes: u64,
+}
+impl Drop for ResourceToken {
+    fn drop(&mut self) {
+        let mut state = self.registry.0.lock().unwrap();
+        if let Some(usage) = state.usage.get_mut(&(self.kind, std::mem::take(&mut self.category))) {
+            usage.live -= 1;
+            usage.bytes -= self.bytes;
+        }
+    }
+}
+
+/// A GPU resource along with the token recording that it is alive.
+pub(crate) struct Tracked<T> {
+    resource: T,
+    _token: ResourceToken,
+}
+impl<T> Tracked<T> {
+    pub fn new(resource: T, token: ResourceToken) -> Self {
+        Self { resource, _token: token }
+    }
+}
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.resource
+    }
+}
+
+/// Approximate number of bytes of GPU memory used by a texture.
+pub(crate) fn texture_bytes(texture: &wgpu::Texture) -> u64 {
+    let info = texture.format().describe();
+    let size = texture.size();
+    let blocks_x =
+        (size.width + info.block_dimensions.0 as u32 - 1) / info.block_dimensions.0 as u32;
+    let blocks_y =
+        (size.height + info.block_dimensions.1 as u32 - 1) / info.block_dimensions.1 as u32;
+    blocks_x as u64
+        * blocks_y as u64
+        * size.depth_or_array_layers as u64
+        * info.block_size as u64
+        * texture.sample_count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_counts_follow_tokens() {
+        let registry = ResourceRegistry::default();
+        let a = registry.track(ResourceKind::Buffer, "test", 16);
+        let b = registry.track(ResourceKind::Buffer, "test", 32);
+        drop(a);
+
+        let report = registry.report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].live, 1);
+        assert_eq!(report[0].peak, 2);
+        assert_eq!(report[0].created, 2);
+        assert_eq!(report[0].bytes, 32);
+        drop(b);
+        assert_eq!(registry.report()[0].live, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn assertions_catch_growth() {
+        let registry = ResourceRegistry::default();
+        registry.set_assertions(true);
+        registry.set_limit(ResourceKind::Texture, "test", 1);
+        let _a = registry.track(ResourceKind::Texture, "test", 0);
+        let _b = registry.track(ResourceKind::Texture, "test", 0);
+    }
+}
@@ -0,0 +1,53 @@
+//! Saving where the user left off, so that a later session can resume with the same view and a
+//! warm tile cache.
+
+use crate::{TerrainQuality, WaterQuality};
+use serde::{Deserialize, Serialize};
+use terra_types::VNode;
+
+/// Snapshot of the view, time of day and quality settings of a `Terrain`, along with the nodes
+/// that were resident in its tile cache.
+///
+/// Returned by `Terrain::session_state` and restored by `Terrain::restore_session`. Plain data,
+/// so it can be saved in any format supported by serde.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    /// Camera position passed to the last call to `update`, in ECEF coordinates.
+    pub camera: [f64; 3],
+    /// View projection matrix passed to the last call to `update`, as columns.
+    pub view_proj: [[f32; 4]; 4],
+    /// Julian day passed to the last call to `update`.
+    pub julian_day: f64,
+    /// Sun direction set with `set_sun_direction`, if any.
+    pub sun_direction: Option<[f32; 3]>,
+    /// Preset set with `set_quality`, if any.
+    pub quality: Option<TerrainQuality>,
+    pub water_quality: WaterQuality,
+    /// Nodes that were needed for rendering, coarsest first.
+    pub resident_nodes: Vec<VNode>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_json() {
+        let state = SessionState {
+            camera: [6371000.0, 1000.0, -250.5],
+            view_proj: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, -1.0],
+                [0.0, 0.0, 0.1, 0.0],
+            ],
+            julian_day: 2459945.5,
+            sun_direction: Some([0.0, 0.6, 0.8]),
+            quality: Some(TerrainQuality::Medium),
+            water_quality: WaterQuality::Reflections,
+            resident_nodes: VNode::roots().to_vec(),
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<SessionState>(&json).unwrap(), state);
+    }
+}
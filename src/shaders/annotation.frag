@@ -0,0 +1,12 @@
+#version 450 core
+
+layout(set = 0, binding = 1) uniform texture2D annotation_atlas;
+layout(set = 0, binding = 2) uniform sampler linear;
+
+layout(location = 0) in vec2 texcoord;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+	out_color = texture(sampler2D(annotation_atlas, linear), texcoord);
+}
@@ -0,0 +1,23 @@
+#version 450 core
+
+struct AnnotationInstance {
+	vec4 rect;
+	vec4 texcoords;
+};
+layout(set = 0, binding = 0, std430) readonly buffer AnnotationInstances {
+	AnnotationInstance annotation_instances[];
+};
+
+layout(location = 0) out vec2 texcoord;
+
+const vec2 CORNERS[6] = vec2[6](
+	vec2(0, 0), vec2(1, 0), vec2(1, 1),
+	vec2(0, 0), vec2(1, 1), vec2(0, 1)
+);
+
+void main() {
+	AnnotationInstance a = annotation_instances[gl_VertexIndex / 6];
+	vec2 corner = CORNERS[gl_VertexIndex % 6];
+	texcoord = mix(a.texcoords.xy, a.texcoords.zw, corner);
+	gl_Position = vec4(mix(a.rect.xy, a.rect.zw, corner), 0.0, 1.0);
+}
@@ -0,0 +1,78 @@
+// Real-time encoders for BC blocks. Endpoints are taken from the bounding box of the block, and
+// every texel picks the closest value that can be interpolated between them.
+
+// Offset in words of the current block within the tile_compression buffer. Rows of blocks are
+// padded to a multiple of 256 bytes so that they can be copied into textures.
+uint block_offset(uvec2 blocks) {
+	uint row_pitch = (blocks.x * 16u + 255u) / 256u * 64u;
+	return (gl_GlobalInvocationID.z * blocks.y + gl_GlobalInvocationID.y) * row_pitch
+		+ gl_GlobalInvocationID.x * 4u;
+}
+
+uint pack_565(vec3 c) {
+	uvec3 q = uvec3(round(clamp(c, 0.0, 1.0) * vec3(31, 63, 31)));
+	return (q.r << 11u) | (q.g << 5u) | q.b;
+}
+vec3 unpack_565(uint c) {
+	return vec3((c >> 11u) & 31u, (c >> 5u) & 63u, c & 31u) / vec3(31, 63, 31);
+}
+
+// Color block of BC1/BC3 in four color mode.
+uvec2 encode_color_block(vec3 texels[16]) {
+	vec3 lo = texels[0], hi = texels[0];
+	for (int i = 1; i < 16; i++) {
+		lo = min(lo, texels[i]);
+		hi = max(hi, texels[i]);
+	}
+
+	// Insetting the bounding box slightly reduces the error for texels inside of it.
+	vec3 inset = (hi - lo) / 16.0;
+	uint c0 = pack_565(hi - inset);
+	uint c1 = pack_565(lo + inset);
+
+	vec3 e0 = unpack_565(c0), e1 = unpack_565(c1);
+	vec3 palette[4] = vec3[4](e0, e1, (2.0 * e0 + e1) / 3.0, (e0 + 2.0 * e1) / 3.0);
+	uint indices = 0u;
+	for (uint i = 0u; i < 16u; i++) {
+		uint best = 0u;
+		float best_distance = dot(texels[i] - palette[0], texels[i] - palette[0]);
+		for (uint j = 1u; j < 4u; j++) {
+			float d = dot(texels[i] - palette[j], texels[i] - palette[j]);
+			if (d < best_distance) {
+				best = j;
+				best_distance = d;
+			}
+		}
+		indices |= best << (2u * i);
+	}
+	return uvec2(c0 | (c1 << 16u), indices);
+}
+
+// Single channel block of BC4, in eight value mode whenever the endpoints differ.
+uvec2 encode_channel_block(float texels[16]) {
+	float lo = texels[0], hi = texels[0];
+	for (int i = 1; i < 16; i++) {
+		lo = min(lo, texels[i]);
+		hi = max(hi, texels[i]);
+	}
+	uint a0 = uint(round(clamp(hi, 0.0, 1.0) * 255.0));
+	uint a1 = uint(round(clamp(lo, 0.0, 1.0) * 255.0));
+
+	uvec2 block = uvec2(a0 | (a1 << 8u), 0u);
+	for (uint i = 0u; i < 16u; i++) {
+		// Position between a1 (0) and a0 (7), mapped to the code of the matching palette entry.
+		float t = a0 == a1 ? 0.0 : (texels[i] * 255.0 - float(a1)) / float(a0 - a1);
+		uint p = uint(round(clamp(t, 0.0, 1.0) * 7.0));
+		uint code = p == 7u ? 0u : p == 0u ? 1u : 8u - p;
+
+		uint bit = 16u + 3u * i;
+		if (bit < 32u) {
+			block.x |= code << bit;
+			if (bit > 29u)
+				block.y |= code >> (32u - bit);
+		} else {
+			block.y |= code << (bit - 32u);
+		}
+	}
+	return block;
+}
@@ -0,0 +1,29 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+
+struct DebugBox {
+	vec4 corners[8];
+	vec4 color;
+};
+layout(set = 0, binding = 1, std430) readonly buffer DebugBoxes {
+	DebugBox debug_boxes[];
+};
+
+layout(location = 0) out vec4 color;
+
+// Pairs of corners joined by each of the twelve edges of a box.
+const uint EDGES[24] = uint[24](
+	0, 1, 1, 3, 3, 2, 2, 0,
+	4, 5, 5, 7, 7, 6, 6, 4,
+	0, 4, 1, 5, 2, 6, 3, 7
+);
+
+void main() {
+	DebugBox b = debug_boxes[gl_VertexIndex / 24];
+	color = b.color;
+	gl_Position = globals.view_proj * vec4(b.corners[EDGES[gl_VertexIndex % 24]].xyz, 1.0);
+}
@@ -4,6 +4,18 @@
 #define xdouble uvec2
 #endif
 
+// Shape of the body being rendered. Must match `terra_types::BODY`. MIN_TERRAIN_HEIGHT is a bound
+// on how far the terrain dips below the polar radius.
+const float PLANET_RADIUS = 6371000.0;
+const float PLANET_SEMIMAJOR_AXIS = 6378137.0;
+const float PLANET_SEMIMINOR_AXIS = 6356752.314245;
+const float MIN_TERRAIN_HEIGHT = -1024.0;
+const vec3 PLANET_AXES = vec3(PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS);
+
+// Side length of the root quadtree nodes, a quarter of the circumference. Must match
+// `terra_types::ROOT_SIDE_LENGTH`.
+const float ROOT_SIDE_LENGTH = PLANET_RADIUS * 1.5707963267948966;
+
 struct Globals {
     mat4 view_proj;
 	mat4 view_proj_inverse;
@@ -15,6 +27,114 @@ struct Globals {
 	float screen_height;
 	float sidereal_time;
 	float exposure;
+	uint simplified_shading;
+	uint water_quality;
+	vec3 moon_direction;
+	float moon_angular_radius;
+	float near_depth;
+	float far_depth;
+	uint debug_view;
+	uint atmosphere;
+};
+
+// Range of log2 luminances covered by the histogram used for auto exposure.
+const float MIN_LOG_LUMINANCE = -10.0;
+const float LOG_LUMINANCE_RANGE = 32.0;
+
+struct Region {
+	vec3 backdrop_color;
+	float fade_distance;
+	float center_longitude;
+	uint num_vertices;
+	vec4 vertices[16];
+};
+
+struct Fog {
+	vec3 color;
+	float density;
+	float falloff;
+	float base_height;
+	vec2 _padding;
+};
+
+struct Grass {
+	float density;
+	float min_height;
+	float max_height;
+	float color_variation;
+	vec2 wind;
+	float time;
+	float _padding;
+};
+
+struct SnowLine {
+	float equator_altitude;
+	float polar_altitude;
+	float transition;
+	float min_normal_y;
+	uint enabled;
+	uint _padding0;
+	uint _padding1;
+	uint _padding2;
+};
+
+struct SplatMaterial {
+	uint landcover_class;
+	float min_normal_y;
+	float max_normal_y;
+	float scale;
+};
+
+struct Splatting {
+	vec4 bounds;
+	uint material_count;
+	uint _padding0;
+	uint _padding1;
+	uint _padding2;
+	SplatMaterial materials[8];
+};
+
+struct Weather {
+	vec3 wind;
+	float intensity;
+	vec3 up;
+	float fall_speed;
+	vec3 east;
+	float wetness;
+	vec3 north;
+	float snow_cover;
+	vec3 camera_delta;
+	float time_step;
+	uint kind;
+	uint reset;
+	float time;
+	float box_radius;
+	float box_height;
+	uint ground_resolution;
+	vec2 _padding;
+	// Terrain height relative to the camera on a grid spanning the particle volume, packed four
+	// samples to a vector.
+	vec4 ground[64];
+};
+
+// Precipitation particle, positioned relative to the camera.
+struct Particle {
+	vec3 position;
+	float size;
+	vec3 velocity;
+	float seed;
+};
+
+const uint PRECIPITATION_RAIN = 0;
+const uint PRECIPITATION_SNOW = 1;
+
+struct Deformation {
+	vec3 center;
+	float radius;
+	float falloff;
+	float amount;
+	uint kind;
+	uint padding;
 };
 
 struct Indirect {
@@ -46,7 +166,10 @@ struct Node {
 	uint level;
 	uvec2 coords;
 
-	vec4 padding[12];
+	uvec4 shadow_mesh_valid_mask;
+	uvec4 probe_mesh_valid_mask;
+
+	vec4 padding[10];
 };
 
 struct GenMeshUniforms {
@@ -67,6 +190,11 @@ vec3 layer_texcoord(Layer layer, vec2 texcoord) {
 	return vec3(layer.origin + layer.ratio * texcoord, layer.slot);
 }
 
+// Threshold in [0, 1) that varies from pixel to pixel, for dithered transitions between LODs.
+float dither_threshold(vec2 frag_coord) {
+	return fract(52.9829189 * fract(dot(frag_coord, vec2(0.06711056, 0.00583715))));
+}
+
 const uint NUM_LAYERS = 24;
 
 const uint BASE_HEIGHTMAPS_LAYER = 0;
@@ -84,6 +212,7 @@ const uint LAND_FRACTION_LAYER = 11;
 const uint ELLIPSOID_LAYER = 12;
 const uint HEIGHTMAPS_LAYER = 13;
 const uint WATERLEVEL_LAYER = 14;
+const uint LANDCOVER_LAYER = 15;
 
 const uint PARENT_BASE_HEIGHTMAPS_LAYER = NUM_LAYERS + BASE_HEIGHTMAPS_LAYER;
 const uint PARENT_DISPLACEMENTS_LAYER = NUM_LAYERS + DISPLACEMENTS_LAYER;
@@ -93,14 +222,22 @@ const uint PARENT_GRASS_CANOPY_LAYER = NUM_LAYERS + GRASS_CANOPY_LAYER;
 const uint PARENT_TREE_ATTRIBUTES_LAYER = NUM_LAYERS + TREE_ATTRIBUTES_LAYER;
 const uint PARENT_AERIAL_PERSPECTIVE_LAYER = NUM_LAYERS + AERIAL_PERSPECTIVE_LAYER;
 const uint PARENT_TREECOVER_LAYER = NUM_LAYERS + TREECOVER_LAYER;
+const uint PARENT_LANDCOVER_LAYER = NUM_LAYERS + LANDCOVER_LAYER;
+const uint PARENT_HEIGHTMAPS_LAYER = NUM_LAYERS + HEIGHTMAPS_LAYER;
 
 const uint SLOTS_PER_LAYER = 30;
 const uint TREE_ATTRIBUTES_BASE_SLOT = 30 + (11 - 2) * SLOTS_PER_LAYER;
 const uint GRASS_CANOPY_BASE_SLOT = 30 + (14 - 2) * SLOTS_PER_LAYER;
 const uint GRASS_BASE_SLOT = 30 + (19 - 2) * SLOTS_PER_LAYER;
 const uint TREE_BILLBOARDS_BASE_SLOT = 30 + (13 - 2) * SLOTS_PER_LAYER;
+const uint ROCKS_BASE_SLOT = 30 + (16 - 2) * SLOTS_PER_LAYER;
 const uint AERIAL_PERSPECTIVE_BASE_SLOT = 30 + SLOTS_PER_LAYER;
 
+// Trees closer than TREE_MODEL_FADE_START are drawn as 3D models and those beyond
+// TREE_MODEL_FADE_END as billboards, with a dithered cross-fade in between.
+const float TREE_MODEL_FADE_START = 100.0;
+const float TREE_MODEL_FADE_END = 150.0;
+
 const uint HEIGHTMAP_INNER_RESOLUTION = 512;
 const uint HEIGHTMAP_BORDER = 4;
 const uint HEIGHTMAP_RESOLUTION = 521;
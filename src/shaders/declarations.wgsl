@@ -19,7 +19,10 @@ struct Node {
 	level: u32,
     coords: vec2<u32>,
 
-	padding2: array<vec4<u32>, 12>,
+	shadow_mesh_valid_mask: array<u32, 4>,
+	probe_mesh_valid_mask: array<u32, 4>,
+
+	padding2: array<vec4<u32>, 10>,
 };
 struct Nodes {
     entries: array<Node>,
@@ -32,6 +35,16 @@ struct GenMeshUniforms {
     entries_per_node: u32,
 };
 
+struct Grass {
+    density: f32,
+    min_height: f32,
+    max_height: f32,
+    color_variation: f32,
+    wind: vec2<f32>,
+    time: f32,
+    padding: f32,
+};
+
 struct Indirect {
     vertex_count: atomic<i32>, // TODO: why doesn't u32 work here?
     instance_count: u32,
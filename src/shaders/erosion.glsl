@@ -0,0 +1,44 @@
+// Shared between the passes of the hydraulic erosion simulation, which is run on heightmap tiles
+// after they are generated. See `Erosion` for a description of the parameters.
+
+layout(binding = 0) readonly buffer UniformBlock {
+	uint slots[4];
+	uint stage;
+	float rainfall;
+	float evaporation;
+	float sediment_capacity;
+	float erosion_rate;
+	float deposition_rate;
+} ubo;
+
+// Simulation state of each texel, stored as (height, water, sediment, original height) with all
+// values in multiples of the texel spacing. The buffer holds two copies of the state for every
+// tile in the batch, which successive passes alternate between.
+layout(binding = 1, std430) buffer ErosionState {
+	vec4 texels[];
+} erosion_state;
+
+layout(set = 0, binding = 2, std140) readonly buffer Nodes {
+	Node nodes[];
+};
+
+const uint EROSION_BATCH = 4;
+const uint STAGE_LOAD = 0;
+const uint STAGE_FLOW = 1;
+const uint STAGE_ERODE = 2;
+
+uint state_index(uint copy, ivec2 texel) {
+	uint tile = copy * EROSION_BATCH + gl_GlobalInvocationID.z;
+	return (tile * HEIGHTMAP_RESOLUTION + uint(texel.y)) * HEIGHTMAP_RESOLUTION + uint(texel.x);
+}
+
+bool in_tile(ivec2 texel) {
+	return all(greaterThanEqual(texel, ivec2(0))) && all(lessThan(texel, ivec2(HEIGHTMAP_RESOLUTION)));
+}
+
+// Matches the spacing that gen-heightmaps.comp scales its noise by.
+float texel_spacing(uint level) {
+	return (ROOT_SIDE_LENGTH / 512.0) / float(1 << level);
+}
+
+const ivec2 NEIGHBORS[4] = ivec2[4](ivec2(1, 0), ivec2(-1, 0), ivec2(0, 1), ivec2(0, -1));
@@ -0,0 +1,20 @@
+// Analytic exponential height fog. Density falls off exponentially with altitude above
+// `fog.base_height`, which lets the fog along a ray be integrated in closed form. Over the
+// distances where fog matters, altitude is treated as varying linearly along the ray.
+vec3 apply_height_fog(Fog fog, vec3 color, vec3 camera, vec3 direction, float distance, vec3 sun_direction) {
+	if (fog.density <= 0)
+		return color;
+
+	vec3 up = normalize(camera);
+	float surface_radius = inversesqrt(dot(up.xy, up.xy) / (PLANET_SEMIMAJOR_AXIS * PLANET_SEMIMAJOR_AXIS) + up.z * up.z / (PLANET_SEMIMINOR_AXIS * PLANET_SEMIMINOR_AXIS));
+	float altitude = length(camera) - surface_radius;
+
+	float k = fog.falloff * dot(direction, up);
+	float start = fog.density * exp(-fog.falloff * (altitude - fog.base_height));
+	float optical_depth = abs(k * distance) > 1e-4 ? start * (1 - exp(-k * distance)) / k : start * distance;
+	float amount = 1 - exp(-optical_depth);
+
+	// Fog is lit by the sun, fading out as it sets.
+	vec3 radiance = fog.color * 20000.0 * smoothstep(-0.1, 0.3, dot(up, sun_direction));
+	return mix(color, radiance, amount);
+}
@@ -0,0 +1,23 @@
+// Individual bindings for the layers that gen-grass.wgsl reads, used in place of tile-layers.wgsl
+// on devices without support for binding arrays.
+@group(0) @binding(6) var displacements: texture_2d_array<f32>;
+@group(0) @binding(7) var normals: texture_2d_array<f32>;
+@group(0) @binding(8) var albedo: texture_2d_array<f32>;
+@group(0) @binding(9) var grass_canopy: texture_2d_array<f32>;
+
+fn layer_dimensions(layer: u32) -> vec2<i32> {
+    let l = layer % NUM_LAYERS;
+    if (l == ALBEDO_LAYER) {                 return textureDimensions(albedo); }
+    else if (l == NORMALS_LAYER) {           return textureDimensions(normals); }
+    else if (l == GRASS_CANOPY_LAYER) {      return textureDimensions(grass_canopy); }
+    return textureDimensions(displacements);
+}
+
+fn layer_load(layer: u32, coords: vec2<i32>, array_index: i32) -> vec4<f32> {
+    let l = layer % NUM_LAYERS;
+    if (l == ALBEDO_LAYER) {                 return textureLoad(albedo, coords, array_index, 0); }
+    else if (l == NORMALS_LAYER) {           return textureLoad(normals, coords, array_index, 0); }
+    else if (l == GRASS_CANOPY_LAYER) {      return textureLoad(grass_canopy, coords, array_index, 0); }
+    else if (l == DISPLACEMENTS_LAYER) {     return textureLoad(displacements, coords, array_index, 0); }
+    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+}
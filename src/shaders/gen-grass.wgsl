@@ -4,8 +4,10 @@ struct Entry {
     albedo: vec3<f32>,
     slant: f32,
     texcoord: vec2<f32>,
-    padding1: vec2<f32>,
-    padding2: vec4<f32>,
+    height: f32,
+    padding1: f32,
+    tint: vec3<f32>,
+    padding2: f32,
 };
 struct Entries {
     entries: array<array<Entry, 1024>>,
@@ -15,33 +17,27 @@ struct Entries {
 @group(0) @binding(1) var<storage, read_write> grass_storage: Entries;
 @group(0) @binding(3) var<storage, read_write> mesh_indirect: Indirects;
 @group(0) @binding(4) var<storage, read> nodes: Nodes;
-@group(0) @binding(5) var linearsamp: sampler;
-@group(0) @binding(6) var displacements: texture_2d_array<f32>;
-@group(0) @binding(7) var normals: texture_2d_array<f32>;
-@group(0) @binding(8) var albedo: texture_2d_array<f32>;
-@group(0) @binding(9) var grass_canopy: texture_2d_array<f32>;
+@group(0) @binding(10) var<uniform> grass: Grass;
+
+// Bilinearly interpolate a layer, using `layer_load` and `layer_dimensions` from whichever of
+// tile-layers.wgsl or gen-grass-layers.wgsl is included.
+fn layer_sample(layer: u32, texcoord: vec2<f32>, array_index: i32) -> vec4<f32> {
+    let dimensions = layer_dimensions(layer);
+    let stexcoord = max(texcoord * vec2<f32>(dimensions) - vec2<f32>(0.5), vec2<f32>(0.0));
+    let f = fract(stexcoord);
+    let base_coords = vec2<i32>(stexcoord - f);
+    let max_coords = dimensions - vec2<i32>(1);
+    let i00 = layer_load(layer, base_coords, array_index);
+    let i10 = layer_load(layer, min(base_coords + vec2<i32>(1,0), max_coords), array_index);
+    let i01 = layer_load(layer, min(base_coords + vec2<i32>(0,1), max_coords), array_index);
+    let i11 = layer_load(layer, min(base_coords + vec2<i32>(1,1), max_coords), array_index);
+    return mix(mix(i00, i10, f.x), mix(i01, i11, f.x), f.y);
+}
 
 fn read_texture(layer: u32, global_id: vec3<u32>) -> vec4<f32> {
 	var node = nodes.entries[ubo.slot];
     let texcoord = layer_texcoord(node.layers[layer], vec2<f32>(global_id.xy) / 128.0);
-    let array_index = node.layers[layer].slot;
-
-    let l = layer % NUM_LAYERS;
-    if (l == ALBEDO_LAYER) {            return textureSampleLevel(albedo, linearsamp, texcoord, array_index, 0.0); }
-    else if (l == NORMALS_LAYER) {           return textureSampleLevel(normals, linearsamp, texcoord, array_index, 0.0); }
-    else if (l == GRASS_CANOPY_LAYER) {      return textureSampleLevel(grass_canopy, linearsamp, texcoord, array_index, 0.0); }
-    else if (l == DISPLACEMENTS_LAYER) {
-        let dimensions = textureDimensions(displacements);
-        let f = fract(texcoord.xy * vec2<f32>(dimensions));
-        let base_coords = vec2<i32>(texcoord.xy * vec2<f32>(dimensions));
-        let i00 = textureLoad(displacements, base_coords, array_index, 0);
-        let i10 = textureLoad(displacements, base_coords + vec2<i32>(1,0), array_index, 0);
-        let i01 = textureLoad(displacements, base_coords + vec2<i32>(0,1), array_index, 0);
-        let i11 = textureLoad(displacements, base_coords + vec2<i32>(1,1), array_index, 0);
-        return mix(mix(i00, i10, f.x), mix(i01, i11, f.y), f.y);
-    }
-
-    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+    return layer_sample(layer, texcoord, node.layers[layer].slot);
 }
 
 @compute
@@ -59,33 +55,28 @@ fn main(
     let rnd3 = random3(vec3<f32>(vec2<f32>(index), 3.0));
     let rnd4 = random3(vec3<f32>(vec2<f32>(index), 4.0));
     let rnd5 = random3(vec3<f32>(vec2<f32>(index), 5.0));
+    let rnd6 = random3(vec3<f32>(vec2<f32>(index), 6.0));
 
     // let texcoord = vec2<f32>(global_id.xy) / 128.0;
     let normal = extract_normal(read_texture(NORMALS_LAYER, global_id).xy);
     let albedo_value = read_texture(ALBEDO_LAYER, global_id).xyz;
     let canopy = read_texture(GRASS_CANOPY_LAYER, global_id);
 
-    if (normal.y < 0.95 || canopy.w <= rnd1) {
+    if (normal.y < 0.95 || canopy.w * grass.density <= rnd1) {
         return;
     }
 
     // Sample displacements texture at random offset (rnd1, rnd).
     let texcoord = layer_texcoord(node.layers[DISPLACEMENTS_LAYER], (vec2<f32>(global_id.xy) + vec2<f32>(rnd1, rnd2)) / 128.0);
     let array_index = node.layers[DISPLACEMENTS_LAYER].slot;
-    let dimensions = textureDimensions(displacements);
-    let stexcoord = max(texcoord.xy * vec2<f32>(dimensions) - vec2<f32>(0.5), vec2<f32>(0.0));
-    let f = fract(stexcoord);
-    let base_coords = vec2<i32>(stexcoord - f);
-    let i00 = textureLoad(displacements, base_coords, array_index, 0);
-    let i10 = textureLoad(displacements, min(base_coords + vec2<i32>(1,0), dimensions-vec2<i32>(1)), array_index, 0);
-    let i01 = textureLoad(displacements, min(base_coords + vec2<i32>(0,1), dimensions-vec2<i32>(1)), array_index, 0);
-    let i11 = textureLoad(displacements, min(base_coords + vec2<i32>(1,1), dimensions-vec2<i32>(1)), array_index, 0);
-    let position = mix(mix(i00, i10, f.x), mix(i01, i11, f.x), f.y);
+    let position = layer_sample(DISPLACEMENTS_LAYER, texcoord, array_index);
 
     let i = atomicAdd(&mesh_indirect.entries[ubo.mesh_base_entry + entry].vertex_count, 15) / 15;
     grass_storage.entries[ubo.storage_base_entry + entry][i].texcoord = texcoord; //layer_to_texcoord(NORMALS_LAYER).xy;
     grass_storage.entries[ubo.storage_base_entry + entry][i].position = position.xyz;
-    grass_storage.entries[ubo.storage_base_entry + entry][i].albedo = ((canopy.rgb - 0.5) * 0.025 + albedo_value) * mix(vec3<f32>(.75), vec3<f32>(1.25), vec3<f32>(rnd2, rnd3, rnd4));
+    grass_storage.entries[ubo.storage_base_entry + entry][i].albedo = (canopy.rgb - 0.5) * 0.025 + albedo_value;
+    grass_storage.entries[ubo.storage_base_entry + entry][i].tint = vec3<f32>(rnd2, rnd3, rnd4);
+    grass_storage.entries[ubo.storage_base_entry + entry][i].height = rnd6;
     grass_storage.entries[ubo.storage_base_entry + entry][i].angle = rnd5 * 2.0 * 3.14159265;
     grass_storage.entries[ubo.storage_base_entry + entry][i].slant = rnd1;
 }
@@ -3,8 +3,11 @@ struct Entry {
     angle: f32,
     albedo: vec3<f32>,
     height: f32,
-    padding0: vec4<f32>,
-    padding1: vec4<f32>,
+    species: u32,
+    padding0: u32,
+    padding1: u32,
+    padding2: u32,
+    padding3: vec4<f32>,
 };
 struct Entries {
     entries: array<array<Entry, 1024>>,
@@ -35,6 +38,7 @@ fn main(
     let rnd3 = random3(vec3<f32>(vec2<f32>(index), 3.0));
     let rnd4 = random3(vec3<f32>(vec2<f32>(index), 4.0));
     let rnd5 = random3(vec3<f32>(vec2<f32>(index), 5.0));
+    let rnd6 = random3(vec3<f32>(vec2<f32>(index), 6.0));
 
     let tree_attr = textureSampleLevel(
         tree_attributes,
@@ -64,6 +68,9 @@ fn main(
     let i = atomicAdd(&mesh_indirect.entries[ubo.mesh_base_entry + entry].vertex_count, 6) / 6;
     tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].position = position.xyz;
     tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].albedo = vec3<f32>(rnd3, rnd4, rnd5);
-    tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].angle = 0.0;
+    tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].angle = 6.2831853 * rnd6;
     tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].height = 10.0;
+
+    // The alpha channel of the tree attributes holds one plus the species index.
+    tree_billboards_storage.entries[ubo.storage_base_entry + entry][i].species = u32(round(tree_attr.a * 255.0)) - 1u;
 }
@@ -10,6 +10,9 @@ layout(set = 0, binding = 0) uniform UniformBlock {
 layout(set = 0, binding = 8, std140) readonly buffer Nodes {
 	Node nodes[];
 };
+layout(set = 0, binding = 1, std140) uniform FogBlock {
+	Fog fog;
+};
 
 // layout(set = 0, binding = 1, std140) uniform NodeBlock {
 // 	vec3 relative_position;
@@ -35,6 +38,8 @@ layout(location = 3) in vec3 normal;
 
 layout(location = 0) out vec4 out_color;
 
+#include "fog.glsl"
+
 vec3 extract_normal(vec2 n) {
 	n = n * 2.0 - vec2(1.0);
 	float y = sqrt(max(1.0 - dot(n, n),0));
@@ -73,5 +78,6 @@ void main() {
 	// 					normalize(vec3(0.4, .7, 0.2)),
 	// 					vec3(100000.0));
 
-	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color.rgb = apply_height_fog(fog, out_color.rgb, globals.camera, normalize(position), length(position), globals.sun_direction);
+	out_color.rgb *= globals.exposure;
 }
\ No newline at end of file
@@ -15,14 +15,19 @@ struct Entry {
     vec3 albedo;
     float slant;
     vec2 texcoord;
-    vec2 _padding1;
-    vec4 _padding2;
+    float height;
+    float _padding1;
+    vec3 tint;
+    float _padding2;
 };
 layout(std430, binding = 2) readonly buffer DataBlock {
     Entry entries[];
 } grass_storage;
 
 layout(set = 0, binding = 3) uniform sampler linear;
+layout(set = 0, binding = 5, std140) uniform GrassBlock {
+    Grass grass;
+};
 // layout(set = 0, binding = 9) uniform texture2DArray displacements;
 
 layout(location = 0) out vec3 position;
@@ -55,8 +60,8 @@ void main() {
 	float morph = 1 - smoothstep(0.7, .99, length(pos) / node.min_distance);
 
     vec3 offset;
-    float width = 0.01;
-    float height = 0.1;
+    float height = mix(grass.min_height, grass.max_height, entry.height);
+    float width = height * 0.1;
 
     if (node.min_distance > 24) {
         width *= mix(1, 1.5, smoothstep(0.7, .99, 4 * length(pos) / node.min_distance));
@@ -82,8 +87,17 @@ void main() {
     vec3 u = cos(entry.angle) * tangent + sin(entry.angle) * bitangent;
     vec3 w = -sin(entry.angle) * tangent + cos(entry.angle) * bitangent;
 
-    position = pos + (u*width*uv.x + (up + w*uv.y*entry.slant)*height*uv.y) * morph;
-    color = mix(entry.albedo, vec3(0, .4, .01), .0*uv.y);
+    // Bend the tip of the blade downwind, swaying with a phase that differs between blades.
+    vec3 east = cross(vec3(0, 0, 1), up);
+    east = length(east) > 1e-4 ? normalize(east) : vec3(1, 0, 0);
+    vec3 north = cross(up, east);
+    float wind_speed = length(grass.wind);
+    vec3 wind_direction = wind_speed > 0 ? (grass.wind.x * east + grass.wind.y * north) / wind_speed : vec3(0);
+    float sway = 0.7 + 0.3 * sin(grass.time * (1.5 + 0.1 * wind_speed) + entry.angle * 4);
+    float bend = min(wind_speed / 10, 1) * 0.6 * sway * uv.y * uv.y;
+
+    position = pos + (u*width*uv.x + (up*(1 - 0.5*bend) + w*uv.y*entry.slant + wind_direction*bend)*height*uv.y) * morph;
+    color = entry.albedo * mix(vec3(1 - grass.color_variation), vec3(1 + grass.color_variation), entry.tint);
     texcoord = entry.texcoord;
     normal = up;//normalize(w + up);
 
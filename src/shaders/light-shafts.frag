@@ -0,0 +1,44 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0) uniform texture2D hdr_color;
+layout(set = 0, binding = 1) uniform sampler linear;
+layout(set = 0, binding = 2, std140) uniform LightShaftBlock {
+	vec2 sun_position;
+	float intensity;
+	float _padding;
+} light_shafts;
+
+layout(location = 0) in vec4 position;
+layout(location = 0) out vec4 out_color;
+
+const int NUM_SAMPLES = 64;
+const float DECAY = 0.97;
+
+// Distance from the sun, as a fraction of the screen height, over which the sky contributes to
+// the shafts. Without this the whole sky would streak towards the sun.
+const float GLOW_RADIUS = 0.2;
+
+// Radial blur towards the sun, accumulating only pixels where the sky is visible. The sky pass
+// writes zero alpha while everything else has an alpha of at least one (water stores its
+// reflection weight above that), so terrain between a pixel and the sun casts a shadow into the
+// shafts.
+void main() {
+	vec2 uv = position.xy * vec2(0.5, -0.5) + 0.5;
+	vec2 size = vec2(textureSize(hdr_color, 0));
+	vec2 aspect = vec2(size.x / size.y, 1);
+
+	vec2 step = (light_shafts.sun_position - uv) / NUM_SAMPLES;
+	vec2 p = uv;
+	float weight = 1;
+	vec3 sum = vec3(0);
+	for (int i = 0; i < NUM_SAMPLES; i++) {
+		vec4 color = textureLod(sampler2D(hdr_color, linear), p, 0);
+		float glow = exp(-length((p - light_shafts.sun_position) * aspect) / GLOW_RADIUS);
+		sum += color.rgb * max(1 - color.a, 0) * glow * weight;
+		weight *= DECAY;
+		p += step;
+	}
+
+	out_color = vec4(sum * light_shafts.intensity / NUM_SAMPLES, 1);
+}
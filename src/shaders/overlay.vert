@@ -0,0 +1,34 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+
+struct OverlayVertex {
+	vec3 position;
+	uint overlay;
+	vec4 color;
+};
+// Origin of each overlay relative to the camera, and the fraction of its distance that it is
+// pulled towards the camera by.
+layout(set = 0, binding = 1, std430) readonly buffer OverlayOrigins {
+	vec4 overlay_origins[];
+};
+layout(set = 0, binding = 2, std430) readonly buffer OverlayVertices {
+	OverlayVertex overlay_vertices[];
+};
+
+layout(location = 0) out vec4 color;
+
+void main() {
+	OverlayVertex v = overlay_vertices[gl_VertexIndex];
+	vec4 origin = overlay_origins[v.overlay];
+
+	// Scaling the position only changes its depth, so the overlay stays in place on screen
+	// while being drawn in front of the terrain it is draped on.
+	vec3 position = (origin.xyz + v.position) * (1.0 - origin.w);
+
+	color = v.color;
+	gl_Position = globals.view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,29 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform WeatherBlock {
+	Weather weather;
+};
+
+layout(location = 0) in vec2 texcoord;
+layout(location = 1) in float opacity;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+	float daylight = smoothstep(-0.1, 0.3, dot(weather.up, globals.sun_direction));
+	vec3 light = vec3(20000.0 * daylight + 500.0);
+
+	vec2 offset = texcoord - 0.5;
+	if (weather.kind == PRECIPITATION_SNOW) {
+		out_color = vec4(light * 0.9, 0.9 * smoothstep(0.5, 0.3, length(offset)));
+	} else {
+		// Drops mostly refract what is behind them, so they only faintly brighten it.
+		out_color = vec4(light * 0.3, 0.25 * smoothstep(0.5, 0.2, abs(offset.x)));
+	}
+	out_color.a *= opacity;
+	out_color.rgb *= globals.exposure;
+}
@@ -0,0 +1,61 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform WeatherBlock {
+	Weather weather;
+};
+layout(set = 0, binding = 2, std430) readonly buffer Particles {
+	Particle precipitation_particles[];
+};
+
+layout(location = 0) out vec2 out_texcoord;
+layout(location = 1) out float out_opacity;
+
+const vec2 corners[6] = vec2[6](
+	vec2(0, 0),
+	vec2(1, 0),
+	vec2(0, 1),
+	vec2(1, 1),
+	vec2(0, 1),
+	vec2(1, 0)
+);
+
+void main() {
+	Particle p = precipitation_particles[gl_VertexIndex / 6];
+	vec2 corner = corners[gl_VertexIndex % 6];
+
+	float distance = length(p.position);
+	vec3 view = p.position / max(distance, 1e-3);
+
+	// Particles are much smaller than a pixel at a distance, so widen them to about a pixel and
+	// make them correspondingly more transparent instead.
+	float pixel = 2.0 * distance / globals.screen_height;
+
+	vec3 axis, side;
+	float extent, width;
+	if (weather.kind == PRECIPITATION_SNOW) {
+		side = normalize(cross(view, weather.up));
+		axis = cross(side, view);
+		width = 0.008 * p.size;
+		extent = width;
+	} else {
+		// Drops are drawn as streaks showing how far they fall during a typical exposure.
+		axis = normalize(p.velocity);
+		side = normalize(cross(axis, view));
+		width = 0.002 * p.size;
+		extent = max(0.02 * length(p.velocity), width);
+	}
+	float widened = max(width, pixel);
+	extent = max(extent, pixel);
+
+	vec3 position = p.position + side * (corner.x - 0.5) * widened + axis * (corner.y - 0.5) * extent;
+
+	out_texcoord = corner;
+	out_opacity = width / widened
+		* smoothstep(0.2, 0.6, distance)
+		* smoothstep(weather.box_radius, weather.box_radius * 0.6, distance);
+	gl_Position = globals.view_proj * vec4(position, 1.0);
+}
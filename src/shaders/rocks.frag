@@ -0,0 +1,35 @@
+#version 450 core
+#include "declarations.glsl"
+#include "pbr.glsl"
+
+layout(early_fragment_tests) in;
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1, std140) uniform FogBlock {
+	Fog fog;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+layout(location = 2) in vec3 normal;
+layout(location = 3) flat in float roughness;
+
+layout(location = 0) out vec4 out_color;
+
+#include "fog.glsl"
+
+void main() {
+	out_color = vec4(1);
+	out_color.rgb = pbr(color,
+						roughness,
+						position,
+						normalize(normal),
+						globals.camera,
+						globals.sun_direction,
+						vec3(100000.0));
+
+	out_color.rgb = apply_height_fog(fog, out_color.rgb, globals.camera, normalize(position), length(position), globals.sun_direction);
+	out_color.rgb *= globals.exposure;
+}
@@ -0,0 +1,87 @@
+#version 450 core
+#include "declarations.glsl"
+#include "hash.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+
+layout(set = 0, binding = 8, std140) readonly buffer Nodes {
+	Node nodes[];
+};
+
+struct Entry {
+    vec3 position;
+    float angle;
+    vec3 albedo;
+    float scale;
+    uint kind;
+    float seed;
+    vec2 _padding1;
+    vec4 _padding2;
+};
+layout(std430, binding = 2) readonly buffer DataBlock {
+    Entry entries[];
+} rocks_storage;
+
+layout(location = 0) out vec3 position;
+layout(location = 1) out vec3 color;
+layout(location = 2) out vec3 normal;
+layout(location = 3) flat out float roughness;
+
+const uint KIND_BUSH = 2;
+
+const vec3 tangents[6] = vec3[6](
+	vec3(0,1,0),
+	vec3(0,-1,0),
+	vec3(1,0,0),
+	vec3(-1,0,0),
+	vec3(1,0,0),
+	vec3(-1,0,0)
+);
+
+// Corners of the octahedron that every rock and bush is built from.
+const vec3 corners[6] = vec3[6](
+	vec3(1,0,0),
+	vec3(-1,0,0),
+	vec3(0,0,1),
+	vec3(0,0,-1),
+	vec3(0,1,0),
+	vec3(0,-1,0)
+);
+
+void main() {
+    uint entry_index = gl_VertexIndex / 6;
+    uint index = gl_VertexIndex % 6;
+    uint slot = gl_InstanceIndex / 16;
+
+    Node node = nodes[slot];
+    Entry entry = rocks_storage.entries[((slot - ROCKS_BASE_SLOT) * 16 + gl_InstanceIndex % 16) * 1024 + entry_index];
+    vec3 pos = entry.position - node.relative_position;
+
+    vec3 up = normalize(pos + globals.camera);
+	vec3 bitangent = normalize(cross(up, tangents[node.face]));
+	vec3 tangent = normalize(cross(up, bitangent));
+
+	float morph = 1 - smoothstep(0.7, .99, length(pos) / node.min_distance);
+
+    vec3 u = cos(entry.angle) * tangent + sin(entry.angle) * bitangent;
+    vec3 w = -sin(entry.angle) * tangent + cos(entry.angle) * bitangent;
+
+    // Stretch each corner by a different amount so that no two instances share a shape. Rocks are
+    // flattened and half buried, while bushes are rounder and sit on top of the ground.
+    vec3 corner = corners[index] * mix(0.6, 1.4, random(uvec2(floatBitsToUint(entry.seed), index)));
+    if (entry.kind == KIND_BUSH) {
+        corner = corner * vec3(1, 0.8, 1) + vec3(0, 0.6, 0);
+        roughness = 0.8;
+    } else {
+        corner = corner * vec3(1, 0.5, 1) + vec3(0, 0.1, 0);
+        roughness = 0.6;
+    }
+
+    position = pos + (u * corner.x + up * corner.y + w * corner.z) * entry.scale * morph;
+    color = entry.albedo;
+    normal = normalize(u * corners[index].x + up * corners[index].y + w * corners[index].z);
+
+    gl_Position = globals.view_proj * vec4(position, 1.0);
+}
@@ -1,7 +1,5 @@
 #version 450 core
 #include "declarations.glsl"
-#include "pbr.glsl"
-#include "hash.glsl"
 
 layout(set = 0, binding = 0) uniform UniformBlock {
 	Globals globals;
@@ -11,19 +9,29 @@ layout(set = 0, binding = 2) uniform sampler nearest;
 layout(set = 0, binding = 3) uniform texture2D sky;
 layout(set = 0, binding = 4) uniform texture2D transmittance;
 layout(set = 0, binding = 5) uniform texture2D skyview;
+layout(set = 0, binding = 6, std140) uniform FogBlock {
+	Fog fog;
+};
 
 layout(location = 0) in vec4 position;
 
 layout(location = 0) out vec4 OutColor;
 
 #include "atmosphere.glsl"
+#include "fog.glsl"
+
+// Distance that rays which don't hit terrain are treated as traveling through the fog, which
+// blends the fog into the sky towards the horizon.
+const float SKY_FOG_DISTANCE = 100000.0;
 
 const float PI = 3.1415926535;
-const vec3 ellipsoid_to_sphere = vec3(1, 1, 1.0033640898210048);
+const vec3 ellipsoid_to_sphere = vec3(1, 1, PLANET_SEMIMAJOR_AXIS / PLANET_SEMIMINOR_AXIS);
 
 void main() {
-	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
-	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
+	// Any two distinct depths along the view ray give its direction. The far plane itself may be
+	// at infinity, so use a depth halfway to it instead.
+	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, globals.near_depth, 1);
+	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, mix(globals.near_depth, globals.far_depth, 0.5), 1);
 	vec3 r = normalize(r1.xyz / r1.w - r0.xyz / r0.w);
 
 	vec3 camera = normalize(globals.camera * ellipsoid_to_sphere);
@@ -44,6 +52,20 @@ void main() {
 	vec4 sv = texture(sampler2D(skyview, linear), (vec2(u, phi) * 127 + 0.5) / 128);
 	OutColor.rgb = sv.rgb * 16;
 
-	OutColor = tonemap(OutColor, globals.exposure, 2.2);
-	OutColor.rgb += dither(gl_FragCoord.xy);
+	// Draw the moon as a sphere lit by the sun, which produces the correct phase.
+	float moon_sin = sin(globals.moon_angular_radius);
+	vec3 disk = (r - globals.moon_direction * dot(r, globals.moon_direction)) / moon_sin;
+	float disk_radius = length(disk);
+	if (dot(r, globals.moon_direction) > 0 && disk_radius < 1.05) {
+		vec3 moon_normal = disk - globals.moon_direction * sqrt(max(1 - dot(disk, disk), 0));
+		float lit = max(dot(normalize(moon_normal), sun), 0);
+		float coverage = smoothstep(1.05, 0.95, disk_radius);
+		OutColor.rgb += coverage * 100000.0 * 0.12 / PI * (lit + 0.002);
+	}
+
+	OutColor.rgb = apply_height_fog(fog, OutColor.rgb, globals.camera, r, SKY_FOG_DISTANCE, sun);
+	OutColor.rgb *= globals.exposure;
+
+	// Zero alpha marks the pixels where the sky is visible, for use by the light shaft pass.
+	OutColor.a = 0;
 }
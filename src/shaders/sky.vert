@@ -1,11 +1,17 @@
 #version 450 core
 #include "declarations.glsl"
 
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+
 layout(location = 0) out vec4 position;
 
 void main() {
 	if(gl_VertexIndex == 0) position = vec4(-1, -1, 0, 1);
 	if(gl_VertexIndex == 1) position = vec4(-1,  3, 0, 1);
 	if(gl_VertexIndex == 2) position = vec4( 3, -1, 0, 1);
+	// Place the sky on the far plane, so that it is only drawn where there is no terrain.
+	position.z = globals.far_depth;
 	gl_Position = position;
 }
@@ -27,6 +27,12 @@ void main() {
 
 	float alpha = smoothstep(1, 0, x) * clamp(0, 1, exp(1-0.7*magnitude));
 
+	// Fade stars out during the day for viewers inside of the atmosphere.
+	if (length(globals.camera) < atmosphereRadius) {
+		float sun_elevation = dot(normalize(globals.camera), normalize(globals.sun_direction));
+		alpha *= smoothstep(0.1, -0.1, sun_elevation);
+	}
+
 	// // Sky calculations
 	// vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
 	// vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
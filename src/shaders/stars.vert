@@ -39,5 +39,6 @@ void main() {
 
 	gl_Position = globals.view_proj * direction;
 	gl_Position.xy += (texcoord-0.5) * gl_Position.w * 4.0/vec2(globals.screen_width, globals.screen_height);
+	gl_Position.z = globals.far_depth * gl_Position.w;
 	position = gl_Position;
 }
@@ -22,6 +22,22 @@ layout(set = 0, binding = 10) uniform sampler nearest;
 layout(set = 0, binding = 11) uniform texture2DArray bent_normals;
 // layout(set = 0, binding = 12) uniform texture2D shadowmap;
 // layout(set = 0, binding = 13) uniform samplerShadow shadow_sampler;
+layout(set = 0, binding = 14, std140) uniform RegionBlock {
+	Region region;
+};
+layout(set = 0, binding = 15) uniform texture2D transmittance;
+layout(set = 0, binding = 16) uniform texture2D skyview;
+layout(set = 0, binding = 17) uniform texture2DArray heightmaps;
+layout(set = 0, binding = 18) uniform texture2DArray waterlevel;
+#ifdef DEBUG_VIEW
+layout(set = 0, binding = 19) uniform texture2DArray treecover;
+#endif
+layout(set = 0, binding = 20, std140) uniform FogBlock {
+	Fog fog;
+};
+layout(set = 0, binding = 21, std140) uniform WeatherBlock {
+	Weather weather;
+};
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 texcoord;
@@ -34,6 +50,13 @@ layout(location = 7) flat in uint instance;
 
 layout(location = 0) out vec4 out_color;
 
+#include "atmosphere.glsl"
+#include "fog.glsl"
+
+const uint WATER_QUALITY_REFLECTIONS = 1;
+const uint WATER_QUALITY_FULL = 2;
+const vec3 ellipsoid_to_sphere = vec3(1, 1, PLANET_SEMIMAJOR_AXIS / PLANET_SEMIMINOR_AXIS);
+
 // float mipmap_level(in vec2 texture_coordinate)
 // {
 //     vec2  dx_vtc        = dFdx(texture_coordinate);
@@ -217,23 +240,197 @@ vec3 layer_to_texcoord(uint layer) {
 	return layer_texcoord(node.layers[layer], texcoord);
 }
 
+#ifdef DEBUG_VIEW
+const uint DEBUG_VIEW_LEVELS = 1;
+const uint DEBUG_VIEW_NORMALS = 2;
+const uint DEBUG_VIEW_ALBEDO = 3;
+const uint DEBUG_VIEW_HEIGHTS = 4;
+const uint DEBUG_VIEW_TREECOVER = 5;
+
+// Same palette as wireframe.frag.
+const vec3 LEVEL_COLORS[8] = vec3[8](
+	vec3(1, 0, 0),
+	vec3(0, 1, 0),
+	vec3(0, 0, 1),
+	vec3(0, 1, 1),
+	vec3(1, 1, 0),
+	vec3(1, 0, 1),
+	vec3(1, 1, 1),
+	vec3(1, 0.5, 0)
+);
+
+// Color for the selected debug view, before being scaled to a radiance comparable to sunlit
+// terrain so that it displays sensibly with both manual and auto exposure.
+vec3 debug_view_color(vec3 bent_normal, vec4 albedo_roughness) {
+	Node node = nodes[instance];
+	if (globals.debug_view == DEBUG_VIEW_LEVELS) {
+		return LEVEL_COLORS[node.level % 8] * mix(0.3, 1.0, morph);
+	} else if (globals.debug_view == DEBUG_VIEW_NORMALS) {
+		return bent_normal * 0.5 + 0.5;
+	} else if (globals.debug_view == DEBUG_VIEW_ALBEDO) {
+		return albedo_roughness.rgb;
+	} else if (globals.debug_view == DEBUG_VIEW_HEIGHTS) {
+		float height;
+		if (node.layers[HEIGHTMAPS_LAYER].slot >= 0) {
+			height = extract_height(textureLod(sampler2DArray(heightmaps, linear), layer_to_texcoord(HEIGHTMAPS_LAYER), 0).x);
+		} else {
+			vec3 p = position + globals.camera;
+			height = length(p) - length(normalize(p / PLANET_AXES) * PLANET_AXES);
+		}
+		vec3 color = height < 0
+			? mix(vec3(0.1, 0.3, 0.8), vec3(0, 0, 0.2), clamp(-height / 6000, 0, 1))
+			: mix(vec3(0.1, 0.5, 0.1), vec3(0.6, 0.4, 0.2), clamp(height / 3000, 0, 1));
+		color = mix(color, vec3(1), clamp((height - 3000) / 3000, 0, 1));
+
+		float contour = abs(fract(height / 100 + 0.5) - 0.5) / max(fwidth(height / 100), 1e-6);
+		return mix(color * 0.4, color, smoothstep(0, 1, contour));
+	} else if (globals.debug_view == DEBUG_VIEW_TREECOVER) {
+		if (node.layers[TREECOVER_LAYER].slot < 0)
+			return vec3(0.2);
+		float density = textureLod(sampler2DArray(treecover, linear), layer_to_texcoord(TREECOVER_LAYER), 0).r;
+		return mix(vec3(0.8, 0.75, 0.6), vec3(0, 0.4, 0), density);
+	}
+	return vec3(1, 0, 1);
+}
+#endif
+
+vec2 region_vertex(uint i) {
+	vec4 v = region.vertices[i / 2];
+	return (i % 2 == 0) ? v.xy : v.zw;
+}
+
+// How far the terrain at `world_position` has faded towards the backdrop, based on its distance
+// outside of the region of interest. Distances are measured on a local equirectangular projection.
+float region_fade(vec3 world_position) {
+	if (region.num_vertices == 0)
+		return 0;
+
+	vec3 d = normalize(world_position / PLANET_AXES);
+	float longitude = atan(d.y, d.x) - region.center_longitude;
+	vec2 p = vec2(longitude - 2 * M_PI * round(longitude / (2 * M_PI)), asin(d.z));
+	vec2 scale = vec2(cos(p.y), 1) * PLANET_SEMIMAJOR_AXIS;
+
+	bool inside = false;
+	float min_distance = 1e30;
+	for (uint i = 0; i < region.num_vertices; i++) {
+		vec2 a = (region_vertex(i) - p) * scale;
+		vec2 b = (region_vertex((i + 1) % region.num_vertices) - p) * scale;
+		if ((a.y > 0) != (b.y > 0) && a.x + (b.x - a.x) * a.y / (a.y - b.y) > 0)
+			inside = !inside;
+
+		vec2 ab = b - a;
+		float t = clamp(-dot(a, ab) / max(dot(ab, ab), 1e-6), 0, 1);
+		min_distance = min(min_distance, length(a + ab * t));
+	}
+	if (inside)
+		return 0;
+	return smoothstep(0, max(region.fade_distance, 1), min_distance);
+}
+
+// Radiance of the sky in direction `r`, looked up the same way as in sky.frag.
+vec3 sky_radiance(vec3 r) {
+	vec3 camera = normalize(globals.camera * ellipsoid_to_sphere);
+	vec3 sun = normalize(globals.sun_direction);
+	vec3 a = normalize(cross(camera, sun));
+	vec3 b = normalize(cross(camera, a));
+
+	float theta = asin(dot(r, camera));
+	float phi = atan(dot(r, b), dot(r, a)) / M_PI * 0.5 + 0.5;
+
+	float camera_distance = length(globals.camera * ellipsoid_to_sphere);
+	float min_theta = -M_PI/2 + asin(planetRadius / camera_distance);
+	float max_theta = camera_distance < atmosphereRadius ? M_PI/2 : -M_PI/2 + asin(atmosphereRadius / camera_distance);
+
+	float u = sqrt(clamp((theta - min_theta) / (max_theta - min_theta), 0, 1));
+	return texture(sampler2D(skyview, linear), (vec2(u, phi) * 127 + 0.5) / 128).rgb * 16;
+}
+
+// Depth of the water below the surface in meters, or zero if it isn't known for this node.
+float water_depth() {
+	Node node = nodes[instance];
+	if (node.layers[WATERLEVEL_LAYER].slot < 0 || node.layers[HEIGHTMAPS_LAYER].slot < 0)
+		return 0;
+	float surface = extract_height(textureLod(sampler2DArray(waterlevel, linear), layer_to_texcoord(WATERLEVEL_LAYER), 0).x);
+	float bed = extract_height(textureLod(sampler2DArray(heightmaps, linear), layer_to_texcoord(HEIGHTMAPS_LAYER), 0).x);
+	return max(surface - bed, 0);
+}
+
+// Fraction of the surface covered by water, with the same falloff along the shore that the
+// materials generator uses.
+float water_amount(float depth) {
+	return smoothstep(0, 1.5, depth);
+}
+
+// Fraction of the light leaving a water surface towards the camera that was reflected rather
+// than transmitted.
+float water_fresnel() {
+	float cos_i = max(dot(-normalize(position), normal), 0);
+	return 0.02 + 0.98 * pow(1 - cos_i, 5);
+}
+
+// Shade a water surface of the given depth whose body has the given radiance, adding
+// reflections of the sky and, at the highest quality, light from the bottom that survives
+// absorption along the refracted path through the water.
+vec3 shade_water(vec3 body, float depth) {
+	vec3 v = normalize(position);
+	vec3 n = normal;
+	float cos_i = max(dot(-v, n), 0);
+	float fresnel = water_fresnel();
+
+	vec3 transmitted = body;
+	if (globals.water_quality >= WATER_QUALITY_FULL && depth > 0) {
+		// Snell's law with an index of refraction of 1.33.
+		float cos_t = sqrt(1 - (1 - cos_i * cos_i) / (1.33 * 1.33));
+		float path_length = depth / max(cos_t, 0.05);
+		vec3 absorption = exp(-path_length * vec3(0.45, 0.09, 0.06));
+
+		vec3 bed = pbr(vec3(.35, .3, .22), 0.9, position, n, globals.camera, globals.sun_direction, vec3(100000.0));
+		bed += 15000 * vec3(.35, .3, .22) * max(0, dot(n, globals.sun_direction));
+		transmitted = mix(body, bed * absorption, exp(-0.25 * depth));
+	}
+
+	vec3 r = reflect(v, n);
+	vec3 reflected = sky_radiance(r) + vec3(100000.0) * pow(max(dot(r, globals.sun_direction), 0), 800);
+	return mix(transmitted, reflected, fresnel);
+}
+
+// Wet surfaces are darker and smoother, while snow settles on all but the steepest slopes.
+vec4 weathered_material(vec4 albedo_roughness, vec3 n) {
+	albedo_roughness.rgb *= mix(1.0, 0.5, weather.wetness);
+	albedo_roughness.a *= mix(1.0, 0.3, weather.wetness);
+
+	if (weather.snow_cover > 0) {
+		float threshold = 1.0 - 0.8 * weather.snow_cover;
+		float snow = smoothstep(threshold, threshold + 0.1, dot(n, normalize(position + globals.camera)));
+		albedo_roughness = mix(albedo_roughness, vec4(0.9, 0.9, 0.95, 0.6), snow);
+	}
+	return albedo_roughness;
+}
+
 void main() {
 	Node node = nodes[instance];
 
+	// Reflection probes skip blending with parent tiles, bent normals and per-node aerial
+	// perspective.
+	bool simplified = globals.simplified_shading != 0;
+
 	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, linear), layer_to_texcoord(NORMALS_LAYER)).xy);
-	if (node.layers[PARENT_NORMALS_LAYER].slot >= 0) {
+	if (!simplified && node.layers[PARENT_NORMALS_LAYER].slot >= 0) {
 		vec3 pn = extract_normal(textureLod(sampler2DArray(normals, linear), layer_to_texcoord(PARENT_NORMALS_LAYER), 0).xy);
 		tex_normal = mix(pn, tex_normal, morph);
 	}
 	vec3 bent_normal = mat3(tangent, normal, bitangent) * tex_normal;
 
 	vec4 albedo_roughness = texture(sampler2DArray(albedo, linear), layer_to_texcoord(ALBEDO_LAYER));
-	if (node.layers[PARENT_ALBEDO_LAYER].slot >= 0) {
+	if (!simplified && node.layers[PARENT_ALBEDO_LAYER].slot >= 0) {
 		vec4 parent_albedo_roughness = textureLod(sampler2DArray(albedo, linear), layer_to_texcoord(PARENT_ALBEDO_LAYER), 0);
 		albedo_roughness = mix(parent_albedo_roughness, albedo_roughness, morph);
 	}
 
-	vec4 bn_value = texture(sampler2DArray(bent_normals, linear), layer_to_texcoord(BENT_NORMALS_LAYER));
+#ifdef DEBUG_VIEW
+	out_color = vec4(debug_view_color(bent_normal, albedo_roughness) * 100000 * globals.exposure, 1);
+	return;
+#endif
 
 	// if (node.grass_canopy_origin.z >= 0) {
 	// 	vec4 canopy = texture(sampler2DArray(grass_canopy, linear), node.grass_canopy_origin + vec3(texcoord * node.grass_canopy_step, 0));
@@ -252,9 +449,11 @@ void main() {
 	// 	shadow = textureLod(sampler2DShadow(shadowmap, shadow_sampler), vec3(shadow_coord, depth), 0);
 	// }
 
+	vec4 material = weathered_material(albedo_roughness, bent_normal);
+
 	out_color = vec4(1);
-	out_color.rgb = pbr(albedo_roughness.rgb,
-						albedo_roughness.a,
+	out_color.rgb = pbr(material.rgb,
+						material.a,
 						position,
 						bent_normal,
 						globals.camera,
@@ -262,21 +461,40 @@ void main() {
 						vec3(100000.0)) * (1-shadow);
 
 	float ambient_strength = max(0, dot(normal, globals.sun_direction)) * max(0, tex_normal.y);
-	if (node.layers[BENT_NORMALS_LAYER].slot >= 0)
-		out_color.rgb += bn_value.a * 15000 * albedo_roughness.rgb * ambient_strength;
-	else
-		out_color.rgb += 15000 * albedo_roughness.rgb * ambient_strength;
+	if (!simplified && node.layers[BENT_NORMALS_LAYER].slot >= 0) {
+		vec4 bn_value = texture(sampler2DArray(bent_normals, linear), layer_to_texcoord(BENT_NORMALS_LAYER));
+		out_color.rgb += bn_value.a * 15000 * material.rgb * ambient_strength;
+	} else
+		out_color.rgb += 15000 * material.rgb * ambient_strength;
+
+	float reflection_weight = 0;
+	if (globals.water_quality >= WATER_QUALITY_REFLECTIONS) {
+		float depth = water_depth();
+		float water = water_amount(depth);
+		if (water > 0) {
+			out_color.rgb = mix(out_color.rgb, shade_water(out_color.rgb, depth), water);
+			reflection_weight = water * water_fresnel();
+		}
+	}
 
 	vec4 ap;
-	if (node.layers[AERIAL_PERSPECTIVE_LAYER].slot >= 0) {
+	if (!simplified && node.layers[AERIAL_PERSPECTIVE_LAYER].slot >= 0) {
 		ap = textureLod(sampler2DArray(aerial_perspective, linear), layer_to_texcoord(AERIAL_PERSPECTIVE_LAYER), 0);
 	} else {
 		ap = textureLod(sampler2DArray(root_aerial_perspective, linear), layer_to_texcoord(ROOT_AERIAL_PERSPECTIVE_LAYER), 0);
 	}
 	out_color.rgb *= ap.a;
 	out_color.rgb += ap.rgb * 16.0;
+	out_color.rgb = apply_height_fog(fog, out_color.rgb, globals.camera, normalize(position), length(position), globals.sun_direction);
+
+	out_color.rgb *= globals.exposure;
+	float fade = region_fade(position + globals.camera);
+	out_color.rgb = mix(out_color.rgb, region.backdrop_color, fade);
 
-	out_color = tonemap(out_color, globals.exposure, 2.2);
+	// Everything but the sky is opaque, so alpha above one is free to carry how much of the
+	// reflected sky made it into the final color. The screen space reflection pass replaces
+	// that much of the sky with whatever it finds on screen along the reflected ray.
+	out_color.a = 1 + reflection_weight * ap.a * (1 - fade);
 
 	out_color.rgb = debug_overlay(out_color.rgb);
 }
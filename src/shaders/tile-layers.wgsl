@@ -0,0 +1,12 @@
+// Every tile cache texture, indexed by layer type. Only usable on devices for which
+// `GpuState::tile_layer_array_supported` returns true. The textures are bound as unfilterable,
+// so they can only be read one texel at a time.
+@group(0) @binding(32) var tile_layers: binding_array<texture_2d_array<f32>, 16>;
+
+fn layer_dimensions(layer: u32) -> vec2<i32> {
+    return textureDimensions(tile_layers[layer % NUM_LAYERS]);
+}
+
+fn layer_load(layer: u32, coords: vec2<i32>, array_index: i32) -> vec4<f32> {
+    return textureLoad(tile_layers[layer % NUM_LAYERS], coords, array_index, 0);
+}
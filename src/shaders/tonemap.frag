@@ -0,0 +1,59 @@
+#version 450 core
+#include "declarations.glsl"
+#include "hash.glsl"
+
+layout(set = 0, binding = 0) uniform texture2D hdr_color;
+layout(set = 0, binding = 1, std140) uniform UniformBlock {
+	uint tonemapper;
+};
+layout(set = 0, binding = 2) uniform texture2D light_shafts;
+layout(set = 0, binding = 3) uniform sampler linear;
+layout(set = 0, binding = 4) uniform texture2D reflections;
+
+layout(location = 0) out vec4 out_color;
+
+const uint TONEMAPPER_ACES = 0;
+const uint TONEMAPPER_REINHARD = 1;
+const uint TONEMAPPER_UNCHARTED2 = 2;
+
+// Fit of the ACES reference rendering transform by Krzysztof Narkowicz.
+vec3 aces(vec3 color) {
+	color *= 0.6;
+	return clamp((color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14), 0, 1);
+}
+
+// Reinhard applied to luminance, so that bright saturated colors keep their hue.
+vec3 reinhard(vec3 color) {
+	float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+	return color / (1.0 + luminance);
+}
+
+vec3 uncharted2_curve(vec3 color) {
+	float A = 0.15;
+	float B = 0.50;
+	float C = 0.10;
+	float D = 0.20;
+	float E = 0.02;
+	float F = 0.30;
+	return ((color*(A*color+C*B)+D*E)/(color*(A*color+B)+D*F))-E/F;
+}
+
+vec3 uncharted2(vec3 color) {
+	return uncharted2_curve(color) / uncharted2_curve(vec3(11.2));
+}
+
+void main() {
+	vec3 color = texelFetch(hdr_color, ivec2(gl_FragCoord.xy), 0).rgb;
+	color += textureLod(sampler2D(light_shafts, linear), gl_FragCoord.xy / vec2(textureSize(hdr_color, 0)), 0).rgb;
+	color = max(color + texelFetch(reflections, ivec2(gl_FragCoord.xy), 0).rgb, 0);
+
+	if (tonemapper == TONEMAPPER_REINHARD) {
+		color = reinhard(color);
+	} else if (tonemapper == TONEMAPPER_UNCHARTED2) {
+		color = uncharted2(color);
+	} else {
+		color = aces(color);
+	}
+
+	out_color = vec4(clamp(color, 0, 1) + dither(gl_FragCoord.xy), 1);
+}
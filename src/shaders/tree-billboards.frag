@@ -1,6 +1,7 @@
 #version 450 core
 #include "declarations.glsl"
 #include "pbr.glsl"
+#include "fog.glsl"
 
 layout(early_fragment_tests) in;
 
@@ -18,6 +19,9 @@ layout(binding = 4) uniform texture2DArray billboards_albedo;
 layout(binding = 5) uniform texture2DArray billboards_normals;
 layout(binding = 6) uniform texture2DArray billboards_ao;
 layout(binding = 7) uniform texture2DArray billboards_depth;
+layout(binding = 11, std140) uniform FogBlock {
+	Fog fog;
+};
 
 #ifndef SHADOWPASS
 layout(binding = 9) uniform texture2D shadowmap;
@@ -32,6 +36,7 @@ layout(location = 3) in vec3 normal;
 layout(location = 4) flat sample in uint slot;
 layout(location = 5) in vec3 right;
 layout(location = 6) in vec3 up;
+layout(location = 7) flat in float fade;
 
 vec3 extract_normal(vec2 n) {
 	n = n * 2.0 - vec2(1.0);
@@ -48,6 +53,10 @@ float mip_map_level(in vec2 texture_coordinate)
 }
 
 void main() {
+	// Nearby trees are drawn by tree-model.frag instead.
+	if (dither_threshold(gl_FragCoord.xy) >= fade)
+		discard;
+
 	vec4 albedo = texture(sampler2DArray(billboards_albedo, linear), vec3(texcoord/6.0, 0));
 	vec2 tx_normal = texture(sampler2DArray(billboards_normals, linear), vec3(texcoord/6.0, 0)).xy;
 	float ao = texture(sampler2DArray(billboards_ao, linear), vec3(texcoord/6.0+1./6, 0), 0).x;
@@ -97,7 +106,8 @@ void main() {
 	// out_color.rgb += ap.rgb * 16.0;
 
 
-	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color.rgb = apply_height_fog(fog, out_color.rgb, globals.camera, normalize(position), length(position), globals.sun_direction);
+	out_color.rgb *= globals.exposure;
 
 	// out_color.rgb = vec3(dot(globals.sun_direction,true_normal));
 
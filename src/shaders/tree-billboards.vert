@@ -14,8 +14,11 @@ struct Entry {
     float angle;
     vec3 albedo;
     float height;
-    vec4 padding0;
-    vec4 padding1;
+    uint species;
+    uint padding0;
+    uint padding1;
+    uint padding2;
+    vec4 padding3;
 };
 layout(std430, binding = 2) readonly buffer DataBlock {
     Entry entries[];
@@ -31,6 +34,7 @@ layout(location = 3) out vec3 normal;
 layout(location = 4) flat sample out uint slot;
 layout(location = 5) out vec3 right;
 layout(location = 6) out vec3 up;
+layout(location = 7) flat out float fade;
 
 const vec3 tangents[6] = vec3[6](
 	vec3(0,1,0),
@@ -49,6 +53,7 @@ void main() {
     Node node = nodes[slot];
     Entry entry = tree_billboards_storage.entries[((slot - TREE_BILLBOARDS_BASE_SLOT) * 16 + gl_InstanceIndex % 16) * 1024 + entry_index];
     position = entry.position - node.relative_position;
+    fade = smoothstep(TREE_MODEL_FADE_START, TREE_MODEL_FADE_END, length(position));
 
     up = normalize(position + globals.camera);
 	vec3 bitangent = normalize(cross(up, tangents[node.face]));
@@ -0,0 +1,62 @@
+#version 450 core
+#include "declarations.glsl"
+#include "pbr.glsl"
+#include "fog.glsl"
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 4) uniform sampler linear_wrap;
+layout(set = 0, binding = 5) uniform texture2D tree_model_albedo;
+layout(set = 0, binding = 6) uniform texture2D shadowmap;
+layout(set = 0, binding = 7) uniform samplerShadow shadow_sampler;
+layout(binding = 8, std140) uniform FogBlock {
+	Fog fog;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+layout(location = 2) in vec2 texcoord;
+layout(location = 3) in vec3 normal;
+layout(location = 4) in float ao;
+layout(location = 5) in vec3 up;
+layout(location = 6) flat in float fade;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+	// Farther trees are drawn by tree-billboards.frag instead.
+	if (dither_threshold(gl_FragCoord.xy) < fade)
+		discard;
+
+	vec4 albedo = texture(sampler2D(tree_model_albedo, linear_wrap), texcoord);
+	if (albedo.a < 0.5)
+		discard;
+
+	// Same adjustments as the billboards so that the transition between them isn't visible.
+	albedo.rgb *= 0.15;
+	albedo.rgb += (color-0.5) * 0.01;
+
+	vec3 true_normal = gl_FrontFacing ? normal : -normal;
+
+	float shadow = 0;
+	vec4 proj_position = globals.shadow_view_proj * vec4(position, 1);
+	vec2 shadow_coord = proj_position.xy * 0.5 * vec2(1,-1) + 0.5;
+	if (all(greaterThan(shadow_coord,vec2(0))) && all(lessThan(shadow_coord,vec2(1)))) {
+		float depth = proj_position.z - 4.0 / 102400.0;
+		shadow = textureLod(sampler2DShadow(shadowmap, shadow_sampler), vec3(shadow_coord, depth), 0);
+	}
+
+	out_color = vec4(1);
+	out_color.rgb = pbr(albedo.rgb,
+						0.4,
+						position,
+						true_normal,
+						globals.camera,
+						globals.sun_direction,
+						vec3(100000.0)) * (1-shadow);
+	out_color.rgb += ao * albedo.rgb * 15000 * max(0, dot(up, globals.sun_direction));
+
+	out_color.rgb = apply_height_fog(fog, out_color.rgb, globals.camera, normalize(position), length(position), globals.sun_direction);
+	out_color.rgb *= globals.exposure;
+}
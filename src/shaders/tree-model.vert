@@ -0,0 +1,95 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+layout(set = 0, binding = 1, std140) readonly buffer Nodes {
+	Node nodes[];
+};
+
+struct Vertex {
+    vec3 position;
+    float ao;
+    vec3 lod_position;
+    uint color;
+    vec3 normal;
+    float texcoord_u;
+    vec3 binormal;
+    float texcoord_v;
+};
+layout(std430, binding = 2) readonly buffer VertexBlock {
+    Vertex vertices[];
+} tree_model_vertices;
+
+struct Entry {
+    vec3 position;
+    float angle;
+    vec3 albedo;
+    float height;
+    uint species;
+    uint padding0;
+    uint padding1;
+    uint padding2;
+    vec4 padding3;
+};
+layout(std430, binding = 3) readonly buffer DataBlock {
+    Entry entries[];
+} tree_billboards_storage;
+
+layout(push_constant) uniform constants {
+    uint species;
+    uint num_species;
+} push_constants;
+
+layout(location = 0) out vec3 position;
+layout(location = 1) out vec3 color;
+layout(location = 2) out vec2 texcoord;
+layout(location = 3) out vec3 normal;
+layout(location = 4) out float ao;
+layout(location = 5) out vec3 up;
+layout(location = 6) flat out float fade;
+
+const vec3 tangents[6] = vec3[6](
+	vec3(0,1,0),
+	vec3(0,-1,0),
+	vec3(1,0,0),
+	vec3(-1,0,0),
+	vec3(1,0,0),
+	vec3(-1,0,0)
+);
+
+// Meters per model unit, chosen so that models line up with the billboards rendered from them.
+const float MODEL_SCALE = 30.0 / 18.0;
+
+void main() {
+    // Instances are indexed the same way as the tree billboard storage.
+    Entry entry = tree_billboards_storage.entries[gl_InstanceIndex];
+    Node node = nodes[TREE_BILLBOARDS_BASE_SLOT + gl_InstanceIndex / (16 * 1024)];
+    Vertex vertex = tree_model_vertices.vertices[gl_VertexIndex];
+
+    vec3 base = entry.position - node.relative_position;
+    fade = smoothstep(TREE_MODEL_FADE_START, TREE_MODEL_FADE_END, length(base));
+
+    // Every species is drawn for every tree, so collapse the triangles of the other ones.
+    if (min(entry.species, push_constants.num_species - 1) != push_constants.species || fade >= 1.0) {
+        gl_Position = vec4(0);
+        return;
+    }
+
+    up = normalize(base + globals.camera);
+	vec3 bitangent = normalize(cross(up, tangents[node.face]));
+	vec3 tangent = normalize(cross(up, bitangent));
+
+    float c = cos(entry.angle);
+    float s = sin(entry.angle);
+    mat3 frame = mat3(c * tangent + s * bitangent, up, c * bitangent - s * tangent);
+
+    position = base + frame * vertex.position * MODEL_SCALE;
+    normal = normalize(frame * vertex.normal);
+    texcoord = vec2(vertex.texcoord_u, 1 - vertex.texcoord_v);
+    ao = vertex.ao;
+    color = entry.albedo;
+
+    gl_Position = globals.view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,38 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 1, std140) readonly buffer Nodes {
+	Node nodes[];
+};
+
+layout(location = 2) in float morph;
+layout(location = 6) in vec2 i_position;
+layout(location = 7) flat in uint instance;
+
+layout(location = 0) out vec4 out_color;
+
+const vec3 LEVEL_COLORS[8] = vec3[8](
+	vec3(1, 0, 0),
+	vec3(0, 1, 0),
+	vec3(0, 0, 1),
+	vec3(0, 1, 1),
+	vec3(1, 1, 0),
+	vec3(1, 0, 1),
+	vec3(1, 1, 1),
+	vec3(1, 0.5, 0)
+);
+
+void main() {
+	Node node = nodes[instance];
+	vec3 color = LEVEL_COLORS[node.level % 8];
+
+	// Highlight patch boundaries, where stitching problems between levels show up.
+	vec2 edge = min(i_position, vec2(64.0) - i_position);
+	if (min(edge.x, edge.y) < 0.5) {
+		out_color = vec4(1, 1, 1, 1);
+		return;
+	}
+
+	// Vertices that have morphed toward the parent grid are drawn darker.
+	out_color = vec4(color * mix(0.3, 1.0, morph), 0.6);
+}
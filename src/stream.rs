@@ -1,189 +1,693 @@
 use crate::cache::layer::LayerType;
+use crate::cache::CpuHeightmap;
+use crate::flat::FlatMap;
 use crate::mapfile::MapFile;
+use crate::procedural::ProceduralPlanet;
+use crate::telemetry;
 use anyhow::Error;
 use futures::{FutureExt, StreamExt};
+use instant::Instant;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::{Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
 use std::thread;
-use std::time::Instant;
+use std::time::Duration;
 use terra_types::VNode;
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch, Semaphore};
+use tracing::Instrument;
 use vec_map::VecMap;
 use zip::result::ZipError;
+use zip::CompressionMethod;
 
-#[derive(Debug)]
+/// A decoded tile, ready to be uploaded as is.
 pub(crate) struct TileResult {
     pub node: VNode,
     pub layers: VecMap<Vec<u8>>,
+    pub heightmap: CpuHeightmap,
+    /// Why loading the tile failed, if it was replaced by an empty tile.
+    pub error: Option<String>,
+}
+
+/// Where streamed tiles come from: the map file, along with anything that alters its tiles.
+#[derive(Clone)]
+pub(crate) struct TileSource {
+    pub mapfile: Arc<MapFile>,
+    /// Replaces the heights of the tiles it covers.
+    pub flat_map: Option<Arc<FlatMap>>,
+    /// Generates every tile in place of the map file.
+    pub procedural_planet: Option<ProceduralPlanet>,
+}
+
+/// Delay before restarting the streamer after its first failure.
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest delay between consecutive restarts of the streamer.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+/// Number of times loading a tile may fail before it is replaced with an empty tile.
+const MAX_TILE_ATTEMPTS: u32 = 5;
+/// Delay before retrying a tile after its first failure, doubled after each further failure.
+const TILE_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+/// Most threads that may be used to decode tiles.
+const MAX_DECODE_THREADS: usize = 4;
+/// How often the streamer checks whether it has been shut down while waiting to restart.
+#[cfg(not(target_arch = "wasm32"))]
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Number of downloaded tiles that may be waiting for or undergoing decoding at once. Downloads
+/// that complete while the budget is exhausted wait before their contents are handed over.
+const DECODE_BUDGET: usize = 16;
+
+/// Runs the CPU heavy part of loading tiles: unpacking them and decompressing their zstd
+/// supercompressed KTX2 layers. Layers are stored in the formats they are uploaded in, so there
+/// is nothing to transcode.
+///
+/// Natively decoding gets its own small pool, so that a burst of tiles can neither hold up other
+/// work on the global rayon pool nor take over every core while frames are being rendered. On the
+/// web there are no threads to spare, so tiles are decoded in place.
+#[derive(Clone)]
+struct Decoder {
+    #[cfg(not(target_arch = "wasm32"))]
+    pool: Arc<rayon::ThreadPool>,
+}
+impl Decoder {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn new() -> Result<Self, Error> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(
+                thread::available_parallelism()
+                    .map(|n| (n.get() / 2).clamp(1, MAX_DECODE_THREADS))
+                    .unwrap_or(1),
+            )
+            .thread_name(|i| format!("tile-decoder{}", i))
+            .build()?;
+        Ok(Self { pool: Arc::new(pool) })
+    }
+    #[cfg(target_arch = "wasm32")]
+    fn new() -> Result<Self, Error> {
+        Ok(Self {})
+    }
+
+    /// Run `f`, turning any panic into an error.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn decode<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let (tx, rx) = oneshot::channel();
+        let span = tracing::Span::current();
+        self.pool.spawn(move || {
+            let _span = span.entered();
+            // Panics would otherwise abort the whole process from within the pool.
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+        rx.await?
+            .map_err(|e| anyhow::format_err!("parsing tile panicked: {}", panic_message(&*e)))?
+    }
+    #[cfg(target_arch = "wasm32")]
+    async fn decode<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        panic::catch_unwind(AssertUnwindSafe(f))
+            .map_err(|e| anyhow::format_err!("parsing tile panicked: {}", panic_message(&*e)))?
+    }
+}
+
+/// Wait for `duration` without blocking other tasks.
+#[cfg(not(target_arch = "wasm32"))]
+async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+#[cfg(target_arch = "wasm32")]
+async fn sleep(duration: Duration) {
+    gloo_timers::future::sleep(duration).await
 }
 
 pub(crate) struct TileStreamerEndpoint {
     sender: UnboundedSender<(VNode, Instant)>,
     receiver: crossbeam::channel::Receiver<TileResult>,
+    /// Tells the streamer to stop, abandoning any tiles in flight.
+    shutdown: watch::Sender<bool>,
+    /// Completes once the streamer has stopped.
+    stopped: Option<oneshot::Receiver<()>>,
+    #[cfg(not(target_arch = "wasm32"))]
     join_handle: Option<thread::JoinHandle<()>>,
-    num_inflight: usize,
+    /// Requested tiles that haven't been returned yet.
+    outstanding: Vec<VNode>,
+
+    source: TileSource,
+    decoder: Decoder,
 }
 impl TileStreamerEndpoint {
-    pub(crate) fn new(
-        mapfile: Arc<MapFile>,
-        transcode_format: wgpu::TextureFormat,
-    ) -> Result<Self, Error> {
+    pub(crate) fn new(source: TileSource) -> Result<Self, Error> {
+        let decoder = Decoder::new()?;
+        let (sender, receiver, shutdown, streamer) =
+            Self::channels(source.clone(), decoder.clone());
+        let (stopped_tx, stopped) = oneshot::channel();
+        #[cfg(not(target_arch = "wasm32"))]
+        let join_handle = Some(Self::spawn(streamer, stopped_tx)?);
+        #[cfg(target_arch = "wasm32")]
+        Self::spawn(streamer, stopped_tx);
+        Ok(Self {
+            sender,
+            receiver,
+            shutdown,
+            stopped: Some(stopped),
+            #[cfg(not(target_arch = "wasm32"))]
+            join_handle,
+            outstanding: Vec::new(),
+            source,
+            decoder,
+        })
+    }
+
+    fn channels(
+        source: TileSource,
+        decoder: Decoder,
+    ) -> (
+        UnboundedSender<(VNode, Instant)>,
+        crossbeam::channel::Receiver<TileResult>,
+        watch::Sender<bool>,
+        TileStreamer,
+    ) {
         let (sender, requests) = unbounded_channel();
         let (results, receiver) = crossbeam::channel::unbounded();
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let streamer = TileStreamer {
+            requests,
+            results,
+            shutdown,
+            // heightmap_tiles: HeightmapCache::new(
+            //     mapfile.layers()[LayerType::Heightmaps].texture_resolution as usize,
+            //     mapfile.layers()[LayerType::Heightmaps].texture_border_size as usize,
+            //     128,
+            // ),
+            source,
+            decoder,
+            decode_budget: Arc::new(Semaphore::new(DECODE_BUDGET)),
+            inflight: Vec::new(),
+            failed_attempts: HashMap::new(),
+            delivered: false,
+        };
+        (sender, receiver, shutdown_tx, streamer)
+    }
 
-        let rt = Runtime::new()?;
-        let join_handle = Some(thread::spawn(move || {
-            rt.block_on(
-                TileStreamer {
-                    requests,
-                    results,
-                    // heightmap_tiles: HeightmapCache::new(
-                    //     mapfile.layers()[LayerType::Heightmaps].texture_resolution as usize,
-                    //     mapfile.layers()[LayerType::Heightmaps].texture_border_size as usize,
-                    //     128,
-                    // ),
-                    transcode_format,
-                    mapfile,
-                }
-                .run(),
-            )
-            .unwrap();
-        }));
+    /// Run `streamer` on its own thread, signalling `stopped` once it exits.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn(
+        streamer: TileStreamer,
+        stopped: oneshot::Sender<()>,
+    ) -> Result<thread::JoinHandle<()>, Error> {
+        Ok(thread::Builder::new().name("tile-streamer".to_owned()).spawn(move || {
+            streamer.supervise();
+            let _ = stopped.send(());
+        })?)
+    }
 
-        Ok(Self { sender, receiver, join_handle, num_inflight: 0 })
+    /// Run `streamer` on the browser's event loop, signalling `stopped` once it exits.
+    #[cfg(target_arch = "wasm32")]
+    fn spawn(streamer: TileStreamer, stopped: oneshot::Sender<()>) {
+        wasm_bindgen_futures::spawn_local(async move {
+            streamer.supervise().await;
+            let _ = stopped.send(());
+        });
     }
 
     pub(crate) fn request_tile(&mut self, node: VNode) {
-        if let Err(_) = self.sender.send((node, Instant::now())) {
-            // The worker thread has panicked (we still have the sender open, so that cannot be why
-            // it exited).
-            self.join_handle.take().unwrap().join().unwrap();
-            unreachable!("TileStreamer exited without panicking");
+        metrics::increment_counter!(telemetry::TILES_REQUESTED);
+        if self.sender.send((node, Instant::now())).is_err() {
+            // The supervisor itself died, which should never happen. Rather than taking down the
+            // whole renderer, start a new streamer and re-issue all outstanding requests.
+            let (sender, receiver, shutdown, streamer) =
+                Self::channels(self.source.clone(), self.decoder.clone());
+            let (stopped_tx, stopped) = oneshot::channel();
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                if let Err(e) = self.join_handle.take().unwrap().join() {
+                    log::error!("Tile streamer exited unexpectedly: {}", panic_message(&*e));
+                }
+                self.join_handle = Some(
+                    Self::spawn(streamer, stopped_tx).expect("Failed to restart tile streamer"),
+                );
+            }
+            #[cfg(target_arch = "wasm32")]
+            Self::spawn(streamer, stopped_tx);
+            self.sender = sender;
+            self.receiver = receiver;
+            self.shutdown = shutdown;
+            self.stopped = Some(stopped);
+            for &n in self.outstanding.iter().chain(std::iter::once(&node)) {
+                let _ = self.sender.send((n, Instant::now()));
+            }
         }
-        self.num_inflight += 1;
+        self.outstanding.push(node);
+        metrics::gauge!(telemetry::TILES_IN_FLIGHT, self.outstanding.len() as f64);
     }
 
     pub(crate) fn try_complete(&mut self) -> Option<TileResult> {
         if let Ok(result) = self.receiver.try_recv() {
-            self.num_inflight -= 1;
+            if let Some(i) = self.outstanding.iter().position(|&n| n == result.node) {
+                self.outstanding.swap_remove(i);
+            }
+            metrics::gauge!(telemetry::TILES_IN_FLIGHT, self.outstanding.len() as f64);
             Some(result)
         } else {
             None
         }
     }
 
+    pub(crate) fn source(&self) -> &TileSource {
+        &self.source
+    }
+
     pub(crate) fn num_inflight(&self) -> usize {
-        self.num_inflight
+        self.outstanding.len()
+    }
+
+    /// Stop the streamer, abandoning any tiles that are still being downloaded, and wait for it
+    /// to exit. Decoding that has already started runs to completion in the background, but its
+    /// results are dropped.
+    pub(crate) async fn shutdown(&mut self) {
+        let _ = self.shutdown.send(true);
+        if let Some(stopped) = self.stopped.take() {
+            let _ = stopped.await;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(join_handle) = self.join_handle.take() {
+            if let Err(e) = join_handle.join() {
+                log::error!("Tile streamer exited unexpectedly: {}", panic_message(&*e));
+            }
+        }
+        self.outstanding.clear();
+    }
+}
+impl Drop for TileStreamerEndpoint {
+    fn drop(&mut self) {
+        // Let the streamer exit on its own rather than waiting for it.
+        let _ = self.shutdown.send(true);
     }
 }
 
+/// Extract a printable message from a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Decode the only level of a KTX2 file, returning `None` if the file is empty.
+fn decode_nonempty(bytes: Cow<[u8]>) -> Result<Option<Vec<u8>>, Error> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let decode = |e: Error| crate::Error::tag(crate::Error::Decode, e);
+    let reader = ktx2::Reader::new(bytes)?;
+    let level = reader
+        .levels()
+        .next()
+        .ok_or_else(|| decode(anyhow::format_err!("KTX2 file has no levels")))?;
+    Ok(Some(zstd::decode_all(Cursor::new(level)).map_err(|e| decode(e.into()))?))
+}
+
+/// Load only the base heightmap of a tile, without going through the tile streamer. Tiles that
+/// don't exist are treated as being entirely at sea level, just like in the tile cache.
+pub(crate) async fn load_heightmap(source: &TileSource, node: VNode) -> Result<Vec<u16>, Error> {
+    let mut heights = vec![0u16; 521 * 521];
+    if let Some(planet) = &source.procedural_planet {
+        heights = planet.heightmap(node);
+    } else if let Some(raw_data) = source.mapfile.read_tile(node).await? {
+        let mut zip = zip::ZipArchive::new(Cursor::new(&*raw_data))?;
+        let file = TileStreamer::get_file(&mut zip, &raw_data, "heights.ktx2")?
+            .ok_or_else(|| anyhow::format_err!("Tile {} has no heightmap", node))?;
+        if let Some(decoded) = decode_nonempty(file)? {
+            bytemuck::cast_slice_mut(&mut heights).copy_from_slice(&decoded);
+        }
+    }
+    if let Some(flat_map) = &source.flat_map {
+        flat_map.apply(node, &mut heights);
+    }
+    Ok(heights)
+}
+
+/// Error produced while loading a specific tile.
+struct TileError {
+    node: VNode,
+    error: Error,
+}
+
 struct TileStreamer {
     requests: UnboundedReceiver<(VNode, Instant)>,
     results: crossbeam::channel::Sender<TileResult>,
-    transcode_format: wgpu::TextureFormat,
-    mapfile: Arc<MapFile>,
+    /// Becomes true once the streamer should stop.
+    shutdown: watch::Receiver<bool>,
+    source: TileSource,
+    decoder: Decoder,
+    /// Limits the number of tiles queued on `decoder`.
+    decode_budget: Arc<Semaphore>,
+
+    /// Requests that have been received but not yet completed. These are re-issued whenever the
+    /// streamer restarts.
+    inflight: Vec<VNode>,
+    /// Number of times loading each tile has failed.
+    failed_attempts: HashMap<VNode, u32>,
+    /// Whether any tile has been delivered since the streamer last restarted.
+    delivered: bool,
 }
 
 impl TileStreamer {
     fn parse_tile(
         node: VNode,
         bytes: &[u8],
-        _transcode_format: wgpu::TextureFormat,
+        flat_map: Option<&FlatMap>,
     ) -> Result<TileResult, Error> {
         let mut zip = zip::ZipArchive::new(Cursor::new(bytes))?;
-        let mut result = TileResult { node, layers: VecMap::new() };
-
-        let mut get_file = |name| -> Result<Option<Vec<u8>>, Error> {
-            match zip.by_name(name) {
-                Ok(mut file) => {
-                    let mut bytes = Vec::new();
-                    file.read_to_end(&mut bytes)?;
-                    Ok(Some(bytes))
-                }
-                Err(ZipError::FileNotFound) => Ok(None),
-                Err(e) => Err(e.into()),
-            }
-        };
+        let mut layers = VecMap::new();
 
-        let decode_nonempty = |bytes: Vec<u8>| -> Result<Option<Vec<u8>>, Error> {
-            if bytes.is_empty() {
-                Ok(None)
-            } else {
-                Ok(Some(zstd::decode_all(Cursor::new(
-                    &ktx2::Reader::new(bytes)?.levels().next().expect("ktx2 has no levels"),
-                ))?))
-            }
+        let mut get_file = |name| Self::get_file(&mut zip, bytes, name);
+        let missing = |name: &str| {
+            crate::Error::tag(
+                crate::Error::Decode,
+                anyhow::format_err!("Tile {} has no {}", node, name),
+            )
         };
 
-        result.layers.insert(
+        layers.insert(
             LayerType::BaseHeightmaps.index(),
-            decode_nonempty(get_file("heights.ktx2")?.expect("layer missing"))?
+            decode_nonempty(get_file("heights.ktx2")?.ok_or_else(|| missing("heights.ktx2"))?)?
                 .unwrap_or_else(|| vec![0u8; 521 * 521 * 2]),
         );
-        result.layers.insert(
+        layers.insert(
             LayerType::TreeCover.index(),
-            decode_nonempty(get_file("treecover.ktx2")?.expect("layer missing"))?
+            decode_nonempty(get_file("treecover.ktx2")?.ok_or_else(|| missing("treecover.ktx2"))?)?
                 .unwrap_or_else(|| vec![0u8; 516 * 516]),
         );
-        result.layers.insert(
+        layers.insert(
             LayerType::LandFraction.index(),
-            decode_nonempty(get_file("landfraction.ktx2")?.expect("layer missing"))?
-                .unwrap_or_else(|| vec![0u8; 516 * 516]),
+            decode_nonempty(
+                get_file("landfraction.ktx2")?.ok_or_else(|| missing("landfraction.ktx2"))?,
+            )?
+            .unwrap_or_else(|| vec![0u8; 516 * 516]),
+        );
+        // Tiles generated before landcover was added have no classification, which is treated
+        // the same as an unknown class.
+        layers.insert(
+            LayerType::Landcover.index(),
+            match get_file("landcover.ktx2")? {
+                Some(bytes) => decode_nonempty(bytes)?.unwrap_or_else(|| vec![0u8; 516 * 516]),
+                None => vec![0u8; 516 * 516],
+            },
         );
 
         if let Some(bytes) = get_file("waterlevel.ktx2")? {
-            result.layers.insert(
+            layers.insert(
                 LayerType::WaterLevel.index(),
                 decode_nonempty(bytes)?.unwrap_or_else(|| vec![0u8; 521 * 521 * 2]),
             );
         }
         if let Some(bytes) = get_file("albedo.ktx2")? {
-            result.layers.insert(
+            layers.insert(
                 LayerType::BaseAlbedo.index(),
                 decode_nonempty(bytes)?.unwrap_or_else(|| vec![0u8; 516 * 516 * 4]),
             );
         }
 
-        if node.level() == 0 {
-            assert!(result.layers.contains_key(LayerType::BaseHeightmaps.index()));
-            assert!(result.layers.contains_key(LayerType::TreeCover.index()));
-            assert!(result.layers.contains_key(LayerType::LandFraction.index()));
-            assert!(result.layers.contains_key(LayerType::BaseAlbedo.index()));
+        // Albedo is only optional below the root tiles, which everything else is derived from.
+        if node.level() == 0 && !layers.contains_key(LayerType::BaseAlbedo.index()) {
+            return Err(missing("albedo.ktx2"));
+        }
+
+        Ok(Self::finish_tile(node, layers, flat_map))
+    }
+
+    /// Extract the CPU copy of the heightmap, and lay out the layers of a tile the way they are
+    /// uploaded. Heights covered by `flat_map` are replaced first.
+    fn finish_tile(
+        node: VNode,
+        mut layers: VecMap<Vec<u8>>,
+        flat_map: Option<&FlatMap>,
+    ) -> TileResult {
+        let mut heights = vec![0u16; 521 * 521];
+        bytemuck::cast_slice_mut(&mut heights)
+            .copy_from_slice(&layers[LayerType::BaseHeightmaps.index()]);
+        if let Some(flat_map) = flat_map {
+            flat_map.apply(node, &mut heights);
+            layers[LayerType::BaseHeightmaps.index()]
+                .copy_from_slice(bytemuck::cast_slice(&heights));
+        }
+        let heightmap = CpuHeightmap::from_streamed(heights);
+
+        for (layer, data) in &mut layers {
+            let layer = LayerType::from_index(layer);
+            let resolution = layer.texture_resolution() as usize;
+            let block_size = layer.texture_formats()[0].block_size() as usize;
+            assert_eq!(resolution % block_size, 0);
+            let resolution_blocks = resolution / block_size;
+            let bytes_per_block = layer.texture_formats()[0].bytes_per_block();
+
+            if data.is_empty() {
+                data.resize(resolution_blocks * resolution_blocks * bytes_per_block, 0);
+            }
+
+            if cfg!(feature = "small-trace") {
+                for y in 0..resolution_blocks {
+                    for x in 0..resolution_blocks {
+                        if x % 16 == 0 && y % 16 == 0 {
+                            continue;
+                        }
+                        let src = ((x & !15) + (y & !15) * resolution_blocks) * bytes_per_block;
+                        let dst = (x + y * resolution_blocks) * bytes_per_block;
+                        data.copy_within(src..src + bytes_per_block, dst);
+                    }
+                }
+            }
+        }
+
+        TileResult { node, layers, heightmap, error: None }
+    }
+
+    /// Get the contents of a file within a tile. Files stored without compression are borrowed
+    /// directly from `bytes` rather than being copied.
+    fn get_file<'a>(
+        zip: &mut zip::ZipArchive<Cursor<&'a [u8]>>,
+        bytes: &'a [u8],
+        name: &str,
+    ) -> Result<Option<Cow<'a, [u8]>>, Error> {
+        match zip.by_name(name) {
+            Ok(file) if file.compression() == CompressionMethod::Stored => {
+                let start = file.data_start() as usize;
+                let contents = bytes
+                    .get(start..start + file.size() as usize)
+                    .ok_or_else(|| anyhow::format_err!("{} extends past end of tile", name))?;
+                Ok(Some(Cow::Borrowed(contents)))
+            }
+            Ok(mut file) => {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                Ok(Some(Cow::Owned(contents)))
+            }
+            Err(ZipError::FileNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Run the streamer until it is shut down or all request senders are dropped, restarting it
+    /// with exponential backoff whenever it fails or panics. Tiles that fail to load are retried
+    /// by `run` itself, so restarts are reserved for problems affecting the whole streamer.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn supervise(mut self) {
+        let mut backoff = INITIAL_RESTART_BACKOFF;
+        loop {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                Runtime::new().map_err(Error::from).and_then(|rt| rt.block_on(self.run()))
+            }));
+
+            match result {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => log::error!("Tile streamer failed: {:?}", e),
+                Err(e) => log::error!(
+                    "Tile streamer panicked with {} tiles in flight ({:?}): {}",
+                    self.inflight.len(),
+                    self.inflight,
+                    panic_message(&*e)
+                ),
+            }
+
+            let delay = self.next_backoff(&mut backoff);
+            let start = Instant::now();
+            while start.elapsed() < delay && !*self.shutdown.borrow() {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Run the streamer on the browser's event loop until it is shut down or all request senders
+    /// are dropped, restarting it with exponential backoff whenever it fails. Panics can't be recovered from on
+    /// the web, so unlike natively they aren't caught.
+    #[cfg(target_arch = "wasm32")]
+    async fn supervise(mut self) {
+        let mut backoff = INITIAL_RESTART_BACKOFF;
+        loop {
+            match self.run().await {
+                Ok(()) => return,
+                Err(e) => log::error!("Tile streamer failed: {:?}", e),
+            }
+            let delay = gloo_timers::future::sleep(self.next_backoff(&mut backoff));
+            let mut shutdown = self.shutdown.clone();
+            futures::select! {
+                () = delay.fuse() => {},
+                _ = shutdown.changed().fuse() => {},
+            }
+        }
+    }
+
+    /// Returns how long to wait before restarting after a failure, and advances `backoff`.
+    fn next_backoff(&mut self, backoff: &mut Duration) -> Duration {
+        if self.delivered {
+            *backoff = INITIAL_RESTART_BACKOFF;
         }
+        self.delivered = false;
+        metrics::increment_counter!(telemetry::STREAMER_RESTARTS);
+        log::warn!("Restarting tile streamer in {:?}", backoff);
+        let delay = *backoff;
+        *backoff = (*backoff * 2).min(MAX_RESTART_BACKOFF);
+        delay
+    }
 
-        Ok(result)
+    fn empty_tile(node: VNode, flat_map: Option<&FlatMap>) -> TileResult {
+        let mut layers = VecMap::new();
+        layers.insert(
+            LayerType::BaseHeightmaps.index(),
+            bytemuck::cast_slice(&vec![0u16; 521 * 521]).to_vec(),
+        );
+        layers.insert(LayerType::TreeCover.index(), vec![0u8; 516 * 516]);
+        layers.insert(LayerType::LandFraction.index(), vec![0u8; 516 * 516]);
+        layers.insert(LayerType::Landcover.index(), vec![0u8; 516 * 516]);
+        Self::finish_tile(node, layers, flat_map)
     }
 
-    async fn run(self) -> Result<(), Error> {
-        let TileStreamer { mut requests, results, mapfile, transcode_format } = self;
-        let mapfile = &*mapfile;
+    async fn load_tile(
+        source: TileSource,
+        decoder: Decoder,
+        decode_budget: Arc<Semaphore>,
+        node: VNode,
+    ) -> Result<TileResult, Error> {
+        // Procedural tiles are generated from scratch, so there is nothing to download.
+        let raw_data = match source.procedural_planet {
+            Some(_) => None,
+            None => source.mapfile.read_tile(node).await?,
+        };
 
+        let _permit = decode_budget.acquire_owned().await?;
+        decoder
+            .decode(move || {
+                let _span = tracing::debug_span!("decode_tile").entered();
+                let flat_map = source.flat_map.as_deref();
+                match (source.procedural_planet, raw_data) {
+                    (Some(planet), _) => {
+                        Ok(Self::finish_tile(node, planet.generate_tile(node), flat_map))
+                    }
+                    (None, Some(raw_data)) => Self::parse_tile(node, &raw_data, flat_map),
+                    (None, None) => Ok(Self::empty_tile(node, flat_map)),
+                }
+            })
+            .await
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
         let mut pending = futures::stream::futures_unordered::FuturesUnordered::new();
+        let source = self.source.clone();
+        let decoder = self.decoder.clone();
+        let decode_budget = self.decode_budget.clone();
+        let start_load = |node: VNode, delay: Duration| {
+            let source = source.clone();
+            let decoder = decoder.clone();
+            let decode_budget = decode_budget.clone();
+            async move {
+                if delay > Duration::ZERO {
+                    sleep(delay).await;
+                }
+                Self::load_tile(source, decoder, decode_budget, node)
+                    .await
+                    .map_err(|error| TileError { node, error })
+            }
+            .instrument(tracing::debug_span!("load_tile", %node))
+        };
+
+        let mut shutdown = self.shutdown.clone();
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+
+        // Resume any requests that were interrupted by a previous failure.
+        for &node in &self.inflight {
+            pending.push(start_load(node, Duration::ZERO));
+        }
+
         loop {
             futures::select! {
-                tile_result = pending.select_next_some() => {
-                    results.send(tile_result?)?;
-                },
-                node = requests.recv().fuse() => if let Some((node, _start)) = node {
-                    pending.push(async move {
-                        match mapfile.read_tile(node).await? {
-                            Some(raw_data) => {
-                                tokio::task::spawn_blocking(move || Self::parse_tile(node, &raw_data, transcode_format)).await.unwrap()
-                            }
-                            None => {
-                                let mut result = TileResult {
-                                    node,
-                                    layers: VecMap::new(),
-                                };
-                                result.layers.insert(LayerType::BaseHeightmaps.index(), bytemuck::cast_slice(&vec![0u16; 521 * 521]).to_vec());
-                                result.layers.insert(LayerType::TreeCover.index(), vec![0u8; 516 * 516]);
-                                result.layers.insert(LayerType::LandFraction.index(), vec![0u8; 516 * 516]);
-                                Ok(result)
-                            }
+                tile_result = pending.select_next_some() => match tile_result {
+                    Ok(tile) => {
+                        if let Some(i) = self.inflight.iter().position(|&n| n == tile.node) {
+                            self.inflight.swap_remove(i);
+                        }
+                        self.failed_attempts.remove(&tile.node);
+                        self.delivered = true;
+                        metrics::increment_counter!(telemetry::TILES_STREAMED);
+                        self.results.send(tile)?;
+                    }
+                    Err(TileError { node, error }) => {
+                        metrics::increment_counter!(telemetry::TILE_FAILURES);
+                        let attempts = self.failed_attempts.entry(node).or_insert(0);
+                        *attempts += 1;
+                        if *attempts < MAX_TILE_ATTEMPTS {
+                            // Retry just this tile, leaving every other load undisturbed.
+                            let delay = TILE_RETRY_BACKOFF * 2u32.pow(*attempts - 1);
+                            log::warn!(
+                                "Failed to load tile {} (attempt {}), retrying in {:?}: {:?}",
+                                node,
+                                attempts,
+                                delay,
+                                error
+                            );
+                            pending.push(start_load(node, delay));
+                            continue;
                         }
-                    }.boxed());
+
+                        // Give up on this tile rather than failing forever.
+                        log::error!(
+                            "Failed to load tile {} after {} attempts, using empty tile: {:?}",
+                            node,
+                            attempts,
+                            error
+                        );
+                        self.failed_attempts.remove(&node);
+                        if let Some(i) = self.inflight.iter().position(|&n| n == node) {
+                            self.inflight.swap_remove(i);
+                        }
+                        let mut tile = Self::empty_tile(node, self.source.flat_map.as_deref());
+                        tile.error = Some(format!("{:#}", error));
+                        metrics::increment_counter!(telemetry::TILES_STREAMED);
+                        self.results.send(tile)?;
+                    }
+                },
+                node = self.requests.recv().fuse() => match node {
+                    Some((node, _start)) => {
+                        self.inflight.push(node);
+                        pending.push(start_load(node, Duration::ZERO));
+                    }
+                    None => break,
                 },
+                // Dropping the pending loads cancels any downloads in progress.
+                _ = shutdown.changed().fuse() => break,
                 complete => break,
             }
         }
@@ -0,0 +1,46 @@
+//! Metrics reported through the `metrics` facade. They go nowhere until the application installs
+//! a recorder, such as the Prometheus exporter from `metrics-exporter-prometheus`.
+//!
+//! Spans around streaming, decoding, tile generation and rendering are emitted with `tracing`
+//! at the debug level, so they can be collected by any `tracing` subscriber.
+
+/// Tiles requested from the tile streamer.
+pub(crate) const TILES_REQUESTED: &str = "terra_tiles_requested_total";
+/// Tiles that finished streaming, including ones replaced by empty tiles after failing.
+pub(crate) const TILES_STREAMED: &str = "terra_tiles_streamed_total";
+/// Attempts to load a tile that failed.
+pub(crate) const TILE_FAILURES: &str = "terra_tile_failures_total";
+/// Bytes downloaded from tile servers.
+pub(crate) const BYTES_DOWNLOADED: &str = "terra_downloaded_bytes_total";
+/// Times the tile streamer restarted after failing.
+pub(crate) const STREAMER_RESTARTS: &str = "terra_streamer_restarts_total";
+/// Tiles that have been requested from the streamer but haven't arrived yet.
+pub(crate) const TILES_IN_FLIGHT: &str = "terra_tiles_in_flight";
+/// Tiles generated on the GPU, labeled by generator.
+pub(crate) const TILES_GENERATED: &str = "terra_tiles_generated_total";
+/// Nodes evicted from the tile cache.
+pub(crate) const NODES_EVICTED: &str = "terra_nodes_evicted_total";
+/// Frames rendered.
+pub(crate) const FRAMES_RENDERED: &str = "terra_frames_rendered_total";
+
+/// Register descriptions and units of the metrics that Terra reports with the installed `metrics`
+/// recorder, so that exporters can include them. Should be called after installing the recorder.
+///
+/// The counters are `terra_tiles_requested_total`, `terra_tiles_streamed_total`,
+/// `terra_tile_failures_total`, `terra_downloaded_bytes_total`, `terra_streamer_restarts_total`,
+/// `terra_tiles_generated_total` (labeled by `generator`), `terra_nodes_evicted_total` and
+/// `terra_frames_rendered_total`. The gauge `terra_tiles_in_flight` counts tiles requested
+/// from the streamer that haven't arrived yet.
+pub fn describe_metrics() {
+    use metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_counter!(TILES_REQUESTED, Unit::Count, "Tiles requested from the tile streamer");
+    describe_counter!(TILES_STREAMED, Unit::Count, "Tiles that finished streaming");
+    describe_counter!(TILE_FAILURES, Unit::Count, "Attempts to load a tile that failed");
+    describe_counter!(BYTES_DOWNLOADED, Unit::Bytes, "Bytes downloaded from tile servers");
+    describe_counter!(STREAMER_RESTARTS, Unit::Count, "Restarts of the tile streamer");
+    describe_gauge!(TILES_IN_FLIGHT, Unit::Count, "Tiles requested that haven't arrived yet");
+    describe_counter!(TILES_GENERATED, Unit::Count, "Tiles generated on the GPU");
+    describe_counter!(NODES_EVICTED, Unit::Count, "Nodes evicted from the tile cache");
+    describe_counter!(FRAMES_RENDERED, Unit::Count, "Frames rendered");
+}
@@ -0,0 +1,248 @@
+//! Instanced 3D models for the trees closest to the camera.
+//!
+//! Trees are generated as billboards, so models are drawn for the same instances: after the tree
+//! billboards are culled, a compute shader turns each of their draws that is within
+//! `TREE_MODEL_FADE_END` of the camera into an instanced draw of every species' model. Vertex
+//! shaders then drop the instances of other species, and both layers are dithered over the fade
+//! range so they cross-fade into each other.
+
+use crate::billboards::Models;
+use crate::cache::layer::MeshType;
+use crate::cache::TileCache;
+use crate::compute_shader::ComputeShader;
+use crate::gpu_state::{DrawIndexedIndirect, GpuState};
+use crate::postprocess::{TargetConfig, HDR_FORMAT};
+use crate::resources::{texture_bytes, ResourceKind, Tracked};
+use maplit::hashmap;
+use std::mem;
+use std::ops::Range;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct TreeModelUniforms {
+    base_entry: u32,
+    num_entries: u32,
+    output_base_entry: u32,
+    first_index: u32,
+    index_count: u32,
+}
+unsafe impl bytemuck::Zeroable for TreeModelUniforms {}
+unsafe impl bytemuck::Pod for TreeModelUniforms {}
+
+struct Species {
+    vertices: Tracked<wgpu::Buffer>,
+    indices: Tracked<wgpu::Buffer>,
+    albedo: (Tracked<wgpu::Texture>, wgpu::TextureView),
+    lod: Range<u32>,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+}
+
+pub(crate) struct TreeModels {
+    species: Vec<Species>,
+    shader: rshader::ShaderSet,
+    gen_indirect: ComputeShader<TreeModelUniforms>,
+    /// Entries of `mesh_indirect` holding the tree billboard draws.
+    billboard_entries: Range<usize>,
+    target_config: TargetConfig,
+}
+impl TreeModels {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        cache: &TileCache,
+        models: &Models,
+    ) -> Result<Self, anyhow::Error> {
+        let mut species = Vec::new();
+        for i in 0..models.species() {
+            let (vertices, indices) = models.make_species_buffers(device, i);
+            let albedo = models.make_species_albedo(device, queue, i)?;
+            let view = albedo.create_view(&Default::default());
+            let token = gpu_state.resources.track(
+                ResourceKind::Texture,
+                "tree_models",
+                texture_bytes(&albedo),
+            );
+            species.push(Species {
+                vertices: track_buffer(gpu_state, vertices),
+                indices: track_buffer(gpu_state, indices),
+                albedo: (Tracked::new(albedo, token), view),
+                lod: models.species_lod(i),
+                bindgroup_pipeline: None,
+            });
+        }
+
+        Ok(Self {
+            species,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "tree-model.vert", "declarations.glsl"),
+                rshader::shader_source!(
+                    "shaders",
+                    "tree-model.frag",
+                    "declarations.glsl",
+                    "pbr.glsl",
+                    "fog.glsl"
+                ),
+            )
+            .map_err(|e| crate::Error::tag(crate::Error::Shader, e))?,
+            gen_indirect: ComputeShader::new(
+                rshader::shader_source!("shaders", "gen-tree-models.comp", "declarations.glsl"),
+                "gen-tree-models".to_owned(),
+            ),
+            billboard_entries: cache.mesh_entries(MeshType::TreeBillboards),
+            target_config: TargetConfig::default(),
+        })
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        target_config: TargetConfig,
+    ) {
+        self.gen_indirect.refresh(device, gpu_state);
+        if self.shader.refresh() || self.target_config != target_config {
+            self.target_config = target_config;
+            for species in &mut self.species {
+                species.bindgroup_pipeline = None;
+            }
+        }
+
+        for (i, species) in self.species.iter_mut().enumerate() {
+            if species.bindgroup_pipeline.is_some() {
+                continue;
+            }
+
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                hashmap!["tree_model_vertices".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &species.vertices,
+                    offset: 0,
+                    size: None,
+                }))],
+                hashmap!["tree_model_albedo".into() => &species.albedo.1],
+                &format!("tree_model.{}", i),
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[wgpu::PushConstantRange {
+                        stages: wgpu::ShaderStages::VERTEX,
+                        range: 0..8,
+                    }],
+                    label: Some("pipeline.tree_model.layout"),
+                });
+            species.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tree_model.vertex"),
+                            source: self.shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.tree_model.fragment"),
+                            source: self.shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: Some(wgpu::BlendState {
+                                color: wgpu::BlendComponent::REPLACE,
+                                alpha: wgpu::BlendComponent::REPLACE,
+                            }),
+                            write_mask: wgpu::ColorWrites::ALL,
+                        })],
+                    }),
+                    primitive: wgpu::PrimitiveState { cull_mode: None, ..Default::default() },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_write_enabled: true,
+                        depth_compare: target_config.depth_compare(false),
+                        bias: Default::default(),
+                        stencil: Default::default(),
+                    }),
+                    multisample: target_config.multisample(),
+                    multiview: None,
+                    label: Some("pipeline.tree_model"),
+                }),
+            ));
+        }
+    }
+
+    /// Build the draws for the current frame. Must run after the tree billboards have been culled
+    /// for the main view.
+    pub fn prepare(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+    ) {
+        let num_entries = self.billboard_entries.len() as u32;
+        for (i, species) in self.species.iter().enumerate() {
+            self.gen_indirect.run(
+                device,
+                encoder,
+                gpu_state,
+                ((num_entries + 63) / 64, 1, 1),
+                &TreeModelUniforms {
+                    base_entry: self.billboard_entries.start as u32,
+                    num_entries,
+                    output_base_entry: i as u32 * num_entries,
+                    first_index: species.lod.start,
+                    index_count: species.lod.end - species.lod.start,
+                },
+            );
+        }
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        let num_entries = self.billboard_entries.len();
+        for (i, species) in self.species.iter().enumerate() {
+            let (bind_group, pipeline) = match species.bindgroup_pipeline {
+                Some((ref bind_group, ref pipeline)) => (bind_group, pipeline),
+                None => continue,
+            };
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, bind_group, &[]);
+            rpass.set_index_buffer(species.indices.slice(..), wgpu::IndexFormat::Uint32);
+            rpass.set_push_constants(
+                wgpu::ShaderStages::VERTEX,
+                0,
+                bytemuck::cast_slice(&[i as u32, self.species.len() as u32]),
+            );
+
+            let base_offset = (i * num_entries * mem::size_of::<DrawIndexedIndirect>()) as u64;
+            if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
+                rpass.multi_draw_indexed_indirect(
+                    &gpu_state.tree_model_indirect,
+                    base_offset,
+                    num_entries as u32,
+                );
+            } else {
+                for j in 0..num_entries {
+                    rpass.draw_indexed_indirect(
+                        &gpu_state.tree_model_indirect,
+                        base_offset + (j * mem::size_of::<DrawIndexedIndirect>()) as u64,
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn track_buffer(gpu_state: &GpuState, buffer: wgpu::Buffer) -> Tracked<wgpu::Buffer> {
+    let token = gpu_state.resources.track(ResourceKind::Buffer, "tree_models", buffer.size());
+    Tracked::new(buffer, token)
+}
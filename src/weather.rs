@@ -0,0 +1,333 @@
+//! Precipitation particles and the wetness and snow cover of the terrain.
+
+use crate::cache::TileCache;
+use crate::compute_shader::ComputeShader;
+use crate::gpu_state::{GpuState, WeatherUniformBlock};
+use crate::postprocess::{TargetConfig, HDR_FORMAT};
+use crate::resources::Tracked;
+use cgmath::{InnerSpace, Vector3};
+use instant::Instant;
+use std::collections::HashMap;
+use terra_types::VNode;
+
+/// Maximum number of precipitation particles, all of which are drawn at full intensity.
+pub(crate) const MAX_PARTICLES: usize = 65536;
+/// Size in bytes of each particle in the particle buffer.
+pub(crate) const PARTICLE_SIZE: usize = 32;
+/// Number of terrain height samples along each side of the grid used for collisions.
+///
+/// Particles collide with heights sampled on the CPU rather than with the depth buffer. The
+/// particle volume surrounds the camera, so most of it is behind or beside the view where the
+/// depth buffer has nothing, and the depth buffer can't be read at all while MSAA is enabled or
+/// when the caller didn't create it with `TEXTURE_BINDING`. The ground beneath an 80 m wide box
+/// varies slowly enough that a 16x16 grid, refreshed every frame from the tiles already resident
+/// on the CPU, is indistinguishable from per-pixel collisions once particles are in motion.
+pub(crate) const GROUND_SAMPLES: usize = 16;
+
+/// Horizontal distance in meters from the camera to the edge of the particle volume.
+const BOX_RADIUS: f32 = 40.0;
+/// Vertical distance in meters from the camera to the top and bottom of the particle volume.
+const BOX_HEIGHT: f32 = 30.0;
+
+/// Type of precipitation to simulate.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PrecipitationKind {
+    #[default]
+    Rain = 0,
+    Snow = 1,
+}
+impl PrecipitationKind {
+    /// Typical terminal velocity in meters per second.
+    fn fall_speed(&self) -> f32 {
+        match self {
+            PrecipitationKind::Rain => 9.0,
+            PrecipitationKind::Snow => 1.0,
+        }
+    }
+}
+
+/// Falling rain or snow, simulated on the GPU in a volume that follows the camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Precipitation {
+    pub kind: PrecipitationKind,
+    /// Fraction of the maximum particle count to draw, from zero to one.
+    pub intensity: f32,
+    /// Wind velocity in ECEF coordinates, in meters per second.
+    pub wind: mint::Vector3<f32>,
+}
+impl Default for Precipitation {
+    fn default() -> Self {
+        Self {
+            kind: PrecipitationKind::default(),
+            intensity: 0.5,
+            wind: mint::Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+        }
+    }
+}
+
+/// How much water and snow has accumulated on the terrain. This is independent of any falling
+/// precipitation, so the ground can stay wet after rain stops.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SurfaceConditions {
+    /// From zero for dry ground to one for soaked ground, which is darker and smoother.
+    pub wetness: f32,
+    /// From zero for no snow to one for snow on all but the steepest slopes.
+    pub snow_cover: f32,
+}
+
+/// Local east, north and up directions at `position`.
+pub(crate) fn local_frame(position: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+    let up = position.normalize();
+    let east = if up.x.abs() < 1e-6 && up.y.abs() < 1e-6 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_z().cross(up).normalize()
+    };
+    (east, up.cross(east), up)
+}
+
+pub(crate) struct Weather {
+    precipitation: Option<Precipitation>,
+    surface: SurfaceConditions,
+
+    /// Camera position and time of the last update while precipitation was enabled.
+    last_update: Option<(Vector3<f64>, Instant)>,
+    reset: bool,
+    time: f32,
+
+    simulate: ComputeShader<()>,
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(Tracked<wgpu::BindGroup>, wgpu::RenderPipeline)>,
+    target_config: TargetConfig,
+}
+impl Weather {
+    pub fn new() -> Self {
+        Self {
+            precipitation: None,
+            surface: SurfaceConditions::default(),
+            last_update: None,
+            reset: true,
+            time: 0.0,
+            simulate: ComputeShader::new(
+                rshader::shader_source!(
+                    "shaders",
+                    "precipitation.comp",
+                    "declarations.glsl",
+                    "hash.glsl"
+                ),
+                "precipitation".to_owned(),
+            ),
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "precipitation.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "precipitation.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            target_config: TargetConfig::default(),
+        }
+    }
+
+    pub fn set_precipitation(&mut self, precipitation: Option<Precipitation>) {
+        if precipitation.map(|p| p.kind) != self.precipitation.map(|p| p.kind) {
+            self.reset = true;
+        }
+        self.precipitation = precipitation;
+    }
+
+    pub fn set_surface_conditions(&mut self, surface: SurfaceConditions) {
+        self.surface = surface;
+    }
+
+    /// Number of particles to simulate and draw.
+    fn particle_count(&self) -> u32 {
+        match self.precipitation {
+            Some(p) => (p.intensity.clamp(0.0, 1.0) * MAX_PARTICLES as f32) as u32,
+            None => 0,
+        }
+    }
+
+    /// Write the weather uniforms for the frame, sampling the terrain around `camera` for
+    /// particles to collide with.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        cache: &TileCache,
+        camera: Vector3<f64>,
+        target_config: TargetConfig,
+    ) {
+        let (east, north, up) = local_frame(camera);
+        let mut block = WeatherUniformBlock {
+            wind: [0.0; 3],
+            intensity: 0.0,
+            up: up.cast().unwrap().into(),
+            fall_speed: 0.0,
+            east: east.cast().unwrap().into(),
+            wetness: self.surface.wetness.clamp(0.0, 1.0),
+            north: north.cast().unwrap().into(),
+            snow_cover: self.surface.snow_cover.clamp(0.0, 1.0),
+            camera_delta: [0.0; 3],
+            time_step: 0.0,
+            kind: 0,
+            reset: 0,
+            time: 0.0,
+            box_radius: BOX_RADIUS,
+            box_height: BOX_HEIGHT,
+            ground_resolution: GROUND_SAMPLES as u32,
+            _padding: [0.0; 2],
+            ground: [[0.0; 4]; GROUND_SAMPLES * GROUND_SAMPLES / 4],
+        };
+
+        match self.precipitation {
+            Some(precipitation) if self.particle_count() > 0 => {
+                let now = Instant::now();
+                let (camera_delta, time_step) = match self.last_update {
+                    Some((last_camera, last_time)) => {
+                        (camera - last_camera, now.duration_since(last_time).as_secs_f32().min(0.1))
+                    }
+                    None => (Vector3::new(0.0, 0.0, 0.0), 0.0),
+                };
+                self.last_update = Some((camera, now));
+                self.time = (self.time + time_step) % 3600.0;
+
+                // Teleporting the camera would otherwise leave a visible gap in the particles.
+                if camera_delta.magnitude() > BOX_RADIUS as f64 {
+                    self.reset = true;
+                }
+
+                block.wind = precipitation.wind.into();
+                block.intensity = precipitation.intensity.clamp(0.0, 1.0);
+                block.fall_speed = precipitation.kind.fall_speed();
+                block.camera_delta = camera_delta.cast().unwrap().into();
+                block.time_step = time_step;
+                block.kind = precipitation.kind as u32;
+                block.reset = self.reset as u32;
+                block.time = self.time;
+
+                let camera_altitude = terra_types::altitude(camera);
+                for j in 0..GROUND_SAMPLES {
+                    for i in 0..GROUND_SAMPLES {
+                        let offset = |k| {
+                            BOX_RADIUS as f64 * (2.0 * k as f64 / (GROUND_SAMPLES - 1) as f64 - 1.0)
+                        };
+                        let position = camera + east * offset(i) + north * offset(j);
+                        let height = cache
+                            .terrain_point(position, VNode::LEVEL_CELL_1M)
+                            .map(|p| (terra_types::altitude(p) - camera_altitude) as f32)
+                            .unwrap_or(-2.0 * BOX_HEIGHT);
+                        let index = j * GROUND_SAMPLES + i;
+                        block.ground[index / 4][index % 4] = height;
+                    }
+                }
+                self.reset = false;
+            }
+            _ => {
+                self.last_update = None;
+                self.reset = true;
+            }
+        }
+        queue.write_buffer(&gpu_state.weather, 0, bytemuck::bytes_of(&block));
+
+        self.simulate.refresh(device, gpu_state);
+        if self.shader.refresh() || self.target_config != target_config {
+            self.bindgroup_pipeline = None;
+            self.target_config = target_config;
+        }
+        if self.bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                device,
+                &self.shader,
+                HashMap::new(),
+                HashMap::new(),
+                "precipitation",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.precipitation.layout"),
+                });
+            self.bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.precipitation.vertex"),
+                            source: self.shader.vertex(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.precipitation.fragment"),
+                            source: self.shader.fragment(),
+                        }),
+                        entry_point: "main",
+                        targets: &[Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                            // Leave alpha alone so it still marks where the sky is visible.
+                            write_mask: wgpu::ColorWrites::COLOR,
+                        })],
+                    }),
+                    primitive: Default::default(),
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_compare: target_config.depth_compare(false),
+                        depth_write_enabled: false,
+                        bias: Default::default(),
+                        stencil: Default::default(),
+                    }),
+                    multisample: target_config.multisample(),
+                    multiview: None,
+                    label: Some("pipeline.precipitation"),
+                }),
+            ));
+        }
+    }
+
+    /// Advance the particles by the time step passed to the last `update`.
+    pub fn simulate(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+    ) {
+        if self.particle_count() > 0 {
+            let workgroups = (MAX_PARTICLES as u32 + 63) / 64;
+            self.simulate.run(device, encoder, gpu_state, (workgroups, 1, 1), &());
+        }
+    }
+
+    /// Draw the particles, which must come after all opaque geometry in the pass.
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        let count = self.particle_count();
+        if count > 0 {
+            let (bind_group, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, bind_group, &[]);
+            rpass.draw(0..count * 6, 0..1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_frame_is_orthonormal() {
+        for position in [
+            Vector3::new(6378137.0, 0.0, 0.0),
+            Vector3::new(1000.0, -4000.0, 6000.0),
+            Vector3::new(0.0, 0.0, 6356752.0),
+        ] {
+            let (east, north, up) = local_frame(position);
+            assert!((east.cross(north) - up).magnitude() < 1e-9);
+            assert!(east.dot(up).abs() < 1e-9 && north.dot(up).abs() < 1e-9);
+        }
+    }
+}
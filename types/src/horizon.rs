@@ -0,0 +1,76 @@
+use crate::{BODY, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS};
+use cgmath::*;
+
+/// Height in meters above sea level of the tallest terrain on the planet. Terrain beyond the
+/// horizon may still be visible up to the point where it is hidden even at this height.
+pub const MAX_TERRAIN_HEIGHT: f64 = BODY.max_terrain_height();
+/// Bound in meters on how far terrain dips below the polar radius. See `Body::min_terrain_height`.
+pub const MIN_TERRAIN_HEIGHT: f64 = BODY.min_terrain_height();
+
+/// Bounds on the recommended near plane distance, in meters.
+const MIN_NEAR_PLANE: f64 = 0.1;
+const MAX_NEAR_PLANE: f64 = 1000.0;
+/// Fraction of the height above the terrain to use as the near plane distance.
+const NEAR_PLANE_FRACTION: f64 = 0.25;
+
+/// Distance in meters to the horizon when viewed from `altitude` meters above sea level, ignoring
+/// terrain. The equatorial radius is used, so this never underestimates the distance.
+pub fn horizon_distance(altitude: f64) -> f64 {
+    let altitude = altitude.max(0.0);
+    (altitude * (2.0 * PLANET_SEMIMAJOR_AXIS + altitude)).sqrt()
+}
+
+/// Distance beyond which no terrain can be seen from `altitude` meters above sea level: the
+/// camera's own horizon distance plus that of the tallest possible mountain behind it.
+pub fn max_visible_distance(altitude: f64) -> f64 {
+    horizon_distance(altitude) + horizon_distance(MAX_TERRAIN_HEIGHT)
+}
+
+/// Recommended near and far plane distances for a camera `altitude` meters above sea level, and
+/// `height_above_terrain` meters above the ground directly below it.
+///
+/// The far plane is placed just past the farthest visible terrain, so nothing on the planet is
+/// clipped. The near plane is pushed out as the camera climbs to improve depth precision.
+pub fn clip_planes(altitude: f64, height_above_terrain: f64) -> (f64, f64) {
+    let near = (height_above_terrain * NEAR_PLANE_FRACTION).clamp(MIN_NEAR_PLANE, MAX_NEAR_PLANE);
+    (near, max_visible_distance(altitude).max(near * 2.0))
+}
+
+/// Approximate altitude above the ellipsoid of a position given in ECEF coordinates.
+pub fn altitude(position: Vector3<f64>) -> f64 {
+    let distance = position.magnitude();
+    if distance == 0.0 {
+        return -PLANET_SEMIMINOR_AXIS;
+    }
+    let d = position / distance;
+    let surface = 1.0
+        / ((d.x * d.x + d.y * d.y) / (PLANET_SEMIMAJOR_AXIS * PLANET_SEMIMAJOR_AXIS)
+            + d.z * d.z / (PLANET_SEMIMINOR_AXIS * PLANET_SEMIMINOR_AXIS))
+            .sqrt();
+    distance - surface
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_planes() {
+        // A person standing at sea level sees about 5 km.
+        assert!((horizon_distance(1.7) - 4657.0).abs() < 10.0);
+        assert_eq!(horizon_distance(-10.0), 0.0);
+
+        let (near, far) = clip_planes(1000.0, 2.0);
+        assert_eq!(near, MIN_NEAR_PLANE);
+        assert!(far > horizon_distance(1000.0) && far < 500_000.0);
+
+        let (near, far) = clip_planes(400_000.0, 400_000.0);
+        assert_eq!(near, MAX_NEAR_PLANE);
+        assert!(far > 2_000_000.0);
+
+        let equator = Vector3::new(PLANET_SEMIMAJOR_AXIS + 100.0, 0.0, 0.0);
+        assert!((altitude(equator) - 100.0).abs() < 1e-6);
+        let pole = Vector3::new(0.0, 0.0, PLANET_SEMIMINOR_AXIS + 100.0);
+        assert!((altitude(pole) - 100.0).abs() < 1e-6);
+    }
+}
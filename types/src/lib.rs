@@ -4,19 +4,80 @@ extern crate lazy_static;
 use serde::{Deserialize, Serialize};
 use std::f64::consts::PI;
 
+mod horizon;
 mod math;
 mod node;
 
+pub use horizon::{
+    altitude, clip_planes, horizon_distance, max_visible_distance, MAX_TERRAIN_HEIGHT,
+    MIN_TERRAIN_HEIGHT,
+};
 pub use math::{BoundingBox, InfiniteFrustum};
 pub use node::{VNode, NODE_OFFSETS};
 
-pub const EARTH_RADIUS: f64 = 6371000.0;
-pub const EARTH_CIRCUMFERENCE: f64 = 2.0 * PI * EARTH_RADIUS;
-pub const EARTH_SEMIMAJOR_AXIS: f64 = 6378137.0;
-pub const EARTH_SEMIMINOR_AXIS: f64 = 6356752.314245;
-pub const ROOT_SIDE_LENGTH: f32 = (EARTH_CIRCUMFERENCE * 0.25) as f32;
+/// A celestial body that terrain can be rendered for.
+///
+/// Only Earth is supported so far. Tiles store heights between -1024 and about 15000 meters,
+/// which doesn't cover the relief of the Moon or Mars, and `terra-generate` has no importers for
+/// their elevation models.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Body {
+    Earth,
+}
+impl Body {
+    /// Mean radius in meters.
+    pub const fn radius(&self) -> f64 {
+        match self {
+            Body::Earth => 6371000.0,
+        }
+    }
+    /// Equatorial radius of the reference ellipsoid in meters.
+    pub const fn semimajor_axis(&self) -> f64 {
+        match self {
+            Body::Earth => 6378137.0,
+        }
+    }
+    /// Polar radius of the reference ellipsoid in meters.
+    pub const fn semiminor_axis(&self) -> f64 {
+        match self {
+            Body::Earth => 6356752.314245,
+        }
+    }
+    /// Height in meters of the tallest terrain above the reference ellipsoid, rounded up.
+    pub const fn max_terrain_height(&self) -> f64 {
+        match self {
+            Body::Earth => 9000.0,
+        }
+    }
+    /// Bound in meters on how far the terrain dips below the polar radius of the reference
+    /// ellipsoid, as a negative height.
+    pub const fn min_terrain_height(&self) -> f64 {
+        match self {
+            Body::Earth => -1024.0,
+        }
+    }
+}
+
+/// The body being rendered. Must match the constants in declarations.glsl.
+pub const BODY: Body = Body::Earth;
+
+pub const PLANET_RADIUS: f64 = BODY.radius();
+pub const PLANET_CIRCUMFERENCE: f64 = 2.0 * PI * PLANET_RADIUS;
+pub const PLANET_SEMIMAJOR_AXIS: f64 = BODY.semimajor_axis();
+pub const PLANET_SEMIMINOR_AXIS: f64 = BODY.semiminor_axis();
+pub const ROOT_SIDE_LENGTH: f32 = (PLANET_CIRCUMFERENCE * 0.25) as f32;
 pub const MAX_QUADTREE_LEVEL: u8 = VNode::LEVEL_CELL_5MM;
 
+#[deprecated(note = "Renamed to `PLANET_RADIUS`")]
+pub const EARTH_RADIUS: f64 = PLANET_RADIUS;
+#[deprecated(note = "Renamed to `PLANET_CIRCUMFERENCE`")]
+pub const EARTH_CIRCUMFERENCE: f64 = PLANET_CIRCUMFERENCE;
+#[deprecated(note = "Renamed to `PLANET_SEMIMAJOR_AXIS`")]
+pub const EARTH_SEMIMAJOR_AXIS: f64 = PLANET_SEMIMAJOR_AXIS;
+#[deprecated(note = "Renamed to `PLANET_SEMIMINOR_AXIS`")]
+pub const EARTH_SEMIMINOR_AXIS: f64 = PLANET_SEMIMINOR_AXIS;
+
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Priority(f32);
 impl Priority {
@@ -1,12 +1,27 @@
 use crate::{
-    InfiniteFrustum, Priority, EARTH_CIRCUMFERENCE, EARTH_RADIUS, EARTH_SEMIMAJOR_AXIS,
-    EARTH_SEMIMINOR_AXIS, MAX_QUADTREE_LEVEL,
+    InfiniteFrustum, Priority, MAX_QUADTREE_LEVEL, MIN_TERRAIN_HEIGHT, PLANET_CIRCUMFERENCE,
+    PLANET_RADIUS, PLANET_SEMIMAJOR_AXIS, PLANET_SEMIMINOR_AXIS,
 };
 use cgmath::*;
 use serde::{Deserialize, Serialize};
 use std::{collections::VecDeque, str::FromStr};
 
-const ROOT_SIDE_LENGTH: f32 = (EARTH_CIRCUMFERENCE * 0.25) as f32;
+const ROOT_SIDE_LENGTH: f32 = (PLANET_CIRCUMFERENCE * 0.25) as f32;
+
+/// Geometric error assumed for nodes whose heights aren't known, as a fraction of the spacing
+/// between height samples.
+const NOMINAL_RELATIVE_ERROR: f32 = 0.25;
+/// Bounds on the geometric error used for LOD selection, relative to the nominal error. These keep
+/// perfectly flat areas from never being refined, and rough ones from being refined excessively.
+const MIN_ERROR_SCALE: f32 = 0.25;
+const MAX_ERROR_SCALE: f32 = 4.0;
+
+/// Radius of a sphere that lies entirely below the terrain surface. Anything hidden behind it is
+/// guaranteed to be hidden behind the planet itself.
+const HORIZON_OCCLUDER_RADIUS: f64 = PLANET_SEMIMINOR_AXIS + MIN_TERRAIN_HEIGHT;
+/// Padding added to node bounds for horizon culling, to account for heights being displaced
+/// along the ellipsoid normal rather than radially.
+const HORIZON_MARGIN: f64 = 100.0;
 
 lazy_static! {
     pub static ref NODE_OFFSETS: [Vector2<i32>; 4] =
@@ -213,24 +228,28 @@ impl VNode {
     pub fn center_wspace(&self) -> Vector3<f64> {
         let normalized = self.cell_position_cspace(0, 0, 0, 1).normalize();
         Vector3::new(
-            normalized.x * EARTH_SEMIMAJOR_AXIS,
-            normalized.y * EARTH_SEMIMAJOR_AXIS,
-            normalized.z * EARTH_SEMIMINOR_AXIS,
+            normalized.x * PLANET_SEMIMAJOR_AXIS,
+            normalized.y * PLANET_SEMIMAJOR_AXIS,
+            normalized.z * PLANET_SEMIMINOR_AXIS,
         )
     }
 
-    fn distance2(&self, point: Vector3<f64>, height_range: (f32, f32)) -> f64 {
+    /// Squared distance from `point` to the closest part of this node, given the range of
+    /// terrain heights within it.
+    pub fn distance2(&self, point: Vector3<f64>, height_range: (f32, f32)) -> f64 {
         const E2: f64 = 1.0
-            - (EARTH_SEMIMINOR_AXIS * EARTH_SEMIMINOR_AXIS)
-                / (EARTH_SEMIMAJOR_AXIS * EARTH_SEMIMAJOR_AXIS);
+            - (PLANET_SEMIMINOR_AXIS * PLANET_SEMIMINOR_AXIS)
+                / (PLANET_SEMIMAJOR_AXIS * PLANET_SEMIMAJOR_AXIS);
 
         let p = (point.x * point.x + point.y * point.y).sqrt();
 
         let mut height = 0.0;
-        let mut latitude =
-            f64::atan2(point.z * (EARTH_SEMIMAJOR_AXIS.powi(2) / EARTH_SEMIMINOR_AXIS.powi(2)), p);
+        let mut latitude = f64::atan2(
+            point.z * (PLANET_SEMIMAJOR_AXIS.powi(2) / PLANET_SEMIMINOR_AXIS.powi(2)),
+            p,
+        );
         for _ in 0..5 {
-            let n = EARTH_SEMIMAJOR_AXIS / (1.0 - E2 * latitude.sin().powi(2)).sqrt();
+            let n = PLANET_SEMIMAJOR_AXIS / (1.0 - E2 * latitude.sin().powi(2)).sqrt();
             latitude = f64::atan2(point.z / p, 1.0 - E2 * n / (n + height));
             height = p / latitude.cos() - n;
         }
@@ -241,9 +260,9 @@ impl VNode {
             point.y - height * longitude.sin() * latitude.cos(),
             point.z - height * latitude.sin(),
         );
-        // let latitude2 = f64::atan2(point.z * EARTH_SEMIMAJOR_AXIS.powi(2) / EARTH_SEMIMINOR_AXIS.powi(2), (point.x.powi(2) + point.y.powi(2)).sqrt());
+        // let latitude2 = f64::atan2(point.z * PLANET_SEMIMAJOR_AXIS.powi(2) / PLANET_SEMIMINOR_AXIS.powi(2), (point.x.powi(2) + point.y.powi(2)).sqrt());
         // assert!((latitude - latitude2).abs() < 0.0000000000001);
-        // return (point.normalize().dot(self.center_wspace().normalize()).acos() * EARTH_SEMIMAJOR_AXIS).powi(2);
+        // return (point.normalize().dot(self.center_wspace().normalize()).acos() * PLANET_SEMIMAJOR_AXIS).powi(2);
 
         // let center = self.center_wspace();
         // let delta = Vector3::new(
@@ -253,34 +272,34 @@ impl VNode {
         // );
         // let shell_point = center.add_element_wise(delta);
 
-        // println!("{} {}", height, shell_point.normalize().dot(self.center_wspace().normalize()).acos() * EARTH_SEMIMAJOR_AXIS);
+        // println!("{} {}", height, shell_point.normalize().dot(self.center_wspace().normalize()).acos() * PLANET_SEMIMAJOR_AXIS);
 
-        // return (point.normalize().dot(shell_point.normalize()).acos() * EARTH_SEMIMAJOR_AXIS).powi(2);
+        // return (point.normalize().dot(shell_point.normalize()).acos() * PLANET_SEMIMAJOR_AXIS).powi(2);
 
         // let point = Vector3::new(
         //     n * latitude.cos() * longitude.cos(),
         //     n * latitude.cos() * longitude.sin(),
-        //     n * EARTH_SEMIMINOR_AXIS.powi(2) / EARTH_SEMIMAJOR_AXIS.powi(2) * latitude.sin(),
+        //     n * PLANET_SEMIMINOR_AXIS.powi(2) / PLANET_SEMIMAJOR_AXIS.powi(2) * latitude.sin(),
         // );
 
-        let min_radius = EARTH_SEMIMAJOR_AXIS + height_range.0 as f64;
-        let max_radius = EARTH_SEMIMAJOR_AXIS + height_range.1 as f64;
+        let min_radius = PLANET_SEMIMAJOR_AXIS + height_range.0 as f64;
+        let max_radius = PLANET_SEMIMAJOR_AXIS + height_range.1 as f64;
 
         let point = point
-            .mul_element_wise(Vector3::new(1.0, 1.0, EARTH_SEMIMAJOR_AXIS / EARTH_SEMIMINOR_AXIS))
+            .mul_element_wise(Vector3::new(1.0, 1.0, PLANET_SEMIMAJOR_AXIS / PLANET_SEMIMINOR_AXIS))
             .normalize()
             .mul_element_wise(Vector3::new(
-                EARTH_SEMIMAJOR_AXIS,
-                EARTH_SEMIMAJOR_AXIS,
-                EARTH_SEMIMAJOR_AXIS,
+                PLANET_SEMIMAJOR_AXIS,
+                PLANET_SEMIMAJOR_AXIS,
+                PLANET_SEMIMAJOR_AXIS,
             ));
         // let point = Vector3::new(
-        //     (EARTH_SEMIMAJOR_AXIS + height) * latitude.cos() * longitude.cos(),
-        //     (EARTH_SEMIMAJOR_AXIS + height) * latitude.cos() * longitude.sin(),
-        //     (EARTH_SEMIMAJOR_AXIS + height) * latitude.sin(),
+        //     (PLANET_SEMIMAJOR_AXIS + height) * latitude.cos() * longitude.cos(),
+        //     (PLANET_SEMIMAJOR_AXIS + height) * latitude.cos() * longitude.sin(),
+        //     (PLANET_SEMIMAJOR_AXIS + height) * latitude.sin(),
         // );
 
-        // let scale = Vector3::new(1.0, 1.0, EARTH_SEMIMINOR_AXIS / EARTH_SEMIMAJOR_AXIS);
+        // let scale = Vector3::new(1.0, 1.0, PLANET_SEMIMINOR_AXIS / PLANET_SEMIMAJOR_AXIS);
         let corners = [
             self.grid_position_cspace(0, 0, 0, 2), //.mul_element_wise(scale),
             self.grid_position_cspace(1, 0, 0, 2), //.mul_element_wise(scale),
@@ -350,17 +369,54 @@ impl VNode {
 
         let center = self
             .cell_position_cspace(0, 0, 0, 1)
-            .normalize_to(EARTH_RADIUS + (height_range.0 as f64 + height_range.1 as f64) * 0.5);
+            .normalize_to(PLANET_RADIUS + (height_range.0 as f64 + height_range.1 as f64) * 0.5);
 
         let mut radius2 = 0.0f64;
         for &c in &corners {
-            radius2 = radius2.max(center.distance2(c * (EARTH_RADIUS + height_range.0 as f64)));
-            radius2 = radius2.max(center.distance2(c * (EARTH_RADIUS + height_range.1 as f64)));
+            radius2 = radius2.max(center.distance2(c * (PLANET_RADIUS + height_range.0 as f64)));
+            radius2 = radius2.max(center.distance2(c * (PLANET_RADIUS + height_range.1 as f64)));
         }
 
         f.intersects_sphere(center, radius2)
     }
 
+    /// Returns the center and radius of a sphere enclosing all terrain in this node, given the
+    /// range of heights within it.
+    pub fn bounding_sphere(&self, height_range: (f32, f32)) -> (Vector3<f64>, f64) {
+        let to_ellipsoid = |v: Vector3<f64>| {
+            Vector3::new(
+                v.x * PLANET_SEMIMAJOR_AXIS,
+                v.y * PLANET_SEMIMAJOR_AXIS,
+                v.z * PLANET_SEMIMINOR_AXIS,
+            )
+        };
+        let corners = [
+            self.grid_position_cspace(0, 0, 0, 2).normalize(),
+            self.grid_position_cspace(1, 0, 0, 2).normalize(),
+            self.grid_position_cspace(1, 1, 0, 2).normalize(),
+            self.grid_position_cspace(0, 1, 0, 2).normalize(),
+        ];
+
+        let (min_height, max_height) = (height_range.0 as f64, height_range.1 as f64);
+        let center = self.center_wspace()
+            + self.cell_position_cspace(0, 0, 0, 1).normalize() * (min_height + max_height) * 0.5;
+
+        let mut radius2 = 0.0f64;
+        for &c in &corners {
+            radius2 = radius2.max(center.distance2(to_ellipsoid(c) + c * min_height));
+            radius2 = radius2.max(center.distance2(to_ellipsoid(c) + c * max_height));
+        }
+
+        (center, radius2.sqrt())
+    }
+
+    /// Returns whether this node is entirely hidden behind the horizon when viewed from `camera`.
+    /// Unlike frustum culling, this only depends on the camera position.
+    pub fn below_horizon(&self, camera: Vector3<f64>, height_range: (f32, f32)) -> bool {
+        let (center, radius) = self.bounding_sphere(height_range);
+        occluded_by_planet(camera, center, radius + HORIZON_MARGIN)
+    }
+
     /// How much this node is needed for the current frame. Nodes with priority less than 1.0 will
     /// not be rendered (they are too detailed).
     pub fn priority(&self, camera: Vector3<f64>, height_range: (f32, f32)) -> Priority {
@@ -375,6 +431,37 @@ impl VNode {
         Priority::from_f32(priority)
     }
 
+    /// How much this node is needed for the current frame, based on how large its geometric error
+    /// would appear on screen. Nodes with priority less than 1.0 will not be rendered.
+    ///
+    /// `geometric_error` is the maximum difference in meters between the heights of this node and
+    /// those of its parent, if known. `error_scale` is the size of a one meter error seen from one
+    /// meter away, as a multiple of the largest tolerated on-screen error.
+    pub fn screen_space_priority(
+        &self,
+        camera: Vector3<f64>,
+        height_range: (f32, f32),
+        geometric_error: Option<f32>,
+        error_scale: f64,
+    ) -> Priority {
+        let nominal_error = self.aprox_side_length() / 512.0 * NOMINAL_RELATIVE_ERROR;
+        let error = match geometric_error {
+            Some(error) => {
+                error.clamp(nominal_error * MIN_ERROR_SCALE, nominal_error * MAX_ERROR_SCALE)
+            }
+            None => nominal_error,
+        };
+
+        let projected_error = error as f64 * error_scale;
+        let distance2 = self.distance2(camera, height_range);
+        let mut priority = (projected_error * projected_error / distance2.max(1e-12)) as f32;
+        if self.level() == 0 {
+            priority = priority.max(2.0);
+        }
+
+        Priority::from_f32(priority)
+    }
+
     pub fn parent(&self) -> Option<(VNode, u8)> {
         if self.level() == 0 {
             return None;
@@ -491,6 +578,33 @@ impl FromStr for VNode {
     }
 }
 
+/// Returns whether a sphere is entirely hidden from `camera` behind a sphere of radius
+/// `HORIZON_OCCLUDER_RADIUS` centered at the origin.
+fn occluded_by_planet(camera: Vector3<f64>, center: Vector3<f64>, radius: f64) -> bool {
+    let r = HORIZON_OCCLUDER_RADIUS;
+    let d2 = camera.magnitude2();
+    if d2 <= r * r {
+        return false;
+    }
+    let d = d2.sqrt();
+    let down = -camera / d;
+
+    let v = center - camera;
+    let distance = v.magnitude();
+    if distance <= radius {
+        return false;
+    }
+
+    // The sphere must lie entirely beyond the plane containing the horizon circle...
+    if v.dot(down) - radius < d - r * r / d {
+        return false;
+    }
+
+    // ...and entirely within the cone of view directions that are blocked by the planet.
+    let angle = (v.dot(down) / distance).clamp(-1.0, 1.0).acos();
+    angle + (radius / distance).asin() < (r / d).asin()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -502,4 +616,32 @@ mod tests {
         let p = node.priority(camera, (0.0, 9000.0));
         assert!(p > Priority::cutoff());
     }
+
+    #[test]
+    fn test_error_priority() {
+        let node = VNode::new(6, 1, 10, 10);
+        let camera = node.center_wspace() * 1.01;
+        let height_range = (0.0, 9000.0);
+
+        let p = node.screen_space_priority(camera, height_range, None, 1000.0);
+        let flat = node.screen_space_priority(camera, height_range, Some(0.0), 1000.0);
+        let rough = node.screen_space_priority(camera, height_range, Some(1000.0), 1000.0);
+        assert!(flat < p && p < rough);
+
+        // Tolerating more error on screen reduces priority.
+        assert!(node.screen_space_priority(camera, height_range, None, 100.0) < p);
+    }
+
+    #[test]
+    fn test_below_horizon() {
+        let camera = Vector3::new(2.0 * PLANET_SEMIMAJOR_AXIS, 0.0, 0.0);
+        let near = VNode::from_cspace(Vector3::new(1.0, 0.1, 0.1), 4).0;
+        let far = VNode::from_cspace(Vector3::new(-1.0, 0.1, 0.1), 4).0;
+        assert!(!near.below_horizon(camera, (0.0, 9000.0)));
+        assert!(far.below_horizon(camera, (0.0, 9000.0)));
+
+        // Nodes near the horizon itself remain visible.
+        let limb = VNode::from_cspace(Vector3::new(0.7, 1.0, 0.0), 4).0;
+        assert!(!limb.below_horizon(camera, (0.0, 9000.0)));
+    }
 }